@@ -182,7 +182,7 @@ pub fn main() {
     let varstring: String = nb_var.to_string();
     let mut basename = String::from("Higgs-");
     basename.push_str(&varstring);
-    let mut reloader = HnswIo::new(directory, basename);
+    let mut reloader = HnswIo::new(&directory, &basename);
     let mut hnsw_opt: Option<Hnsw<f32, DistL2>> = None;
     let hnsw: Hnsw<f32, DistL2>;
     //
@@ -250,7 +250,7 @@ pub fn main() {
             let mut fname = String::from("Higgs");
             fname.push_str("-");
             fname.push_str(&varstring);
-            let _res = hnsw.file_dump(&fname);
+            let _res = hnsw.file_dump(&directory, &fname);
         }
     }
     //
@@ -283,7 +283,7 @@ pub fn main() {
         embed_params.nb_grad_batch = 40;
         embed_params.grad_factor = 5;
         log::info!("graph projection on layer : {}", projection_layer);
-        graphprojection = KGraphProjection::<f32>::new(&hnsw, knbn, projection_layer);
+        graphprojection = KGraphProjection::<f32>::new(&hnsw, knbn, projection_layer).unwrap();
         embedder = Embedder::from_hkgraph(&graphprojection, embed_params);
         let embed_res = embedder.embed();
         assert!(embed_res.is_ok());
@@ -0,0 +1,69 @@
+//! Embedding of text documents given as a sparse document by term matrix.
+//!
+//! The matrix is read in MatrixMarket format (scikit-learn : `scipy.io.mmwrite("docs.mtx", CountVectorizer().fit_transform(docs))`),
+//! with optional ids and vocabulary files, one string by line. Counts are transformed in TF-IDF weights and rows are
+//! embedded with the cosine distance on sparse rows, without any dense copy of the data.
+//!
+//! Usage : sparse_text docs.mtx \[ids.txt\] \[vocabulary.txt\]. Without argument a synthetic corpus of 2 topics is embedded.
+//!
+
+use std::path::Path;
+
+use sprs::{CsMat, TriMat};
+
+use annembed::pipeline::{EmbedParams, GraphParams};
+use annembed::sparse::{embed_sparse, SparseTextData};
+
+// documents of 2 topics, each drawing 5 terms in its own vocabulary of 50 terms, and 3 terms in a common vocabulary of 10
+fn synthetic_corpus(nb_doc: usize) -> CsMat<f32> {
+    let mut counts = TriMat::<f32>::new((nb_doc, 110));
+    for i in 0..nb_doc {
+        let topic = i % 2;
+        for k in 0..5 {
+            counts.add_triplet(i, 50 * topic + (7 * i + 11 * k) % 50, 1. + (k % 2) as f32);
+        }
+        for k in 0..3 {
+            counts.add_triplet(i, 100 + (i + 3 * k) % 10, 1.);
+        }
+    }
+    counts.to_csr()
+} // end of synthetic_corpus
+
+pub fn main() {
+    let _ = env_logger::builder().is_test(true).try_init();
+    //
+    let args: Vec<String> = std::env::args().collect();
+    let mut text_data = if args.len() > 1 {
+        let ids = args.get(2).map(Path::new);
+        let vocabulary = args.get(3).map(Path::new);
+        SparseTextData::load(Path::new(&args[1]), ids, vocabulary).unwrap()
+    } else {
+        println!("no matrix given, embedding a synthetic corpus");
+        SparseTextData::new(synthetic_corpus(2000), None, None).unwrap()
+    };
+    text_data.apply_tfidf().unwrap();
+    //
+    let params = EmbedParams {
+        graph: GraphParams {
+            knbn: Some(15),
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+    let output = embed_sparse(text_data.get_matrix(), &params).unwrap();
+    let diagnostics = &output.diagnostics;
+    println!(
+        "embedded {} documents, graph edges : {}, components : {}, times(ms) hnsw graph embedding : {:?}",
+        diagnostics.nb_points, diagnostics.nb_edges, diagnostics.nb_components, diagnostics.times_ms
+    );
+    let coordinates = output.get_coordinates_in_data_order();
+    for i in 0..coordinates.nrows().min(5) {
+        let id = text_data.get_row_ids().map_or(i.to_string(), |ids| ids[i].clone());
+        println!(
+            "document {} : {:?}, main terms : {:?}",
+            id,
+            coordinates.row(i).to_vec(),
+            text_data.get_top_terms(i, 5)
+        );
+    }
+} // end of main
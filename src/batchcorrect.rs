@@ -0,0 +1,276 @@
+//! Correction of a categorical covariate (sequencing batch, acquisition site...) in diffusion embeddings.
+//!
+//! When data come from several batches, points of a batch tend to be neighbours of points of the same batch and
+//! the map separates batches instead of biological (or physical) states. Two corrections are provided :
+//!
+//! - [BatchCorrectionMethod::Regression] regresses diffusion coordinates on batch indicators : the least squares fit of
+//!   a one hot encoded categorical covariate is the batch mean, so each batch is centered and the global mean restored.
+//! - [BatchCorrectionMethod::KernelBalance] adjusts kernel weights before the spectral step : the weights of the edges of a node
+//!   are rescaled so that the node distributes its transition probability between the batches present in its neighbourhood
+//!   in proportion of the batch sizes in the whole data. Rescaling factors are bounded by [MAX_BALANCE_FACTOR].
+//!
+//! With [Embedder](crate::embedder::Embedder) (see [Embedder::set_batch_correction](crate::embedder::Embedder::set_batch_correction))
+//! regression applies to the diffusion maps initialization and kernel balance to the graph used for initialization and
+//! cross entropy optimization. With [DiffusionMaps](crate::diffmaps::DiffusionMaps) both apply to the returned coordinates.
+//!
+
+use std::collections::HashMap;
+use std::hash::Hash;
+
+use ndarray::Array2;
+use num_traits::cast::FromPrimitive;
+use num_traits::Float;
+
+use crate::error::AnnembedError;
+use crate::fromhnsw::kgraph::KGraph;
+use crate::tools::io::DataLabels;
+use crate::tools::nodeparam::*;
+
+/// maximal factor applied to the weights of a batch in a neighbourhood by kernel balance
+pub const MAX_BALANCE_FACTOR: f32 = 10.;
+
+/// Correction applied, see the [module](self) documentation.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum BatchCorrectionMethod {
+    /// batch means are regressed out of diffusion coordinates
+    Regression,
+    /// kernel weights are rebalanced between batches
+    KernelBalance,
+    /// kernel balance then regression
+    Both,
+}
+
+/// A categorical covariate given for each node of a graph, and the correction to apply.
+#[derive(Clone, Debug)]
+pub struct BatchCorrection {
+    /// batch (in 0..nb_batches) of each node, indexed by node rank in the graph
+    batches: Vec<usize>,
+    nb_batches: usize,
+    method: BatchCorrectionMethod,
+} // end of BatchCorrection
+
+impl BatchCorrection {
+    /// batches\[i\] is the batch of node i, batches are numbered from 0.
+    pub fn new(batches: Vec<usize>, method: BatchCorrectionMethod) -> Self {
+        let nb_batches = batches.iter().max().map_or(0, |b| b + 1);
+        BatchCorrection {
+            batches,
+            nb_batches,
+            method,
+        }
+    }
+
+    /// builds the covariate of nodes of kgraph from labels of their DataId. Each distinct label is a batch,
+    /// numbered in order of first appearance in node order. All nodes must be labeled.
+    pub fn from_labels<F, L>(kgraph: &KGraph<F>, labels: &DataLabels<L>, method: BatchCorrectionMethod) -> Result<Self, AnnembedError>
+    where
+        F: Float + FromPrimitive + std::marker::Sync + Send + std::fmt::UpperExp + std::iter::Sum,
+        L: Clone + Eq + Hash,
+    {
+        let mut levels = HashMap::<L, usize>::new();
+        let mut batches = Vec::<usize>::with_capacity(kgraph.get_nb_nodes());
+        for i in 0..kgraph.get_nb_nodes() {
+            let data_id = kgraph.get_data_id_from_idx(i).unwrap();
+            let label = labels
+                .get(data_id)
+                .ok_or_else(|| AnnembedError::InvalidParameter(format!("batch correction, no batch for DataId {}", data_id)))?;
+            let nb_levels = levels.len();
+            batches.push(*levels.entry(label.clone()).or_insert(nb_levels));
+        }
+        log::info!("batch correction, nb nodes : {}, nb batches : {}", batches.len(), levels.len());
+        Ok(BatchCorrection::new(batches, method))
+    } // end of from_labels
+
+    /// returns batch of each node
+    pub fn get_batches(&self) -> &[usize] {
+        &self.batches
+    }
+
+    pub fn get_nb_batches(&self) -> usize {
+        self.nb_batches
+    }
+
+    pub fn get_method(&self) -> BatchCorrectionMethod {
+        self.method
+    }
+
+    /// true if diffusion coordinates are regressed on batches
+    pub fn do_regression(&self) -> bool {
+        self.method != BatchCorrectionMethod::KernelBalance
+    }
+
+    /// true if kernel weights are rebalanced between batches
+    pub fn do_kernel_balance(&self) -> bool {
+        self.method != BatchCorrectionMethod::Regression
+    }
+
+    /// returns the correction for the subgraph made of nodes (given by rank in the graph, in subgraph order)
+    pub fn get_restricted(&self, nodes: &[usize]) -> Self {
+        BatchCorrection {
+            batches: nodes.iter().map(|n| self.batches[*n]).collect(),
+            nb_batches: self.nb_batches,
+            method: self.method,
+        }
+    }
+
+    fn check_size(&self, nb_nodes: usize) -> Result<(), AnnembedError> {
+        if self.batches.len() != nb_nodes {
+            return Err(AnnembedError::InvalidParameter(format!(
+                "batch correction, covariate given for {} nodes, graph has {}",
+                self.batches.len(),
+                nb_nodes
+            )));
+        }
+        Ok(())
+    }
+
+    /// regresses batch means out of coordinates (rows indexed by node). Returns the fraction of variance removed.
+    pub fn regress<F: Float>(&self, coordinates: &mut Array2<F>) -> Result<f64, AnnembedError> {
+        self.check_size(coordinates.nrows())?;
+        let fraction = regress_out_covariate(coordinates, &self.batches, self.nb_batches);
+        log::info!("batch correction, fraction of variance of diffusion coordinates explained by batches : {:.3e}", fraction);
+        Ok(fraction)
+    }
+
+    /// rebalances kernel weights between batches, see [balance_kernel]
+    pub fn balance(&self, node_params: &mut NodeParams) -> Result<(), AnnembedError> {
+        self.check_size(node_params.get_nb_nodes())?;
+        balance_kernel(node_params, &self.batches, self.nb_batches);
+        Ok(())
+    }
+} // end of impl BatchCorrection
+
+/// least squares regression of each column of coordinates on the indicators of the nb_batches levels of covariate.
+/// Residuals plus the global mean replace coordinates. Returns the fraction of total variance explained by batches.
+pub fn regress_out_covariate<F: Float>(coordinates: &mut Array2<F>, covariate: &[usize], nb_batches: usize) -> f64 {
+    let (nb_rows, dim) = coordinates.dim();
+    assert_eq!(nb_rows, covariate.len());
+    if nb_rows == 0 {
+        return 0.;
+    }
+    let mut counts = vec![0usize; nb_batches];
+    let mut means = Array2::<f64>::zeros((nb_batches, dim));
+    let mut global = vec![0f64; dim];
+    for (i, row) in coordinates.rows().into_iter().enumerate() {
+        counts[covariate[i]] += 1;
+        for j in 0..dim {
+            let x = row[j].to_f64().unwrap();
+            means[[covariate[i], j]] += x;
+            global[j] += x;
+        }
+    }
+    for (mut mean, count) in means.rows_mut().into_iter().zip(counts.iter()) {
+        if *count > 0 {
+            mean.mapv_inplace(|x| x / *count as f64);
+        }
+    }
+    global.iter_mut().for_each(|x| *x /= nb_rows as f64);
+    //
+    let mut total_var = 0.;
+    let mut explained_var = 0.;
+    for (i, mut row) in coordinates.rows_mut().into_iter().enumerate() {
+        let b = covariate[i];
+        for j in 0..dim {
+            let x = row[j].to_f64().unwrap();
+            total_var += (x - global[j]) * (x - global[j]);
+            explained_var += (means[[b, j]] - global[j]) * (means[[b, j]] - global[j]);
+            row[j] = F::from(x - means[[b, j]] + global[j]).unwrap();
+        }
+    }
+    if total_var > 0. {
+        explained_var / total_var
+    } else {
+        0.
+    }
+} // end of regress_out_covariate
+
+/// For each node, weights of edges towards batch b are multiplied by $p_{b} / w_{b}$ where $w_{b}$ is the fraction of the node weight
+/// going to batch b and $p_{b}$ the fraction of batch b in data, renormalized on batches present in the neighbourhood.
+/// Factors are clipped to \[1/[MAX_BALANCE_FACTOR], [MAX_BALANCE_FACTOR]\] and the total weight of each node is preserved.
+pub fn balance_kernel(node_params: &mut NodeParams, covariate: &[usize], nb_batches: usize) {
+    let nb_nodes = covariate.len();
+    let mut frequencies = vec![0f32; nb_batches];
+    covariate.iter().for_each(|b| frequencies[*b] += 1. / nb_nodes as f32);
+    let mut local = vec![0f32; nb_batches];
+    let mut nb_modified = 0;
+    for param in node_params.params.iter_mut() {
+        local.iter_mut().for_each(|w| *w = 0.);
+        for edge in &param.edges {
+            local[covariate[edge.get_node()]] += edge.weight;
+        }
+        let total: f32 = local.iter().sum();
+        if total <= 0. {
+            continue;
+        }
+        let present: f32 = (0..nb_batches).filter(|b| local[*b] > 0.).map(|b| frequencies[b]).sum();
+        if local.iter().filter(|w| **w > 0.).count() < 2 {
+            continue;
+        }
+        for edge in param.edges.iter_mut() {
+            let b = covariate[edge.get_node()];
+            let factor = (frequencies[b] / present) / (local[b] / total);
+            edge.weight *= factor.clamp(1. / MAX_BALANCE_FACTOR, MAX_BALANCE_FACTOR);
+        }
+        let new_total: f32 = param.edges.iter().map(|e| e.weight).sum();
+        param.edges.iter_mut().for_each(|e| e.weight *= total / new_total);
+        nb_modified += 1;
+    }
+    log::info!("batch correction, kernel weights rebalanced for {} nodes with neighbours in several batches", nb_modified);
+} // end of balance_kernel
+
+//=====================================================================================
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    fn log_init_test() {
+        let _ = env_logger::builder().is_test(true).try_init();
+    }
+
+    #[test]
+    fn test_regress_out_covariate() {
+        log_init_test();
+        // 2 batches shifted along first axis, same structure along second axis
+        let nb = 100;
+        let covariate: Vec<usize> = (0..nb).map(|i| i % 2).collect();
+        let mut coordinates = Array2::<f32>::from_shape_fn((nb, 2), |(i, j)| {
+            let state = ((i / 2) % 5) as f32;
+            if j == 0 {
+                5. * (i % 2) as f32 + 0.1 * state
+            } else {
+                state
+            }
+        });
+        let fraction = regress_out_covariate(&mut coordinates, &covariate, 2);
+        log::info!("fraction explained : {:.3e}", fraction);
+        // batch variance 6.25 on first axis, state variance 0.02 and 2. on the axes
+        assert!((fraction - 6.25 / 8.27).abs() < 1.0E-3);
+        // batch means are now equal, state structure is kept
+        let mean = |b: usize, j: usize| (0..nb).filter(|i| covariate[*i] == b).map(|i| coordinates[[i, j]]).sum::<f32>() / (nb / 2) as f32;
+        assert!((mean(0, 0) - mean(1, 0)).abs() < 1.0E-4);
+        assert!((coordinates[[0, 1]] - coordinates[[1, 1]]).abs() < 1.0E-6);
+        assert!((coordinates[[2, 1]] - coordinates[[0, 1]] - 1.).abs() < 1.0E-6);
+    } // end of test_regress_out_covariate
+
+    #[test]
+    fn test_balance_kernel() {
+        log_init_test();
+        // node 0 sends 0.9 of its weight to batch 0 and 0.1 to batch 1, batches have equal sizes
+        let covariate = vec![0, 0, 0, 1, 1, 1];
+        let edges = vec![OutEdge::new(1, 0.45), OutEdge::new(2, 0.45), OutEdge::new(3, 0.1)];
+        let mut params: Vec<NodeParam> = (0..6).map(|_| NodeParam::new(1., Vec::new())).collect();
+        params[0] = NodeParam::new(1., edges);
+        params[4] = NodeParam::new(1., vec![OutEdge::new(5, 1.)]);
+        let mut node_params = NodeParams::new(params, 3);
+        balance_kernel(&mut node_params, &covariate, 2);
+        let edges = &node_params.params[0].edges;
+        let total: f32 = edges.iter().map(|e| e.weight).sum();
+        assert!((total - 1.).abs() < 1.0E-5);
+        assert!((edges[0].weight + edges[1].weight - 0.5).abs() < 1.0E-5);
+        assert!((edges[2].weight - 0.5).abs() < 1.0E-5);
+        // a neighbourhood in one batch is unchanged
+        assert_eq!(node_params.params[4].edges[0].weight, 1.);
+    } // end of test_balance_kernel
+} // end of mod tests
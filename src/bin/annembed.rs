@@ -1,9 +1,59 @@
 //! annembed binary.  
 //!
 //! This module provides just access to floating point data embedding.  
-//! Command syntax is embed input --csv csvfile  [--outfile | -o  output_name] [--delim u8] [hnsw params] [embed params].  
+//! Command syntax is embed input --csv csvfile | --npy npyfile  [--outfile | -o  output_name] [--delim u8] [hnsw params] [embed params].  
 //!
-//!  --outfile or -o to specify the name of csv file containing embedded vectors. By default the name is "embedded.csv"
+//!  --outfile or -o to specify the name of csv file containing embedded vectors. By default the name is "embedded.csv".
+//!    If the name ends with .npy the embedding is written in numpy format (f8) instead.
+//!
+//!  --npy to read data from a numpy file (2 dimensional array of f4 or f8 as written by numpy.save) instead of a csv file.
+//!    npy input and output need the npy feature (a default one).
+//!  --dmap to embed with diffusion maps (see [DiffusionMaps]) instead of the gradient embedder. The embedding is not hierarchical,
+//!    uncertainty, safetensors and html dumps are not available.
+//!  --quality file to write in csv (metric, value) quality indicators of the embedding : final loss and quality estimate
+//!    from edge lengths (see [get_quality_estimate_from_edge_length](Embedder::get_quality_estimate_from_edge_length))
+//!    with the gradient embedder, spectral gap, diffusion time and dimension with diffusion maps.
+//!
+//!  --dumpgraph to dump the graph extracted from the Hnsw structure in a file (bincode format) before embedding.  
+//!  --kgraph to embed a graph previously dumped with --dumpgraph instead of a csv file. No Hnsw structure nor data are needed,
+//!    so graph construction and embedding can run on different machines. The hnsw subcommand is then ignored.  
+//!    Embedding from a reloaded graph is not hierarchical.
+//!  --checkpoint dir to write stage checkpoints in dir : hnsw dump, graph (bincode), state of the gradient optimizer after
+//!    each gradient batch (see Embedder::set_checkpoint) and completed stages in file *stages*.  
+//!  --resume with --checkpoint continues an interrupted run from the last completed stage or gradient batch. When the graph
+//!    is checkpointed neither csv data nor hnsw are reloaded. Embedding parameters must be those of the interrupted run,
+//!    an error otherwise. Checkpointed runs are not hierarchical.
+//!  --report file to write in json a run report : wall and cpu time, peak resident memory, time of stages,
+//!    graph statistics and final loss of the embedding. See [RunReport](annembed::tools::report::RunReport).
+//!  --savereference file to dump the data and its embedding as a reference artifact (bincode), used to place new points
+//!    with --watch (if embedded with DistL2). Only for one step embeddings of csv or npy data, an error otherwise
+//!    (checkpointed, hierarchical, sparse or dumped graph runs). See [ReferenceArtifact](annembed::service::ReferenceArtifact).  
+//!  --watch dir runs as a service : dir is polled for new csv files, each file is embedded (with the L2 distance)
+//!    and its embedding is written in directory given by --watchout (default dir/embedded) as name.embedded.csv.  
+//!    With --reference file (an artifact dumped by --savereference) rows of new files are placed in the reference embedding
+//!    instead of being embedded independently.
+//!  --sparse file to embed the rows of a sparse matrix in MatrixMarket coordinate format (documents by terms, as written
+//!    by scikit-learn mmwrite) with the cosine distance on sparse rows instead of a csv file. With --dist DistDot rows are
+//!    normalized and compared with the dot distance, with --dist DistL2 rows are compared with the euclidean distance,
+//!    other hnsw distances are ignored.  
+//!    --tfidf transforms term counts into TF-IDF weights, --ids file gives the id of each row (one by line), written as first
+//!    column of the output, and --vocabulary file the term of each column (one by line) to display the main terms of some rows.
+//!    See module [sparse](annembed::sparse).
+//!  --uncertainty to add to each embedded vector a last column with the uncertainty of its position,
+//!    see [get_local_uncertainty](Embedder::get_local_uncertainty).
+//!  --safetensors file to also dump the embedding in safetensors format (embedding, DataId of rows, uncertainty of positions),
+//!    readable by ML serving stacks. See [dump_safetensors](Embedder::dump_safetensors).
+//!  --html file to write a self-contained html page with an interactive scatter plot of the 2 first dimensions
+//!    of the embedding (hover shows DataId and label, points colored by label). See [HtmlPlot](annembed::tools::htmlplot::HtmlPlot).  
+//!  --labels file gives the label of each row (one by line). Labels are written as the first field of csv outputs,
+//!    in a sidecar file *labels.txt* of npy outputs, and color the html plot. An output name ending with .json
+//!    writes records of label and coordinates. See [write_labeled_embedding](annembed::tools::io::write_labeled_embedding).
+//!  --clusters file to write in csv the cluster of each DataId, obtained by spectral rotation of the diffusion eigenvectors
+//!    of the graph. See [cluster_by_rotation](annembed::diffmaps::DiffusionMaps::cluster_by_rotation).
+//!    --nbclusters k imposes the number of clusters, by default it is given by the largest eigengap.
+//!
+//! Progress of hnsw insertion, graph extraction, svd and gradient batches is displayed with an estimation of remaining time
+//! computed from the throughput of the first blocks, chunks or batches.
 //!
 //! hnsw is an optional subcommand to change default parameters of the Hnsw structure. See [hnsw_rs](https://crates.io/crates/hnsw_rs).  
 //! embed is an optional subcommand to change default parameters related to the embedding: gradient, edge sampling etc. See [EmbedderParams]
@@ -11,12 +61,15 @@
 //! - Parameters for embed subcommand. The options give access to some fields of the [EmbedderParams] structure.  
 //!  --stepg    : a float value , initial gradient step, default is 2.  
 //!  --scale    : a float value, scale modification factor, default is 1.  
+//!  --autoscale : flag, scale factor is chosen from data by Berry-Giannakis-Harlim bandwidth selection.  
 //!  --nbsample : number of edge sampling , default is 10   
 //!  --layer    : in case of hierarchical embedding num of the lower layer we consider to run preliminary step.  
 //!
 //! - Parameters for the hnsw subcommand. For more details see [hnsw_rs](https://crates.io/crates/hnsw_rs).   
 //! --nbconn  : defines the number of connections by node in a layer.   Can range from 4 to 64 or more if necessary and enough memory
-//! --dist    : name of distance to use: "DistL1", "DistL2", "DistCosine", "DistJeyffreys"
+//! --dist    : name of distance to use: "DistL1", "DistL2", "DistCosine", "DistJeyffreys" or "DistPlugin", "DistDot" with --sparse only
+//! --distlib : with DistPlugin, path of a dynamic library exporting a distance, see [load_distance_plugin](annembed::tools::distplugin::load_distance_plugin)
+//! --distsym : with DistPlugin, name of the exported distance, default is "annembed_distance"
 //! --ef      : controls the with of the search, a good guess is between 24 and 64 or more if necessay
 //! --knbn    : the number of nodes to use in retrieval requests.  
 //!     
@@ -25,17 +78,200 @@
 //! The Julia directory provides helpers to get Persistence diagrams and barcodes and vizualize them using Ripserer.jl
 
 use cpu_time::ProcessTime;
+use std::fs::OpenOptions;
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
 use std::time::{Duration, SystemTime};
 
 use anyhow::anyhow;
 use clap::{Arg, ArgAction, ArgMatches, Command};
 
+use hnsw_rs::hnswio::HnswIo;
 use hnsw_rs::prelude::*;
+use ndarray::Array2;
+use sprs::CsMat;
 
 use annembed::fromhnsw::hubness;
 use annembed::fromhnsw::kgproj::KGraphProjection;
+use annembed::diffmaps::{iter_insert_hnsw, DiffusionMaps, DiffusionParams};
 use annembed::fromhnsw::kgraph::{kgraph_from_hnsw_all, KGraph};
 use annembed::prelude::*;
+use annembed::tools::distplugin::{load_distance_plugin, DEFAULT_PLUGIN_SYMBOL};
+use annembed::EmbedParams;
+use annembed::sparse::{
+    normalize_rows, sparse_insert_hnsw, DistSparseCosine, DistSparseDot, DistSparseL2, SparseEntry, SparseTextData,
+};
+use annembed::service::{DirectoryWatcher, ReferenceArtifact, ReferenceTransformer, WatchMode, REFERENCE_DISTANCE};
+use annembed::tools::io::{write_labeled_embedding, DataLabels};
+#[cfg(feature = "npy")]
+use annembed::tools::io::{read_npy_to_array2, write_array2_to_npy};
+use annembed::tools::report::{GraphReport, ResourceMonitor};
+use annembed::tools::stage::{set_progress_callback, StageProgress};
+
+// last progress display : stage and time, to display each stage at most once a second
+static LAST_PROGRESS: std::sync::Mutex<Option<(&'static str, SystemTime)>> = std::sync::Mutex::new(None);
+
+// displays progress of insertion, graph extraction, svd and gradient stages and estimated remaining time
+fn print_stage_progress(progress: &StageProgress) {
+    let mut last = LAST_PROGRESS.lock().unwrap();
+    let done = progress.nb_done >= progress.nb_total;
+    let display = match *last {
+        Some((stage, time)) => {
+            done || stage != progress.stage || time.elapsed().map_or(true, |d| d.as_secs() >= 1)
+        }
+        None => true,
+    };
+    if !display {
+        return;
+    }
+    *last = Some((progress.stage, SystemTime::now()));
+    let remaining = match progress.get_remaining_ms() {
+        Some(ms) => format!("{:.1}", ms / 1000.),
+        None => String::from("unknown"),
+    };
+    println!(
+        " {} : {} / {} ({:.1}%), elapsed time(s) {:.1}, remaining time(s) {}",
+        progress.stage,
+        progress.nb_done,
+        progress.nb_total,
+        100. * progress.get_fraction(),
+        progress.elapsed_ms as f64 / 1000.,
+        remaining
+    );
+}
+
+// parallel insertion of data in hnsw, reporting insertion progress
+fn insert_hnsw<Dist>(data_with_id: &[(&Vec<f64>, usize)], hnsw: &mut Hnsw<f64, Dist>) -> Result<usize, AnnembedError>
+where
+    Dist: Distance<f64> + Send + Sync,
+{
+    iter_insert_hnsw(data_with_id.iter().map(|(v, id)| (v.as_slice(), *id)), hnsw)
+}
+
+// file listing completed stages of a checkpointed run, one line by stage : name and value (file or basename)
+const CKPT_STAGES: &str = "stages";
+const CKPT_HNSW: &str = "annembed_ckpt";
+const CKPT_KGRAPH: &str = "kgraph.bin";
+const CKPT_OPTIMIZER: &str = "optimizer.bin";
+
+// maximum number of clusters searched by eigengap with --clusters
+const MAX_ROTATION_CLUSTERS: usize = 10;
+
+// completed stages of a run checkpointed in dir
+struct Checkpoint {
+    dir: PathBuf,
+    stages: Vec<(String, String)>,
+} // end of struct Checkpoint
+
+impl Checkpoint {
+    // creates dir if necessary. If resume, completed stages are read from dir, else they are reset.
+    fn open(dir: &Path, resume: bool) -> Result<Self, anyhow::Error> {
+        std::fs::create_dir_all(dir)?;
+        let path = dir.join(CKPT_STAGES);
+        let mut stages = Vec::<(String, String)>::new();
+        if resume && path.exists() {
+            for line in BufReader::new(OpenOptions::new().read(true).open(&path)?).lines() {
+                let line = line?;
+                let mut fields = line.splitn(2, ' ');
+                match (fields.next(), fields.next()) {
+                    (Some(stage), Some(value)) => stages.push((stage.to_string(), value.to_string())),
+                    _ => return Err(anyhow!("bad line in checkpoint stages file : {}", line)),
+                }
+            }
+            log::info!("resuming from checkpoint {}, completed stages : {:?}", dir.display(), stages);
+        } else {
+            std::fs::write(&path, "")?;
+            // an optimizer state left by another run must not be resumed
+            let optimizer = dir.join(CKPT_OPTIMIZER);
+            if optimizer.exists() {
+                std::fs::remove_file(optimizer)?;
+            }
+        }
+        Ok(Checkpoint { dir: dir.to_path_buf(), stages })
+    }
+
+    // returns value recorded for stage if it was completed
+    fn get(&self, stage: &str) -> Option<&str> {
+        self.stages.iter().find(|(s, _)| s == stage).map(|(_, v)| v.as_str())
+    }
+
+    fn get_path(&self, name: &str) -> PathBuf {
+        self.dir.join(name)
+    }
+
+    // records stage as completed, the stages file is flushed so that an interruption keeps it
+    fn complete(&mut self, stage: &str, value: &str) -> Result<(), anyhow::Error> {
+        let mut file = OpenOptions::new().append(true).open(self.dir.join(CKPT_STAGES))?;
+        writeln!(file, "{} {}", stage, value)?;
+        file.sync_all()?;
+        self.stages.push((stage.to_string(), value.to_string()));
+        log::info!("checkpoint, stage {} completed", stage);
+        Ok(())
+    }
+} // end of impl Checkpoint
+
+// hnsw (reloaded or built and dumped) then graph extraction and dump
+fn get_kgraph_checkpointed<Dist>(
+    data_with_id: &Vec<(&Vec<f64>, usize)>,
+    hnswparams: &HnswParams,
+    nb_layer: usize,
+    dist: Dist,
+    ckpt: &mut Checkpoint,
+) -> Result<KGraph<f64>, anyhow::Error>
+where
+    Dist: Distance<f64> + Send + Sync,
+{
+    let hnswio;
+    let hnsw = match ckpt.get("hnsw") {
+        Some(basename) => {
+            log::info!("reloading hnsw from checkpoint");
+            hnswio = HnswIo::new(&ckpt.dir, basename);
+            hnswio.load_hnsw_with_dist::<f64, Dist>(dist)?
+        }
+        None => {
+            let mut hnsw = Hnsw::<f64, Dist>::new(hnswparams.max_conn, data_with_id.len(), nb_layer, hnswparams.ef_c, dist);
+            insert_hnsw(data_with_id, &mut hnsw)?;
+            let basename = hnsw.file_dump(&ckpt.dir, CKPT_HNSW)?;
+            ckpt.complete("hnsw", &basename)?;
+            hnsw
+        }
+    };
+    hnsw.dump_layer_info();
+    let kgraph = kgraph_from_hnsw_all(&hnsw, hnswparams.knbn)?;
+    kgraph.dump(&ckpt.get_path(CKPT_KGRAPH))?;
+    ckpt.complete("kgraph", CKPT_KGRAPH)?;
+    Ok(kgraph)
+} // end of get_kgraph_checkpointed
+
+// gradient optimization checkpointed by the embedder after each gradient batch, resumed if a checkpoint exists.
+// returns layout reindexed by DataId
+fn embed_checkpointed(
+    kgraph: &KGraph<f64>,
+    embedparams: EmbedderParams,
+    ckpt: &mut Checkpoint,
+    uncertainty: bool,
+    dumps: &EmbedderDumps,
+) -> Result<(Array2<f64>, Option<f64>), anyhow::Error> {
+    let optimizer_path = ckpt.get_path(CKPT_OPTIMIZER);
+    let mut embedder = Embedder::new(kgraph, embedparams);
+    embedder.set_checkpoint(&optimizer_path, 1);
+    if optimizer_path.exists() {
+        // the embedder resumes with the parameters of the checkpoint, they must be those asked for
+        let mut ckpt_params = Embedder::<f64>::get_checkpoint_parameters(&optimizer_path)?;
+        ckpt_params.num_threads = embedparams.num_threads;
+        if ckpt_params != embedparams {
+            log::error!("embedding parameters differ from those of the interrupted run");
+            ckpt_params.log();
+            return Err(anyhow!("embedding parameters differ from those of the interrupted run"));
+        }
+        log::info!("resuming gradient optimization from checkpoint");
+        embedder.resume_from(&optimizer_path)?;
+    } else {
+        embedder.embed()?;
+    }
+    dumps.dump(&embedder);
+    Ok((get_output(&embedder, uncertainty), embedder.get_final_loss()))
+} // end of embed_checkpointed
 
 /// Defines parameters to drive ann computations. See the crate [hnsw_rs](https://crates.io/crates/hnsw_rs)
 #[derive(Debug, Clone)]
@@ -46,8 +282,10 @@ pub struct HnswParams {
     ef_c: usize,
     /// number of neighbours asked for
     knbn: usize,
-    /// distance to use in Hnsw. Default is "DistL2". Other choices are "DistL1", "DistCosine", DistJeffreys
+    /// distance to use in Hnsw. Default is "DistL2". Other choices are "DistL1", "DistCosine", DistJeffreys, DistPlugin
     distance: String,
+    /// dynamic library and symbol of the distance if distance is DistPlugin
+    plugin: Option<(String, String)>,
 } // end of struct HnswParams
 
 impl HnswParams {
@@ -57,6 +295,7 @@ impl HnswParams {
             ef_c: 400,
             knbn: 10,
             distance: String::from("DistL2"),
+            plugin: None,
         }
     }
 
@@ -67,6 +306,7 @@ impl HnswParams {
             ef_c,
             knbn,
             distance,
+            plugin: None,
         }
     }
 } // end impl block
@@ -95,6 +335,20 @@ fn parse_hnsw_cmd(matches: &ArgMatches) -> Result<HnswParams, anyhow::Error> {
             "DistJeffreys" => {
                 hnswparams.distance = String::from("DistJeffreys");
             }
+            "DistDot" => {
+                if matches.get_one::<String>("sparse").is_none() {
+                    return Err(anyhow!("DistDot is only available with --sparse"));
+                }
+                hnswparams.distance = String::from("DistDot");
+            }
+            "DistPlugin" => {
+                hnswparams.distance = String::from("DistPlugin");
+                let lib = matches
+                    .get_one::<String>("distlib")
+                    .ok_or(anyhow!("DistPlugin needs a library, use --distlib"))?;
+                let symbol = matches.get_one::<String>("distsym").unwrap();
+                hnswparams.plugin = Some((lib.clone(), symbol.clone()));
+            }
             _ => {
                 return Err(anyhow!("not a valid distance"));
             }
@@ -113,8 +367,15 @@ fn parse_embed_cmd(matches: &ArgMatches) -> Result<EmbedderParams, anyhow::Error
     //
     let mut embedparams = EmbedderParams::default();
     embedparams.scale_rho = *matches.get_one::<f64>("scale").unwrap();
+    embedparams.auto_scale_rho = matches.get_flag("autoscale");
     embedparams.nb_sampling_by_edge = *matches.get_one::<usize>("nbsample").unwrap();
     embedparams.hierarchy_layer = *matches.get_one::<usize>("hierarchy").unwrap();
+    if let Some(min_dist) = matches.get_one::<f64>("mindist") {
+        let spread = *matches.get_one::<f64>("spread").unwrap();
+        embedparams
+            .set_min_dist_spread(*min_dist, spread)
+            .map_err(|e| anyhow!("{}", e))?;
+    }
     //
     return Ok(embedparams);
 } // end of parse_embed_cmd
@@ -127,22 +388,35 @@ fn get_kgraph<Dist>(
     hnswparams: &HnswParams,
     nb_layer: usize,
     hubdim_asked: bool,
+    dist: Dist,
+    ckpt: Option<&mut Checkpoint>,
 ) -> KGraph<f64>
 where
-    Dist: Distance<f64> + Default + Send + Sync,
+    Dist: Distance<f64> + Send + Sync,
 {
     //
-    let nb_data = data_with_id.len();
-    let hnsw = Hnsw::<f64, Dist>::new(
-        hnswparams.max_conn,
-        nb_data,
-        nb_layer,
-        hnswparams.ef_c,
-        Dist::default(),
-    );
-    hnsw.parallel_insert(&data_with_id);
-    hnsw.dump_layer_info();
-    let kgraph = kgraph_from_hnsw_all(&hnsw, hnswparams.knbn).unwrap();
+    let kgraph = match ckpt {
+        Some(ckpt) => match get_kgraph_checkpointed(data_with_id, hnswparams, nb_layer, dist, ckpt) {
+            Ok(kgraph) => kgraph,
+            Err(e) => {
+                log::error!("checkpointed graph construction failed : {}", e);
+                std::process::exit(1);
+            }
+        },
+        None => {
+            let nb_data = data_with_id.len();
+            let mut hnsw = Hnsw::<f64, Dist>::new(
+                hnswparams.max_conn,
+                nb_data,
+                nb_layer,
+                hnswparams.ef_c,
+                dist,
+            );
+            insert_hnsw(data_with_id, &mut hnsw).unwrap();
+            hnsw.dump_layer_info();
+            kgraph_from_hnsw_all(&hnsw, hnswparams.knbn).unwrap()
+        }
+    };
     if hubdim_asked {
         // hubness and intrinsic dimension.
         log::info!("minimum number of neighbours {}", kgraph.get_max_nbng());
@@ -190,56 +464,74 @@ fn get_kgraph_projection<Dist>(
     hnswparams: &HnswParams,
     nb_layer: usize,
     layer_proj: usize,
+    dist: Dist,
 ) -> KGraphProjection<f64>
 where
-    Dist: Distance<f64> + Default + Send + Sync,
+    Dist: Distance<f64> + Send + Sync,
 {
     //
     let nb_data = data_with_id.len();
-    let hnsw = Hnsw::<f64, Dist>::new(
+    let mut hnsw = Hnsw::<f64, Dist>::new(
         hnswparams.max_conn,
         nb_data,
         nb_layer,
         hnswparams.ef_c,
-        Dist::default(),
+        dist,
     );
-    hnsw.parallel_insert(&data_with_id);
+    insert_hnsw(data_with_id, &mut hnsw).unwrap();
     hnsw.dump_layer_info();
-    let graphprojection = KGraphProjection::<f64>::new(&hnsw, hnswparams.knbn, layer_proj);
+    let graphprojection = KGraphProjection::<f64>::new(&hnsw, hnswparams.knbn, layer_proj).unwrap();
     graphprojection
 } // end of get_kgraph_projection
 
 //
 
+// loads the distance of a plugin, exits if it fails
+fn get_plugin_distance(hnswparams: &HnswParams) -> DistFn<f64> {
+    let (lib, symbol) = hnswparams.plugin.as_ref().unwrap();
+    match load_distance_plugin(std::path::Path::new(lib), symbol) {
+        Ok(dist) => dist,
+        Err(e) => {
+            log::error!("could not load distance plugin : {}", e);
+            std::process::exit(1);
+        }
+    }
+} // end of get_plugin_distance
+
 // dispatching according to distance ... use a macro
 fn get_kgraph_with_distname(
     data_with_id: &Vec<(&Vec<f64>, usize)>,
     hnswparams: &HnswParams,
     nb_layer: usize,
     hubdim: bool,
+    ckpt: Option<&mut Checkpoint>,
 ) -> KGraph<f64> {
     let kgraph = match hnswparams.distance.as_str() {
         "DistL2" => {
-            let kgraph = get_kgraph::<DistL2>(&data_with_id, &hnswparams, nb_layer, hubdim);
+            let kgraph = get_kgraph(&data_with_id, &hnswparams, nb_layer, hubdim, DistL2 {}, ckpt);
             kgraph
         }
         "DistL1" => {
-            let kgraph = get_kgraph::<DistL1>(&data_with_id, &hnswparams, nb_layer, hubdim);
+            let kgraph = get_kgraph(&data_with_id, &hnswparams, nb_layer, hubdim, DistL1 {}, ckpt);
             kgraph
         }
         "DistJeffreys" => {
-            let kgraph = get_kgraph::<DistJeffreys>(&data_with_id, &hnswparams, nb_layer, hubdim);
+            let kgraph = get_kgraph(&data_with_id, &hnswparams, nb_layer, hubdim, DistJeffreys {}, ckpt);
             kgraph
         }
         "DistCosine" => {
-            let kgraph = get_kgraph::<DistCosine>(&data_with_id, &hnswparams, nb_layer, hubdim);
+            let kgraph = get_kgraph(&data_with_id, &hnswparams, nb_layer, hubdim, DistCosine {}, ckpt);
             kgraph
         }
         "DistJensenShannon" => {
             let kgraph =
-                get_kgraph::<DistJensenShannon>(&data_with_id, &hnswparams, nb_layer, hubdim);
+                get_kgraph(&data_with_id, &hnswparams, nb_layer, hubdim, DistJensenShannon {}, ckpt);
             kgraph
         }
+        "DistPlugin" => {
+            let dist = get_plugin_distance(hnswparams);
+            get_kgraph(&data_with_id, &hnswparams, nb_layer, hubdim, dist, ckpt)
+        }
         _ => {
             log::error!("unknown distance : {}", hnswparams.distance);
             std::process::exit(1);
@@ -258,32 +550,26 @@ fn get_kgraphproj_with_distname(
     let kgraph_projection = match hnswparams.distance.as_str() {
         "DistL2" => {
             let kgraph =
-                get_kgraph_projection::<DistL2>(&data_with_id, &hnswparams, nb_layer, layer_proj);
+                get_kgraph_projection(&data_with_id, &hnswparams, nb_layer, layer_proj, DistL2 {});
             kgraph
         }
         "DistL1" => {
             let kgraph =
-                get_kgraph_projection::<DistL1>(&data_with_id, &hnswparams, nb_layer, layer_proj);
+                get_kgraph_projection(&data_with_id, &hnswparams, nb_layer, layer_proj, DistL1 {});
             kgraph
         }
         "DistJeffreys" => {
-            let kgraph = get_kgraph_projection::<DistJeffreys>(
-                &data_with_id,
-                &hnswparams,
-                nb_layer,
-                layer_proj,
-            );
+            let kgraph = get_kgraph_projection(&data_with_id, &hnswparams, nb_layer, layer_proj, DistJeffreys {});
             kgraph
         }
         "DistCosine" => {
-            let kgraph = get_kgraph_projection::<DistCosine>(
-                &data_with_id,
-                &hnswparams,
-                nb_layer,
-                layer_proj,
-            );
+            let kgraph = get_kgraph_projection(&data_with_id, &hnswparams, nb_layer, layer_proj, DistCosine {});
             kgraph
         }
+        "DistPlugin" => {
+            let dist = get_plugin_distance(hnswparams);
+            get_kgraph_projection(&data_with_id, &hnswparams, nb_layer, layer_proj, dist)
+        }
         _ => {
             log::error!("unknown distance : {}", hnswparams.distance);
             std::process::exit(1);
@@ -292,13 +578,401 @@ fn get_kgraphproj_with_distname(
     kgraph_projection
 } // end of get_kgraphproj_with_distname
 
+// embedding reindexed by DataId, with the uncertainty of positions as last column if asked for
+fn get_output(embedder: &Embedder<f64>, uncertainty: bool) -> Array2<f64> {
+    let embedded = embedder.get_embedded_reindexed();
+    if !uncertainty {
+        return embedded;
+    }
+    match embedder.get_local_uncertainty_reindexed() {
+        Some(u) => ndarray::concatenate![ndarray::Axis(1), embedded, u.insert_axis(ndarray::Axis(1))],
+        None => {
+            log::error!("could not compute uncertainty of embedded positions");
+            embedded
+        }
+    }
+} // end of get_output
+
+// dumps the embedding in safetensors format if asked for
+fn dump_safetensors(file: Option<&String>, embedder: &Embedder<f64>) {
+    let Some(file) = file else {
+        return;
+    };
+    match embedder.dump_safetensors(Path::new(file)) {
+        Ok(()) => log::info!("embedding dumped in safetensors file {}", file),
+        Err(e) => log::error!("could not dump embedding in safetensors file {} : {}", file, e),
+    }
+} // end of dump_safetensors
+
+// dumps an interactive html plot of the embedding if asked for
+fn dump_html(file: Option<&String>, labels: Option<&DataLabels<String>>, embedder: &Embedder<f64>) {
+    let Some(file) = file else {
+        return;
+    };
+    match embedder.dump_html(Path::new(file), labels) {
+        Ok(()) => log::info!("html plot written in {}", file),
+        Err(e) => log::error!("could not write html plot in {} : {}", file, e),
+    }
+} // end of dump_html
+
+// optional dumps of the Embedder besides the csv output
+struct EmbedderDumps<'a> {
+    safetensors: Option<&'a String>,
+    html: Option<&'a String>,
+    /// labels of rows for the outputs and the html plot, line i of the labels file being attached to DataId i
+    labels: Option<DataLabels<String>>,
+}
+
+impl<'a> EmbedderDumps<'a> {
+    fn from_matches(matches: &'a ArgMatches) -> Self {
+        let labels = matches.get_one::<String>("labels").map(|file| {
+            let lines: Result<Vec<String>, std::io::Error> = OpenOptions::new()
+                .read(true)
+                .open(file)
+                .and_then(|f| BufReader::new(f).lines().collect());
+            match lines {
+                Ok(lines) => DataLabels::from_vec(lines),
+                Err(e) => {
+                    log::error!("could not read labels file {} : {}", file, e);
+                    std::process::exit(1);
+                }
+            }
+        });
+        EmbedderDumps {
+            safetensors: matches.get_one::<String>("safetensors"),
+            html: matches.get_one::<String>("html"),
+            labels,
+        }
+    }
+
+    fn dump(&self, embedder: &Embedder<f64>) {
+        dump_safetensors(self.safetensors, embedder);
+        dump_html(self.html, self.labels.as_ref(), embedder);
+    }
+} // end of impl EmbedderDumps
+
+// checkpointed embedding of kgraph, dumped in csv_output. Exits on error.
+// returns the final loss of the embedding
+fn write_checkpointed_embedding(
+    kgraph: &KGraph<f64>,
+    embedparams: EmbedderParams,
+    ckpt: &mut Checkpoint,
+    csv_output: &str,
+    uncertainty: bool,
+    dumps: &EmbedderDumps,
+) -> Option<f64> {
+    let (embedded, final_loss) = match embed_checkpointed(kgraph, embedparams, ckpt, uncertainty, dumps) {
+        Ok(res) => res,
+        Err(e) => {
+            log::error!("checkpointed embedding failed : {}", e);
+            std::process::exit(1);
+        }
+    };
+    write_embedding(csv_output, &embedded, dumps.labels.as_ref());
+    if let Err(e) = ckpt.complete("embedded", csv_output) {
+        log::error!("could not record completion in checkpoint : {}", e);
+    }
+    final_loss
+} // end of write_checkpointed_embedding
+
+// service mode : embeds new csv files of watch_dir, or places their rows in a reference embedding. Runs until killed.
+fn run_watch(
+    watch_dir: &Path,
+    watchout: Option<&String>,
+    reference: Option<&String>,
+    hnswparams: &HnswParams,
+    embedparams: EmbedderParams,
+    delim: u8,
+) {
+    let mode = match reference {
+        Some(file) => {
+            let transformer = ReferenceArtifact::reload(Path::new(file)).and_then(ReferenceTransformer::new);
+            match transformer {
+                Ok(transformer) => WatchMode::Transform(transformer),
+                Err(e) => {
+                    log::error!("could not load reference artifact {} : {}", file, e);
+                    std::process::exit(1);
+                }
+            }
+        }
+        None => {
+            if hnswparams.distance != "DistL2" {
+                log::warn!("watch mode embeds with DistL2, distance {} ignored", hnswparams.distance);
+            }
+            WatchMode::Embed(EmbedParams {
+                hnsw: annembed::pipeline::HnswParams {
+                    max_nb_connection: hnswparams.max_conn,
+                    ef_construction: hnswparams.ef_c,
+                    ..Default::default()
+                },
+                graph: annembed::pipeline::GraphParams {
+                    knbn: Some(hnswparams.knbn),
+                    ..Default::default()
+                },
+                method: annembed::pipeline::EmbeddingMethod::Embedder(embedparams),
+                ..Default::default()
+            })
+        }
+    };
+    let output_dir = watchout.map_or(watch_dir.join("embedded"), PathBuf::from);
+    let mut watcher = DirectoryWatcher::new(watch_dir, &output_dir);
+    watcher.set_delimiter(delim);
+    println!("watching {}, embeddings written in {}", watch_dir.display(), output_dir.display());
+    let stop = std::sync::atomic::AtomicBool::new(false);
+    if let Err(e) = watcher.run(&mode, &stop) {
+        log::error!("watch mode failed : {}", e);
+        std::process::exit(1);
+    }
+} // end of run_watch
+
+// graph of the rows of a sparse matrix, row i having DataId i
+fn get_sparse_kgraph<D>(csr: &CsMat<f32>, hnswparams: &HnswParams, distance: D) -> Result<KGraph<f64>, AnnembedError>
+where
+    D: Distance<SparseEntry> + Send + Sync,
+{
+    let nb_data = csr.rows();
+    let nb_layer = 16.min((nb_data as f32).ln().trunc() as usize).max(1);
+    let mut hnsw = Hnsw::<SparseEntry, D>::new(hnswparams.max_conn, nb_data, nb_layer, hnswparams.ef_c, distance);
+    sparse_insert_hnsw(csr, None, &mut hnsw)?;
+    kgraph_from_hnsw_all(&hnsw, hnswparams.knbn)
+} // end of get_sparse_kgraph
+
+// embeds rows of a sparse matrix with the cosine (or dot, or L2) distance, rows are written in matrix order preceded by their id if given
+fn run_sparse(
+    sparse_file: &str,
+    matches: &ArgMatches,
+    hnswparams: &HnswParams,
+    embedparams: EmbedderParams,
+    csv_output: &str,
+    uncertainty: bool,
+    monitor: &mut ResourceMonitor,
+) {
+    let ids_file = matches.get_one::<String>("ids").map(Path::new);
+    let vocabulary_file = matches.get_one::<String>("vocabulary").map(Path::new);
+    let mut text_data = match SparseTextData::load(Path::new(sparse_file), ids_file, vocabulary_file) {
+        Ok(text_data) => text_data,
+        Err(e) => {
+            log::error!("could not load sparse data from {} : {}", sparse_file, e);
+            std::process::exit(1);
+        }
+    };
+    if matches.get_flag("tfidf") {
+        text_data.apply_tfidf().unwrap();
+    }
+    if !["DistL2", "DistCosine", "DistDot"].contains(&hnswparams.distance.as_str()) {
+        log::warn!("sparse data are embedded with the cosine distance, distance {} ignored", hnswparams.distance);
+    }
+    monitor.end_stage("load");
+    let csr = text_data.get_matrix();
+    let nb_data = csr.rows();
+    println!("sparse matrix nb rows : {}, nb columns : {}, nnz : {}", nb_data, csr.cols(), csr.nnz());
+    let kgraph_res = match hnswparams.distance.as_str() {
+        "DistDot" => normalize_rows(csr).and_then(|normalized| get_sparse_kgraph(&normalized, hnswparams, DistSparseDot)),
+        "DistL2" => get_sparse_kgraph(csr, hnswparams, DistSparseL2),
+        _ => get_sparse_kgraph(csr, hnswparams, DistSparseCosine),
+    };
+    let kgraph = match kgraph_res {
+        Ok(kgraph) => kgraph,
+        Err(e) => {
+            log::error!("could not build the graph of sparse rows : {}", e);
+            std::process::exit(1);
+        }
+    };
+    monitor.end_stage("graph");
+    let mut embedder = Embedder::new(&kgraph, embedparams);
+    if embedder.embed().is_err() {
+        log::error!("embedding failed");
+        std::process::exit(1);
+    }
+    // rows were inserted with their rank as DataId
+    let embedded = get_output(&embedder, uncertainty);
+    let dumps = EmbedderDumps::from_matches(matches);
+    dumps.dump(&embedder);
+    // labels given by --labels, else the ids of the rows if the file has some
+    let row_ids = text_data.get_row_ids().map(|ids| DataLabels::from_vec(ids.clone()));
+    write_embedding(csv_output, &embedded, dumps.labels.as_ref().or(row_ids.as_ref()));
+    if text_data.get_vocabulary().is_some() {
+        for i in 0..nb_data.min(5) {
+            println!("row {} main terms : {:?}", i, text_data.get_top_terms(i, 5));
+        }
+    }
+    dump_report(matches.get_one::<String>("report"), monitor, &kgraph, embedder.get_final_loss());
+    dump_clusters(matches.get_one::<String>("clusters"), matches.get_one::<usize>("nbclusters").copied(), &kgraph);
+} // end of run_sparse
+
+// dumps data and its embedding (rows ordered as data) as a reference artifact, with the name of the distance
+fn save_reference(file: &str, data: &[Vec<f64>], embedded: &Array2<f64>, hnswparams: &HnswParams) {
+    let dim = data.first().map_or(0, |v| v.len());
+    let rows = Array2::from_shape_fn((data.len(), dim), |(i, j)| data[i][j] as f32);
+    if hnswparams.distance != REFERENCE_DISTANCE {
+        log::warn!("reference artifact of distance {} cannot be used by --reference (only {})", hnswparams.distance, REFERENCE_DISTANCE);
+    }
+    let artifact = ReferenceArtifact::new(
+        rows,
+        (0..data.len()).collect(),
+        embedded.mapv(|x| x as f32),
+        hnswparams.knbn,
+        &hnswparams.distance,
+    );
+    match artifact.and_then(|artifact| artifact.dump(Path::new(file))) {
+        Ok(()) => log::info!("reference artifact dumped in {}", file),
+        Err(e) => log::error!("could not dump reference artifact in {} : {}", file, e),
+    }
+} // end of save_reference
+
+// clusters the graph by spectral rotation of its diffusion eigenvectors and dumps the label of each DataId if asked for
+fn dump_clusters(clusters_file: Option<&String>, nb_clusters: Option<usize>, kgraph: &KGraph<f64>) {
+    let Some(clusters_file) = clusters_file else {
+        return;
+    };
+    if let Some(nb) = nb_clusters.filter(|nb| *nb < 2) {
+        log::error!("spectral rotation clustering needs at least 2 clusters, got {}", nb);
+        return;
+    }
+    // dmaps needs at least 2 coordinates
+    let max_clusters = nb_clusters.unwrap_or(MAX_ROTATION_CLUSTERS);
+    let mut dmaps = DiffusionMaps::new(DiffusionParams::new(max_clusters.max(3) - 1, None));
+    let res = dmaps
+        .embed_kgraph_typed::<f64, f64>(kgraph)
+        .and_then(|embedded| dmaps.cluster_by_rotation(&embedded, nb_clusters));
+    let clusters = match res {
+        Ok(clusters) => clusters,
+        Err(e) => {
+            log::error!("spectral rotation clustering failed : {}", e);
+            return;
+        }
+    };
+    println!("spectral rotation : {} clusters, eigengap : {:?}", clusters.get_nb_clusters(), clusters.get_eigengap());
+    let data_ids = dmaps.get_data_ids().unwrap();
+    let res = csv::Writer::from_path(clusters_file).map_err(anyhow::Error::from).and_then(|mut csv_w| {
+        for (rank, label) in clusters.get_labels().iter().enumerate() {
+            csv_w.write_record(&[data_ids[rank].to_string(), label.to_string()])?;
+        }
+        csv_w.flush()?;
+        Ok(())
+    });
+    match res {
+        Ok(()) => log::info!("clusters written in {}", clusters_file),
+        Err(e) => log::error!("could not write clusters in {} : {}", clusters_file, e),
+    }
+} // end of dump_clusters
+
+// npy input and output go through ndarray-npy, see feature npy
+#[cfg(feature = "npy")]
+fn read_npy_data(path: &Path) -> Result<Vec<Vec<f64>>, AnnembedError> {
+    read_npy_to_array2::<f64>(path).map(|mat| mat.rows().into_iter().map(|r| r.to_vec()).collect())
+}
+
+#[cfg(not(feature = "npy"))]
+fn read_npy_data(_path: &Path) -> Result<Vec<Vec<f64>>, AnnembedError> {
+    Err(AnnembedError::InvalidParameter(String::from("reading npy files needs the npy feature")))
+}
+
+#[cfg(feature = "npy")]
+fn write_npy_embedding(path: &Path, embedded: &Array2<f64>) -> Result<(), AnnembedError> {
+    write_array2_to_npy(path, embedded)
+}
+
+#[cfg(not(feature = "npy"))]
+fn write_npy_embedding(_path: &Path, _embedded: &Array2<f64>) -> Result<(), AnnembedError> {
+    Err(AnnembedError::InvalidParameter(String::from("writing npy files needs the npy feature")))
+}
+
+// writes the embedding, rows ordered by DataId, in numpy format if the output name ends with .npy, else in csv.
+// With labels the format is given by write_labeled_embedding. Exits on error.
+fn write_embedding(output: &str, embedded: &Array2<f64>, labels: Option<&DataLabels<String>>) {
+    let res = if let Some(labels) = labels {
+        let data_ids: Vec<DataId> = (0..embedded.nrows()).collect();
+        write_labeled_embedding(Path::new(output), labels, &data_ids, embedded).map_err(anyhow::Error::from)
+    } else if output.ends_with(".npy") {
+        write_npy_embedding(Path::new(output), embedded).map_err(anyhow::Error::from)
+    } else {
+        csv::Writer::from_path(output)
+            .map_err(anyhow::Error::from)
+            .and_then(|mut csv_w| write_csv_array2(&mut csv_w, embedded).map_err(anyhow::Error::from))
+            .map(|_| ())
+    };
+    match res {
+        Ok(()) => log::info!("embedding written in {}", output),
+        Err(e) => {
+            log::error!("could not write embedding in {} : {}", output, e);
+            std::process::exit(1);
+        }
+    }
+} // end of write_embedding
+
+// embeds the graph with diffusion maps, rows ordered by DataId, and returns quality indicators. Exits on error.
+fn dmap_embedding(kgraph: &KGraph<f64>, dim: usize) -> (Array2<f64>, Vec<(&'static str, Option<f64>)>) {
+    let mut dmaps = DiffusionMaps::new(DiffusionParams::new(dim, None));
+    let embedded = match dmaps.embed_kgraph::<f64>(kgraph) {
+        Ok(embedded) => embedded,
+        Err(e) => {
+            log::error!("diffusion maps embedding failed : {}", e);
+            std::process::exit(1);
+        }
+    };
+    let data_ids = dmaps.get_data_ids().unwrap();
+    let mut order: Vec<usize> = (0..data_ids.len()).collect();
+    order.sort_unstable_by_key(|i| data_ids[*i]);
+    let quality = vec![
+        ("spectral_gap", dmaps.get_spectral_gap()),
+        ("diffusion_time", dmaps.get_diffusion_time()),
+        ("dimension", dmaps.get_embedding_dim().map(|d| d as f64)),
+    ];
+    (embedded.select(ndarray::Axis(0), &order), quality)
+} // end of dmap_embedding
+
+// quality indicators of a gradient embedding, the edge length estimate uses knbn neighbours
+fn embedder_quality(embedder: &Embedder<f64>, knbn: usize) -> Vec<(&'static str, Option<f64>)> {
+    vec![
+        ("final_loss", embedder.get_final_loss()),
+        ("edge_length_quality", embedder.get_quality_estimate_from_edge_length(knbn)),
+    ]
+} // end of embedder_quality
+
+// dumps quality indicators in csv (metric, value) if asked for, missing values are written as NaN
+fn dump_quality(quality_file: Option<&String>, quality: &[(&str, Option<f64>)]) {
+    let Some(quality_file) = quality_file else {
+        return;
+    };
+    let res = csv::Writer::from_path(quality_file).map_err(anyhow::Error::from).and_then(|mut csv_w| {
+        csv_w.write_record(["metric", "value"])?;
+        for (metric, value) in quality {
+            csv_w.write_record(&[metric.to_string(), format!("{:.5e}", value.unwrap_or(f64::NAN))])?;
+        }
+        csv_w.flush()?;
+        Ok(())
+    });
+    match res {
+        Ok(()) => log::info!("quality indicators written in {}", quality_file),
+        Err(e) => log::error!("could not write quality indicators in {} : {}", quality_file, e),
+    }
+} // end of dump_quality
+
+// ends the embedding stage and dumps the run report in json if asked for
+fn dump_report(report_file: Option<&String>, monitor: &mut ResourceMonitor, kgraph: &KGraph<f64>, final_loss: Option<f64>) {
+    monitor.end_stage("embedding");
+    let Some(report_file) = report_file else {
+        return;
+    };
+    let mut report = monitor.get_report(kgraph.get_nb_nodes());
+    report.graph = Some(GraphReport::from_kgraph(kgraph));
+    report.final_loss = final_loss;
+    match report.dump_json(Path::new(report_file)) {
+        Ok(()) => log::info!("run report written in {}", report_file),
+        Err(e) => log::error!("could not write run report in {} : {}", report_file, e),
+    }
+} // end of dump_report
+
 pub fn main() {
     println!("initializing default logger from environment ...");
     let _ = env_logger::Builder::from_default_env().init();
     log::info!("logger initialized from default environment");
+    set_progress_callback(print_stage_progress);
+    let mut monitor = ResourceMonitor::new();
     //
     let hnswparams: HnswParams;
-    let embedparams: EmbedderParams;
+    let mut embedparams: EmbedderParams;
     //
     let embedcmd = Command::new("embed")
         .arg(
@@ -334,6 +1008,30 @@ pub fn main() {
                 .value_parser(clap::value_parser!(f64))
                 .default_value("1.0 f64")
                 .help("spatial scale factor"),
+        )
+        .arg(
+            Arg::new("autoscale")
+                .required(false)
+                .long("autoscale")
+                .action(ArgAction::SetTrue)
+                .help("choose spatial scale factor from data (Berry-Giannakis-Harlim), overrides --scale"),
+        )
+        .arg(
+            Arg::new("mindist")
+                .required(false)
+                .long("mindist")
+                .action(ArgAction::Set)
+                .value_parser(clap::value_parser!(f64))
+                .help("umap min_dist, fits the embedded kernel with --spread"),
+        )
+        .arg(
+            Arg::new("spread")
+                .required(false)
+                .long("spread")
+                .action(ArgAction::Set)
+                .value_parser(clap::value_parser!(f64))
+                .default_value("1.0")
+                .help("umap spread, used with --mindist"),
         );
 
     let hnswcmd = Command::new("hnsw")
@@ -343,7 +1041,20 @@ pub fn main() {
             .required(true)
             .action(ArgAction::Set)
             .value_parser(clap::value_parser!(String))
-            .help("distance is required   \"DistL1\" , \"DistL2\", \"DistCosine\", \"DistJeyffreys\"  "))
+            .help("distance is required   \"DistL1\" , \"DistL2\", \"DistCosine\", \"DistJeyffreys\", \"DistPlugin\", \"DistDot\" (with --sparse)  "))
+        .arg(Arg::new("distlib")
+            .long("distlib")
+            .required(false)
+            .action(ArgAction::Set)
+            .value_parser(clap::value_parser!(String))
+            .help("dynamic library exporting the distance used with DistPlugin"))
+        .arg(Arg::new("distsym")
+            .long("distsym")
+            .required(false)
+            .action(ArgAction::Set)
+            .value_parser(clap::value_parser!(String))
+            .default_value(DEFAULT_PLUGIN_SYMBOL)
+            .help("name of the distance exported by the library"))
         .arg(Arg::new("nb_conn")
             .long("nbconn")
             .required(true)
@@ -375,9 +1086,180 @@ pub fn main() {
                 .long("csv")
                 .action(ArgAction::Set)
                 .value_parser(clap::value_parser!(String))
-                .required(true)
+                .required_unless_present_any(["kgraphfile", "watch", "sparse", "npyfile"])
                 .help("expecting a csv file"),
         )
+        .arg(
+            Arg::new("npyfile")
+                .long("npy")
+                .action(ArgAction::Set)
+                .value_parser(clap::value_parser!(String))
+                .conflicts_with_all(["csvfile", "kgraphfile", "sparse", "watch"])
+                .help("expecting a npy file (2 dimensional array of f4 or f8)"),
+        )
+        .arg(
+            Arg::new("dmap")
+                .long("dmap")
+                .action(ArgAction::SetTrue)
+                .conflicts_with_all(["checkpoint", "watch", "sparse", "uncertainty", "safetensors", "html"])
+                .help("embed with diffusion maps instead of the gradient embedder"),
+        )
+        .arg(
+            Arg::new("quality")
+                .long("quality")
+                .required(false)
+                .action(ArgAction::Set)
+                .value_parser(clap::value_parser!(String))
+                .help("csv file where quality indicators of the embedding are written"),
+        )
+        .arg(
+            Arg::new("kgraphfile")
+                .long("kgraph")
+                .action(ArgAction::Set)
+                .value_parser(clap::value_parser!(String))
+                .conflicts_with("csvfile")
+                .help("expecting a graph file dumped with --dumpgraph"),
+        )
+        .arg(
+            Arg::new("sparse")
+                .long("sparse")
+                .action(ArgAction::Set)
+                .value_parser(clap::value_parser!(String))
+                .conflicts_with_all(["csvfile", "kgraphfile"])
+                .help("expecting a sparse matrix in MatrixMarket format, embedded with the cosine distance (or DistDot)"),
+        )
+        .arg(
+            Arg::new("tfidf")
+                .long("tfidf")
+                .action(ArgAction::SetTrue)
+                .requires("sparse")
+                .help("transform counts of the sparse matrix into TF-IDF weights"),
+        )
+        .arg(
+            Arg::new("ids")
+                .long("ids")
+                .action(ArgAction::Set)
+                .value_parser(clap::value_parser!(String))
+                .requires("sparse")
+                .help("file with the id of each row of the sparse matrix, one by line"),
+        )
+        .arg(
+            Arg::new("vocabulary")
+                .long("vocabulary")
+                .action(ArgAction::Set)
+                .value_parser(clap::value_parser!(String))
+                .requires("sparse")
+                .help("file with the term of each column of the sparse matrix, one by line"),
+        )
+        .arg(
+            Arg::new("watch")
+                .long("watch")
+                .action(ArgAction::Set)
+                .value_parser(clap::value_parser!(String))
+                .conflicts_with_all(["csvfile", "kgraphfile"])
+                .help("directory polled for new csv files to embed"),
+        )
+        .arg(
+            Arg::new("watchout")
+                .long("watchout")
+                .action(ArgAction::Set)
+                .value_parser(clap::value_parser!(String))
+                .requires("watch")
+                .help("directory where embeddings of watched files are written"),
+        )
+        .arg(
+            Arg::new("reference")
+                .long("reference")
+                .action(ArgAction::Set)
+                .value_parser(clap::value_parser!(String))
+                .requires("watch")
+                .help("reference artifact (dumped with --savereference) in which rows of watched files are placed"),
+        )
+        .arg(
+            Arg::new("savereference")
+                .long("savereference")
+                .action(ArgAction::Set)
+                .value_parser(clap::value_parser!(String))
+                .help("file where data and its embedding are dumped as a reference artifact"),
+        )
+        .arg(
+            Arg::new("dumpgraph")
+                .long("dumpgraph")
+                .required(false)
+                .action(ArgAction::Set)
+                .value_parser(clap::value_parser!(String))
+                .help("expecting a file name to dump the graph"),
+        )
+        .arg(
+            Arg::new("checkpoint")
+                .long("checkpoint")
+                .required(false)
+                .action(ArgAction::Set)
+                .value_parser(clap::value_parser!(String))
+                .help("directory where stage checkpoints are written"),
+        )
+        .arg(
+            Arg::new("resume")
+                .long("resume")
+                .action(ArgAction::SetTrue)
+                .requires("checkpoint")
+                .help("resume an interrupted run from its last completed checkpointed stage"),
+        )
+        .arg(
+            Arg::new("uncertainty")
+                .long("uncertainty")
+                .action(ArgAction::SetTrue)
+                .help("add the uncertainty of each embedded position as last column"),
+        )
+        .arg(
+            Arg::new("safetensors")
+                .long("safetensors")
+                .required(false)
+                .action(ArgAction::Set)
+                .value_parser(clap::value_parser!(String))
+                .help("file where the embedding is also dumped in safetensors format"),
+        )
+        .arg(
+            Arg::new("html")
+                .long("html")
+                .required(false)
+                .action(ArgAction::Set)
+                .value_parser(clap::value_parser!(String))
+                .help("html file where an interactive scatter plot of the embedding is written"),
+        )
+        .arg(
+            Arg::new("labels")
+                .long("labels")
+                .required(false)
+                .action(ArgAction::Set)
+                .value_parser(clap::value_parser!(String))
+                .help("file with the label of each row (one by line), written in the outputs and coloring the html plot"),
+        )
+        .arg(
+            Arg::new("clusters")
+                .long("clusters")
+                .required(false)
+                .action(ArgAction::Set)
+                .value_parser(clap::value_parser!(String))
+                .help("csv file where the cluster of each DataId, by spectral rotation of diffusion eigenvectors, is written"),
+        )
+        .arg(
+            Arg::new("nbclusters")
+                .long("nbclusters")
+                .required(false)
+                .requires("clusters")
+                .action(ArgAction::Set)
+                .value_parser(clap::value_parser!(usize))
+                .help("number of clusters with --clusters, default is given by the largest eigengap"),
+        )
+        .arg(
+            Arg::new("report")
+                .long("report")
+                .required(false)
+                .action(ArgAction::Set)
+                .value_parser(clap::value_parser!(String))
+                .help("json file where the run report is written"),
+        )
         .arg(
             Arg::new("outfile")
                 .long("out")
@@ -439,14 +1321,6 @@ pub fn main() {
     }
     embedparams.log();
 
-    let csv_file = matches.get_one::<String>("csvfile").unwrap();
-    let fname = csv_file.clone();
-    //
-    let delim_opt = matches.get_one::<u8>("delim");
-    let delim = match delim_opt {
-        Some(c) => *c,
-        None => b',',
-    };
     // set output filename and check if option is present in command
     let mut csv_output = String::from("embedded.csv");
     let csv_out = matches.get_one::<String>("outfile");
@@ -454,17 +1328,159 @@ pub fn main() {
         csv_output = csv_out.unwrap().clone();
     }
     log::info!("output file : {:?}", &csv_output);
-
+    let report_file = matches.get_one::<String>("report");
+    let uncertainty = matches.get_flag("uncertainty");
+    let dumps = EmbedderDumps::from_matches(&matches);
+    let clusters_file = matches.get_one::<String>("clusters");
+    let nb_clusters = matches.get_one::<usize>("nbclusters").copied();
+    let dmap = matches.get_flag("dmap");
+    let quality_file = matches.get_one::<String>("quality");
+    if dmap && embedparams.get_hierarchy_layer() > 0 {
+        log::warn!("diffusion maps embedding is not hierarchical, layer option ignored");
+        embedparams.set_hierarchy_layer(0);
+    }
+    // a reference artifact is dumped only by one step embeddings of csv or npy data
+    if matches.get_one::<String>("savereference").is_some() {
+        let unsupported = if matches.get_one::<String>("checkpoint").is_some() {
+            Some("checkpointed runs")
+        } else if embedparams.get_hierarchy_layer() > 0 {
+            Some("hierarchical embeddings")
+        } else if matches.get_one::<String>("sparse").is_some() {
+            Some("sparse data")
+        } else if matches.get_one::<String>("kgraphfile").is_some() {
+            Some("embeddings of a dumped graph")
+        } else if matches.get_one::<String>("watch").is_some() {
+            Some("the service mode")
+        } else {
+            None
+        };
+        if let Some(case) = unsupported {
+            log::error!("--savereference is not available for {}", case);
+            std::process::exit(1);
+        }
+    }
+    //
+    if let Some(watch_dir) = matches.get_one::<String>("watch") {
+        let delim = matches.get_one::<char>("delim").map_or(b',', |c| *c as u8);
+        run_watch(
+            Path::new(watch_dir),
+            matches.get_one::<String>("watchout"),
+            matches.get_one::<String>("reference"),
+            &hnswparams,
+            embedparams,
+            delim,
+        );
+        return;
+    }
+    if let Some(sparse_file) = matches.get_one::<String>("sparse") {
+        run_sparse(
+            sparse_file,
+            &matches,
+            &hnswparams,
+            embedparams,
+            &csv_output,
+            uncertainty,
+            &mut monitor,
+        );
+        return;
+    }
+    // embedding from a dumped graph, we do not need data nor hnsw
+    if let Some(kgraph_file) = matches.get_one::<String>("kgraphfile") {
+        let kgraph = match KGraph::<f64>::reload(std::path::Path::new(kgraph_file)) {
+            Ok(kgraph) => kgraph,
+            Err(e) => {
+                log::error!("could not reload graph from file {} : {}", kgraph_file, e);
+                std::process::exit(1);
+            }
+        };
+        monitor.end_stage("graph");
+        if dmap {
+            let (embedded, quality) = dmap_embedding(&kgraph, embedparams.get_dimension());
+            write_embedding(&csv_output, &embedded, dumps.labels.as_ref());
+            dump_quality(quality_file, &quality);
+            dump_report(report_file, &mut monitor, &kgraph, None);
+            dump_clusters(clusters_file, nb_clusters, &kgraph);
+            return;
+        }
+        let mut embedder = Embedder::new(&kgraph, embedparams);
+        let embed_res = embedder.embed();
+        if embed_res.is_err() {
+            log::error!("embedding failed");
+            std::process::exit(1);
+        }
+        write_embedding(&csv_output, &get_output(&embedder, uncertainty), dumps.labels.as_ref());
+        dumps.dump(&embedder);
+        if quality_file.is_some() {
+            dump_quality(quality_file, &embedder_quality(&embedder, kgraph.get_max_nbng()));
+        }
+        dump_report(report_file, &mut monitor, &kgraph, embedder.get_final_loss());
+        dump_clusters(clusters_file, nb_clusters, &kgraph);
+        return;
+    }
+    let dumpgraph = matches.get_one::<String>("dumpgraph");
+    //
+    let mut checkpoint = match matches.get_one::<String>("checkpoint") {
+        Some(dir) => match Checkpoint::open(Path::new(dir), matches.get_flag("resume")) {
+            Ok(ckpt) => Some(ckpt),
+            Err(e) => {
+                log::error!("could not open checkpoint directory {} : {}", dir, e);
+                std::process::exit(1);
+            }
+        },
+        None => None,
+    };
+    if let Some(ckpt) = checkpoint.as_mut() {
+        if embedparams.get_hierarchy_layer() > 0 {
+            log::warn!("checkpointed runs are not hierarchical, layer option ignored");
+            embedparams.set_hierarchy_layer(0);
+        }
+        if let Some(out) = ckpt.get("embedded") {
+            println!("checkpointed run already completed, embedding in {}", out);
+            return;
+        }
+        // with a checkpointed graph we need neither data nor hnsw
+        if ckpt.get("kgraph").is_some() {
+            let kgraph = match KGraph::<f64>::reload(&ckpt.get_path(CKPT_KGRAPH)) {
+                Ok(kgraph) => kgraph,
+                Err(e) => {
+                    log::error!("could not reload checkpointed graph : {}", e);
+                    std::process::exit(1);
+                }
+            };
+            monitor.end_stage("graph");
+            let final_loss = write_checkpointed_embedding(&kgraph, embedparams, ckpt, &csv_output, uncertainty, &dumps);
+            dump_report(report_file, &mut monitor, &kgraph, final_loss);
+            dump_clusters(clusters_file, nb_clusters, &kgraph);
+            return;
+        }
+    }
+    //
+    let (fname, res) = match matches.get_one::<String>("npyfile") {
+        Some(npy_file) => {
+            let res = read_npy_data(Path::new(npy_file));
+            (npy_file.clone(), res)
+        }
+        None => {
+            let fname = matches.get_one::<String>("csvfile").unwrap().clone();
+            let delim_opt = matches.get_one::<u8>("delim");
+            let delim = match delim_opt {
+                Some(c) => *c,
+                None => b',',
+            };
+            let res = get_toembed_from_csv::<f64>(Path::new(&fname), delim);
+            (fname, res)
+        }
+    };
     // open file
     let filepath = std::path::Path::new(&fname);
-    let res = get_toembed_from_csv::<f64>(filepath, delim);
-    if res.is_err() {
-        log::error!("could not open file : {:?}", filepath);
+    if let Err(e) = res.as_ref() {
+        log::error!("could not read file : {:?}, {}", filepath, e);
         std::process::exit(1);
     }
-    log::info!("csv file {} read", fname);
+    log::info!("data file {} read", fname);
     //
-    let data = res.unwrap();
+    let data: Vec<Vec<f64>> = res.unwrap();
+    monitor.end_stage("load");
     let data_with_id: Vec<(&Vec<f64>, usize)> = data.iter().zip(0..data.len()).collect();
     let nb_data = data.len();
     let nb_layer = 16.min((nb_data as f32).ln().trunc() as usize);
@@ -472,18 +1488,40 @@ pub fn main() {
     let cpu_start = ProcessTime::now();
     let sys_now = SystemTime::now();
 
-    log::info!("dumping in csv file {}", csv_output);
-    let mut csv_w = csv::Writer::from_path(csv_output).unwrap();
-    //
+    if let Some(ckpt) = checkpoint.as_mut() {
+        let kgraph = get_kgraph_with_distname(&data_with_id, &hnswparams, nb_layer, false, Some(&mut *ckpt));
+        monitor.end_stage("graph");
+        let final_loss = write_checkpointed_embedding(&kgraph, embedparams, ckpt, &csv_output, uncertainty, &dumps);
+        dump_report(report_file, &mut monitor, &kgraph, final_loss);
+        dump_clusters(clusters_file, nb_clusters, &kgraph);
+        return;
+    }
     if embedparams.get_hierarchy_layer() == 0 {
         let hubdim = true; // to get hubness and intrinsic dimension info
-        let kgraph = get_kgraph_with_distname(&data_with_id, &hnswparams, nb_layer, hubdim);
+        let kgraph = get_kgraph_with_distname(&data_with_id, &hnswparams, nb_layer, hubdim, None);
+        monitor.end_stage("graph");
         let cpu_time: Duration = cpu_start.elapsed();
         println!(
             " graph construction sys time(s) {:?} cpu time {:?}",
             sys_now.elapsed().unwrap().as_secs(),
             cpu_time.as_secs()
         );
+        if let Some(graph_file) = dumpgraph {
+            if let Err(e) = kgraph.dump(std::path::Path::new(graph_file)) {
+                log::error!("could not dump graph in file {} : {}", graph_file, e);
+            }
+        }
+        if dmap {
+            let (embedded, quality) = dmap_embedding(&kgraph, embedparams.get_dimension());
+            write_embedding(&csv_output, &embedded, dumps.labels.as_ref());
+            if let Some(file) = matches.get_one::<String>("savereference") {
+                save_reference(file, &data, &embedded, &hnswparams);
+            }
+            dump_quality(quality_file, &quality);
+            dump_report(report_file, &mut monitor, &kgraph, None);
+            dump_clusters(clusters_file, nb_clusters, &kgraph);
+            return;
+        }
         let mut embedder = Embedder::new(&kgraph, embedparams);
         let embed_res = embedder.embed();
         if embed_res.is_err() {
@@ -492,8 +1530,16 @@ pub fn main() {
         }
         //
         // we can use get_embedded_reindexed as we indexed DataId contiguously in hnsw!
-        let _res = write_csv_array2(&mut csv_w, &embedder.get_embedded_reindexed());
-        csv_w.flush().unwrap();
+        write_embedding(&csv_output, &get_output(&embedder, uncertainty), dumps.labels.as_ref());
+        dumps.dump(&embedder);
+        if let Some(file) = matches.get_one::<String>("savereference") {
+            save_reference(file, &data, &embedder.get_embedded_reindexed(), &hnswparams);
+        }
+        if quality_file.is_some() {
+            dump_quality(quality_file, &embedder_quality(&embedder, hnswparams.knbn));
+        }
+        dump_report(report_file, &mut monitor, &kgraph, embedder.get_final_loss());
+        dump_clusters(clusters_file, nb_clusters, &kgraph);
     }
     // end not hierarchical
     else {
@@ -503,11 +1549,17 @@ pub fn main() {
             nb_layer,
             embedparams.get_hierarchy_layer(),
         );
+        monitor.end_stage("graph");
         let mut embedder = Embedder::from_hkgraph(&graphprojection, embedparams);
         let embed_res = embedder.embed();
         assert!(embed_res.is_ok());
         assert!(embedder.get_embedded().is_some());
-        let _res = write_csv_array2(&mut csv_w, &embedder.get_embedded_reindexed());
-        csv_w.flush().unwrap();
+        write_embedding(&csv_output, &get_output(&embedder, uncertainty), dumps.labels.as_ref());
+        dumps.dump(&embedder);
+        if quality_file.is_some() {
+            dump_quality(quality_file, &embedder_quality(&embedder, hnswparams.knbn));
+        }
+        dump_report(report_file, &mut monitor, graphprojection.get_large_graph(), embedder.get_final_loss());
+        dump_clusters(clusters_file, nb_clusters, graphprojection.get_large_graph());
     }
 } // end of main
@@ -0,0 +1,283 @@
+//! Synthetic datasets with ground truth, so that examples, benchmarks and validation metrics can run without external data.
+//!
+//! - [swiss_roll] and [s_curve] : 2-dimensional manifolds in $R^3$, ground truth is the position on the manifold.
+//! - [gaussian_blobs] : isotropic gaussian clusters, ground truth is the blob of each point (labels) and its center.
+//! - [hypercube_manifold] : a uniform hypercube of dimension d isometrically embedded in a larger ambient space,
+//!   ground truth is the position in the hypercube (so the intrinsic dimension is d).
+//! - [harlim_gaussian_1d] : points of a 1-dimensional standard gaussian, used by Berry-Harlim to validate
+//!   variable bandwidth kernels. Ground truth is the coordinate and the density at the point.
+//!
+//! All generators are deterministic for a given seed. Coordinates are f32, ground truth parameters are f64.
+
+use ndarray::{Array1, Array2};
+use rand::Rng;
+use rand_distr::{Distribution, StandardNormal};
+use rand_xoshiro::rand_core::SeedableRng;
+use rand_xoshiro::Xoshiro256PlusPlus;
+
+use crate::error::AnnembedError;
+
+/// A generated dataset : points in rows and for each point its ground truth parameters.
+pub struct Dataset {
+    /// name of generator
+    name: &'static str,
+    /// points in rows
+    data: Array2<f32>,
+    /// ground truth parameters of each point (row i for point i), see each generator
+    params: Array2<f64>,
+    /// cluster of each point, for generators with clusters
+    labels: Option<Vec<usize>>,
+} // end of Dataset
+
+impl Dataset {
+    /// returns name of generator
+    pub fn get_name(&self) -> &'static str {
+        self.name
+    }
+
+    /// returns points in rows
+    pub fn get_data(&self) -> &Array2<f32> {
+        &self.data
+    }
+
+    /// returns points as vectors, as needed for Hnsw insertion
+    pub fn get_data_as_vec(&self) -> Vec<Vec<f32>> {
+        self.data.rows().into_iter().map(|r| r.to_vec()).collect()
+    }
+
+    /// returns ground truth parameters, one row by point
+    pub fn get_params(&self) -> &Array2<f64> {
+        &self.params
+    }
+
+    /// returns labels of points if the dataset has clusters
+    pub fn get_labels(&self) -> Option<&Vec<usize>> {
+        self.labels.as_ref()
+    }
+
+    pub fn get_nb_points(&self) -> usize {
+        self.data.nrows()
+    }
+
+    /// returns dimension of ambient space
+    pub fn get_dim(&self) -> usize {
+        self.data.ncols()
+    }
+} // end of impl Dataset
+
+fn check_nb_points(nb_points: usize) -> Result<(), AnnembedError> {
+    if nb_points == 0 {
+        return Err(AnnembedError::InvalidParameter(String::from("dataset must have at least one point")));
+    }
+    Ok(())
+}
+
+// adds isotropic gaussian noise of standard deviation noise to data
+fn add_noise(data: &mut Array2<f32>, noise: f64, rng: &mut Xoshiro256PlusPlus) {
+    if noise > 0. {
+        for x in data.iter_mut() {
+            let g: f64 = StandardNormal.sample(rng);
+            *x += (noise * g) as f32;
+        }
+    }
+}
+
+/// Swiss roll : $(t \cos t, h, t \sin t)$ with $t$ uniform in $[1.5 \pi, 4.5 \pi]$ and $h$ uniform in $[0, 21]$
+/// as in scikit-learn, plus gaussian noise of standard deviation noise.
+/// Ground truth parameters are $(t, h)$.
+pub fn swiss_roll(nb_points: usize, noise: f64, seed: u64) -> Result<Dataset, AnnembedError> {
+    check_nb_points(nb_points)?;
+    let mut rng = Xoshiro256PlusPlus::seed_from_u64(seed);
+    let mut data = Array2::<f32>::zeros((nb_points, 3));
+    let mut params = Array2::<f64>::zeros((nb_points, 2));
+    for i in 0..nb_points {
+        let t = 1.5 * std::f64::consts::PI * (1. + 2. * rng.gen::<f64>());
+        let h = 21. * rng.gen::<f64>();
+        data[[i, 0]] = (t * t.cos()) as f32;
+        data[[i, 1]] = h as f32;
+        data[[i, 2]] = (t * t.sin()) as f32;
+        params[[i, 0]] = t;
+        params[[i, 1]] = h;
+    }
+    add_noise(&mut data, noise, &mut rng);
+    Ok(Dataset {
+        name: "swiss_roll",
+        data,
+        params,
+        labels: None,
+    })
+} // end of swiss_roll
+
+/// S curve : $(\sin t, h, sign(t) (\cos t - 1))$ with $t$ uniform in $[-1.5 \pi, 1.5 \pi]$ and $h$ uniform in $[0, 2]$,
+/// plus gaussian noise of standard deviation noise.
+/// Ground truth parameters are $(t, h)$.
+pub fn s_curve(nb_points: usize, noise: f64, seed: u64) -> Result<Dataset, AnnembedError> {
+    check_nb_points(nb_points)?;
+    let mut rng = Xoshiro256PlusPlus::seed_from_u64(seed);
+    let mut data = Array2::<f32>::zeros((nb_points, 3));
+    let mut params = Array2::<f64>::zeros((nb_points, 2));
+    for i in 0..nb_points {
+        let t = 3. * std::f64::consts::PI * (rng.gen::<f64>() - 0.5);
+        let h = 2. * rng.gen::<f64>();
+        data[[i, 0]] = t.sin() as f32;
+        data[[i, 1]] = h as f32;
+        data[[i, 2]] = (t.signum() * (t.cos() - 1.)) as f32;
+        params[[i, 0]] = t;
+        params[[i, 1]] = h;
+    }
+    add_noise(&mut data, noise, &mut rng);
+    Ok(Dataset {
+        name: "s_curve",
+        data,
+        params,
+        labels: None,
+    })
+} // end of s_curve
+
+/// nb_blobs isotropic gaussian blobs of standard deviation std_dev in dimension dim.
+/// Centers are uniform in $[-10, 10]^{dim}$, points are distributed among blobs in turn.
+/// Labels give the blob of each point and ground truth parameters are the center of the blob of each point.
+pub fn gaussian_blobs(
+    nb_points: usize,
+    nb_blobs: usize,
+    dim: usize,
+    std_dev: f64,
+    seed: u64,
+) -> Result<Dataset, AnnembedError> {
+    check_nb_points(nb_points)?;
+    if nb_blobs == 0 || dim == 0 || std_dev.is_nan() || std_dev < 0. {
+        return Err(AnnembedError::InvalidParameter(format!(
+            "gaussian_blobs, nb_blobs {}, dim {}, std_dev {}",
+            nb_blobs, dim, std_dev
+        )));
+    }
+    let mut rng = Xoshiro256PlusPlus::seed_from_u64(seed);
+    let centers = Array2::<f64>::from_shape_fn((nb_blobs, dim), |_| 20. * rng.gen::<f64>() - 10.);
+    let labels: Vec<usize> = (0..nb_points).map(|i| i % nb_blobs).collect();
+    let mut data = Array2::<f32>::zeros((nb_points, dim));
+    let mut params = Array2::<f64>::zeros((nb_points, dim));
+    for i in 0..nb_points {
+        let center = centers.row(labels[i]);
+        params.row_mut(i).assign(&center);
+        for j in 0..dim {
+            let g: f64 = StandardNormal.sample(&mut rng);
+            data[[i, j]] = (center[j] + std_dev * g) as f32;
+        }
+    }
+    Ok(Dataset {
+        name: "gaussian_blobs",
+        data,
+        params,
+        labels: Some(labels),
+    })
+} // end of gaussian_blobs
+
+/// Uniform points of the hypercube $[0,1]^d$, d being manifold_dim, mapped in $R^{D}$ (D = ambient_dim) by a random isometry
+/// (orthonormal columns obtained by Gram-Schmidt on a gaussian matrix).
+/// Ground truth parameters are the coordinates in the hypercube, distances are preserved by the embedding.
+pub fn hypercube_manifold(
+    nb_points: usize,
+    manifold_dim: usize,
+    ambient_dim: usize,
+    seed: u64,
+) -> Result<Dataset, AnnembedError> {
+    check_nb_points(nb_points)?;
+    if manifold_dim == 0 || ambient_dim < manifold_dim {
+        return Err(AnnembedError::InvalidParameter(format!(
+            "hypercube_manifold, manifold_dim {} must be > 0 and <= ambient_dim {}",
+            manifold_dim, ambient_dim
+        )));
+    }
+    let mut rng = Xoshiro256PlusPlus::seed_from_u64(seed);
+    // columns of isometry are orthonormalized gaussian vectors
+    let mut isometry = Array2::<f64>::zeros((ambient_dim, manifold_dim));
+    for k in 0..manifold_dim {
+        let mut column = Array1::<f64>::zeros(ambient_dim);
+        let mut norm = 0.;
+        // a gaussian vector is almost surely independent of previous columns
+        while norm < 1.0E-6 {
+            column = Array1::from_shape_fn(ambient_dim, |_| StandardNormal.sample(&mut rng));
+            for l in 0..k {
+                let previous = isometry.column(l);
+                let proj = column.dot(&previous);
+                column.scaled_add(-proj, &previous);
+            }
+            norm = column.dot(&column).sqrt();
+        }
+        isometry.column_mut(k).assign(&(column / norm));
+    }
+    let params = Array2::<f64>::from_shape_fn((nb_points, manifold_dim), |_| rng.gen::<f64>());
+    let data = params.dot(&isometry.t()).mapv(|x| x as f32);
+    Ok(Dataset {
+        name: "hypercube_manifold",
+        data,
+        params,
+        labels: None,
+    })
+} // end of hypercube_manifold
+
+/// Points sampled from the 1-dimensional standard gaussian, as in Berry T., Harlim J. Variable bandwidth diffusion kernels.
+/// Appl. Comput. Harmon. Anal. 2016. Points are in rows of a 1 column matrix.
+/// Ground truth parameters are the coordinate $x$ and the density $\exp(-x^2/2) / \sqrt{2 \pi}$ at $x$.
+pub fn harlim_gaussian_1d(nb_points: usize, seed: u64) -> Result<Dataset, AnnembedError> {
+    check_nb_points(nb_points)?;
+    let mut rng = Xoshiro256PlusPlus::seed_from_u64(seed);
+    let mut data = Array2::<f32>::zeros((nb_points, 1));
+    let mut params = Array2::<f64>::zeros((nb_points, 2));
+    let normalization = (2. * std::f64::consts::PI).sqrt();
+    for i in 0..nb_points {
+        let x: f64 = StandardNormal.sample(&mut rng);
+        data[[i, 0]] = x as f32;
+        params[[i, 0]] = x;
+        params[[i, 1]] = (-0.5 * x * x).exp() / normalization;
+    }
+    Ok(Dataset {
+        name: "harlim_gaussian_1d",
+        data,
+        params,
+        labels: None,
+    })
+} // end of harlim_gaussian_1d
+
+//=======================================================================
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_manifolds() {
+        let roll = swiss_roll(500, 0., 17).unwrap();
+        assert_eq!(roll.get_data().dim(), (500, 3));
+        for i in 0..roll.get_nb_points() {
+            let t = roll.get_params()[[i, 0]];
+            assert!((roll.get_data()[[i, 0]] as f64 - t * t.cos()).abs() < 1.0E-4);
+        }
+        // same seed same data
+        let again = swiss_roll(500, 0., 17).unwrap();
+        assert_eq!(roll.get_data(), again.get_data());
+        let curve = s_curve(100, 0.1, 3).unwrap();
+        assert_eq!(curve.get_params().dim(), (100, 2));
+        // isometry preserves distances
+        let cube = hypercube_manifold(50, 3, 10, 5).unwrap();
+        let (data, params) = (cube.get_data(), cube.get_params());
+        let dist_data = data.row(0).iter().zip(data.row(1).iter()).map(|(a, b)| ((a - b) * (a - b)) as f64).sum::<f64>();
+        let dist_params = params.row(0).iter().zip(params.row(1).iter()).map(|(a, b)| (a - b) * (a - b)).sum::<f64>();
+        assert!((dist_data - dist_params).abs() < 1.0E-4);
+        assert!(hypercube_manifold(50, 4, 3, 5).is_err());
+    } // end of test_manifolds
+
+    #[test]
+    fn test_blobs_and_gaussian() {
+        let blobs = gaussian_blobs(300, 3, 4, 0.5, 11).unwrap();
+        let labels = blobs.get_labels().unwrap();
+        assert_eq!(labels.iter().filter(|l| **l == 2).count(), 100);
+        assert_eq!(blobs.get_params().row(2), blobs.get_params().row(5));
+        let gaussian = harlim_gaussian_1d(2000, 1).unwrap();
+        let mean = gaussian.get_data().iter().map(|x| *x as f64).sum::<f64>() / 2000.;
+        assert!(mean.abs() < 0.1);
+        let max_density = gaussian.get_params().column(1).iter().fold(0f64, |m, d| m.max(*d));
+        assert!(max_density <= 1. / (2. * std::f64::consts::PI).sqrt());
+    } // end of test_blobs_and_gaussian
+} // end of mod tests
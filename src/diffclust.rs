@@ -0,0 +1,442 @@
+//! Clustering on diffusion distances.
+//!
+//! - The number of clusters is chosen from the spectrum stored by [DiffusionMaps] in the last embedding :
+//!   for C clusters the C first eigenvalues are close to 1 and the retained C maximizes the eigengap $\lambda_{C-1} - \lambda_{C}$.
+//! - Euclidean distances between diffusion maps coordinates are diffusion distances (up to truncation of the spectrum),
+//!   so clusters are searched by k-medoids on the C-1 first coordinates of the embedding.
+//! - k-medoids is initialized by k-means++ seeding and alternates assignment to the nearest medoid and medoid update.
+//!   The new medoid of a cluster is searched among the members nearest to the cluster mean, so that an update is linear in cluster size.
+//! - Alternatively ([ClusterAssignment::Rotation]) the C first eigenvectors are rotated to near indicator vectors which give
+//!   the discrete assignment without random initialization, see [discretize_by_rotation]. Medoids are then those of the clusters found.
+//!
+
+use ndarray::{s, Array1, Array2, ArrayView1, ArrayView2};
+use num_traits::cast::FromPrimitive;
+use num_traits::Float;
+use rand::Rng;
+use rand_xoshiro::rand_core::SeedableRng;
+use rand_xoshiro::Xoshiro256PlusPlus;
+use rayon::prelude::*;
+
+use hnsw_rs::prelude::DataId;
+
+use crate::diffmaps::DiffusionMaps;
+use crate::error::AnnembedError;
+use crate::fromhnsw::kgraph::KGraph;
+use crate::spectralclust::discretize_by_rotation;
+use crate::tools::nodeparam::NodeIdx;
+
+/// default maximum number of alternate iterations of k-medoids
+const DEFAULT_MAX_ITER: usize = 100;
+
+/// number of members nearest to the cluster mean examined as new medoid
+const NB_MEDOID_CANDIDATES: usize = 50;
+
+/// returns the number of clusters C in \[2, max_clusters\] maximizing the eigengap $\lambda_{C-1} - \lambda_{C}$, and the gap.
+/// eigenvalues are expected decreasing, the first one being the stationary one. None if less than 3 eigenvalues.
+pub fn select_nb_clusters_by_eigengap(eigenvalues: &[f64], max_clusters: usize) -> Option<(usize, f64)> {
+    let max_clusters = max_clusters.min(eigenvalues.len().saturating_sub(1));
+    let mut best: Option<(usize, f64)> = None;
+    for c in 2..=max_clusters {
+        let gap = eigenvalues[c - 1] - eigenvalues[c];
+        log::debug!("eigengap nb clusters : {}, gap : {:.3e}", c, gap);
+        if best.is_none_or(|(_, g)| gap > g) {
+            best = Some((c, gap));
+        }
+    }
+    best
+} // end of select_nb_clusters_by_eigengap
+
+/// Method giving discrete clusters from the spectral embedding
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum ClusterAssignment {
+    /// k-medoids on diffusion coordinates. This is the default
+    KMedoids,
+    /// rotation of eigenvectors to near indicator vectors
+    Rotation,
+}
+
+/// Parameters of clustering on diffusion distances
+#[derive(Copy, Clone, Debug)]
+pub struct DiffusionClustering {
+    /// maximum number of clusters searched with the eigengap
+    max_clusters: usize,
+    /// number of clusters imposed, bypassing the eigengap
+    nb_clusters: Option<usize>,
+    max_iter: usize,
+    /// seed of k-means++ initialization
+    seed: u64,
+    assignment: ClusterAssignment,
+} // end of DiffusionClustering
+
+/// result of clustering on diffusion distances
+#[derive(Clone, Debug)]
+pub struct DiffusionClusters {
+    /// label of each node, indexed by node rank (NodeIdx) as rows of the embedding
+    labels: Vec<usize>,
+    /// medoid (node rank) of each cluster
+    medoids: Vec<NodeIdx>,
+    /// DataId of each medoid
+    medoid_ids: Vec<DataId>,
+    /// eigengap giving the number of clusters, None if it was imposed
+    eigengap: Option<f64>,
+    /// sum of diffusion distances of nodes to their medoid
+    cost: f64,
+} // end of DiffusionClusters
+
+impl DiffusionClusters {
+    /// labels indexed by node rank (NodeIdx), as rows of the embedding clustered
+    pub fn get_labels(&self) -> &Vec<usize> {
+        &self.labels
+    }
+
+    /// number of clusters
+    pub fn get_nb_clusters(&self) -> usize {
+        self.medoids.len()
+    }
+
+    /// medoids (node rank) of clusters, medoid of cluster c at rank c
+    pub fn get_medoids(&self) -> &Vec<NodeIdx> {
+        &self.medoids
+    }
+
+    /// DataId of medoids of clusters, medoid of cluster c at rank c
+    pub fn get_medoid_ids(&self) -> &Vec<DataId> {
+        &self.medoid_ids
+    }
+
+    /// eigengap that selected the number of clusters, None if the number of clusters was imposed
+    pub fn get_eigengap(&self) -> Option<f64> {
+        self.eigengap
+    }
+
+    /// sum of diffusion distances of nodes to their medoid
+    pub fn get_cost(&self) -> f64 {
+        self.cost
+    }
+} // end of impl DiffusionClusters
+
+impl DiffusionClustering {
+    /// max_clusters is the maximum number of clusters searched with the eigengap. It must be at least 2,
+    /// which is checked at clustering.
+    pub fn new(max_clusters: usize) -> Self {
+        DiffusionClustering {
+            max_clusters,
+            nb_clusters: None,
+            max_iter: DEFAULT_MAX_ITER,
+            seed: 4664397,
+            assignment: ClusterAssignment::KMedoids,
+        }
+    }
+
+    /// sets the method of assignment to clusters (default k-medoids)
+    pub fn set_assignment(&mut self, assignment: ClusterAssignment) {
+        self.assignment = assignment;
+    }
+
+    /// imposes the number of clusters instead of selecting it from the eigengap. It must be at least 2, checked at clustering.
+    pub fn set_nb_clusters(&mut self, nb_clusters: usize) {
+        self.nb_clusters = Some(nb_clusters);
+    }
+
+    /// set maximum number of k-medoids iterations (default 100)
+    pub fn set_max_iter(&mut self, max_iter: usize) {
+        self.max_iter = max_iter;
+    }
+
+    /// set seed of k-medoids initialization
+    pub fn set_seed(&mut self, seed: u64) {
+        self.seed = seed;
+    }
+
+    /// embeds the graph with dmaps (see [DiffusionMaps::embed_kgraph]) and clusters the embedding.
+    /// The embedding dimension of dmaps parameters limits the number of coordinates used.
+    pub fn cluster_kgraph<F>(&self, dmaps: &mut DiffusionMaps, kgraph: &KGraph<F>) -> Result<DiffusionClusters, AnnembedError>
+    where
+        F: Float + FromPrimitive + std::marker::Sync + Send + std::fmt::UpperExp + std::iter::Sum,
+    {
+        let embedded = dmaps.embed_kgraph_typed::<F, f64>(kgraph)?;
+        self.cluster_embedding(dmaps, &embedded)
+    } // end of cluster_kgraph
+
+    /// clusters the last embedding computed by dmaps. The number of clusters is selected from the eigenvalues stored in dmaps,
+    /// so the embedding must come from a symetric laplacian (not a magnetic or bi-diffusion embedding) unless it is imposed.
+    pub fn cluster_embedding<G>(&self, dmaps: &DiffusionMaps, embedded: &Array2<G>) -> Result<DiffusionClusters, AnnembedError>
+    where
+        G: Float,
+    {
+        if self.max_clusters < 2 {
+            return Err(AnnembedError::InvalidParameter(format!(
+                "diffusion clustering, max number of clusters must be at least 2, got {}",
+                self.max_clusters
+            )));
+        }
+        let (nb_clusters, eigengap) = match self.nb_clusters {
+            Some(nb_clusters) if nb_clusters < 2 => {
+                return Err(AnnembedError::InvalidParameter(format!(
+                    "diffusion clustering, number of clusters must be at least 2, got {}",
+                    nb_clusters
+                )));
+            }
+            Some(nb_clusters) => (nb_clusters, None),
+            None => {
+                let eigenvalues = dmaps.get_eigenvalues().ok_or_else(|| {
+                    AnnembedError::InvalidParameter(String::from(
+                        "diffusion clustering, no eigenvalues stored, impose the number of clusters",
+                    ))
+                })?;
+                let (nb_clusters, gap) = select_nb_clusters_by_eigengap(eigenvalues, self.max_clusters)
+                    .ok_or(AnnembedError::NotEnoughEigenvectors {
+                        computed: eigenvalues.len(),
+                        needed: 3,
+                    })?;
+                (nb_clusters, Some(gap))
+            }
+        };
+        if nb_clusters > embedded.nrows() {
+            return Err(AnnembedError::InvalidParameter(format!(
+                "diffusion clustering, {} clusters asked for {} points",
+                nb_clusters,
+                embedded.nrows()
+            )));
+        }
+        let nb_coords = (nb_clusters - 1).clamp(1, embedded.ncols());
+        if nb_coords < nb_clusters - 1 {
+            log::warn!(
+                "diffusion clustering, embedding dimension {} lower than nb clusters - 1 : {}",
+                embedded.ncols(),
+                nb_clusters - 1
+            );
+        }
+        let coords = embedded.slice(s![.., ..nb_coords]).mapv(|x| x.to_f64().unwrap());
+        let (labels, medoids, cost) = match self.assignment {
+            ClusterAssignment::KMedoids => self.kmedoids(&coords.view(), nb_clusters),
+            ClusterAssignment::Rotation => rotation_clusters(dmaps, &coords.view(), nb_clusters)?,
+        };
+        log::info!("diffusion clustering, nb clusters : {}, cost : {:.3e}", medoids.len(), cost);
+        let medoid_ids = match dmaps.get_data_ids() {
+            Some(ids) if ids.len() == embedded.nrows() => medoids.iter().map(|m| ids[*m]).collect(),
+            _ => medoids.clone(),
+        };
+        Ok(DiffusionClusters {
+            labels,
+            medoids,
+            medoid_ids,
+            eigengap,
+            cost,
+        })
+    } // end of cluster_embedding
+
+    // k-medoids on rows of coords. returns labels, medoids and cost
+    pub(crate) fn kmedoids(&self, coords: &ArrayView2<f64>, nb_clusters: usize) -> (Vec<usize>, Vec<NodeIdx>, f64) {
+        let mut medoids = self.kmeans_plusplus(coords, nb_clusters);
+        let (mut labels, mut cost) = assign(coords, &medoids);
+        for iter in 0..self.max_iter {
+            let new_medoids: Vec<NodeIdx> = (0..nb_clusters)
+                .into_par_iter()
+                .map(|c| update_medoid(coords, &labels, c, medoids[c]))
+                .collect();
+            if new_medoids == medoids {
+                log::debug!("k-medoids converged at iteration {}", iter);
+                break;
+            }
+            let (new_labels, new_cost) = assign(coords, &new_medoids);
+            if new_cost >= cost {
+                break;
+            }
+            medoids = new_medoids;
+            labels = new_labels;
+            cost = new_cost;
+        }
+        (labels, medoids, cost)
+    } // end of kmedoids
+
+    // k-means++ seeding : each new medoid is drawn with probability proportional to squared distance to the nearest one chosen
+    fn kmeans_plusplus(&self, coords: &ArrayView2<f64>, nb_clusters: usize) -> Vec<NodeIdx> {
+        let nbrow = coords.nrows();
+        let mut rng = Xoshiro256PlusPlus::seed_from_u64(self.seed);
+        let mut medoids = vec![rng.gen_range(0..nbrow)];
+        let mut dist2: Array1<f64> = coords
+            .rows()
+            .into_iter()
+            .map(|row| distance(&row, &coords.row(medoids[0])).powi(2))
+            .collect();
+        while medoids.len() < nb_clusters {
+            let total: f64 = dist2.sum();
+            let next = if total > 0. {
+                let mut threshold = rng.gen::<f64>() * total;
+                let mut next = nbrow - 1;
+                for (i, d) in dist2.iter().enumerate() {
+                    if threshold < *d {
+                        next = i;
+                        break;
+                    }
+                    threshold -= d;
+                }
+                next
+            } else {
+                // all points on chosen medoids, take any other point
+                (0..nbrow).find(|i| !medoids.contains(i)).unwrap()
+            };
+            medoids.push(next);
+            for (i, row) in coords.rows().into_iter().enumerate() {
+                dist2[i] = dist2[i].min(distance(&row, &coords.row(next)).powi(2));
+            }
+        }
+        medoids
+    } // end of kmeans_plusplus
+} // end of impl DiffusionClustering
+
+// clusters from the rotation of the nb_clusters first eigenvectors stored in dmaps, medoids and cost in coords
+fn rotation_clusters(
+    dmaps: &DiffusionMaps,
+    coords: &ArrayView2<f64>,
+    nb_clusters: usize,
+) -> Result<(Vec<usize>, Vec<NodeIdx>, f64), AnnembedError> {
+    let eigenvectors = dmaps.get_eigenvectors().ok_or_else(|| {
+        AnnembedError::InvalidParameter(String::from(
+            "diffusion clustering by rotation, no eigenvectors stored (magnetic or bi-diffusion embedding)",
+        ))
+    })?;
+    if eigenvectors.ncols() < nb_clusters {
+        return Err(AnnembedError::NotEnoughEigenvectors {
+            computed: eigenvectors.ncols(),
+            needed: nb_clusters,
+        });
+    }
+    if eigenvectors.nrows() != coords.nrows() {
+        return Err(AnnembedError::InvalidParameter(format!(
+            "diffusion clustering by rotation, {} eigenvector rows for {} points",
+            eigenvectors.nrows(),
+            coords.nrows()
+        )));
+    }
+    let x = eigenvectors.slice(s![.., ..nb_clusters]).mapv(|v| v as f64);
+    let labels = discretize_by_rotation(&x.view())?.labels;
+    let nb_found = labels.iter().max().map_or(0, |l| l + 1);
+    let medoids: Vec<NodeIdx> = (0..nb_found)
+        .into_par_iter()
+        .map(|c| {
+            let first = labels.iter().position(|l| *l == c).unwrap();
+            update_medoid(coords, &labels, c, first)
+        })
+        .collect();
+    let cost = labels
+        .iter()
+        .enumerate()
+        .map(|(i, l)| distance(&coords.row(i), &coords.row(medoids[*l])))
+        .sum();
+    Ok((labels, medoids, cost))
+} // end of rotation_clusters
+
+fn distance(a: &ArrayView1<f64>, b: &ArrayView1<f64>) -> f64 {
+    a.iter().zip(b.iter()).map(|(x, y)| (x - y) * (x - y)).sum::<f64>().sqrt()
+}
+
+// assigns each row to its nearest medoid, returns labels and sum of distances
+fn assign(coords: &ArrayView2<f64>, medoids: &[NodeIdx]) -> (Vec<usize>, f64) {
+    let assigned: Vec<(usize, f64)> = (0..coords.nrows())
+        .into_par_iter()
+        .map(|i| {
+            medoids
+                .iter()
+                .enumerate()
+                .map(|(c, m)| (c, distance(&coords.row(i), &coords.row(*m))))
+                .fold((0, f64::MAX), |best, cand| if cand.1 < best.1 { cand } else { best })
+        })
+        .collect();
+    let cost = assigned.iter().map(|a| a.1).sum();
+    (assigned.into_iter().map(|a| a.0).collect(), cost)
+} // end of assign
+
+// returns the member of cluster minimizing the sum of distances to members, searched among members nearest to the mean
+fn update_medoid(coords: &ArrayView2<f64>, labels: &[usize], cluster: usize, medoid: NodeIdx) -> NodeIdx {
+    let members: Vec<usize> = (0..labels.len()).filter(|i| labels[*i] == cluster).collect();
+    if members.is_empty() {
+        return medoid;
+    }
+    let mut mean = Array1::<f64>::zeros(coords.ncols());
+    for i in &members {
+        mean += &coords.row(*i);
+    }
+    mean /= members.len() as f64;
+    let mut candidates: Vec<(usize, f64)> = members
+        .iter()
+        .map(|i| (*i, distance(&coords.row(*i), &mean.view())))
+        .collect();
+    candidates.sort_unstable_by(|a, b| a.1.total_cmp(&b.1));
+    candidates.truncate(NB_MEDOID_CANDIDATES);
+    if !candidates.iter().any(|c| c.0 == medoid) {
+        candidates.push((medoid, 0.));
+    }
+    let cost = |m: usize| members.iter().map(|i| distance(&coords.row(*i), &coords.row(m))).sum::<f64>();
+    let mut best = (medoid, cost(medoid));
+    for (m, _) in candidates {
+        let c = cost(m);
+        if c < best.1 {
+            best = (m, c);
+        }
+    }
+    best.0
+} // end of update_medoid
+
+//==========================================================================================
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::diffmaps::DiffusionParams;
+
+    fn log_init_test() {
+        let _ = env_logger::builder().is_test(true).try_init();
+    }
+
+    #[test]
+    fn test_eigengap_selection() {
+        log_init_test();
+        // 3 eigenvalues near 1 then a drop
+        let eigenvalues = [1., 0.98, 0.95, 0.4, 0.35, 0.3];
+        assert_eq!(select_nb_clusters_by_eigengap(&eigenvalues, 5).map(|s| s.0), Some(3));
+        // max_clusters bounds the search
+        assert_eq!(select_nb_clusters_by_eigengap(&eigenvalues, 2).map(|s| s.0), Some(2));
+        assert!(select_nb_clusters_by_eigengap(&eigenvalues[..2], 5).is_none());
+    } // end of test_eigengap_selection
+
+    #[test]
+    fn test_invalid_nb_clusters() {
+        log_init_test();
+        let dmaps = DiffusionMaps::new(DiffusionParams::new(2, None));
+        let embedded = Array2::<f64>::zeros((10, 2));
+        let mut clustering = DiffusionClustering::new(5);
+        clustering.set_nb_clusters(1);
+        assert!(matches!(clustering.cluster_embedding(&dmaps, &embedded), Err(AnnembedError::InvalidParameter(_))));
+        let clustering = DiffusionClustering::new(1);
+        assert!(matches!(clustering.cluster_embedding(&dmaps, &embedded), Err(AnnembedError::InvalidParameter(_))));
+    } // end of test_invalid_nb_clusters
+
+    #[test]
+    fn test_kmedoids_blobs() {
+        log_init_test();
+        // 3 well separated groups of 20 points on a line
+        let nb = 60;
+        let mut rng = Xoshiro256PlusPlus::seed_from_u64(1234);
+        let coords = Array2::<f64>::from_shape_fn((nb, 2), |(i, j)| {
+            let center = if j == 0 { 10. * (i / 20) as f64 } else { 0. };
+            center + rng.gen::<f64>() - 0.5
+        });
+        let clustering = DiffusionClustering::new(5);
+        let (labels, medoids, cost) = clustering.kmedoids(&coords.view(), 3);
+        log::info!("medoids : {:?}, cost : {:.3e}", medoids, cost);
+        for g in 0..3 {
+            let group = &labels[20 * g..20 * (g + 1)];
+            assert!(group.iter().all(|l| *l == group[0]));
+            // medoid of a group is in the group
+            assert_eq!(medoids[group[0]] / 20, g);
+        }
+        assert_ne!(labels[0], labels[20]);
+        assert_ne!(labels[20], labels[40]);
+        assert_ne!(labels[0], labels[40]);
+        assert!(cost < nb as f64);
+    } // end of test_kmedoids_blobs
+} // end of mod tests
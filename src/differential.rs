@@ -0,0 +1,271 @@
+//! Differential embedding of two conditions of the same points (before/after, case/control).
+//!
+//! The two datasets are matched : row i of both datasets is the point with DataId ids\[i\].
+//! - each condition is embedded by the same [AnnEmbedPipeline] (Hnsw, graph and embedding parameters).
+//! - the embedding of condition B is mapped in the space of condition A by the similarity transformation
+//!   (rotation or reflection, uniform scale and translation) minimizing the squared distance between anchors
+//!   (orthogonal Procrustes). Anchors are points expected not to move, by default all points.
+//! - the displacement of a point is its aligned coordinates in B minus its coordinates in A. [DisplacementStats]
+//!   summarize norms of displacements, and the residual on anchors measures the quality of the alignment.
+//!
+
+use ndarray::{Array1, Array2, ArrayBase, Axis, Data, Ix2};
+use ndarray_linalg::{svddc::JobSvd, SVDDC};
+
+use hnsw_rs::prelude::*;
+
+use crate::error::AnnembedError;
+use crate::pipeline::AnnEmbedPipeline;
+
+/// Summary of displacement norms between conditions
+#[derive(Clone, Debug)]
+pub struct DisplacementStats {
+    pub mean_norm: f64,
+    pub median_norm: f64,
+    /// quantile 0.9 of displacement norms
+    pub q90_norm: f64,
+    pub max_norm: f64,
+    /// mean displacement vector (global drift)
+    pub mean_displacement: Array1<f64>,
+    /// root mean square of displacement norms of anchors, after alignment
+    pub anchor_rmsd: f64,
+    /// scale factor applied to condition B to align it on condition A
+    pub scale: f64,
+}
+
+/// Result of [differential_embed]. Rows of all matrices correspond to ids given in input.
+#[derive(Clone, Debug)]
+pub struct DifferentialEmbedding {
+    data_ids: Vec<DataId>,
+    embedding_a: Array2<f64>,
+    /// embedding of condition B mapped in the space of A
+    embedding_b: Array2<f64>,
+    displacements: Array2<f64>,
+    stats: DisplacementStats,
+}
+
+impl DifferentialEmbedding {
+    /// DataId of each row
+    pub fn get_data_ids(&self) -> &[DataId] {
+        &self.data_ids
+    }
+
+    /// embedding of condition A
+    pub fn get_embedding_a(&self) -> &Array2<f64> {
+        &self.embedding_a
+    }
+
+    /// embedding of condition B aligned in the space of condition A
+    pub fn get_embedding_b(&self) -> &Array2<f64> {
+        &self.embedding_b
+    }
+
+    /// displacement vectors (B aligned - A)
+    pub fn get_displacements(&self) -> &Array2<f64> {
+        &self.displacements
+    }
+
+    /// norm of the displacement of each row
+    pub fn get_displacement_norms(&self) -> Vec<f64> {
+        self.displacements.rows().into_iter().map(|r| r.dot(&r).sqrt()).collect()
+    }
+
+    pub fn get_stats(&self) -> &DisplacementStats {
+        &self.stats
+    }
+
+    /// returns the DataIds of the nb points with largest displacements, in decreasing order of displacement
+    pub fn get_most_displaced(&self, nb: usize) -> Vec<(DataId, f64)> {
+        let mut displaced: Vec<(DataId, f64)> = self.data_ids.iter().copied().zip(self.get_displacement_norms()).collect();
+        displaced.sort_unstable_by(|a, b| b.1.partial_cmp(&a.1).unwrap_or(std::cmp::Ordering::Equal));
+        displaced.truncate(nb);
+        displaced
+    }
+} // end of impl DifferentialEmbedding
+
+// reorders rows of an embedding given with data_ids so that row i corresponds to ids[i]
+fn reorder_rows<F: num_traits::Float>(
+    embedding: &Array2<F>,
+    data_ids: &[DataId],
+    ids: &[DataId],
+) -> Result<Array2<f64>, AnnembedError> {
+    let rank: std::collections::HashMap<DataId, usize> = data_ids.iter().enumerate().map(|(r, d)| (*d, r)).collect();
+    let mut reordered = Array2::<f64>::zeros((ids.len(), embedding.ncols()));
+    for (i, id) in ids.iter().enumerate() {
+        let r = *rank
+            .get(id)
+            .ok_or_else(|| AnnembedError::Embedding(format!("DataId {} not embedded", id)))?;
+        for j in 0..embedding.ncols() {
+            reordered[[i, j]] = embedding[[r, j]].to_f64().ok_or(AnnembedError::FloatConversion)?;
+        }
+    }
+    Ok(reordered)
+} // end of reorder_rows
+
+/// Aligns rows of b on rows of a by the similarity transformation minimizing the sum of squared distances on anchors
+/// (rows of rank anchors). Returns b transformed and the scale factor.
+pub fn procrustes_align(a: &Array2<f64>, b: &Array2<f64>, anchors: &[usize]) -> Result<(Array2<f64>, f64), AnnembedError> {
+    if a.dim() != b.dim() || anchors.is_empty() {
+        return Err(AnnembedError::InvalidParameter(format!(
+            "procrustes_align, shapes {:?} {:?}, nb anchors {}",
+            a.dim(),
+            b.dim(),
+            anchors.len()
+        )));
+    }
+    let a_anchors = a.select(Axis(0), anchors);
+    let b_anchors = b.select(Axis(0), anchors);
+    let center_a = a_anchors.mean_axis(Axis(0)).unwrap();
+    let center_b = b_anchors.mean_axis(Axis(0)).unwrap();
+    let a_centered = &a_anchors - &center_a;
+    let b_centered = &b_anchors - &center_b;
+    // rotation from svd of B^t A : R = U V^t
+    let cross = b_centered.t().dot(&a_centered);
+    let (u, s, vt) = cross
+        .svddc(JobSvd::All)
+        .map_err(|e| AnnembedError::SvdFailed(e.to_string()))?;
+    let u = u.ok_or(AnnembedError::MissingSvdResult("left singular vectors"))?;
+    let vt = vt.ok_or(AnnembedError::MissingSvdResult("right singular vectors"))?;
+    let rotation = u.dot(&vt);
+    let norm_b = b_centered.iter().map(|x| x * x).sum::<f64>();
+    let scale = if norm_b > 0. { s.sum() / norm_b } else { 1. };
+    let aligned = (b - &center_b).dot(&rotation) * scale + &center_a;
+    Ok((aligned, scale))
+} // end of procrustes_align
+
+// quantile q of sorted values, by linear interpolation
+fn sorted_quantile(sorted: &[f64], q: f64) -> f64 {
+    let pos = q * (sorted.len() - 1) as f64;
+    let (low, high) = (pos.floor() as usize, pos.ceil() as usize);
+    sorted[low] + (sorted[high] - sorted[low]) * (pos - low as f64)
+}
+
+/// Embeds two matched conditions with the same pipeline and computes displacements of points between conditions.
+///
+/// Row i of data_a and data_b is the point with DataId ids\[i\]. anchors are DataIds of points used to align
+/// condition B on condition A, all points if None.
+pub fn differential_embed<T, D, S>(
+    pipeline: &AnnEmbedPipeline<D>,
+    data_a: &ArrayBase<S, Ix2>,
+    data_b: &ArrayBase<S, Ix2>,
+    ids: &[DataId],
+    anchors: Option<&[DataId]>,
+) -> Result<DifferentialEmbedding, AnnembedError>
+where
+    T: Clone + Send + Sync,
+    D: Distance<T> + Clone + Send + Sync,
+    S: Data<Elem = T> + Sync,
+{
+    if data_a.nrows() != data_b.nrows() || data_a.nrows() != ids.len() {
+        return Err(AnnembedError::InvalidParameter(format!(
+            "differential_embed, conditions must be matched, nb rows {} {}, nb ids {}",
+            data_a.nrows(),
+            data_b.nrows(),
+            ids.len()
+        )));
+    }
+    let rank: std::collections::HashMap<DataId, usize> = ids.iter().enumerate().map(|(r, d)| (*d, r)).collect();
+    let anchor_ranks: Vec<usize> = match anchors {
+        Some(anchors) => anchors
+            .iter()
+            .map(|d| {
+                rank.get(d)
+                    .copied()
+                    .ok_or_else(|| AnnembedError::InvalidParameter(format!("anchor {} is not a DataId of data", d)))
+            })
+            .collect::<Result<Vec<usize>, AnnembedError>>()?,
+        None => (0..ids.len()).collect(),
+    };
+    //
+    let result_a = pipeline.run::<T, f64, S>(data_a, Some(ids))?;
+    let embedding_a = reorder_rows(result_a.get_embedding(), result_a.get_data_ids(), ids)?;
+    let result_b = pipeline.run::<T, f64, S>(data_b, Some(ids))?;
+    let embedding_b = reorder_rows(result_b.get_embedding(), result_b.get_data_ids(), ids)?;
+    let (embedding_b, scale) = procrustes_align(&embedding_a, &embedding_b, &anchor_ranks)?;
+    let displacements = &embedding_b - &embedding_a;
+    //
+    let norms: Vec<f64> = displacements.rows().into_iter().map(|r| r.dot(&r).sqrt()).collect();
+    let anchor_rmsd = (anchor_ranks.iter().map(|r| norms[*r] * norms[*r]).sum::<f64>() / anchor_ranks.len() as f64).sqrt();
+    let mut sorted = norms.clone();
+    sorted.sort_unstable_by(|a, b| a.partial_cmp(b).unwrap_or(std::cmp::Ordering::Equal));
+    let stats = DisplacementStats {
+        mean_norm: norms.iter().sum::<f64>() / norms.len() as f64,
+        median_norm: sorted_quantile(&sorted, 0.5),
+        q90_norm: sorted_quantile(&sorted, 0.9),
+        max_norm: sorted[sorted.len() - 1],
+        mean_displacement: displacements.mean_axis(Axis(0)).unwrap(),
+        anchor_rmsd,
+        scale,
+    };
+    log::info!(
+        "differential_embed, mean displacement {:.3e}, q90 {:.3e}, anchor rmsd {:.3e}, scale {:.3e}",
+        stats.mean_norm,
+        stats.q90_norm,
+        stats.anchor_rmsd,
+        scale
+    );
+    Ok(DifferentialEmbedding {
+        data_ids: ids.to_vec(),
+        embedding_a,
+        embedding_b,
+        displacements,
+        stats,
+    })
+} // end of differential_embed
+
+//=======================================================================
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::diffmaps::DiffusionParams;
+    use crate::pipeline::{GraphParams, HnswParams};
+
+    #[test]
+    fn test_procrustes_align() {
+        let a = Array2::<f64>::from_shape_fn((20, 2), |(i, j)| ((i * 7 + j * 3) % 11) as f64 + j as f64);
+        // b is a rotated, reflected, scaled and translated copy of a
+        let (c, s) = (0.6, 0.8);
+        let rotation = ndarray::arr2(&[[c, s], [s, -c]]);
+        let b = a.dot(&rotation) * 3. + &ndarray::arr1(&[5., -2.]);
+        let (aligned, scale) = procrustes_align(&a, &b, &(0..20).collect::<Vec<usize>>()).unwrap();
+        assert!((scale - 1. / 3.).abs() < 1.0E-10);
+        assert!(aligned.iter().zip(a.iter()).all(|(x, y)| (x - y).abs() < 1.0E-8));
+        assert!(procrustes_align(&a, &b, &[]).is_err());
+    } // end of test_procrustes_align
+
+    #[test]
+    fn test_differential_embed() {
+        // 2 clusters, the second one moves away in condition b
+        let data_a = Array2::<f32>::from_shape_fn((200, 3), |(i, j)| {
+            (i % 2) as f32 * 4. + (((i * 5 + j * 7) % 13) as f32) / 13.
+        });
+        let mut data_b = data_a.clone();
+        for i in (1..200).step_by(2) {
+            data_b[[i, 0]] += 6.;
+        }
+        let ids: Vec<DataId> = (0..200).collect();
+        let anchors: Vec<DataId> = (0..200).step_by(2).collect();
+        let mut pipeline = AnnEmbedPipeline::new(DistL2 {});
+        pipeline
+            .set_hnsw(HnswParams {
+                max_nb_connection: 12,
+                ef_construction: 64,
+                ..Default::default()
+            })
+            .set_graph(GraphParams {
+                knbn: Some(8),
+                ..Default::default()
+            })
+            .set_diffusion_maps(DiffusionParams::new(2, Some(1.)));
+        let diff = differential_embed(&pipeline, &data_a, &data_b, &ids, Some(&anchors)).unwrap();
+        assert_eq!(diff.get_displacements().dim(), (200, 2));
+        assert_eq!(diff.get_data_ids(), ids.as_slice());
+        let stats = diff.get_stats();
+        assert!(stats.max_norm >= stats.q90_norm && stats.q90_norm >= stats.median_norm);
+        // mismatched conditions are rejected
+        let res = differential_embed(&pipeline, &data_a, &data_b.slice(ndarray::s![..100, ..]).to_owned(), &ids, None);
+        assert!(matches!(res, Err(AnnembedError::InvalidParameter(_))));
+    } // end of test_differential_embed
+} // end of mod tests
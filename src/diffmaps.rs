@@ -2,28 +2,240 @@
 //!
 //! This module (presently) computes a diffusion embedding for the kernel constructed from nearest neighbours
 //! stored in a Hnsw structure, see in module [embedder](crate::embedder).  
-//! In particular the kernel sets the diagonal to 0 and nearest neighbour weight to 1.
+//! In particular the kernel sets by default the diagonal to 0 (see [SelfEdgeWeight](crate::graphlaplace::SelfEdgeWeight)) and nearest neighbour weight to 1.
+//! Neighbourhoods are by default the k nearest neighbours, an epsilon ball graph as in the convergence results of graph laplacians
+//! can be asked for with [DiffusionParams::set_radius].
+//! The variable bandwidth kernels of Berry-Harlim, adapted to non uniform sampling, can be asked for with
+//! [DiffusionParams::set_variable_bandwidth].  
+//! *Berry T., Harlim J. Variable bandwidth diffusion kernels. Appl. Comput. Harmon. Anal. 2016*
 //!
 //!
 
+use std::fs::OpenOptions;
+use std::io::{BufReader, BufWriter, Write};
+use std::sync::Arc;
+
 use num_traits::cast::FromPrimitive;
 use num_traits::Float;
 
 use hnsw_rs::prelude::*;
-use ndarray::Array2;
+use ndarray::{s, Array2, ArrayBase, Data, Ix2};
+use rayon::prelude::*;
 use ndarray_linalg::Scalar;
+use serde::{Deserialize, Serialize};
+use sprs::CsMat;
 
+use crate::batchcorrect::BatchCorrection;
+use crate::diffclust::{ClusterAssignment, DiffusionClustering, DiffusionClusters};
 use crate::embedder::*;
-use crate::fromhnsw::*;
+use crate::error::AnnembedError;
+use crate::featimportance::{get_laplacian_scores, FeatureSelection, LaplacianScores};
+use crate::fromhnsw::{kgraph::KGraph, *};
 use crate::graphlaplace::*;
+use crate::labelprop::{LabelPropagation, PropagatedLabels, PropagationKernel, PropagationScheme};
 use crate::tools::nodeparam::*;
+use crate::tools::quant::ExactQuantiles;
+use crate::tools::safetensors::SafeTensorsWriter;
+use crate::tools::report::StageProfile;
+use crate::tools::stage::{CancellationToken, EmbeddingObserver, ObserverScope, ProgressMeter, Stage, StageProfiler};
+use crate::tools::threads::install_in_pool;
+
+/// Rescaling of laplacian eigenvectors in spectral embedding.
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum EigenWeighting {
+    /// diffusion maps weighting $\lambda^{t}$, with $\lambda$ eigenvalues of the transition matrix
+    Diffusion,
+    /// commute time (resistance distance) weighting $1/\sqrt{\lambda}$, with $\lambda$ eigenvalues of the normalized laplacian I - G.  
+    /// Euclidean distances in the embedding approximate (up to truncation) commute time distances in the graph.
+    CommuteTime,
+    /// Laplacian eigenmaps : raw eigenvectors of the normalized laplacian, without eigenvalue weighting nor degree rescaling,
+    /// as scikit-learn SpectralEmbedding (without its final degree rescaling).
+    Eigenmaps,
+    /// multi-scale weighting $ \sum_{t \ge 1} \lambda^{t} = \lambda / (1 - \lambda) $ integrating diffusion over all times
+    /// (Coifman-Lafon 2006), it does not need a diffusion time.
+    MultiScale,
+}
+
+/// Strategies to choose diffusion time t when it is not given.  
+/// All strategies work on the computed spectrum, normalized so that the first eigenvalue is 1.
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum TimeSelection {
+    /// the time given
+    Fixed(f32),
+    /// $ (\lambda_{2}/\lambda_{1})^t = 0.9 $, bounded by 5. This is the default
+    SpectralGap,
+    /// t such that eigenvalues beyond the knee of the eigenvalue decay are damped under 0.1
+    Knee,
+    /// knee of the Von Neumann entropy of the diffusion operator as a function of t, as in PHATE.
+    /// See Moon et al. Visualizing structure and transitions in high-dimensional biological data. Nature Biotechnology 2019.
+    VonNeumannEntropy,
+    /// smallest t such that the embedding dimensions retain the given fraction of $ \sum_{k \ge 1} \lambda_{k}^{2t} $
+    RetainedInformation(f64),
+}
+
+/// Strategies to choose the embedding dimension from the spectrum, asked_dim being the maximal dimension.
+/// Both work on the non trivial eigenvalues $\lambda_{1} \ge \lambda_{2} \ge ...$ normalized so that the first eigenvalue is 1.
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum DimSelection {
+    /// dimension d maximizing the relative gap $ (\lambda_{d} - \lambda_{d+1}) / \lambda_{d} $
+    LargestGap,
+    /// largest dimension d such that $ \lambda_{d} / \lambda_{1} $ is greater than the ratio given (in \]0,1\])
+    EigenvalueRatio(f64),
+}
+
+/// default maximal number of neighbours of a node in radius graphs
+const RADIUS_MAX_KNBN: usize = 256;
 
-#[derive(Copy, Clone)]
+/// maximal diffusion time searched by selection strategies other than SpectralGap
+const MAX_DIFFUSION_TIME: f64 = 100.;
+
+/// maximal number of diffusion times of a multi-scale embedding, see [DiffusionParams::set_times]
+pub const MAX_DIFFUSION_TIMES: usize = 8;
+
+/// quantile levels of scales and densities in [DmapStats]
+pub const DMAP_STATS_QUANTILES: [f64; 5] = [0.05, 0.25, 0.5, 0.75, 0.95];
+
+// index of the knee of a curve, the point at maximal distance from the chord joining its ends (Kneedle)
+fn knee_index(values: &[f64]) -> usize {
+    let n = values.len();
+    if n < 3 {
+        return 0;
+    }
+    let (first, last) = (values[0], values[n - 1]);
+    let range = if (last - first).abs() > 0. { last - first } else { 1. };
+    let mut best = (0, f64::MIN);
+    for (k, v) in values.iter().enumerate() {
+        let x = k as f64 / (n - 1) as f64;
+        let y = (v - first) / range;
+        let dist = (x - y).abs();
+        if dist > best.1 {
+            best = (k, dist);
+        }
+    }
+    best.0
+} // end of knee_index
+
+/// returns embedding dimension (between 2 and max_dim) from normalized spectrum (first value 1.) according to selection strategy.
+pub(crate) fn select_dimension(normalized_lambdas: &[f64], max_dim: usize, selection: DimSelection) -> usize {
+    // non trivial eigenvalues, we need one beyond max_dim for gaps
+    let lambdas = &normalized_lambdas[1..normalized_lambdas.len().min(max_dim + 2)];
+    let max_dim = max_dim.min(lambdas.len());
+    let dim = match selection {
+        DimSelection::LargestGap => {
+            let mut best = (max_dim, f64::MIN);
+            for d in 2..=max_dim.min(lambdas.len() - 1) {
+                let gap = if lambdas[d - 1] > 0. { (lambdas[d - 1] - lambdas[d]) / lambdas[d - 1] } else { 0. };
+                if gap > best.1 {
+                    best = (d, gap);
+                }
+            }
+            best.0
+        }
+        DimSelection::EigenvalueRatio(ratio) => lambdas[..max_dim]
+            .iter()
+            .rposition(|l| *l >= ratio * lambdas[0])
+            .map_or(2, |k| k + 1),
+    };
+    let dim = dim.max(2);
+    log::info!("select_dimension {:?}, dimension : {}", selection, dim);
+    dim
+} // end of select_dimension
+
+/// returns diffusion time from normalized spectrum (first value 1.) according to selection strategy.
+pub(crate) fn select_diffusion_time(normalized_lambdas: &[f64], asked_dim: usize, selection: TimeSelection) -> f64 {
+    let bounded = |t: f64| t.max(1.).min(MAX_DIFFUSION_TIME);
+    let time = match selection {
+        TimeSelection::Fixed(t) => t as f64,
+        TimeSelection::SpectralGap => {
+            5.0f64.min(0.9f64.ln() / (normalized_lambdas[2] / normalized_lambdas[1]).ln())
+        }
+        TimeSelection::Knee => {
+            let knee = 1 + knee_index(&normalized_lambdas[1..]);
+            let lambda_knee = normalized_lambdas[knee].min(1. - f64::EPSILON);
+            log::debug!("eigenvalue decay knee at rank {}, value {:.3e}", knee, lambda_knee);
+            bounded(0.1f64.ln() / lambda_knee.ln())
+        }
+        TimeSelection::VonNeumannEntropy => {
+            let entropies: Vec<f64> = (1..=MAX_DIFFUSION_TIME as usize)
+                .map(|t| {
+                    let powers: Vec<f64> = normalized_lambdas.iter().map(|l| l.abs().powi(t as i32)).collect();
+                    let sum = powers.iter().sum::<f64>();
+                    -powers
+                        .iter()
+                        .map(|p| p / sum)
+                        .filter(|p| *p > 0.)
+                        .map(|p| p * p.ln())
+                        .sum::<f64>()
+                })
+                .collect();
+            (1 + knee_index(&entropies)) as f64
+        }
+        TimeSelection::RetainedInformation(fraction) => {
+            let retained = |t: f64| {
+                let powers: Vec<f64> = normalized_lambdas[1..].iter().map(|l| l.abs().powf(2. * t)).collect();
+                let total = powers.iter().sum::<f64>();
+                powers.iter().take(asked_dim).sum::<f64>() / total
+            };
+            let mut t = 0.1;
+            while t < MAX_DIFFUSION_TIME && retained(t) < fraction {
+                t += 0.1;
+            }
+            t.min(MAX_DIFFUSION_TIME)
+        }
+    };
+    log::info!("diffusion time selection {:?}, t = {:.3e}", selection, time);
+    time
+} // end of select_diffusion_time
+
+#[derive(Copy, Clone, Serialize, Deserialize)]
 pub struct DiffusionParams {
     /// dimension of embedding
     asked_dim: usize,
     /// embedding time
     t: Option<f32>,
+    /// strategy to choose time if t is not given
+    time_selection: TimeSelection,
+    /// diffusion times of a multi-scale embedding, the nb_times first ones are used
+    times: [f32; MAX_DIFFUSION_TIMES],
+    nb_times: usize,
+    /// if set, the charge q of the magnetic laplacian used instead of the symetrized laplacian
+    magnetic_q: Option<f32>,
+    /// if true, keep the asymetric transition matrix and compute left/right singular pairs
+    bidiffusion: bool,
+    /// rescaling of eigenvectors, defaults to diffusion weighting
+    weighting: EigenWeighting,
+    /// if true, the kernel scale is chosen by Berry-Giannakis-Harlim criterion instead of 1.
+    auto_scale: bool,
+    /// optional sparsification of the dense kernel before svd
+    sparsification: Option<KernelSparsification>,
+    /// floating point type of svd computations
+    precision: SvdPrecision,
+    /// solver of leading eigen pairs of the laplacian, default to svd
+    solver: EigenSolver,
+    /// randomized svd used for large or csr laplacians
+    approx_svd: ApproxSvdMode,
+    /// seed of randomized svd and eigen solver initialization, default to a fixed seed
+    seed: Option<u64>,
+    /// self edge inclusion and weight in kernel, default to no self edge
+    self_edge: SelfEdgeWeight,
+    /// number of neighbours in kernel, default to hnsw max_nb_connection
+    knbn: Option<usize>,
+    /// if set, neighbourhoods are obtained by a search in hnsw with this ef
+    ef_search: Option<usize>,
+    /// if set, nodes are connected to all neighbours within this radius
+    radius: Option<f32>,
+    /// if set, edges are reweighted by shared nearest neighbours, edges with a lower jaccard index are dropped
+    snn: Option<f32>,
+    /// if set, edge distances are rescaled to reduce hubness before kernel construction
+    hubness_reduction: Option<hubness::HubnessReduction>,
+    /// if set, exponent beta of the variable bandwidth kernel
+    variable_bandwidth: Option<f32>,
+    /// if set, the dimension is chosen from the spectrum, asked_dim being the maximal one
+    dim_selection: Option<DimSelection>,
+    /// if set, number of threads of the pool running the embedding instead of the pool of the caller
+    num_threads: Option<usize>,
+    /// if true, the laplacian of the last embedding is kept for feature scoring and label propagation
+    keep_laplacian: bool,
 } // end of DiffusionParams
 
 impl DiffusionParams {
@@ -31,23 +243,297 @@ impl DiffusionParams {
         DiffusionParams {
             asked_dim,
             t: t_opt,
+            time_selection: TimeSelection::SpectralGap,
+            times: [0.; MAX_DIFFUSION_TIMES],
+            nb_times: 0,
+            magnetic_q: None,
+            bidiffusion: false,
+            weighting: EigenWeighting::Diffusion,
+            auto_scale: false,
+            sparsification: None,
+            precision: SvdPrecision::F32,
+            solver: EigenSolver::Svd,
+            approx_svd: ApproxSvdMode::default(),
+            seed: None,
+            self_edge: SelfEdgeWeight::None,
+            knbn: None,
+            ef_search: None,
+            radius: None,
+            snn: None,
+            hubness_reduction: None,
+            variable_bandwidth: None,
+            dim_selection: None,
+            num_threads: None,
+            keep_laplacian: false,
         }
     }
+    /// use the magnetic laplacian with charge q (between 0. and 0.25) to keep track of edge directions of the neighbour graph.
+    /// See [get_magnetic_dmap_embedding]
+    pub fn set_magnetic_q(&mut self, q: f32) {
+        self.magnetic_q = Some(q);
+    }
+    /// get magnetic charge if magnetic laplacian is asked for
+    pub fn get_magnetic_q(&self) -> Option<f32> {
+        self.magnetic_q
+    }
+    /// keep the directed knn graph without symetrization and use its singular pairs (bi-diffusion).
+    /// See [get_bidiffusion_embedding]
+    pub fn set_bidiffusion(&mut self, bidiffusion: bool) {
+        self.bidiffusion = bidiffusion;
+    }
+    /// returns true if bi-diffusion is asked for
+    pub fn get_bidiffusion(&self) -> bool {
+        self.bidiffusion
+    }
+    /// set eigenvector weighting (used with the symetrized laplacian)
+    pub fn set_weighting(&mut self, weighting: EigenWeighting) {
+        self.weighting = weighting;
+    }
+    /// get eigenvector weighting
+    pub fn get_weighting(&self) -> EigenWeighting {
+        self.weighting
+    }
+    /// set to true to select kernel scale from data (Berry-Giannakis-Harlim). Default is a fixed scale factor 1.
+    pub fn set_auto_scale(&mut self, auto_scale: bool) {
+        self.auto_scale = auto_scale;
+    }
+    /// sparsify the dense kernel before svd to reduce memory and svd time. See [KernelSparsification]  
+    /// Returns an error for [KernelSparsification::TopK] with k = 0.
+    pub fn set_kernel_sparsification(&mut self, sparsification: KernelSparsification) -> Result<(), AnnembedError> {
+        sparsification.check()?;
+        self.sparsification = Some(sparsification);
+        Ok(())
+    }
+    /// set floating type used in svd, independently of input distances and output coordinates types. Default to f32.  
+    /// Magnetic and bi-diffusion modes always use f32.
+    pub fn set_svd_precision(&mut self, precision: SvdPrecision) {
+        self.precision = precision;
+    }
+    /// set the solver of leading eigen pairs of the laplacian, see [EigenSolver]. Default to [EigenSolver::Svd].  
+    /// Magnetic and bi-diffusion modes always use svd.
+    pub fn set_eigen_solver(&mut self, solver: EigenSolver) {
+        self.solver = solver;
+    }
+    /// get solver of eigen pairs
+    pub fn get_eigen_solver(&self) -> EigenSolver {
+        self.solver
+    }
+    /// set the randomized svd (rank and subspace iterations or adaptative precision) used for laplacians
+    /// too large for a full svd, see [ApproxSvdMode]
+    pub fn set_approx_svd(&mut self, mode: ApproxSvdMode) {
+        self.approx_svd = mode;
+    }
+    /// get randomized svd mode
+    pub fn get_approx_svd(&self) -> ApproxSvdMode {
+        self.approx_svd
+    }
+    /// set the seed of randomized svd and lobpcg initialization. Without seed a fixed default seed is used
+    /// so that runs are reproducible, changing the seed gives another randomization.
+    pub fn set_seed(&mut self, seed: u64) {
+        self.seed = Some(seed);
+    }
+    /// get seed if one was set
+    pub fn get_seed(&self) -> Option<u64> {
+        self.seed
+    }
+    /// set self edge weight added to the kernel. See [SelfEdgeWeight]
+    pub fn set_self_edge(&mut self, self_edge: SelfEdgeWeight) {
+        self.self_edge = self_edge;
+    }
+    /// get self edge weight
+    pub fn get_self_edge(&self) -> SelfEdgeWeight {
+        self.self_edge
+    }
+    /// set the number of neighbours used in the kernel, independently of hnsw max_nb_connection.
+    /// If it is larger than max_nb_connection an ef_search should be given.
+    pub fn set_knbn(&mut self, knbn: usize) {
+        self.knbn = Some(knbn);
+    }
+    /// neighbourhoods are obtained by a knn search of each point in the hnsw with the given ef_search
+    /// instead of being extracted from the hnsw structure. See [kgraph_from_hnsw_search]
+    pub fn set_ef_search(&mut self, ef_search: usize) {
+        self.ef_search = Some(ef_search);
+    }
+    /// connect each node to all its neighbours within radius (epsilon ball graph) instead of a fixed number of neighbours.
+    /// knbn is then the maximal number of neighbours of a node. See [kgraph_from_hnsw_radius]
+    pub fn set_radius(&mut self, radius: f32) {
+        self.radius = Some(radius);
+    }
+    /// reweight edges by shared nearest neighbours before kernel construction, dropping edges with jaccard index
+    /// below min_jaccard (each node keeps its best neighbour). See [KGraph::to_snn_graph](crate::fromhnsw::kgraph::KGraph::to_snn_graph)
+    pub fn set_snn(&mut self, min_jaccard: f32) {
+        self.snn = Some(min_jaccard);
+    }
+    /// rescale edge distances to reduce hubness before kernel construction (and after snn reweighting if any).
+    /// See [Hubness::reduce](crate::fromhnsw::hubness::Hubness::reduce)
+    pub fn set_hubness_reduction(&mut self, reduction: hubness::HubnessReduction) {
+        self.hubness_reduction = Some(reduction);
+    }
+    /// use the variable bandwidth kernel of Berry-Harlim with bandwidth $ \rho = q^{\beta} $, q being a density estimate,
+    /// instead of the fixed local scaling. beta = -0.5 is the choice of Berry-Harlim. See [variable_bandwidth_edges]
+    pub fn set_variable_bandwidth(&mut self, beta: f32) {
+        self.variable_bandwidth = Some(beta);
+    }
+    /// exponent beta of the variable bandwidth kernel if asked for
+    pub fn get_variable_bandwidth(&self) -> Option<f32> {
+        self.variable_bandwidth
+    }
     /// get embedding time
     pub fn get_t(&self) -> Option<f32> {
         self.t
     }
-    ///
+    /// concatenates embeddings at each of the given diffusion times (at most [MAX_DIFFUSION_TIMES]), giving
+    /// asked_dim coordinates by time. Only used with [EigenWeighting::Diffusion] and the symetrized laplacian,
+    /// an empty slice goes back to a single time. Returns an error if there are more than [MAX_DIFFUSION_TIMES] times.
+    pub fn set_times(&mut self, times: &[f32]) -> Result<(), AnnembedError> {
+        if times.len() > MAX_DIFFUSION_TIMES {
+            log::error!(
+                "DiffusionParams::set_times, got {} times, at most {} are possible",
+                times.len(),
+                MAX_DIFFUSION_TIMES
+            );
+            return Err(AnnembedError::InvalidParameter(format!(
+                "{} diffusion times, at most {} are possible",
+                times.len(),
+                MAX_DIFFUSION_TIMES
+            )));
+        }
+        self.nb_times = times.len();
+        self.times[..self.nb_times].copy_from_slice(times);
+        Ok(())
+    }
+    /// diffusion times of a multi-scale embedding, empty if a single time is used
+    pub fn get_times(&self) -> &[f32] {
+        &self.times[..self.nb_times]
+    }
+    /// set the strategy used to choose diffusion time when no time is given. Default is [TimeSelection::SpectralGap]
+    pub fn set_time_selection(&mut self, selection: TimeSelection) {
+        self.time_selection = selection;
+    }
+    /// returns the time selection in use, Fixed if a time was given
+    pub fn get_time_selection(&self) -> TimeSelection {
+        match self.t {
+            Some(t) => TimeSelection::Fixed(t),
+            None => self.time_selection,
+        }
+    }
+    /// choose the embedding dimension from the decay of the spectrum, asked_dim is then the maximal dimension.
+    /// The dimension chosen is given by [DiffusionMaps::get_embedding_dim]. Only used with the symetrized laplacian.
+    pub fn set_dim_selection(&mut self, selection: DimSelection) {
+        self.dim_selection = Some(selection);
+    }
+    /// returns the dimension selection strategy if any
+    pub fn get_dim_selection(&self) -> Option<DimSelection> {
+        self.dim_selection
+    }
+    /// returns the asked dimension (maximal dimension with a dimension selection)
     pub fn get_embedding_dimension(&self) -> usize {
         return self.asked_dim;
     }
+    /// runs embeddings in a rayon pool of nb_threads threads (graph extraction from hnsw included)
+    /// instead of the pool of the caller, see [install_in_pool]
+    pub fn set_num_threads(&mut self, nb_threads: usize) {
+        self.num_threads = Some(nb_threads);
+    }
+    /// returns the number of threads asked for, None if embeddings run in the pool of the caller
+    pub fn get_num_threads(&self) -> Option<usize> {
+        self.num_threads
+    }
+    /// set to true to keep the laplacian of the last embedding, needed by [DiffusionMaps::get_laplacian_scores] and
+    /// [DiffusionMaps::label_propagation]. Default is false as the laplacian can be large.
+    pub fn set_keep_laplacian(&mut self, keep: bool) {
+        self.keep_laplacian = keep;
+    }
+    /// returns true if the laplacian of the last embedding is kept
+    pub fn get_keep_laplacian(&self) -> bool {
+        self.keep_laplacian
+    }
 } // end of DiffusionParams
 
+/// Diagnostics of the last embedding of a [DiffusionMaps], see [DiffusionMaps::get_stats].  
+/// Quantiles are given at levels [DMAP_STATS_QUANTILES].
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct DmapStats {
+    /// quantiles of node scales (mean distance to first neighbours), None for an embedding of an affinity matrix
+    pub scale_quantiles: Option<Vec<f32>>,
+    /// quantiles of kernel density estimates, see [DiffusionMaps::get_density]
+    pub density_quantiles: Vec<f32>,
+    /// eigenvalues of the symetric laplacian, None with magnetic or bi-diffusion embeddings
+    pub eigenvalues: Option<Vec<f64>>,
+    /// diffusion time used, None with commute time weighting
+    pub time: Option<f64>,
+    /// dimension of the embedding (without multi-scale concatenation), see [DimSelection]
+    pub dim: Option<usize>,
+} // end of DmapStats
+
+/// Spectrum of the last embedding of a [DiffusionMaps] with rows of eigenvectors sorted by DataId, see [DiffusionMaps::get_spectrum].
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct DmapSpectrum {
+    /// DataId of each row of eigenvectors, increasing
+    pub data_ids: Vec<DataId>,
+    /// eigenvalues of the symetric laplacian normalized so that the first (stationary) one is 1, decreasing
+    pub eigenvalues: Vec<f64>,
+    /// eigenvectors in columns (the stationary one and asked_dim following ones), row i is the node with DataId data_ids\[i\]
+    pub eigenvectors: Array2<f32>,
+    /// diffusion time used, None without diffusion weighting
+    pub time: Option<f64>,
+    /// spectral gap $ 1 - \lambda_{1} $ of normalized eigenvalues
+    pub spectral_gap: f64,
+} // end of DmapSpectrum
+
+// quantiles of values at levels DMAP_STATS_QUANTILES
+fn stats_quantiles(values: &[f32]) -> Vec<f32> {
+    let mut quant = ExactQuantiles::<f32>::new(0.);
+    for v in values {
+        quant.insert(*v);
+    }
+    DMAP_STATS_QUANTILES.iter().map(|q| quant.query(*q).unwrap().1).collect()
+}
+
+// fitted state of DiffusionMaps with the coordinates of its last embedding, see DiffusionMaps::dump
+#[derive(Serialize, Deserialize)]
+struct DiffusionMapsDump {
+    params: DiffusionParams,
+    node_params: Option<NodeParams>,
+    target_embedding: Option<Array2<f64>>,
+    time: Option<f64>,
+    data_ids: Option<Vec<DataId>>,
+    eigenvalues: Option<Vec<f64>>,
+    eigenvectors: Option<Array2<f32>>,
+    embedding: Array2<f64>,
+} // end of DiffusionMapsDump
+
 pub struct DiffusionMaps {
     /// parameters to use
     params: DiffusionParams,
     /// node parameters coming from graph transformation
     _node_params: Option<NodeParams>,
+    /// in bi-diffusion mode, the embedding of nodes as targets of edges (right singular vectors)
+    target_embedding: Option<Array2<f64>>,
+    /// diffusion time used in last embedding
+    time: Option<f64>,
+    /// DataId of each row of last embedding
+    data_ids: Option<Vec<DataId>>,
+    /// eigenvalues of the symetric laplacian computed in last embedding (decreasing)
+    eigenvalues: Option<Vec<f64>>,
+    /// eigenvectors of the symetric laplacian of last embedding, the stationary one and the asked_dim following ones
+    eigenvectors: Option<Array2<f32>>,
+    /// optional correction of a categorical covariate
+    batch_correction: Option<BatchCorrection>,
+    /// laplacian of last embedding, kept for feature scoring if asked for
+    laplacian: Option<GraphLaplacian>,
+    /// kernel density estimate of nodes of last embedding
+    density: Option<Vec<f32>>,
+    /// scales of nodes of last embedding, if computed from a graph
+    scales: Option<Vec<f32>>,
+    /// dimension of last embedding
+    dim: Option<usize>,
+    /// time and memory of the stages of last embedding
+    profile: Option<Vec<StageProfile>>,
+    /// observer of the stages of embeddings
+    observer: Option<Arc<dyn EmbeddingObserver>>,
+    /// token stopping the randomized svd
+    cancellation: Option<CancellationToken>,
 } // end of DiffusionMaps
 
 impl DiffusionMaps {
@@ -56,30 +542,687 @@ impl DiffusionMaps {
         DiffusionMaps {
             params,
             _node_params: None,
+            target_embedding: None,
+            time: None,
+            data_ids: None,
+            eigenvalues: None,
+            eigenvectors: None,
+            batch_correction: None,
+            laplacian: None,
+            density: None,
+            scales: None,
+            dim: None,
+            profile: None,
+            observer: None,
+            cancellation: None,
+        }
+    }
+
+    /// sets the observer notified of the stages of embeddings run by this structure, see [EmbeddingObserver]
+    pub fn set_observer<O>(&mut self, observer: O)
+    where
+        O: EmbeddingObserver + 'static,
+    {
+        self.observer = Some(Arc::new(observer));
+    }
+
+    /// sets a token stopping the randomized svd of embeddings run by this structure.
+    /// A cancelled embedding returns [AnnembedError::Cancelled].
+    pub fn set_cancellation_token(&mut self, token: &CancellationToken) {
+        self.cancellation = Some(token.clone());
+    }
+
+    /// sets a batch covariate, indexed by node rank in the graph to embed, corrected in the returned coordinates
+    /// (not in eigenvectors). See [batchcorrect](crate::batchcorrect).
+    pub fn set_batch_correction(&mut self, correction: BatchCorrection) {
+        self.batch_correction = Some(correction);
+    }
+
+    /// In bi-diffusion mode, [embed_hnsw](Self::embed_hnsw) returns the embedding of nodes as sources of edges.
+    /// This function returns the embedding of nodes as targets, after a call to embed_hnsw.
+    pub fn get_target_embedding(&self) -> Option<&Array2<f64>> {
+        self.target_embedding.as_ref()
+    }
+
+    /// returns the eigenvalues (decreasing, first is the stationary one) of the symetric laplacian computed in last embedding.  
+    /// None before embedding or with magnetic or bi-diffusion embeddings.
+    pub fn get_eigenvalues(&self) -> Option<&Vec<f64>> {
+        self.eigenvalues.as_ref()
+    }
+
+    /// returns the eigenvectors (in columns, by decreasing eigenvalues) of the symetric laplacian computed in last embedding,
+    /// the stationary one followed by asked_dim ones. Rows are ordered as in [get_data_ids](Self::get_data_ids).  
+    /// Eigenvectors of the random walk laplacian are obtained by dividing rows by the square root of degrees.
+    /// None as for [get_eigenvalues](Self::get_eigenvalues).
+    pub fn get_eigenvectors(&self) -> Option<&Array2<f32>> {
+        self.eigenvectors.as_ref()
+    }
+
+    /// dumps an embedding returned by this structure in safetensors format, see [safetensors](crate::tools::safetensors).  
+    /// Tensors are *embedding* (F32), *data_ids* (U64), and if available *eigenvalues* (F64), *eigenvectors* (F32)
+    /// and *target_embedding* (F32, bi-diffusion mode). Diffusion time is stored as metadata.
+    pub fn dump_safetensors<G: Float>(&self, path: &std::path::Path, embedded: &Array2<G>) -> Result<(), AnnembedError> {
+        let mut writer = SafeTensorsWriter::new();
+        writer.add_array2_f32("embedding", embedded)?;
+        if let Some(data_ids) = self.data_ids.as_ref() {
+            if data_ids.len() != embedded.nrows() {
+                return Err(AnnembedError::InvalidParameter(format!(
+                    "dump_safetensors, embedding has {} rows, last embedding had {}",
+                    embedded.nrows(),
+                    data_ids.len()
+                )));
+            }
+            writer.add_u64("data_ids", data_ids)?;
+        }
+        if let Some(eigenvalues) = self.eigenvalues.as_ref() {
+            writer.add_array1_f64("eigenvalues", eigenvalues)?;
+        }
+        if let Some(eigenvectors) = self.eigenvectors.as_ref() {
+            writer.add_array2_f32("eigenvectors", eigenvectors)?;
+        }
+        if let Some(target) = self.target_embedding.as_ref() {
+            writer.add_array2_f32("target_embedding", target)?;
+        }
+        writer.add_metadata("format", "annembed");
+        writer.add_metadata("method", "diffusion maps");
+        if let Some(time) = self.time {
+            writer.add_metadata("diffusion_time", &time.to_string());
         }
+        writer.write(path)
+    } // end of dump_safetensors
+
+    /// dumps in bincode format the parameters, the spectrum (eigenvalues and eigenvectors), diffusion time and DataIds of the last
+    /// embedding with its coordinates embedded (as returned by the embedding), see [reload](Self::reload).  
+    /// The laplacian and batch correction are not dumped, so laplacian scores need a new embedding.
+    pub fn dump<G: Float>(&self, path: &std::path::Path, embedded: &Array2<G>) -> Result<(), AnnembedError> {
+        if self.data_ids.as_ref().is_some_and(|ids| ids.len() != embedded.nrows()) {
+            return Err(AnnembedError::InvalidParameter(format!(
+                "DiffusionMaps::dump, embedding has {} rows, last embedding had {}",
+                embedded.nrows(),
+                self.data_ids.as_ref().unwrap().len()
+            )));
+        }
+        let to_dump = DiffusionMapsDump {
+            params: self.params,
+            node_params: self._node_params.clone(),
+            target_embedding: self.target_embedding.clone(),
+            time: self.time,
+            data_ids: self.data_ids.clone(),
+            eigenvalues: self.eigenvalues.clone(),
+            eigenvectors: self.eigenvectors.clone(),
+            embedding: embedded.mapv(|x| x.to_f64().unwrap()),
+        };
+        let mut writer = BufWriter::new(OpenOptions::new().write(true).create(true).truncate(true).open(path)?);
+        bincode::serialize_into(&mut writer, &to_dump).map_err(|e| AnnembedError::Io(std::io::Error::other(e)))?;
+        writer.flush()?;
+        log::info!("diffusion maps dumped in {}", path.display());
+        Ok(())
+    } // end of dump
+
+    /// reloads a DiffusionMaps dumped by [dump](Self::dump) and the coordinates of its embedding, rows being DataIds
+    /// of [get_data_ids](Self::get_data_ids).
+    pub fn reload(path: &std::path::Path) -> Result<(Self, Array2<f64>), AnnembedError> {
+        let reader = BufReader::new(OpenOptions::new().read(true).open(path)?);
+        let dumped: DiffusionMapsDump =
+            bincode::deserialize_from(reader).map_err(|e| AnnembedError::Io(std::io::Error::other(e)))?;
+        if dumped.data_ids.as_ref().is_some_and(|ids| ids.len() != dumped.embedding.nrows()) {
+            return Err(AnnembedError::InvalidParameter(String::from(
+                "DiffusionMaps::reload inconsistent number of rows and ids",
+            )));
+        }
+        let dim = dumped.embedding.ncols() / dumped.params.get_times().len().max(1);
+        let dmaps = DiffusionMaps {
+            params: dumped.params,
+            _node_params: dumped.node_params,
+            target_embedding: dumped.target_embedding,
+            time: dumped.time,
+            data_ids: dumped.data_ids,
+            eigenvalues: dumped.eigenvalues,
+            eigenvectors: dumped.eigenvectors,
+            batch_correction: None,
+            laplacian: None,
+            density: None,
+            scales: None,
+            dim: Some(dim),
+            profile: None,
+            observer: None,
+            cancellation: None,
+        };
+        Ok((dmaps, dumped.embedding))
+    } // end of reload
+
+    /// Laplacian scores of features (columns of data, rows indexed by DataId) computed with the laplacian of the last
+    /// embedding, and selection of features. See [laplacian_scores_kgraph](crate::featimportance::laplacian_scores_kgraph).  
+    /// Fails before embedding, if the laplacian is not kept (see [DiffusionParams::set_keep_laplacian]) or after magnetic
+    /// or bi-diffusion embeddings.
+    pub fn get_laplacian_scores<S, T>(
+        &self,
+        data: &ArrayBase<S, Ix2>,
+        selection: FeatureSelection,
+    ) -> Result<LaplacianScores, AnnembedError>
+    where
+        S: Data<Elem = T> + Sync,
+        T: Float + Send + Sync,
+    {
+        match (self.laplacian.as_ref(), self.data_ids.as_ref()) {
+            (Some(laplacian), Some(data_ids)) => get_laplacian_scores(laplacian, data_ids, data, selection),
+            _ => Err(AnnembedError::InvalidParameter(String::from(
+                "get_laplacian_scores, no laplacian kept from last embedding, see DiffusionParams::set_keep_laplacian",
+            ))),
+        }
+    } // end of get_laplacian_scores
+
+    /// semi-supervised classification : propagates classes of seeds, given as (DataId, class), to all nodes of the last
+    /// embedding on the laplacian it used, see [PropagationScheme]. Nodes are indexed as rows of the embedding
+    /// (see [get_data_ids](Self::get_data_ids)) and the classes of the result are the classes of seeds in increasing order.  
+    /// Fails before embedding, if the laplacian is not kept (see [DiffusionParams::set_keep_laplacian]), after magnetic
+    /// or bi-diffusion embeddings, or if a seed DataId is not embedded.
+    pub fn label_propagation(
+        &self,
+        seeds: &[(DataId, usize)],
+        scheme: PropagationScheme,
+    ) -> Result<PropagatedLabels<usize>, AnnembedError> {
+        match (self.laplacian.as_ref(), self.data_ids.as_ref()) {
+            (Some(laplacian), Some(data_ids)) => {
+                LabelPropagation::new(PropagationKernel::Diffusion).propagate_laplacian(laplacian, data_ids, seeds, scheme)
+            }
+            _ => Err(AnnembedError::InvalidParameter(String::from(
+                "label_propagation, no laplacian kept from last embedding, see DiffusionParams::set_keep_laplacian",
+            ))),
+        }
+    } // end of label_propagation
+
+    /// discrete clusters of an embedding returned by the last call to embed_kgraph or embed_hnsw, obtained by rotating the
+    /// first eigenvectors to near indicator vectors, see [ClusterAssignment::Rotation](crate::diffclust::ClusterAssignment::Rotation).  
+    /// Without nb_clusters it is chosen by the eigengap, up to the number of eigenvectors stored (embedding dimension + 1).
+    pub fn cluster_by_rotation<G: Float>(
+        &self,
+        embedded: &Array2<G>,
+        nb_clusters: Option<usize>,
+    ) -> Result<DiffusionClusters, AnnembedError> {
+        let mut clustering = DiffusionClustering::new((self.params.asked_dim + 1).max(2));
+        clustering.set_assignment(ClusterAssignment::Rotation);
+        if let Some(nb_clusters) = nb_clusters {
+            clustering.set_nb_clusters(nb_clusters);
+        }
+        clustering.cluster_embedding(self, embedded)
+    } // end of cluster_by_rotation
+
+    /// returns the spectral gap $\lambda_{0} - \lambda_{1}$ of the last embedding, see [get_eigenvalues](Self::get_eigenvalues)
+    pub fn get_spectral_gap(&self) -> Option<f64> {
+        match self.eigenvalues.as_ref() {
+            Some(lambdas) if lambdas.len() >= 2 => Some(lambdas[0] - lambdas[1]),
+            _ => None,
+        }
+    }
+
+    /// returns the dimension of the last embedding, chosen from the spectrum if a [DimSelection] is set.
+    /// With multi-scale times the embedding has this number of coordinates by time.
+    pub fn get_embedding_dim(&self) -> Option<usize> {
+        self.dim
+    }
+
+    /// returns the diffusion time used in last embedding (None before embedding or with commute time weighting)
+    pub fn get_diffusion_time(&self) -> Option<f64> {
+        self.time
     }
 
+    /// returns the DataId of each row of the last embedding, rows being ordered as nodes of the neighbourhood graph.
+    pub fn get_data_ids(&self) -> Option<&Vec<DataId>> {
+        self.data_ids.as_ref()
+    }
+
+    /// returns for each node of the last embedding its DataId and its kernel density estimate, the degree
+    /// $ q_{i} = \sum_{j} (K_{ij} + K_{ji})/2 $ of the symetrized kernel before any batch balancing or self edge.
+    /// As kernel rows are normalized, it is 1 on average and is larger for nodes in dense regions, appearing
+    /// in many neighbourhoods. None before embedding or after a reload.
+    pub fn get_density(&self) -> Option<Vec<(DataId, f32)>> {
+        match (self.data_ids.as_ref(), self.density.as_ref()) {
+            (Some(data_ids), Some(density)) => Some(data_ids.iter().copied().zip(density.iter().copied()).collect()),
+            _ => None,
+        }
+    }
+
+    /// returns time and peak resident memory of the stages (graph, node_params, laplacian, svd) of the last embedding,
+    /// in order of completion. None before embedding or after a reload.
+    pub fn get_profile(&self) -> Option<&Vec<StageProfile>> {
+        self.profile.as_ref()
+    }
+
+    /// returns the diagnostics of the last embedding (quantiles of scales and densities, spectrum and diffusion time).
+    /// None before embedding or after a reload.
+    pub fn get_stats(&self) -> Option<DmapStats> {
+        let density = self.density.as_ref().filter(|d| !d.is_empty())?;
+        Some(DmapStats {
+            scale_quantiles: self.scales.as_ref().map(|s| stats_quantiles(s)),
+            density_quantiles: stats_quantiles(density),
+            eigenvalues: self.eigenvalues.clone(),
+            time: self.time,
+            dim: self.dim,
+        })
+    } // end of get_stats
+
+    /// returns normalized eigenvalues, eigenvectors with rows sorted by DataId, diffusion time and spectral gap of the last embedding.  
+    /// None before embedding or with magnetic or bi-diffusion embeddings.
+    pub fn get_spectrum(&self) -> Option<DmapSpectrum> {
+        let eigenvalues = self.eigenvalues.as_ref().filter(|l| l.len() >= 2 && l[0] > 0.)?;
+        let eigenvectors = self.eigenvectors.as_ref()?;
+        let data_ids = self.data_ids.as_ref().filter(|d| d.len() == eigenvectors.nrows())?;
+        let mut order: Vec<usize> = (0..data_ids.len()).collect();
+        order.sort_unstable_by_key(|i| data_ids[*i]);
+        let eigenvalues: Vec<f64> = eigenvalues.iter().map(|l| l / eigenvalues[0]).collect();
+        Some(DmapSpectrum {
+            data_ids: order.iter().map(|i| data_ids[*i]).collect(),
+            spectral_gap: 1. - eigenvalues[1],
+            eigenvalues,
+            eigenvectors: eigenvectors.select(ndarray::Axis(0), &order),
+            time: self.time,
+        })
+    } // end of get_spectrum
+
     /// do the whole work chain : hnsw construction, graph conversion, NodeParams transformation
     /// T is the type on which distances in Hnsw are computed,  
-    /// F is f32 or f64 depending on how diffusions Maps is to be computed.
-    pub fn embed_hnsw<T, D, F>(&mut self, hnsw: &Hnsw<T, D>) -> Array2<F>
+    /// F is f32 or f64, it is the type of graph distances and of the returned coordinates.
+    /// The type used in svd is set by [DiffusionParams::set_svd_precision].
+    pub fn embed_hnsw<T, D, F>(&mut self, hnsw: &Hnsw<T, D>) -> Result<Array2<F>, AnnembedError>
     where
         D: Distance<T> + Send + Sync,
         T: Clone + Send + Sync,
         F: Float + FromPrimitive + std::marker::Sync + Send + std::fmt::UpperExp + std::iter::Sum,
+    {
+        self.embed_hnsw_typed::<T, D, F, F>(hnsw)
+    }
+
+    /// same as [embed_hnsw](Self::embed_hnsw) with independent types :
+    /// F is the type of distances in the neighbourhood graph, G the type of output coordinates.
+    pub fn embed_hnsw_typed<T, D, F, G>(&mut self, hnsw: &Hnsw<T, D>) -> Result<Array2<G>, AnnembedError>
+    where
+        D: Distance<T> + Send + Sync,
+        T: Clone + Send + Sync,
+        F: Float + FromPrimitive + std::marker::Sync + Send + std::fmt::UpperExp + std::iter::Sum,
+        G: Float + FromPrimitive + Send,
     {
         //
-        let knbn = hnsw.get_max_nb_connection();
-        let kgraph = kgraph_from_hnsw_all::<T, D, F>(hnsw, knbn as usize).unwrap();
+        let profiler = StageProfiler::start();
+        let _observer = ObserverScope::enter(self.observer.clone());
+        let embedded = install_in_pool(self.params.num_threads, || {
+            let kgraph = kgraph_from_hnsw_params::<T, D, F>(hnsw, self.params.knbn, self.params.ef_search, self.params.radius)?;
+            self.embed_kgraph_in_pool::<F, G>(&kgraph)
+        })?;
+        self.profile = Some(profiler.get_profiles());
+        embedded
+    } // end of embed_hnsw_typed
+
+    /// embeds a neighbourhood graph, for example a class conditional graph obtained by
+    /// [KGraph::filter_by_label](crate::fromhnsw::kgraph::KGraph::filter_by_label)
+    /// or a precomputed knn graph given by [KGraph::from_knn_arrays](crate::fromhnsw::kgraph::KGraph::from_knn_arrays),
+    /// in which case no Hnsw structure is needed.  
+    /// Rows of result are indexed by node rank in the graph, see [get_data_ids](Self::get_data_ids).
+    pub fn embed_kgraph<F>(&mut self, kgraph: &KGraph<F>) -> Result<Array2<F>, AnnembedError>
+    where
+        F: Float + FromPrimitive + std::marker::Sync + Send + std::fmt::UpperExp + std::iter::Sum,
+    {
+        self.embed_kgraph_typed::<F, F>(kgraph)
+    }
+
+    /// same as [embed_kgraph](Self::embed_kgraph) with output coordinates of type G
+    pub fn embed_kgraph_typed<F, G>(&mut self, kgraph: &KGraph<F>) -> Result<Array2<G>, AnnembedError>
+    where
+        F: Float + FromPrimitive + std::marker::Sync + Send + std::fmt::UpperExp + std::iter::Sum,
+        G: Float + FromPrimitive + Send,
+    {
+        let profiler = StageProfiler::start();
+        let _observer = ObserverScope::enter(self.observer.clone());
+        let embedded = install_in_pool(self.params.num_threads, || self.embed_kgraph_in_pool::<F, G>(kgraph))?;
+        self.profile = Some(profiler.get_profiles());
+        embedded
+    } // end of embed_kgraph_typed
+
+    // embed_kgraph_typed once in the pool asked for by DiffusionParams::set_num_threads
+    fn embed_kgraph_in_pool<F, G>(&mut self, kgraph: &KGraph<F>) -> Result<Array2<G>, AnnembedError>
+    where
+        F: Float + FromPrimitive + std::marker::Sync + Send + std::fmt::UpperExp + std::iter::Sum,
+        G: Float + FromPrimitive + Send,
+    {
+        self.data_ids = Some(
+            (0..kgraph.get_nb_nodes())
+                .map(|i| *kgraph.get_data_id_from_idx(i).unwrap())
+                .collect(),
+        );
+        let snn_graph;
+        let kgraph = match self.params.snn {
+            Some(min_jaccard) => {
+                snn_graph = kgraph.to_snn_graph(min_jaccard);
+                &snn_graph
+            }
+            None => kgraph,
+        };
+        let reduced_graph;
+        let kgraph = match self.params.hubness_reduction {
+            Some(reduction) => {
+                reduced_graph = hubness::Hubness::new(kgraph).reduce(reduction);
+                &reduced_graph
+            }
+            None => kgraph,
+        };
         // get NodeParams. CAVEAT to_proba_edges apply initial shift!!
-        let nodeparams = to_proba_edges::<F>(&kgraph, 1., 2.);
-        let embedded =
-            get_dmap_embedding::<F>(&nodeparams, self.params.asked_dim, self.params.get_t());
+        let scale_rho = if self.params.auto_scale && self.params.variable_bandwidth.is_none() {
+            get_bgh_scale_rho(kgraph, 2.)
+        } else {
+            1.
+        };
+        let mut nodeparams = match self.params.variable_bandwidth {
+            Some(beta) => variable_bandwidth_edges(kgraph, beta)?,
+            None => to_proba_edges::<F>(kgraph, scale_rho, 2.)?,
+        };
+        self.density = Some(kernel_density(&nodeparams));
+        self.scales = Some(nodeparams.params.iter().map(|p| p.scale).collect());
+        if let Some(correction) = self.batch_correction.as_ref().filter(|c| c.do_kernel_balance()) {
+            correction.balance(&mut nodeparams)?;
+        }
+        self.eigenvalues = None;
+        self.eigenvectors = None;
+        self.laplacian = None;
+        let symetric_only = !self.params.get_times().is_empty() || self.params.dim_selection.is_some();
+        if symetric_only && (self.params.get_bidiffusion() || self.params.get_magnetic_q().is_some()) {
+            log::error!("embed_kgraph, multi-scale times and dimension selection are only possible with the symetrized laplacian");
+            return Err(AnnembedError::InvalidParameter(String::from(
+                "multi-scale times and dimension selection are not possible with magnetic and bi-diffusion embeddings",
+            )));
+        }
+        if self.params.get_bidiffusion() {
+            let (mut source, target, time) = get_bidiffusion_embedding::<G>(
+                &nodeparams,
+                self.params.asked_dim,
+                self.params.get_time_selection(),
+            )?;
+            let mut target = target.mapv(|x| x.to_f64().unwrap());
+            if let Some(correction) = self.batch_correction.as_ref().filter(|c| c.do_regression()) {
+                correction.regress(&mut source)?;
+                correction.regress(&mut target)?;
+            }
+            self.target_embedding = Some(target);
+            self.time = Some(time);
+            self.dim = Some(self.params.asked_dim);
+            return Ok(source);
+        }
+        let (mut embedded, time) = match self.params.get_magnetic_q() {
+            Some(q) => get_magnetic_dmap_embedding::<G>(
+                &nodeparams,
+                self.params.asked_dim,
+                q,
+                self.params.get_time_selection(),
+            )
+            .map(|(embedded, time)| {
+                self.dim = Some(self.params.asked_dim);
+                (embedded, Some(time))
+            }),
+            None => {
+                let laplacian = get_laplacian_with_options(&nodeparams, &self.get_kernel_options());
+                self.embed_laplacian::<G>(laplacian)
+            }
+        }?;
+        self.time = time;
+        if let Some(correction) = self.batch_correction.as_ref().filter(|c| c.do_regression()) {
+            correction.regress(&mut embedded)?;
+        }
         //
+        Ok(embedded)
+    } // end of embed_kgraph_in_pool
+
+    /// embeds a sparse affinity matrix given by the user (for example a connectivity graph from a scanpy workflow)
+    /// used directly as the kernel, without NodeParams computation. Node i is row i and has DataId i.  
+    /// The matrix is symetrized as (A + A^t)/2, entries must be non negative and each node must have a non null degree.
+    /// Self edges and kernel sparsification of [DiffusionParams] apply, magnetic and bi-diffusion embeddings
+    /// that need a directed graph are not possible.
+    /// A batch correction is only applied by regression, kernel balancing needs NodeParams.
+    pub fn embed_from_affinity<G>(&mut self, affinity: &CsMat<f32>) -> Result<Array2<G>, AnnembedError>
+    where
+        G: Float + FromPrimitive + Send,
+    {
+        let profiler = StageProfiler::start();
+        let _observer = ObserverScope::enter(self.observer.clone());
+        let embedded = install_in_pool(self.params.num_threads, || self.embed_from_affinity_in_pool::<G>(affinity))?;
+        self.profile = Some(profiler.get_profiles());
         embedded
+    } // end of embed_from_affinity
+
+    fn embed_from_affinity_in_pool<G>(&mut self, affinity: &CsMat<f32>) -> Result<Array2<G>, AnnembedError>
+    where
+        G: Float + FromPrimitive + Send,
+    {
+        if self.params.get_bidiffusion() || self.params.get_magnetic_q().is_some() {
+            log::error!("embed_from_affinity, magnetic and bi-diffusion embeddings need a directed graph");
+            return Err(AnnembedError::InvalidParameter(String::from(
+                "magnetic and bi-diffusion embeddings are not possible from an affinity matrix",
+            )));
+        }
+        if self.batch_correction.as_ref().is_some_and(|c| c.do_kernel_balance()) {
+            log::warn!("embed_from_affinity, kernel balance of batch correction not applied");
+        }
+        self.eigenvalues = None;
+        self.eigenvectors = None;
+        self.laplacian = None;
+        self.target_embedding = None;
+        let laplacian = get_laplacian_from_affinity(affinity, &self.get_kernel_options())?;
+        self.data_ids = Some((0..affinity.rows()).collect());
+        let mut density = vec![0f32; affinity.rows()];
+        for (val, (i, j)) in affinity.iter() {
+            density[i] += 0.5 * val;
+            density[j] += 0.5 * val;
+        }
+        self.density = Some(density);
+        self.scales = None;
+        let (mut embedded, time) = self.embed_laplacian::<G>(laplacian)?;
+        self.time = time;
+        if let Some(correction) = self.batch_correction.as_ref().filter(|c| c.do_regression()) {
+            correction.regress(&mut embedded)?;
+        }
+        Ok(embedded)
+    } // end of embed_from_affinity_in_pool
+
+    fn get_kernel_options(&self) -> KernelOptions {
+        KernelOptions {
+            sparsification: self.params.sparsification,
+            self_edge: self.params.self_edge,
+        }
     }
+
+    // spectral embedding of laplacian, keeps the spectrum and the laplacian for scoring
+    fn embed_laplacian<G>(&mut self, mut laplacian: GraphLaplacian) -> Result<(Array2<G>, Option<f64>), AnnembedError>
+    where
+        G: Float + FromPrimitive,
+    {
+        laplacian.set_approx_svd(self.params.approx_svd);
+        if let Some(seed) = self.params.seed {
+            laplacian.set_seed(seed);
+        }
+        if let Some(token) = self.cancellation.as_ref() {
+            laplacian.set_cancellation_token(token);
+        }
+        let res = embed_from_laplacian::<G>(
+            &mut laplacian,
+            self.params.asked_dim,
+            self.params.dim_selection,
+            self.params.get_time_selection(),
+            self.params.get_times(),
+            self.params.get_weighting(),
+            self.params.precision,
+            self.params.solver,
+        );
+        self.eigenvalues = laplacian
+            .get_eigenvalues()
+            .map(|s| s.iter().map(|x| *x as f64).collect());
+        let nb_blocks = self.params.get_times().len().max(1);
+        self.dim = res.as_ref().ok().map(|(embedded, _)| embedded.ncols() / nb_blocks);
+        let nb_vectors = self.dim.unwrap_or(self.params.asked_dim) + 1;
+        self.eigenvectors = laplacian
+            .get_eigenvectors()
+            .map(|u| u.slice(s![.., ..nb_vectors.min(u.ncols())]).to_owned());
+        self.laplacian = self.params.keep_laplacian.then_some(laplacian);
+        res
+    } // end of embed_laplacian
 } // end of impl DiffusionsMaps
 
+// degrees of the symetrized kernel (P + P^t)/2 of node params
+fn kernel_density(nodeparams: &NodeParams) -> Vec<f32> {
+    let mut density = vec![0f32; nodeparams.get_nb_nodes()];
+    for (i, param) in nodeparams.params.iter().enumerate() {
+        for edge in &param.edges {
+            density[i] += 0.5 * edge.weight;
+            density[edge.get_node()] += 0.5 * edge.weight;
+        }
+    }
+    density
+} // end of kernel_density
+
+/// number of neighbours used for the ad hoc bandwidth of [variable_bandwidth_edges]
+const VB_ADHOC_KNBN: usize = 8;
+
+// median of values, 1. if there is no positive finite value
+fn positive_median(mut values: Vec<f64>) -> f64 {
+    values.retain(|v| v.is_finite() && *v > 0.);
+    if values.is_empty() {
+        return 1.;
+    }
+    let mid = values.len() / 2;
+    *values.select_nth_unstable_by(mid, |a, b| a.total_cmp(b)).1
+}
+
+/// variable bandwidth kernel of Berry-Harlim with bandwidth $ \rho(x) = q_{0}(x)^{\beta} $ on the edges of the graph.
+///  - an ad hoc bandwidth $\rho_{0}$ (root mean square distance to the first neighbours) gives the density estimate $q_{0}$
+///  - $ K(x,y) = \exp(-d(x,y)^2 / (4 \epsilon \rho(x) \rho(y))) $, $q(x) = \sum_{y} K(x,y) / \rho(x)^{d} $
+///  - edge weights are $ K(x,y) / (q(x) q(y))^{\alpha} $ with $ \alpha = -d/4 + d \beta / 2 + 1/2 $
+///
+/// d is the intrinsic dimension estimated by TWO-NN and $\epsilon$ (as the ad hoc one) is the median of scaled squared distances on edges.
+/// Weights are not normalized to probabilities, the symetric normalization is done with the laplacian, so eigen pairs
+/// are those of the Markov operator of the normalized kernel (the final $\rho^{-2}$ rescaling of the generator is not applied).
+/// Node scales are the bandwidths $\rho$.
+pub(crate) fn variable_bandwidth_edges<F>(kgraph: &KGraph<F>, beta: f32) -> Result<NodeParams, AnnembedError>
+where
+    F: Float + FromPrimitive + std::marker::Sync + Send + std::fmt::UpperExp + std::iter::Sum,
+{
+    let nb_nodes = kgraph.get_nb_nodes();
+    let stage = Stage::enter("node_params");
+    stage.record_size("nb_nodes", nb_nodes);
+    if let Some(i) = (0..nb_nodes).find(|i| kgraph.out_edges(*i).is_empty()) {
+        log::error!("variable_bandwidth_edges, node rank {} has no neighbour", i);
+        return Err(AnnembedError::GraphConstruction(format!("node rank {} has no neighbour", i)));
+    }
+    if kgraph.get_neighbours().iter().flatten().any(|e| !e.weight.is_finite()) {
+        return Err(AnnembedError::NonFinite("neighbour distances"));
+    }
+    let dim = intrinsic_dim::two_nn(kgraph, false)?.get_global();
+    let alpha = -dim / 4. + dim * beta as f64 / 2. + 0.5;
+    log::info!("variable_bandwidth_edges beta : {:.2e}, dimension : {:.2e}, alpha : {:.2e}", beta, dim, alpha);
+    let sq_dist = |e: &OutEdge<F>| e.weight.to_f64().unwrap().powi(2);
+    // ad hoc bandwidth, null bandwidths (duplicated points) are set to the smallest positive one
+    let mut rho0: Vec<f64> = (0..nb_nodes)
+        .map(|i| {
+            let edges = &kgraph.out_edges(i)[..VB_ADHOC_KNBN.min(kgraph.out_edges(i).len())];
+            (edges.iter().map(sq_dist).sum::<f64>() / edges.len() as f64).sqrt()
+        })
+        .collect();
+    let min_rho0 = rho0.iter().filter(|r| **r > 0.).fold(f64::INFINITY, |acc, r| acc.min(*r));
+    let min_rho0 = if min_rho0.is_finite() { min_rho0 } else { 1. };
+    rho0.iter_mut().filter(|r| **r <= 0.).for_each(|r| *r = min_rho0);
+    // log of kernel sums (self edge included) divided by bandwidth^d
+    let log_density = |rho: &[f64], factor: f64| -> (f64, Vec<f64>) {
+        let scaled: Vec<f64> = (0..nb_nodes)
+            .flat_map(|i| kgraph.out_edges(i).iter().map(move |e| sq_dist(e) / (factor * rho[i] * rho[e.get_node()])))
+            .collect();
+        let epsilon = positive_median(scaled);
+        let log_q = (0..nb_nodes)
+            .into_par_iter()
+            .map(|i| {
+                let sum = kgraph
+                    .out_edges(i)
+                    .iter()
+                    .map(|e| (-sq_dist(e) / (factor * epsilon * rho[i] * rho[e.get_node()])).exp())
+                    .sum::<f64>();
+                (1. + sum).ln() - dim * rho[i].ln()
+            })
+            .collect();
+        (epsilon, log_q)
+    };
+    let (_, log_q0) = log_density(&rho0, 2.);
+    // bandwidth rho = q0^beta, centered in log to avoid overflows (a constant factor goes in epsilon)
+    let mean_log_q0 = log_q0.iter().sum::<f64>() / nb_nodes as f64;
+    let rho: Vec<f64> = log_q0.iter().map(|l| (beta as f64 * (l - mean_log_q0)).exp()).collect();
+    let (epsilon, log_q) = log_density(&rho, 4.);
+    let mean_log_q = log_q.iter().sum::<f64>() / nb_nodes as f64;
+    let params: Vec<NodeParam> = (0..nb_nodes)
+        .into_par_iter()
+        .map(|i| {
+            let mut edges: Vec<OutEdge<f32>> = kgraph
+                .out_edges(i)
+                .iter()
+                .map(|e| {
+                    let j = e.get_node();
+                    let k = -sq_dist(e) / (4. * epsilon * rho[i] * rho[j]);
+                    let weight = (k - alpha * (log_q[i] + log_q[j] - 2. * mean_log_q)).exp();
+                    OutEdge::<f32>::new(j, (weight as f32).max(f32::MIN_POSITIVE))
+                })
+                .collect();
+            edges.sort_unstable_by(|a, b| b.weight.total_cmp(&a.weight));
+            NodeParam::new(rho[i] as f32, edges)
+        })
+        .collect();
+    if params.iter().flat_map(|p| p.edges.iter()).any(|e| !e.weight.is_finite()) {
+        log::error!("variable_bandwidth_edges, non finite kernel weights, try a beta closer to 0");
+        return Err(AnnembedError::NonFinite("variable bandwidth kernel"));
+    }
+    Ok(NodeParams::new(params, kgraph.get_max_nbng()))
+} // end of variable_bandwidth_edges
+
+/// builds the neighbourhood graph of an hnsw with knbn neighbours (default to max_nb_connection).
+/// If ef_search is given neighbourhoods come from a knn search of each point, else they are extracted from the hnsw.  
+/// If radius is given, nodes are connected to all neighbours within radius, up to knbn (default to RADIUS_MAX_KNBN).
+pub(crate) fn kgraph_from_hnsw_params<T, D, F>(
+    hnsw: &Hnsw<T, D>,
+    knbn: Option<usize>,
+    ef_search: Option<usize>,
+    radius: Option<f32>,
+) -> Result<KGraph<F>, AnnembedError>
+where
+    D: Distance<T> + Send + Sync,
+    T: Clone + Send + Sync,
+    F: Float + FromPrimitive + std::marker::Sync + Send,
+{
+    let max_nb_conn = hnsw.get_max_nb_connection() as usize;
+    if let Some(radius) = radius {
+        let max_knbn = knbn.unwrap_or(RADIUS_MAX_KNBN);
+        let ef = ef_search.unwrap_or(max_knbn);
+        return kgraph_from_hnsw_radius::<T, D, F>(hnsw, radius, max_knbn, ef).map_err(|_| {
+            AnnembedError::GraphConstruction(String::from("kgraph_from_hnsw_radius failed"))
+        });
+    }
+    let knbn = knbn.unwrap_or(max_nb_conn);
+    let res = match ef_search {
+        Some(ef) => kgraph_from_hnsw_search::<T, D, F>(hnsw, knbn, ef),
+        None => {
+            if knbn > max_nb_conn {
+                log::warn!(
+                    "knbn {} greater than hnsw max_nb_connection {}, set an ef_search to get larger neighbourhoods",
+                    knbn,
+                    max_nb_conn
+                );
+            }
+            kgraph_from_hnsw_all::<T, D, F>(hnsw, knbn)
+        }
+    };
+    res.map_err(|_| AnnembedError::GraphConstruction(String::from("kgraph extraction from hnsw failed")))
+} // end of kgraph_from_hnsw_params
+
+// spectral embeddings drop the first eigenvector so they need at least 2 dimensions
+fn check_asked_dim(asked_dim: usize) -> Result<(), AnnembedError> {
+    if asked_dim < 2 {
+        log::error!("asked embedding dimension {} must be >= 2", asked_dim);
+        return Err(AnnembedError::InvalidParameter(format!("asked embedding dimension {} must be >= 2", asked_dim)));
+    }
+    Ok(())
+}
+
 // this function initialize and returns embedding by a svd (or else?)
 // We are intersested in first eigenvalues (excpeting 1.) of transition probability matrix
 // i.e last non null eigenvalues of laplacian matrix!!
@@ -89,22 +1232,79 @@ pub(crate) fn get_dmap_embedding<F>(
     initial_space: &NodeParams,
     asked_dim: usize,
     t_opt: Option<f32>,
-) -> Array2<F>
+    solver: EigenSolver,
+    approx_svd: ApproxSvdMode,
+    seed: Option<u64>,
+    cancellation: Option<&CancellationToken>,
+) -> Result<Array2<F>, AnnembedError>
 where
     F: Float + FromPrimitive,
 {
     //
-    assert!(asked_dim >= 2);
+    check_asked_dim(asked_dim)?;
     // get eigen values of normalized symetric lapalcian
     let mut laplacian = get_laplacian(initial_space);
-    //
+    laplacian.set_approx_svd(approx_svd);
+    if let Some(seed) = seed {
+        laplacian.set_seed(seed);
+    }
+    if let Some(token) = cancellation {
+        laplacian.set_cancellation_token(token);
+    }
+    let selection = match t_opt {
+        Some(t) => TimeSelection::Fixed(t),
+        None => TimeSelection::SpectralGap,
+    };
+    embed_from_laplacian(&mut laplacian, asked_dim, None, selection, &[], EigenWeighting::Diffusion, SvdPrecision::F32, solver)
+        .map(|(embedded, _)| embedded)
+} // end of get_dmap_initial_embedding
+
+/// computes the spectral embedding from a symetric laplacian (as returned by [get_laplacian]).
+/// Eigenvectors are rescaled by degrees to get eigenvectors of the random walk laplacian and weighted according to weighting
+/// (no rescaling nor weighting with [EigenWeighting::Eigenmaps]).
+/// time_selection is only used with [EigenWeighting::Diffusion].  
+/// If times is not empty, embeddings at each time are concatenated, giving asked_dim * times.len() coordinates.  
+/// If dim_selection is set, asked_dim is the maximal dimension and the dimension is chosen from the spectrum.  
+/// The svd (or the eigen solver given) runs in the type given by precision, weighting is done in f64 and results converted to F.  
+/// Returns the embedding and the diffusion time used (None without diffusion weighting or with multiple times).
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn embed_from_laplacian<F>(
+    laplacian: &mut GraphLaplacian,
+    asked_dim: usize,
+    dim_selection: Option<DimSelection>,
+    time_selection: TimeSelection,
+    times: &[f32],
+    weighting: EigenWeighting,
+    precision: SvdPrecision,
+    solver: EigenSolver,
+) -> Result<(Array2<F>, Option<f64>), AnnembedError>
+where
+    F: Float + FromPrimitive,
+{
+    check_asked_dim(asked_dim)?;
+    if !times.is_empty() && (weighting != EigenWeighting::Diffusion || times.iter().any(|t| t.is_nan() || *t <= 0.)) {
+        log::error!("embed_from_laplacian, multi-scale times {:?} need diffusion weighting and positive times", times);
+        return Err(AnnembedError::InvalidParameter(String::from(
+            "multi-scale times need diffusion weighting and positive times",
+        )));
+    }
     log::debug!("got laplacian, going to svd ... asked_dim :  {}", asked_dim);
-    let svd_res = laplacian.do_svd(asked_dim + 25).unwrap();
+    let svd_res = laplacian.do_eigen(asked_dim + 25, precision, solver)?;
     // As we used a laplacian and probability transitions we eigenvectors corresponding to lower eigenvalues
-    let lambdas = svd_res.get_sigma().as_ref().unwrap();
+    let lambdas = svd_res
+        .get_sigma()
+        .as_ref()
+        .ok_or(AnnembedError::MissingSvdResult("singular values"))?;
     // singular vectors are stored in decrasing order according to lapack for both gesdd and gesvd.
     if lambdas.len() > 2 && lambdas[1] > lambdas[0] {
-        panic!("svd spectrum not decreasing");
+        log::error!("svd spectrum not decreasing");
+        return Err(AnnembedError::SpectrumNotDecreasing);
+    }
+    if lambdas.len() < asked_dim + 1 || lambdas.len() < 3 {
+        return Err(AnnembedError::NotEnoughEigenvectors {
+            computed: lambdas.len(),
+            needed: (asked_dim + 1).max(3),
+        });
     }
     // we examine spectrum
     // our laplacian is without the term I of I-G , we use directly G symetrized so we consider upper eigenvalues
@@ -123,76 +1323,673 @@ where
     //
     log::debug!("keeping columns from 1 to : {}", asked_dim);
     // We get U at index in range first_non_zero-max_dim..first_non_zero
-    let u = svd_res.get_u().as_ref().unwrap();
+    let u = svd_res
+        .get_u()
+        .as_ref()
+        .ok_or(AnnembedError::MissingSvdResult("left singular vectors"))?;
+    if lambdas.iter().chain(u.iter()).any(|x| !x.is_finite()) {
+        log::error!("embed_from_laplacian, non finite eigen pairs");
+        return Err(AnnembedError::NonFinite("eigen pairs"));
+    }
     log::debug!("u shape : nrows: {} ,  ncols : {} ", u.nrows(), u.ncols());
     // we can get svd from approx range so that nrows and ncols can be number of nodes!
-    let mut embedded = Array2::<F>::zeros((u.nrows(), asked_dim));
+
     // according to theory (See Luxburg or Lafon-Keller diffusion maps) we must go back to eigen vectors of rw laplacian.
     // Appendix A of Coifman-Lafon Diffusion Maps. Applied Comput Harmonical Analysis 2006.
     // moreover we must get back to type F
     let normalized_lambdas = lambdas / (*lambdas)[0];
-    let time = match t_opt {
-        Some(t) => t,
-        _ => 5.0f32.min(0.9f32.ln() / (normalized_lambdas[2] / normalized_lambdas[1]).ln()),
+    let asked_dim = match dim_selection {
+        Some(selection) => select_dimension(normalized_lambdas.as_slice().unwrap(), asked_dim, selection),
+        None => asked_dim,
     };
-    log::info!("get_dmap_initial_embedding applying dmap time {:.2e}", time);
-    let sum_diag = laplacian.degrees.iter().sum::<f32>();
+    let nb_blocks = times.len().max(1);
+    let mut embedded = Array2::<F>::zeros((u.nrows(), asked_dim * nb_blocks));
+    let mut time_used = None;
+    // weights of coordinate j of block k are at j + k * asked_dim
+    let eigen_weights: Vec<f64> = match weighting {
+        EigenWeighting::Diffusion if !times.is_empty() => {
+            log::info!("embed_from_laplacian applying dmap times {:?}", times);
+            times
+                .iter()
+                .flat_map(|t| (0..asked_dim).map(|j| normalized_lambdas[j + 1].pow(*t as f64)))
+                .collect()
+        }
+        EigenWeighting::Diffusion => {
+            let time = select_diffusion_time(normalized_lambdas.as_slice().unwrap(), asked_dim, time_selection);
+            log::info!("embed_from_laplacian applying dmap time {:.2e}", time);
+            time_used = Some(time);
+            (0..asked_dim).map(|j| normalized_lambdas[j + 1].pow(time)).collect()
+        }
+        EigenWeighting::CommuteTime => {
+            // eigenvalues of I - G, guarded against degenerate (disconnected) spectrum
+            log::info!("embed_from_laplacian applying commute time weighting");
+            (0..asked_dim)
+                .map(|j| 1. / (1. - normalized_lambdas[j + 1]).max(f32::EPSILON as f64).sqrt())
+                .collect()
+        }
+        EigenWeighting::Eigenmaps => {
+            log::info!("embed_from_laplacian, laplacian eigenmaps without weighting");
+            vec![1.; asked_dim]
+        }
+        EigenWeighting::MultiScale => {
+            log::info!("embed_from_laplacian applying multi-scale weighting");
+            (0..asked_dim)
+                .map(|j| normalized_lambdas[j + 1] / (1. - normalized_lambdas[j + 1]).max(f32::EPSILON as f64))
+                .collect()
+        }
+    };
+    let sum_diag = laplacian.degrees.iter().map(|d| *d as f64).sum::<f64>();
+    let rescale = weighting != EigenWeighting::Eigenmaps;
     for i in 0..u.nrows() {
         let row_i = u.row(i);
-        let weight_i = (laplacian.degrees[i] / sum_diag).sqrt();
-        for j in 0..asked_dim {
+        let weight_i = if rescale {
+            (laplacian.degrees[i] as f64 / sum_diag).sqrt()
+        } else {
+            1.
+        };
+        for (j, w) in eigen_weights.iter().enumerate() {
             // divide j value by diagonal and convert to F. take l_{i}^{t} as in dmap
-            embedded[[i, j]] =
-                F::from_f32(normalized_lambdas[j + 1].pow(time) * row_i[j + 1] / weight_i).unwrap();
+            embedded[[i, j]] = F::from_f64(w * row_i[j % asked_dim + 1] / weight_i)
+                .ok_or(AnnembedError::FloatConversion)?;
         }
     }
-    log::trace!("ended get_dmap_initial_embedding");
-    return embedded;
-} // end of get_dmap_initial_embedding
+    log::trace!("ended embed_from_laplacian");
+    return Ok((embedded, time_used));
+} // end of embed_from_laplacian
+
+/// Embedding with the magnetic laplacian (see [get_magnetic_laplacian]) which keeps the asymetry of the neighbour graph in phases.  
+/// Eigenvectors are complex, coordinates are given by the real and imaginary parts of the first non trivial eigenvectors
+/// (dimension j uses eigenvector 1 + j/2, real part for even j and imaginary part for odd j), weighted by $\lambda^{t}$
+/// and degrees as in [get_dmap_embedding]. Returns the embedding and the diffusion time used.
+pub(crate) fn get_magnetic_dmap_embedding<F>(
+    initial_space: &NodeParams,
+    asked_dim: usize,
+    q: f32,
+    time_selection: TimeSelection,
+) -> Result<(Array2<F>, f64), AnnembedError>
+where
+    F: Float + FromPrimitive,
+{
+    check_asked_dim(asked_dim)?;
+    let magnetic = get_magnetic_laplacian(initial_space, q);
+    let nbnodes = magnetic.degrees.len();
+    let mut laplacian = magnetic.to_real_symetric();
+    // each complex eigenvalue appears twice in the real symetric form
+    let svd_res = laplacian.do_svd(2 * (asked_dim + 25))?;
+    let lambdas = svd_res
+        .get_sigma()
+        .as_ref()
+        .ok_or(AnnembedError::MissingSvdResult("singular values"))?;
+    let u = svd_res
+        .get_u()
+        .as_ref()
+        .ok_or(AnnembedError::MissingSvdResult("left singular vectors"))?;
+    // eigen pairs are at even ranks
+    let nb_pairs = u.ncols() / 2;
+    let nb_eigen_needed = 1 + (asked_dim + 1) / 2;
+    if nb_pairs < nb_eigen_needed + 1 {
+        log::error!("get_magnetic_dmap_embedding, not enough eigenvectors computed : {}", nb_pairs);
+        return Err(AnnembedError::NotEnoughEigenvectors {
+            computed: nb_pairs,
+            needed: nb_eigen_needed + 1,
+        });
+    }
+    let pair_lambdas: Vec<f64> = (0..nb_pairs).map(|k| (lambdas[2 * k] / lambdas[0]) as f64).collect();
+    log::info!(
+        " magnetic laplacian first 3 eigen values {:.2e} {:.2e} {:.2e}",
+        pair_lambdas[0],
+        pair_lambdas[1],
+        pair_lambdas[2]
+    );
+    let time = select_diffusion_time(&pair_lambdas, asked_dim, time_selection);
+    log::info!("get_magnetic_dmap_embedding applying dmap time {:.2e}", time);
+    let sum_diag = magnetic.degrees.iter().map(|d| *d as f64).sum::<f64>();
+    let mut embedded = Array2::<F>::zeros((nbnodes, asked_dim));
+    for i in 0..nbnodes {
+        let weight_i = (magnetic.degrees[i] as f64 / sum_diag).sqrt();
+        for j in 0..asked_dim {
+            let k = 1 + j / 2;
+            // real part in first half of rows, imaginary part in second half
+            let row = if j % 2 == 0 { i } else { i + nbnodes };
+            embedded[[i, j]] = F::from_f64(pair_lambdas[k].pow(time) * u[[row, 2 * k]] as f64 / weight_i)
+                .ok_or(AnnembedError::FloatConversion)?;
+        }
+    }
+    Ok((embedded, time))
+} // end of get_magnetic_dmap_embedding
+
+/// Bi-diffusion embedding of the directed knn graph (see [get_directed_laplacian]).  
+/// Returns the pair (source, target) of embeddings. Source coordinates come from left singular vectors divided by
+/// square root of out degrees, target coordinates from right singular vectors divided by square root of in degrees.
+/// Both are weighted by $\sigma^{t}$, the first (trivial) singular pair is dropped as in [get_dmap_embedding].
+/// The diffusion time used is returned as third element.
+pub(crate) fn get_bidiffusion_embedding<F>(
+    initial_space: &NodeParams,
+    asked_dim: usize,
+    time_selection: TimeSelection,
+) -> Result<(Array2<F>, Array2<F>, f64), AnnembedError>
+where
+    F: Float + FromPrimitive,
+{
+    check_asked_dim(asked_dim)?;
+    let (mut laplacian, in_degrees) = get_directed_laplacian(initial_space);
+    let svd_res = laplacian.do_svd(asked_dim + 25)?;
+    let sigmas = svd_res
+        .get_sigma()
+        .as_ref()
+        .ok_or(AnnembedError::MissingSvdResult("singular values"))?;
+    let u = svd_res
+        .get_u()
+        .as_ref()
+        .ok_or(AnnembedError::MissingSvdResult("left singular vectors"))?;
+    let vt = svd_res
+        .get_vt()
+        .as_ref()
+        .ok_or(AnnembedError::MissingSvdResult("right singular vectors"))?;
+    if sigmas.len() < asked_dim + 1 || sigmas.len() < 3 {
+        return Err(AnnembedError::NotEnoughEigenvectors {
+            computed: sigmas.len(),
+            needed: (asked_dim + 1).max(3),
+        });
+    }
+    log::info!(
+        " bi-diffusion first 3 singular values {:.2e} {:.2e} {:.2e}",
+        sigmas[0],
+        sigmas[1],
+        sigmas[2]
+    );
+    let normalized_sigmas: Vec<f64> = sigmas.iter().map(|s| (s / sigmas[0]) as f64).collect();
+    let time = select_diffusion_time(&normalized_sigmas, asked_dim, time_selection);
+    log::info!("get_bidiffusion_embedding applying dmap time {:.2e}", time);
+    let nbnodes = u.nrows();
+    let sum_out = laplacian.degrees.iter().map(|d| *d as f64).sum::<f64>();
+    let sum_in = in_degrees.iter().map(|d| *d as f64).sum::<f64>();
+    let mut source = Array2::<F>::zeros((nbnodes, asked_dim));
+    let mut target = Array2::<F>::zeros((nbnodes, asked_dim));
+    for i in 0..nbnodes {
+        let weight_out = (laplacian.degrees[i] as f64 / sum_out).sqrt();
+        let weight_in = (in_degrees[i] as f64 / sum_in).sqrt();
+        for j in 0..asked_dim {
+            let sigma_t = normalized_sigmas[j + 1].pow(time);
+            source[[i, j]] = F::from_f64(sigma_t * u[[i, j + 1]] as f64 / weight_out)
+                .ok_or(AnnembedError::FloatConversion)?;
+            // nodes never chosen as neighbours stay at origin in target space
+            if weight_in > 0. {
+                target[[i, j]] = F::from_f64(sigma_t * vt[[j + 1, i]] as f64 / weight_in)
+                    .ok_or(AnnembedError::FloatConversion)?;
+            }
+        }
+    }
+    Ok((source, target, time))
+} // end of get_bidiffusion_embedding
 
 //======================================================================================================================
 
-/// This function runs a parallel insertion of rows of an `Array2<T>` into a  Hnsw<T,D>.  
+/// default block size of parallel insertions in hnsw
+pub const INSERTION_BLOCKSIZE: usize = 10000;
+
+/// This function runs a parallel insertion of rows of an `Array2<T>` (or of a view) into a  Hnsw<T,D>.  
 /// The hnsw structure must have chosen main parameters as the number of connection and layers, but
 /// be empty.   
+/// Points are given their row index as DataId, see [array2_insert_hnsw_with_ids] to keep external identifiers.  
 /// Returns number of point inserted if success.
-pub fn array2_insert_hnsw<T, D>(data: &Array2<T>, hnsw: &mut Hnsw<T, D>) -> Result<usize, usize>
+pub fn array2_insert_hnsw<T, D, S>(data: &ArrayBase<S, Ix2>, hnsw: &mut Hnsw<T, D>) -> Result<usize, AnnembedError>
 where
     T: Clone + Send + Sync,
     D: Distance<T> + Send + Sync,
+    S: Data<Elem = T> + Sync,
+{
+    let ids: Vec<DataId> = (0..data.nrows()).collect();
+    array2_insert_hnsw_with_ids(data, &ids, hnsw)
+} // end of array2_insert_hnsw
+
+/// Parallel insertion of rows of an `Array2<T>` into an empty Hnsw<T,D>, row i being inserted with DataId ids\[i\].  
+/// DataIds are kept by the graph extracted from hnsw (see [KGraph]) so embeddings can be reindexed by them.  
+/// Returns number of point inserted if success.
+pub fn array2_insert_hnsw_with_ids<T, D, S>(
+    data: &ArrayBase<S, Ix2>,
+    ids: &[DataId],
+    hnsw: &mut Hnsw<T, D>,
+) -> Result<usize, AnnembedError>
+where
+    T: Clone + Send + Sync,
+    D: Distance<T> + Send + Sync,
+    S: Data<Elem = T> + Sync,
+{
+    array2_insert_hnsw_by_blocks(data, ids, INSERTION_BLOCKSIZE, hnsw)
+} // end of array2_insert_hnsw_with_ids
+
+/// Same as [array2_insert_hnsw_with_ids] with a given block size.  
+/// If data is in standard layout all rows are given at once to the parallel insertion of Hnsw.
+/// Otherwise (strided views, column major arrays) blocks of rows are inserted in parallel, each thread copying and
+/// inserting the rows of its block, so at most one block per thread is duplicated at any time.
+pub fn array2_insert_hnsw_by_blocks<T, D, S>(
+    data: &ArrayBase<S, Ix2>,
+    ids: &[DataId],
+    blocksize: usize,
+    hnsw: &mut Hnsw<T, D>,
+) -> Result<usize, AnnembedError>
+where
+    T: Clone + Send + Sync,
+    D: Distance<T> + Send + Sync,
+    S: Data<Elem = T> + Sync,
 {
     //
     if hnsw.get_nb_point() > 0 {
         log::error!(
-            "array2_insert_hnsw , insertion on non empty hnsw structure, nb point : {}",
+            "array2_insert_hnsw_by_blocks , insertion on non empty hnsw structure, nb point : {}",
             hnsw.get_nb_point()
         );
-        return Err(1);
-    }
-    // we do parallel insertion by blocks of size blocksize
-    let blocksize = 10000;
-    let (nb_row, _) = data.dim();
-
-    let nb_block = nb_row / blocksize;
-    for i in 0..nb_block {
-        let start = i * blocksize;
-        let end = i * blocksize + blocksize - 1;
-        let to_insert = (start..=end)
-            .into_iter()
-            .map(|n| (data.row(n).to_slice().unwrap(), n))
-            .collect();
+        return Err(AnnembedError::InvalidParameter(String::from("insertion in non empty hnsw")));
+    }
+    if ids.len() != data.nrows() {
+        log::error!(
+            "array2_insert_hnsw_by_blocks , nb ids {} != nb rows {}",
+            ids.len(),
+            data.nrows()
+        );
+        return Err(AnnembedError::InvalidParameter(format!("nb ids {} != nb rows {}", ids.len(), data.nrows())));
+    }
+    if blocksize == 0 || data.ncols() == 0 {
+        log::error!(
+            "array2_insert_hnsw_by_blocks , block size {}, data dimension {}",
+            blocksize,
+            data.ncols()
+        );
+        return Err(AnnembedError::InvalidParameter(format!(
+            "block size {}, data dimension {}",
+            blocksize,
+            data.ncols()
+        )));
+    }
+    let mut sorted = ids.to_vec();
+    sorted.sort_unstable();
+    if sorted.windows(2).any(|w| w[0] == w[1]) {
+        log::error!("array2_insert_hnsw_by_blocks , ids are not unique");
+        return Err(AnnembedError::InvalidParameter(String::from("ids are not unique")));
+    }
+    //
+    let (nb_row, dim) = data.dim();
+    let stage = Stage::enter("insertion");
+    stage.record_size("nb_point", nb_row);
+    stage.record_size("dim", dim);
+    if let Some(slice) = data.as_slice() {
+        let to_insert: Vec<(&[T], DataId)> = slice.chunks(dim).zip(ids.iter().copied()).collect();
         hnsw.parallel_insert_slice(&to_insert);
+        stage.report_progress(nb_row, nb_row);
+        return Ok(hnsw.get_nb_point());
     }
-    let start = nb_block * blocksize;
-    let to_insert = (start..nb_row)
-        .into_iter()
-        .map(|n| (data.row(n).to_slice().unwrap(), n))
-        .collect();
+    let nb_block = nb_row.div_ceil(blocksize);
+    let hnsw_ref: &Hnsw<T, D> = hnsw;
+    // progress is reported by blocks, the meter can be shared between threads (contrary to Stage)
+    let meter = ProgressMeter::new("insertion");
+    let nb_block_done = std::sync::atomic::AtomicUsize::new(0);
+    (0..nb_block).into_par_iter().for_each(|b| {
+        let start = b * blocksize;
+        let end = (start + blocksize).min(nb_row);
+        let view = data.slice(s![start..end, ..]);
+        // copies only if block is not in standard layout
+        let block = view.as_standard_layout();
+        for (k, row) in block.as_slice().unwrap().chunks(dim).enumerate() {
+            hnsw_ref.insert_slice((row, ids[start + k]));
+        }
+        let done = nb_block_done.fetch_add(1, std::sync::atomic::Ordering::Relaxed) + 1;
+        meter.report(done, nb_block);
+    });
+    //
+    Ok(hnsw.get_nb_point())
+} // end of array2_insert_hnsw_by_blocks
+
+/// Parallel insertion of (data, DataId) couples given by an iterator into an empty Hnsw<T,D>.
+/// The slices are collected (not copied) and given at once to the parallel insertion of Hnsw.  
+/// Uniqueness of DataIds is left to the caller.  
+/// The data dimension is given by the first slice, empty slices or slices of another length are not inserted.
+/// The iteration goes on with the other rows and then [AnnembedError::InvalidRows] lists the DataIds of the skipped rows,
+/// the hnsw structure then contains the valid rows.  
+/// Returns number of point inserted if success.
+pub fn iter_insert_hnsw<'a, T, D, I>(iter: I, hnsw: &mut Hnsw<T, D>) -> Result<usize, AnnembedError>
+where
+    T: Clone + Send + Sync + 'a,
+    D: Distance<T> + Send + Sync,
+    I: Iterator<Item = (&'a [T], DataId)>,
+{
+    //
+    if hnsw.get_nb_point() > 0 {
+        log::error!(
+            "iter_insert_hnsw , insertion on non empty hnsw structure, nb point : {}",
+            hnsw.get_nb_point()
+        );
+        return Err(AnnembedError::InvalidParameter(String::from("insertion in non empty hnsw")));
+    }
+    let stage = Stage::enter("insertion");
+    let mut iter = iter.peekable();
+    let dim = iter.peek().map(|(data, _)| data.len());
+    let (to_insert, invalid): (Vec<(&[T], DataId)>, Vec<_>) =
+        iter.partition(|(data, _)| !data.is_empty() && Some(data.len()) == dim);
+    let invalid_rows: Vec<DataId> = invalid.iter().map(|(_, id)| *id).collect();
     hnsw.parallel_insert_slice(&to_insert);
+    stage.report_progress(to_insert.len(), to_insert.len());
+    stage.record_size("nb_point", hnsw.get_nb_point());
     //
+    if !invalid_rows.is_empty() {
+        log::error!(
+            "iter_insert_hnsw , {} rows with dimension different from {:?} not inserted",
+            invalid_rows.len(),
+            dim
+        );
+        return Err(AnnembedError::InvalidRows {
+            reason: "dimension differs from first row",
+            rows: invalid_rows,
+        });
+    }
     Ok(hnsw.get_nb_point())
-} // end of array2_insert_hnsw
+} // end of iter_insert_hnsw
 
 //=======================================================================
 
-mod tests {} // end of mod tests
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    fn log_init_test() {
+        let _ = env_logger::builder().is_test(true).try_init();
+    }
+
+    #[test]
+    fn test_time_selection() {
+        log_init_test();
+        // spectrum with a clear knee after 3 eigenvalues
+        let lambdas = [1., 0.95, 0.9, 0.85, 0.2, 0.15, 0.1, 0.08, 0.05, 0.03];
+        // knee at the first small eigenvalue
+        assert_eq!(knee_index(&lambdas[1..]), 3);
+        let t = select_diffusion_time(&lambdas, 2, TimeSelection::Fixed(3.));
+        assert_eq!(t, 3.);
+        let t_knee = select_diffusion_time(&lambdas, 2, TimeSelection::Knee);
+        assert!(0.2f64.powf(t_knee) <= 0.1 + 1.0E-10);
+        let t_entropy = select_diffusion_time(&lambdas, 2, TimeSelection::VonNeumannEntropy);
+        assert!((1. ..=MAX_DIFFUSION_TIME).contains(&t_entropy));
+        // retained fraction increases with time
+        let t_low = select_diffusion_time(&lambdas, 3, TimeSelection::RetainedInformation(0.5));
+        let t_high = select_diffusion_time(&lambdas, 3, TimeSelection::RetainedInformation(0.9));
+        assert!(t_low <= t_high);
+    } // end of test_time_selection
+
+    #[test]
+    fn test_insert_with_ids() {
+        log_init_test();
+        let data = ndarray::arr2(&[[0., 0.], [1., 0.], [0., 1.], [1., 1.], [5., 5.]]);
+        let ids: Vec<DataId> = vec![10, 20, 30, 40, 50];
+        let mut hnsw = Hnsw::<f64, DistL2>::new(4, 5, 16, 20, DistL2 {});
+        // duplicated ids are rejected
+        let res = array2_insert_hnsw_with_ids(&data, &[1, 1, 2, 3, 4], &mut hnsw);
+        assert!(matches!(res, Err(AnnembedError::InvalidParameter(_))));
+        let nb = array2_insert_hnsw_with_ids(&data, &ids, &mut hnsw).unwrap();
+        assert_eq!(nb, 5);
+        let kgraph = kgraph_from_hnsw_all::<f64, DistL2, f32>(&hnsw, 3).unwrap();
+        for id in &ids {
+            let idx = kgraph.get_idx_from_dataid(id).unwrap();
+            assert_eq!(kgraph.get_data_id_from_idx(idx), Some(id));
+        }
+    } // end of test_insert_with_ids
+
+    #[test]
+    fn test_insert_non_contiguous() {
+        log_init_test();
+        // column major array, rows are not contiguous
+        let data = ndarray::arr2(&[[0., 1., 0., 1., 5., 6., 7.], [0., 0., 1., 1., 5., 6., 7.]]).reversed_axes();
+        assert!(data.row(0).as_slice().is_none());
+        let ids: Vec<DataId> = (0..data.nrows()).map(|i| 100 + i).collect();
+        let mut hnsw = Hnsw::<f64, DistL2>::new(4, 7, 16, 20, DistL2 {});
+        let nb = array2_insert_hnsw_by_blocks(&data.view(), &ids, 3, &mut hnsw).unwrap();
+        assert_eq!(nb, 7);
+        // each point is its own nearest neighbour with its id
+        for (i, row) in data.rows().into_iter().enumerate() {
+            let neighbours = hnsw.search(&row.to_vec(), 1, 16);
+            assert_eq!(neighbours[0].d_id, ids[i]);
+        }
+    } // end of test_insert_non_contiguous
+
+    #[test]
+    fn test_iter_insert_invalid_rows() {
+        log_init_test();
+        let rows: Vec<Vec<f32>> = vec![vec![0., 0.], vec![1., 0.], vec![1.], vec![0., 1.], vec![], vec![1., 1.]];
+        let mut hnsw = Hnsw::<f32, DistL2>::new(4, 6, 16, 20, DistL2 {});
+        let res = iter_insert_hnsw(rows.iter().enumerate().map(|(i, r)| (r.as_slice(), 10 * i)), &mut hnsw);
+        match res {
+            Err(AnnembedError::InvalidRows { rows, .. }) => assert_eq!(rows, vec![20, 40]),
+            _ => panic!("invalid rows not reported"),
+        }
+        // valid rows are inserted
+        assert_eq!(hnsw.get_nb_point(), 4);
+    } // end of test_iter_insert_invalid_rows
+
+    #[test]
+    fn test_dump_reload() {
+        log_init_test();
+        let mut params = DiffusionParams::new(2, Some(2.));
+        params.set_weighting(EigenWeighting::CommuteTime);
+        let mut dmaps = DiffusionMaps::new(params);
+        dmaps.time = Some(2.);
+        dmaps.data_ids = Some(vec![3, 1, 2]);
+        dmaps.eigenvalues = Some(vec![1., 0.5, 0.25]);
+        dmaps.eigenvectors = Some(Array2::<f32>::from_shape_fn((3, 3), |(i, j)| (i + 3 * j) as f32));
+        let embedded = Array2::<f32>::from_shape_fn((3, 2), |(i, j)| (i * 2 + j) as f32 * 0.5);
+        let path = std::env::temp_dir().join(format!("annembed_test_dmaps_{}.bin", std::process::id()));
+        // number of rows must match the last embedding
+        assert!(dmaps.dump(&path, &embedded.slice(ndarray::s![..2, ..]).to_owned()).is_err());
+        dmaps.dump(&path, &embedded).unwrap();
+        let (reloaded, coordinates) = DiffusionMaps::reload(&path).unwrap();
+        let _ = std::fs::remove_file(&path);
+        assert_eq!(coordinates, embedded.mapv(|x| x as f64));
+        assert_eq!(reloaded.get_data_ids(), dmaps.get_data_ids());
+        assert_eq!(reloaded.get_eigenvalues(), dmaps.get_eigenvalues());
+        assert_eq!(reloaded.get_eigenvectors(), dmaps.get_eigenvectors());
+        assert_eq!(reloaded.get_diffusion_time(), Some(2.));
+        assert_eq!(reloaded.params.get_weighting(), EigenWeighting::CommuteTime);
+        assert_eq!(reloaded.params.get_embedding_dimension(), 2);
+        assert_eq!(reloaded.get_embedding_dim(), Some(2));
+    } // end of test_dump_reload
+
+    #[test]
+    fn test_get_spectrum() {
+        log_init_test();
+        let mut dmaps = DiffusionMaps::new(DiffusionParams::new(2, Some(2.)));
+        assert!(dmaps.get_spectrum().is_none());
+        dmaps.time = Some(2.);
+        dmaps.data_ids = Some(vec![3, 1, 2]);
+        dmaps.eigenvalues = Some(vec![2., 1., 0.5]);
+        dmaps.eigenvectors = Some(Array2::<f32>::from_shape_fn((3, 3), |(i, j)| (i + 3 * j) as f32));
+        let spectrum = dmaps.get_spectrum().unwrap();
+        assert_eq!(spectrum.data_ids, vec![1, 2, 3]);
+        assert_eq!(spectrum.eigenvalues, vec![1., 0.5, 0.25]);
+        assert_eq!(spectrum.spectral_gap, 0.5);
+        assert_eq!(spectrum.time, Some(2.));
+        // row of DataId 1 is row 1 of eigenvectors
+        assert_eq!(spectrum.eigenvectors.row(0).to_vec(), vec![1., 4., 7.]);
+        assert_eq!(spectrum.eigenvectors.row(2).to_vec(), vec![0., 3., 6.]);
+    } // end of test_get_spectrum
+
+    #[test]
+    fn test_set_times() {
+        log_init_test();
+        let mut params = DiffusionParams::new(2, None);
+        assert!(params.get_times().is_empty());
+        params.set_times(&[1., 2., 4.]).unwrap();
+        assert_eq!(params.get_times(), &[1., 2., 4.]);
+        // too many times are rejected, previous times are kept
+        let times: Vec<f32> = (1..=10).map(|t| t as f32).collect();
+        assert!(matches!(params.set_times(&times), Err(AnnembedError::InvalidParameter(_))));
+        assert_eq!(params.get_times(), &[1., 2., 4.]);
+        params.set_times(&times[..MAX_DIFFUSION_TIMES]).unwrap();
+        assert_eq!(params.get_times(), &times[..MAX_DIFFUSION_TIMES]);
+        params.set_times(&[]).unwrap();
+        assert!(params.get_times().is_empty());
+        // multi-scale embedding : one block of asked_dim coordinates by time
+        let data = Array2::<f32>::from_shape_fn((200, 3), |(i, j)| (i % 2) as f32 * 5. + (((i * 7 + j * 3) % 13) as f32) / 13.);
+        let mut hnsw = Hnsw::<f32, DistL2>::new(10, 200, 8, 48, DistL2 {});
+        array2_insert_hnsw(&data, &mut hnsw).unwrap();
+        let times = [1f32, 2., 4.];
+        params.set_times(&times).unwrap();
+        let mut dmaps = DiffusionMaps::new(params);
+        let embedded: Array2<f64> = dmaps.embed_hnsw(&hnsw).unwrap();
+        assert_eq!(embedded.dim(), (200, 2 * times.len()));
+        assert!(dmaps.get_diffusion_time().is_none());
+        // coordinate j of block k is coordinate j of the first block weighted by lambda_{j+1}^(t_k - t_0)
+        let lambdas = dmaps.get_eigenvalues().unwrap();
+        for k in 1..times.len() {
+            for j in 0..2 {
+                let factor = (lambdas[j + 1] / lambdas[0]).powf((times[k] - times[0]) as f64);
+                for i in 0..embedded.nrows() {
+                    let expected = factor * embedded[[i, j]];
+                    assert!((embedded[[i, j + 2 * k]] - expected).abs() <= 1.0E-4 * expected.abs().max(1.0E-6));
+                }
+            }
+        }
+    } // end of test_set_times
+
+    #[test]
+    fn test_num_threads() {
+        log_init_test();
+        let data = Array2::<f32>::from_shape_fn((200, 3), |(i, j)| (i % 2) as f32 * 5. + (((i * 7 + j * 3) % 13) as f32) / 13.);
+        let mut hnsw = Hnsw::<f32, DistL2>::new(10, 200, 8, 48, DistL2 {});
+        array2_insert_hnsw(&data, &mut hnsw).unwrap();
+        let mut params = DiffusionParams::new(2, Some(1.));
+        params.set_num_threads(2);
+        assert_eq!(params.get_num_threads(), Some(2));
+        let embedded: Array2<f32> = DiffusionMaps::new(params).embed_hnsw(&hnsw).unwrap();
+        assert_eq!(embedded.dim(), (200, 2));
+        params.set_num_threads(0);
+        let res = DiffusionMaps::new(params).embed_hnsw::<f32, DistL2, f32>(&hnsw);
+        assert!(matches!(res, Err(AnnembedError::InvalidParameter(_))));
+    } // end of test_num_threads
+
+    #[test]
+    fn test_eigenmaps() {
+        log_init_test();
+        let data = Array2::<f32>::from_shape_fn((200, 3), |(i, j)| (i % 2) as f32 * 5. + (((i * 7 + j * 3) % 13) as f32) / 13.);
+        let mut hnsw = Hnsw::<f32, DistL2>::new(10, 200, 8, 48, DistL2 {});
+        array2_insert_hnsw(&data, &mut hnsw).unwrap();
+        let mut params = DiffusionParams::new(2, None);
+        params.set_weighting(EigenWeighting::Eigenmaps);
+        let mut dmaps = DiffusionMaps::new(params);
+        let embedded: Array2<f64> = dmaps.embed_hnsw(&hnsw).unwrap();
+        assert_eq!(embedded.dim(), (200, 2));
+        // no diffusion time without diffusion weighting
+        assert!(dmaps.get_diffusion_time().is_none());
+        // coordinates are the raw eigenvectors, orthonormal without degree rescaling nor eigenvalue weighting
+        let gram = embedded.t().dot(&embedded);
+        for j in 0..2 {
+            assert!((gram[[j, j]] - 1.).abs() < 1.0E-3, "column {} has norm {:.3e}", j, gram[[j, j]].sqrt());
+        }
+        assert!(gram[[0, 1]].abs() < 1.0E-3);
+    } // end of test_eigenmaps
+
+    #[test]
+    fn test_keep_laplacian() {
+        log_init_test();
+        let data = Array2::<f32>::from_shape_fn((200, 3), |(i, j)| (i % 2) as f32 * 5. + (((i * 7 + j * 3) % 13) as f32) / 13.);
+        let mut hnsw = Hnsw::<f32, DistL2>::new(10, 200, 8, 48, DistL2 {});
+        array2_insert_hnsw(&data, &mut hnsw).unwrap();
+        let mut params = DiffusionParams::new(2, Some(1.));
+        assert!(!params.get_keep_laplacian());
+        // laplacian is not kept by default
+        let mut dmaps = DiffusionMaps::new(params);
+        let _embedded: Array2<f32> = dmaps.embed_hnsw(&hnsw).unwrap();
+        let res = dmaps.get_laplacian_scores(&data, FeatureSelection::Best(1));
+        assert!(matches!(res, Err(AnnembedError::InvalidParameter(_))));
+        //
+        params.set_keep_laplacian(true);
+        let mut dmaps = DiffusionMaps::new(params);
+        let _embedded: Array2<f32> = dmaps.embed_hnsw(&hnsw).unwrap();
+        let scores = dmaps.get_laplacian_scores(&data, FeatureSelection::Best(1)).unwrap();
+        assert_eq!(scores.get_scores().len(), 3);
+        // data must have a row for each embedded DataId
+        let res = dmaps.get_laplacian_scores(&data.slice(s![..100, ..]), FeatureSelection::Best(1));
+        assert!(res.is_err());
+    } // end of test_keep_laplacian
+
+    #[test]
+    fn test_select_dimension() {
+        log_init_test();
+        // gap after the third non trivial eigenvalue
+        let lambdas = [1., 0.9, 0.85, 0.8, 0.2, 0.15, 0.1];
+        assert_eq!(select_dimension(&lambdas, 5, DimSelection::LargestGap), 3);
+        assert_eq!(select_dimension(&lambdas, 5, DimSelection::EigenvalueRatio(0.5)), 3);
+        assert_eq!(select_dimension(&lambdas, 5, DimSelection::EigenvalueRatio(0.1)), 5);
+        // at least 2 dimensions
+        assert_eq!(select_dimension(&lambdas, 5, DimSelection::EigenvalueRatio(0.99)), 2);
+        assert_eq!(select_dimension(&lambdas, 2, DimSelection::LargestGap), 2);
+    } // end of test_select_dimension
+
+    #[test]
+    fn test_variable_bandwidth() {
+        log_init_test();
+        // brute force knn graph of a 1d gaussian sample
+        let dataset = crate::datasets::harlim_gaussian_1d(500, 4524).unwrap();
+        let x: Vec<f32> = dataset.get_data().column(0).to_vec();
+        let knbn = 16;
+        let mut indices = Array2::<usize>::zeros((x.len(), knbn));
+        let mut dists = Array2::<f32>::zeros((x.len(), knbn));
+        for i in 0..x.len() {
+            let mut neighbours: Vec<(usize, f32)> =
+                (0..x.len()).filter(|j| *j != i).map(|j| (j, (x[i] - x[j]).abs())).collect();
+            neighbours.sort_unstable_by(|a, b| a.1.total_cmp(&b.1));
+            for (k, (j, d)) in neighbours.iter().take(knbn).enumerate() {
+                indices[[i, k]] = *j;
+                dists[[i, k]] = *d;
+            }
+        }
+        let kgraph = KGraph::from_knn_arrays(&indices, &dists).unwrap();
+        let nodeparams = variable_bandwidth_edges(&kgraph, -0.5).unwrap();
+        assert_eq!(nodeparams.get_nb_nodes(), 500);
+        for param in &nodeparams.params {
+            assert_eq!(param.edges.len(), knbn);
+            assert!(param.edges.iter().all(|e| e.weight.is_finite() && e.weight > 0.));
+            assert!(param.edges.windows(2).all(|w| w[0].weight >= w[1].weight));
+        }
+        // bandwidth is smaller in the dense center than in the tails
+        let center = (0..x.len()).min_by(|a, b| x[*a].abs().total_cmp(&x[*b].abs())).unwrap();
+        let tail = (0..x.len()).max_by(|a, b| x[*a].abs().total_cmp(&x[*b].abs())).unwrap();
+        assert!(nodeparams.params[center].scale < nodeparams.params[tail].scale);
+        // beta = 0 is a fixed bandwidth
+        let nodeparams = variable_bandwidth_edges(&kgraph, 0.).unwrap();
+        assert!(nodeparams.params.iter().all(|p| (p.scale - 1.).abs() < 1.0E-6));
+    } // end of test_variable_bandwidth
+
+    #[test]
+    fn test_kernel_density() {
+        log_init_test();
+        // a star : node 0 is in all neighbourhoods, leaves 1 and 2 are neighbours
+        let params = vec![
+            NodeParam::new(1., vec![OutEdge::new(1, 0.5), OutEdge::new(2, 0.5)]),
+            NodeParam::new(1., vec![OutEdge::new(0, 0.75), OutEdge::new(2, 0.25)]),
+            NodeParam::new(1., vec![OutEdge::new(0, 0.75), OutEdge::new(1, 0.25)]),
+        ];
+        let nodeparams = NodeParams::new(params, 2);
+        let density = kernel_density(&nodeparams);
+        assert_eq!(density, vec![1.25, 0.875, 0.875]);
+        let mut dmaps = DiffusionMaps::new(DiffusionParams::new(2, None));
+        assert!(dmaps.get_density().is_none());
+        dmaps.data_ids = Some(vec![7, 8, 9]);
+        dmaps.density = Some(density);
+        assert_eq!(dmaps.get_density().unwrap()[0], (7, 1.25));
+    } // end of test_kernel_density
+
+    #[test]
+    fn test_stats() {
+        log_init_test();
+        let mut dmaps = DiffusionMaps::new(DiffusionParams::new(2, None));
+        assert!(dmaps.get_stats().is_none());
+        dmaps.density = Some((1..=100).map(|i| i as f32).collect());
+        dmaps.eigenvalues = Some(vec![1., 0.8, 0.5]);
+        dmaps.time = Some(3.);
+        let stats = dmaps.get_stats().unwrap();
+        assert_eq!(stats.density_quantiles, vec![5., 25., 50., 75., 95.]);
+        assert!(stats.scale_quantiles.is_none());
+        assert_eq!(stats.eigenvalues, Some(vec![1., 0.8, 0.5]));
+        assert_eq!(stats.time, Some(3.));
+        dmaps.scales = Some(vec![2.; 10]);
+        assert_eq!(dmaps.get_stats().unwrap().scale_quantiles, Some(vec![2.; 5]));
+    } // end of test_stats
+} // end of mod tests
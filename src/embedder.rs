@@ -23,19 +23,28 @@ use ndarray::{Array1, Array2, ArrayView1};
 use ndarray_linalg::{Lapack, Scalar};
 
 
-use quantiles::ckms::CKMS;     // we could use also greenwald_khanna
-use csv::Writer;
-use crate::tools::io::write_csv_labeled_array2;
+use crate::tools::quant::Quantiles;
+use crate::tools::io::{CsvArrayWriter, CsvOptions};
+use crate::tools::safetensors::SafeTensorsWriter;
+use crate::tools::htmlplot::HtmlPlot;
+use crate::tools::io::DataLabels;
 
 // threading needs
 use rayon::prelude::*;
 use parking_lot::RwLock;
 use std::sync::Arc;
 
+use std::fs::OpenOptions;
+use std::path::PathBuf;
+use std::io::{BufReader, BufWriter, Write};
+use serde::{Deserialize, Serialize};
+
 use rand::{Rng, thread_rng};
 use rand::distributions::Uniform;
 use rand_distr::WeightedAliasIndex;
 use rand_distr::{Normal, Distribution};
+use rand_xoshiro::Xoshiro256PlusPlus;
+use rand_xoshiro::rand_core::SeedableRng;
 
 use indexmap::set::*;
 
@@ -48,10 +57,47 @@ use crate::fromhnsw::{kgraph::KGraph, kgraph::kgraph_from_hnsw_all , kgproj::*};
 use crate::embedparams::*;
 use crate::diffmaps::*;
 use crate::tools::{dichotomy::*,nodeparam::*};
+use crate::tools::report::StageProfile;
+use crate::tools::stage::{is_cancelled, CancellationToken, EmbeddingObserver, ObserverScope, Stage, StageProfiler};
+use crate::tools::threads::install_in_pool;
+use crate::tools::clip::{ClipStrategy, Clipping};
+use crate::error::AnnembedError;
+use crate::batchcorrect::BatchCorrection;
 
 /// do not consider probabilities under PROBA_MIN, thresolded!!
 const PROBA_MIN: f32 = 1.0E-5;
 
+/// connected components with less nodes are not optimized, they keep their spectral position in their layout box
+const MIN_COMPONENT_EMBED_SIZE: usize = 50;
+
+/// number of edge samples drawn from one rng in a gradient batch
+const SAMPLE_CHUNK_SIZE: usize = 1024;
+
+// phases of the embedding using random numbers, each one gets its own stream
+const RNG_INIT: u64 = 0;
+const RNG_PROJECTION: u64 = 1;
+const RNG_TRANSFORM: u64 = 3;
+const RNG_GRADIENT: u64 = 4;
+
+/// added to the square root of the second moment in Adam updates
+const ADAM_EPSILON: f64 = 1.0E-8;
+
+/// added to mean squared distances before taking log of local radii in densMAP term
+const DENS_EPSILON: f64 = 1.0E-8;
+
+/// added to the variance of log embedded radii in densMAP term, as in umap-learn
+const DENS_VAR_SHIFT: f64 = 0.1;
+
+// a rng for a phase and an index (gradient batch chunk, new point...) so that parallel sampling do not depend on threads scheduling.
+// Without seed the rng is seeded from thread_rng
+fn get_rng(seed : Option<u64>, phase : u64, index : usize) -> Xoshiro256PlusPlus {
+    match seed {
+        Some(seed) => Xoshiro256PlusPlus::seed_from_u64(
+            seed ^ (index as u64).wrapping_mul(0x9E37_79B9_7F4A_7C15) ^ phase.wrapping_mul(0xC2B2_AE3D_27D4_EB4F)),
+        None => Xoshiro256PlusPlus::seed_from_u64(thread_rng().gen::<u64>()),
+    }
+} // end of get_rng
+
 
 // to be used in emdedded space so small dimension. no need for simd and 
 #[inline]
@@ -73,6 +119,62 @@ impl <F> Distance<F> for DistL2F
 //=====================================================================================
 
 
+// fitted state of an Embedder, see Embedder::dump
+#[derive(Serialize, Deserialize)]
+struct EmbedderDump<F> {
+    parameters : EmbedderParams,
+    /// DataId of each node (the IndexSet of the graph), row i of embedding has DataId data_ids\[i\]
+    data_ids : Vec<DataId>,
+    initial_space : Option<NodeParams>,
+    embedding : Array2<F>,
+    components : Option<Vec<usize>>,
+    final_ce : Option<f64>,
+} // end of EmbedderDump
+
+
+// optimizer state written every n gradient batches, see Embedder::set_checkpoint
+#[derive(Serialize, Deserialize)]
+struct EmbedderCheckpoint<F> {
+    parameters : EmbedderParams,
+    /// number of gradient batches done. The random generator of a batch depends only on seed and batch rank
+    nb_batch_done : usize,
+    /// DataId of each node, row i of embedding has DataId data_ids\[i\]
+    data_ids : Vec<DataId>,
+    embedding : Array2<F>,
+    /// moments of nodes if optimizer is momentum or Adam
+    moments : Option<Vec<NodeMoments<F>>>,
+} // end of EmbedderCheckpoint
+
+
+// moments of a node for momentum and Adam optimizers, see GradientOptimizer
+#[derive(Clone, Serialize, Deserialize)]
+struct NodeMoments<F> {
+    /// last displacement for momentum, first moment of (minus) gradient for Adam
+    first : Array1<F>,
+    /// second moment of gradient for Adam, empty for momentum
+    second : Array1<F>,
+    /// number of updates of the node, for Adam bias correction
+    nb_update : u32,
+} // end of NodeMoments
+
+// starting positions of the 2 nodes of a sampled edge, kept if the optimizer works on displacements
+type EdgeStart<F> = Option<(Array1<F>, Array1<F>)>;
+
+
+
+/// cross entropy (loss) along a gradient optimization, see [Embedder::get_loss_history].  
+/// With [PairSampling::PaCMAP] or [PairSampling::TriMap] it is the cross entropy of the embedded graph, not the objective optimized.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct LossHistory {
+    /// (gradient batch, cross entropy after the batch), the first entry is the loss of the initial embedding
+    /// (batch 0, or the number of batches done when resuming from a checkpoint). The loss is computed every
+    /// [loss_period](EmbedderParams::loss_period) batches and after the last one
+    pub losses : Vec<(usize, f64)>,
+    /// cross entropy at the end of the optimization
+    pub final_loss : f64,
+} // end of LossHistory
+
+
 
 /// The structure corresponding to the embedding process. 
 /// It must be initialized by the graph extracted from Hnsw according to the choosen strategy
@@ -91,6 +193,22 @@ pub struct Embedder<'a,F> {
     initial_embedding : Option<Array2<F>>,
     /// final embedding
     embedding: Option<Array2<F>>,
+    /// rank of connected component of each node if components were embedded separately
+    components: Option<Vec<usize>>,
+    /// cross entropy at the end of the last gradient optimization
+    final_ce: Option<f64>,
+    /// cross entropy along gradient batches of the last optimization
+    loss_history: Option<LossHistory>,
+    /// optional correction of a categorical covariate, see [batchcorrect](crate::batchcorrect)
+    batch_correction: Option<BatchCorrection>,
+    /// time and memory of the stages of the last embedding
+    profile: Option<Vec<StageProfile>>,
+    /// file and period (in gradient batches) of optimizer checkpoints
+    checkpoint: Option<(PathBuf, usize)>,
+    /// observer of the stages and gradient iterations of embeddings
+    observer: Option<Arc<dyn EmbeddingObserver>>,
+    /// token stopping gradient iterations and randomized svd
+    cancellation: Option<CancellationToken>,
 } // end of Embedder
 
 
@@ -98,20 +216,62 @@ impl<'a,F> Embedder<'a,F>
 where
     F: Float + Lapack + Scalar + ndarray::ScalarOperand + Send + Sync,
 {
-    /// constructor from a graph and asked embedding dimension
+    /// constructor from a graph and embedding parameters. The graph does not need the Hnsw structure it comes from
+    /// to be in memory, it can be a graph reloaded with [KGraph::reload](crate::fromhnsw::kgraph::KGraph::reload).
     pub fn new(kgraph : &'a KGraph<F>, parameters : EmbedderParams) -> Self {
         Embedder::<F>{kgraph : Some(kgraph), hkgraph : None, parameters , initial_space:None, 
-                initial_embedding : None, embedding:None}
+                initial_embedding : None, embedding:None, components : None, final_ce : None, loss_history : None, batch_correction : None, profile : None, checkpoint : None,
+                observer : None, cancellation : None}
     } // end of new
 
 
+    /// construction from edge probabilities computed by the user, see [NodeParams::from_affinities].  
+    /// As there is no graph, the embedding must be retrieved by [get_embedded](Self::get_embedded), row i corresponding to node i.
+    pub fn from_node_params(node_params : NodeParams, parameters : EmbedderParams) -> Self {
+        Embedder::<F>{kgraph : None, hkgraph : None, parameters , initial_space: Some(node_params), 
+                initial_embedding : None, embedding:None, components : None, final_ce : None, loss_history : None, batch_correction : None, profile : None, checkpoint : None,
+                observer : None, cancellation : None}
+    } // end of from_node_params
+
+
     /// construction from a hierarchical graph
     pub fn from_hkgraph(graph_projection : &'a KGraphProjection<F>, parameters : EmbedderParams) -> Self {
         Embedder::<F>{kgraph : None, hkgraph : Some(graph_projection), parameters , initial_space:None, 
-                initial_embedding : None, embedding:None}
+                initial_embedding : None, embedding:None, components : None, final_ce : None, loss_history : None, batch_correction : None, profile : None, checkpoint : None,
+                observer : None, cancellation : None}
     } // end of from_hkgraph
 
 
+    /// sets a batch covariate (indexed by node rank in the graph, the large one in hierarchical case) to correct
+    /// before the final embedding. See [batchcorrect](crate::batchcorrect).
+    pub fn set_batch_correction(&mut self, correction : BatchCorrection) {
+        self.batch_correction = Some(correction);
+    }
+
+
+    /// sets the observer notified of the stages and gradient iterations of embeddings run by this Embedder, see [EmbeddingObserver]
+    pub fn set_observer<O>(&mut self, observer : O)
+    where
+        O: EmbeddingObserver + 'static,
+    {
+        self.observer = Some(Arc::new(observer));
+    }
+
+
+    /// sets a token stopping gradient iterations and randomized svd of embeddings run by this Embedder.
+    /// A cancelled embedding returns [AnnembedError::Cancelled].
+    pub fn set_cancellation_token(&mut self, token : &CancellationToken) {
+        self.cancellation = Some(token.clone());
+    }
+
+
+    // an embedder run as a step of this one has the same observer and token
+    fn share_run_state<'b>(&self, embedder : &mut Embedder<'b,F>) {
+        embedder.observer = self.observer.clone();
+        embedder.cancellation = self.cancellation.clone();
+    }
+
+
     pub fn get_asked_dimension(&self) -> usize {
         self.parameters.asked_dim
     }
@@ -120,6 +280,16 @@ where
         self.parameters.scale_rho
     }
 
+    // returns scale_rho from parameters or estimated from graph if auto_scale_rho is set
+    fn get_effective_scale_rho(&self, kgraph : &KGraph<F>) -> f32 {
+        if self.parameters.auto_scale_rho {
+            get_bgh_scale_rho(kgraph, self.parameters.beta as f32)
+        }
+        else {
+            self.parameters.scale_rho as f32
+        }
+    } // end of get_effective_scale_rho
+
     pub fn get_b(&self) -> f64 {
         self.parameters.b
     }
@@ -132,34 +302,55 @@ where
         self.parameters.nb_grad_batch
     }
 
-    /// dispatch to one_step embed or hierarchical embedding
-    pub fn embed(&mut self) -> Result<usize, usize> {
+    /// dispatch to one_step embed or hierarchical embedding.  
+    /// Runs in a pool of its own if [EmbedderParams::set_num_threads] was called.
+    pub fn embed(&mut self) -> Result<usize, AnnembedError> {
+        let profiler = StageProfiler::start();
+        let _observer = ObserverScope::enter(self.observer.clone());
+        let res = install_in_pool(self.parameters.num_threads, || self.embed_dispatch())?;
+        self.profile = Some(profiler.get_profiles());
+        res
+    } // end of embed
+
+
+    fn embed_dispatch(&mut self) -> Result<usize, AnnembedError> {
         if self.kgraph.is_some() {
             log::info!("doing one step embedding");
             return self.one_step_embed();
         }
+        else if self.hkgraph.is_none() && self.initial_space.is_some() {
+            log::info!("doing embedding of user node params");
+            self.parameters.log();
+            return self.embed_initial_space();
+        }
         else {
             log::info!("doing 2 step embedding");
             return self.h_embed();
         }
-    } // end of embed
+    } // end of embed_dispatch
 
 
     /// do hierarchical embedding on GraphPrrojection
-    pub fn h_embed(&mut self) -> Result<usize, usize> {
+    pub fn h_embed(&mut self) -> Result<usize, AnnembedError> {
         if self.hkgraph.is_none() {
             log::error!("Embedder::h_embed , graph projection is none");
-            return Err(1);
+            return Err(AnnembedError::Embedding(String::from("no graph projection")));
         }
         log::debug!("in h_embed");
         // one_step embed of the small graph.
-        let graph_projection = self.hkgraph.as_ref().unwrap();
+        let graph_projection = self.hkgraph.unwrap();
         log::info!(" embedding first (small) graph");
         let mut first_step_parameters = self.parameters;
         first_step_parameters.nb_grad_batch = self.parameters.grad_factor * self.parameters.nb_grad_batch;
         log::info!("nb initial batch : {}", first_step_parameters.nb_grad_batch);
         first_step_parameters.grad_step = 1.;
         let mut embedder_first_step = Embedder::new(graph_projection.get_small_graph(), first_step_parameters);
+        self.share_run_state(&mut embedder_first_step);
+        // nodes of the small graph are the first nodes of the large graph
+        if let Some(correction) = self.batch_correction.as_ref() {
+            let small_nodes : Vec<NodeIdx> = (0..graph_projection.get_small_graph().get_nb_nodes()).collect();
+            embedder_first_step.set_batch_correction(correction.get_restricted(&small_nodes));
+        }
         let cpu_start = ProcessTime::now();
         let sys_start = SystemTime::now();
         let res_first = embedder_first_step.one_step_embed();
@@ -167,17 +358,18 @@ where
             log::error!("Embedder::h_embed first step failed");
             return res_first;
         }
-        println!(" first step embedding sys time(ms) {:.2e} cpu time(ms) {:.2e}", sys_start.elapsed().unwrap().as_millis(), cpu_start.elapsed().as_millis());
+        log::info!("first step embedding sys time(ms) {:.2e} cpu time(ms) {:.2e}", sys_start.elapsed().unwrap().as_millis(), cpu_start.elapsed().as_millis());
         // get initial embedding
         let large_graph = graph_projection.get_large_graph();
         log::info!("computing proba edges for large graph ...");
-        self.initial_space = Some(to_proba_edges(large_graph, self.parameters.scale_rho as f32, self.parameters.beta as f32));
+        self.initial_space = Some(to_proba_edges(large_graph, self.get_effective_scale_rho(large_graph), self.parameters.beta as f32)?);
+        self.balance_batches()?;
         let nb_nodes_large = large_graph.get_nb_nodes();
         let first_embedding = embedder_first_step.get_embedded().unwrap();
         // use projection to initialize large graph
         let quant = graph_projection.get_projection_distance_quant();
         if quant.count() > 0 {
-            println!(" projection distance quantile at 0.05 : {:.2e} , 0.5 :  {:.2e}, 0.95 : {:.2e}, 0.99 : {:.2e}", 
+            log::info!("projection distance quantile at 0.05 : {:.2e} , 0.5 :  {:.2e}, 0.95 : {:.2e}, 0.99 : {:.2e}", 
                         quant.query(0.05).unwrap().1, quant.query(0.5).unwrap().1, 
                         quant.query(0.95).unwrap().1, quant.query(0.99).unwrap().1);
         };
@@ -186,7 +378,7 @@ where
         log::info!("doing projection");
         let (nb_nodes_small, _) = first_embedding.dim();
         // we were cautious on indexation so we can do:
-        let mut rng = thread_rng();
+        let mut rng = get_rng(self.parameters.seed, RNG_PROJECTION, 0);
         for i in 0..nb_nodes_small {
             for j in 0..dim {
                 second_step_init[[i,j]] = first_embedding[[i,j]];
@@ -194,7 +386,7 @@ where
         }
         let median_dist =  quant.query(0.5).unwrap().1;
         // we sample a random position around first embedding position
-        // TODO: amount of clipping
+        let clipping = self.parameters.clipping;
         let normal = Normal::<f32>::new(0., 1.0).unwrap();
         for i in nb_nodes_small..nb_nodes_large {
             let projected_edge = graph_projection.get_projection_by_nodeidx(&i);
@@ -202,8 +394,8 @@ where
             let ratio = projected_edge.weight.to_f32().unwrap() / median_dist;
             let correction = (ratio/dim as f32).sqrt();
             for j in 0..dim { 
-                let clipped_correction = clip(correction * normal.sample(&mut rng), 2.);
-                second_step_init[[i,j]] = first_embedding[[projected_edge.node,j]] + F::from(clipped_correction).unwrap();
+                let clipped_correction = clipping.clip((correction * normal.sample(&mut rng)) as f64, 2.);
+                second_step_init[[i,j]] = first_embedding[[projected_edge.get_node(),j]] + F::from(clipped_correction).unwrap();
             }
         }
         log::debug!("projection done");
@@ -211,18 +403,20 @@ where
         self.initial_embedding = Some(second_step_init);
         // cross entropy optimize
         log::info!("optimizing second step");
-        let embedding_res = self.entropy_optimize(&self.parameters, self.initial_embedding.as_ref().unwrap());
+        let embedding_res = self.entropy_optimize(&self.parameters, self.initial_embedding.as_ref().unwrap(), 0, None);
         //
-        println!(" first + second step embedding sys time(s) {:.2e} cpu time(s) {:.2e}", sys_start.elapsed().unwrap().as_secs(), cpu_start.elapsed().as_secs());
+        log::info!("first + second step embedding sys time(s) {:.2e} cpu time(s) {:.2e}", sys_start.elapsed().unwrap().as_secs(), cpu_start.elapsed().as_secs());
         //
         match embedding_res {
-            Ok(embedding) => {
+            Ok((embedding, loss_history)) => {
                 self.embedding = Some(embedding);
+                self.final_ce = Some(loss_history.final_loss);
+                self.loss_history = Some(loss_history);
                 return Ok(1);
             }
-            _ => {
+            Err(e) => {
                 log::error!("Embedder::embed : embedding optimization failed");
-                return Err(1);
+                return Err(e);
             }        
         }
     } // end of h_embed
@@ -230,43 +424,301 @@ where
 
 
     /// do the embedding
-    pub fn one_step_embed(&mut self) -> Result<usize, usize> {
+    pub fn one_step_embed(&mut self) -> Result<usize, AnnembedError> {
         //
         log::info!("doing 1 step embedding");
         self.parameters.log();
         let graph_to_embed = self.kgraph.unwrap();
+        if self.parameters.layout_components {
+            let components = graph_to_embed.get_connected_components();
+            let nb_components = components.iter().max().map_or(0, |c| c + 1);
+            if nb_components > 1 {
+                return self.components_embed(components, nb_components);
+            }
+        }
         // construction of initial neighbourhood, scales and proba of edges from distances.
         // we will need  initial_space representation for graph laplacian and in cross entropy optimization
-        self.initial_space = Some(to_proba_edges(graph_to_embed, self.parameters.scale_rho as f32, self.parameters.beta as f32));
+        self.initial_space = Some(to_proba_edges(graph_to_embed, self.get_effective_scale_rho(graph_to_embed), self.parameters.beta as f32)?);
+        self.embed_initial_space()
+    } // end embed
+
+
+    // initialization (diffusion maps or random) and cross entropy optimization once initial_space is set
+    fn embed_initial_space(&mut self) -> Result<usize, AnnembedError> {
         // we can initialize embedding with diffusion maps or pure random.
         let mut initial_embedding;
+        self.balance_batches()?;
         if self.parameters.dmap_init {
             // initial embedding via diffusion maps, in this case we have to have a coherent box normalization with random case
             let cpu_start = ProcessTime::now();
             let sys_start = SystemTime::now();
-            initial_embedding = get_dmap_embedding(self.initial_space.as_ref().unwrap(), self.parameters.get_dimension(), None);
-            println!(" dmap initialization sys time(ms) {:.2e} cpu time(ms) {:.2e}", sys_start.elapsed().unwrap().as_millis(), cpu_start.elapsed().as_millis());
-            set_data_box(&mut initial_embedding, 1.);
+            initial_embedding = match get_dmap_embedding(self.initial_space.as_ref().unwrap(), self.parameters.get_dimension(), None,
+                                                            self.parameters.eigen_solver, self.parameters.approx_svd, self.parameters.seed,
+                                                            self.cancellation.as_ref()) {
+                Ok(embedding) => embedding,
+                Err(e) => {
+                    log::error!("Embedder::one_step_embed dmap initialization failed : {}", e);
+                    return Err(e);
+                }
+            };
+            log::info!("dmap initialization sys time(ms) {:.2e} cpu time(ms) {:.2e}", sys_start.elapsed().unwrap().as_millis(), cpu_start.elapsed().as_millis());
+            if let Some(correction) = self.batch_correction.as_ref().filter(|c| c.do_regression()) {
+                correction.regress(&mut initial_embedding)?;
+            }
+            set_data_box(&mut initial_embedding, 1., &self.parameters.clipping);
         }
         else {
             // if we use random initialization we must have a box size coherent with renormalizes scales, so box size is 1.
             initial_embedding = self.get_random_init(1.);
         }
-        let embedding_res = self.entropy_optimize(&self.parameters, &initial_embedding);
+        let embedding_res = self.entropy_optimize(&self.parameters, &initial_embedding, 0, None);
         // optional store dump initial embedding
         self.initial_embedding = Some(initial_embedding);
         //
         match embedding_res {
-            Ok(embedding) => {
+            Ok((embedding, loss_history)) => {
                 self.embedding = Some(embedding);
+                self.final_ce = Some(loss_history.final_loss);
+                self.loss_history = Some(loss_history);
                 return Ok(1);
             }
-            _ => {
+            Err(e) => {
                 log::error!("Embedder::embed : embedding optimization failed");
-                return Err(1);
+                return Err(e);
             }        
         }
-    } // end embed
+    } // end of embed_initial_space
+
+
+    // rebalances edge probabilities of initial_space between batches if asked for
+    fn balance_batches(&mut self) -> Result<(), AnnembedError> {
+        match (self.batch_correction.as_ref(), self.initial_space.as_mut()) {
+            (Some(correction), Some(initial_space)) if correction.do_kernel_balance() => correction.balance(initial_space),
+            _ => Ok(()),
+        }
+    } // end of balance_batches
+
+
+    // Each connected component is embedded on its own, then component layouts are placed on a grid in the 2 first dimensions.
+    // The box of a component has a side proportional to the square root of its size relative to the largest component,
+    // so that point densities stay comparable between components. Component layouts are clipped with the clipping of parameters.
+    // Components too small to be optimized keep their diffusion maps position, or the center of their box if it can not be computed.
+    // The loss history is the sum of the cross entropies of optimized components.
+    fn components_embed(&mut self, components : Vec<usize>, nb_components : usize) -> Result<usize, AnnembedError> {
+        let graph = self.kgraph.unwrap();
+        // edge probabilities of the whole graph, used by uncertainty estimation and new points placement
+        self.initial_space = Some(to_proba_edges(graph, self.get_effective_scale_rho(graph), self.parameters.beta as f32)?);
+        self.balance_batches()?;
+        let dim = self.get_asked_dimension();
+        let nb_nodes = graph.get_nb_nodes();
+        log::info!("graph has {} connected components, embedding them separately", nb_components);
+        let mut members = vec![Vec::<NodeIdx>::new(); nb_components];
+        for (node, c) in components.iter().enumerate() {
+            members[*c].push(node);
+        }
+        let largest = members[0].len() as f64;
+        let nb_grid_col = (nb_components as f64).sqrt().ceil() as usize;
+        // distance between centers of grid cells, the largest component has a box of side 1.
+        let pitch = 1.25;
+        let min_size = MIN_COMPONENT_EMBED_SIZE.max(4 * graph.get_max_nbng());
+        let mut sub_parameters = self.parameters;
+        sub_parameters.layout_components = false;
+        // components are embedded in the pool already installed by embed
+        sub_parameters.num_threads = None;
+        let mut embedding = Array2::<F>::zeros((nb_nodes, dim));
+        let mut initial_embedding = Array2::<F>::zeros((nb_nodes, dim));
+        // cross entropies of optimized components by gradient batch
+        let mut losses = std::collections::BTreeMap::<usize, f64>::new();
+        let mut final_loss = 0.;
+        for (c, nodes) in members.iter().enumerate() {
+            let side = (nodes.len() as f64 / largest).sqrt();
+            let center = [(c % nb_grid_col) as f64 * pitch, (c / nb_grid_col) as f64 * pitch];
+            let sub_embedded : Array2<F>;
+            let sub_initial : Array2<F>;
+            if nodes.len() >= min_size {
+                log::debug!("embedding component {} , nb nodes : {}", c, nodes.len());
+                let subgraph = graph.get_subgraph(nodes);
+                let mut sub_embedder = Embedder::new(&subgraph, sub_parameters);
+                self.share_run_state(&mut sub_embedder);
+                if let Some(correction) = self.batch_correction.as_ref() {
+                    sub_embedder.set_batch_correction(correction.get_restricted(nodes));
+                }
+                sub_embedder.one_step_embed()?;
+                if let Some(history) = sub_embedder.get_loss_history() {
+                    for (batch, loss) in &history.losses {
+                        *losses.entry(*batch).or_insert(0.) += loss;
+                    }
+                    final_loss += history.final_loss;
+                }
+                let mut embedded = sub_embedder.get_embedded().unwrap().clone();
+                let mut initial = sub_embedder.get_initial_embedding().unwrap().clone();
+                set_data_box(&mut embedded, side, &self.parameters.clipping);
+                set_data_box(&mut initial, side, &self.parameters.clipping);
+                sub_embedded = embedded;
+                sub_initial = initial;
+            }
+            else {
+                // too few points for negative sampling, we keep the spectral position
+                log::debug!("component {} , nb nodes : {} not optimized", c, nodes.len());
+                sub_embedded = self.get_small_component_layout(graph, nodes, side);
+                sub_initial = sub_embedded.clone();
+            }
+            for (i, node) in nodes.iter().enumerate() {
+                for j in 0..dim {
+                    let shift = if j < 2 { F::from(center[j]).unwrap() } else { F::zero() };
+                    embedding[[*node, j]] = sub_embedded[[i,j]] + shift;
+                    initial_embedding[[*node, j]] = sub_initial[[i,j]] + shift;
+                }
+            }
+        }
+        //
+        self.initial_embedding = Some(initial_embedding);
+        self.embedding = Some(embedding);
+        self.components = Some(components);
+        self.final_ce = Some(final_loss);
+        self.loss_history = Some(LossHistory{losses : losses.into_iter().collect(), final_loss});
+        Ok(1)
+    } // end of components_embed
+
+
+    // diffusion maps layout of a small component in a box of given side centered at origin.
+    // If it can not be computed (too few nodes or edges), all nodes are at the center.
+    fn get_small_component_layout(&self, graph : &KGraph<F>, nodes : &[NodeIdx], side : f64) -> Array2<F> {
+        let dim = self.get_asked_dimension();
+        let subgraph = graph.get_subgraph(nodes);
+        let layout_res = to_proba_edges(&subgraph, self.get_effective_scale_rho(&subgraph), self.parameters.beta as f32)
+            .and_then(|node_params| get_dmap_embedding::<F>(&node_params, dim, None, self.parameters.eigen_solver,
+                        self.parameters.approx_svd, self.parameters.seed, None));
+        match layout_res {
+            Ok(mut layout) if layout.iter().all(|x| x.is_finite()) => {
+                set_data_box(&mut layout, side, &self.parameters.clipping);
+                layout
+            }
+            _ => {
+                log::debug!("no spectral layout for a component of {} nodes, nodes are put at its center", nodes.len());
+                Array2::<F>::zeros((nodes.len(), dim))
+            }
+        }
+    } // end of get_small_component_layout
+
+
+    /// returns, for each node (indexed as rows of [get_embedded](Self::get_embedded)), the rank of its connected component
+    /// if the graph was not connected and components were laid out separately. Components are ranked by decreasing size.
+    pub fn get_component_ids(&self) -> Option<&Vec<usize>> {
+        self.components.as_ref()
+    }
+
+
+    /// Runs further gradient batches starting from an existing layout instead of a diffusion maps or random initialization.  
+    /// The layout rows must be indexed by DataId as returned by [get_embedded_reindexed](Self::get_embedded_reindexed),
+    /// so it can come from a previous run on the same graph, possibly reloaded from a csv file.  
+    /// The new parameters are used for edge weights and optimization (number of batches, gradient step ...), the initialization
+    /// related parameters are ignored. As the optimization is tuned for layouts produced by this crate, a layout coming from
+    /// elsewhere should be rescaled to a similar range.
+    pub fn refine(&mut self, layout : &Array2<F>, parameters : EmbedderParams) -> Result<usize, AnnembedError> {
+        let profiler = StageProfiler::start();
+        let _observer = ObserverScope::enter(self.observer.clone());
+        let res = install_in_pool(parameters.num_threads, || self.refine_in_pool(layout, parameters))?;
+        self.profile = Some(profiler.get_profiles());
+        res
+    } // end of refine
+
+
+    fn refine_in_pool(&mut self, layout : &Array2<F>, parameters : EmbedderParams) -> Result<usize, AnnembedError> {
+        log::info!("refining an existing embedding");
+        let kgraph = if self.hkgraph.is_some()
+                            { self.hkgraph.as_ref().unwrap().get_large_graph() } 
+                     else   {self.kgraph.as_ref().unwrap() };
+        let nb_nodes = kgraph.get_nb_nodes();
+        if layout.ncols() != parameters.get_dimension() {
+            log::error!("Embedder::refine layout dimension {} do not match asked dimension {}", layout.ncols(), parameters.get_dimension());
+            return Err(AnnembedError::InvalidParameter(format!("layout dimension {} do not match asked dimension {}",
+                        layout.ncols(), parameters.get_dimension())));
+        }
+        // go from DataId indexation to node indexation
+        let mut initial_embedding = Array2::<F>::zeros((nb_nodes, layout.ncols()));
+        for i in 0..nb_nodes {
+            let data_id = *kgraph.get_data_id_from_idx(i).unwrap();
+            if data_id >= layout.nrows() {
+                log::error!("Embedder::refine no row in layout for data_id {}", data_id);
+                return Err(AnnembedError::InvalidParameter(format!("no row in layout for data_id {}", data_id)));
+            }
+            initial_embedding.row_mut(i).assign(&layout.row(data_id));
+        }
+        self.parameters = parameters;
+        self.parameters.log();
+        self.initial_space = Some(to_proba_edges(kgraph, self.get_effective_scale_rho(kgraph), self.parameters.beta as f32)?);
+        let embedding_res = self.entropy_optimize(&self.parameters, &initial_embedding, 0, None);
+        self.initial_embedding = Some(initial_embedding);
+        //
+        match embedding_res {
+            Ok((embedding, loss_history)) => {
+                self.embedding = Some(embedding);
+                self.final_ce = Some(loss_history.final_loss);
+                self.loss_history = Some(loss_history);
+                return Ok(1);
+            }
+            Err(e) => {
+                log::error!("Embedder::refine : embedding optimization failed");
+                return Err(e);
+            }        
+        }
+    } // end of refine_in_pool
+
+
+    /// Places new points in the embedding without modifying it (out-of-sample extension, as the umap transform).  
+    /// Neighbours of new points are searched in hnsw, which must be the structure the graph of the embedder was extracted from,
+    /// as their DataIds must be nodes of the graph. Edge probabilities of a new point are computed as for nodes of the graph
+    /// and its embedded scale is normalized by the scales of the stored [NodeParams].  
+    /// A new point starts at the barycenter of its embedded neighbours (weighted by edge probabilities), then only its coordinates
+    /// are optimized, with the gradient batches of the embedder parameters, against the frozen embedding.  
+    /// Returns a matrix with row i the coordinates of new_data\[i\].
+    pub fn transform<T, D>(&self, hnsw : &Hnsw<T, D>, new_data : &[Vec<T>]) -> Result<Array2<F>, AnnembedError>
+        where T : Clone + Send + Sync, D : Distance<T> + Send + Sync {
+        let embedded = self.embedding.as_ref().ok_or_else(|| AnnembedError::Embedding(String::from("transform needs an embedding")))?;
+        let kgraph = match (self.hkgraph, self.kgraph) {
+            (Some(hkgraph), _) => hkgraph.get_large_graph(),
+            (None, Some(kgraph)) => kgraph,
+            (None, None) => return Err(AnnembedError::Embedding(String::from("transform needs the graph of the embedding"))),
+        };
+        let scale_rho = self.get_effective_scale_rho(kgraph);
+        let beta = self.parameters.beta as f32;
+        // components laid out separately do not keep edge probabilities of the whole graph
+        let computed;
+        let node_params = match self.initial_space.as_ref() {
+            Some(node_params) => node_params,
+            None => {
+                computed = to_proba_edges(kgraph, scale_rho, beta)?;
+                &computed
+            }
+        };
+        let nb_nodes = node_params.get_nb_nodes();
+        if nb_nodes != embedded.nrows() {
+            return Err(AnnembedError::Embedding(format!("transform, nb nodes {} != nb embedded {}", nb_nodes, embedded.nrows())));
+        }
+        let mean_scale = node_params.params.iter().map(|p| p.scale).sum::<f32>() / nb_nodes as f32;
+        let knbn = kgraph.get_max_nbng();
+        let neighbours = hnsw.parallel_search(new_data, knbn, hnsw.get_ef_construction().max(knbn));
+        let transformed = neighbours.par_iter().enumerate().map(|(i, neighbours)| {
+            let edges : Vec<OutEdge<F>> = neighbours.iter()
+                .filter_map(|n| kgraph.get_idx_from_dataid(&n.d_id).map(|node| OutEdge::<F>::new(node, F::from(n.distance).unwrap())))
+                .collect();
+            if edges.is_empty() {
+                return Err(AnnembedError::InvalidParameter(format!("transform, new point {} has no neighbour in the graph", i)));
+            }
+            let node_param = get_scale_from_proba_normalisation(kgraph, scale_rho, beta, &edges)?;
+            let scale = embedded_scale_from_initial_scale(node_param.scale, mean_scale, self.parameters.kernel_width as f32) as f64;
+            let mut rng = get_rng(self.parameters.seed, RNG_TRANSFORM, i);
+            Ok(optimize_new_point(&node_param.edges, scale, embedded, &self.parameters, &mut rng))
+        }).collect::<Result<Vec<Array1<F>>, AnnembedError>>()?;
+        //
+        let mut res = Array2::<F>::zeros((new_data.len(), embedded.ncols()));
+        for (i, point) in transformed.iter().enumerate() {
+            res.row_mut(i).assign(point);
+        }
+        log::info!("transform placed {} new points", new_data.len());
+        Ok(res)
+    } // end of transform
 
 
     /// At the end returns the embedded data as Matrix. 
@@ -278,9 +730,65 @@ where
         return self.embedding.as_ref();
     }
 
+    /// returns the cross entropy (loss) at the end of the gradient optimization, None before embedding.
+    pub fn get_final_loss(&self) -> Option<f64> {
+        self.final_ce
+    }
+
+    /// returns the cross entropy computed every loss_period gradient batches (an epoch sampling each edge nb_sampling_by_edge times)
+    /// of the last optimization and its final value, to assess convergence and compare parameters, see [LossHistory].
+    /// In the hierarchical case it is the history of the optimization on the whole graph. If connected components were
+    /// embedded separately, losses of optimized components are summed by batch. None before embedding or after a reload.
+    pub fn get_loss_history(&self) -> Option<&LossHistory> {
+        self.loss_history.as_ref()
+    }
+
+    /// returns time and peak resident memory of the stages (node_params, laplacian, svd, gradient) of the last call to
+    /// [embed](Self::embed) or [refine](Self::refine), in order of completion. None before embedding or after a reload.
+    pub fn get_profile(&self) -> Option<&Vec<StageProfile>> {
+        self.profile.as_ref()
+    }
+
+    /// returns for each node (row of [get_embedded](Self::get_embedded)) an estimate of the uncertainty of its position,
+    /// in units of the embedded space.  
+    /// A node is placed by the attraction of its neighbours in the original graph, so we estimate the stability of
+    /// the barycenter of its embedded neighbours (weighted by edge probabilities) by a leave one edge out jackknife.
+    /// The jackknife needs at least 2 neighbours (with positive weight), nodes with less get NaN (no estimate).  
+    /// None before embedding.
+    pub fn get_local_uncertainty(&self) -> Option<Array1<f64>> {
+        let embedded = self.embedding.as_ref()?;
+        let node_params = self.initial_space.as_ref()?;
+        if node_params.get_nb_nodes() != embedded.nrows() {
+            log::error!("get_local_uncertainty, nb nodes {} != nb embedded {}", node_params.get_nb_nodes(), embedded.nrows());
+            return None;
+        }
+        Some(jackknife_uncertainty(embedded, node_params))
+    }
+
+    /// same as [get_local_uncertainty](Self::get_local_uncertainty), indexed by DataId. DataIds must be contiguous from 0
+    /// as for [get_embedded_reindexed](Self::get_embedded_reindexed).
+    pub fn get_local_uncertainty_reindexed(&self) -> Option<Array1<f64>> {
+        let uncertainty = self.get_local_uncertainty()?;
+        let mut reindexed = Array1::<f64>::zeros(uncertainty.len());
+        for (node, data_id) in self.get_data_ids().iter().enumerate() {
+            reindexed[*data_id] = uncertainty[node];
+        }
+        Some(reindexed)
+    }
 
 
 
+
+    /// returns the DataId corresponding to each row of the matrix returned by [get_embedded](Self::get_embedded).  
+    /// This enables aligning labels or any metadata attached to data, see [DataLabels](crate::tools::io::DataLabels).
+    pub fn get_data_ids(&self) -> Vec<DataId> {
+        let kgraph = if self.hkgraph.is_some()
+                            { self.hkgraph.as_ref().unwrap().get_large_graph() } 
+                     else   {self.kgraph.as_ref().unwrap() };
+        (0..kgraph.get_nb_nodes()).map(|i| *kgraph.get_data_id_from_idx(i).unwrap()).collect()
+    } // end of get_data_ids
+
+
     /// returns embedded data reindexed by DataId. This requires the DataId to be contiguous from 0 to nbdata.  
     ///  See [crate::fromhnsw::kgraph::KGraph::get_idx_from_dataid]
     pub fn get_embedded_reindexed(&self) -> Array2<F> {
@@ -321,6 +829,163 @@ where
         self.embedding.as_ref().unwrap().row(node)
     }
 
+    /// dumps the embedding in safetensors format, see [safetensors](crate::tools::safetensors).
+    /// Tensors are *embedding* (F32, rows ordered as nodes, as [get_embedded](Self::get_embedded)), *data_ids* (U64, DataId of each row)
+    /// and *uncertainty* (F64, see [get_local_uncertainty](Self::get_local_uncertainty)). Final loss and dimension are stored as metadata.
+    pub fn dump_safetensors(&self, path : &std::path::Path) -> Result<(), AnnembedError> {
+        let embedded = self.embedding.as_ref().ok_or_else(|| AnnembedError::Embedding(String::from("no embedding to dump")))?;
+        let mut writer = SafeTensorsWriter::new();
+        writer.add_array2_f32("embedding", embedded)?;
+        writer.add_u64("data_ids", &self.get_data_ids())?;
+        if let Some(uncertainty) = self.get_local_uncertainty() {
+            writer.add_array1_f64("uncertainty", uncertainty.as_slice().unwrap())?;
+        }
+        writer.add_metadata("format", "annembed");
+        writer.add_metadata("dimension", &embedded.ncols().to_string());
+        if let Some(loss) = self.final_ce {
+            writer.add_metadata("final_loss", &loss.to_string());
+        }
+        writer.write(path)
+    } // end of dump_safetensors
+
+    /// writes an interactive html scatter plot of the 2 first dimensions of the embedding, see [HtmlPlot].  
+    /// Points are colored by their label in labels if given, points without label get an empty label.
+    pub fn dump_html<L : std::fmt::Display>(&self, path : &std::path::Path, labels : Option<&DataLabels<L>>) -> Result<(), AnnembedError> {
+        let embedded = self.embedding.as_ref().ok_or_else(|| AnnembedError::Embedding(String::from("no embedding to plot")))?;
+        let data_ids = self.get_data_ids();
+        let labels : Option<Vec<String>> = labels.map(|labels| {
+            data_ids.iter().map(|id| labels.get(id).map(|l| l.to_string()).unwrap_or_default()).collect()
+        });
+        HtmlPlot::default().write(path, &embedded.view(), &data_ids, labels.as_deref())
+    } // end of dump_html
+
+
+    /// dumps in bincode format the fitted state : parameters, edge probabilities ([NodeParams]), DataId of nodes
+    /// (in the order of the graph IndexSet), final coordinates, components and final loss.  
+    /// The dump starts with [EDGE_IDX_BITS], it can only be reloaded by a build with the same edge index width.
+    /// See [reload](Self::reload).
+    pub fn dump(&self, path : &std::path::Path) -> Result<(), AnnembedError> {
+        let embedding = self.embedding.as_ref().ok_or_else(|| AnnembedError::Embedding(String::from("no embedding to dump")))?;
+        let data_ids = if self.kgraph.is_some() || self.hkgraph.is_some() { self.get_data_ids() } else { (0..embedding.nrows()).collect() };
+        let to_dump = EmbedderDump{parameters : self.parameters, data_ids, initial_space : self.initial_space.clone(),
+                            embedding : embedding.clone(), components : self.components.clone(), final_ce : self.final_ce};
+        let mut writer = BufWriter::new(OpenOptions::new().write(true).create(true).truncate(true).open(path)?);
+        // width of edge indexes of NodeParams first, so that reload can check it before decoding edges
+        bincode::serialize_into(&mut writer, &EDGE_IDX_BITS).map_err(|e| AnnembedError::Io(std::io::Error::other(e)))?;
+        bincode::serialize_into(&mut writer, &to_dump).map_err(|e| AnnembedError::Io(std::io::Error::other(e)))?;
+        writer.flush()?;
+        log::info!("embedder dumped in {}", path.display());
+        Ok(())
+    } // end of dump
+
+
+    /// reloads an Embedder dumped by [dump](Self::dump), without running the embedding again.  
+    /// kgraph is the graph of the dumped embedder (for example reloaded by [KGraph::reload]), it is necessary for
+    /// [transform](Self::transform) which also needs the Hnsw structure (reloaded by hnsw_rs io).
+    /// Its nodes must have the DataIds of the dumped nodes, in the same order. Without graph rows of the embedding are nodes
+    /// of the dumped [NodeParams].
+    pub fn reload(path : &std::path::Path, kgraph : Option<&'a KGraph<F>>) -> Result<Self, AnnembedError> {
+        let mut reader = BufReader::new(OpenOptions::new().read(true).open(path)?);
+        let edge_idx_bits : u32 = bincode::deserialize_from(&mut reader).map_err(|e| AnnembedError::Io(std::io::Error::other(e)))?;
+        check_edge_idx_bits(edge_idx_bits)?;
+        let dumped : EmbedderDump<F> = bincode::deserialize_from(reader).map_err(|e| AnnembedError::Io(std::io::Error::other(e)))?;
+        if dumped.embedding.nrows() != dumped.data_ids.len() {
+            return Err(AnnembedError::InvalidParameter(format!("Embedder::reload inconsistent dump, nb rows {} != nb ids {}",
+                        dumped.embedding.nrows(), dumped.data_ids.len())));
+        }
+        if let Some(kgraph) = kgraph {
+            let same_nodes = kgraph.get_nb_nodes() == dumped.data_ids.len() &&
+                dumped.data_ids.iter().enumerate().all(|(i, id)| kgraph.get_data_id_from_idx(i) == Some(id));
+            if !same_nodes {
+                log::error!("Embedder::reload, nodes of graph do not match dumped nodes");
+                return Err(AnnembedError::InvalidParameter(String::from("nodes of graph do not match dumped embedder")));
+            }
+        }
+        log::info!("embedder reloaded from {}, nb nodes : {}", path.display(), dumped.data_ids.len());
+        Ok(Embedder::<F>{kgraph, hkgraph : None, parameters : dumped.parameters, initial_space : dumped.initial_space,
+                initial_embedding : None, embedding : Some(dumped.embedding), components : dumped.components,
+                final_ce : dumped.final_ce, loss_history : None, batch_correction : None, profile : None, checkpoint : None,
+                observer : None, cancellation : None})
+    } // end of reload
+
+
+    /// asks for a checkpoint of the gradient optimization every every_n gradient batches, and when the optimization
+    /// is cancelled (see [CancellationToken](crate::tools::stage::CancellationToken)). Each checkpoint replaces the previous one in file path.  
+    /// The checkpoint stores the parameters, the number of batches done, the coordinates and the moments of the
+    /// optimizer (see [GradientOptimizer]), the random generator of a batch
+    /// depending only on the seed and the rank of the batch. See [resume_from](Self::resume_from).  
+    /// Only the optimization on the whole graph is checkpointed, not the first step of a hierarchical embedding
+    /// nor the optimizations of connected components embedded separately.
+    pub fn set_checkpoint(&mut self, path : &std::path::Path, every_n : usize) {
+        self.checkpoint = Some((path.to_path_buf(), every_n.max(1)));
+    }
+
+
+    /// returns the parameters stored in a checkpoint written by [set_checkpoint](Self::set_checkpoint), without reading the
+    /// optimizer state. It can be used to check the parameters of a run before [resume_from](Self::resume_from).
+    pub fn get_checkpoint_parameters(path : &std::path::Path) -> Result<EmbedderParams, AnnembedError> {
+        let reader = BufReader::new(OpenOptions::new().read(true).open(path)?);
+        // parameters are the first field of the checkpoint
+        bincode::deserialize_from(reader).map_err(|e| AnnembedError::Io(std::io::Error::other(e)))
+    }
+
+
+    /// resumes the gradient optimization from a checkpoint written by an Embedder on the same graph, see [set_checkpoint](Self::set_checkpoint).
+    /// The parameters of the checkpoint are used, except the number of threads. The initialization is skipped and
+    /// the remaining gradient batches are run, so that with a seed (and one thread) the embedding is the same as without interruption.
+    /// Checkpoints go on if asked for.
+    pub fn resume_from(&mut self, path : &std::path::Path) -> Result<usize, AnnembedError> {
+        let reader = BufReader::new(OpenOptions::new().read(true).open(path)?);
+        let checkpoint : EmbedderCheckpoint<F> = bincode::deserialize_from(reader).map_err(|e| AnnembedError::Io(std::io::Error::other(e)))?;
+        let kgraph = match (self.hkgraph, self.kgraph) {
+            (Some(hkgraph), _) => hkgraph.get_large_graph(),
+            (None, Some(kgraph)) => kgraph,
+            (None, None) => return Err(AnnembedError::Embedding(String::from("resume_from needs the graph of the checkpoint"))),
+        };
+        let same_nodes = kgraph.get_nb_nodes() == checkpoint.data_ids.len() &&
+            checkpoint.data_ids.iter().enumerate().all(|(i, id)| kgraph.get_data_id_from_idx(i) == Some(id));
+        if !same_nodes || checkpoint.embedding.dim() != (checkpoint.data_ids.len(), checkpoint.parameters.get_dimension()) {
+            log::error!("Embedder::resume_from, nodes of graph do not match checkpoint {}", path.display());
+            return Err(AnnembedError::InvalidParameter(String::from("nodes of graph do not match checkpoint")));
+        }
+        log::info!("resuming embedding from {} after {} gradient batches", path.display(), checkpoint.nb_batch_done);
+        let num_threads = self.parameters.num_threads;
+        self.parameters = checkpoint.parameters;
+        self.parameters.num_threads = num_threads;
+        let profiler = StageProfiler::start();
+        let _observer = ObserverScope::enter(self.observer.clone());
+        let res = install_in_pool(num_threads, || {
+            self.initial_space = Some(to_proba_edges(kgraph, self.get_effective_scale_rho(kgraph), self.parameters.beta as f32)?);
+            self.balance_batches()?;
+            let (embedding, loss_history) = self.entropy_optimize(&self.parameters, &checkpoint.embedding, checkpoint.nb_batch_done, checkpoint.moments)?;
+            self.initial_embedding = None;
+            self.embedding = Some(embedding);
+            self.final_ce = Some(loss_history.final_loss);
+            self.loss_history = Some(loss_history);
+            Ok(1)
+        })?;
+        self.profile = Some(profiler.get_profiles());
+        res
+    } // end of resume_from
+
+
+    // writes the checkpoint in a temporary file renamed at the end, so that an interruption leaves the previous checkpoint
+    fn write_checkpoint(&self, path : &std::path::Path, params : &EmbedderParams, nb_batch_done : usize, embedding : Array2<F>,
+                moments : Option<Vec<NodeMoments<F>>>) -> Result<(), AnnembedError> {
+        let data_ids = if self.kgraph.is_some() || self.hkgraph.is_some() { self.get_data_ids() } else { (0..embedding.nrows()).collect() };
+        let checkpoint = EmbedderCheckpoint{parameters : *params, nb_batch_done, data_ids, embedding, moments};
+        let mut tmp_name = path.as_os_str().to_owned();
+        tmp_name.push(".tmp");
+        let tmp_path = PathBuf::from(tmp_name);
+        let mut writer = BufWriter::new(OpenOptions::new().write(true).create(true).truncate(true).open(&tmp_path)?);
+        bincode::serialize_into(&mut writer, &checkpoint).map_err(|e| AnnembedError::Io(std::io::Error::other(e)))?;
+        writer.flush()?;
+        drop(writer);
+        std::fs::rename(&tmp_path, path)?;
+        log::info!("embedder checkpoint after {} gradient batches written in {}", nb_batch_done, path.display());
+        Ok(())
+    } // end of write_checkpoint
+
     
      /// returns the initial embedding. Same remark as for method get_embedded. Storage is optional TODO
      pub fn get_initial_embedding(&self) -> Option<&Array2<F>> {
@@ -359,7 +1024,7 @@ where
         let nb_nodes = self.initial_space.as_ref().unwrap().get_nb_nodes();
         let mut initial_embedding = Array2::<F>::zeros((nb_nodes, self.get_asked_dimension()));
         let unif = Uniform::<f32>::new(-size/2. , size/2.);
-        let mut rng = thread_rng();
+        let mut rng = get_rng(self.parameters.seed, RNG_INIT, 0);
         for i in 0..nb_nodes {
             for j in 0..self.get_asked_dimension() {
                 initial_embedding[[i,j]] = F::from(rng.sample(unif)).unwrap();
@@ -389,7 +1054,7 @@ where
         }
         else {
             log::info!("could not find kgraph");
-            std::process::exit(1);
+            return None;
         }
         // we loop on kgraph nodes, loop on edges of node, get extremity id , converts to index, compute embedded distance and sum
         let neighbours = kgraph.get_neighbours();
@@ -400,10 +1065,10 @@ where
                 let mut transformed_neighborhood = Vec::<OutEdge<F>>::with_capacity(neighbours[n].len());
                 let mut node_edge_length = F::max_value();
                 for edge in  &neighbours[n] {
-                    let ext_embedded = self.get_embedded_by_nodeid(edge.node);
+                    let ext_embedded = self.get_embedded_by_nodeid(edge.get_node());
                     // now we can compute distance for corresponding edge in embedded space. We must use L2
                     node_edge_length = distl2(node_embedded.as_slice().unwrap(), &ext_embedded.as_slice().unwrap()).min(node_edge_length);
-                    transformed_neighborhood.push(OutEdge::<F>::new(edge.node, node_edge_length));
+                    transformed_neighborhood.push(OutEdge::<F>::new(edge.get_node(), node_edge_length));
                 }
                 // sort transformed_neighborhood
                 transformed_neighborhood.sort_unstable_by(|a,b|  a.weight.partial_cmp(&b.weight).unwrap());
@@ -446,7 +1111,7 @@ where
         }
         hnsw.parallel_insert_slice(&data_with_id);
         // compute kgraph from hnsw and sum edge length 
-        let optimal_graph : Result<KGraph<F>, usize>  = kgraph_from_hnsw_all(&hnsw, nbng);
+        let optimal_graph : Result<KGraph<F>, AnnembedError>  = kgraph_from_hnsw_all(&hnsw, nbng);
         if optimal_graph.is_err() {
             log::error!("could not compute optimal graph");
             return None;
@@ -509,7 +1174,7 @@ where
         let transformed_kgraph = self.get_transformed_kgraph();
         if transformed_kgraph.is_none() {
             log::error!("cannot ask for embedded quality before embedding");
-            std::process::exit(1);
+            return None;
         }
         let transformed_kgraph = transformed_kgraph.unwrap();
         // compute max edge length from kgraph constructed from embedded points corresponding to nbng neighbours
@@ -522,9 +1187,9 @@ where
         // now we can for each node see if best of propagated initial edges encounter ball in reconstructed kgraph from embedded data
         assert_eq!(max_edges_embedded.len(), transformed_kgraph.len());
 
-        let mut embedded_radii = CKMS::<f64>::new(0.01);
-        let mut ratio_dist_q = CKMS::<f64>::new(0.01);
-        let mut max_edges_q = CKMS::<f64>::new(0.01);
+        let mut embedded_radii = Quantiles::<f64>::new(0.01);
+        let mut ratio_dist_q = Quantiles::<f64>::new(0.01);
+        let mut max_edges_q = Quantiles::<f64>::new(0.01);
         let nb_nodes = max_edges_embedded.len(); 
         let mut nodes_match = Vec::with_capacity(nb_nodes);
         let mut first_dist = Vec::with_capacity(nb_nodes);
@@ -551,31 +1216,34 @@ where
         // some stats
         let nb_without_match = nodes_match.iter().fold(0, |acc, x| if *x == 0 {acc +1} else {acc});
         let mean_nbmatch: f64 = nodes_match.iter().sum::<usize>() as f64 / (nodes_match.len() - nb_without_match)  as f64;
-        println!("\n\n a guess at quality ");
-        println!("  nb neighbourhoods without a match : {},  mean number of neighbours conserved when match : {:.3e}", nb_without_match,  mean_nbmatch);
-        println!("  embedded radii quantiles at 0.05 : {:.2e} , 0.25 : {:.2e}, 0.5 :  {:.2e}, 0.75 : {:.2e}, 0.85 : {:.2e}, 0.95 : {:.2e} \n", 
+        log::info!("a guess at quality");
+        log::info!("  nb neighbourhoods without a match : {},  mean number of neighbours conserved when match : {:.3e}", nb_without_match,  mean_nbmatch);
+        log::info!("  embedded radii quantiles at 0.05 : {:.2e} , 0.25 : {:.2e}, 0.5 :  {:.2e}, 0.75 : {:.2e}, 0.85 : {:.2e}, 0.95 : {:.2e}", 
             embedded_radii.query(0.05).unwrap().1, embedded_radii.query(0.25).unwrap().1, embedded_radii.query(0.5).unwrap().1, 
             embedded_radii.query(0.75).unwrap().1, embedded_radii.query(0.85).unwrap().1, embedded_radii.query(0.95).unwrap().1);
         //
-        println!("\n quantiles on max edges in embedded space");
-        println!("  quantiles at 0.05 : {:.2e} , 0.25 : {:.2e}, 0.5 :  {:.2e}, 0.75 : {:.2e}, 0.85 : {:.2e}, 0.95 : {:.2e} \n", 
+        log::info!("quantiles on max edges in embedded space");
+        log::info!("  quantiles at 0.05 : {:.2e} , 0.25 : {:.2e}, 0.5 :  {:.2e}, 0.75 : {:.2e}, 0.85 : {:.2e}, 0.95 : {:.2e}", 
             max_edges_q.query(0.05).unwrap().1, max_edges_q.query(0.25).unwrap().1, max_edges_q.query(0.5).unwrap().1, 
             max_edges_q.query(0.75).unwrap().1, max_edges_q.query(0.85).unwrap().1, max_edges_q.query(0.95).unwrap().1);        
         // The smaller the better!
         // we give quantiles on ratio : distance of neighbours in origin space / distance of last neighbour in embedded space
-        println!("\n statistics on conservation of neighborhood (of size nbng)");
-        println!("  quantiles on ratio : distance in embedded space of neighbours of origin space / distance of last neighbour in embedded space");
-        println!("  quantiles at 0.05 : {:.2e} , 0.25 : {:.2e}, 0.5 :  {:.2e}, 0.75 : {:.2e}, 0.85 : {:.2e}, 0.95 : {:.2e} \n", 
+        log::info!("statistics on conservation of neighborhood (of size nbng)");
+        log::info!("  quantiles on ratio : distance in embedded space of neighbours of origin space / distance of last neighbour in embedded space");
+        log::info!("  quantiles at 0.05 : {:.2e} , 0.25 : {:.2e}, 0.5 :  {:.2e}, 0.75 : {:.2e}, 0.85 : {:.2e}, 0.95 : {:.2e}", 
             ratio_dist_q.query(0.05).unwrap().1, ratio_dist_q.query(0.25).unwrap().1, ratio_dist_q.query(0.5).unwrap().1, 
             ratio_dist_q.query(0.75).unwrap().1, ratio_dist_q.query(0.85).unwrap().1, ratio_dist_q.query(0.95).unwrap().1);
         
         let median_ratio = ratio_dist_q.query(0.5).unwrap().1;
-        println!("\n quality index: ratio of distance to neighbours in origin space / distance to last neighbour in embedded space");
-        println!("  neighborhood are conserved in radius multiplied by median  : {:.2e}, mean {:.2e} ", median_ratio, mean_ratio.0 / mean_ratio.1 as f64);
+        log::info!("quality index: ratio of distance to neighbours in origin space / distance to last neighbour in embedded space");
+        log::info!("  neighborhood are conserved in radius multiplied by median  : {:.2e}, mean {:.2e} ", median_ratio, mean_ratio.0 / mean_ratio.1 as f64);
         //
-        let mut csv_dist = Writer::from_path("first_dist.csv").unwrap();
-        let _res = write_csv_labeled_array2(&mut csv_dist, first_dist.as_slice(), &self.get_embedded_reindexed());
-        csv_dist.flush().unwrap();
+        match CsvArrayWriter::to_path(std::path::Path::new("first_dist.csv"), &CsvOptions::default()) {
+            Ok(mut csv_dist) => {
+                let _res = csv_dist.write_labeled_array2(first_dist.as_slice(), &self.get_embedded_reindexed());
+            }
+            Err(e) => log::error!("could not dump first_dist.csv : {}", e),
+        }
         ///
         let cpu_time: Duration = cpu_start.elapsed();
         log::info!(" quality estimation,  sys time(s) {:?} cpu time {:?}", sys_now.elapsed().unwrap().as_secs(), cpu_time.as_secs());
@@ -624,23 +1292,65 @@ where
     // The initial density makes the embedded graph asymetric as the initial graph.
     // The optimization function thus should try to restore asymetry and local scale as far as possible.
     // returns the embedded data after restauration of the original indexation/identification of datas! (time consuming bug)
-    fn entropy_optimize(&self, params : &EmbedderParams, initial_embedding : &Array2<F>) -> Result<Array2<F>, String> {
+    // returns the optimized embedding and the cross entropy along batches.
+    // nb_batch_done gradient batches were already done and moments are the optimizer state (when resuming from a checkpoint)
+    fn entropy_optimize(&self, params : &EmbedderParams, initial_embedding : &Array2<F>, nb_batch_done : usize,
+                moments : Option<Vec<NodeMoments<F>>>) -> Result<(Array2<F>, LossHistory), AnnembedError> {
         //
         log::debug!("in Embedder::entropy_optimize");
         //
         if self.initial_space.is_none() {
             log::error!("Embedder::entropy_optimize : initial_space not constructed, exiting");
-            return Err(String::from(" initial_space not constructed, no NodeParams"));
+            return Err(AnnembedError::Embedding(String::from("initial_space not constructed, no NodeParams")));
+        }
+        if !params.exaggeration.is_finite() || params.exaggeration < 1. {
+            log::error!("Embedder::entropy_optimize : exaggeration factor must be a finite value >= 1, got {}", params.exaggeration);
+            return Err(AnnembedError::InvalidParameter(format!("exaggeration factor {} must be >= 1", params.exaggeration)));
+        }
+        if params.exaggeration != 1. && params.exaggeration_batches > 0 && params.pair_sampling != PairSampling::Negative {
+            log::error!("Embedder::entropy_optimize : early exaggeration is only possible with PairSampling::Negative, got {:?}", params.pair_sampling);
+            return Err(AnnembedError::InvalidParameter(format!("early exaggeration is not possible with pair sampling {:?}", params.pair_sampling)));
+        }
+        if params.loss_period == 0 {
+            log::error!("Embedder::entropy_optimize : loss period must be >= 1");
+            return Err(AnnembedError::InvalidParameter(String::from("loss period must be >= 1")));
+        }
+        if !(params.kernel_width > 0. && params.kernel_width.is_finite()) {
+            log::error!("Embedder::entropy_optimize : kernel width must be positive, got {}", params.kernel_width);
+            return Err(AnnembedError::InvalidParameter(format!("kernel width {} must be positive", params.kernel_width)));
+        }
+        let stage = Stage::enter("gradient");
+        let mut ce_optimization = EntropyOptim::new(self.initial_space.as_ref().unwrap(), params, initial_embedding);
+        if let Some(moments) = moments {
+            ce_optimization.set_moments(moments);
+        }
+        if params.dens_lambda > 0. {
+            // densMAP needs distances in original space
+            let kgraph = match (self.hkgraph, self.kgraph) {
+                (Some(hkgraph), _) => hkgraph.get_large_graph(),
+                (None, Some(kgraph)) => kgraph,
+                (None, None) => {
+                    log::error!("Embedder::entropy_optimize : density term needs a graph with original distances");
+                    return Err(AnnembedError::InvalidParameter(String::from("dens_lambda > 0 needs a graph with original distances")));
+                }
+            };
+            ce_optimization.set_density(get_original_log_radii(kgraph, self.initial_space.as_ref().unwrap()));
         }
-        let ce_optimization = EntropyOptim::new(self.initial_space.as_ref().unwrap(), params, initial_embedding);
         // compute initial value of objective function
-        let start = ProcessTime::now();
         let initial_ce = ce_optimization.ce_compute_threaded();
-        let cpu_time: Duration = start.elapsed();
-        println!(" initial cross entropy value {:.2e},  in time {:?}", initial_ce, cpu_time);
+        if !initial_ce.is_finite() {
+            log::error!("Embedder::entropy_optimize : non finite initial cross entropy");
+            return Err(AnnembedError::NonFinite("initial embedding"));
+        }
+        stage.record("initial_ce", initial_ce);
+        let mut losses = vec![(nb_batch_done, initial_ce)];
         // We manage some iterations on gradient computing
-        let grad_step_init = params.grad_step;
-        log::info!("grad_step_init : {:.2e}", grad_step_init);
+        // Adam has its own step in embedded coordinates units
+        let grad_step_init = match params.optimizer {
+            GradientOptimizer::Adam { lr, .. } => lr,
+            _ => params.grad_step,
+        };
+        log::info!("grad_step_init : {:.2e}, schedule : {:?}, optimizer : {:?}", grad_step_init, params.step_schedule, params.optimizer);
         //
         log::debug!("in Embedder::entropy_optimize  ... gradient iterations");
         let nb_sample_by_iter = params.nb_sampling_by_edge * ce_optimization.get_nb_edges();
@@ -648,18 +1358,54 @@ where
         log::info!("\n optimizing embedding");
         log::info!(" nb edges {} , number of edge sampling by grad iteration {}", ce_optimization.get_nb_edges(), nb_sample_by_iter);
         log::info!(" nb iteration : {}  sampling size {} ", self.get_nb_grad_batch(), nb_sample_by_iter);
-        let cpu_start = ProcessTime::now();
-        let sys_start = SystemTime::now();
-        for iter in 1..=self.get_nb_grad_batch() {
+        stage.record_size("nb_nodes", self.get_nb_nodes());
+        stage.record_size("nb_edges", ce_optimization.get_nb_edges());
+        stage.record_size("nb_grad_batch", self.get_nb_grad_batch());
+        stage.record_size("nb_sample_by_iter", nb_sample_by_iter);
+        for iter in (nb_batch_done + 1)..=self.get_nb_grad_batch() {
+            if is_cancelled(self.cancellation.as_ref()) {
+                log::info!("Embedder::entropy_optimize cancelled at gradient iteration {}", iter);
+                // the state reached can be resumed
+                if let Some((path, _)) = self.checkpoint.as_ref() {
+                    self.write_checkpoint(path, params, iter - 1, ce_optimization.get_embedded_raw(), ce_optimization.get_moments())?;
+                }
+                return Err(AnnembedError::Cancelled);
+            }
             // loop on edges
-            let grad_step = grad_step_init * (1.- iter as f64/self.get_nb_grad_batch() as f64);
-            ce_optimization.gradient_iteration_threaded(nb_sample_by_iter, grad_step);
+            let grad_step = grad_step_init * params.step_schedule.factor(iter, self.get_nb_grad_batch());
+            let exaggeration = if iter <= params.exaggeration_batches { params.exaggeration } else { 1. };
+            // density term on last fraction dens_frac of batches
+            ce_optimization.update_density(iter as f64 / self.get_nb_grad_batch() as f64 > 1. - params.dens_frac);
+            ce_optimization.gradient_iteration_threaded(nb_sample_by_iter, grad_step, exaggeration, iter);
+            stage.report_progress(iter, self.get_nb_grad_batch());
+            // the loss is a pass on all edges, it is computed only when recorded or observed
+            let observed = self.observer.as_ref().is_some_and(|o| o.epoch_period() > 0 && iter % o.epoch_period() == 0);
+            let recorded = iter % params.loss_period == 0 || iter == self.get_nb_grad_batch();
+            if recorded || observed {
+                let ce = ce_optimization.ce_compute_threaded();
+                if recorded {
+                    losses.push((iter, ce));
+                }
+                if observed {
+                    self.observer.as_ref().unwrap().on_epoch(iter, self.get_nb_grad_batch(), ce);
+                }
+            }
+            if let Some((path, every_n)) = self.checkpoint.as_ref() {
+                if iter % every_n == 0 && iter < self.get_nb_grad_batch() {
+                    self.write_checkpoint(path, params, iter, ce_optimization.get_embedded_raw(), ce_optimization.get_moments())?;
+                }
+            }
 //            let cpu_time: Duration = start.elapsed();
 //            log::debug!("ce after grad iteration time(ms) {:.2e} grad iter {:.2e}",  cpu_time.as_millis(), ce_optimization.ce_compute_threaded());
         }
-        println!(" gradient iterations sys time(s) {:.2e} , cpu_time(s) {:.2e}",  sys_start.elapsed().unwrap().as_secs(), cpu_start.elapsed().as_secs());
-        let final_ce = ce_optimization.ce_compute_threaded();
-        println!(" final cross entropy value {:.2e}", final_ce);
+        stage.record("iterations_sys_ms", stage.get_sys_ms() as f64);
+        // embedding has not moved since last loss computation
+        let final_ce = losses.last().unwrap().1;
+        if !final_ce.is_finite() {
+            log::error!("Embedder::entropy_optimize : non finite cross entropy after gradient iterations");
+            return Err(AnnembedError::NonFinite("embedding"));
+        }
+        stage.record("final_ce", final_ce);
         // return reindexed data (if possible)
         let dim = self.get_asked_dimension();
         let nbrow = self.get_nb_nodes();
@@ -674,7 +1420,7 @@ where
             }
         }
         //
-        Ok(reindexed)
+        Ok((reindexed, LossHistory{losses, final_loss : final_ce}))
         //
     } // end of entropy_optimize
 
@@ -701,9 +1447,41 @@ struct EntropyOptim<'a, F> {
     pos_edge_distribution : WeightedAliasIndex<f32>,
     /// embedding parameters
     params : &'a EmbedderParams,
+    /// moments of each node if optimizer is momentum or Adam
+    moments : Option<Vec<RwLock<NodeMoments<F>>>>,
+    /// densMAP term if dens_lambda > 0
+    density : Option<DensityTerm>,
 } // end of EntropyOptim
 
 
+// densMAP term : minus dens_lambda times the correlation between logs of local radii in original and embedded space
+struct DensityTerm {
+    /// original log radii, centered and reduced
+    orig_radii : Vec<f64>,
+    /// sum of edge weights
+    mu_tot : f64,
+    /// statistics of embedded radii, refreshed before each batch
+    stats : RwLock<DensityStats>,
+} // end of DensityTerm
+
+
+// statistics of embedded local radii at the beginning of a gradient batch
+#[derive(Default)]
+struct DensityStats {
+    /// true if the term is used in current batch
+    active : bool,
+    /// sum of embedded weights of out edges of each node
+    phi_sum : Vec<f64>,
+    /// log of embedded local radii
+    log_radii : Vec<f64>,
+    mean : f64,
+    /// standard deviation (shifted by DENS_VAR_SHIFT) of log radii
+    std : f64,
+    /// covariance of log embedded radii and reduced log original radii
+    cov : f64,
+} // end of DensityStats
+
+
 
 
 impl <'a, F> EntropyOptim<'a,F> 
@@ -737,20 +1515,27 @@ impl <'a, F> EntropyOptim<'a,F>
             embedded.push(Arc::new(RwLock::new(initial_embed.row(i).to_owned())));
         }
         // compute embedded scales
-        let embedded_scales = estimate_embedded_scales_from_initial_scales(&initial_scales);
+        let embedded_scales = estimate_embedded_scales_from_initial_scales(&initial_scales, params.kernel_width as f32);
         // get qunatile on embedded scales
-        let mut scales_q = CKMS::<f32>::new(0.001);
+        let mut scales_q = Quantiles::<f32>::new(0.001);
         for s in &embedded_scales {
             scales_q.insert(*s);
         }
-        println!("\n\n embedded scales quantiles at 0.05 : {:.2e} , 0.5 :  {:.2e}, 0.95 : {:.2e}, 0.99 : {:.2e}", 
+        log::info!("embedded scales quantiles at 0.05 : {:.2e} , 0.5 :  {:.2e}, 0.95 : {:.2e}, 0.99 : {:.2e}", 
         scales_q.query(0.05).unwrap().1, scales_q.query(0.5).unwrap().1, 
         scales_q.query(0.95).unwrap().1, scales_q.query(0.99).unwrap().1);
-        println!("");  
         //
+        let dim = initial_embed.ncols();
+        let moments = match params.optimizer {
+            GradientOptimizer::Sgd => None,
+            GradientOptimizer::Momentum { .. } => Some((0..nbrow).map(|_| RwLock::new(NodeMoments{first : Array1::zeros(dim), 
+                                    second : Array1::zeros(0), nb_update : 0})).collect()),
+            GradientOptimizer::Adam { .. } => Some((0..nbrow).map(|_| RwLock::new(NodeMoments{first : Array1::zeros(dim), 
+                                    second : Array1::zeros(dim), nb_update : 0})).collect()),
+        };
         EntropyOptim { node_params,  edges, embedded, embedded_scales, 
                             pos_edge_distribution : pos_edge_sampler,
-                            params : params}
+                            params : params, moments, density : None}
         // construct field embedded
     }  // end of new 
 
@@ -761,10 +1546,148 @@ impl <'a, F> EntropyOptim<'a,F>
     } // end of get_nb_edges
 
 
+    // restores moments of a checkpoint, ignored if they do not match the optimizer
+    fn set_moments(&mut self, moments : Vec<NodeMoments<F>>) {
+        match self.moments.as_ref() {
+            Some(current) if current.len() == moments.len() && current[0].read().second.len() == moments[0].second.len() => {
+                self.moments = Some(moments.into_iter().map(RwLock::new).collect());
+            }
+            _ => { log::warn!("EntropyOptim::set_moments, moments do not match optimizer, ignored"); }
+        }
+    } // end of set_moments
+
+
+    // sets the densMAP term from the logs of local radii of nodes in original space
+    fn set_density(&mut self, orig_log_radii : Vec<f64>) {
+        let nb_nodes = orig_log_radii.len() as f64;
+        let mean = orig_log_radii.iter().sum::<f64>() / nb_nodes;
+        let std = (orig_log_radii.iter().map(|r| (r - mean) * (r - mean)).sum::<f64>() / nb_nodes).sqrt().max(DENS_EPSILON);
+        let orig_radii = orig_log_radii.iter().map(|r| (r - mean) / std).collect();
+        let mu_tot = self.edges.iter().map(|e| e.1.weight as f64).sum::<f64>();
+        self.density = Some(DensityTerm{orig_radii, mu_tot, stats : RwLock::new(DensityStats::default())});
+    } // end of set_density
+
+
+    // computes statistics of embedded radii if active, this must be called between batches
+    fn update_density(&self, active : bool) {
+        let density = match self.density.as_ref() {
+            Some(density) => density,
+            None => { return; }
+        };
+        let mut stats = density.stats.write();
+        stats.active = active;
+        if !active {
+            return;
+        }
+        let b = self.params.b;
+        let (phi_sum, log_radii) : (Vec<f64>, Vec<f64>) = (0..self.embedded.len()).into_par_iter().map(|i| {
+            let y_i = self.embedded[i].read();
+            let scale = self.embedded_scales[i] as f64;
+            let (mut phi_sum, mut radius) = (0., 0.);
+            for edge in &self.node_params.get_node_param(i).edges {
+                let y_j = self.embedded[edge.get_node()].read();
+                let d_ij : f64 = y_i.iter().zip(y_j.iter()).map(|(vi,vj)| (*vi-*vj)*(*vi-*vj)).sum::<F>().to_f64().unwrap();
+                let phi = 1. / (1. + (d_ij / (scale * scale)).powf(b));
+                phi_sum += phi;
+                radius += phi * d_ij;
+            }
+            (phi_sum, (DENS_EPSILON + radius / phi_sum.max(DENS_EPSILON)).ln())
+        }).unzip();
+        let nb_nodes = log_radii.len() as f64;
+        let mean = log_radii.iter().sum::<f64>() / nb_nodes;
+        let var = log_radii.iter().map(|r| (r - mean) * (r - mean)).sum::<f64>() / nb_nodes;
+        stats.std = (var + DENS_VAR_SHIFT).sqrt();
+        stats.cov = log_radii.iter().zip(density.orig_radii.iter()).map(|(r, o)| r * o).sum::<f64>() / (nb_nodes - 1.).max(1.);
+        stats.mean = mean;
+        stats.phi_sum = phi_sum;
+        stats.log_radii = log_radii;
+        log::debug!("density term, correlation of log radii : {:.3e}", stats.cov / stats.std);
+    } // end of update_density
+
+
+    // coefficient of (y_i - y_j) in the gradient of the densMAP term with respect to y_i (and its opposite for y_j),
+    // for sampled edge i->j of weight mu at squared distance d_ij, phi being its embedded weight and dphi (d phi/ d d_ij) / phi.
+    // Edges being sampled proportionally to their weight the contribution is divided by mu, as in umap-learn.
+    fn density_coeff(&self, node_i : NodeIdx, node_j : NodeIdx, mu : f64, phi : f64, dphi : f64) -> f64 {
+        let density = match self.density.as_ref() {
+            Some(density) => density,
+            None => { return 0.; }
+        };
+        let stats = density.stats.read();
+        if !stats.active {
+            return 0.;
+        }
+        let b = self.params.b;
+        let dr_i = phi / stats.phi_sum[node_i] * ((1. - b * (1. - phi)) / stats.log_radii[node_i].exp() + dphi);
+        let dr_j = phi / stats.phi_sum[node_j] * ((1. - b * (1. - phi)) / stats.log_radii[node_j].exp() + dphi);
+        let slope = stats.cov / (stats.std * stats.std);
+        let weight_i = density.orig_radii[node_i] - slope * (stats.log_radii[node_i] - stats.mean);
+        let weight_j = density.orig_radii[node_j] - slope * (stats.log_radii[node_j] - stats.mean);
+        let nb_nodes = self.embedded.len() as f64;
+        2. * self.params.dens_lambda * density.mu_tot * (weight_i * dr_i + weight_j * dr_j) / (mu * stats.std * nb_nodes)
+    } // end of density_coeff
+
+
+    // clones moments of nodes, None for plain sgd
+    fn get_moments(&self) -> Option<Vec<NodeMoments<F>>> {
+        self.moments.as_ref().map(|moments| moments.iter().map(|m| m.read().clone()).collect())
+    } // end of get_moments
+
+
+    // returns the step used in displacements and the starting positions of the nodes of an edge needed by update_node.
+    // Adam normalizes the gradient, it is computed with a unit step and grad_step is applied in update_node
+    fn get_displacement_step(&self, grad_step : f64, y_i : &Array1<F>, y_j : &Array1<F>) -> (f64, EdgeStart<F>) {
+        match self.params.optimizer {
+            GradientOptimizer::Sgd => (grad_step, None),
+            GradientOptimizer::Momentum { .. } => (grad_step, Some((y_i.clone(), y_j.clone()))),
+            GradientOptimizer::Adam { .. } => (1., Some((y_i.clone(), y_j.clone()))),
+        }
+    } // end of get_displacement_step
+
+
+    // writes the new position y_new of node computed from y_old by a gradient step.
+    // With momentum or Adam the displacement y_new - y_old is the gradient contribution that updates the moments of node,
+    // for Adam it was computed with a unit step and grad_step is the Adam step.
+    fn update_node(&self, node : NodeIdx, y_old : &Array1<F>, y_new : Array1<F>, grad_step : f64) {
+        let moments = match self.moments.as_ref() {
+            Some(moments) => moments,
+            None => {
+                *(self.get_embedded_data(node).write()) = y_new;
+                return;
+            }
+        };
+        let delta = y_new - y_old;
+        let mut moments = moments[node].write();
+        let NodeMoments{first, second, nb_update} = &mut *moments;
+        let mut y = y_old.clone();
+        match self.params.optimizer {
+            GradientOptimizer::Momentum { beta } => {
+                let beta = F::from_f64(beta).unwrap();
+                first.zip_mut_with(&delta, |m, d| *m = beta * *m + *d);
+                y += &*first;
+            }
+            GradientOptimizer::Adam { beta1, beta2, .. } => {
+                *nb_update += 1;
+                let (b1, b2) = (F::from_f64(beta1).unwrap(), F::from_f64(beta2).unwrap());
+                let corr1 = F::one() - b1.powi(*nb_update as i32);
+                let corr2 = F::one() - b2.powi(*nb_update as i32);
+                let step = F::from_f64(grad_step).unwrap();
+                let eps = F::from_f64(ADAM_EPSILON).unwrap();
+                for k in 0..y.len() {
+                    first[k] = b1 * first[k] + (F::one() - b1) * delta[k];
+                    second[k] = b2 * second[k] + (F::one() - b2) * delta[k] * delta[k];
+                    y[k] += step * (first[k] / corr1) / ((second[k] / corr2).sqrt() + eps);
+                }
+            }
+            GradientOptimizer::Sgd => { y += &delta; }
+        }
+        *(self.get_embedded_data(node).write()) = y;
+    } // end of update_node
+
+
 
     // return result as an Array2<F> cloning data to result to struct Embedder
     // We return data in rows as (re)indexed in graph construction after hnsw!!
-    #[allow(unused)]
     fn get_embedded_raw(& self) -> Array2<F> {
         let nbrow = self.embedded.len();
         let nbcol = self.params.asked_dim;
@@ -819,7 +1742,7 @@ impl <'a, F> EntropyOptim<'a,F>
 
         for edge in self.edges.iter() {
             let node_i = edge.0;
-            let node_j = edge.1.node;
+            let node_j = edge.1.get_node();
             assert!(node_i != node_j);
             let weight_ij = edge.1.weight as f64;
             let weight_ij_embed = cauchy_edge_weight(&self.embedded[node_i].read(), 
@@ -832,8 +1755,9 @@ impl <'a, F> EntropyOptim<'a,F>
                 ce_entropy += - (1. - weight_ij) * (1. - weight_ij_embed).ln();
             }            
             if !ce_entropy.is_finite() {
+                // entropy_optimize reports the error
                 log::debug!("weight_ij {} weight_ij_embed {}", weight_ij, weight_ij_embed);
-                std::panic!();
+                return f64::NAN;
             }
         }
         //
@@ -850,7 +1774,7 @@ impl <'a, F> EntropyOptim<'a,F>
         let ce_entropy = self.edges.par_iter()
             .fold( || 0.0f64, | entropy : f64, edge| entropy + {
                 let node_i = edge.0;
-                let node_j = edge.1.node;
+                let node_j = edge.1.get_node();
                 let weight_ij = edge.1.weight as f64;
                 let weight_ij_embed = cauchy_edge_weight(&self.embedded[node_i].read(), 
                         self.embedded_scales[node_i] as f64, b,
@@ -873,8 +1797,9 @@ impl <'a, F> EntropyOptim<'a,F>
 
 
     // TODO : pass functions corresponding to edge_weight and grad_edge_weight as arguments to test others weight function
-    /// This function optimize cross entropy for Shannon cross entropy
-    fn ce_optim_edge_shannon(&self, threaded : bool, grad_step : f64)
+    /// This function optimize cross entropy for Shannon cross entropy.
+    /// The attractive weight of the sampled edge is multiplied by exaggeration (1. out of early exaggeration)
+    fn ce_optim_edge_shannon<R : Rng>(&self, threaded : bool, grad_step : f64, exaggeration : f64, rng : &mut R)
     where
         F: Float + NumAssign + std::iter::Sum + num_traits::cast::FromPrimitive + ndarray::ScalarOperand
     {
@@ -885,23 +1810,24 @@ impl <'a, F> EntropyOptim<'a,F>
         let node_j;
         let node_i;
         if threaded {
-            edge_idx_sampled = thread_rng().sample(&self.pos_edge_distribution);
+            edge_idx_sampled = rng.sample(&self.pos_edge_distribution);
             node_i = self.edges[edge_idx_sampled].0; 
-            node_j = self.edges[edge_idx_sampled].1.node;
+            node_j = self.edges[edge_idx_sampled].1.get_node();
             y_i = self.get_embedded_data(node_i).read().to_owned();
             y_j = self.get_embedded_data(node_j).read().to_owned();
         } // end threaded
         else {
-            edge_idx_sampled = thread_rng().sample(&self.pos_edge_distribution);
+            edge_idx_sampled = rng.sample(&self.pos_edge_distribution);
             node_i = self.edges[edge_idx_sampled].0; 
             y_i = self.get_embedded_data(node_i).write().to_owned();
-            node_j = self.edges[edge_idx_sampled].1.node;
+            node_j = self.edges[edge_idx_sampled].1.get_node();
             y_j = self.get_embedded_data(node_j).write().to_owned(); 
         };
         // get coordinate of node
         // we locks once and directly a write lock as conflicts should be small, many edges, some threads. see Recht Hogwild!
         let dim = self.params.asked_dim;
         let mut gradient = Array1::<F>::zeros(dim);
+        let (disp_step, y_start) = self.get_displacement_step(grad_step, &y_i, &y_j);
         //
         assert!(node_i != node_j);
         let weight = self.edges[edge_idx_sampled].1.weight as f64;
@@ -913,32 +1839,43 @@ impl <'a, F> EntropyOptim<'a,F>
         let d_ij_scaled = d_ij/(scale*scale);
         // this coeff is common for P and 1.-P part
         let coeff : f64;
+        let cauchy_weight_ij : f64;
         if b != 1. { 
             let cauchy_weight = 1./ (1. + d_ij_scaled.powf(b));
             coeff =  2. * b * cauchy_weight * d_ij_scaled.powf(b - 1.)/ (scale*scale);
+            cauchy_weight_ij = cauchy_weight;
         }
         else {
             let cauchy_weight = 1./ (1. + d_ij_scaled);
             coeff =  2. * b * cauchy_weight / (scale*scale);
+            cauchy_weight_ij = cauchy_weight;
         }
         if d_ij_scaled > 0. {
             // repulsion annhinilate  attraction if P<= 1. / (alfa + 1). choose 0.1/ PROBA_MIN 
             let alfa = (1./ PROBA_MIN) as f64;
             let coeff_repulsion = 1. / (d_ij_scaled*d_ij_scaled).max(alfa);
             // clipping makes each point i or j making at most half way to the other in case of attraction
-            let coeff_ij = (grad_step * coeff * (- weight + (1.-weight) * coeff_repulsion)).max(-0.49);
+            let coeff_ij = disp_step * coeff * (- weight * exaggeration + (1.-weight) * coeff_repulsion);
+            let coeff_ij = if self.density.is_some() {
+                let dens_coeff = self.density_coeff(node_i, node_j, weight, cauchy_weight_ij, coeff / 2.);
+                coeff_ij + self.params.clipping.clip(disp_step * dens_coeff, 0.49)
+            } else { coeff_ij };
+            let coeff_ij = if coeff_ij < 0. { self.params.clipping.clip(coeff_ij, 0.49) } else { coeff_ij };
             gradient = (&y_j - &y_i) * F::from(coeff_ij).unwrap();
             log::trace!("norm attracting coeff {:.2e} gradient {:.2e}", coeff_ij, l2_norm(&gradient.view()).to_f64().unwrap());
         }
         y_i -= &gradient;
         y_j += &gradient;
-        *(self.get_embedded_data(node_j).write()) = y_j;
+        match y_start.as_ref() {
+            Some((_, y_j_start)) => self.update_node(node_j, y_j_start, y_j, grad_step),
+            None => *(self.get_embedded_data(node_j).write()) = y_j,
+        }
         // now we loop on negative sampling filtering out nodes that are either node_i or are in node_i neighbours.
         let asked_nb_neg = 5;
         let mut got_nb_neg = 0;
         let mut _nb_failed = 0;
         while got_nb_neg < asked_nb_neg {
-            let neg_node : NodeIdx = thread_rng().gen_range(0..self.embedded_scales.len());
+            let neg_node : NodeIdx = rng.gen_range(0..self.embedded_scales.len());
             if neg_node != node_i && neg_node != node_j && self.node_params.get_node_param(node_i).get_edge(neg_node).is_none() {
                 // get a read lock, as neg_node is not the locked nodes node_i and node_j
                 let neg_data = self.get_embedded_data(neg_node);
@@ -970,7 +1907,7 @@ impl <'a, F> EntropyOptim<'a,F>
                 let alfa = 1./16.;
                 if d_ik > 0. {
                     let coeff_repulsion = 1. /(d_ik_scaled * d_ik_scaled).max(alfa);  // !!
-                    let coeff_ik =  (grad_step * coeff * coeff_repulsion).min(2.);
+                    let coeff_ik =  self.params.clipping.clip(disp_step * coeff * coeff_repulsion, 2.);
                     gradient = (&y_k - &y_i) * F::from_f64(coeff_ik).unwrap();
                     log::trace!("norm repulsive  coeff gradient {:.2e} {:.2e}", coeff_ik , l2_norm(&gradient.view()).to_f64().unwrap());
                 }
@@ -978,28 +1915,257 @@ impl <'a, F> EntropyOptim<'a,F>
             } // end node_neg is accepted
         }  // end of loop on neg sampling
         // final update of node_i
-        *(self.get_embedded_data(node_i).write()) = y_i;
+        match y_start.as_ref() {
+            Some((y_i_start, _)) => self.update_node(node_i, y_i_start, y_i, grad_step),
+            None => *(self.get_embedded_data(node_i).write()) = y_i,
+        }
     } // end of ce_optim_from_point
 
 
 
+    // PaCMAP update for a near pair sampled as an edge, with its mid-near and further pairs, see PairSampling::PaCMAP.
+    // weights are the staged weights of near, mid-near and further pairs. Only node_i moves for mid-near and further pairs
+    fn pacmap_optim_edge<R : Rng>(&self, grad_step : f64, weights : (f64, f64, f64), mn_ratio : f64, fp_ratio : f64, rng : &mut R) {
+        let (w_nb, w_mn, w_fp) = weights;
+        let edge_idx_sampled = rng.sample(&self.pos_edge_distribution);
+        let node_i = self.edges[edge_idx_sampled].0;
+        let node_j = self.edges[edge_idx_sampled].1.get_node();
+        let mut y_i = self.get_embedded_data(node_i).read().to_owned();
+        let mut y_j = self.get_embedded_data(node_j).read().to_owned();
+        let (disp_step, y_start) = self.get_displacement_step(grad_step, &y_i, &y_j);
+        let sq_dist = |a : &Array1<F>, b : &Array1<F>| a.iter().zip(b.iter()).map(|(x, y)| (*x - *y) * (*x - *y)).sum::<F>().to_f64().unwrap();
+        // near pair, loss w_nb * d/(10 + d) with d = 1 + squared distance. coeff is -2 * step * derivative of loss
+        let d_ij = 1. + sq_dist(&y_i, &y_j);
+        let coeff_ij = self.params.clipping.clip(- 2. * disp_step * w_nb * 10. / ((10. + d_ij) * (10. + d_ij)), 0.49);
+        let gradient = (&y_j - &y_i) * F::from_f64(coeff_ij).unwrap();
+        y_i -= &gradient;
+        y_j += &gradient;
+        match y_start.as_ref() {
+            Some((_, y_j_start)) => self.update_node(node_j, y_j_start, y_j, grad_step),
+            None => *(self.get_embedded_data(node_j).write()) = y_j,
+        }
+        // mid-near pairs : a neighbour of a neighbour, loss w_mn * d/(10000 + d)
+        if w_mn > 0. {
+            for _ in 0..get_nb_pairs(mn_ratio, rng) {
+                if let Some(node_k) = self.sample_mid_near(node_i, rng) {
+                    let y_k = self.get_embedded_data(node_k).read().to_owned();
+                    let d_ik = 1. + sq_dist(&y_i, &y_k);
+                    let coeff_ik = self.params.clipping.clip(- 2. * disp_step * w_mn * 10000. / ((10000. + d_ik) * (10000. + d_ik)), 0.49);
+                    y_i -= &((&y_k - &y_i) * F::from_f64(coeff_ik).unwrap());
+                }
+            }
+        }
+        // further pairs : random non neighbours, loss w_fp / (1 + d)
+        let nb_nodes = self.embedded.len();
+        let mut got_nb_fp = 0;
+        let nb_fp = get_nb_pairs(fp_ratio, rng);
+        while got_nb_fp < nb_fp && nb_nodes > self.node_params.get_node_param(node_i).get_nb_edges() + 1 {
+            let node_k : NodeIdx = rng.gen_range(0..nb_nodes);
+            if node_k == node_i || self.node_params.get_node_param(node_i).get_edge(node_k).is_some() {
+                continue;
+            }
+            got_nb_fp += 1;
+            let y_k = self.get_embedded_data(node_k).read().to_owned();
+            let d_ik = 1. + sq_dist(&y_i, &y_k);
+            let coeff_ik = self.params.clipping.clip(2. * disp_step * w_fp / ((1. + d_ik) * (1. + d_ik)), 2.);
+            y_i -= &((&y_k - &y_i) * F::from_f64(coeff_ik).unwrap());
+        }
+        match y_start.as_ref() {
+            Some((y_i_start, _)) => self.update_node(node_i, y_i_start, y_i, grad_step),
+            None => *(self.get_embedded_data(node_i).write()) = y_i,
+        }
+    } // end of pacmap_optim_edge
+
+
+    // TriMap update for triplets made of a sampled edge (i,j) and nb_far non neighbours k of i, loss s_ik/(s_ij + s_ik)
+    // with s = 1/(1 + squared distance). node_k does not move.
+    fn trimap_optim_edge<R : Rng>(&self, grad_step : f64, nb_far : usize, rng : &mut R) {
+        let edge_idx_sampled = rng.sample(&self.pos_edge_distribution);
+        let node_i = self.edges[edge_idx_sampled].0;
+        let node_j = self.edges[edge_idx_sampled].1.get_node();
+        let mut y_i = self.get_embedded_data(node_i).read().to_owned();
+        let mut y_j = self.get_embedded_data(node_j).read().to_owned();
+        let (disp_step, y_start) = self.get_displacement_step(grad_step, &y_i, &y_j);
+        let similarity = |a : &Array1<F>, b : &Array1<F>| 1. / (1. + a.iter().zip(b.iter()).map(|(x, y)| (*x - *y) * (*x - *y)).sum::<F>().to_f64().unwrap());
+        let nb_nodes = self.embedded.len();
+        let mut got_nb_far = 0;
+        while got_nb_far < nb_far && nb_nodes > self.node_params.get_node_param(node_i).get_nb_edges() + 1 {
+            let node_k : NodeIdx = rng.gen_range(0..nb_nodes);
+            if node_k == node_i || self.node_params.get_node_param(node_i).get_edge(node_k).is_some() {
+                continue;
+            }
+            got_nb_far += 1;
+            let y_k = self.get_embedded_data(node_k).read().to_owned();
+            let (s_ij, s_ik) = (similarity(&y_i, &y_j), similarity(&y_i, &y_k));
+            let norm = (s_ij + s_ik) * (s_ij + s_ik);
+            // coefficients are -step * gradient of loss with respect to y_i along (y_j - y_i) and (y_k - y_i)
+            let coeff_ij = self.params.clipping.clip(- 2. * disp_step * s_ik * s_ij * s_ij / norm, 0.49);
+            let coeff_ik = self.params.clipping.clip(2. * disp_step * s_ij * s_ik * s_ik / norm, 2.);
+            let gradient = (&y_j - &y_i) * F::from_f64(coeff_ij).unwrap();
+            y_i -= &gradient;
+            y_j += &gradient;
+            y_i -= &((&y_k - &y_i) * F::from_f64(coeff_ik).unwrap());
+        }
+        match y_start.as_ref() {
+            Some((y_i_start, y_j_start)) => {
+                self.update_node(node_j, y_j_start, y_j, grad_step);
+                self.update_node(node_i, y_i_start, y_i, grad_step);
+            }
+            None => {
+                *(self.get_embedded_data(node_j).write()) = y_j;
+                *(self.get_embedded_data(node_i).write()) = y_i;
+            }
+        }
+    } // end of trimap_optim_edge
+
+
+    // samples a neighbour of a neighbour of node that is neither node nor one of its neighbours, None after some failures
+    fn sample_mid_near<R : Rng>(&self, node : NodeIdx, rng : &mut R) -> Option<NodeIdx> {
+        let node_param = self.node_params.get_node_param(node);
+        for _ in 0..10 {
+            let neighbour = &node_param.edges[rng.gen_range(0..node_param.edges.len())];
+            let second = &self.node_params.get_node_param(neighbour.get_node()).edges;
+            if second.is_empty() {
+                continue;
+            }
+            let candidate = second[rng.gen_range(0..second.len())].get_node();
+            if candidate != node && node_param.get_edge(candidate).is_none() {
+                return Some(candidate);
+            }
+        }
+        None
+    } // end of sample_mid_near
+
+
+    // one sample of the loss chosen by params.pair_sampling. exaggeration only applies to PairSampling::Negative,
+    // entropy_optimize rejects it for the other samplings
+    fn optim_edge<R : Rng>(&self, threaded : bool, grad_step : f64, exaggeration : f64, pacmap_weights : (f64, f64, f64), rng : &mut R) {
+        match self.params.pair_sampling {
+            PairSampling::Negative => self.ce_optim_edge_shannon(threaded, grad_step, exaggeration, rng),
+            PairSampling::PaCMAP { mn_ratio, fp_ratio } => self.pacmap_optim_edge(grad_step, pacmap_weights, mn_ratio, fp_ratio, rng),
+            PairSampling::TriMap { nb_far } => self.trimap_optim_edge(grad_step, nb_far, rng),
+        }
+    } // end of optim_edge
+
+
 #[allow(unused)]
-    fn gradient_iteration(&self, nb_sample : usize, grad_step : f64) {
+    fn gradient_iteration(&self, nb_sample : usize, grad_step : f64, exaggeration : f64, batch : usize) {
+        let mut rng = get_rng(self.params.seed, RNG_GRADIENT, batch);
+        let pacmap_weights = get_pacmap_weights(batch, self.params.nb_grad_batch);
         for _ in 0..nb_sample {
-            self.ce_optim_edge_shannon(false, grad_step);
+            self.optim_edge(false, grad_step, exaggeration, pacmap_weights, &mut rng);
         }
     } // end of gradient_iteration
 
 
 
-    fn gradient_iteration_threaded(&self, nb_sample : usize, grad_step : f64) {
-        (0..nb_sample).into_par_iter().for_each( |_| self.ce_optim_edge_shannon(true, grad_step));
+    // samples are drawn by chunks, each chunk with its rng depending on batch and chunk rank.
+    fn gradient_iteration_threaded(&self, nb_sample : usize, grad_step : f64, exaggeration : f64, batch : usize) {
+        let pacmap_weights = get_pacmap_weights(batch, self.params.nb_grad_batch);
+        let nb_chunks = nb_sample.div_ceil(SAMPLE_CHUNK_SIZE);
+        (0..nb_chunks).into_par_iter().for_each( |c| {
+            let mut rng = get_rng(self.params.seed, RNG_GRADIENT, batch * nb_chunks + c);
+            let chunk_size = SAMPLE_CHUNK_SIZE.min(nb_sample - c * SAMPLE_CHUNK_SIZE);
+            for _ in 0..chunk_size {
+                self.optim_edge(true, grad_step, exaggeration, pacmap_weights, &mut rng);
+            }
+        });
     } // end of gradient_iteration_threaded
     
     
 }  // end of impl EntropyOptim
 
 
+// staged weights of near, mid-near and further pairs of PaCMAP at gradient batch (1..=nb_batch), the 450 iterations
+// of PaCMAP being mapped on the batches : mid-near weight decreases from 1000 to 3 in the first phase and vanishes in the last one.
+fn get_pacmap_weights(batch : usize, nb_batch : usize) -> (f64, f64, f64) {
+    let t = 450. * batch as f64 / nb_batch.max(1) as f64;
+    if t <= 100. {
+        (2., 1000. * (1. - t / 100.) + 3. * t / 100., 1.)
+    }
+    else if t <= 200. {
+        (3., 3., 1.)
+    }
+    else {
+        (1., 0., 1.)
+    }
+} // end of get_pacmap_weights
+
+
+// number of pairs to draw for a mean ratio : integer part plus one with probability the fractional part
+fn get_nb_pairs<R : Rng>(ratio : f64, rng : &mut R) -> usize {
+    let nb = ratio.floor();
+    nb as usize + usize::from(rng.gen::<f64>() < ratio - nb)
+} // end of get_nb_pairs
+
+
+// common coefficient of the gradient of the cross entropy of an edge with respect to its end point,
+// d_scaled being the squared distance divided by the squared scale
+fn cauchy_grad_coeff(d_scaled : f64, scale : f64, b : f64) -> f64 {
+    if b != 1. {
+        2. * b * d_scaled.powf(b - 1.) / ((1. + d_scaled.powf(b)) * scale * scale)
+    }
+    else {
+        2. * b / ((1. + d_scaled) * scale * scale)
+    }
+} // end of cauchy_grad_coeff
+
+
+// optimizes the position of a new point against a frozen embedding, edges going from the new point to nodes of the embedding.
+// The gradient is the one of EntropyOptim::ce_optim_edge_shannon, only the new point moves.
+fn optimize_new_point<F, R>(edges : &[OutEdge<f32>], scale : f64, embedded : &Array2<F>, params : &EmbedderParams, rng : &mut R) -> Array1<F>
+    where R : Rng, F: Float + NumAssign + std::iter::Sum + num_traits::cast::FromPrimitive + ndarray::ScalarOperand {
+    // start at the barycenter of neighbours, edge weights sum to 1
+    let mut y_i = Array1::<F>::zeros(embedded.ncols());
+    for edge in edges {
+        y_i.scaled_add(F::from(edge.weight).unwrap(), &embedded.row(edge.get_node()));
+    }
+    let sampler = WeightedAliasIndex::new(edges.iter().map(|e| e.weight).collect()).unwrap();
+    let nb_sample_by_iter = params.nb_sampling_by_edge * edges.len();
+    let nb_nodes = embedded.nrows();
+    let b = params.b;
+    let sq_dist = |a : &Array1<F>, other : &ArrayView1<F>| a.iter().zip(other.iter()).map(|(x, y)| (*x - *y) * (*x - *y)).sum::<F>().to_f64().unwrap();
+    for iter in 1..=params.nb_grad_batch {
+        let grad_step = params.grad_step * params.step_schedule.factor(iter, params.nb_grad_batch);
+        for _ in 0..nb_sample_by_iter {
+            let edge = &edges[rng.sample(&sampler)];
+            let y_j = embedded.row(edge.get_node());
+            let weight = edge.weight as f64;
+            let d_ij_scaled = sq_dist(&y_i, &y_j) / (scale * scale);
+            if d_ij_scaled > 0. {
+                let alfa = (1./ PROBA_MIN) as f64;
+                let coeff_repulsion = 1. / (d_ij_scaled * d_ij_scaled).max(alfa);
+                let coeff_ij = grad_step * cauchy_grad_coeff(d_ij_scaled, scale, b) * (- weight + (1.-weight) * coeff_repulsion);
+                let coeff_ij = if coeff_ij < 0. { params.clipping.clip(coeff_ij, 0.49) } else { coeff_ij };
+                let gradient = (&y_j - &y_i) * F::from(coeff_ij).unwrap();
+                y_i -= &gradient;
+            }
+            // negative sampling among nodes that are not neighbours
+            if nb_nodes <= edges.len() {
+                continue;
+            }
+            let mut got_nb_neg = 0;
+            while got_nb_neg < 5 {
+                let neg_node : NodeIdx = rng.gen_range(0..nb_nodes);
+                if edges.iter().any(|e| e.get_node() == neg_node) {
+                    continue;
+                }
+                got_nb_neg += 1;
+                let y_k = embedded.row(neg_node);
+                let d_ik_scaled = sq_dist(&y_i, &y_k) / (scale * scale);
+                if d_ik_scaled > 0. {
+                    let coeff_repulsion = 1. / (d_ik_scaled * d_ik_scaled).max(1./16.);
+                    let coeff_ik = params.clipping.clip(grad_step * cauchy_grad_coeff(d_ik_scaled, scale, b) * coeff_repulsion, 2.);
+                    let gradient = (&y_k - &y_i) * F::from_f64(coeff_ik).unwrap();
+                    y_i -= &gradient;
+                }
+            }
+        }
+    }
+    y_i
+} // end of optimize_new_point
+
+
 //===============================================================================================================
 
 
@@ -1010,32 +2176,35 @@ impl <'a, F> EntropyOptim<'a,F>
 // This function relies on get_scale_from_proba_normalisation function which construct proabability-weighted edge around each node.
 // These 2 function are also the base of module dmap
 //
-pub(crate) fn to_proba_edges<F>(kgraph : & KGraph<F>, scale_rho : f32, beta : f32) -> NodeParams
+pub(crate) fn to_proba_edges<F>(kgraph : & KGraph<F>, scale_rho : f32, beta : f32) -> Result<NodeParams, AnnembedError>
     where F : Float + num_traits::cast::FromPrimitive + std::marker::Sync + std::marker::Send + std::fmt::UpperExp + std::iter::Sum {
     //
-    let mut perplexity_q : CKMS<f32> = CKMS::<f32>::new(0.001);
-    let mut scale_q : CKMS<f32> = CKMS::<f32>::new(0.001);
-    let mut weight_q :  CKMS<f32> = CKMS::<f32>::new(0.001);
-    let neighbour_hood = kgraph.get_neighbours();
+    let mut perplexity_q : Quantiles<f32> = Quantiles::<f32>::new(0.001);
+    let mut scale_q : Quantiles<f32> = Quantiles::<f32>::new(0.001);
+    let mut weight_q :  Quantiles<f32> = Quantiles::<f32>::new(0.001);
+    let nb_nodes = kgraph.get_nb_nodes();
+    let stage = Stage::enter("node_params");
+    stage.record_size("nb_nodes", nb_nodes);
     // a closure to compute scale and perplexity
-    let scale_perplexity = | i : usize | ->  (usize, Option<(f32, NodeParam)>) {
-        if neighbour_hood[i].len() > 0 {
-            let node_param = get_scale_from_proba_normalisation(kgraph, scale_rho, beta, &neighbour_hood[i]);
+    let scale_perplexity = | i : usize | ->  Result<(usize, Option<(f32, NodeParam)>), AnnembedError> {
+        let edges = kgraph.out_edges(i);
+        if edges.len() > 0 {
+            let node_param = get_scale_from_proba_normalisation(kgraph, scale_rho, beta, edges)?;
             let perplexity = node_param.get_perplexity();
-            return (i, Some((perplexity, node_param)));
+            return Ok((i, Some((perplexity, node_param))));
         }
         else {
-            return (i, None);
+            return Ok((i, None));
         }
     };
-    let mut opt_node_params :  Vec::<(usize,Option<(f32, NodeParam)>)> =  Vec::<(usize,Option<(f32, NodeParam)>)>::new();
-    let mut node_params : Vec<NodeParam> = (0..neighbour_hood.len()).into_iter().map(|_| NodeParam::default()).collect();
+    let mut opt_node_params = Vec::new();
+    let mut node_params : Vec<NodeParam> = (0..nb_nodes).into_iter().map(|_| NodeParam::default()).collect();
     //
-    (0..neighbour_hood.len()).into_par_iter().map(|i| scale_perplexity(i)).collect_into_vec(&mut opt_node_params);
+    (0..nb_nodes).into_par_iter().map(|i| scale_perplexity(i)).collect_into_vec(&mut opt_node_params);
     // now we process serial information related to opt_node_params
     let mut max_nbng = 0;
-    for opt_param in &opt_node_params {
-        match opt_param {
+    for opt_param in opt_node_params {
+        match opt_param? {
             (i, Some(param)) => {
                 perplexity_q.insert(param.0);
                 scale_q.insert(param.1.scale);
@@ -1043,36 +2212,88 @@ pub(crate) fn to_proba_edges<F>(kgraph : & KGraph<F>, scale_rho : f32, beta : f3
                 let j = thread_rng().gen_range(0..param.1.edges.len());
                 weight_q.insert(param.1.edges[j].weight);
                 max_nbng = param.1.edges.len().max(max_nbng);
-                assert_eq!(param.1.edges.len(), neighbour_hood[*i].len());
-                node_params[*i] = param.1.clone();
+                assert_eq!(param.1.edges.len(), kgraph.out_edges(i).len());
+                node_params[i] = param.1;
             }
             (i, None) => {
-                println!("to_proba_edges , node rank {}, has no neighbour, use hnsw.set_keeping_pruned(true)", i);
                 log::error!("to_proba_edges , node rank {}, has no neighbour, use hnsw.set_keeping_pruned(true)", i);
-                std::process::exit(1);
+                return Err(AnnembedError::GraphConstruction(format!("node rank {} has no neighbour, use hnsw.set_keeping_pruned(true)", i)));
             }
         };
     }
-    // dump info on quantiles
-    println!("\n constructed initial space");
-    println!("\n scales quantile at 0.05 : {:.2e} , 0.5 :  {:.2e}, 0.95 : {:.2e}, 0.99 : {:.2e}", 
+    // log info on quantiles, diffusion maps users get them from DiffusionMaps::get_stats
+    log::info!("constructed initial space");
+    log::info!("scales quantile at 0.05 : {:.2e} , 0.5 :  {:.2e}, 0.95 : {:.2e}, 0.99 : {:.2e}", 
     scale_q.query(0.05).unwrap().1, scale_q.query(0.5).unwrap().1, 
     scale_q.query(0.95).unwrap().1, scale_q.query(0.99).unwrap().1);
     //
-    println!("\n edge weight quantile at 0.05 : {:.2e} , 0.5 :  {:.2e}, 0.95 : {:.2e}, 0.99 : {:.2e}", 
+    log::info!("edge weight quantile at 0.05 : {:.2e} , 0.5 :  {:.2e}, 0.95 : {:.2e}, 0.99 : {:.2e}", 
     weight_q.query(0.05).unwrap().1, weight_q.query(0.5).unwrap().1, 
     weight_q.query(0.95).unwrap().1, weight_q.query(0.99).unwrap().1);
     //
-    println!("\n perplexity quantile at 0.05 : {:.2e} , 0.5 :  {:.2e}, 0.95 : {:.2e}, 0.99 : {:.2e}", 
+    log::info!("perplexity quantile at 0.05 : {:.2e} , 0.5 :  {:.2e}, 0.95 : {:.2e}, 0.99 : {:.2e}", 
     perplexity_q.query(0.05).unwrap().1, perplexity_q.query(0.5).unwrap().1, 
     perplexity_q.query(0.95).unwrap().1, perplexity_q.query(0.99).unwrap().1);
-    println!("");    
     //
-    NodeParams::new(node_params, max_nbng)
+    Ok(NodeParams::new(node_params, max_nbng))
 }  // end of construction of node params
 
 
 
+// mean distance to first neighbour of a node and of its neighbours.
+fn get_mean_rho<F> (kgraph : & KGraph<F>, neighbours: &[OutEdge<F>]) -> f32
+    where F : Float + num_traits::cast::FromPrimitive + Sync + Send + std::fmt::UpperExp + std::iter::Sum {
+    let rho_x = neighbours[0].weight.to_f32().unwrap();
+    // distance to first neighbour of each neighbour y_i, summed without allocation
+    let rho_y_sum = neighbours.iter()
+        .map(|e| kgraph.get_first_neighbour_dist(e.get_node()).unwrap().to_f32().unwrap())
+        .sum::<f32>();
+    (rho_y_sum + rho_x) / ((neighbours.len() + 1) as f32)
+} // end of get_mean_rho
+
+
+
+/// Berry-Giannakis-Harlim automatic bandwidth selection.  
+/// With the shifted and locally rescaled distances r used in [to_proba_edges] the kernel sum is $S(s) = \sum_{i,j} \exp(-(r_{ij}/s)^{\beta})$.  
+/// We return the scale s (to be used as scale_rho) maximizing $d \log(S)/ d \log(\epsilon)$ with $\epsilon = s^{\beta}$, scanned on a logarithmic grid.
+/// The maximal slope is an estimate of half the intrinsic dimension.
+/// See Berry, Giannakis, Harlim. Nonparametric forecasting of low-dimensional dynamical systems. Phys Rev E 2015.
+pub(crate) fn get_bgh_scale_rho<F>(kgraph : & KGraph<F>, beta : f32) -> f32
+    where F : Float + num_traits::cast::FromPrimitive + std::marker::Sync + std::marker::Send + std::fmt::UpperExp + std::iter::Sum {
+    // normalized distances
+    let dists : Vec<f32> = kgraph.par_iter_neighbourhoods()
+        .filter(|(_, neighbours)| !neighbours.is_empty())
+        .flat_map_iter(|(_, neighbours)| {
+            let mean_rho = get_mean_rho(kgraph, neighbours);
+            let first_dist = neighbours[0].weight.to_f32().unwrap();
+            neighbours.iter().map(move |n| if mean_rho > 0. { (n.weight.to_f32().unwrap() - first_dist).max(0.) / mean_rho } else { 0. })
+        })
+        .collect();
+    if dists.is_empty() {
+        return 1.;
+    }
+    // scan log grid from 1.0E-2 to 1.0E2
+    let nb_grid = 81;
+    let log_s : Vec<f64> = (0..nb_grid).map(|k| (-2. + 4. * k as f64 / (nb_grid - 1) as f64) * std::f64::consts::LN_10).collect();
+    let log_sum : Vec<f64> = log_s.par_iter().map(|ls| {
+        let s = ls.exp() as f32;
+        dists.iter().map(|r| (-(r / s).pow(beta)).exp() as f64).sum::<f64>().ln()
+    }).collect();
+    // derivative w.r.t log(epsilon) = beta * log(s), centered on grid midpoints
+    let mut best = (0usize, f64::MIN);
+    for k in 0..nb_grid - 1 {
+        let slope = (log_sum[k + 1] - log_sum[k]) / (beta as f64 * (log_s[k + 1] - log_s[k]));
+        if slope > best.1 {
+            best = (k, slope);
+        }
+    }
+    let scale_rho = (0.5 * (log_s[best.0] + log_s[best.0 + 1])).exp() as f32;
+    log::info!("BGH bandwidth selection scale_rho : {:.3e}, max slope : {:.3e} (dimension estimate {:.2e})", scale_rho, best.1, 2. * best.1);
+    scale_rho
+} // end of get_bgh_scale_rho
+
+
+
 // Simplest function where we know really what we do and why. 
 // Given a graph, scale and exponent parameters transform a list of distance-edge to neighbours into a list of proba-edge.
 // 
@@ -1087,21 +2308,18 @@ pub(crate) fn to_proba_edges<F>(kgraph : & KGraph<F>, scale_rho : f32, beta : f3
 // This function returns the local scale (i.e mean distance of a point to its nearest neighbour)
 // and vector of proba weight to nearest neighbours.
 //
-fn get_scale_from_proba_normalisation<F> (kgraph : & KGraph<F>, scale_rho : f32, beta : f32, neighbours: &Vec<OutEdge<F>>) -> NodeParam 
+fn get_scale_from_proba_normalisation<F> (kgraph : & KGraph<F>, scale_rho : f32, beta : f32, neighbours: &[OutEdge<F>]) -> Result<NodeParam, AnnembedError>
     where F : Float + num_traits::cast::FromPrimitive + Sync + Send + std::fmt::UpperExp + std::iter::Sum {
     //
 //        log::trace!("in get_scale_from_proba_normalisation");
     let nbgh = neighbours.len();
     assert!(nbgh > 0);
+    if neighbours.iter().any(|n| !n.weight.is_finite()) {
+        log::error!("get_scale_from_proba_normalisation, non finite distance to neighbours");
+        return Err(AnnembedError::NonFinite("neighbour distances"));
+    }
     // determnine mean distance to nearest neighbour at local scale, reason why we need kgraph as argument.
-    let rho_x = neighbours[0].weight.to_f32().unwrap();
-    let mut rho_y_s = Vec::<f32>::with_capacity(neighbours.len() + 1);
-    for i in 0..nbgh {
-        let y_i = neighbours[i].node; // y_i is a NodeIx = usize
-        rho_y_s.push(kgraph.get_neighbours()[y_i][0].weight.to_f32().unwrap());
-    } // end of for i
-    rho_y_s.push(rho_x);
-    let mean_rho = rho_y_s.iter().sum::<f32>() / (rho_y_s.len() as f32);
+    let mean_rho = get_mean_rho(kgraph, neighbours);
     // we set scale so that transition proba do not vary more than PROBA_MIN between first and last neighbour
     // exp(- (first_dist -last_dist)/scale) >= PROBA_MIN
     // TODO do we need some optimization with respect to this 1 ? as we have lambda for high variations
@@ -1128,7 +2346,7 @@ fn get_scale_from_proba_normalisation<F> (kgraph : & KGraph<F>, scale_rho : f32,
             }
             let mut probas_edge = neighbours
                 .iter()
-                .map(|n| OutEdge::<f32>::new(n.node, remap_weight(n.weight, first_dist, scale, beta).max(PROBA_MIN)) )
+                .map(|n| OutEdge::<f32>::new(n.get_node(), remap_weight(n.weight, first_dist, scale, beta).max(PROBA_MIN)) )
                 .collect::<Vec<OutEdge<f32>>>();
             //
             let proba_range = probas_edge[probas_edge.len() - 1].weight / probas_edge[0].weight;
@@ -1136,59 +2354,34 @@ fn get_scale_from_proba_normalisation<F> (kgraph : & KGraph<F>, scale_rho : f32,
             log::trace!("scale : {:.2e} , first neighbour proba {:2e}, last neighbour proba {:2e} proba gap {:.2e}", scale, probas_edge[0].weight, 
                             probas_edge[probas_edge.len() - 1].weight,
                             proba_range);
-            if proba_range < PROBA_MIN {
+            // NaN comes from a null scale
+            if proba_range.is_nan() || proba_range < PROBA_MIN {
                 log::error!(" first dist {:2e} last dist {:2e}", first_dist, last_dist);
                 log::error!("scale : {:.2e} , first neighbour proba {:2e}, last neighbour proba {:2e} proba gap {:.2e}", scale, probas_edge[0].weight, 
                                 probas_edge[probas_edge.len() - 1].weight,
                                 proba_range);            
+                return Err(AnnembedError::Embedding(format!("proba range {:.2e} too low edge proba, increase scale_rho or reduce beta", proba_range)));
             }
-            assert!(proba_range >= PROBA_MIN, "proba range {:.2e} too low edge proba, increase scale_rho or reduce beta", proba_range);
             //
             let sum = probas_edge.iter().map(|e| e.weight).sum::<f32>();
             for i in 0..nbgh {
                 probas_edge[i].weight = probas_edge[i].weight / sum;
             }
-            return NodeParam::new(scale, probas_edge);
-        }
-        else {
-            all_equal = true;
+            return Ok(NodeParam::new(scale, probas_edge));
         }
     }
-    if all_equal  {
-        // all neighbours are at the same distance!
-        let probas_edge = neighbours
-            .iter()
-            .map(|n| OutEdge::<f32>::new(n.node, 1.0 / nbgh as f32))
-            .collect::<Vec<OutEdge<f32>>>();
-        return NodeParam::new(scale, probas_edge);
-    }
-    else {
-        log::error!("fatal error in get_scale_from_proba_normalisation, should not happen!");
-        std::panic!("incoherence error");
-    }
+    // all neighbours are at the same distance!
+    let probas_edge = neighbours
+        .iter()
+        .map(|n| OutEdge::<f32>::new(n.get_node(), 1.0 / nbgh as f32))
+        .collect::<Vec<OutEdge<f32>>>();
+    Ok(NodeParam::new(scale, probas_edge))
 } // end of get_scale_from_proba_normalisation
     
 
 
 
 
-// restrain value
-fn clip<F>(f : F, max : f64) -> F 
-    where  F: Float + num_traits::FromPrimitive {
-    let f_r = f.to_f64().unwrap();
-    let truncated = if f_r > max {
-        log::trace!("truncated >");
-        max
-    }
-    else if f_r < -max {
-        log::trace!("truncated <");
-        -max
-    }
-    else {
-        f_r
-    };
-    return F::from(truncated).unwrap();
-}   // end clip
 
 
 
@@ -1237,14 +2430,10 @@ where F :  Float + std::iter::Sum + num_traits::cast::FromPrimitive {
 
 
 // in embedded space (in unit ball or unit box) the scale is chosen as the scale at corresponding point / divided by mean initial scales.
-fn estimate_embedded_scales_from_initial_scales(initial_scales :&Vec<f32>) -> Vec<f32> {
+fn estimate_embedded_scales_from_initial_scales(initial_scales :&Vec<f32>, width : f32) -> Vec<f32> {
     log::trace!("estimate_embedded_scale_from_initial_scales");
     let mean_scale : f32 = initial_scales.iter().sum::<f32>() / (initial_scales.len() as f32);
-    let scale_sup = 4.0;  // CAVEAT seems we can go up to 4.
-    let scale_inf = 1./scale_sup;
-    let width = 0.2;
-    // We want embedded scae impact between 0.5 and 2 (amplitude 4) , we take into account the square in cauchy weight
-    let embedded_scale : Vec<f32> = initial_scales.iter().map(|&x| width * (x/mean_scale).min(scale_sup).max(scale_inf)).collect();
+    let embedded_scale : Vec<f32> = initial_scales.iter().map(|&x| embedded_scale_from_initial_scale(x, mean_scale, width)).collect();
     //
     for i in 0..embedded_scale.len() {
         log::trace!("embedded scale for node {} : {:.2e}", i , embedded_scale[i]);
@@ -1254,14 +2443,42 @@ fn estimate_embedded_scales_from_initial_scales(initial_scales :&Vec<f32>) -> Ve
 }  // end of estimate_embedded_scale_from_initial_scales
 
 
+// logs of local radii of nodes in original space for densMAP term : mean of squared distances to neighbours
+// weighted by edge probabilities of node_params.
+fn get_original_log_radii<F>(kgraph : &KGraph<F>, node_params : &NodeParams) -> Vec<f64>
+    where F : Float + num_traits::cast::FromPrimitive + std::iter::Sum + std::fmt::UpperExp + Send + Sync {
+    (0..node_params.get_nb_nodes()).into_par_iter().map(|i| {
+        let node_param = node_params.get_node_param(i);
+        let (mut weight_sum, mut radius) = (0., 0.);
+        for edge in kgraph.get_out_edges_by_idx(i) {
+            if let Some(proba_edge) = node_param.get_edge(edge.get_node()) {
+                let dist = edge.weight.to_f64().unwrap();
+                weight_sum += proba_edge.weight as f64;
+                radius += proba_edge.weight as f64 * dist * dist;
+            }
+        }
+        (DENS_EPSILON + radius / weight_sum.max(DENS_EPSILON)).ln()
+    }).collect()
+} // end of get_original_log_radii
+
+
+// embedded scale of a node of initial scale x, mean_scale being the mean of initial scales of the graph,
+// width is the embedded scale of a node of mean scale (EmbedderParams::kernel_width)
+fn embedded_scale_from_initial_scale(x : f32, mean_scale : f32, width : f32) -> f32 {
+    let scale_sup = 4.0;  // CAVEAT seems we can go up to 4.
+    let scale_inf = 1./scale_sup;
+    // We want embedded scae impact between 0.5 and 2 (amplitude 4) , we take into account the square in cauchy weight
+    width * (x/mean_scale).min(scale_sup).max(scale_inf)
+} // end of embedded_scale_from_initial_scale
+
+
 // renormalize data (center and enclose in a box of a given box size) before optimization of cross entropy
-fn set_data_box<F>(data : &mut Array2<F>, box_size : f64) 
+fn set_data_box<F>(data : &mut Array2<F>, box_size : f64, clipping : &Clipping) 
     where  F: Float +  NumAssign + std::iter::Sum<F> + num_traits::cast::FromPrimitive + ndarray::ScalarOperand  {
     let nbdata = data.nrows();
     let dim = data.ncols();
     //
     let mut means = Array1::<F>::zeros(dim);
-    let mut max_max = F::zero();
     //
     for j in 0..dim  {
         for i in 0..nbdata {
@@ -1272,18 +2489,60 @@ fn set_data_box<F>(data : &mut Array2<F>, box_size : f64)
     for j in 0..dim  {
         for i in 0..nbdata {
             data[[i,j]] = data[[i,j]] - means[j];
-            max_max = max_max.max(data[[i,j]].abs());          
         }
     }
-    //
-    max_max /= F::from(0.5 * box_size).unwrap();
+    // bound is the max of absolute values for hard clipping, possibly a quantile
+    let mut abs_values : Vec<f64> = data.iter().map(|f| f.to_f64().unwrap().abs()).collect();
+    let bound = clipping.get_bound(&mut abs_values);
+    let half_box = 0.5 * box_size;
     for f in data.iter_mut()  {
-        *f = (*f)/max_max;
-        assert!((*f).abs() <= F::one());
+        let clipped = if bound > 0. { clipping.clip(f.to_f64().unwrap(), bound) / bound } else { 0. };
+        *f = F::from(clipped * half_box).unwrap();
+        assert!((*f).abs() <= F::from(half_box).unwrap());
     }    
 }  // end of set_data_box
 
 
+// leave one edge out jackknife of the barycenter of embedded neighbours of each node, weighted by edge probabilities.
+// returns the jackknife standard deviation (norm of the deviation in embedded space), NaN with less than 2 neighbours.
+fn jackknife_uncertainty<F : Float + Send + Sync>(embedded : &Array2<F>, node_params : &NodeParams) -> Array1<f64> {
+    let dim = embedded.ncols();
+    let position = |node : NodeIdx| -> Vec<f64> { embedded.row(node).iter().map(|x| x.to_f64().unwrap()).collect() };
+    let uncertainty : Vec<f64> = (0..node_params.get_nb_nodes()).into_par_iter().map(|n| {
+        let edges = &node_params.get_node_param(n).edges;
+        if edges.len() < 2 {
+            return f64::NAN;
+        }
+        // weighted sum of neighbour positions
+        let mut sum = vec![0f64; dim];
+        let mut sum_w = 0f64;
+        let positions : Vec<(f64, Vec<f64>)> = edges.iter().map(|e| (e.weight as f64, position(e.get_node()))).collect();
+        for (w, y) in &positions {
+            sum_w += w;
+            sum.iter_mut().zip(y.iter()).for_each(|(s, v)| *s += w * v);
+        }
+        // barycenters with one edge left out
+        let left_out : Vec<Vec<f64>> = positions.iter()
+                .filter(|(w, _)| sum_w - w > 0.)
+                .map(|(w, y)| sum.iter().zip(y.iter()).map(|(s, v)| (s - w * v) / (sum_w - w)).collect())
+                .collect();
+        let nb = left_out.len();
+        if nb < 2 {
+            return f64::NAN;
+        }
+        let mut mean = vec![0f64; dim];
+        for b in &left_out {
+            mean.iter_mut().zip(b.iter()).for_each(|(m, v)| *m += v / nb as f64);
+        }
+        let dispersion : f64 = left_out.iter()
+                .map(|b| b.iter().zip(mean.iter()).map(|(v, m)| (v - m) * (v - m)).sum::<f64>())
+                .sum();
+        ((nb - 1) as f64 / nb as f64 * dispersion).sqrt()
+    }).collect();
+    Array1::from(uncertainty)
+} // end of jackknife_uncertainty
+
+
 
 #[cfg(test)]
 mod tests {
@@ -1301,6 +2560,37 @@ mod tests {
 
 
 
+    #[test]
+    fn test_jackknife_uncertainty() {
+        // node 0 has 2 neighbours at the same place, node 1 has 2 spread neighbours, node 2 one neighbour, node 3 none
+        let edges = |e : &[(NodeIdx, f32)]| e.iter().map(|(n, w)| OutEdge::<f32>::new(*n, *w)).collect::<Vec<OutEdge<f32>>>();
+        let params = vec![NodeParam::new(1., edges(&[(4, 0.5), (5, 0.5)])), NodeParam::new(1., edges(&[(4, 0.5), (6, 0.5)])),
+                        NodeParam::new(1., edges(&[(6, 1.)])), NodeParam::new(1., edges(&[])),
+                        NodeParam::new(1., edges(&[(5, 1.)])), NodeParam::new(1., edges(&[(4, 1.)])), NodeParam::new(1., edges(&[(4, 1.)]))];
+        let node_params = NodeParams::new(params, 2);
+        let embedded = ndarray::arr2(&[[0f32, 0.], [0., 0.], [0., 0.], [0., 0.], [1., 0.], [1., 0.], [3., 0.]]);
+        let uncertainty = jackknife_uncertainty(&embedded, &node_params);
+        assert_eq!(uncertainty[0], 0.);
+        // left out barycenters are 1 and 3 : sqrt(1/2 * (1 + 1))
+        assert!((uncertainty[1] - 1.).abs() < 1.0E-10);
+        // no jackknife estimate with less than 2 neighbours
+        assert!(uncertainty[2].is_nan());
+        assert!(uncertainty[3].is_nan());
+    } // end of test_jackknife_uncertainty
+
+    #[test]
+    fn test_proba_edges_non_finite() {
+        log_init_test();
+        // 4 points on a line, each with its 2 nearest neighbours
+        let indices = ndarray::arr2(&[[1usize, 2], [0, 2], [1, 3], [2, 1]]);
+        let dists = ndarray::arr2(&[[1f32, 2.], [1., 1.], [1., 1.], [1., 2.]]);
+        let kgraph = KGraph::<f32>::from_knn_arrays(&indices, &dists).unwrap();
+        assert!(to_proba_edges(&kgraph, 1., 2.).is_ok());
+        let neighbours = vec![OutEdge::<f32>::new(1, 1.), OutEdge::<f32>::new(2, f32::NAN)];
+        let res = get_scale_from_proba_normalisation(&kgraph, 1., 2., &neighbours);
+        assert!(matches!(res, Err(AnnembedError::NonFinite(_))));
+    } // end of test_proba_edges_non_finite
+
     #[cfg(test)]
     fn gen_rand_data_f32(nb_elem: usize , dim:usize) -> Vec<Vec<f32>> {
         let mut data = Vec::<Vec<f32>>::with_capacity(nb_elem);
@@ -1313,6 +2603,17 @@ mod tests {
         }
         data
     } // end of gen_rand_data_f32
+
+    // graph with 10 neighbours of nb_elem random points in dimension 10, through a Hnsw with distance L1
+    fn mini_kgraph(nb_elem : usize) -> KGraph<f32> {
+        let data = gen_rand_data_f32(nb_elem, 10);
+        let data_with_id : Vec<(&Vec<f32>, usize)> = data.iter().zip(0..data.len()).collect();
+        let nb_layer = 16.min((nb_elem as f32).ln().trunc() as usize);
+        let mut hns = Hnsw::<f32, DistL1>::new(50, nb_elem, nb_layer, 50, DistL1{});
+        hns.set_keeping_pruned(true);
+        hns.parallel_insert(&data_with_id);
+        kgraph_from_hnsw_all(&hns, 10).unwrap()
+    } // end of mini_kgraph
     
     #[test]
     fn mini_embed_full() {
@@ -1321,7 +2622,7 @@ mod tests {
         let nb_elem = 500;
         let embed_dim = 20;
         let data = gen_rand_data_f32(nb_elem, embed_dim);
-        let data_with_id = data.iter().zip(0..data.len()).collect();
+        let data_with_id : Vec<_> = data.iter().zip(0..data.len()).collect();
         // hnsw construction
         let ef_c = 50;
         let max_nb_connection = 50;
@@ -1345,5 +2646,394 @@ mod tests {
     } // end of mini_embed_full
 
 
+    #[test]
+    fn mini_embed_refine() {
+        log_init_test();
+        let kgraph = mini_kgraph(500);
+        let embed_params = EmbedderParams::default();
+        let mut embedder = Embedder::new(&kgraph, embed_params);
+        assert!(embedder.embed().is_ok());
+        let layout = embedder.get_embedded_reindexed();
+        // a few more batches with a smaller step
+        let mut refine_params = embed_params;
+        refine_params.nb_grad_batch = 5;
+        refine_params.grad_step = 0.5;
+        assert!(embedder.refine(&layout, refine_params).is_ok());
+        assert_eq!(embedder.get_embedded().unwrap().dim(), layout.dim());
+    } // end of mini_embed_refine
+
+
+    #[test]
+    fn mini_embed_seed() {
+        log_init_test();
+        let kgraph = mini_kgraph(300);
+        // gradient updates are lock free so we need one thread to get exactly the same embedding
+        let pool = rayon::ThreadPoolBuilder::new().num_threads(1).build().unwrap();
+        let run = |seed : u64| {
+            let mut embed_params = EmbedderParams::default();
+            embed_params.set_dmap_init(false);
+            embed_params.nb_grad_batch = 5;
+            embed_params.set_seed(seed);
+            let mut embedder = Embedder::new(&kgraph, embed_params);
+            pool.install(|| embedder.embed()).unwrap();
+            embedder.get_embedded_reindexed()
+        };
+        let first = run(17);
+        assert_eq!(first, run(17));
+        assert_ne!(first, run(18));
+    } // end of mini_embed_seed
+
+
+    #[test]
+    fn mini_embed_checkpoint() {
+        log_init_test();
+        let kgraph = mini_kgraph(300);
+        // one thread and a seed to get exactly the same embedding
+        let mut embed_params = EmbedderParams::default();
+        embed_params.set_dmap_init(false);
+        embed_params.nb_grad_batch = 6;
+        embed_params.set_seed(11);
+        embed_params.set_num_threads(1);
+        let mut embedder = Embedder::new(&kgraph, embed_params);
+        embedder.embed().unwrap();
+        let uninterrupted = embedder.get_embedded().unwrap().clone();
+        // loss of initial embedding, every loss_period (5) batches and after the last batch
+        let history = embedder.get_loss_history().unwrap();
+        assert_eq!(history.losses.iter().map(|(batch, _)| *batch).collect::<Vec<usize>>(), vec![0, 5, 6]);
+        assert_eq!(history.final_loss, history.losses[2].1);
+        assert_eq!(embedder.get_final_loss(), Some(history.final_loss));
+        // checkpoint after batch 3, the last batch is not checkpointed
+        let path = std::env::temp_dir().join("annembed_test_checkpoint.bin");
+        let _ = std::fs::remove_file(&path);
+        let mut embedder = Embedder::new(&kgraph, embed_params);
+        embedder.set_checkpoint(&path, 3);
+        embedder.embed().unwrap();
+        assert_eq!(embedder.get_embedded().unwrap(), &uninterrupted);
+        // resume with other parameters, those of the checkpoint are used
+        let mut other_params = embed_params;
+        other_params.nb_grad_batch = 1;
+        let mut resumed = Embedder::new(&kgraph, other_params);
+        resumed.resume_from(&path).unwrap();
+        assert_eq!(resumed.get_embedded().unwrap(), &uninterrupted);
+        assert_eq!(resumed.get_nb_grad_batch(), 6);
+        assert_eq!(resumed.get_loss_history().unwrap().losses[0].0, 3);
+        // a cancelled run checkpoints the state reached, runs of other embedders go on
+        let token = CancellationToken::new();
+        token.cancel();
+        let mut cancelled = Embedder::new(&kgraph, embed_params);
+        cancelled.set_checkpoint(&path, 3);
+        cancelled.set_cancellation_token(&token);
+        assert!(matches!(cancelled.embed(), Err(AnnembedError::Cancelled)));
+        let mut resumed = Embedder::new(&kgraph, embed_params);
+        resumed.resume_from(&path).unwrap();
+        assert_eq!(resumed.get_embedded().unwrap(), &uninterrupted);
+        let _ = std::fs::remove_file(&path);
+    } // end of mini_embed_checkpoint
+
+
+    #[test]
+    fn mini_embed_optimizers() {
+        log_init_test();
+        let kgraph = mini_kgraph(300);
+        let mut embed_params = EmbedderParams::default();
+        embed_params.set_dmap_init(false);
+        embed_params.nb_grad_batch = 6;
+        embed_params.set_seed(7);
+        embed_params.set_num_threads(1);
+        for (schedule, optimizer) in [(StepSchedule::Cosine, GradientOptimizer::momentum()), (StepSchedule::Constant, GradientOptimizer::adam()),
+                    (StepSchedule::Linear, GradientOptimizer::adam())] {
+            embed_params.set_step_schedule(schedule);
+            embed_params.set_optimizer(optimizer);
+            let mut embedder = Embedder::new(&kgraph, embed_params);
+            embedder.embed().unwrap();
+            assert!(embedder.get_final_loss().unwrap().is_finite());
+            assert!(embedder.get_embedded().unwrap().iter().all(|x| x.is_finite()));
+        }
+        // moments are checkpointed so that resuming gives the same embedding
+        let mut embedder = Embedder::new(&kgraph, embed_params);
+        embedder.embed().unwrap();
+        let uninterrupted = embedder.get_embedded().unwrap().clone();
+        let path = std::env::temp_dir().join("annembed_test_checkpoint_adam.bin");
+        let _ = std::fs::remove_file(&path);
+        let mut embedder = Embedder::new(&kgraph, embed_params);
+        embedder.set_checkpoint(&path, 4);
+        embedder.embed().unwrap();
+        let mut other_params = EmbedderParams::default();
+        other_params.set_num_threads(1);
+        let mut resumed = Embedder::new(&kgraph, other_params);
+        resumed.resume_from(&path).unwrap();
+        assert_eq!(resumed.get_embedded().unwrap(), &uninterrupted);
+        let _ = std::fs::remove_file(&path);
+    } // end of mini_embed_optimizers
+
+
+    #[test]
+    fn mini_embed_exaggeration() {
+        log_init_test();
+        let kgraph = mini_kgraph(300);
+        let mut embed_params = EmbedderParams::default();
+        embed_params.set_dmap_init(false);
+        embed_params.nb_grad_batch = 8;
+        embed_params.set_seed(11);
+        embed_params.set_num_threads(1);
+        // reference run without exaggeration
+        let mut embedder = Embedder::new(&kgraph, embed_params);
+        embedder.embed().unwrap();
+        let reference = embedder.get_embedded().unwrap().clone();
+        // a factor 1 is no exaggeration
+        embed_params.set_early_exaggeration(1., 2);
+        let mut embedder = Embedder::new(&kgraph, embed_params);
+        embedder.embed().unwrap();
+        assert_eq!(embedder.get_embedded().unwrap(), &reference);
+        // exaggerated attraction changes the trajectory
+        embed_params.set_early_exaggeration(4., 2);
+        let mut embedder = Embedder::new(&kgraph, embed_params);
+        embedder.embed().unwrap();
+        assert!(embedder.get_final_loss().unwrap().is_finite());
+        let embedded = embedder.get_embedded().unwrap();
+        assert!(embedded.iter().all(|x| x.is_finite()));
+        assert_ne!(embedded, &reference);
+        // exaggeration is only possible with negative sampling
+        let mut pacmap_params = embed_params;
+        pacmap_params.set_pair_sampling(PairSampling::pacmap());
+        let mut embedder = Embedder::new(&kgraph, pacmap_params);
+        assert!(embedder.embed().is_err());
+        // a factor less than 1 is rejected
+        embed_params.set_early_exaggeration(0.5, 2);
+        let mut embedder = Embedder::new(&kgraph, embed_params);
+        assert!(embedder.embed().is_err());
+    } // end of mini_embed_exaggeration
+
+
+    #[test]
+    fn mini_embed_components() {
+        log_init_test();
+        // 2 rings of 120 nodes, each node linked to its 10 nearest ring neighbours, and a clique of 5 nodes
+        let (ring, small) = (120, 5);
+        let nb_nodes = 2 * ring + small;
+        let mut indices = Array2::<usize>::from_elem((nb_nodes, 10), nb_nodes);
+        let mut dists = Array2::<f32>::zeros((nb_nodes, 10));
+        for i in 0..2 * ring {
+            let (start, rank) = (i - i % ring, i % ring);
+            for k in 0..5 {
+                let offset = k + 1;
+                indices[[i, 2 * k]] = start + (rank + offset) % ring;
+                indices[[i, 2 * k + 1]] = start + (rank + ring - offset) % ring;
+                dists[[i, 2 * k]] = 0.1 * offset as f32;
+                dists[[i, 2 * k + 1]] = 0.1 * offset as f32;
+            }
+        }
+        for i in 2 * ring..nb_nodes {
+            for (k, j) in (2 * ring..nb_nodes).filter(|j| *j != i).enumerate() {
+                indices[[i, k]] = j;
+                dists[[i, k]] = 0.1 * (1 + k) as f32;
+            }
+        }
+        let kgraph = KGraph::<f32>::from_knn_arrays(&indices, &dists).unwrap();
+        let mut embed_params = EmbedderParams::default();
+        assert!(!embed_params.layout_components);
+        embed_params.set_layout_components(true);
+        embed_params.set_seed(3);
+        let mut embedder = Embedder::new(&kgraph, embed_params);
+        embedder.embed().unwrap();
+        let components = embedder.get_component_ids().unwrap();
+        assert_eq!(components.iter().max(), Some(&2));
+        // whole graph state is available after a component layout
+        assert_eq!(embedder.get_nb_nodes(), nb_nodes);
+        let history = embedder.get_loss_history().unwrap();
+        assert!(!history.losses.is_empty() && history.final_loss.is_finite());
+        assert_eq!(embedder.get_final_loss(), Some(history.final_loss));
+        assert_eq!(embedder.get_local_uncertainty().unwrap().len(), nb_nodes);
+        let embedded = embedder.get_embedded().unwrap();
+        assert!(embedded.iter().all(|x| x.is_finite()));
+        // the small component is not optimized but keeps a spectral layout, not random positions
+        let small_nodes : Vec<NodeIdx> = (0..nb_nodes).filter(|n| components[*n] == 2).collect();
+        assert_eq!(small_nodes.len(), small);
+        let initial = embedder.get_initial_embedding().unwrap();
+        for n in &small_nodes {
+            assert_eq!(embedded.row(*n), initial.row(*n));
+        }
+    } // end of mini_embed_components
+
+
+    #[test]
+    fn mini_embed_densmap() {
+        log_init_test();
+        // a dense and a sparse cluster
+        let nb_elem = 600;
+        let dim = 10;
+        let mut rng = Xoshiro256PlusPlus::seed_from_u64(5);
+        let normal = Normal::<f32>::new(0., 1.).unwrap();
+        let data : Vec<Vec<f32>> = (0..nb_elem).map(|i| {
+            let (center, width) = if i % 2 == 0 { (0., 0.1) } else { (10., 1.) };
+            (0..dim).map(|_| center + width * normal.sample(&mut rng)).collect()
+        }).collect();
+        let data_with_id : Vec<(&Vec<f32>, usize)> = data.iter().zip(0..data.len()).collect();
+        let nb_layer = 16.min((nb_elem as f32).ln().trunc() as usize);
+        let mut hns = Hnsw::<f32, DistL2>::new(24, nb_elem, nb_layer, 48, DistL2{});
+        hns.set_keeping_pruned(true);
+        hns.parallel_insert(&data_with_id);
+        let kgraph : KGraph<f32> = kgraph_from_hnsw_all(&hns, 10).unwrap();
+        let mut embed_params = EmbedderParams::default();
+        embed_params.set_dmap_init(false);
+        embed_params.set_layout_components(false);
+        embed_params.set_seed(7);
+        embed_params.set_num_threads(1);
+        // mean embedded distance to graph neighbours of the sparse cluster over the dense one
+        let radius_ratio = |embedder : &Embedder<f32>| -> f64 {
+            let mut radii = [(0f64, 0usize); 2];
+            for (i, edges) in kgraph.get_neighbours().iter().enumerate() {
+                let x = embedder.get_embedded_by_nodeid(i);
+                let radius = edges.iter().map(|e| {
+                    let y = embedder.get_embedded_by_nodeid(e.get_node());
+                    x.iter().zip(y.iter()).map(|(a, b)| ((a - b) * (a - b)) as f64).sum::<f64>().sqrt()
+                }).sum::<f64>() / edges.len().max(1) as f64;
+                let cluster = *kgraph.get_data_id_from_idx(i).unwrap() % 2;
+                radii[cluster].0 += radius;
+                radii[cluster].1 += 1;
+            }
+            (radii[1].0 / radii[1].1 as f64) / (radii[0].0 / radii[0].1 as f64)
+        };
+        let mut embedder = Embedder::new(&kgraph, embed_params);
+        embedder.embed().unwrap();
+        let ratio_umap = radius_ratio(&embedder);
+        //
+        embed_params.set_densmap(2., 0.3).unwrap();
+        let mut embedder = Embedder::new(&kgraph, embed_params);
+        embedder.embed().unwrap();
+        assert!(embedder.get_final_loss().unwrap().is_finite());
+        assert!(embedder.get_embedded().unwrap().iter().all(|x| x.is_finite()));
+        let ratio_densmap = radius_ratio(&embedder);
+        log::info!("radius ratio of sparse to dense cluster, umap : {:.3e}, densmap : {:.3e}", ratio_umap, ratio_densmap);
+        // the density term keeps the sparse cluster wider than the dense one
+        assert!(ratio_densmap > ratio_umap);
+        // invalid parameters
+        assert!(embed_params.set_densmap(-1., 0.3).is_err());
+        assert!(embed_params.set_densmap(2., 1.5).is_err());
+        // no original distances without graph
+        let neighbours : Vec<Vec<(NodeIdx, f32)>> = (0..100).map(|i| vec![((i + 1) % 100, 0.5), ((i + 99) % 100, 0.5)]).collect();
+        let node_params = NodeParams::from_affinities(neighbours, None).unwrap();
+        let mut embedder = Embedder::<f32>::from_node_params(node_params, embed_params);
+        assert!(embedder.embed().is_err());
+    } // end of mini_embed_densmap
+
+
+    #[test]
+    fn mini_embed_pacmap() {
+        log_init_test();
+        // weights phases on 45 batches
+        assert_eq!(get_pacmap_weights(0, 45), (2., 1000., 1.));
+        assert_eq!(get_pacmap_weights(15, 45), (3., 3., 1.));
+        assert_eq!(get_pacmap_weights(45, 45), (1., 0., 1.));
+        let mut rng = Xoshiro256PlusPlus::seed_from_u64(3);
+        let mean = (0..10000).map(|_| get_nb_pairs(0.5, &mut rng)).sum::<usize>() as f64 / 10000.;
+        assert!((mean - 0.5).abs() < 0.05);
+        assert_eq!(get_nb_pairs(2., &mut rng), 2);
+        //
+        let kgraph = mini_kgraph(300);
+        let mut embed_params = EmbedderParams::default();
+        embed_params.set_dmap_init(false);
+        embed_params.set_pair_sampling(PairSampling::pacmap());
+        embed_params.set_seed(13);
+        embed_params.set_num_threads(1);
+        let mut embedder = Embedder::new(&kgraph, embed_params);
+        embedder.embed().unwrap();
+        assert!(embedder.get_final_loss().unwrap().is_finite());
+        let embedded = embedder.get_embedded().unwrap().clone();
+        assert!(embedded.iter().all(|x| x.is_finite()));
+        // sampling depends only on seed with one thread
+        let mut embedder = Embedder::new(&kgraph, embed_params);
+        embedder.embed().unwrap();
+        assert_eq!(embedder.get_embedded().unwrap(), &embedded);
+    } // end of mini_embed_pacmap
+
+
+    #[test]
+    fn mini_embed_trimap() {
+        log_init_test();
+        // 2 separated clusters must stay separated
+        let nb_elem = 400;
+        let dim = 10;
+        let mut rng = Xoshiro256PlusPlus::seed_from_u64(17);
+        let unif =  Uniform::<f32>::new(0.,1.);
+        let data : Vec<Vec<f32>> = (0..nb_elem).map(|i| {
+            let center = if i % 2 == 0 { 0. } else { 10. };
+            (0..dim).map(|_| center + rng.sample(unif)).collect()
+        }).collect();
+        let data_with_id : Vec<(&Vec<f32>, usize)> = data.iter().zip(0..data.len()).collect();
+        let nb_layer = 16.min((nb_elem as f32).ln().trunc() as usize);
+        let mut hns = Hnsw::<f32, DistL2>::new(24, nb_elem, nb_layer, 48, DistL2{});
+        hns.set_keeping_pruned(true);
+        hns.parallel_insert(&data_with_id);
+        let kgraph : KGraph<f32> = kgraph_from_hnsw_all(&hns, 10).unwrap();
+        let mut embed_params = EmbedderParams::default();
+        embed_params.set_pair_sampling(PairSampling::trimap());
+        let mut embedder = Embedder::new(&kgraph, embed_params);
+        embedder.embed().unwrap();
+        let layout = embedder.get_embedded_reindexed();
+        assert!(layout.iter().all(|x| x.is_finite()));
+        let centroid = |parity : usize| -> Array1<f32> {
+            let rows : Vec<usize> = (0..nb_elem).filter(|i| i % 2 == parity).collect();
+            rows.iter().fold(Array1::<f32>::zeros(layout.ncols()), |acc, i| acc + layout.row(*i)) / rows.len() as f32
+        };
+        let spread = |parity : usize, center : &Array1<f32>| -> f32 {
+            let rows : Vec<usize> = (0..nb_elem).filter(|i| i % 2 == parity).collect();
+            rows.iter().map(|i| (&layout.row(*i) - center).mapv(|x| x * x).sum().sqrt()).sum::<f32>() / rows.len() as f32
+        };
+        let (c0, c1) = (centroid(0), centroid(1));
+        let gap = (&c0 - &c1).mapv(|x| x * x).sum().sqrt();
+        log::info!("trimap clusters gap {:.3e}, spreads {:.3e} {:.3e}", gap, spread(0, &c0), spread(1, &c1));
+        assert!(gap > spread(0, &c0).max(spread(1, &c1)));
+    } // end of mini_embed_trimap
+
+
+    #[test]
+    fn mini_embed_transform() {
+        log_init_test();
+        // 2 separated clusters
+        let nb_elem = 600;
+        let dim = 10;
+        let mut rng = thread_rng();
+        let unif =  Uniform::<f32>::new(0.,1.);
+        let data : Vec<Vec<f32>> = (0..nb_elem).map(|i| {
+            let center = if i % 2 == 0 { 0. } else { 10. };
+            (0..dim).map(|_| center + rng.sample(unif)).collect()
+        }).collect();
+        let data_with_id : Vec<(&Vec<f32>, usize)> = data.iter().zip(0..data.len()).collect();
+        let nb_layer = 16.min((nb_elem as f32).ln().trunc() as usize);
+        let mut hns = Hnsw::<f32, DistL2>::new(24, nb_elem, nb_layer, 48, DistL2{});
+        hns.set_keeping_pruned(true);
+        hns.parallel_insert(&data_with_id);
+        let kgraph : KGraph<f32> = kgraph_from_hnsw_all(&hns, 10).unwrap();
+        let embed_params = EmbedderParams::default();
+        let mut embedder = Embedder::new(&kgraph, embed_params);
+        assert!(embedder.embed().is_ok());
+        let layout = embedder.get_embedded_reindexed();
+        let centroid = |parity : usize| -> Array1<f32> {
+            let rows : Vec<usize> = (0..nb_elem).filter(|i| i % 2 == parity).collect();
+            rows.iter().fold(Array1::<f32>::zeros(layout.ncols()), |acc, i| acc + layout.row(*i)) / rows.len() as f32
+        };
+        let centroids = [centroid(0), centroid(1)];
+        // new points are perturbed copies of data, they must be placed in the cluster of their origin
+        let new_data : Vec<Vec<f32>> = (0..20).map(|i| data[i].iter().map(|x| x + 0.1 * rng.sample(unif)).collect()).collect();
+        let transformed = embedder.transform(&hns, &new_data).unwrap();
+        assert_eq!(transformed.dim(), (20, embed_params.asked_dim));
+        // embedding is not modified
+        assert_eq!(embedder.get_embedded_reindexed(), layout);
+        for i in 0..20 {
+            let d = |c : &Array1<f32>| (&transformed.row(i) - c).mapv(|x| x * x).sum();
+            assert!(d(&centroids[i % 2]) < d(&centroids[1 - i % 2]));
+        }
+        // a reloaded embedder gives the same layout and can transform
+        let path = std::env::temp_dir().join(format!("annembed_test_embedder_{}.bin", std::process::id()));
+        embedder.dump(&path).unwrap();
+        let reloaded = Embedder::<f32>::reload(&path, Some(&kgraph)).unwrap();
+        let _ = std::fs::remove_file(&path);
+        assert_eq!(reloaded.get_embedded_reindexed(), layout);
+        let transformed = reloaded.transform(&hns, &new_data).unwrap();
+        assert_eq!(transformed.dim(), (20, embed_params.asked_dim));
+    } // end of mini_embed_transform
+
+
 
 } // end of tests
@@ -1,5 +1,93 @@
 //! This module defines parameters for ann embedding.
 //!
+
+use serde::{Deserialize, Serialize};
+
+use crate::graphlaplace::{ApproxSvdMode, EigenSolver};
+use crate::tools::clip::Clipping;
+use crate::error::AnnembedError;
+
+/// schedule of the gradient step along gradient batches. The step at batch t (1..=nb_batch) is the initial step
+/// multiplied by [factor](Self::factor)
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum StepSchedule {
+    /// 1 - t/nb_batch . This is the default
+    Linear,
+    /// (1 + cos(pi * t/nb_batch))/2 , keeps a larger step in first batches and decays slowly at the end
+    Cosine,
+    /// the initial step at each batch
+    Constant,
+}
+
+impl StepSchedule {
+    /// factor applied to initial step at gradient batch iter (1..=nb_batch)
+    pub fn factor(&self, iter : usize, nb_batch : usize) -> f64 {
+        match self {
+            StepSchedule::Linear => 1. - iter as f64 / nb_batch as f64,
+            StepSchedule::Cosine => 0.5 * (1. + (std::f64::consts::PI * iter as f64 / nb_batch as f64).cos()),
+            StepSchedule::Constant => 1.,
+        }
+    }
+} // end of impl StepSchedule
+
+
+/// update rule of embedded coordinates in the stochastic gradient.  
+/// Momentum and Adam keep moments for each node, updated each time the node is moved by a sampled edge
+/// (so Adam bias correction uses the number of updates of the node).
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum GradientOptimizer {
+    /// plain stochastic gradient, the step is grad_step. This is the default
+    Sgd,
+    /// heavy ball : the displacement of a node is beta times its previous displacement plus the gradient step. Step is grad_step
+    Momentum { beta : f64 },
+    /// Adam. The step is lr, in units of the embedding box (of size 1 at initialization) and replaces grad_step
+    Adam { lr : f64, beta1 : f64, beta2 : f64 },
+}
+
+impl GradientOptimizer {
+    /// momentum with beta = 0.9
+    pub fn momentum() -> Self {
+        GradientOptimizer::Momentum { beta : 0.9 }
+    }
+
+    /// Adam with lr = 0.01, beta1 = 0.9, beta2 = 0.999
+    pub fn adam() -> Self {
+        GradientOptimizer::Adam { lr : 0.01, beta1 : 0.9, beta2 : 0.999 }
+    }
+} // end of impl GradientOptimizer
+
+
+/// sampling of pairs of nodes and loss of the gradient batches
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum PairSampling {
+    /// edges of the graph are sampled according to their probability, each with 5 negative (non neighbour) samples,
+    /// and the loss is the cross entropy. This is the default
+    Negative,
+    /// PaCMAP (Wang Y., Huang H., Rudin C., Shaposhnik Y. JMLR 2021) : each sampled edge (near pair) comes with mn_ratio mid-near pairs
+    /// and fp_ratio further pairs (random non neighbours) on average. As the embedder only knows the graph, mid-near pairs
+    /// are neighbours of neighbours that are not neighbours. Weights of the 3 kinds of pairs are staged along batches as in PaCMAP,
+    /// mid-near pairs driving global structure in the first batches.
+    PaCMAP { mn_ratio : f64, fp_ratio : f64 },
+    /// TriMap (Amid E., Warmuth M.K. arXiv:1910.00204) : triplets (i, j, k) made of a sampled edge (i,j) and nb_far random non neighbours k
+    /// of i, with loss s(i,k)/(s(i,j) + s(i,k)) where s(i,j) = 1/(1 + ||y_{i} - y_{j}||^2).  
+    /// As edges are sampled according to their probability, it plays the role of the weight of TriMap triplets.
+    /// The random triplets of TriMap, which need original distances between any points, are not used.
+    TriMap { nb_far : usize },
+}
+
+impl PairSampling {
+    /// PaCMAP with default ratios : 0.5 mid-near pair and 2 further pairs by near pair
+    pub fn pacmap() -> Self {
+        PairSampling::PaCMAP { mn_ratio : 0.5, fp_ratio : 2. }
+    }
+
+    /// TriMap with 5 far nodes by sampled edge as in TriMap
+    pub fn trimap() -> Self {
+        PairSampling::TriMap { nb_far : 5 }
+    }
+} // end of impl PairSampling
+
+
 #[cfg_attr(doc, katexit::katexit)]
 /// It is necessary to describe briefly the model used in the embedding:
 /// 
@@ -46,7 +134,11 @@
 /// 
 /// by default b = 1.
 /// The coefficient $a_{x}$ is deduced from the scale coefficient in the original space with some
-/// restriction to avoid too large fluctuations.
+/// restriction to avoid too large fluctuations : it is kernel_width (default 0.2) times the ratio of the scale of x
+/// to the mean scale, clipped to \[1/4, 4\].
+///
+/// Umap uses $w(x,y) = 1/(1 + a ||x - y||^{2b})$ , so for a node of mean scale $a = kernel\_width^{-2b}$.
+/// The (a, b) of Umap can be fitted from its min_dist and spread parameters with [EmbedderParams::set_min_dist_spread].
 /// 
 /// 
 /// 
@@ -70,10 +162,33 @@
 /// 
 /// - expression of the gradient
 /// 
+/// - schedule of the step and update rule
+///
+/// By default the step decreases linearly from $\gamma_{0}$ to 0 along gradient batches and each sampled edge
+/// moves its end points by the gradient times the step. See [StepSchedule] and [GradientOptimizer] for the alternatives.
+/// As in t-Sne the attractive weights can be multiplied by a factor during the first batches (early exaggeration),
+/// see [EmbedderParams::set_early_exaggeration].
+///
+/// - PaCMAP pair sampling
+///
+/// With [PairSampling::PaCMAP] the losses of PaCMAP replace the cross entropy in gradient batches, $\tilde{d} = 1 + ||y_{i} - y_{j}||^{2}$ being
+/// the embedded distance of a pair : $w_{NB} \tilde{d}/(10 + \tilde{d})$ for near pairs, $w_{MN} \tilde{d}/(10000 + \tilde{d})$ for mid-near pairs
+/// and $w_{FP}/(1 + \tilde{d})$ for further pairs. Embedded scales and the density term are not used.
+/// The same holds for the triplet loss of [PairSampling::TriMap]. In both cases the loss history and final loss still report the
+/// cross entropy of the embedded graph, not the objective being optimized, so they compare embeddings but are not a convergence
+/// criterion of these samplings.
+///
+/// - density preservation
+///
+/// As in [densMAP](https://doi.org/10.1038/s41587-020-00801-7) the opposite of the correlation between the logs of local radii
+/// in original and embedded space, weighted by dens_lambda, can be added to the cross entropy in the last batches.
+/// The local radius of a node is the mean of squared distances to its neighbours weighted by edge probabilities in original space
+/// and by embedded edge weights in embedded space. See [EmbedderParams::set_densmap].
+/// 
 
 
 /// main parameters driving Embeding
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, PartialEq, Serialize, Deserialize)]
 pub struct EmbedderParams {
     /// embedding dimension : default to 2
     pub asked_dim : usize,
@@ -95,7 +210,43 @@ pub struct EmbedderParams {
     /// As the first iterations run on few points we can do more iterations. Default is 4.
     pub grad_factor : usize, 
     /// if layer > 0 means we have hierarchical initialization
-    pub hierarchy_layer : usize
+    pub hierarchy_layer : usize,
+    /// if true and the graph is not connected, each connected component is embedded separately and 
+    /// component layouts are arranged on a grid. default to false
+    pub layout_components : bool,
+    /// if true, scale_rho is estimated from data by the Berry-Giannakis-Harlim criterion. default to false
+    pub auto_scale_rho : bool,
+    /// clipping used in scaling diffusion maps coordinates into the initial box, in placement around projected points
+    /// in hierarchical case and on gradient coefficients. default to [Clipping::Hard]
+    pub clipping : Clipping,
+    /// solver of eigen pairs in diffusion maps initialization. default to [EigenSolver::Svd]
+    pub eigen_solver : EigenSolver,
+    /// randomized svd used in diffusion maps initialization of large graphs. default to rank 20 with 5 subspace iterations
+    pub approx_svd : ApproxSvdMode,
+    /// seed of random initialization, edge and negative sampling and randomized svd. default to None:
+    /// gradient sampling is then randomized at each run and randomized svd uses a fixed seed.
+    pub seed : Option<u64>,
+    /// number of threads of the pool running the embedding. default to None : the pool of the caller (global rayon pool) is used.
+    pub num_threads : Option<usize>,
+    /// schedule of the gradient step along batches. default to [StepSchedule::Linear]
+    pub step_schedule : StepSchedule,
+    /// update rule of the gradient. default to [GradientOptimizer::Sgd]
+    pub optimizer : GradientOptimizer,
+    /// factor (>= 1) multiplying the attractive weight of edges during the first exaggeration_batches gradient batches,
+    /// only with [PairSampling::Negative]. default to 1.
+    pub exaggeration : f64,
+    /// number of gradient batches with exaggerated attraction. default to 0
+    pub exaggeration_batches : usize,
+    /// embedded scale of a node of mean scale, the embedded kernel being 1/(1 + (d/(kernel_width * relative scale))^(2b)). default 0.2
+    pub kernel_width : f64,
+    /// weight of the density correlation term of densMAP. default to 0. : no density term
+    pub dens_lambda : f64,
+    /// fraction of last gradient batches using the density term. default to 0.3
+    pub dens_frac : f64,
+    /// sampling of pairs in gradient batches. default to [PairSampling::Negative]
+    pub pair_sampling : PairSampling,
+    /// the cross entropy, a pass on all edges, is computed every loss_period gradient batches and after the last one. default to 5
+    pub loss_period : usize,
 } // end of EmbedderParams
 
 
@@ -111,7 +262,26 @@ impl EmbedderParams {
         let nb_grad_batch = 15;
         let grad_factor : usize = 4;
         let hierarchy_layer = 0;
-        EmbedderParams{asked_dim, dmap_init, beta, b, scale_rho, grad_step, nb_sampling_by_edge , nb_grad_batch, grad_factor, hierarchy_layer}
+        let layout_components = false;
+        let auto_scale_rho = false;
+        let clipping = Clipping::Hard;
+        let eigen_solver = EigenSolver::Svd;
+        let approx_svd = ApproxSvdMode::default();
+        let seed = None;
+        let num_threads = None;
+        let step_schedule = StepSchedule::Linear;
+        let optimizer = GradientOptimizer::Sgd;
+        let exaggeration = 1.;
+        let exaggeration_batches = 0;
+        let kernel_width = 0.2;
+        let dens_lambda = 0.;
+        let dens_frac = 0.3;
+        let pair_sampling = PairSampling::Negative;
+        let loss_period = 5;
+        EmbedderParams{asked_dim, dmap_init, beta, b, scale_rho, grad_step, nb_sampling_by_edge , nb_grad_batch, grad_factor, hierarchy_layer,
+                    layout_components, auto_scale_rho, clipping, eigen_solver, approx_svd, seed, num_threads,
+                    step_schedule, optimizer, exaggeration, exaggeration_batches, kernel_width,
+                    dens_lambda, dens_frac, pair_sampling, loss_period}
     }
 
 
@@ -126,6 +296,20 @@ impl EmbedderParams {
         log::info!("\t number of gradient batch : {}", self.nb_grad_batch);
         log::info!("\t factor for nbgradient batch in first hierarchical pass is  : {}", self.grad_factor);
         log::info!("\t hierarchy layer  : {}", self.hierarchy_layer);
+        log::info!("\t layout of connected components : {}", self.layout_components);
+        log::info!("\t automatic scale factor : {}", self.auto_scale_rho);
+        log::info!("\t clipping : {:?}", self.clipping);
+        log::info!("\t eigen solver : {:?}", self.eigen_solver);
+        log::info!("\t approximated svd : {:?}", self.approx_svd);
+        log::info!("\t seed : {:?}", self.seed);
+        log::info!("\t number of threads : {:?}", self.num_threads);
+        log::info!("\t step schedule : {:?}", self.step_schedule);
+        log::info!("\t gradient optimizer : {:?}", self.optimizer);
+        log::info!("\t early exaggeration : {} during {} batches", self.exaggeration, self.exaggeration_batches);
+        log::info!("\t density term weight : {}, on last fraction of batches : {}", self.dens_lambda, self.dens_frac);
+        log::info!("\t pair sampling : {:?}", self.pair_sampling);
+        log::info!("\t loss computed every {} batches", self.loss_period);
+        log::info!("\t embedded kernel width : {:.3e}, (a, b) : ({:.3e}, {:.3e})", self.kernel_width, self.get_a(), self.get_b());
     }
 
     /// set to false if random initialization is preferred
@@ -160,5 +344,210 @@ impl EmbedderParams {
 
     pub fn get_hierarchy_layer(&self) -> usize {
         self.hierarchy_layer
-    }    
+    }
+
+    /// set to true to embed each connected component of a disconnected graph separately and arrange component layouts
+    /// on a grid, instead of embedding the graph in one pass (the default).  
+    /// Components with less than max(50, 4 * max number of neighbours) nodes are not optimized, they keep their diffusion maps position.
+    pub fn set_layout_components(&mut self, val : bool) {
+        self.layout_components = val;
+    }
+
+    /// set to true to choose scale_rho from data (Berry-Giannakis-Harlim bandwidth selection) instead of the fixed value
+    pub fn set_auto_scale_rho(&mut self, val : bool) {
+        self.auto_scale_rho = val;
+    }
+
+    /// sets the clipping strategy, see [Clipping]
+    pub fn set_clipping(&mut self, clipping : Clipping) {
+        self.clipping = clipping;
+    }
+
+    /// sets the solver of eigen pairs used in diffusion maps initialization, see [EigenSolver]
+    pub fn set_eigen_solver(&mut self, solver : EigenSolver) {
+        self.eigen_solver = solver;
+    }
+
+    /// sets rank and number of subspace iterations (or adaptative precision) of the randomized svd, see [ApproxSvdMode]
+    pub fn set_approx_svd(&mut self, mode : ApproxSvdMode) {
+        self.approx_svd = mode;
+    }
+
+    /// sets the seed of all random steps to get reproducible embeddings.  
+    /// Gradient updates are threaded and applied without locking so runs are exactly reproducible only with one thread.
+    pub fn set_seed(&mut self, seed : u64) {
+        self.seed = Some(seed);
+    }
+
+    /// runs [embed](crate::embedder::Embedder::embed) and [refine](crate::embedder::Embedder::refine)
+    /// in a rayon pool of nb_threads threads instead of the pool of the caller.
+    pub fn set_num_threads(&mut self, nb_threads : usize) {
+        self.num_threads = Some(nb_threads);
+    }
+
+    /// sets the schedule of the gradient step along batches, see [StepSchedule]
+    pub fn set_step_schedule(&mut self, schedule : StepSchedule) {
+        self.step_schedule = schedule;
+    }
+
+    /// sets the update rule of the gradient, see [GradientOptimizer]
+    pub fn set_optimizer(&mut self, optimizer : GradientOptimizer) {
+        self.optimizer = optimizer;
+    }
+
+    /// multiplies the attractive weight of edges by factor (>= 1) during the first nb_batch gradient batches, as the early
+    /// exaggeration of t-Sne. This helps clusters to separate in the first batches, in particular with a poor initialization.
+    /// Typical values are a factor 4 to 12 during a quarter of the batches.  
+    /// Only possible with [PairSampling::Negative], embedding with another pair sampling and a factor other than 1 returns an error.
+    pub fn set_early_exaggeration(&mut self, factor : f64, nb_batch : usize) {
+        self.exaggeration = factor;
+        self.exaggeration_batches = nb_batch;
+    }
+
+    /// sets the sampling of pairs in gradient batches, see [PairSampling].  
+    /// With PaCMAP or TriMap the recorded loss stays the cross entropy and not the PaCMAP or TriMap objective.
+    pub fn set_pair_sampling(&mut self, pair_sampling : PairSampling) {
+        self.pair_sampling = pair_sampling;
+    }
+
+    /// sets the number of gradient batches between 2 computations of the cross entropy recorded in the
+    /// [loss history](crate::embedder::Embedder::get_loss_history). The loss of the initial embedding and of the last batch are always computed.  
+    /// Each computation is a pass on all edges with their negative samples, period 1 gives the whole curve at the cost of this pass after each batch.  
+    /// Returns an error if period is 0.
+    pub fn set_loss_period(&mut self, period : usize) -> Result<(), AnnembedError> {
+        if period == 0 {
+            log::error!("EmbedderParams::set_loss_period, period must be >= 1");
+            return Err(AnnembedError::InvalidParameter(String::from("loss period must be >= 1")));
+        }
+        self.loss_period = period;
+        Ok(())
+    }
+
+    /// adds the densMAP density correlation term with weight lambda (umap-learn uses 2.) to the objective during the last fraction frac
+    /// (in \[0,1\], umap-learn uses 0.3) of gradient batches, to preserve the variations of local density of the original data.  
+    /// Original distances are needed, so this is not possible with an embedder constructed from user node params.  
+    /// Returns an error if lambda is negative or frac is not in \[0,1\].
+    pub fn set_densmap(&mut self, lambda : f64, frac : f64) -> Result<(), AnnembedError> {
+        if !(lambda >= 0. && lambda.is_finite() && (0. ..=1.).contains(&frac)) {
+            log::error!("EmbedderParams::set_densmap, need lambda >= 0 and frac in [0,1], got lambda {} frac {}", lambda, frac);
+            return Err(AnnembedError::InvalidParameter(format!("densmap lambda {} must be >= 0 and frac {} in [0,1]", lambda, frac)));
+        }
+        self.dens_lambda = lambda;
+        self.dens_frac = frac;
+        Ok(())
+    }
+
+    /// sets b and kernel_width from the min_dist and spread parameters of Umap, fitting as umap-learn does the curve
+    /// 1/(1 + a * d^(2b)) to 1 for d < min_dist and exp(-(d - min_dist)/spread) above.
+    /// Distances are in units of the embedding (initialized in a box of size 1). Returns the fitted (a, b).
+    pub fn set_min_dist_spread(&mut self, min_dist : f64, spread : f64) -> Result<(f64, f64), AnnembedError> {
+        if !(spread > 0. && spread.is_finite() && (0. ..=spread).contains(&min_dist)) {
+            log::error!("EmbedderParams::set_min_dist_spread, need 0 <= min_dist <= spread, got min_dist {} spread {}", min_dist, spread);
+            return Err(AnnembedError::InvalidParameter(format!("min_dist {} and spread {} must satisfy 0 <= min_dist <= spread", min_dist, spread)));
+        }
+        let (a, b) = fit_ab(min_dist, spread);
+        log::info!("min_dist {:.3e} spread {:.3e} fitted a : {:.3e}, b : {:.3e}", min_dist, spread, a, b);
+        self.b = b;
+        self.kernel_width = a.powf(-1. / (2. * b));
+        Ok((a, b))
+    }
+
+    /// returns the coefficient a of Umap embedded kernel 1/(1 + a * d^(2b)) for a node of mean scale, i.e kernel_width^(-2b)
+    pub fn get_a(&self) -> f64 {
+        self.kernel_width.powf(-2. * self.b)
+    }
+
+    /// returns the exponent b of the embedded kernel
+    pub fn get_b(&self) -> f64 {
+        self.b
+    }
 } // end of impl EmbedderParams
+
+
+
+// least squares fit (Levenberg-Marquardt) of 1/(1 + a * x^(2b)) to the curve defined by min_dist and spread
+// on 300 points regularly spaced in [0, 3 * spread] as in umap-learn find_ab_params. Starts from (1,1) as scipy curve_fit
+fn fit_ab(min_dist : f64, spread : f64) -> (f64, f64) {
+    let nb_points = 300;
+    let xs : Vec<f64> = (0..nb_points).map(|i| 3. * spread * i as f64 / (nb_points - 1) as f64).collect();
+    let ys : Vec<f64> = xs.iter().map(|x| if *x < min_dist { 1. } else { (-(x - min_dist) / spread).exp() }).collect();
+    // residuals and jacobian of model with respect to (a,b)
+    let residuals = |a : f64, b : f64| -> f64 {
+        xs.iter().zip(ys.iter()).map(|(x, y)| { let r = y - 1. / (1. + a * x.powf(2. * b)); r * r }).sum()
+    };
+    let (mut a, mut b) = (1., 1.);
+    let mut lambda = 1.0E-3;
+    let mut cost = residuals(a, b);
+    for _ in 0..200 {
+        // normal equations J^t J and J^t r
+        let (mut jaa, mut jab, mut jbb, mut ga, mut gb) = (0., 0., 0., 0., 0.);
+        for (x, y) in xs.iter().zip(ys.iter()) {
+            if *x <= 0. {
+                continue;
+            }
+            let u = x.powf(2. * b);
+            let f = 1. / (1. + a * u);
+            let da = - u * f * f;
+            let db = - a * u * 2. * x.ln() * f * f;
+            let r = y - f;
+            jaa += da * da;
+            jab += da * db;
+            jbb += db * db;
+            ga += da * r;
+            gb += db * r;
+        }
+        let (maa, mbb) = (jaa * (1. + lambda), jbb * (1. + lambda));
+        let det = maa * mbb - jab * jab;
+        if det.abs() < f64::MIN_POSITIVE {
+            break;
+        }
+        let delta_a = (mbb * ga - jab * gb) / det;
+        let delta_b = (maa * gb - jab * ga) / det;
+        let (new_a, new_b) = (a + delta_a, b + delta_b);
+        let new_cost = if new_a > 0. && new_b > 0. { residuals(new_a, new_b) } else { f64::INFINITY };
+        if new_cost < cost {
+            let converged = (cost - new_cost) <= 1.0E-12 * cost;
+            a = new_a;
+            b = new_b;
+            cost = new_cost;
+            lambda /= 10.;
+            if converged {
+                break;
+            }
+        }
+        else {
+            lambda *= 10.;
+            if lambda > 1.0E10 {
+                break;
+            }
+        }
+    }
+    (a, b)
+} // end of fit_ab
+
+
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    fn log_init_test() {
+        let _ = env_logger::builder().is_test(true).try_init();
+    }
+
+    #[test]
+    fn test_fit_ab() {
+        log_init_test();
+        // umap-learn values for default min_dist = 0.1, spread = 1.
+        let (a, b) = fit_ab(0.1, 1.);
+        log::info!("a : {:.4e}, b : {:.4e}", a, b);
+        assert!((a - 1.577).abs() < 1.0E-2 && (b - 0.895).abs() < 1.0E-2);
+        let mut params = EmbedderParams::default();
+        assert!((params.get_a() - 25.).abs() < 1.0E-9);
+        params.set_min_dist_spread(0.5, 1.).unwrap();
+        assert!((params.get_a() - 0.583).abs() < 1.0E-2 && (params.get_b() - 1.334).abs() < 1.0E-2);
+        assert!(params.set_min_dist_spread(2., 1.).is_err());
+    } // end of test_fit_ab
+
+} // end of mod tests
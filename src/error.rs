@@ -0,0 +1,57 @@
+//! Errors returned by the crate : io, graph construction, spectral computations (svd, laplacian, diffusion maps),
+//! embedding and parameter validation.
+//!
+//! Library users can match on [AnnembedError] instead of catching panics.
+
+/// Errors of the crate.
+#[derive(Debug, thiserror::Error)]
+pub enum AnnembedError {
+    /// randomized range approximation did not return a matrix
+    #[error("range approximation failed")]
+    RangeApproximation,
+    /// matrix could not be passed as a slice to lapack
+    #[error("matrix not contiguous or not in standard order")]
+    NotContiguous,
+    /// lapack svd driver failed
+    #[error("svd failed : {0}")]
+    SvdFailed(String),
+    /// a field of SvdResult we need was not computed
+    #[error("svd did not return {0}")]
+    MissingSvdResult(&'static str),
+    /// singular values are expected in decreasing order
+    #[error("svd spectrum not decreasing")]
+    SpectrumNotDecreasing,
+    /// we need more eigen vectors than computed
+    #[error("not enough eigenvectors, computed {computed}, needed {needed}")]
+    NotEnoughEigenvectors { computed: usize, needed: usize },
+    /// conversion between float types failed
+    #[error("float conversion failed")]
+    FloatConversion,
+    /// neighbourhood graph construction failed
+    #[error("graph construction failed : {0}")]
+    GraphConstruction(String),
+    /// dump or reload of a Hnsw structure failed
+    #[error("hnsw io failed : {0}")]
+    HnswIo(String),
+    /// the Embedder did not produce an embedding
+    #[error("embedding failed : {0}")]
+    Embedding(String),
+    /// io failure
+    #[error("io error : {0}")]
+    Io(#[from] std::io::Error),
+    /// invalid parameter or input data
+    #[error("invalid parameter : {0}")]
+    InvalidParameter(String),
+    /// matrix is not in the representation (full or csr) asked for
+    #[error("matrix is not in {0} representation")]
+    MatrixRepresentation(&'static str),
+    /// NaN or infinite values found, most often coming from input data
+    #[error("non finite values in {0}")]
+    NonFinite(&'static str),
+    /// some rows of input data could not be used, rows are identified by their DataId
+    #[error("{} invalid rows ({reason}), first ones : {:?}", .rows.len(), &.rows[..(.rows.len().min(10))])]
+    InvalidRows { reason: &'static str, rows: Vec<usize> },
+    /// computation stopped by a [CancellationToken](crate::tools::stage::CancellationToken)
+    #[error("computation cancelled")]
+    Cancelled,
+} // end of AnnembedError
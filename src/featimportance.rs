@@ -0,0 +1,506 @@
+//! Interpretation of a region (or a cluster) of an embedding by the input features that drive its neighbourhoods.
+//!
+//! Rows of data and of embedding correspond to the same points (use [get_embedded_reindexed](crate::embedder::Embedder::get_embedded_reindexed)
+//! when DataId are ranks of data rows). For the points of a region each feature gets :
+//! - axis_correlations : correlations of the feature with the local axes of the region, that is the principal axes of the
+//!   embedded coordinates of the region, by decreasing variance. axis_r2, the sum of their squares, is the fraction of the
+//!   variance of the feature in the region explained linearly by embedded positions.
+//! - neighbour_score : $1 - \overline{(x_{i} - x_{j})^{2}} / 2 \sigma^{2}$ where the mean runs on pairs of embedded neighbours
+//!   of the region and $\sigma^{2}$ is the variance of the feature in the region. It is near 0 for a feature unrelated to
+//!   neighbourhoods and near 1 for a feature on which neighbours agree (a local version of the Laplacian score of
+//!   He X., Cai D., Niyogi P. Laplacian score for feature selection. NIPS 2005).
+//! - neighbour_contribution : mean fraction of the squared L2 distance between embedded neighbours due to the feature.
+//! - contrast : difference of means of the feature between the region and the other points, divided by the pooled
+//!   standard deviation (Cohen's d), describing what sets the region apart.
+//!
+//! Neighbours are the knbn nearest neighbours in embedded space among points of the region, found with a Hnsw.
+//!
+//! For unsupervised feature selection on the whole data, [laplacian_scores_kgraph] (or
+//! [DiffusionMaps::get_laplacian_scores](crate::diffmaps::DiffusionMaps::get_laplacian_scores), reusing the laplacian of
+//! the last embedding if kept, see [DiffusionParams::set_keep_laplacian](crate::diffmaps::DiffusionParams::set_keep_laplacian)) ranks features by their Laplacian score on the neighbourhood graph and returns a selection mask.
+//!
+
+use ndarray::{Array1, Array2, ArrayBase, Axis, Data, Ix2};
+use ndarray_linalg::{svddc::JobSvd, SVDDC};
+use num_traits::cast::FromPrimitive;
+use num_traits::Float;
+use rayon::prelude::*;
+
+use hnsw_rs::prelude::*;
+
+use crate::embedder::to_proba_edges;
+use crate::error::AnnembedError;
+use crate::fromhnsw::kgraph::KGraph;
+use crate::graphlaplace::{get_laplacian, GraphLaplacian};
+
+/// default number of embedded neighbours of a point
+const DEFAULT_KNBN: usize = 10;
+
+/// Criterion used to rank features, see the [module](self) documentation.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum FeatureCriterion {
+    AxisR2,
+    NeighbourScore,
+    NeighbourContribution,
+    /// ranking on absolute value of contrast
+    Contrast,
+}
+
+/// Scores of a feature in a region
+#[derive(Clone, Debug)]
+pub struct FeatureScore {
+    /// rank of the feature (column of data)
+    pub feature: usize,
+    /// correlation with each local axis
+    pub axis_correlations: Vec<f64>,
+    pub axis_r2: f64,
+    pub neighbour_score: f64,
+    pub neighbour_contribution: f64,
+    pub contrast: f64,
+}
+
+impl FeatureScore {
+    /// returns the value of criterion, contrast in absolute value
+    pub fn get_value(&self, criterion: FeatureCriterion) -> f64 {
+        match criterion {
+            FeatureCriterion::AxisR2 => self.axis_r2,
+            FeatureCriterion::NeighbourScore => self.neighbour_score,
+            FeatureCriterion::NeighbourContribution => self.neighbour_contribution,
+            FeatureCriterion::Contrast => self.contrast.abs(),
+        }
+    }
+} // end of impl FeatureScore
+
+/// Result of [LocalFeatures::explain_region]
+#[derive(Clone, Debug)]
+pub struct LocalFeatureImportance {
+    nb_points: usize,
+    /// local axes in columns, in embedded coordinates
+    local_axes: Array2<f64>,
+    /// variance of embedded coordinates along each local axis
+    axis_variances: Vec<f64>,
+    /// scores, indexed by feature
+    scores: Vec<FeatureScore>,
+} // end of LocalFeatureImportance
+
+impl LocalFeatureImportance {
+    /// number of points in the region
+    pub fn get_nb_points(&self) -> usize {
+        self.nb_points
+    }
+
+    /// local axes of the region (in columns, by decreasing variance) in the embedded space
+    pub fn get_local_axes(&self) -> &Array2<f64> {
+        &self.local_axes
+    }
+
+    /// variance of embedded coordinates of the region along each local axis
+    pub fn get_axis_variances(&self) -> &[f64] {
+        &self.axis_variances
+    }
+
+    /// scores of all features, indexed by feature
+    pub fn get_scores(&self) -> &[FeatureScore] {
+        &self.scores
+    }
+
+    /// returns scores sorted by decreasing value of criterion. NaN values come last.
+    pub fn get_ranked(&self, criterion: FeatureCriterion) -> Vec<&FeatureScore> {
+        let mut ranked: Vec<&FeatureScore> = self.scores.iter().collect();
+        ranked.sort_unstable_by(|a, b| {
+            let (va, vb) = (a.get_value(criterion), b.get_value(criterion));
+            vb.partial_cmp(&va).unwrap_or_else(|| va.is_nan().cmp(&vb.is_nan()))
+        });
+        ranked
+    }
+
+    /// returns the nb features of highest criterion
+    pub fn get_top_features(&self, criterion: FeatureCriterion, nb: usize) -> Vec<usize> {
+        self.get_ranked(criterion).iter().take(nb).map(|s| s.feature).collect()
+    }
+} // end of impl LocalFeatureImportance
+
+/// Computation of [LocalFeatureImportance] of regions of an embedding.
+#[derive(Copy, Clone, Debug)]
+pub struct LocalFeatures {
+    /// number of embedded neighbours of a point
+    knbn: usize,
+}
+
+impl Default for LocalFeatures {
+    fn default() -> Self {
+        LocalFeatures { knbn: DEFAULT_KNBN }
+    }
+}
+
+impl LocalFeatures {
+    pub fn new(knbn: usize) -> Self {
+        LocalFeatures { knbn }
+    }
+
+    pub fn get_knbn(&self) -> usize {
+        self.knbn
+    }
+
+    /// scores features of data for the points of region (ranks of rows of data and embedding).
+    /// The region must have more than knbn points.
+    pub fn explain_region<S, T, F>(
+        &self,
+        data: &ArrayBase<S, Ix2>,
+        embedding: &Array2<F>,
+        region: &[usize],
+    ) -> Result<LocalFeatureImportance, AnnembedError>
+    where
+        S: Data<Elem = T> + Sync,
+        T: Float + Send + Sync,
+        F: Float,
+    {
+        let nb_data = data.nrows();
+        if embedding.nrows() != nb_data {
+            return Err(AnnembedError::InvalidParameter(format!(
+                "explain_region, data has {} rows, embedding {}",
+                nb_data,
+                embedding.nrows()
+            )));
+        }
+        if region.len() <= self.knbn || region.iter().any(|i| *i >= nb_data) {
+            return Err(AnnembedError::InvalidParameter(format!(
+                "explain_region, region of {} points must have more than knbn = {} points, all in 0..{}",
+                region.len(),
+                self.knbn,
+                nb_data
+            )));
+        }
+        let nb_points = region.len();
+        // local axes and coordinates of region points on them
+        let embedded = Array2::<f64>::from_shape_fn((nb_points, embedding.ncols()), |(i, j)| {
+            embedding[[region[i], j]].to_f64().unwrap()
+        });
+        let centered = &embedded - &embedded.mean_axis(Axis(0)).unwrap();
+        let (local_axes, axis_variances) = get_local_axes(&centered)?;
+        let axis_coordinates = centered.dot(&local_axes);
+        // pairs of embedded neighbours and squared input distances between them
+        let pairs = self.get_neighbour_pairs(&embedded);
+        let in_region = {
+            let mut in_region = vec![false; nb_data];
+            region.iter().for_each(|i| in_region[*i] = true);
+            in_region
+        };
+        let pair_sq_dist: Vec<f64> = pairs
+            .par_iter()
+            .map(|(i, j)| {
+                let (ri, rj) = (data.row(region[*i]), data.row(region[*j]));
+                ri.iter().zip(rj.iter()).map(|(a, b)| (*a - *b).to_f64().unwrap().powi(2)).sum()
+            })
+            .collect();
+        log::debug!("explain_region, nb points : {}, nb neighbour pairs : {}", nb_points, pairs.len());
+        //
+        let scores: Vec<FeatureScore> = (0..data.ncols())
+            .into_par_iter()
+            .map(|f| {
+                let column = data.column(f);
+                let values = Array1::<f64>::from_iter(region.iter().map(|i| column[*i].to_f64().unwrap()));
+                let (mean, var) = mean_var(values.iter().copied());
+                let (mean_out, var_out) = mean_var(
+                    column.iter().zip(in_region.iter()).filter(|(_, r)| !**r).map(|(x, _)| x.to_f64().unwrap()),
+                );
+                let axis_correlations: Vec<f64> = axis_coordinates
+                    .columns()
+                    .into_iter()
+                    .zip(axis_variances.iter())
+                    .map(|(coords, axis_var)| {
+                        if var > 0. && *axis_var > 0. {
+                            let cov = values.iter().zip(coords.iter()).map(|(x, c)| (x - mean) * c).sum::<f64>() / nb_points as f64;
+                            cov / (var * axis_var).sqrt()
+                        } else {
+                            0.
+                        }
+                    })
+                    .collect();
+                let axis_r2 = axis_correlations.iter().map(|c| c * c).sum::<f64>();
+                //
+                let mut msd = 0.;
+                let mut contribution = 0.;
+                for ((i, j), sq_dist) in pairs.iter().zip(pair_sq_dist.iter()) {
+                    let sq_diff = (values[*i] - values[*j]).powi(2);
+                    msd += sq_diff;
+                    if *sq_dist > 0. {
+                        contribution += sq_diff / sq_dist;
+                    }
+                }
+                let nb_pairs = pairs.len().max(1) as f64;
+                let neighbour_score = if var > 0. { 1. - msd / nb_pairs / (2. * var) } else { f64::NAN };
+                let contrast = if nb_points < nb_data {
+                    let pooled = (var + var_out) / 2.;
+                    if pooled > 0. { (mean - mean_out) / pooled.sqrt() } else { 0. }
+                } else {
+                    0.
+                };
+                FeatureScore {
+                    feature: f,
+                    axis_correlations,
+                    axis_r2,
+                    neighbour_score,
+                    neighbour_contribution: contribution / nb_pairs,
+                    contrast,
+                }
+            })
+            .collect();
+        //
+        Ok(LocalFeatureImportance {
+            nb_points,
+            local_axes,
+            axis_variances,
+            scores,
+        })
+    } // end of explain_region
+
+    /// scores features for the points with label cluster, labels being given for each row of data
+    pub fn explain_cluster<S, T, F>(
+        &self,
+        data: &ArrayBase<S, Ix2>,
+        embedding: &Array2<F>,
+        labels: &[usize],
+        cluster: usize,
+    ) -> Result<LocalFeatureImportance, AnnembedError>
+    where
+        S: Data<Elem = T> + Sync,
+        T: Float + Send + Sync,
+        F: Float,
+    {
+        if labels.len() != data.nrows() {
+            return Err(AnnembedError::InvalidParameter(format!(
+                "explain_cluster, {} labels for {} rows",
+                labels.len(),
+                data.nrows()
+            )));
+        }
+        let region: Vec<usize> = (0..labels.len()).filter(|i| labels[*i] == cluster).collect();
+        self.explain_region(data, embedding, &region)
+    } // end of explain_cluster
+
+    // pairs (i, j) of ranks in region such that j is among the knbn embedded neighbours of i
+    fn get_neighbour_pairs(&self, embedded: &Array2<f64>) -> Vec<(usize, usize)> {
+        let nb_points = embedded.nrows();
+        let rows: Vec<Vec<f32>> = embedded.rows().into_iter().map(|r| r.iter().map(|x| *x as f32).collect()).collect();
+        let nb_layer = 16.min((nb_points as f32).ln().trunc() as usize).max(1);
+        let hnsw = Hnsw::<f32, DistL2>::new(24, nb_points, nb_layer, 64, DistL2 {});
+        let data_with_id: Vec<(&Vec<f32>, usize)> = rows.iter().zip(0..nb_points).collect();
+        hnsw.parallel_insert(&data_with_id);
+        let neighbours = hnsw.parallel_search(&rows, self.knbn + 1, 64.max(self.knbn + 1));
+        neighbours
+            .iter()
+            .enumerate()
+            .flat_map(|(i, ngbs)| {
+                ngbs.iter()
+                    .filter(move |n| n.d_id != i)
+                    .take(self.knbn)
+                    .map(move |n| (i, n.d_id))
+            })
+            .collect()
+    } // end of get_neighbour_pairs
+} // end of impl LocalFeatures
+
+// mean and variance (biased) of values
+fn mean_var(values: impl Iterator<Item = f64>) -> (f64, f64) {
+    let (mut n, mut sum, mut sum2) = (0usize, 0., 0.);
+    for x in values {
+        n += 1;
+        sum += x;
+        sum2 += x * x;
+    }
+    if n == 0 {
+        return (0., 0.);
+    }
+    let mean = sum / n as f64;
+    (mean, (sum2 / n as f64 - mean * mean).max(0.))
+} // end of mean_var
+
+// principal axes (columns, decreasing variance) of centered rows and variances along them
+fn get_local_axes(centered: &Array2<f64>) -> Result<(Array2<f64>, Vec<f64>), AnnembedError> {
+    let nb_points = centered.nrows() as f64;
+    let covariance = centered.t().dot(centered) / nb_points;
+    let (u, s, _) = covariance
+        .svddc(JobSvd::Some)
+        .map_err(|e| AnnembedError::SvdFailed(e.to_string()))?;
+    let u = u.ok_or(AnnembedError::MissingSvdResult("left singular vectors"))?;
+    Ok((u, s.to_vec()))
+} // end of get_local_axes
+
+//=====================================================================================
+
+/// Selection of features by Laplacian score, lower scores being better
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum FeatureSelection {
+    /// the given number of features of lowest score
+    Best(usize),
+    /// features with a score less than or equal to the value
+    MaxScore(f64),
+}
+
+/// Laplacian scores of features and selection mask, see [laplacian_scores_kgraph]
+#[derive(Clone, Debug)]
+pub struct LaplacianScores {
+    /// score of each feature, NaN for features constant on the graph
+    scores: Vec<f64>,
+    /// true for selected features
+    mask: Vec<bool>,
+} // end of LaplacianScores
+
+impl LaplacianScores {
+    fn new(scores: Vec<f64>, selection: FeatureSelection) -> Self {
+        let mut laplacian_scores = LaplacianScores {
+            mask: vec![false; scores.len()],
+            scores,
+        };
+        let selected: Vec<usize> = match selection {
+            FeatureSelection::Best(nb) => laplacian_scores.get_ranked().into_iter().filter(|f| !laplacian_scores.scores[*f].is_nan()).take(nb).collect(),
+            FeatureSelection::MaxScore(max) => (0..laplacian_scores.scores.len()).filter(|f| laplacian_scores.scores[*f] <= max).collect(),
+        };
+        selected.iter().for_each(|f| laplacian_scores.mask[*f] = true);
+        log::info!("laplacian scores, selected {} features out of {}", selected.len(), laplacian_scores.scores.len());
+        laplacian_scores
+    }
+
+    /// score of each feature
+    pub fn get_scores(&self) -> &[f64] {
+        &self.scores
+    }
+
+    /// selection mask, indexed by feature
+    pub fn get_mask(&self) -> &[bool] {
+        &self.mask
+    }
+
+    /// all features by increasing score, NaN scores last
+    pub fn get_ranked(&self) -> Vec<usize> {
+        let mut ranked: Vec<usize> = (0..self.scores.len()).collect();
+        ranked.sort_by(|a, b| {
+            let (sa, sb) = (self.scores[*a], self.scores[*b]);
+            sa.partial_cmp(&sb).unwrap_or_else(|| sa.is_nan().cmp(&sb.is_nan()))
+        });
+        ranked
+    }
+
+    /// selected features by increasing score
+    pub fn get_selected(&self) -> Vec<usize> {
+        self.get_ranked().into_iter().filter(|f| self.mask[*f]).collect()
+    }
+} // end of impl LaplacianScores
+
+/// Laplacian scores of columns of data. Row i of laplacian is the node with DataId data_ids\[i\] and rows of data are
+/// indexed by DataId.
+pub(crate) fn get_laplacian_scores<S, T>(
+    laplacian: &GraphLaplacian,
+    data_ids: &[usize],
+    data: &ArrayBase<S, Ix2>,
+    selection: FeatureSelection,
+) -> Result<LaplacianScores, AnnembedError>
+where
+    S: Data<Elem = T> + Sync,
+    T: Float + Send + Sync,
+{
+    if let Some(data_id) = data_ids.iter().find(|d| **d >= data.nrows()) {
+        return Err(AnnembedError::InvalidParameter(format!(
+            "laplacian scores, DataId {} out of data rows (nb rows {})",
+            data_id,
+            data.nrows()
+        )));
+    }
+    let scores: Vec<f64> = (0..data.ncols())
+        .into_par_iter()
+        .map(|f| {
+            let column = data.column(f);
+            let feature: Vec<f64> = data_ids.iter().map(|d| column[*d].to_f64().unwrap()).collect();
+            laplacian.get_laplacian_score(&feature)
+        })
+        .collect::<Result<Vec<f64>, AnnembedError>>()?;
+    Ok(LaplacianScores::new(scores, selection))
+} // end of get_laplacian_scores
+
+/// Ranks the features (columns of data, rows being indexed by DataId) by their Laplacian score on the neighbourhood graph
+/// (with edge weights of diffusion maps) and selects features according to selection.  
+/// The score of a feature f is $\tilde{f}^{t} L \tilde{f} / \tilde{f}^{t} D \tilde{f}$ with $L = D - S$ the laplacian of the symetrized graph
+/// and $\tilde{f}$ the feature centered by the degree weighted mean. It is in \[0, 2\], low for features varying smoothly on the graph.
+pub fn laplacian_scores_kgraph<F, S, T>(
+    kgraph: &KGraph<F>,
+    data: &ArrayBase<S, Ix2>,
+    selection: FeatureSelection,
+) -> Result<LaplacianScores, AnnembedError>
+where
+    F: Float + FromPrimitive + std::marker::Sync + Send + std::fmt::UpperExp + std::iter::Sum,
+    S: Data<Elem = T> + Sync,
+    T: Float + Send + Sync,
+{
+    let nodeparams = to_proba_edges::<F>(kgraph, 1., 2.)?;
+    let laplacian = get_laplacian(&nodeparams);
+    let data_ids: Vec<usize> = (0..kgraph.get_nb_nodes())
+        .map(|i| *kgraph.get_data_id_from_idx(i).unwrap())
+        .collect();
+    get_laplacian_scores(&laplacian, &data_ids, data, selection)
+} // end of laplacian_scores_kgraph
+
+//=====================================================================================
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    use rand::distributions::Uniform;
+    use rand::{Rng, SeedableRng};
+    use rand_xoshiro::Xoshiro256PlusPlus;
+
+    fn log_init_test() {
+        let _ = env_logger::builder().is_test(true).try_init();
+    }
+
+    #[test]
+    fn test_explain_region() {
+        log_init_test();
+        // a 2d embedding, feature 0 follows the first embedded axis, feature 1 is noise,
+        // feature 2 is higher in the region (x > 0) than elsewhere but constant in each part
+        let nb = 2000;
+        let mut rng = Xoshiro256PlusPlus::seed_from_u64(4493);
+        let unif = Uniform::<f64>::new(-1., 1.);
+        let embedding = Array2::<f64>::from_shape_fn((nb, 2), |_| rng.sample(unif));
+        let data = Array2::<f32>::from_shape_fn((nb, 3), |(i, f)| match f {
+            0 => (3. * embedding[[i, 0]]) as f32,
+            1 => rng.sample(unif) as f32,
+            _ => {
+                if embedding[[i, 0]] > 0. {
+                    1. + 0.1 * rng.sample(unif) as f32
+                } else {
+                    0.1 * rng.sample(unif) as f32
+                }
+            }
+        });
+        let region: Vec<usize> = (0..nb).filter(|i| embedding[[*i, 0]] > 0.).collect();
+        let importance = LocalFeatures::default().explain_region(&data, &embedding, &region).unwrap();
+        let scores = importance.get_scores();
+        for score in scores {
+            log::info!("{:?}", score);
+        }
+        assert_eq!(importance.get_nb_points(), region.len());
+        assert!(scores[0].axis_r2 > 0.95);
+        assert!(scores[1].axis_r2 < 0.05);
+        assert!(scores[0].neighbour_score > 0.9);
+        assert!(scores[1].neighbour_score.abs() < 0.2);
+        assert!(scores[0].neighbour_contribution > scores[2].neighbour_contribution);
+        assert_eq!(importance.get_top_features(FeatureCriterion::NeighbourScore, 1), vec![0]);
+        assert_eq!(importance.get_top_features(FeatureCriterion::Contrast, 1), vec![2]);
+        assert!(scores[2].contrast > 5.);
+    } // end of test_explain_region
+
+    #[test]
+    fn test_laplacian_scores_selection() {
+        log_init_test();
+        let scores = vec![0.5, f64::NAN, 0.1, 1.2, 0.3];
+        let best = LaplacianScores::new(scores.clone(), FeatureSelection::Best(2));
+        assert_eq!(best.get_selected(), vec![2, 4]);
+        assert_eq!(best.get_mask(), &[false, false, true, false, true]);
+        assert_eq!(best.get_ranked(), vec![2, 4, 0, 3, 1]);
+        let thresholded = LaplacianScores::new(scores, FeatureSelection::MaxScore(0.5));
+        assert_eq!(thresholded.get_selected(), vec![2, 4, 0]);
+    } // end of test_laplacian_scores_selection
+} // end of mod tests
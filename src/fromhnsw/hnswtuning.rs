@@ -0,0 +1,233 @@
+//! Suggestion of Hnsw parameters from graph diagnostics on a sample of data.
+//!
+//! For each candidate (max_nb_connection, ef_construction) a Hnsw is built on the sample and the graph of
+//! its knbn neighbours (see [kgraph_from_hnsw_all]) is evaluated by:
+//! - recall of neighbourhoods against exact neighbours of a subset of sample points,
+//! - number of connected components,
+//! - hubness (standardized third moment of in degrees, see [Hubness]).
+//!
+//! Build time and memory are extrapolated to the full data size. The recommended candidate is the cheapest one
+//! reaching the target recall with a connected graph, or the one with best recall if none does.
+
+use std::time::SystemTime;
+
+use crate::error::AnnembedError;
+use rayon::prelude::*;
+
+use hnsw_rs::prelude::*;
+
+use super::hubness::Hubness;
+use super::kgraph::{kgraph_from_hnsw_all, KGraph};
+
+/// default maximal number of sample points whose neighbourhoods are compared to exact ones
+const NB_RECALL_QUERIES: usize = 200;
+
+/// results of the probe of one (max_nb_connection, ef_construction) setting
+#[derive(Clone, Debug)]
+pub struct HnswProbe {
+    pub max_nb_connection: usize,
+    pub ef_construction: usize,
+    /// mean fraction of exact knbn neighbours found in graph neighbourhoods
+    pub recall: f64,
+    /// number of connected components of the graph
+    pub nb_components: usize,
+    /// standardized third moment of in degrees, large values signal hubs
+    pub hubness: f64,
+    /// build time on the sample in milliseconds
+    pub sample_time_ms: u128,
+    /// build time extrapolated to full data size, in milliseconds
+    pub expected_time_ms: f64,
+    /// memory extrapolated to full data size, in bytes
+    pub expected_memory: usize,
+} // end of HnswProbe
+
+/// result of [HnswTuning::probe], probes in the order of candidates and rank of recommended probe.
+#[derive(Clone, Debug)]
+pub struct HnswSuggestion {
+    pub probes: Vec<HnswProbe>,
+    pub recommended: usize,
+} // end of HnswSuggestion
+
+impl HnswSuggestion {
+    /// returns the recommended probe
+    pub fn get_recommended(&self) -> &HnswProbe {
+        &self.probes[self.recommended]
+    }
+}
+
+/// Probes Hnsw settings on a data sample and recommends one.
+pub struct HnswTuning {
+    /// (max_nb_connection, ef_construction) settings tried
+    candidates: Vec<(usize, usize)>,
+    /// number of neighbours of graph
+    knbn: usize,
+    /// recall asked for
+    target_recall: f64,
+    /// number of points whose neighbourhoods are checked
+    nb_queries: usize,
+} // end of HnswTuning
+
+impl HnswTuning {
+    /// knbn is the number of neighbours the graph will be extracted with.
+    pub fn new(knbn: usize) -> Self {
+        let candidates = vec![(8, 64), (16, 100), (24, 200), (32, 300), (48, 400), (64, 600)];
+        HnswTuning {
+            candidates,
+            knbn,
+            target_recall: 0.9,
+            nb_queries: NB_RECALL_QUERIES,
+        }
+    }
+
+    /// sets the (max_nb_connection, ef_construction) couples to probe
+    pub fn set_candidates(&mut self, candidates: Vec<(usize, usize)>) {
+        self.candidates = candidates;
+    }
+
+    /// sets the recall a recommended setting must reach, default to 0.9
+    pub fn set_target_recall(&mut self, recall: f64) {
+        self.target_recall = recall;
+    }
+
+    /// sets the number of sample points whose neighbourhoods are compared to exact ones
+    pub fn set_nb_queries(&mut self, nb_queries: usize) {
+        self.nb_queries = nb_queries;
+    }
+
+    /// probes candidates on sample, extrapolating time and memory to nb_total points.
+    /// The distance is passed by value to each Hnsw so D must be Clone.
+    pub fn probe<T, D>(&self, sample: &[Vec<T>], distance: D, nb_total: usize) -> Result<HnswSuggestion, AnnembedError>
+    where
+        T: Clone + Send + Sync,
+        D: Distance<T> + Clone + Send + Sync,
+    {
+        let nb_sample = sample.len();
+        if nb_sample <= self.knbn + 1 || self.candidates.is_empty() {
+            log::error!("HnswTuning::probe sample size {} too small or no candidates", nb_sample);
+            return Err(AnnembedError::InvalidParameter(format!("sample size {} too small or no candidates", nb_sample)));
+        }
+        if self.nb_queries == 0 {
+            log::error!("HnswTuning::probe number of recall queries must be positive");
+            return Err(AnnembedError::InvalidParameter(String::from("number of recall queries must be positive")));
+        }
+        let exact = self.get_exact_neighbours(sample, &distance);
+        let dim = sample[0].len();
+        let nb_total = nb_total.max(nb_sample);
+        let scale = (nb_total as f64 * (nb_total as f64).ln()) / (nb_sample as f64 * (nb_sample as f64).ln());
+        let mut probes = Vec::<HnswProbe>::with_capacity(self.candidates.len());
+        for (max_nb_connection, ef_construction) in &self.candidates {
+            let nb_layer = 16.min((nb_sample as f32).ln().trunc() as usize).max(1);
+            let start = SystemTime::now();
+            let mut hnsw = Hnsw::<T, D>::new(*max_nb_connection, nb_sample, nb_layer, *ef_construction, distance.clone());
+            hnsw.set_keeping_pruned(true);
+            let data_with_id: Vec<(&Vec<T>, usize)> = sample.iter().zip(0..nb_sample).collect();
+            hnsw.parallel_insert(&data_with_id);
+            let sample_time_ms = start.elapsed().unwrap().as_millis();
+            let kgraph: KGraph<f32> = kgraph_from_hnsw_all(&hnsw, self.knbn)?;
+            let recall = get_recall(&kgraph, &exact);
+            let nb_components = kgraph.get_connected_components().iter().max().map_or(0, |c| c + 1);
+            let hubness = Hubness::new(&kgraph).get_standard3m();
+            // vector, plus neighbours at layer 0 (2 * max_nb_connection) and upper layers stored as (id, distance, pointer)
+            let point_size = dim * std::mem::size_of::<T>() + 3 * max_nb_connection * 24;
+            let probe = HnswProbe {
+                max_nb_connection: *max_nb_connection,
+                ef_construction: *ef_construction,
+                recall,
+                nb_components,
+                hubness,
+                sample_time_ms,
+                expected_time_ms: sample_time_ms as f64 * scale,
+                expected_memory: point_size * nb_total,
+            };
+            log::info!("HnswTuning probe : {:?}", probe);
+            probes.push(probe);
+        }
+        // cheapest in memory reaching recall with connected graph, else best recall
+        let recommended = (0..probes.len())
+            .filter(|i| probes[*i].recall >= self.target_recall && probes[*i].nb_components == 1)
+            .min_by_key(|i| probes[*i].expected_memory)
+            .unwrap_or_else(|| {
+                log::warn!("HnswTuning no candidate reaches recall {:.3e} with a connected graph", self.target_recall);
+                (0..probes.len())
+                    .max_by(|a, b| probes[*a].recall.total_cmp(&probes[*b].recall))
+                    .unwrap()
+            });
+        log::info!("HnswTuning recommends : {:?}", probes[recommended]);
+        Ok(HnswSuggestion { probes, recommended })
+    } // end of probe
+
+    // exact knbn neighbours (by sample rank) of nb_queries points spread over sample, nb_queries must be positive.
+    // NaN distances are sorted last.
+    fn get_exact_neighbours<T, D>(&self, sample: &[Vec<T>], distance: &D) -> Vec<(usize, Vec<usize>)>
+    where
+        T: Clone + Send + Sync,
+        D: Distance<T> + Send + Sync,
+    {
+        let nb_queries = self.nb_queries.min(sample.len());
+        let step = sample.len() / nb_queries;
+        (0..nb_queries)
+            .into_par_iter()
+            .map(|q| {
+                let i = q * step;
+                let mut dists: Vec<(usize, f32)> = (0..sample.len())
+                    .filter(|j| *j != i)
+                    .map(|j| (j, distance.eval(&sample[i], &sample[j])))
+                    .collect();
+                dists.sort_unstable_by(|a, b| a.1.total_cmp(&b.1));
+                (i, dists.iter().take(self.knbn).map(|d| d.0).collect())
+            })
+            .collect()
+    } // end of get_exact_neighbours
+} // end of impl HnswTuning
+
+// mean fraction of exact neighbours present in graph neighbourhoods, DataId are sample ranks
+fn get_recall(kgraph: &KGraph<f32>, exact: &[(usize, Vec<usize>)]) -> f64 {
+    let mut recall = 0.;
+    for (data_id, neighbours) in exact {
+        let found: Vec<DataId> = match kgraph.get_idx_from_dataid(data_id) {
+            Some(idx) => kgraph
+                .get_out_edges_by_idx(idx)
+                .iter()
+                .map(|e| *kgraph.get_data_id_from_idx(e.get_node()).unwrap())
+                .collect(),
+            None => Vec::new(),
+        };
+        let nb_found = neighbours.iter().filter(|n| found.contains(n)).count();
+        recall += nb_found as f64 / neighbours.len().max(1) as f64;
+    }
+    recall / exact.len().max(1) as f64
+} // end of get_recall
+
+//=======================================================================
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use rand::distributions::{Distribution, Uniform};
+    use rand::thread_rng;
+
+    fn log_init_test() {
+        let _ = env_logger::builder().is_test(true).try_init();
+    }
+
+    #[test]
+    fn test_hnsw_tuning() {
+        log_init_test();
+        let unif = Uniform::<f32>::new(0., 1.);
+        let mut rng = thread_rng();
+        let sample: Vec<Vec<f32>> = (0..2000).map(|_| (0..10).map(|_| unif.sample(&mut rng)).collect()).collect();
+        let mut tuning = HnswTuning::new(10);
+        tuning.set_candidates(vec![(4, 16), (24, 200)]);
+        tuning.set_nb_queries(50);
+        let suggestion = tuning.probe(&sample, DistL2 {}, 100000).unwrap();
+        assert_eq!(suggestion.probes.len(), 2);
+        // more connections and larger ef give better recall and more memory
+        assert!(suggestion.probes[1].recall >= suggestion.probes[0].recall);
+        assert!(suggestion.probes[1].expected_memory > suggestion.probes[0].expected_memory);
+        assert!(suggestion.get_recommended().recall > 0.);
+        // no recall query is an error
+        tuning.set_nb_queries(0);
+        assert!(tuning.probe(&sample, DistL2 {}, 100000).is_err());
+    } // end of test_hnsw_tuning
+} // end of mod tests
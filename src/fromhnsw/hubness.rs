@@ -8,19 +8,71 @@
 //! *Radovanovic M., Nanopoulos A., Ivanovic I.. Journal Machine Learning 2010*
 //! Cf [Hubs](https://www.jmlr.org/papers/volume11/radovanovic10a/radovanovic10a.pdf)
 //!
+//! Hubness can be reduced before embedding by rescaling distances of the graph, see [Hubness::reduce] and
+//! **Local and Global Scaling Reduce Hubs in Space**  
+//! *Schnitzer D., Flexer A., Schedl M., Widmer G. Journal Machine Learning 2012*
+//!
 
-use rayon::iter::{IntoParallelIterator, ParallelIterator};
+use rayon::iter::{IntoParallelIterator, IntoParallelRefIterator, ParallelIterator};
 use std::sync::atomic::{AtomicU32, Ordering};
 
 use num_traits::cast::FromPrimitive;
 use num_traits::Float;
 
+#[cfg(feature = "hdrhistogram")]
 use hdrhistogram::Histogram;
 use indxvec::{Indices, Vecops};
 
 use hnsw_rs::hnsw::DataId;
+use serde::{Deserialize, Serialize};
 
 use super::kgraph::*;
+#[cfg(feature = "hdrhistogram")]
+use crate::error::AnnembedError;
+use crate::tools::nodeparam::OutEdge;
+
+/// Hubness reduction by rescaling of edge distances of a KGraph, see [Hubness::reduce].  
+/// Statistics of a node are computed on distances to its neighbours in the graph.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum HubnessReduction {
+    /// local scaling by NICDM (non iterative contextual dissimilarity measure) : $ d(x,y) / \sqrt{\mu_{x} \mu_{y}} $
+    /// with $\mu_{x}$ the mean distance of x to its neighbours.
+    LocalScaling,
+    /// mutual proximity : $ 1 - P(X > d(x,y)) P(Y > d(x,y)) $ with X (resp. Y) gaussian with mean and standard deviation
+    /// of distances of x (resp. y) to its neighbours.
+    MutualProximity,
+}
+
+// complementary error function, Numerical Recipes erfcc, fractional error less than 1.2E-7
+fn erfc(x: f64) -> f64 {
+    let z = x.abs();
+    let t = 1. / (1. + 0.5 * z);
+    let poly = -1.26551223
+        + t * (1.00002368
+            + t * (0.37409196
+                + t * (0.09678418
+                    + t * (-0.18628806
+                        + t * (0.27886807 + t * (-1.13520398 + t * (1.48851587 + t * (-0.82215223 + t * 0.17087277))))))));
+    let res = t * (-z * z + poly).exp();
+    if x >= 0. {
+        res
+    } else {
+        2. - res
+    }
+}
+
+// P(X > d) for X gaussian (mean, sigma), a step function if sigma is null
+fn gaussian_survival(d: f64, mean: f64, sigma: f64) -> f64 {
+    if sigma > 0. {
+        0.5 * erfc((d - mean) / (sigma * std::f64::consts::SQRT_2))
+    } else if d < mean {
+        1.
+    } else if d > mean {
+        0.
+    } else {
+        0.5
+    }
+}
 
 pub struct Hubness<'a, F> {
     /// The graph we work for
@@ -42,9 +94,7 @@ where
         }
         //
         let scan_node = |node: usize, counts_atom: &Vec<AtomicU32>| {
-            let neighbours = kgraph.get_out_edges_by_idx(node);
-            for edge in neighbours {
-                let n = edge.node;
+            for n in kgraph.neighbour_nodes(node) {
                 // we increment hub count for n as it is cited in this edge
                 // note fecth_add possible only on arch implementing atomic ops on u32
                 counts_atom[n].fetch_add(1, Ordering::SeqCst);
@@ -95,10 +145,28 @@ where
         return s3m;
     } // end of get_standard3m
 
+    /// returns hubness counts at given quantiles (exact, does not need feature hdrhistogram)
+    pub fn get_hubness_quantiles(&self, quantiles: &[f64]) -> Vec<u32> {
+        if self.counts.is_empty() {
+            return vec![0; quantiles.len()];
+        }
+        let mut sorted = self.counts.clone();
+        sorted.sort_unstable();
+        let nb_counts = sorted.len();
+        quantiles
+            .iter()
+            .map(|q| {
+                let rank = ((q.clamp(0., 1.) * nb_counts as f64).ceil() as usize).clamp(1, nb_counts);
+                sorted[rank - 1]
+            })
+            .collect()
+    } // end of get_hubness_quantiles
+
     /// get an histogram of hubness counts and prints histogram summary
     /// quantiles for which thresholds are given are :  
     /// 0.1, 0.25, 0.5, 0.75, 0.9 , 0.99, 0.999, 0.9999
-    pub fn get_hubness_histogram(&self) -> Result<Histogram<u32>, anyhow::Error> {
+    #[cfg(feature = "hdrhistogram")]
+    pub fn get_hubness_histogram(&self) -> Result<Histogram<u32>, AnnembedError> {
         // record histogram length from 1 to readmaxsize with slot of size readmaxsize/10**prec
         // lowest value arg in init must be >= 1
         let max_value = 2 * (self.counts.len() as f64).sqrt() as u64;
@@ -113,7 +181,7 @@ where
                 "hubness::get_hubness_histogram, could not create histogram , error : {:?}",
                 histo.as_ref().err()
             );
-            return Err(anyhow::anyhow!("histogram construction failed"));
+            return Err(AnnembedError::InvalidParameter(format!("histogram construction failed : {:?}", histo.err())));
         }
         let mut histo = histo.unwrap();
         let mut nb_out_histo = 0u32;
@@ -125,7 +193,7 @@ where
         }
         // display result
         if nb_out_histo > 0 {
-            println!(
+            log::warn!(
                 "number of too large values : {}, maximum value : {}",
                 nb_out_histo, max_value
             );
@@ -136,11 +204,8 @@ where
             .map(|f| histo.value_at_quantile(*f))
             .collect::<Vec<u64>>();
         //
-        println!("\n hubness quantiles : ");
-        println!("======================");
-        println!("quantiles : {:?}", quantiles);
-        println!("thresholds : {:?}", thresholds);
-        println!("\n");
+        log::info!("hubness quantiles : {:?}", quantiles);
+        log::info!("hubness thresholds : {:?}", thresholds);
         //
         Ok(histo)
     } // end of get_hubness_histogram
@@ -184,9 +249,157 @@ where
         hubs_dataid
     } // end of get_largest_hubs_by_dataid
 
+    /// returns the hubness count of each node with its DataId, in node rank order
+    pub fn get_scores(&self) -> Vec<(DataId, u32)> {
+        self.counts
+            .iter()
+            .enumerate()
+            .map(|(i, c)| (*self.kgraph.get_data_id_from_idx(i).unwrap(), *c))
+            .collect()
+    } // end of get_scores
+
+    // returns the k nodes with largest (or smallest) counts, ties broken by node rank
+    fn sorted_scores(&self, k: usize, descending: bool) -> Vec<(DataId, u32)> {
+        let mut ranks: Vec<usize> = (0..self.counts.len()).collect();
+        if descending {
+            ranks.sort_by(|a, b| self.counts[*b].cmp(&self.counts[*a]));
+        } else {
+            ranks.sort_by_key(|i| self.counts[*i]);
+        }
+        ranks
+            .iter()
+            .take(k)
+            .map(|i| (*self.kgraph.get_data_id_from_idx(*i).unwrap(), self.counts[*i]))
+            .collect()
+    } // end of sorted_scores
+
+    /// returns the k nodes occurring most often as neighbours (hubs), by decreasing count
+    pub fn top_hubs(&self, k: usize) -> Vec<(DataId, u32)> {
+        self.sorted_scores(k, true)
+    }
+
+    /// returns the k nodes occurring least often as neighbours (anti-hubs, often outliers), by increasing count
+    pub fn anti_hubs(&self, k: usize) -> Vec<(DataId, u32)> {
+        self.sorted_scores(k, false)
+    }
+
     /// returns the hubness of a given DataId
     pub fn get_dataid_hubness(&self, data_id: &DataId) -> usize {
         let index = self.kgraph.get_idx_from_dataid(data_id).unwrap();
         self.counts[index] as usize
     } // end of get_dataid_hubness
+
+    /// returns a graph with the same edges as the graph analyzed, with distances rescaled to reduce hubness.
+    /// Out edges are sorted again by increasing rescaled distance, the graph can be embedded as the original one.
+    pub fn reduce(&self, reduction: HubnessReduction) -> KGraph<F> {
+        let kgraph = self.kgraph;
+        // mean and standard deviation of distances to neighbours
+        let stats: Vec<(f64, f64)> = kgraph
+            .get_neighbours()
+            .par_iter()
+            .map(|edges| {
+                if edges.is_empty() {
+                    return (0., 0.);
+                }
+                let nb = edges.len() as f64;
+                let mean = edges.iter().map(|e| e.weight.to_f64().unwrap()).sum::<f64>() / nb;
+                let var = edges.iter().map(|e| (e.weight.to_f64().unwrap() - mean).powi(2)).sum::<f64>() / nb;
+                (mean, var.sqrt())
+            })
+            .collect();
+        let rescale = |i: usize, j: usize, d: f64| -> f64 {
+            match reduction {
+                HubnessReduction::LocalScaling => {
+                    let mu = (stats[i].0 * stats[j].0).sqrt();
+                    if mu > 0. {
+                        d / mu
+                    } else {
+                        0.
+                    }
+                }
+                HubnessReduction::MutualProximity => {
+                    1. - gaussian_survival(d, stats[i].0, stats[i].1) * gaussian_survival(d, stats[j].0, stats[j].1)
+                }
+            }
+        };
+        let neighbours: Vec<Vec<OutEdge<F>>> = (0..kgraph.get_nb_nodes())
+            .into_par_iter()
+            .map(|i| {
+                let mut edges: Vec<OutEdge<F>> = kgraph
+                    .out_edges(i)
+                    .iter()
+                    .map(|e| {
+                        let d = rescale(i, e.get_node(), e.weight.to_f64().unwrap());
+                        OutEdge::<F>::new(e.get_node(), F::from_f64(d).unwrap())
+                    })
+                    .collect();
+                edges.sort_unstable_by(|a, b| a.partial_cmp(b).unwrap_or(std::cmp::Ordering::Less));
+                edges
+            })
+            .collect();
+        log::info!("Hubness::reduce done with {:?}", reduction);
+        KGraph {
+            max_nbng: kgraph.get_max_nbng(),
+            nbnodes: kgraph.get_nb_nodes(),
+            neighbours,
+            node_set: kgraph.node_set.clone(),
+        }
+    } // end of reduce
 } // end of impl block for Hubness
+
+//=======================================================================
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use ndarray::Array2;
+
+    fn log_init_test() {
+        let _ = env_logger::builder().is_test(true).try_init();
+    }
+
+    #[test]
+    fn test_hubness_reduce() {
+        log_init_test();
+        assert!((erfc(0.) - 1.).abs() < 1.0e-6);
+        assert!((erfc(1.) - 0.157_299_2).abs() < 1.0e-6);
+        assert!((erfc(-1.) - 1.842_700_8).abs() < 1.0e-6);
+        // points 0, 1, 3, 7 on a line, 2 neighbours each
+        let indices = Array2::from_shape_vec((4, 2), vec![1, 2, 0, 2, 1, 0, 2, 1]).unwrap();
+        let dists = Array2::from_shape_vec((4, 2), vec![1f32, 3., 1., 2., 2., 3., 4., 6.]).unwrap();
+        let kgraph = KGraph::from_knn_arrays(&indices, &dists).unwrap();
+        let hubness = Hubness::new(&kgraph);
+        // mean distances to neighbours are 2, 1.5, 2.5, 5
+        let scaled = hubness.reduce(HubnessReduction::LocalScaling);
+        assert_eq!(scaled.get_nb_nodes(), 4);
+        let edges = scaled.out_edges(0);
+        assert_eq!(edges[0].get_node(), 1);
+        assert!((edges[0].weight - 1. / 3f32.sqrt()).abs() < 1.0e-5);
+        assert!((edges[1].weight - 3. / 5f32.sqrt()).abs() < 1.0e-5);
+        let edges = scaled.out_edges(3);
+        assert!((edges[0].weight - 4. / 12.5f32.sqrt()).abs() < 1.0e-5);
+        //
+        let mp = hubness.reduce(HubnessReduction::MutualProximity);
+        for i in 0..4 {
+            let edges = mp.out_edges(i);
+            assert_eq!(edges.len(), 2);
+            assert!(edges.iter().all(|e| (0. ..=1.).contains(&e.weight)));
+            assert!(edges[0].weight <= edges[1].weight);
+        }
+    } // end of test_hubness_reduce
+
+    #[test]
+    fn test_hubness_scores() {
+        log_init_test();
+        // node 3 is cited by all, node 0 by nobody
+        let indices = Array2::from_shape_vec((4, 2), vec![3, 1, 3, 2, 3, 1, 1, 2]).unwrap();
+        let dists = Array2::from_shape_vec((4, 2), vec![1f32, 2., 1., 2., 1., 2., 1., 2.]).unwrap();
+        let kgraph = KGraph::from_knn_arrays(&indices, &dists).unwrap();
+        let hubness = Hubness::new(&kgraph);
+        assert_eq!(hubness.get_scores(), vec![(0, 0), (1, 3), (2, 2), (3, 3)]);
+        assert_eq!(hubness.top_hubs(2), vec![(1, 3), (3, 3)]);
+        assert_eq!(hubness.anti_hubs(1), vec![(0, 0)]);
+        assert_eq!(hubness.anti_hubs(10).len(), 4);
+    } // end of test_hubness_scores
+} // end of mod tests
@@ -0,0 +1,233 @@
+//! Intrinsic dimension estimation from distances to neighbours stored in a [KGraph].
+//!
+//! Two estimators are provided:
+//! - TWO-NN, which only uses the ratio $ \mu = r_{2}/r_{1} $ of distances to the 2 first neighbours of each point.
+//!   $\mu$ follows a Pareto law of exponent d, the global estimate is the maximum likelihood $ d = N / \sum_{i} \ln(\mu_{i}) $.
+//!   *Facco E., d'Errico M., Rodriguez A., Laio A. Estimating the intrinsic dimension of datasets by a minimal
+//!   neighborhood information. Scientific Reports 2017*
+//! - the maximum likelihood estimator of Levina-Bickel over neighbours k1..=k2, averaged on points as suggested by
+//!   MacKay-Ghahramani (average of inverses of local estimates).
+//!   *Levina E. and Bickel P.J. Maximum likelihood estimation of intrinsic dimension. NIPS 2004*
+//!
+//! Local dimensions are indexed by node rank in the graph (see [KGraph::get_data_id_from_idx]).
+//! Points with a null distance to their first neighbour (duplicated data) are not used and get a NaN local dimension.
+//! See also [Hubness](super::hubness::Hubness), which is correlated to intrinsic dimension.
+
+use num_traits::cast::FromPrimitive;
+use num_traits::Float;
+use rayon::prelude::*;
+
+use super::kgraph::KGraph;
+use crate::error::AnnembedError;
+use crate::tools::nodeparam::OutEdge;
+
+/// Global intrinsic dimension and optional local dimensions of each node.
+#[derive(Clone, Debug)]
+pub struct IntrinsicDim {
+    /// global estimate
+    global: f64,
+    /// local estimates, indexed by node rank in the graph. NaN for points without usable distances
+    local: Option<Vec<f64>>,
+    /// number of points used in global estimation
+    nb_used: usize,
+} // end of IntrinsicDim
+
+impl IntrinsicDim {
+    /// global intrinsic dimension
+    pub fn get_global(&self) -> f64 {
+        self.global
+    }
+
+    /// local intrinsic dimension of each node if asked for
+    pub fn get_local(&self) -> Option<&Vec<f64>> {
+        self.local.as_ref()
+    }
+
+    /// number of points with usable distances
+    pub fn get_nb_used(&self) -> usize {
+        self.nb_used
+    }
+} // end of impl IntrinsicDim
+
+// ln(r2/r1) of a neighbourhood, None if not enough neighbours or null first distance
+fn log_ratio_two_nn<F: Float>(edges: &[OutEdge<F>]) -> Option<f64> {
+    if edges.len() < 2 {
+        return None;
+    }
+    let r1 = edges[0].weight.to_f64()?;
+    let r2 = edges[1].weight.to_f64()?;
+    if r1 > 0. && r2.is_finite() {
+        Some((r2 / r1).ln())
+    } else {
+        None
+    }
+}
+
+/// TWO-NN estimation of intrinsic dimension. Graph neighbours must be sorted by increasing distance.
+/// If local is true, the dimension of a node is the TWO-NN estimate pooled over the node and its neighbours.
+pub fn two_nn<F>(kgraph: &KGraph<F>, local: bool) -> Result<IntrinsicDim, AnnembedError>
+where
+    F: Float + FromPrimitive + std::fmt::UpperExp + Send + Sync + std::iter::Sum,
+{
+    let neighbours = kgraph.get_neighbours();
+    let log_mu: Vec<Option<f64>> = neighbours.par_iter().map(|edges| log_ratio_two_nn(edges)).collect();
+    let (nb_used, sum) = log_mu.iter().flatten().fold((0usize, 0f64), |acc, l| (acc.0 + 1, acc.1 + l));
+    if nb_used == 0 || sum <= 0. {
+        log::error!("two_nn, no point with 2 neighbours at distinct positive distances");
+        return Err(AnnembedError::InvalidParameter(String::from(
+            "two_nn needs 2 neighbours at distinct positive distances",
+        )));
+    }
+    let global = nb_used as f64 / sum;
+    log::info!("two_nn intrinsic dimension {:.3e}, nb points used {}", global, nb_used);
+    let local = local.then(|| {
+        (0..neighbours.len())
+            .into_par_iter()
+            .map(|i| {
+                let pooled = std::iter::once(i)
+                    .chain(neighbours[i].iter().map(|e| e.get_node()))
+                    .filter_map(|j| log_mu[j]);
+                let (nb, sum) = pooled.fold((0usize, 0f64), |acc, l| (acc.0 + 1, acc.1 + l));
+                if nb > 0 && sum > 0. {
+                    nb as f64 / sum
+                } else {
+                    f64::NAN
+                }
+            })
+            .collect()
+    });
+    Ok(IntrinsicDim { global, local, nb_used })
+} // end of two_nn
+
+// Levina-Bickel inverse estimates (1/m_k) of a neighbourhood for k in k1..=k2 (k counted from 1), None if distances are not usable
+fn levina_bickel_inverses<F: Float>(edges: &[OutEdge<F>], k1: usize, k2: usize) -> Option<Vec<f64>> {
+    if edges.len() < k2 {
+        return None;
+    }
+    let log_dists: Vec<f64> = edges[..k2].iter().map(|e| e.weight.to_f64().unwrap_or(f64::NAN).ln()).collect();
+    if log_dists.iter().any(|l| !l.is_finite()) {
+        return None;
+    }
+    let inverses = (k1..=k2)
+        .map(|k| {
+            // 1/m_k = 1/(k-1) * sum_{j<k} ln(T_k/T_j)
+            let sum = log_dists[..k - 1].iter().map(|l| log_dists[k - 1] - l).sum::<f64>();
+            sum / (k - 1) as f64
+        })
+        .collect();
+    Some(inverses)
+}
+
+/// Levina-Bickel estimation of intrinsic dimension with neighbours k1..=k2 (2 <= k1 <= k2).
+/// Points with less than k2 neighbours are not used.
+/// Levina-Bickel use k1 = 10 and k2 = 20, which needs graphs with at least 20 neighbours.
+/// If local is true, the dimension of a node is its local estimate averaged over k.
+pub fn levina_bickel<F>(kgraph: &KGraph<F>, k1: usize, k2: usize, local: bool) -> Result<IntrinsicDim, AnnembedError>
+where
+    F: Float + FromPrimitive + std::fmt::UpperExp + Send + Sync + std::iter::Sum,
+{
+    if k1 < 2 || k2 < k1 {
+        return Err(AnnembedError::InvalidParameter(format!(
+            "levina_bickel needs 2 <= k1 <= k2, got k1 {} k2 {}",
+            k1, k2
+        )));
+    }
+    let neighbours = kgraph.get_neighbours();
+    let inverses: Vec<Option<Vec<f64>>> =
+        neighbours.par_iter().map(|edges| levina_bickel_inverses(edges, k1, k2)).collect();
+    let nb_k = k2 - k1 + 1;
+    let mut sum_inverses = vec![0f64; nb_k];
+    let mut nb_used = 0;
+    for inv in inverses.iter().flatten() {
+        nb_used += 1;
+        for (s, x) in sum_inverses.iter_mut().zip(inv) {
+            *s += x;
+        }
+    }
+    if nb_used == 0 || sum_inverses.iter().any(|s| *s <= 0.) {
+        log::error!("levina_bickel, no point with {} neighbours at distinct positive distances", k2);
+        return Err(AnnembedError::InvalidParameter(format!(
+            "levina_bickel needs {} neighbours at distinct positive distances",
+            k2
+        )));
+    }
+    // MacKay-Ghahramani : for each k inverse of the mean of inverses, then mean over k
+    let global = sum_inverses.iter().map(|s| nb_used as f64 / s).sum::<f64>() / nb_k as f64;
+    log::info!("levina_bickel intrinsic dimension {:.3e}, nb points used {}", global, nb_used);
+    let local = local.then(|| {
+        inverses
+            .iter()
+            .map(|inv| match inv {
+                Some(inv) if inv.iter().all(|x| *x > 0.) => inv.iter().map(|x| 1. / x).sum::<f64>() / nb_k as f64,
+                _ => f64::NAN,
+            })
+            .collect()
+    });
+    Ok(IntrinsicDim { global, local, nb_used })
+} // end of levina_bickel
+
+//=======================================================================
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use ndarray::Array2;
+    use rand::Rng;
+    use rand_xoshiro::rand_core::SeedableRng;
+    use rand_xoshiro::Xoshiro256PlusPlus;
+
+    fn log_init_test() {
+        let _ = env_logger::builder().is_test(true).try_init();
+    }
+
+    // knn graph by brute force of points uniform in the unit cube of dimension dim, embedded in space of dimension dim + 3
+    fn cube_kgraph(nb_points: usize, dim: usize, knbn: usize) -> KGraph<f32> {
+        let mut rng = Xoshiro256PlusPlus::seed_from_u64(4517);
+        let data: Vec<Vec<f32>> = (0..nb_points)
+            .map(|_| (0..dim + 3).map(|j| if j < dim { rng.gen::<f32>() } else { 0. }).collect())
+            .collect();
+        let mut indices = Array2::<usize>::zeros((nb_points, knbn));
+        let mut dists = Array2::<f32>::zeros((nb_points, knbn));
+        for i in 0..nb_points {
+            let mut neighbours: Vec<(usize, f32)> = (0..nb_points)
+                .filter(|j| *j != i)
+                .map(|j| (j, data[i].iter().zip(&data[j]).map(|(a, b)| (a - b) * (a - b)).sum::<f32>().sqrt()))
+                .collect();
+            neighbours.sort_unstable_by(|a, b| a.1.total_cmp(&b.1));
+            for (k, (j, d)) in neighbours.iter().take(knbn).enumerate() {
+                indices[[i, k]] = *j;
+                dists[[i, k]] = *d;
+            }
+        }
+        KGraph::from_knn_arrays(&indices, &dists).unwrap()
+    }
+
+    #[test]
+    fn test_intrinsic_dim() {
+        log_init_test();
+        for dim in [2, 4] {
+            let kgraph = cube_kgraph(2000, dim, 20);
+            let twonn = two_nn(&kgraph, true).unwrap();
+            log::info!("dim {} two_nn {:.3e}", dim, twonn.get_global());
+            assert!((twonn.get_global() - dim as f64).abs() < 0.15 * dim as f64);
+            let local = twonn.get_local().unwrap();
+            assert_eq!(local.len(), 2000);
+            let median = {
+                let mut sorted = local.clone();
+                sorted.sort_unstable_by(|a, b| a.total_cmp(b));
+                sorted[1000]
+            };
+            assert!((median - dim as f64).abs() < 0.3 * dim as f64);
+            //
+            let lb = levina_bickel(&kgraph, 10, 20, false).unwrap();
+            log::info!("dim {} levina_bickel {:.3e}", dim, lb.get_global());
+            assert!((lb.get_global() - dim as f64).abs() < 0.15 * dim as f64);
+            assert!(lb.get_local().is_none());
+            assert_eq!(lb.get_nb_used(), 2000);
+        }
+        let kgraph = cube_kgraph(100, 2, 5);
+        assert!(levina_bickel(&kgraph, 10, 20, false).is_err());
+        assert!(levina_bickel(&kgraph, 1, 3, false).is_err());
+    } // end of test_intrinsic_dim
+} // end of mod tests
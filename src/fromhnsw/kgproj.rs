@@ -7,7 +7,6 @@
 //! so we can infer how well the smaller graph represent the whole graph.
 //!
 
-use anyhow::anyhow;
 
 use num_traits::cast::FromPrimitive;
 use num_traits::Float;
@@ -21,14 +20,36 @@ use std::cmp::Ordering;
 use std::sync::Arc;
 
 use indexmap::set::*;
-use quantiles::ckms::CKMS;
+use crate::tools::quant::Quantiles;
 use std::collections::HashMap; // we could use also greenwald_khanna
 
 use hnsw_rs::prelude::*;
 
 use super::kgraph::*;
+use crate::error::AnnembedError;
 use crate::tools::nodeparam::*;
 
+/// Estimates of the error made by replacing points out of the small graph by their representative (nearest point in small graph).
+/// See [KGraphProjection::get_projection_error].
+#[derive(Clone, Debug)]
+pub struct ProjectionError {
+    /// number of points projected on a representative
+    pub nb_projected: usize,
+    /// number of distinct representatives used
+    pub nb_representatives: usize,
+    /// maximum number of projected points sharing a representative
+    pub max_load: usize,
+    /// mean distance to representative
+    pub mean_dist: f64,
+    /// quantiles 0.5, 0.9, 0.99 of distance to representative
+    pub dist_quantiles: [f64; 3],
+    /// maximum distance to representative
+    pub max_dist: f64,
+    /// quantiles 0.5, 0.9, 0.99 of the ratio of distance to representative by distance to nearest neighbour in large graph.
+    /// Ratios much above 1 mean that the representative is far compared to the local scale of data.
+    pub relative_quantiles: [f64; 3],
+} // end of ProjectionError
+
 /// Construct a projection Graph from Hnsw data on layers above a given layers.  
 /// Maintain for each point in the Hnsw structure nearest point in projected structure.
 /// Possibly stores matrix of distances between filtered points
@@ -54,13 +75,15 @@ where
     //
     /// construct graph from layers above layer, projects data of another layers on point in layers above layer arg
     /// nbng is the maximum number of neighours to keep. It should be comparable to
-    /// the parameter *max_nb_conn* used in the Hnsw structure.
-    pub fn new<T, D>(hnsw: &Hnsw<T, D>, nbng: usize, layer: usize) -> Self
+    /// the parameter *max_nb_conn* used in the Hnsw structure.  
+    /// Returns an error if there is no point to collect in layers above layer.
+    pub fn new<T, D>(hnsw: &Hnsw<T, D>, nbng: usize, layer: usize) -> Result<Self, AnnembedError>
     where
         T: Clone + Send + Sync,
         D: Distance<T> + Send + Sync,
     {
         log::debug!("KGraphProjection new  layer : {}", layer);
+        check_edge_idx(hnsw.get_point_indexation().get_nb_point())?;
         let mut nb_point_to_collect = 0;
         let mut nb_point_below_nbng: usize = 0;
         let mut mean_deficient_neighbour_size: usize = 0;
@@ -73,10 +96,6 @@ where
                 "KGraphProjection::new, layer argument greater than nb_layer!!, layer : {}",
                 layer
             );
-            println!(
-                "KGraphProjection::new, layer argument greater than nb_layer!!, layer : {}",
-                layer
-            );
         }
         for l in (layer..=max_level_observed).rev() {
             nb_point_to_collect += hnsw.get_point_indexation().get_layer_nb_point(l);
@@ -88,8 +107,7 @@ where
         }
         if nb_point_to_collect <= 0 {
             log::error!("!!!!!!!!!!!! KGraphProjection cannot collect points !!!!!!!!!!!!!, check layer argument");
-            println!("!!!!!!!!!!!! KGraphProjection cannot collect points !!!!!!!!!!!!!, check layer argument");
-            std::process::exit(1);
+            return Err(AnnembedError::InvalidParameter(format!("no point to collect above layer {}", layer)));
         }
         //
         let layer_u8 = layer as u8;
@@ -142,10 +160,7 @@ where
                         if n_p_id.0 as usize >= layer {
                             // get index of !!! (and not get) IndexMap interface is error prone
                             let neighbour_idx = upper_index_set.get_index_of(&n_origin_id).unwrap();
-                            vec_tmp.push(OutEdge::<F> {
-                                node: neighbour_idx,
-                                weight: F::from_f32(neighbours_hnsw[m][j].distance).unwrap(),
-                            });
+                            vec_tmp.push(OutEdge::<F>::new(neighbour_idx, F::from_f32(neighbours_hnsw[m][j].distance).unwrap()));
                         }
                     } // end of for j
                 } // end of for m
@@ -189,29 +204,27 @@ where
                 let best_distance = F::infinity();
                 // get nearest point in upper layers.
                 // possibly use a BinaryHeap?
-                let mut best_edge = OutEdge::<F> {
-                    node: usize::MAX,
-                    weight: best_distance,
-                };
+                let mut best_edge: Option<OutEdge<F>> = None;
                 for m in layer..=max_level_observed {
                     for j in 0..neighbours_hnsw[m].len() {
                         let n_origin_id = neighbours_hnsw[m][j].get_origin_id();
                         let n_p_id = neighbours_hnsw[m][j].p_id;
                         if n_p_id.0 >= layer_u8
-                            && F::from(neighbours_hnsw[m][j].distance).unwrap() < best_edge.weight
+                            && F::from(neighbours_hnsw[m][j].distance).unwrap()
+                                < best_edge.map_or(best_distance, |e| e.weight)
                         {
                             // store edge with remapping dataid to nodeidx
                             let neighbour_index =
                                 upper_index_set.get_index_of(&n_origin_id).unwrap();
-                            best_edge = OutEdge::<F> {
-                                node: neighbour_index,
-                                weight: F::from_f32(neighbours_hnsw[m][j].distance).unwrap(),
-                            };
+                            best_edge = Some(OutEdge::<F>::new(
+                                neighbour_index,
+                                F::from_f32(neighbours_hnsw[m][j].distance).unwrap(),
+                            ));
                         }
                     } // end of for j
                 }
                 // we have best edge, insert it in
-                if best_edge.weight < F::infinity() && best_edge.node < usize::MAX {
+                if let Some(best_edge) = best_edge.filter(|e| e.weight < F::infinity()) {
                     let index = index_set.get_index_of(&point.get_origin_id()).unwrap();
                     proj_data.insert(index, best_edge);
                 } else {
@@ -246,10 +259,7 @@ where
                 let index = upper_index_set
                     .get_index_of(&point.get_origin_id())
                     .unwrap();
-                let best_edge = OutEdge::<F> {
-                    node: index,
-                    weight: F::from_f32(0.).unwrap(),
-                };
+                let best_edge = OutEdge::<F>::new(index, F::from_f32(0.).unwrap());
                 proj_data.insert(index, best_edge);
             }
         }
@@ -292,10 +302,7 @@ where
                         let n_origin_id = neighbours_hnsw[m][j].get_origin_id();
                         // points are already indexed , or panic!
                         let neighbour_idx = index_set.get_index_of(&n_origin_id).unwrap();
-                        vec_tmp.push(OutEdge::<F> {
-                            node: neighbour_idx,
-                            weight: F::from_f32(neighbours_hnsw[m][j].distance).unwrap(),
-                        });
+                        vec_tmp.push(OutEdge::<F>::new(neighbour_idx, F::from_f32(neighbours_hnsw[m][j].distance).unwrap()));
                     } // end of for j
                 } // end of for m
                 vec_tmp.sort_unstable_by(|a, b| a.partial_cmp(b).unwrap_or(Ordering::Less));
@@ -332,12 +339,12 @@ where
         }
         log::trace!("Projection exiting from new");
         //
-        KGraphProjection {
+        Ok(KGraphProjection {
             layer,
             small_graph: upper_graph,
             proj_data: proj_data,
             large_graph: whole_graph,
-        }
+        })
     } // end of new
 
     /// get layer corresponding above which the projection is done. The layer is included in the projection.
@@ -355,7 +362,7 @@ where
     /// The argument is a DataId
     pub fn get_distance_to_projection_by_dataid(&self, data_id: &DataId) -> F {
         let edge = self.proj_data.get(&data_id).unwrap();
-        self.proj_data.get(&edge.node).unwrap().weight
+        self.proj_data.get(&edge.get_node()).unwrap().weight
     } // end of get_distance_to_projection
 
     /// return a reference to the small graph
@@ -369,8 +376,8 @@ where
     }
 
     /// returns quantile stats on distances to projection point
-    pub fn get_projection_distance_quant(&self) -> CKMS<f32> {
-        let mut quant = CKMS::<f32>::new(0.001);
+    pub fn get_projection_distance_quant(&self) -> Quantiles<f32> {
+        let mut quant = Quantiles::<f32>::new(0.001);
         for (_, edge) in self.proj_data.iter() {
             quant.insert(F::to_f32(&edge.weight).unwrap());
         }
@@ -378,8 +385,52 @@ where
         quant
     }
 
+    /// returns the distribution of distances from points out of the small graph to their representative,
+    /// absolute and relative to the distance of each point to its nearest neighbour in the large graph.
+    pub fn get_projection_error(&self) -> ProjectionError {
+        let mut dist_quant = Quantiles::<f64>::new(0.001);
+        let mut relative_quant = Quantiles::<f64>::new(0.001);
+        let mut loads = HashMap::<NodeIdx, usize>::new();
+        let mut mean_dist = 0.;
+        let mut max_dist: f64 = 0.;
+        // nodes of the small graph (the first ones of the large graph) are their own representative
+        let nb_small = self.small_graph.get_nb_nodes();
+        for (node, edge) in self.proj_data.iter().filter(|(node, _)| **node >= nb_small) {
+            let dist = F::to_f64(&edge.weight).unwrap();
+            dist_quant.insert(dist);
+            mean_dist += dist;
+            max_dist = max_dist.max(dist);
+            *loads.entry(edge.get_node()).or_insert(0) += 1;
+            if let Some(first_dist) = self.large_graph.get_first_neighbour_dist(*node) {
+                let first_dist = F::to_f64(&first_dist).unwrap();
+                if first_dist > 0. {
+                    relative_quant.insert(dist / first_dist);
+                }
+            }
+        }
+        let nb_projected = dist_quant.count();
+        let get_quantiles = |quant: &Quantiles<f64>| {
+            let mut q = [0.; 3];
+            for (i, level) in [0.5, 0.9, 0.99].iter().enumerate() {
+                q[i] = quant.query(*level).map_or(0., |v| v.1);
+            }
+            q
+        };
+        let error = ProjectionError {
+            nb_projected,
+            nb_representatives: loads.len(),
+            max_load: loads.values().max().copied().unwrap_or(0),
+            mean_dist: mean_dist / nb_projected.max(1) as f64,
+            dist_quantiles: get_quantiles(&dist_quant),
+            max_dist,
+            relative_quantiles: get_quantiles(&relative_quant),
+        };
+        log::info!("projection error : {:?}", error);
+        error
+    } // end of get_projection_error
+
     /// dump csc matrix of distances between pointsfrom projected graph.
-    pub fn dump_sparse_mat_for_ripser(&self, fname: &str) -> Result<(), anyhow::Error> {
+    pub fn dump_sparse_mat_for_ripser(&self, fname: &str) -> Result<(), AnnembedError> {
         let path = Path::new(fname);
         log::debug!("in to_ripser_sparse_dist : fname : {}", path.display());
         let fileres = OpenOptions::new()
@@ -387,12 +438,13 @@ where
             .create(true)
             .truncate(true)
             .open(path);
-        let file;
-        if fileres.is_ok() {
-            file = fileres.unwrap();
-        } else {
-            return Err(anyhow!("could not open file : {}", path.display()));
-        }
+        let file = match fileres {
+            Ok(file) => file,
+            Err(e) => {
+                log::error!("could not open file : {}", path.display());
+                return Err(AnnembedError::Io(e));
+            }
+        };
         //
         let mut bufwriter = BufWriter::new(file);
         let res = self.small_graph.to_ripser_sparse_dist(&mut bufwriter);
@@ -522,6 +574,14 @@ mod tests {
         hns.parallel_insert(&data_with_id);
         hns.dump_layer_info();
         //
-        let _graph_projection = KGraphProjection::<f32>::new(&hns, knbn, layer);
+        let graph_projection = KGraphProjection::<f32>::new(&hns, knbn, layer).unwrap();
+        let error = graph_projection.get_projection_error();
+        let nb_small = graph_projection.get_small_graph().get_nb_nodes();
+        assert!(error.nb_projected > 0);
+        assert!(error.nb_projected <= graph_projection.get_large_graph().get_nb_nodes() - nb_small);
+        assert!(error.dist_quantiles[2] > 0.);
+        assert!(error.nb_representatives <= graph_projection.get_small_graph().get_nb_nodes());
+        assert!(error.dist_quantiles[0] <= error.dist_quantiles[2] && error.dist_quantiles[2] <= error.max_dist);
+        assert!(error.relative_quantiles[0] > 0. && error.relative_quantiles[0] <= error.relative_quantiles[2]);
     } // end of test_graph_projection
 } // end of mod tests
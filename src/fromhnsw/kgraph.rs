@@ -3,27 +3,33 @@
 //! 
 //! 
 
-use anyhow::anyhow;
 
 use num_traits::Float;
 use num_traits::cast::FromPrimitive;
 
 // to dump to ripser
-use std::io::Write;
+use std::io::{Write, BufWriter, BufReader};
+use std::fs::OpenOptions;
+use std::path::Path;
+
+use serde::{Serialize, Deserialize, de::DeserializeOwned};
 
 use indexmap::set::*;
 
+use ndarray::Array2;
+
 use std::cmp::Ordering;
 
 use rand::thread_rng;
 
-use quantiles::ckms::CKMS;     // we could use also greenwald_khanna
 
 use rayon::prelude::*;
 
 use hnsw_rs::prelude::*;
 
-use crate::tools::{dimension::*,nodeparam::*};
+use crate::tools::{dimension::*,nodeparam::*,quant::Quantiles,stage::Stage};
+use crate::tools::io::DataLabels;
+use crate::error::AnnembedError;
 use rand::distributions::Distribution;
 
 // morally F should be f32 and f64.  
@@ -34,6 +40,24 @@ use rand::distributions::Distribution;
 //====================================================================================================
 
 
+/// Which edges are kept when filtering a graph by labels of its nodes, see [KGraph::filter_by_label].
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum LabelFilter {
+    /// keep edges between nodes with the same label (within class graph)
+    SameLabel,
+    /// keep edges between nodes with different labels (between class graph)
+    OtherLabel,
+}
+
+/// Threshold on edge length used by [KGraph::prune_edges].
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum EdgePruning {
+    /// drop edges longer than this quantile (in \[0,1\]) of all edge lengths
+    Quantile(f64),
+    /// drop edges longer than this distance
+    MaxDist(f64),
+}
+
 /// A structure to keep track of min and max distance to neighbour.
 /// We keep assume that Nan are excluded once we have reached the point we need this.
 struct RangeNghb<F:Float>(F, F);
@@ -54,7 +78,7 @@ pub struct KGraphStat<F:Float> {
     max_in_degree : usize,
     ///  We maintain quantiles on distances to first neighbours ad f32
     /// This can serve as an indicator on relative density around a point.
-    min_radius_q : CKMS<f32>,
+    min_radius_q : Quantiles<f32>,
 }  // end of KGraphStat
 
 
@@ -127,6 +151,16 @@ pub struct KGraph<F> {
 
 
 
+// what we serialize of a KGraph. The IndexSet is dumped as the vector of DataId in index order.
+#[derive(Serialize, Deserialize)]
+struct KGraphDump<F> {
+    max_nbng : usize,
+    nbnodes : usize,
+    neighbours : Vec<Vec<OutEdge<F>>>,
+    data_ids : Vec<DataId>,
+}  // end of struct KGraphDump
+
+
 impl <F> KGraph<F> 
     where F : FromPrimitive + Float + std::fmt::UpperExp + Sync + Send + std::iter::Sum
 {
@@ -141,6 +175,40 @@ impl <F> KGraph<F>
         }
     }  // end of new
 
+    /// builds a graph from a precomputed knn search (FAISS or any other tool).  
+    /// Row i of indices and dists describes point with DataId i : indices\[\[i,j\]\] is the rank of its j-th neighbour
+    /// and dists\[\[i,j\]\] its distance. Neighbours do not need to be sorted.  
+    /// The point itself (often returned first by knn searches), repeated neighbours and indices out of range
+    /// (missing neighbours) are skipped.
+    pub fn from_knn_arrays(indices : &Array2<usize>, dists : &Array2<F>) -> Result<Self, AnnembedError> {
+        if indices.dim() != dists.dim() {
+            log::error!("KGraph::from_knn_arrays, indices shape {:?} != dists shape {:?}", indices.dim(), dists.dim());
+            return Err(AnnembedError::InvalidParameter(String::from("indices and distances arrays must have the same shape")));
+        }
+        let nbnodes = indices.nrows();
+        check_edge_idx(nbnodes)?;
+        let mut neighbours = Vec::<Vec<OutEdge<F>>>::with_capacity(nbnodes);
+        let mut max_nbng = 0;
+        for (i, (row_idx, row_dist)) in indices.rows().into_iter().zip(dists.rows()).enumerate() {
+            let mut edges = Vec::<OutEdge<F>>::with_capacity(row_idx.len());
+            for (&j, &d) in row_idx.iter().zip(row_dist.iter()) {
+                if j == i || j >= nbnodes || edges.iter().any(|e| e.get_node() == j) {
+                    continue;
+                }
+                if d.is_nan() || d < F::zero() {
+                    log::error!("KGraph::from_knn_arrays, invalid distance between {} and {}", i, j);
+                    return Err(AnnembedError::InvalidParameter(format!("invalid distance between {} and {}", i, j)));
+                }
+                edges.push(OutEdge::<F>::new(j, d));
+            }
+            edges.sort_unstable_by(| a, b | a.partial_cmp(b).unwrap_or(Ordering::Less));
+            max_nbng = max_nbng.max(edges.len());
+            neighbours.push(edges);
+        }
+        log::info!("KGraph::from_knn_arrays, nb nodes : {}, max nb neighbours : {}", nbnodes, max_nbng);
+        Ok(KGraph{max_nbng, nbnodes, neighbours, node_set : (0..nbnodes).collect()})
+    } // end of from_knn_arrays
+
     /// get number of nodes of graph
     pub fn get_nb_nodes(&self) -> usize {
         self.nbnodes
@@ -156,6 +224,31 @@ impl <F> KGraph<F>
         &self.neighbours
     }
 
+    /// returns the out edges of a node given its index, sorted by increasing weight, as a borrowed slice
+    pub fn out_edges(&self, node : NodeIdx) -> &[OutEdge<F>] {
+        &self.neighbours[node]
+    }
+
+    /// iterates on NodeIdx of neighbours of a node, without allocation
+    pub fn neighbour_nodes(&self, node : NodeIdx) -> impl Iterator<Item = NodeIdx> + '_ {
+        self.neighbours[node].iter().map(|e| e.get_node())
+    }
+
+    /// iterates on (NodeIdx, out edges) of all nodes, borrowing the graph
+    pub fn iter_neighbourhoods(&self) -> impl ExactSizeIterator<Item = (NodeIdx, &[OutEdge<F>])> + '_ {
+        self.neighbours.iter().enumerate().map(|(i, edges)| (i, edges.as_slice()))
+    }
+
+    /// parallel version of [iter_neighbourhoods](Self::iter_neighbourhoods)
+    pub fn par_iter_neighbourhoods(&self) -> impl IndexedParallelIterator<Item = (NodeIdx, &[OutEdge<F>])> + '_ {
+        self.neighbours.par_iter().enumerate().map(|(i, edges)| (i, edges.as_slice()))
+    }
+
+    /// distance (weight) to the nearest neighbour of a node, None if node has no neighbour
+    pub fn get_first_neighbour_dist(&self, node : NodeIdx) -> Option<F> {
+        self.neighbours[node].first().map(|e| e.weight)
+    }
+
     /// get out edges from node given its index
     pub fn get_out_edges_by_idx(&self, node : NodeIdx) -> &Vec<OutEdge<F>> {
         &self.neighbours[node]
@@ -180,10 +273,10 @@ impl <F> KGraph<F>
 
 
     /// given a DataId returns list of edges from corresponding point or None if error occurs
-    pub fn get_out_edges_by_data_id(&self,  data_id: &DataId) -> Result<&Vec<OutEdge<F>>, anyhow::Error> {
+    pub fn get_out_edges_by_data_id(&self,  data_id: &DataId) -> Result<&Vec<OutEdge<F>>, AnnembedError> {
         let idx = self.get_idx_from_dataid(data_id);
         if idx.is_none() {
-            return Err(anyhow!("bad data_id"));
+            return Err(AnnembedError::InvalidParameter(format!("bad data_id {}", data_id)));
         }
         //
         let idx = idx.unwrap();
@@ -196,7 +289,7 @@ impl <F> KGraph<F>
     ///     Maximum likelyhood estimation of intrinsic dimension.
     ///     Levina E. and Bickel P.J NIPS 2004.  [Levina-Bickel](https://www.stat.berkeley.edu/~bickel/mldim.pdf)
     /// 
-    pub fn intrinsic_dim_at_data_id(&self, data_id : &DataId) -> Result<f64,anyhow::Error>   {
+    pub fn intrinsic_dim_at_data_id(&self, data_id : &DataId) -> Result<f64,AnnembedError>   {
         //
         let edges_res = self.get_out_edges_by_data_id(data_id);
         if edges_res.is_err() {
@@ -217,7 +310,7 @@ impl <F> KGraph<F>
     /// **Note : As recommended in the Paper cited, the estimation needs more than 20 neighbours around each point.**
     ///        We provide an estimation even if this condition is not fulfilled but it is less robust.
     // TODO : get an histogram of dimensions
-    pub fn estimate_intrinsic_dim(&self, sampling_size: usize) ->  Result<(f64,f64),anyhow::Error> {
+    pub fn estimate_intrinsic_dim(&self, sampling_size: usize) ->  Result<(f64,f64),AnnembedError> {
         // we sample points, ignoring the probability to sample twice or more the ame point.
         // TODO sampling without replacement?
         let mut dims = Vec::<f64>::with_capacity(sampling_size);
@@ -235,7 +328,7 @@ impl <F> KGraph<F>
         }
         if dims.len() == 0 {
             log::error!("could not sample dimension");
-            return Err(anyhow!("could not sample points"));
+            return Err(AnnembedError::InvalidParameter(String::from("could not sample points with enough neighbours")));
         }
         let mean_dim : f64 = dims.iter().sum::<f64>()/dims.len() as f64;
         let mut sigma = dims.iter().fold(0., |acc, d| acc + (d-mean_dim)*(d-mean_dim));
@@ -272,13 +365,13 @@ impl <F> KGraph<F>
     /// The dump corresponds to Ripser working on a distance matrix given in sparse format. See Ripser Code or Julia Ripserer
     /// We need to symetrize the matrix as we dump a distance matrix
     /// Note that ripser do not complain for no symetric data but Ripserer does 
-    pub(crate) fn to_ripser_sparse_dist(&self, writer : &mut dyn Write) -> Result<(), anyhow::Error> {
+    pub(crate) fn to_ripser_sparse_dist(&self, writer : &mut dyn Write) -> Result<(), AnnembedError> {
         log::debug!("in to_ripser_sparse_dist");
         //
         for i in 0..self.nbnodes {
             for n in &self.neighbours[i] {
-                write!(writer, "{} {} {:.5E}\n", i, n.node, n.weight)?;
-                write!(writer, "{} {} {:.5E}\n", n.node, i, n.weight)?;
+                write!(writer, "{} {} {:.5E}\n", i, n.get_node(), n.weight)?;
+                write!(writer, "{} {} {:.5E}\n", n.get_node(), i, n.weight)?;
             }
         }
         //
@@ -297,7 +390,7 @@ impl <F> KGraph<F>
         let mut max_max_r = F::zero();
         let mut min_min_r = F::max_value();
         //
-        let mut quant = CKMS::<f32>::new(0.001);
+        let mut quant = Quantiles::<f32>::new(0.001);
         //
         for i in 0..self.neighbours.len() {
             if self.neighbours[i].len() > 0 {
@@ -310,7 +403,7 @@ impl <F> KGraph<F>
                 // compute in_degrees
                 ranges.push(RangeNghb(min_r, max_r));
                 for j in 0..self.neighbours[i].len() {
-                    in_degrees[self.neighbours[i][j].node] += 1;
+                    in_degrees[self.neighbours[i][j].get_node()] += 1;
                 }
            }
         }
@@ -325,13 +418,13 @@ impl <F> KGraph<F>
             mean_in_degree /= in_degrees.len() as f32;
         }
         //
-        println!("\n minimal graph statistics \n");
-        println!("\t max in degree : {:.2e}", max_in_degree);
-        println!("\t mean in degree : {:.2e}", mean_in_degree);
-        println!("\t max max range : {:.2e} ", max_max_r.to_f32().unwrap());
-        println!("\t min min range : {:.2e} ", min_min_r.to_f32().unwrap());
+        log::info!("minimal graph statistics");
+        log::info!("\t max in degree : {:.2e}", max_in_degree);
+        log::info!("\t mean in degree : {:.2e}", mean_in_degree);
+        log::info!("\t max max range : {:.2e} ", max_max_r.to_f32().unwrap());
+        log::info!("\t min min range : {:.2e} ", min_min_r.to_f32().unwrap());
         if quant.count() > 0 {
-            println!("min radius quantile at 0.05 : {:.2e} , 0.5 :  {:.2e}, 0.95 : {:.2e}, 0.99 : {:.2e}", 
+            log::info!("min radius quantile at 0.05 : {:.2e} , 0.5 :  {:.2e}, 0.95 : {:.2e}, 0.99 : {:.2e}", 
                         quant.query(0.05).unwrap().1, quant.query(0.5).unwrap().1, 
                         quant.query(0.95).unwrap().1, quant.query(0.99).unwrap().1);
         }
@@ -340,9 +433,337 @@ impl <F> KGraph<F>
                     min_radius_q : quant}
     }  // end of get_kraph_stats
 
+
+    /// Computes the connected components of the graph, edges being considered as undirected.  
+    /// Returns for each node index the rank of its component. Components are numbered by decreasing size
+    /// so that component 0 is the largest one.
+    pub fn get_connected_components(&self) -> Vec<usize> {
+        // union find with path halving
+        let mut parents : Vec<NodeIdx> = (0..self.nbnodes).collect();
+        fn find(parents : &mut [NodeIdx], mut node : NodeIdx) -> NodeIdx {
+            while parents[node] != node {
+                parents[node] = parents[parents[node]];
+                node = parents[node];
+            }
+            node
+        }
+        for i in 0..self.neighbours.len() {
+            for edge in &self.neighbours[i] {
+                let root_i = find(&mut parents, i);
+                let root_j = find(&mut parents, edge.get_node());
+                if root_i != root_j {
+                    parents[root_i.max(root_j)] = root_i.min(root_j);
+                }
+            }
+        }
+        // count sizes of components by root
+        let mut sizes = vec![0usize; self.nbnodes];
+        for i in 0..self.nbnodes {
+            let root = find(&mut parents, i);
+            sizes[root] += 1;
+        }
+        // rank roots by decreasing size, ties broken by root index to stay deterministic
+        let mut roots : Vec<NodeIdx> = (0..self.nbnodes).filter(|r| sizes[*r] > 0).collect();
+        roots.sort_by(|a,b| sizes[*b].cmp(&sizes[*a]).then(a.cmp(b)));
+        let mut rank_of_root = vec![0usize; self.nbnodes];
+        for (rank, root) in roots.iter().enumerate() {
+            rank_of_root[*root] = rank;
+        }
+        let components : Vec<usize> = (0..self.nbnodes).map(|i| rank_of_root[find(&mut parents, i)]).collect();
+        log::info!("get_connected_components, nb components : {}", roots.len());
+        //
+        components
+    } // end of get_connected_components
+
+
+    /// extracts the graph induced by a subset of nodes given by their NodeIdx.  
+    /// Nodes are reindexed in the order of the slice, edges going out of the subset are dropped.
+    pub(crate) fn get_subgraph(&self, nodes : &[NodeIdx]) -> KGraph<F> {
+        let mut node_set = IndexSet::<DataId>::with_capacity(nodes.len());
+        for node in nodes {
+            node_set.insert(*self.node_set.get_index(*node).unwrap());
+        }
+        let mut neighbours = Vec::<Vec<OutEdge<F>>>::with_capacity(nodes.len());
+        let mut max_nbng = 0;
+        for node in nodes {
+            let edges : Vec<OutEdge<F>> = self.neighbours[*node].iter()
+                    .filter_map(|e| node_set.get_index_of(self.node_set.get_index(e.get_node()).unwrap()).map(|n| OutEdge::<F>::new(n, e.weight)))
+                    .collect();
+            max_nbng = max_nbng.max(edges.len());
+            neighbours.push(edges);
+        }
+        KGraph{max_nbng, nbnodes : nodes.len(), neighbours, node_set}
+    } // end of get_subgraph
+
+
+    /// returns a graph with the same nodes (and NodeIdx) retaining only edges whose ends share a label
+    /// or have different labels according to filter.  
+    /// Edges with an end without label are dropped. Useful for supervised diffusion maps and class conditional statistics.  
+    /// Nodes whose edges are all dropped are kept without out edge, they are reported in the log and by
+    /// [get_isolated_nodes](Self::get_isolated_nodes). They must be removed before embedding the filtered graph.
+    pub fn filter_by_label<L : PartialEq>(&self, labels : &DataLabels<L>, filter : LabelFilter) -> KGraph<F> {
+        let node_labels : Vec<Option<&L>> = self.node_set.iter().map(|d| labels.get(d)).collect();
+        let mut max_nbng = 0;
+        let mut nb_kept = 0;
+        let neighbours : Vec<Vec<OutEdge<F>>> = (0..self.nbnodes).map(|i| {
+            let edges : Vec<OutEdge<F>> = self.neighbours[i].iter()
+                    .filter(|e| match (node_labels[i], node_labels[e.get_node()]) {
+                        (Some(li), Some(lj)) => (li == lj) == (filter == LabelFilter::SameLabel),
+                        _ => false,
+                    })
+                    .map(|e| OutEdge::<F>::new(e.get_node(), e.weight))
+                    .collect();
+            max_nbng = max_nbng.max(edges.len());
+            nb_kept += edges.len();
+            edges
+        }).collect();
+        log::info!("filter_by_label {:?}, nb edges kept : {}", filter, nb_kept);
+        let filtered = KGraph{max_nbng, nbnodes : self.nbnodes, neighbours, node_set : self.node_set.clone()};
+        let nb_isolated = filtered.get_isolated_nodes().len();
+        if nb_isolated > 0 {
+            log::warn!("filter_by_label {:?}, {} nodes have no out edge left", filter, nb_isolated);
+        }
+        filtered
+    } // end of filter_by_label
+
+
+    /// returns the NodeIdx of nodes without out edge. Such nodes cannot be embedded.
+    pub fn get_isolated_nodes(&self) -> Vec<NodeIdx> {
+        (0..self.nbnodes).filter(|i| self.neighbours[*i].is_empty()).collect()
+    } // end of get_isolated_nodes
+
+
+    /// returns for each node (by NodeIdx) the fraction of its out edges going to a node with the same label.  
+    /// The value is None for nodes without label or without labelled neighbour.
+    pub fn get_label_purity<L : PartialEq>(&self, labels : &DataLabels<L>) -> Vec<Option<f64>> {
+        let node_labels : Vec<Option<&L>> = self.node_set.iter().map(|d| labels.get(d)).collect();
+        (0..self.nbnodes).map(|i| {
+            let label = node_labels[i]?;
+            let labelled : Vec<bool> = self.neighbours[i].iter()
+                    .filter_map(|e| node_labels[e.get_node()].map(|l| l == label))
+                    .collect();
+            if labelled.is_empty() {
+                return None;
+            }
+            Some(labelled.iter().filter(|same| **same).count() as f64 / labelled.len() as f64)
+        }).collect()
+    } // end of get_label_purity
+
+
+    /// adds bridge edges between connected components so that the graph becomes connected.  
+    /// 
+    /// The graph must have been extracted from hnsw (its DataIds are those of the hnsw).  
+    /// At each round (as in Boruvka algorithm) for each component, up to max_repr of its points search their nearest neighbour
+    /// outside of the component with a filtered Hnsw search. The shortest bridge of each component is added in both directions,
+    /// with the distance as weight. Rounds go on until the graph is connected or no bridge is found.  
+    /// Returns the number of bridges added.
+    pub fn connect_components<T, D>(&mut self, hnsw : &Hnsw<T,D>, max_repr : usize, ef_search : usize) -> Result<usize, AnnembedError>
+        where   T : Clone + Send + Sync,
+                D : Distance<T> + Send + Sync {
+        // vectors of nodes, by NodeIdx
+        let mut points = vec![None; self.nbnodes];
+        let mut point_iter = hnsw.get_point_indexation().into_iter();
+        while let Some(point) = point_iter.next() {
+            if let Some(idx) = self.node_set.get_index_of(&point.get_origin_id()) {
+                points[idx] = Some(point);
+            }
+        }
+        if points.iter().any(|p| p.is_none()) {
+            log::error!("connect_components, graph has nodes not in hnsw");
+            return Err(AnnembedError::GraphConstruction(String::from("connect_components, graph has nodes not in hnsw")));
+        }
+        let max_repr = max_repr.max(1);
+        let mut nb_bridges = 0;
+        loop {
+            let components = self.get_connected_components();
+            let nb_components = components.iter().max().map_or(0, |c| c + 1);
+            if nb_components <= 1 {
+                break;
+            }
+            log::info!("connect_components, nb components : {}", nb_components);
+            let mut members = vec![Vec::<NodeIdx>::new(); nb_components];
+            for (node, c) in components.iter().enumerate() {
+                members[*c].push(node);
+            }
+            // shortest bridge (distance, from, to) going out of each component
+            let bridges : Vec<Option<(f32, NodeIdx, NodeIdx)>> = members.par_iter().enumerate().map(|(c, nodes)| {
+                let filter = |id : &DataId| self.node_set.get_index_of(id).map_or(false, |idx| components[idx] != c);
+                let step = (nodes.len() / max_repr).max(1);
+                let mut best : Option<(f32, NodeIdx, NodeIdx)> = None;
+                for node in nodes.iter().step_by(step) {
+                    let found = hnsw.search_filter(points[*node].as_ref().unwrap().get_v(), 1, ef_search.max(1), Some(&filter));
+                    if let Some(n) = found.first() {
+                        let to = self.node_set.get_index_of(&n.d_id).unwrap();
+                        if components[to] != c && best.map_or(true, |b| n.distance < b.0) {
+                            best = Some((n.distance, *node, to));
+                        }
+                    }
+                }
+                best
+            }).collect();
+            let mut added = 0;
+            for (distance, from, to) in bridges.into_iter().flatten() {
+                // a bridge can be found from both of its components
+                if self.neighbours[from].iter().any(|e| e.get_node() == to) {
+                    continue;
+                }
+                let weight = F::from_f32(distance).unwrap();
+                self.insert_edge(from, to, weight);
+                self.insert_edge(to, from, weight);
+                added += 1;
+            }
+            if added == 0 {
+                log::warn!("connect_components, no bridge found, graph stays with {} components, possibly increase ef_search", nb_components);
+                break;
+            }
+            nb_bridges += added;
+        }
+        log::info!("connect_components, nb bridges added : {}", nb_bridges);
+        Ok(nb_bridges)
+    } // end of connect_components
+
+
+    /// returns the graph with the same edges reweighted by shared nearest neighbours (SNN).  
+    /// The weight of edge (i,j) becomes $1 - J(i,j)$ where J is the Jaccard index of the sets $\{i\} \cup N(i)$ and $\{j\} \cup N(j)$,
+    /// so weights stay dissimilarities in \[0,1\) and can go through the kernel construction as distances.
+    /// Edges with a Jaccard index below min_jaccard are dropped (0. keeps all edges), except the edge with
+    /// the largest Jaccard index of each node so that no node gets isolated.
+    pub fn to_snn_graph(&self, min_jaccard : f32) -> KGraph<F> {
+        // sorted closed neighbourhoods
+        let closed : Vec<Vec<NodeIdx>> = (0..self.nbnodes).into_par_iter().map(|i| {
+            let mut nodes : Vec<NodeIdx> = self.neighbour_nodes(i).collect();
+            nodes.push(i);
+            nodes.sort_unstable();
+            nodes.dedup();
+            nodes
+        }).collect();
+        let jaccard = |a : &[NodeIdx], b : &[NodeIdx]| -> f32 {
+            let (mut ia, mut ib, mut inter) = (0, 0, 0);
+            while ia < a.len() && ib < b.len() {
+                match a[ia].cmp(&b[ib]) {
+                    Ordering::Less => ia += 1,
+                    Ordering::Greater => ib += 1,
+                    Ordering::Equal => { inter += 1; ia += 1; ib += 1; }
+                }
+            }
+            inter as f32 / (a.len() + b.len() - inter) as f32
+        };
+        let neighbours : Vec<Vec<OutEdge<F>>> = (0..self.nbnodes).into_par_iter().map(|i| {
+            let mut edges : Vec<OutEdge<F>> = self.neighbour_nodes(i)
+                .map(|j| OutEdge::<F>::new(j, F::from_f32(1. - jaccard(&closed[i], &closed[j])).unwrap()))
+                .collect();
+            edges.sort_unstable_by(| a, b | a.partial_cmp(b).unwrap_or(Ordering::Less));
+            // the first edge has the largest jaccard index and is always kept
+            let max_weight = F::from_f32(1. - min_jaccard).unwrap();
+            let nb_kept = edges.iter().skip(1).take_while(|e| e.weight <= max_weight).count() + 1;
+            edges.truncate(nb_kept);
+            edges
+        }).collect();
+        let max_nbng = neighbours.iter().map(|e| e.len()).max().unwrap_or(0);
+        KGraph{max_nbng, nbnodes : self.nbnodes, neighbours, node_set : self.node_set.clone()}
+    } // end of to_snn_graph
+
+
+    /// returns a graph with the same nodes retaining only edges not longer than the threshold given by pruning.  
+    /// Quantiles of edge lengths are estimated with [Quantiles].
+    /// The nearest neighbour of each node is always kept so that no node gets isolated.
+    pub fn prune_edges(&self, pruning : EdgePruning) -> KGraph<F> {
+        let max_dist = match pruning {
+            EdgePruning::MaxDist(d) => d,
+            EdgePruning::Quantile(q) => {
+                let mut quant = Quantiles::<f32>::new(0.001);
+                for e in self.neighbours.iter().flatten() {
+                    quant.insert(e.weight.to_f32().unwrap());
+                }
+                quant.query(q).map(|(_, d)| d as f64).unwrap_or(f64::INFINITY)
+            }
+        };
+        let neighbours : Vec<Vec<OutEdge<F>>> = self.neighbours.par_iter().map(|edges| {
+            edges.iter().enumerate()
+                .filter(|(k, e)| *k == 0 || e.weight.to_f64().unwrap() <= max_dist)
+                .map(|(_, e)| OutEdge::<F>::new(e.get_node(), e.weight))
+                .collect()
+        }).collect();
+        let max_nbng = neighbours.iter().map(|e| e.len()).max().unwrap_or(0);
+        let nb_edges = self.neighbours.iter().map(|e| e.len()).sum::<usize>();
+        let nb_kept = neighbours.iter().map(|e| e.len()).sum::<usize>();
+        log::info!("prune_edges {:?}, max dist : {:.3e}, nb edges kept : {} / {}", pruning, max_dist, nb_kept, nb_edges);
+        KGraph{max_nbng, nbnodes : self.nbnodes, neighbours, node_set : self.node_set.clone()}
+    } // end of prune_edges
+
+
+    // inserts an edge keeping out edges sorted by increasing weight
+    fn insert_edge(&mut self, from : NodeIdx, to : NodeIdx, weight : F) {
+        let edges = &mut self.neighbours[from];
+        let pos = edges.iter().position(|e| e.weight > weight).unwrap_or(edges.len());
+        edges.insert(pos, OutEdge::<F>::new(to, weight));
+        self.max_nbng = self.max_nbng.max(edges.len());
+    } // end of insert_edge
+
 } // end of block impl KGraph
 
 
+
+impl <F> KGraph<F> 
+    where F : FromPrimitive + Float + Serialize + DeserializeOwned
+{
+    /// dumps the graph in bincode format, so that embedding can be run later (or on another machine)
+    /// without the Hnsw structure or the original data. See [reload](Self::reload).  
+    /// The dump starts with [EDGE_IDX_BITS], it can only be reloaded by a build with the same edge index width (see feature large_graph).
+    pub fn dump(&self, path : &Path) -> Result<(), AnnembedError> {
+        log::info!("dumping kgraph in file : {}", path.display());
+        let fileres = OpenOptions::new().write(true).create(true).truncate(true).open(path);
+        if let Err(e) = fileres {
+            log::error!("KGraph::dump could not open file {}", path.display());
+            return Err(AnnembedError::Io(e));
+        }
+        let mut bufwriter = BufWriter::new(fileres.unwrap());
+        // width of edge indexes first, so that reload can check it before decoding edges
+        bincode::serialize_into(&mut bufwriter, &EDGE_IDX_BITS).map_err(|e| AnnembedError::Io(std::io::Error::other(e)))?;
+        let to_dump = KGraphDump{max_nbng : self.max_nbng, nbnodes : self.nbnodes, neighbours : self.neighbours.clone(),
+                                    data_ids : self.node_set.iter().cloned().collect()};
+        bincode::serialize_into(bufwriter, &to_dump).map_err(|e| AnnembedError::Io(std::io::Error::other(e)))?;
+        //
+        Ok(())
+    } // end of dump
+
+
+    /// reloads a graph dumped by [dump](Self::dump).  
+    /// Returns an error if the file is not consistent : neighbour out of the nodes, duplicated DataId, other edge index width.  
+    /// The reloaded graph is embedded as any graph by [Embedder::new](crate::embedder::Embedder::new).
+    pub fn reload(path : &Path) -> Result<KGraph<F>, AnnembedError> {
+        log::info!("reloading kgraph from file : {}", path.display());
+        let fileres = OpenOptions::new().read(true).open(path);
+        if let Err(e) = fileres {
+            log::error!("KGraph::reload could not open file {}", path.display());
+            return Err(AnnembedError::Io(e));
+        }
+        let mut bufreader = BufReader::new(fileres.unwrap());
+        let edge_idx_bits : u32 = bincode::deserialize_from(&mut bufreader).map_err(|e| AnnembedError::Io(std::io::Error::other(e)))?;
+        check_edge_idx_bits(edge_idx_bits)?;
+        let dumped : KGraphDump<F> = bincode::deserialize_from(bufreader).map_err(|e| AnnembedError::Io(std::io::Error::other(e)))?;
+        if dumped.neighbours.len() != dumped.nbnodes || dumped.data_ids.len() != dumped.nbnodes {
+            return Err(AnnembedError::InvalidParameter(format!("KGraph::reload inconsistent number of nodes in file {}", path.display())));
+        }
+        // a corrupt or foreign dump must not panic later when indexing nodes
+        for (node, edges) in dumped.neighbours.iter().enumerate() {
+            if let Some(edge) = edges.iter().find(|e| e.get_node() >= dumped.nbnodes) {
+                log::error!("KGraph::reload node {} has neighbour {} out of {} nodes", node, edge.get_node(), dumped.nbnodes);
+                return Err(AnnembedError::InvalidParameter(format!("KGraph::reload node {} has neighbour {} out of {} nodes in file {}",
+                                node, edge.get_node(), dumped.nbnodes, path.display())));
+            }
+        }
+        let node_set : IndexSet<DataId> = dumped.data_ids.into_iter().collect();
+        if node_set.len() != dumped.nbnodes {
+            log::error!("KGraph::reload duplicated DataId in file {}", path.display());
+            return Err(AnnembedError::InvalidParameter(format!("KGraph::reload duplicated DataId in file {}", path.display())));
+        }
+        //
+        Ok(KGraph{max_nbng : dumped.max_nbng, nbnodes : dumped.nbnodes, neighbours : dumped.neighbours, node_set})
+    } // end of reload
+} // end of block impl KGraph for dump/reload
+
+
 /// initialization of a graph with expected number of neighbours nbng.  
 /// 
 /// This initialization corresponds to the case where use all points of the hnsw structure
@@ -350,77 +771,134 @@ impl <F> KGraph<F>
 /// nbng is the maximal number of neighbours kept. The effective mean number can be less,
 /// in this case use the Hnsw.set_keeping_pruned(true) to restrict pruning in the search.
 ///
-pub fn kgraph_from_hnsw_all<T, D, F>(hnsw : &Hnsw<T,D>, nbng : usize) -> std::result::Result<KGraph<F>, usize> 
+pub fn kgraph_from_hnsw_all<T, D, F>(hnsw : &Hnsw<T,D>, nbng : usize) -> std::result::Result<KGraph<F>, AnnembedError> 
+    where   T : Clone + Send + Sync, 
+            D : Distance<T> + Send + Sync,
+            F : Float + FromPrimitive + Send + Sync {
+    kgraph_from_hnsw_all_with_progress(hnsw, nbng, &mut |_ : &ExtractionProgress| {})
+}   // end kgraph_from_hnsw_all
+
+
+
+/// default number of points processed in parallel between two calls to progress callback
+pub const EXTRACTION_CHUNKSIZE : usize = 100_000;
+
+/// State of graph extraction passed to callback of [kgraph_from_hnsw_all_with_progress]
+#[derive(Copy, Clone, Debug)]
+pub struct ExtractionProgress {
+    /// number of points whose neighbourhood is extracted
+    pub nb_processed : usize,
+    /// total number of points in Hnsw
+    pub nb_point : usize,
+    /// number of edges collected so far
+    pub nb_edges : usize,
+    /// elapsed time since beginning of extraction in milliseconds
+    pub elapsed_ms : u128,
+}
+
+impl ExtractionProgress {
+    /// fraction of points processed
+    pub fn get_fraction(&self) -> f64 {
+        self.nb_processed as f64 / self.nb_point.max(1) as f64
+    }
+
+    /// estimated remaining time in milliseconds, extrapolated from the rate observed so far
+    pub fn get_remaining_ms(&self) -> Option<f64> {
+        if self.nb_processed == 0 {
+            return None;
+        }
+        Some(self.elapsed_ms as f64 * (self.nb_point - self.nb_processed) as f64 / self.nb_processed as f64)
+    }
+} // end of impl ExtractionProgress
+
+
+/// Same as [kgraph_from_hnsw_all] but points are processed in parallel by chunks of [EXTRACTION_CHUNKSIZE] points
+/// and progress is called (from the calling thread) after each chunk, so that caller can display progress and estimate completion.
+pub fn kgraph_from_hnsw_all_with_progress<T, D, F>(hnsw : &Hnsw<T,D>, nbng : usize, progress : &mut dyn FnMut(&ExtractionProgress)) 
+            -> std::result::Result<KGraph<F>, AnnembedError> 
     where   T : Clone + Send + Sync, 
             D : Distance<T> + Send + Sync,
-            F : Float + FromPrimitive {
+            F : Float + FromPrimitive + Send + Sync {
     //
     log::debug!("entering kgraph_from_hnsw_all");
     //
+    let stage = Stage::enter("graph");
+    let start = std::time::SystemTime::now();
     let max_nbng = nbng;
-    let mut nb_point_below_nbng = 0;
-    let mut mean_deficient_neighbour_size: usize = 0;   
-    let mut minimum_nbng = nbng;
-    let mut mean_nbng = 0u64;
     // We must extract the whole structure , for each point the list of its nearest neighbours and weight<F> of corresponding edge
     let max_nb_conn = hnsw.get_max_nb_connection() as usize;    // morally this the k of knn bu we have that for each layer
     // check consistency between max_nb_conn and nbng
     if max_nb_conn < nbng {
-        log::info!("init_from_hnsw_all: number of neighbours must be less than hnsw max_nb_connection : {} ", max_nb_conn);
-        println!("init_from_hnsw_all: number of neighbours must be less than hnsw max_nb_connection : {} ", max_nb_conn);
+        log::warn!("init_from_hnsw_all: number of neighbours must be less than hnsw max_nb_connection : {} ", max_nb_conn);
     }
     let point_indexation = hnsw.get_point_indexation();
     let nb_point = point_indexation.get_nb_point();
+    check_edge_idx(nb_point)?;
+    // first pass remaps DataId to 0..nb_point so that neighbours can be reindexed in parallel
     let mut node_set = IndexSet::<DataId>::with_capacity(nb_point);
-    // now we have nb_point we can allocate neighbour field, and we push vectors inside as we will fill in ordre we do not know!
+    for point in point_indexation.into_iter() {
+        node_set.insert(point.get_origin_id());
+    }
+    if node_set.len() != nb_point {
+        log::error!("kgraph_from_hnsw_all, found {} distinct DataId for {} points", node_set.len(), nb_point);
+        return Err(AnnembedError::GraphConstruction(format!("found {} distinct DataId for {} points", node_set.len(), nb_point)));
+    }
     let mut neighbours = Vec::<Vec<OutEdge<F>>>::with_capacity(nb_point);
     for _i in 0..nb_point {
         neighbours.push(Vec::<OutEdge<F>>::new());
-    }        
+    }
     //
-    let point_indexation = hnsw.get_point_indexation();
+    let mut nb_point_below_nbng = 0;
+    let mut mean_deficient_neighbour_size: usize = 0;   
+    let mut minimum_nbng = nbng;
+    let mut mean_nbng = 0u64;
+    let mut nb_processed = 0;
     let mut point_iter = point_indexation.into_iter();
-    while let Some(point) = point_iter.next() {
-        // now point is an Arc<Point<F>>
-        // point_id must be in 0..nb_point. CAVEAT This is not enforced as in petgraph. We should check that
-        let point_id = point.get_origin_id();
-        // remap _point_id
-        let (index, _) = node_set.insert_full(point_id);
-        //
-        let neighbours_hnsw = point.get_neighborhood_id();
-        // neighbours_hnsw contains neighbours in each layer
-        // we flatten the layers and transfer neighbours to KGraph::_neighbours
-        // possibly use a BinaryHeap?
-        let nb_layer = neighbours_hnsw.len();
-        let mut vec_tmp = Vec::<OutEdge<F>>::with_capacity(max_nb_conn*nb_layer);
-        for i in 0..nb_layer {
-            for j in 0..neighbours_hnsw[i].len() {
-                // remap id. nodeset enforce reindexation from 0 too nbnodes whatever the number of node will be
-                let (neighbour_idx, _) = node_set.insert_full(neighbours_hnsw[i][j].get_origin_id());
-                assert!(index != neighbour_idx);
-                vec_tmp.push(OutEdge::<F>{ node : neighbour_idx, weight : F::from_f32(neighbours_hnsw[i][j].distance).unwrap()});
-            }
+    loop {
+        let chunk : Vec<_> = point_iter.by_ref().take(EXTRACTION_CHUNKSIZE).collect();
+        if chunk.is_empty() {
+            break;
         }
-        vec_tmp.sort_unstable_by(| a, b | a.partial_cmp(b).unwrap_or(Ordering::Less));
-        assert!(vec_tmp.len() <= 1 || vec_tmp[0].weight <= vec_tmp[1].weight);    // temporary , check we did not invert order
-        // keep only the asked size. Could we keep more ?
-        if vec_tmp.len() < nbng {
-            nb_point_below_nbng += 1;
-            mean_deficient_neighbour_size += vec_tmp.len();
-            log::trace!("neighbours must have {} neighbours, point {} got only {}", max_nbng, point_id, vec_tmp.len());
-            if vec_tmp.len() == 0 {
-                let p_id = point.get_point_id();
-                log::warn!(" graph will not be connected, isolated point at layer {}  , pos in layer : {} ", p_id.0, p_id.1);
+        let extracted : Vec<(usize, Vec<OutEdge<F>>)> = chunk.par_iter().map(|point| {
+            let index = node_set.get_index_of(&point.get_origin_id()).unwrap();
+            // neighbours_hnsw contains neighbours in each layer
+            // we flatten the layers and transfer neighbours to KGraph::_neighbours
+            let neighbours_hnsw = point.get_neighborhood_id();
+            let nb_layer = neighbours_hnsw.len();
+            let mut vec_tmp = Vec::<OutEdge<F>>::with_capacity(max_nb_conn*nb_layer);
+            for layer in neighbours_hnsw.iter() {
+                for neighbour in layer {
+                    let neighbour_idx = node_set.get_index_of(&neighbour.get_origin_id()).unwrap();
+                    assert!(index != neighbour_idx);
+                    vec_tmp.push(OutEdge::<F>::new(neighbour_idx, F::from_f32(neighbour.distance).unwrap()));
+                }
             }
-        }
-        vec_tmp.truncate(nbng);
-        mean_nbng += vec_tmp.len() as u64;
-        minimum_nbng = minimum_nbng.min(vec_tmp.len());
-        //
+            vec_tmp.sort_unstable_by(| a, b | a.partial_cmp(b).unwrap_or(Ordering::Less));
+            if vec_tmp.len() < nbng {
+                log::trace!("neighbours must have {} neighbours, point {} got only {}", max_nbng, point.get_origin_id(), vec_tmp.len());
+                if vec_tmp.is_empty() {
+                    let p_id = point.get_point_id();
+                    log::warn!(" graph will not be connected, isolated point at layer {}  , pos in layer : {} ", p_id.0, p_id.1);
+                }
+            }
+            // keep only the asked size. Could we keep more ?
+            vec_tmp.truncate(nbng);
+            (index, vec_tmp)
+        }).collect();
         // We insert neighborhood info at slot corresponding to index beccause we want to access points in coherence with neighbours referencing
-        // =====================================================================================================================================
-        //  
-        neighbours[index] = vec_tmp;
+        for (index, vec_tmp) in extracted {
+            if vec_tmp.len() < nbng {
+                nb_point_below_nbng += 1;
+                mean_deficient_neighbour_size += vec_tmp.len();
+            }
+            mean_nbng += vec_tmp.len() as u64;
+            minimum_nbng = minimum_nbng.min(vec_tmp.len());
+            neighbours[index] = vec_tmp;
+        }
+        nb_processed += chunk.len();
+        let elapsed_ms = start.elapsed().map_or(0, |d| d.as_millis());
+        progress(&ExtractionProgress{nb_processed, nb_point, nb_edges : mean_nbng as usize, elapsed_ms});
+        stage.report_progress(nb_processed, nb_point);
     }
     let nbnodes = neighbours.len();
     assert_eq!(neighbours.len(), nb_point);
@@ -435,12 +913,152 @@ pub fn kgraph_from_hnsw_all<T, D, F>(hnsw : &Hnsw<T,D>, nbng : usize) -> std::re
     if mean_nbng < nbng as f64 {
         log::warn!(" mean number of neighbours obtained : {:.3e}", mean_nbng);
         log::warn!(" possibly use hnsw.set_keeping_pruned(true)");
-        println!(" mean number of neighbours obtained : {:.3e}", mean_nbng);
-        println!(" possibly use hnsw.set_keeping_pruned(true)");
     }
+    stage.record_size("nb_nodes", nbnodes);
+    stage.record_size("nbng", nbng);
+    stage.record("mean_nbng", mean_nbng);
+    stage.record_size("min_nbng", minimum_nbng);
     //
     Ok(KGraph{max_nbng, nbnodes, neighbours, node_set})
-}   // end kgraph_from_hnsw_all
+}   // end kgraph_from_hnsw_all_with_progress
+
+
+
+/// initialization of a KGraph by a knn search in the hnsw structure of each of its points.  
+/// 
+/// Contrary to [kgraph_from_hnsw_all] the number of neighbours knbn is not bounded by the max_nb_connection used to build the Hnsw
+/// and the precision of neighbourhoods can be adjusted with ef_search (it must be greater than knbn).
+/// Searches are done in parallel, this is more costly than extracting neighbourhoods stored in the Hnsw.
+pub fn kgraph_from_hnsw_search<T, D, F>(hnsw : &Hnsw<T,D>, knbn : usize, ef_search : usize) -> std::result::Result<KGraph<F>, AnnembedError> 
+    where   T : Clone + Send + Sync, 
+            D : Distance<T> + Send + Sync,
+            F : Float + FromPrimitive + Send + Sync {
+    //
+    log::debug!("entering kgraph_from_hnsw_search, knbn : {}, ef_search : {}", knbn, ef_search);
+    if knbn == 0 {
+        log::error!("kgraph_from_hnsw_search, number of neighbours must be > 0");
+        return Err(AnnembedError::InvalidParameter(String::from("number of neighbours must be > 0")));
+    }
+    let stage = Stage::enter("graph");
+    let ef_search = ef_search.max(knbn + 1);
+    let point_indexation = hnsw.get_point_indexation();
+    check_edge_idx(point_indexation.get_nb_point())?;
+    let mut node_set = IndexSet::<DataId>::with_capacity(point_indexation.get_nb_point());
+    let mut points = Vec::with_capacity(point_indexation.get_nb_point());
+    let mut point_iter = point_indexation.into_iter();
+    while let Some(point) = point_iter.next() {
+        node_set.insert(point.get_origin_id());
+        points.push(point);
+    }
+    let nbnodes = node_set.len();
+    // we ask for one more neighbour as the point itself is found by its search.
+    // searches are done by chunks of EXTRACTION_CHUNKSIZE points to report progress
+    let mut searched = Vec::<(usize, Vec<Neighbour>)>::with_capacity(nbnodes);
+    for chunk in points.chunks(EXTRACTION_CHUNKSIZE) {
+        let found : Vec<(usize, Vec<Neighbour>)> = chunk.par_iter()
+            .map(|point| (node_set.get_index_of(&point.get_origin_id()).unwrap(), hnsw.search(point.get_v(), knbn + 1, ef_search)))
+            .collect();
+        searched.extend(found);
+        stage.report_progress(searched.len(), points.len());
+    }
+    let mut neighbours = vec![Vec::<OutEdge<F>>::new(); nbnodes];
+    let mut minimum_nbng = knbn;
+    for (index, found) in searched {
+        let mut edges : Vec<OutEdge<F>> = found.iter()
+            .filter_map(|n| node_set.get_index_of(&n.d_id).map(|idx| (idx, n.distance)))
+            .filter(|(idx, _)| *idx != index)
+            .map(|(idx, distance)| OutEdge::<F>::new(idx, F::from_f32(distance).unwrap()))
+            .collect();
+        edges.sort_unstable_by(| a, b | a.partial_cmp(b).unwrap_or(Ordering::Less));
+        edges.truncate(knbn);
+        minimum_nbng = minimum_nbng.min(edges.len());
+        neighbours[index] = edges;
+    }
+    if minimum_nbng < knbn {
+        log::warn!("kgraph_from_hnsw_search, minimal number of neighbours found {}, asked {}, possibly increase ef_search", minimum_nbng, knbn);
+    }
+    stage.record_size("nb_nodes", nbnodes);
+    stage.record_size("nbng", knbn);
+    stage.record_size("ef_search", ef_search);
+    stage.record_size("min_nbng", minimum_nbng);
+    log::trace!("exiting kgraph_from_hnsw_search");
+    Ok(KGraph{max_nbng : knbn, nbnodes, neighbours, node_set})
+}   // end kgraph_from_hnsw_search
+
+
+
+/// initialization of a KGraph connecting each point to all points within distance radius (epsilon ball graph).  
+/// 
+/// Hnsw has no range query so each point does knn searches with a number of neighbours doubling
+/// until the farthest neighbour found is beyond radius or max_knbn neighbours are found.  
+/// Points can have no neighbour, so radius should be chosen from a quantile of neighbour distances
+/// (see [KGraphStat::get_radius_at_quantile]). The number of neighbours of a point is bounded by max_knbn.
+pub fn kgraph_from_hnsw_radius<T, D, F>(hnsw : &Hnsw<T,D>, radius : f32, max_knbn : usize, ef_search : usize) -> std::result::Result<KGraph<F>, AnnembedError> 
+    where   T : Clone + Send + Sync, 
+            D : Distance<T> + Send + Sync,
+            F : Float + FromPrimitive + Send + Sync {
+    //
+    log::debug!("entering kgraph_from_hnsw_radius, radius : {:.3e}, max_knbn : {}", radius, max_knbn);
+    if max_knbn == 0 || !(radius > 0.) {
+        log::error!("kgraph_from_hnsw_radius, radius {:.3e} and max_knbn {} must be > 0", radius, max_knbn);
+        return Err(AnnembedError::InvalidParameter(format!("radius {:.3e} and max_knbn {} must be > 0", radius, max_knbn)));
+    }
+    let point_indexation = hnsw.get_point_indexation();
+    check_edge_idx(point_indexation.get_nb_point())?;
+    let mut node_set = IndexSet::<DataId>::with_capacity(point_indexation.get_nb_point());
+    let mut points = Vec::with_capacity(point_indexation.get_nb_point());
+    let mut point_iter = point_indexation.into_iter();
+    while let Some(point) = point_iter.next() {
+        node_set.insert(point.get_origin_id());
+        points.push(point);
+    }
+    let nbnodes = node_set.len();
+    let start_knbn = (hnsw.get_max_nb_connection() as usize).min(max_knbn);
+    let searched : Vec<(usize, Vec<Neighbour>)> = points.par_iter()
+        .map(|point| {
+            let mut k = start_knbn;
+            loop {
+                // one more neighbour as the point itself is found by its search
+                let found = hnsw.search(point.get_v(), k + 1, ef_search.max(k + 1));
+                let beyond = found.last().map_or(true, |n| n.distance > radius);
+                if beyond || found.len() < k + 1 || k >= max_knbn {
+                    break (node_set.get_index_of(&point.get_origin_id()).unwrap(), found);
+                }
+                k = (2 * k).min(max_knbn);
+            }
+        })
+        .collect();
+    let mut neighbours = vec![Vec::<OutEdge<F>>::new(); nbnodes];
+    let mut max_nbng = 0;
+    let mut nb_isolated = 0;
+    let mut nb_capped = 0;
+    for (index, found) in searched {
+        let mut edges : Vec<OutEdge<F>> = found.iter()
+            .filter(|n| n.distance <= radius)
+            .filter_map(|n| node_set.get_index_of(&n.d_id).map(|idx| (idx, n.distance)))
+            .filter(|(idx, _)| *idx != index)
+            .map(|(idx, distance)| OutEdge::<F>::new(idx, F::from_f32(distance).unwrap()))
+            .collect();
+        edges.sort_unstable_by(| a, b | a.partial_cmp(b).unwrap_or(Ordering::Less));
+        if edges.len() >= max_knbn {
+            nb_capped += 1;
+        }
+        edges.truncate(max_knbn);
+        if edges.is_empty() {
+            nb_isolated += 1;
+        }
+        max_nbng = max_nbng.max(edges.len());
+        neighbours[index] = edges;
+    }
+    if nb_isolated > 0 {
+        log::warn!("kgraph_from_hnsw_radius, {} points without neighbour within radius {:.3e}", nb_isolated, radius);
+    }
+    if nb_capped > 0 {
+        log::info!("kgraph_from_hnsw_radius, {} points with neighbourhood truncated to {}", nb_capped, max_knbn);
+    }
+    log::trace!("exiting kgraph_from_hnsw_radius");
+    Ok(KGraph{max_nbng, nbnodes, neighbours, node_set})
+}   // end kgraph_from_hnsw_radius
 
 
 
@@ -448,12 +1066,13 @@ pub fn kgraph_from_hnsw_all<T, D, F>(hnsw : &Hnsw<T,D>, nbng : usize) -> std::re
     /// 
     /// The number of neighbours asked for must be smaller than for init_from_hnsw_all as we do inspect only 
     /// a fraction of the points and a fraction of the neighbourhood of each point. (all the focus is inside a layer)
-    pub fn kgraph_from_hnsw_layer<T, D, F>(hnsw : &Hnsw<T,D>, nbng : usize ,layer : usize) -> std::result::Result<KGraph<F>, usize> 
+    pub fn kgraph_from_hnsw_layer<T, D, F>(hnsw : &Hnsw<T,D>, nbng : usize ,layer : usize) -> std::result::Result<KGraph<F>, AnnembedError> 
         where   T : Clone + Send + Sync, 
                 D : Distance<T> + Send + Sync,
                 F : Float + FromPrimitive {
         //
         log::trace!("init_from_hnsw_layer");
+        check_edge_idx(hnsw.get_point_indexation().get_nb_point())?;
         //
         let max_nbng = nbng;
         let mut nb_point_below_nbng: usize = 0;
@@ -502,7 +1121,7 @@ pub fn kgraph_from_hnsw_all<T, D, F>(hnsw : &Hnsw<T,D>, nbng : usize) -> std::re
                         if n_p_id.0 as usize >= l {
                             // remap id. nodeset enforce reindexation from 0 to nbpoint
                             let (neighbour_idx, _) = node_set.insert_full(n_origin_id);
-                            vec_tmp.push(OutEdge::<F>{ node : neighbour_idx, weight : F::from_f32(neighbours_hnsw[m][j].distance).unwrap()});
+                            vec_tmp.push(OutEdge::<F>::new(neighbour_idx, F::from_f32(neighbours_hnsw[m][j].distance).unwrap()));
                         }
                     } // end of for j
                 } // end of for on m
@@ -540,8 +1159,7 @@ pub fn kgraph_from_hnsw_all<T, D, F>(hnsw : &Hnsw<T,D>, nbng : usize) -> std::re
                 nb_point_below_nbng,  mean_deficient_neighbour_size as f64/nb_point_below_nbng as f64);
         }
         if mean_nbng < nbng as f64 {
-            println!(" mean number of neighbours obtained : {:.3e}", mean_nbng);
-            println!(" possibly use hnsw.reset_keeping_pruned(true)");
+            log::warn!("mean number of neighbours obtained : {:.3e} below nbng {}, possibly use hnsw.reset_keeping_pruned(true)", mean_nbng, nbng);
         }
         //
         Ok(KGraph{max_nbng, nbnodes, neighbours, node_set})
@@ -610,7 +1228,7 @@ fn test_full_hnsw() {
     println!("\n\n test_serial nb_elem {:?}", nb_elem);
     //
     let data = gen_rand_data_f32(nb_elem, dim);
-    let data_with_id = data.iter().zip(0..data.len()).collect();
+    let data_with_id : Vec<_> = data.iter().zip(0..data.len()).collect();
 
     let ef_c = 50;
     let max_nb_connection = 50;
@@ -640,6 +1258,8 @@ fn test_full_hnsw() {
     let hubness = self::hubness::Hubness::new(&kgraph);
     let s3 = hubness.get_standard3m();
     log::info!(" estimation of hubness : {:.3e}", s3);
+    let thresholds = hubness.get_hubness_quantiles(&[0.5, 0.99]);
+    assert!(thresholds[0] <= thresholds[1]);
 
 }  // end of test_full_hnsw
 
@@ -699,6 +1319,243 @@ fn test_layer_hnsw() {
 
 
 
+#[test]
+fn test_connected_components() {
+    log_init_test();
+    // 2 chains 0 <- 1 <- 2 and 3 -> 4 plus an isolated node 5
+    let mut kgraph = KGraph::<f32>::new();
+    kgraph.nbnodes = 6;
+    kgraph.max_nbng = 1;
+    kgraph.node_set = (0..6).collect();
+    kgraph.neighbours = vec![vec![], vec![OutEdge::new(0, 1.)], vec![OutEdge::new(1, 1.)],
+                                vec![OutEdge::new(4, 1.)], vec![], vec![]];
+    let components = kgraph.get_connected_components();
+    assert_eq!(components, vec![0, 0, 0, 1, 1, 2]);
+    // subgraph of second component
+    let subgraph = kgraph.get_subgraph(&[3, 4]);
+    assert_eq!(subgraph.get_nb_nodes(), 2);
+    assert_eq!(subgraph.get_out_edges_by_idx(0)[0].get_node(), 1);
+    assert_eq!(*subgraph.get_data_id_from_idx(1).unwrap(), 4);
+} // end of test_connected_components
+
+
+#[test]
+fn test_filter_by_label() {
+    log_init_test();
+    // nodes 0,1 in class a, 2 in class b, 3 without label
+    let mut kgraph = KGraph::<f32>::new();
+    kgraph.nbnodes = 4;
+    kgraph.max_nbng = 3;
+    kgraph.node_set = [10, 11, 12, 13].into_iter().collect();
+    kgraph.neighbours = vec![vec![OutEdge::new(1, 1.), OutEdge::new(2, 1.), OutEdge::new(3, 1.)], vec![OutEdge::new(0, 1.)],
+                                vec![OutEdge::new(0, 2.)], vec![OutEdge::new(0, 1.)]];
+    let labels = DataLabels::from_pairs(vec![(10, "a"), (11, "a"), (12, "b")]);
+    let within = kgraph.filter_by_label(&labels, LabelFilter::SameLabel);
+    assert_eq!(within.get_out_edges_by_idx(0).len(), 1);
+    assert_eq!(within.get_out_edges_by_idx(0)[0].get_node(), 1);
+    assert!(within.get_out_edges_by_idx(2).is_empty());
+    assert!(within.get_out_edges_by_idx(3).is_empty());
+    // node 2 (single in its class) and node 3 (no label) lose all their edges and are reported
+    assert_eq!(within.get_isolated_nodes(), vec![2, 3]);
+    assert_eq!(within.get_nb_nodes(), 4);
+    let between = kgraph.filter_by_label(&labels, LabelFilter::OtherLabel);
+    assert_eq!(between.get_out_edges_by_idx(0)[0].get_node(), 2);
+    assert_eq!(between.get_out_edges_by_idx(2)[0].weight, 2.);
+    assert_eq!(between.get_isolated_nodes(), vec![1, 3]);
+    let purity = kgraph.get_label_purity(&labels);
+    assert_eq!(purity, vec![Some(0.5), Some(1.), Some(0.), None]);
+} // end of test_filter_by_label
+
+
+#[test]
+fn test_radius_graph() {
+    log_init_test();
+    // points on a line at abscissa 0, 1, 2, ..., 19
+    let data : Vec<Vec<f32>> = (0..20).map(|i| vec![i as f32, 0.]).collect();
+    let hnsw = Hnsw::<f32, DistL2>::new(4, data.len(), 4, 32, DistL2{});
+    for (i, v) in data.iter().enumerate() {
+        hnsw.insert((v, i));
+    }
+    let kgraph : KGraph<f32> = kgraph_from_hnsw_radius(&hnsw, 2.5, 50, 64).unwrap();
+    // interior points have 4 neighbours within radius
+    let idx = kgraph.get_idx_from_dataid(&10).unwrap();
+    let edges = kgraph.get_out_edges_by_idx(idx);
+    assert_eq!(edges.len(), 4);
+    assert!(edges.iter().all(|e| e.weight <= 2.5));
+    // end points have 2
+    let idx = kgraph.get_idx_from_dataid(&0).unwrap();
+    assert_eq!(kgraph.get_out_edges_by_idx(idx).len(), 2);
+    // cap on neighbourhood size
+    let capped : KGraph<f32> = kgraph_from_hnsw_radius(&hnsw, 100., 5, 64).unwrap();
+    assert_eq!(capped.get_max_nbng(), 5);
+} // end of test_radius_graph
+
+
+#[test]
+fn test_connect_components() {
+    log_init_test();
+    // 3 separated clusters of 10 points on a line
+    let data : Vec<Vec<f32>> = (0..30).map(|i| vec![(i / 10) as f32 * 100. + (i % 10) as f32, 0.]).collect();
+    let hnsw = Hnsw::<f32, DistL2>::new(4, data.len(), 4, 32, DistL2{});
+    for (i, v) in data.iter().enumerate() {
+        hnsw.insert((v, i));
+    }
+    let mut kgraph : KGraph<f32> = kgraph_from_hnsw_radius(&hnsw, 2.5, 10, 32).unwrap();
+    assert_eq!(kgraph.get_connected_components().iter().max(), Some(&2));
+    let nb_bridges = kgraph.connect_components(&hnsw, 10, 32).unwrap();
+    assert_eq!(nb_bridges, 2);
+    assert!(kgraph.get_connected_components().iter().all(|c| *c == 0));
+    // bridge from cluster end 9 to 10 has length 91
+    let idx = kgraph.get_idx_from_dataid(&9).unwrap();
+    let edges = kgraph.get_out_edges_by_idx(idx);
+    assert_eq!(edges.last().unwrap().weight, 91.);
+} // end of test_connect_components
+
+
+#[test]
+fn test_neighbour_iteration() {
+    log_init_test();
+    let mut kgraph = KGraph::<f32>::new();
+    kgraph.nbnodes = 3;
+    kgraph.max_nbng = 2;
+    kgraph.node_set = [10, 20, 30].into_iter().collect();
+    kgraph.neighbours = vec![vec![OutEdge::new(1, 0.5), OutEdge::new(2, 1.)], vec![OutEdge::new(0, 0.5)], vec![]];
+    assert_eq!(kgraph.neighbour_nodes(0).collect::<Vec<NodeIdx>>(), vec![1, 2]);
+    assert_eq!(kgraph.get_first_neighbour_dist(1), Some(0.5));
+    assert_eq!(kgraph.get_first_neighbour_dist(2), None);
+    let sizes : Vec<usize> = kgraph.iter_neighbourhoods().map(|(_, edges)| edges.len()).collect();
+    assert_eq!(sizes, vec![2, 1, 0]);
+    let par_sizes : Vec<(NodeIdx, usize)> = kgraph.par_iter_neighbourhoods().map(|(i, edges)| (i, edges.len())).collect();
+    assert_eq!(par_sizes, vec![(0, 2), (1, 1), (2, 0)]);
+} // end of test_neighbour_iteration
+
+
+#[test]
+fn test_extraction_progress() {
+    log_init_test();
+    let nb_elem = 1000;
+    let data = gen_rand_data_f32(nb_elem, 5);
+    let data_with_id : Vec<(&Vec<f32>, usize)> = data.iter().zip(0..data.len()).collect();
+    let mut hns = Hnsw::<f32, DistL2>::new(16, nb_elem, 16, 100, DistL2{});
+    hns.set_keeping_pruned(true);
+    hns.parallel_insert(&data_with_id);
+    let mut calls = Vec::<ExtractionProgress>::new();
+    let kgraph : KGraph<f32> = kgraph_from_hnsw_all_with_progress(&hns, 10, &mut |p : &ExtractionProgress| calls.push(*p)).unwrap();
+    let last = calls.last().unwrap();
+    assert_eq!(last.nb_processed, nb_elem);
+    assert_eq!(last.get_fraction(), 1.);
+    let nb_edges : usize = kgraph.iter_neighbourhoods().map(|(_, edges)| edges.len()).sum();
+    assert_eq!(last.nb_edges, nb_edges);
+    // same graph as without progress
+    let kgraph_ref : KGraph<f32> = kgraph_from_hnsw_all(&hns, 10).unwrap();
+    for (i, edges) in kgraph_ref.iter_neighbourhoods() {
+        let data_id = kgraph_ref.get_data_id_from_idx(i).unwrap();
+        let j = kgraph.get_idx_from_dataid(data_id).unwrap();
+        assert_eq!(edges.len(), kgraph.out_edges(j).len());
+    }
+} // end of test_extraction_progress
+
+
+#[test]
+fn test_snn_graph() {
+    log_init_test();
+    // 0,1,2 form a triangle, 3 is linked to 2 only
+    let mut kgraph = KGraph::<f32>::new();
+    kgraph.nbnodes = 4;
+    kgraph.max_nbng = 3;
+    kgraph.node_set = (0..4).collect();
+    kgraph.neighbours = vec![vec![OutEdge::new(1, 1.), OutEdge::new(2, 1.)], vec![OutEdge::new(0, 1.), OutEdge::new(2, 1.)],
+                                vec![OutEdge::new(0, 1.), OutEdge::new(1, 1.), OutEdge::new(3, 5.)], vec![OutEdge::new(2, 5.)]];
+    let snn = kgraph.to_snn_graph(0.);
+    // {0,1,2} and {0,1,2} : jaccard 1
+    assert_eq!(snn.get_out_edges_by_idx(0)[0].weight, 0.);
+    // {2,3} and {0,1,2,3} : jaccard 1/2
+    assert_eq!(snn.get_out_edges_by_idx(3)[0].weight, 0.5);
+    // edge 2 -> 3 is the last one after reweighting
+    assert_eq!(snn.get_out_edges_by_idx(2).last().unwrap().get_node(), 3);
+    let pruned = kgraph.to_snn_graph(0.6);
+    // 2 -> 3 is dropped, 3 -> 2 is the only edge of 3 and is kept
+    assert_eq!(pruned.get_out_edges_by_idx(2).len(), 2);
+    assert_eq!(pruned.get_out_edges_by_idx(3).len(), 1);
+    assert_eq!(pruned.get_out_edges_by_idx(3)[0].get_node(), 2);
+    assert!(pruned.get_isolated_nodes().is_empty());
+} // end of test_snn_graph
+
+
+#[test]
+fn test_prune_edges() {
+    log_init_test();
+    // edge lengths 1, 2, 3, 4 (node 0) and 10 (node 1)
+    let mut kgraph = KGraph::<f32>::new();
+    kgraph.nbnodes = 5;
+    kgraph.max_nbng = 4;
+    kgraph.node_set = (0..5).collect();
+    kgraph.neighbours = vec![vec![OutEdge::new(1, 1.), OutEdge::new(2, 2.), OutEdge::new(3, 3.), OutEdge::new(4, 4.)],
+                                vec![OutEdge::new(0, 10.)], vec![], vec![], vec![]];
+    let pruned = kgraph.prune_edges(EdgePruning::MaxDist(2.5));
+    assert_eq!(pruned.get_max_nbng(), 2);
+    assert_eq!(pruned.get_out_edges_by_idx(0).len(), 2);
+    // nearest neighbour is kept
+    assert_eq!(pruned.get_out_edges_by_idx(1).len(), 1);
+    // 0.6 quantile of 5 lengths is the third one
+    let pruned = kgraph.prune_edges(EdgePruning::Quantile(0.6));
+    assert_eq!(pruned.get_out_edges_by_idx(0).last().unwrap().weight, 3.);
+    assert_eq!(pruned.get_nb_nodes(), 5);
+} // end of test_prune_edges
+
+
+#[test]
+fn test_dump_reload() {
+    log_init_test();
+    //
+    let mut kgraph = KGraph::<f32>::new();
+    kgraph.nbnodes = 3;
+    kgraph.max_nbng = 2;
+    kgraph.node_set = [10, 20, 30].into_iter().collect();
+    kgraph.neighbours = vec![vec![OutEdge::new(1, 0.5), OutEdge::new(2, 1.)], vec![OutEdge::new(0, 0.5)], vec![OutEdge::new(1, 2.)]];
+    let path = std::env::temp_dir().join("annembed_test_kgraph.bin");
+    kgraph.dump(&path).unwrap();
+    let reloaded = KGraph::<f32>::reload(&path).unwrap();
+    assert_eq!(reloaded.get_nb_nodes(), 3);
+    assert_eq!(reloaded.get_max_nbng(), 2);
+    assert_eq!(reloaded.get_idx_from_dataid(&30), Some(2));
+    assert_eq!(reloaded.get_out_edges_by_idx(0)[1].get_node(), 2);
+    assert_eq!(reloaded.get_out_edges_by_idx(2)[0].weight, 2.);
+    // a dump with another edge index width is rejected
+    let mut bytes = std::fs::read(&path).unwrap();
+    bytes[..4].copy_from_slice(&(EDGE_IDX_BITS / 2).to_le_bytes());
+    std::fs::write(&path, bytes).unwrap();
+    assert!(KGraph::<f32>::reload(&path).is_err());
+    // a neighbour out of the nodes is rejected
+    kgraph.neighbours[1].push(OutEdge::new(3, 1.));
+    kgraph.dump(&path).unwrap();
+    assert!(matches!(KGraph::<f32>::reload(&path), Err(AnnembedError::InvalidParameter(_))));
+    let _ = std::fs::remove_file(&path);
+} // end of test_dump_reload
+
+
+#[test]
+fn test_from_knn_arrays() {
+    log_init_test();
+    // first neighbour is the point itself as returned by FAISS, 9 marks a missing neighbour
+    let indices = ndarray::arr2(&[[0, 2, 1], [1, 0, 9], [2, 1, 0]]);
+    let dists = ndarray::arr2(&[[0., 2., 1.], [0., 1., 0.], [0., 3., 2.]]);
+    let kgraph = KGraph::<f32>::from_knn_arrays(&indices, &dists).unwrap();
+    assert_eq!(kgraph.get_nb_nodes(), 3);
+    assert_eq!(kgraph.get_max_nbng(), 2);
+    assert_eq!(kgraph.get_data_id_from_idx(2), Some(&2));
+    // edges are sorted by distance
+    let nodes : Vec<NodeIdx> = kgraph.neighbour_nodes(0).collect();
+    assert_eq!(nodes, vec![1, 2]);
+    assert_eq!(kgraph.get_out_edges_by_idx(1).len(), 1);
+    assert_eq!(kgraph.get_out_edges_by_idx(2)[0].weight, 2.);
+    // shape mismatch and invalid distances
+    assert!(KGraph::<f32>::from_knn_arrays(&indices, &dists.slice(ndarray::s![.., ..2]).to_owned()).is_err());
+    let dists = ndarray::arr2(&[[0., -2., 1.], [0., 1., 0.], [0., 3., 2.]]);
+    assert!(KGraph::<f32>::from_knn_arrays(&indices, &dists).is_err());
+} // end of test_from_knn_arrays
+
+
 #[test]
 fn test_small_indexset() {
     let _ = env_logger::builder().is_test(true).try_init();
@@ -1,14 +1,26 @@
 //! This modules gathers everything coming from hnsw_rs.  
 //! It covers graph coming from hnsw (see [KGraph](kgraph::KGraph)) and projection on a smaller KGraph (see [KGraphProjection](kgproj::KGraphProjection))
 //! 
-//! It provides local intrinsic dimension and hubness estimations
+//! It provides intrinsic dimension (see [intrinsic_dim]) and hubness estimations.  
+//! A KGraph can also be built directly from data, without Hnsw, by NN-Descent (see [nndescent]).
 
 pub mod kgraph;
 
 pub use kgraph::kgraph_from_hnsw_all;
+pub use kgraph::{kgraph_from_hnsw_all_with_progress, ExtractionProgress};
+pub use kgraph::kgraph_from_hnsw_search;
+pub use kgraph::kgraph_from_hnsw_radius;
 
 pub mod kgproj;
 
+/// NN-Descent construction of a KGraph without Hnsw.
+pub mod nndescent;
+pub use nndescent::{kgraph_from_nndescent, NNDescentParams};
+
 pub mod toripserer;
 /// Hubness computations in the extracted Kgraph.
 pub mod hubness;
+/// TWO-NN and Levina-Bickel intrinsic dimension estimations from a KGraph.
+pub mod intrinsic_dim;
+/// Suggestion of Hnsw parameters from graph quality on a data sample.
+pub mod hnswtuning;
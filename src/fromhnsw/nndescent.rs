@@ -0,0 +1,324 @@
+//! NN-Descent construction of a KGraph directly from data, as an alternative to the extraction of a graph from a Hnsw.
+//!
+//! Implements Dong W., Moses C., Li K. Efficient k-nearest neighbor graph construction for generic similarity measures. WWW 2011.
+//!
+//! - Each node starts with knbn random neighbours. At each iteration the neighbours of a node and its reverse neighbours
+//!   (nodes having it as neighbour) are joined : every pair of them is compared, and each node of the pair is proposed to the other.
+//! - Only pairs involving a neighbour inserted since the last iteration (a new neighbour) are compared, and the number of new
+//!   neighbours joined is bounded by sample_rate * knbn.
+//! - Iterations stop when the number of neighbour lists updates falls below delta * nb_nodes * knbn.
+//!
+//! The graph has exactly knbn neighbours by node and no other structure than neighbour lists is kept, so memory is lower than with a Hnsw.
+//! Joins and updates run in parallel with rayon, node by node, on chunks of nodes.
+
+use indexmap::set::IndexSet;
+use ndarray::{ArrayBase, Data, Ix2};
+use num_traits::cast::FromPrimitive;
+use num_traits::Float;
+use rand::seq::SliceRandom;
+use rand::Rng;
+use rand_xoshiro::rand_core::SeedableRng;
+use rand_xoshiro::Xoshiro256PlusPlus;
+use rayon::prelude::*;
+
+use hnsw_rs::prelude::*;
+
+use super::kgraph::KGraph;
+use crate::error::AnnembedError;
+use crate::tools::nodeparam::{check_edge_idx, OutEdge};
+use crate::tools::stage::Stage;
+
+/// number of nodes joined before their proposals are applied
+const JOIN_CHUNKSIZE: usize = 10000;
+
+/// Parameters of NN-Descent
+#[derive(Copy, Clone, Debug)]
+pub struct NNDescentParams {
+    /// number of neighbours of each node
+    pub knbn: usize,
+    /// maximum number of iterations, default 10
+    pub max_iter: usize,
+    /// iterations stop when the fraction of neighbours updated is below delta, default 0.001
+    pub delta: f64,
+    /// fraction of new neighbours (and reverse neighbours) joined at each iteration, default 1.
+    pub sample_rate: f64,
+    /// seed of random initialization and sampling
+    pub seed: u64,
+}
+
+impl NNDescentParams {
+    pub fn new(knbn: usize) -> Self {
+        NNDescentParams {
+            knbn,
+            max_iter: 10,
+            delta: 0.001,
+            sample_rate: 1.,
+            seed: 2011,
+        }
+    }
+} // end of impl NNDescentParams
+
+// a neighbour in a list, new if inserted since it was last joined
+#[derive(Copy, Clone, Debug)]
+struct Candidate {
+    dist: f32,
+    node: u32,
+    new: bool,
+}
+
+// inserts node in list sorted by increasing distance and of size at most knbn. returns true if list was modified
+fn try_insert(list: &mut Vec<Candidate>, knbn: usize, node: u32, dist: f32) -> bool {
+    if list.len() >= knbn && dist >= list[knbn - 1].dist {
+        return false;
+    }
+    if list.iter().any(|c| c.node == node) {
+        return false;
+    }
+    let pos = list.partition_point(|c| c.dist <= dist);
+    list.insert(pos, Candidate { dist, node, new: true });
+    list.truncate(knbn);
+    true
+} // end of try_insert
+
+// a rng for node i at a given iteration and phase, so that parallel runs are reproducible
+fn get_rng(seed: u64, iter: usize, phase: usize, node: usize) -> Xoshiro256PlusPlus {
+    Xoshiro256PlusPlus::seed_from_u64(
+        seed ^ (node as u64).wrapping_mul(0x9E37_79B9_7F4A_7C15) ^ ((2 * iter + phase) as u64).wrapping_mul(0xC2B2_AE3D_27D4_EB4F),
+    )
+}
+
+/// builds the KGraph of params.knbn neighbours of rows of data by NN-Descent.
+/// Row i is node of DataId ids\[i\], or i if ids is None. Rows not in standard layout are copied once.
+pub fn kgraph_from_nndescent<T, D, F, S>(
+    data: &ArrayBase<S, Ix2>,
+    ids: Option<&[DataId]>,
+    params: &NNDescentParams,
+    distance: &D,
+) -> Result<KGraph<F>, AnnembedError>
+where
+    T: Clone + Send + Sync,
+    D: Distance<T> + Send + Sync,
+    F: Float + FromPrimitive + Send + Sync,
+    S: Data<Elem = T>,
+{
+    let dim = data.ncols();
+    if dim == 0 {
+        return Err(AnnembedError::InvalidParameter(String::from("nndescent, data dimension 0")));
+    }
+    let data = data.as_standard_layout();
+    let rows: Vec<&[T]> = data.as_slice().unwrap().chunks(dim).collect();
+    kgraph_from_nndescent_slices(&rows, ids, params, distance)
+} // end of kgraph_from_nndescent
+
+/// same as [kgraph_from_nndescent] with data given as slices
+pub fn kgraph_from_nndescent_slices<T, D, F>(
+    rows: &[&[T]],
+    ids: Option<&[DataId]>,
+    params: &NNDescentParams,
+    distance: &D,
+) -> Result<KGraph<F>, AnnembedError>
+where
+    T: Clone + Send + Sync,
+    D: Distance<T> + Send + Sync,
+    F: Float + FromPrimitive + Send + Sync,
+{
+    let nb_nodes = rows.len();
+    let knbn = params.knbn;
+    if knbn == 0 || nb_nodes <= knbn || nb_nodes >= u32::MAX as usize {
+        return Err(AnnembedError::InvalidParameter(format!(
+            "nndescent, knbn {} for {} points",
+            knbn, nb_nodes
+        )));
+    }
+    check_edge_idx(nb_nodes)?;
+    let node_set: IndexSet<DataId> = match ids {
+        Some(ids) => ids.iter().copied().collect(),
+        None => (0..nb_nodes).collect(),
+    };
+    if node_set.len() != nb_nodes {
+        return Err(AnnembedError::InvalidParameter(format!(
+            "nndescent, {} distinct ids for {} points",
+            node_set.len(),
+            nb_nodes
+        )));
+    }
+    let stage = Stage::enter("graph");
+    stage.record_size("nb_nodes", nb_nodes);
+    stage.record_size("nbng", knbn);
+    // random initialization
+    let mut lists: Vec<Vec<Candidate>> = (0..nb_nodes)
+        .into_par_iter()
+        .map(|i| {
+            let mut rng = get_rng(params.seed, 0, 0, i);
+            let mut list = Vec::<Candidate>::with_capacity(knbn);
+            while list.len() < knbn {
+                let j = rng.gen_range(0..nb_nodes);
+                if j != i {
+                    try_insert(&mut list, knbn, j as u32, distance.eval(rows[i], rows[j]));
+                }
+            }
+            list
+        })
+        .collect();
+    //
+    let sample_size = ((params.sample_rate * knbn as f64).ceil() as usize).max(1);
+    for iter in 0..params.max_iter {
+        // sample new neighbours, which become old, and collect old ones
+        let (new_fwd, old_fwd): (Vec<Vec<u32>>, Vec<Vec<u32>>) = lists
+            .par_iter_mut()
+            .enumerate()
+            .map(|(i, list)| {
+                let mut rng = get_rng(params.seed, iter, 0, i);
+                let old: Vec<u32> = list.iter().filter(|c| !c.new).map(|c| c.node).collect();
+                let mut new_pos: Vec<usize> = (0..list.len()).filter(|p| list[*p].new).collect();
+                new_pos.shuffle(&mut rng);
+                new_pos.truncate(sample_size);
+                let new = new_pos
+                    .iter()
+                    .map(|p| {
+                        list[*p].new = false;
+                        list[*p].node
+                    })
+                    .collect();
+                (new, old)
+            })
+            .unzip();
+        let mut new_rev = vec![Vec::<u32>::new(); nb_nodes];
+        let mut old_rev = vec![Vec::<u32>::new(); nb_nodes];
+        for i in 0..nb_nodes {
+            for u in &new_fwd[i] {
+                new_rev[*u as usize].push(i as u32);
+            }
+            for u in &old_fwd[i] {
+                old_rev[*u as usize].push(i as u32);
+            }
+        }
+        // local joins, proposals are applied after each chunk
+        let mut nb_updates = 0usize;
+        for start in (0..nb_nodes).step_by(JOIN_CHUNKSIZE) {
+            let end = (start + JOIN_CHUNKSIZE).min(nb_nodes);
+            let worst: Vec<f32> = lists.iter().map(|l| l[l.len() - 1].dist).collect();
+            let mut proposals: Vec<(u32, u32, f32)> = (start..end)
+                .into_par_iter()
+                .flat_map_iter(|v| {
+                    let mut rng = get_rng(params.seed, iter, 1, v);
+                    let mut join_list = |fwd: &Vec<u32>, rev: &Vec<u32>| {
+                        let mut rev = rev.clone();
+                        rev.shuffle(&mut rng);
+                        rev.truncate(sample_size);
+                        let mut joined = fwd.clone();
+                        joined.extend(rev);
+                        joined.sort_unstable();
+                        joined.dedup();
+                        joined
+                    };
+                    let new = join_list(&new_fwd[v], &new_rev[v]);
+                    let old = join_list(&old_fwd[v], &old_rev[v]);
+                    let mut proposals = Vec::<(u32, u32, f32)>::new();
+                    let mut propose = |u1: u32, u2: u32| {
+                        if u1 == u2 {
+                            return;
+                        }
+                        let d = distance.eval(rows[u1 as usize], rows[u2 as usize]);
+                        if d < worst[u1 as usize] {
+                            proposals.push((u1, u2, d));
+                        }
+                        if d < worst[u2 as usize] {
+                            proposals.push((u2, u1, d));
+                        }
+                    };
+                    for (a, u1) in new.iter().enumerate() {
+                        for u2 in &new[a + 1..] {
+                            propose(*u1, *u2);
+                        }
+                        for u2 in &old {
+                            propose(*u1, *u2);
+                        }
+                    }
+                    proposals.into_iter()
+                })
+                .collect();
+            proposals.par_sort_unstable_by_key(|p| p.0);
+            nb_updates += lists
+                .par_iter_mut()
+                .enumerate()
+                .map(|(i, list)| {
+                    let lo = proposals.partition_point(|p| (p.0 as usize) < i);
+                    let hi = proposals.partition_point(|p| (p.0 as usize) <= i);
+                    proposals[lo..hi]
+                        .iter()
+                        .filter(|p| try_insert(list, knbn, p.1, p.2))
+                        .count()
+                })
+                .sum::<usize>();
+        }
+        log::debug!("nndescent iteration {}, nb updates : {}", iter, nb_updates);
+        stage.report_progress(iter + 1, params.max_iter);
+        if (nb_updates as f64) < params.delta * (nb_nodes * knbn) as f64 {
+            log::info!("nndescent converged at iteration {}", iter);
+            break;
+        }
+    }
+    //
+    let neighbours: Vec<Vec<OutEdge<F>>> = lists
+        .iter()
+        .map(|list| {
+            list.iter()
+                .map(|c| OutEdge::new(c.node as usize, F::from_f32(c.dist).unwrap()))
+                .collect()
+        })
+        .collect();
+    Ok(KGraph {
+        max_nbng: knbn,
+        nbnodes: nb_nodes,
+        neighbours,
+        node_set,
+    })
+} // end of kgraph_from_nndescent_slices
+
+//=======================================================================
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use ndarray::Array2;
+
+    fn log_init_test() {
+        let _ = env_logger::builder().is_test(true).try_init();
+    }
+
+    #[test]
+    fn test_nndescent_recall() {
+        log_init_test();
+        let nb = 1000;
+        let knbn = 10;
+        let mut rng = Xoshiro256PlusPlus::seed_from_u64(4567);
+        let data = Array2::<f32>::from_shape_fn((nb, 5), |_| rng.gen::<f32>());
+        let ids: Vec<DataId> = (0..nb).map(|i| 10 + 2 * i).collect();
+        let params = NNDescentParams::new(knbn);
+        let kgraph: KGraph<f32> = kgraph_from_nndescent(&data, Some(&ids), &params, &DistL2 {}).unwrap();
+        assert_eq!(kgraph.get_nb_nodes(), nb);
+        assert_eq!(kgraph.get_data_id_from_idx(3), Some(&16));
+        // exactly knbn neighbours sorted by distance, and recall against brute force
+        let mut nb_found = 0;
+        for (i, edges) in kgraph.iter_neighbourhoods() {
+            assert_eq!(edges.len(), knbn);
+            assert!(edges.windows(2).all(|w| w[0].weight <= w[1].weight));
+            assert!(edges.iter().all(|e| e.get_node() != i));
+            let row = data.row(i);
+            let mut dists: Vec<(usize, f32)> = (0..nb)
+                .filter(|j| *j != i)
+                .map(|j| (j, DistL2 {}.eval(row.as_slice().unwrap(), data.row(j).as_slice().unwrap())))
+                .collect();
+            dists.sort_unstable_by(|a, b| a.1.total_cmp(&b.1));
+            nb_found += dists[..knbn]
+                .iter()
+                .filter(|(j, _)| edges.iter().any(|e| e.get_node() == *j))
+                .count();
+        }
+        let recall = nb_found as f64 / (nb * knbn) as f64;
+        log::info!("nndescent recall : {:.3e}", recall);
+        assert!(recall > 0.95);
+    } // end of test_nndescent_recall
+} // end of mod tests
@@ -9,7 +9,7 @@
 //!
 //!
 
-use anyhow::anyhow;
+use crate::error::AnnembedError;
 
 // to dump to ripser
 use std::fs::OpenOptions;
@@ -50,7 +50,7 @@ where
         knbn: usize,
         ef_c: usize,
         outbson: &String,
-    ) -> Result<(), anyhow::Error> {
+    ) -> Result<(), AnnembedError> {
         // search neighbours
         let point_indexation = self.hnsw.get_point_indexation();
         log::debug!("extract_neighbourhood asking for {} points", knbn);
@@ -90,25 +90,25 @@ where
         let res = serde::ser::Serialize::serialize::<bson::Serializer>(&v, serializer);
         if !res.is_ok() {
             log::error!("serialization of distance matrix failed");
-            return Err(anyhow!("serialization of distance matrix failed"));
+            return Err(AnnembedError::Io(std::io::Error::other(res.err().unwrap())));
         }
         let bson_v = res.unwrap();
         let path = Path::new(outbson);
         let fileres = OpenOptions::new().write(true).create(true).open(path);
-        let file;
-        if fileres.is_ok() {
-            file = fileres.unwrap();
-        } else {
-            log::error!("could not open file : {}", path.display());
-            return Err(anyhow!("could not open file : {}", path.display()));
-        }
+        let file = match fileres {
+            Ok(file) => file,
+            Err(e) => {
+                log::error!("could not open file : {}", path.display());
+                return Err(AnnembedError::Io(e));
+            }
+        };
         let mut doc = bson::Document::new();
         doc.insert("limat", bson_v);
         let bufwriter = BufWriter::new(file);
         let res = doc.to_writer(bufwriter);
         if res.is_err() {
             log::error!("dump of bson failed: {}", res.clone().err().unwrap());
-            return Err(anyhow!("dump of bson failed: {}", res.err().unwrap()));
+            return Err(AnnembedError::Io(std::io::Error::other(res.err().unwrap())));
         }
         //
         return Ok(());
@@ -132,14 +132,14 @@ where
         knbn: usize,
         layer: usize,
         fname: &String,
-    ) -> Result<(), anyhow::Error> {
+    ) -> Result<(), AnnembedError> {
         // construct a graph projection from layer
-        let graph_projection = KGraphProjection::<f32>::new(&self.hnsw, knbn, layer);
+        let graph_projection = KGraphProjection::<f32>::new(&self.hnsw, knbn, layer)?;
         let quant = graph_projection.get_projection_distance_quant();
         if quant.count() > 0 {
-            println!("\n\n projection distance from lower layers to upper layers");
-            println!(
-                "\n quantile at 0.05 : {:.2e} , 0.5 :  {:.2e}, 0.95 : {:.2e}, 0.99 : {:.2e}",
+            log::info!("projection distance from lower layers to upper layers");
+            log::info!(
+                "quantile at 0.05 : {:.2e} , 0.5 :  {:.2e}, 0.95 : {:.2e}, 0.99 : {:.2e}",
                 quant.query(0.05).unwrap().1,
                 quant.query(0.5).unwrap().1,
                 quant.query(0.95).unwrap().1,
@@ -149,11 +149,9 @@ where
         // testing output for ripser
         log::debug!("output fashion projection for ripser : {}", fname);
         let res = graph_projection.dump_sparse_mat_for_ripser(fname);
-        if res.is_err() {
+        if let Err(e) = res {
             log::error!("graph_projection dump_sparse_mat_for_ripser failed");
-            return Err(anyhow!(
-                "graph_projection dump_sparse_mat_for_ripser failed"
-            ));
+            return Err(e);
         }
         //
         return Ok(());
@@ -2,17 +2,33 @@
 
 use std::collections::HashMap;
 
-use ndarray::{Array1, Array2, Axis};
+use ndarray::{Array1, Array2, ArrayView1, ArrayView2, Axis};
+use rand_distr::{Distribution, StandardNormal};
+use rand_xoshiro::rand_core::SeedableRng;
+use rand_xoshiro::Xoshiro256PlusPlus;
+use serde::{Deserialize, Serialize};
 use sprs::{CsMat, TriMatBase};
 
-use ndarray_linalg::SVDDC;
+use ndarray_linalg::lobpcg::{lobpcg, LobpcgResult, TruncatedOrder};
+use ndarray_linalg::{Lapack, Scalar, SVDDC};
 
-use crate::tools::{nodeparam::*, svdapprox::*};
+use crate::error::AnnembedError;
+use crate::tools::{
+    nodeparam::*,
+    stage::{CancellationToken, Stage},
+    svdapprox::*,
+};
 
 const FULL_MAT_REPR: usize = 5000;
 
 const FULL_SVD_SIZE_LIMIT: usize = 5000;
 
+/// tolerance on residual norms of eigen pairs computed by [EigenSolver::Lobpcg]
+pub const LOBPCG_TOLERANCE: f32 = 1.0E-4;
+
+/// maximum number of iterations of [EigenSolver::Lobpcg]
+pub const LOBPCG_MAX_ITER: usize = 500;
+
 /// We use a normalized symetric laplacian to go to the svd.
 /// But we want the left eigenvectors of the normalized R(andom)W(alk) laplacian so we must keep track
 /// of degrees (rown L1 norms)
@@ -21,10 +37,16 @@ pub(crate) struct GraphLaplacian {
     sym_laplacian: MatRepr<f32>,
     // the vector giving D of the symtrized graph
     pub(crate) degrees: Array1<f32>,
-    //
-    _s: Option<Array1<f32>>,
-    //
-    _u: Option<Array2<f32>>,
+    // singular values of last svd, stored by do_svd
+    s: Option<Array1<f32>>,
+    // left singular vectors (eigenvectors of the symetric laplacian) of last svd, stored by do_svd
+    u: Option<Array2<f32>>,
+    // randomized svd used when full svd is not possible
+    approx_mode: ApproxSvdMode,
+    // seed of randomized svd and lobpcg initialization
+    seed: u64,
+    // token stopping the randomized svd
+    cancellation: Option<CancellationToken>,
 }
 
 impl GraphLaplacian {
@@ -32,11 +54,29 @@ impl GraphLaplacian {
         GraphLaplacian {
             sym_laplacian,
             degrees,
-            _s: None,
-            _u: None,
+            s: None,
+            u: None,
+            approx_mode: ApproxSvdMode::default(),
+            seed: DEFAULT_SVD_SEED,
+            cancellation: None,
         }
     } // end of new for GraphLaplacian
 
+    /// sets the randomized svd used for csr or large laplacians, see [ApproxSvdMode]
+    pub fn set_approx_svd(&mut self, mode: ApproxSvdMode) {
+        self.approx_mode = mode;
+    }
+
+    /// sets the seed of randomized svd and of lobpcg initial block. Defaults to [DEFAULT_SVD_SEED]
+    pub fn set_seed(&mut self, seed: u64) {
+        self.seed = seed;
+    }
+
+    /// sets a token stopping the randomized svd when cancelled
+    pub fn set_cancellation_token(&mut self, token: &CancellationToken) {
+        self.cancellation = Some(token.clone());
+    }
+
     #[inline]
     fn is_csr(&self) -> bool {
         self.sym_laplacian.is_csr()
@@ -46,79 +86,527 @@ impl GraphLaplacian {
         self.degrees.len()
     }
 
-    fn do_full_svd(&mut self) -> Result<SvdResult<f32>, String> {
-        //
-        log::info!("GraphLaplacian doing full svd");
-        let b = self.sym_laplacian.get_full_mut().unwrap();
-        log::trace!(
-            "GraphLaplacian ... size nbrow {} nbcol {} ",
-            b.shape()[0],
-            b.shape()[1]
+    pub fn do_svd(&mut self, asked_dim: usize) -> Result<SvdResult<f32>, AnnembedError> {
+        let nbrow = self.get_nbrow();
+        let stage = self.enter_svd_stage(asked_dim);
+        let svd_res = spectral_svd(
+            &mut self.sym_laplacian,
+            nbrow,
+            asked_dim,
+            &self.approx_mode,
+            self.seed,
+            self.cancellation.as_ref(),
         );
+        // we keep at most asked_dim eigen pairs as full svd returns all of them
+        if let Ok(res) = &svd_res {
+            self.store_eigen(res.get_sigma().as_ref(), res.get_u().as_ref(), asked_dim);
+            self.record_spectrum(&stage);
+        }
+        svd_res
+    } // end of init_from_sv_approx
+
+    /// svd computed in the asked precision, results are returned in f64.
+    /// With [SvdPrecision::F64] the laplacian is converted to f64 before svd, stored eigen pairs are f32 anyway.
+    pub fn do_svd_with_precision(
+        &mut self,
+        asked_dim: usize,
+        precision: SvdPrecision,
+    ) -> Result<SvdResult<f64>, AnnembedError> {
+        let to_f64 = |a: &Option<Array2<f32>>| a.as_ref().map(|a| a.mapv(|x| x as f64));
+        match precision {
+            SvdPrecision::F32 => {
+                let res = self.do_svd(asked_dim)?;
+                Ok(SvdResult {
+                    s: res.get_sigma().as_ref().map(|s| s.mapv(|x| x as f64)),
+                    u: to_f64(res.get_u()),
+                    vt: to_f64(res.get_vt()),
+                })
+            }
+            SvdPrecision::F64 => {
+                log::info!("GraphLaplacian doing svd in f64, csr : {}", self.is_csr());
+                let stage = self.enter_svd_stage(asked_dim);
+                let mut mat_f64 = match self.sym_laplacian.get_data() {
+                    MatMode::FULL(mat) => MatRepr::from_array2(mat.mapv(|x| x as f64)),
+                    MatMode::CSR(csr) => MatRepr::from_csrmat(csr.map(|x| *x as f64)),
+                };
+                let res = spectral_svd(
+                    &mut mat_f64,
+                    self.get_nbrow(),
+                    asked_dim,
+                    &self.approx_mode,
+                    self.seed,
+                    self.cancellation.as_ref(),
+                )?;
+                let s_f32 = res.get_sigma().as_ref().map(|s| s.mapv(|x| x as f32));
+                let u_f32 = res.get_u().as_ref().map(|u| u.mapv(|x| x as f32));
+                self.store_eigen(s_f32.as_ref(), u_f32.as_ref(), asked_dim);
+                self.record_spectrum(&stage);
+                Ok(res)
+            }
+        }
+    } // end of do_svd_with_precision
 
-        let slice_for_svd_opt = b.as_slice_mut();
-        if slice_for_svd_opt.is_none() {
-            println!("direct_svd Matrix cannot be transformed into a slice : not contiguous or not in standard order");
-            return Err(String::from("not contiguous or not in standard order"));
-        }
-        // use divide conquer (calls lapack gesdd), faster but could use svd (lapack gesvd)
-        log::trace!("direct_svd calling svddc driver");
-        let res_svd_b = b.svddc(JobSvd::Some);
-        if res_svd_b.is_err() {
-            log::info!("GraphLaplacian do_full_svd svddc failed");
-            return Err(String::from("GraphLaplacian svddc failed"));
+    /// computes asked_dim leading eigen pairs with the given solver, results are returned in f64 as in
+    /// [do_svd_with_precision](Self::do_svd_with_precision).  
+    /// With [EigenSolver::Lobpcg] singular values are replaced by eigenvalues of the symetric laplacian
+    /// (they coincide for the leading, positive, ones).
+    pub fn do_eigen(
+        &mut self,
+        asked_dim: usize,
+        precision: SvdPrecision,
+        solver: EigenSolver,
+    ) -> Result<SvdResult<f64>, AnnembedError> {
+        if solver == EigenSolver::Svd {
+            return self.do_svd_with_precision(asked_dim, precision);
+        }
+        let nbrow = self.get_nbrow();
+        // lobpcg works on blocks of 3 * asked_dim vectors, it is of no use on small matrices
+        if nbrow < 5 * asked_dim {
+            log::info!("GraphLaplacian nb nodes {} too small for lobpcg, using svd", nbrow);
+            return self.do_svd_with_precision(asked_dim, precision);
+        }
+        log::info!("GraphLaplacian doing lobpcg in {:?}, csr : {}", precision, self.is_csr());
+        let stage = self.enter_svd_stage(asked_dim);
+        let res = match precision {
+            SvdPrecision::F32 => {
+                let res = lobpcg_eigen(&self.sym_laplacian, asked_dim, self.seed)?;
+                let to_f64 = |a: &Option<Array2<f32>>| a.as_ref().map(|a| a.mapv(|x| x as f64));
+                SvdResult {
+                    s: res.get_sigma().as_ref().map(|s| s.mapv(|x| x as f64)),
+                    u: to_f64(res.get_u()),
+                    vt: to_f64(res.get_vt()),
+                }
+            }
+            SvdPrecision::F64 => {
+                let mat_f64 = match self.sym_laplacian.get_data() {
+                    MatMode::FULL(mat) => MatRepr::from_array2(mat.mapv(|x| x as f64)),
+                    MatMode::CSR(csr) => MatRepr::from_csrmat(csr.map(|x| *x as f64)),
+                };
+                lobpcg_eigen(&mat_f64, asked_dim, self.seed)?
+            }
         };
-        // we have to decode res and fill in SvdApprox fields.
-        // lax does encapsulte dgesvd (double) and sgesvd (single)  which returns U and Vt as vectors.
-        // We must reconstruct Array2 from slices.
-        // now we must match results
-        // u is (m,r) , vt must be (r, n) with m = self.data.shape()[0]  and n = self.data.shape()[1]
-        let res_svd_b = res_svd_b.unwrap();
-        // must truncate to asked dim
-        let s: Array1<f32> = res_svd_b.1;
-        //
-        Ok(SvdResult {
-            s: Some(s),
-            u: res_svd_b.0,
-            vt: None,
-        })
-    } // end of do_full_svd
-
-    /// do a partial approxlated svd
-    fn do_approx_svd(&mut self, asked_dim: usize) -> Result<SvdResult<f32>, String> {
-        assert!(asked_dim >= 2);
-        // get eigen values of normalized symetric lapalcian
-        //
-        //  switch to full or partial svd depending on csr representation and size
-        // csr implies approx svd.
-        log::info!(
-            "got laplacian, going to approximated svd ... asked_dim :  {}",
-            asked_dim
-        );
-        let mut svdapprox = SvdApprox::new(&self.sym_laplacian);
-        // TODO adjust epsil ?
-        // we need one dim more beccause we get rid of first eigen vector as in dmap, and for slowly decreasing spectrum RANK approx is
-        // better see Halko-Tropp
-        let svdmode = RangeApproxMode::RANK(RangeRank::new(20, 5));
-        let svd_res = svdapprox.direct_svd(svdmode);
-        log::trace!("exited svd");
-        if !svd_res.is_ok() {
-            println!("svd approximation failed");
-            std::panic!();
-        }
-        return svd_res;
-    } // end if do_approx_svd
-
-    pub fn do_svd(&mut self, asked_dim: usize) -> Result<SvdResult<f32>, String> {
-        if !self.is_csr() && self.get_nbrow() <= FULL_SVD_SIZE_LIMIT {
-            // try direct svd
-            self.do_full_svd()
-        } else {
-            self.do_approx_svd(asked_dim)
+        let s_f32 = res.get_sigma().as_ref().map(|s| s.mapv(|x| x as f32));
+        let u_f32 = res.get_u().as_ref().map(|u| u.mapv(|x| x as f32));
+        self.store_eigen(s_f32.as_ref(), u_f32.as_ref(), asked_dim);
+        self.record_spectrum(&stage);
+        Ok(res)
+    } // end of do_eigen
+
+    fn enter_svd_stage(&self, asked_dim: usize) -> Stage {
+        let stage = Stage::enter("svd");
+        stage.record_size("nb_nodes", self.get_nbrow());
+        stage.record_size("asked_dim", asked_dim);
+        stage.record("csr", if self.is_csr() { 1. } else { 0. });
+        stage
+    }
+
+    // spectral gap is between the first (stationary) eigenvalue and the next one
+    fn record_spectrum(&self, stage: &Stage) {
+        if let Some(s) = &self.s {
+            stage.record_size("nb_eigenvalues", s.len());
+            if s.len() >= 2 {
+                stage.record("spectral_gap", s[0] - s[1]);
+                stage.record("last_eigenvalue", s[s.len() - 1]);
+            }
         }
-    } // end of init_from_sv_approx
+    }
+
+    // keep at most asked_dim eigen pairs
+    fn store_eigen(&mut self, s: Option<&Array1<f32>>, u: Option<&Array2<f32>>, asked_dim: usize) {
+        self.s = s.map(|s| s.slice(ndarray::s![..asked_dim.min(s.len())]).to_owned());
+        self.u = u.map(|u| u.slice(ndarray::s![.., ..asked_dim.min(u.ncols())]).to_owned());
+    }
+
+    /// returns singular values stored by last call to do_svd
+    pub fn get_eigenvalues(&self) -> Option<&Array1<f32>> {
+        self.s.as_ref()
+    }
+
+    /// returns eigenvectors (in columns, by decreasing eigenvalues) stored by last call to do_svd (at most asked_dim of them)
+    pub fn get_eigenvectors(&self) -> Option<&Array2<f32>> {
+        self.u.as_ref()
+    }
+
+    /// product of the symetric matrix $D^{-1/2} G D^{-1/2}$ by a vector
+    pub(crate) fn sym_dot_vector(&self, vec: &ArrayView1<f32>) -> Array1<f32> {
+        self.sym_laplacian.mat_dot_vector(vec)
+    }
+
+    /// Laplacian score (He X., Cai D., Niyogi P. NIPS 2005) of a feature given by its value on each node :
+    /// $\tilde{f}^{t} L \tilde{f} / \tilde{f}^{t} D \tilde{f}$ with $L = D - S$ and $\tilde{f}$ the feature centered by the degree weighted mean.
+    /// It is computed from the symetric laplacian as $1 - g^{t} N g / g^{t} g$ with $g = D^{1/2} \tilde{f}$.  
+    /// Scores are in \[0, 2\], low scores are given to features that vary smoothly on the graph. A constant feature gets NaN.  
+    /// Returns an error if the feature has not one value by node.
+    pub fn get_laplacian_score(&self, feature: &[f64]) -> Result<f64, AnnembedError> {
+        if feature.len() != self.get_nbrow() {
+            log::error!(
+                "get_laplacian_score, feature has {} values for {} nodes",
+                feature.len(),
+                self.get_nbrow()
+            );
+            return Err(AnnembedError::InvalidParameter(format!(
+                "feature has {} values for {} nodes",
+                feature.len(),
+                self.get_nbrow()
+            )));
+        }
+        let total_degree: f64 = self.degrees.iter().map(|d| *d as f64).sum();
+        let mean = self.degrees.iter().zip(feature).map(|(d, f)| *d as f64 * f).sum::<f64>() / total_degree;
+        let g = Array1::<f32>::from_iter(self.degrees.iter().zip(feature).map(|(d, f)| ((*d as f64).sqrt() * (f - mean)) as f32));
+        let norm2: f64 = g.iter().map(|x| (*x as f64) * (*x as f64)).sum();
+        if norm2 <= 0. {
+            return Ok(f64::NAN);
+        }
+        let ng = self.sym_laplacian.mat_dot_vector(&g.view());
+        let quad: f64 = g.iter().zip(ng.iter()).map(|(x, y)| *x as f64 * *y as f64).sum();
+        Ok(1. - quad / norm2)
+    } // end of get_laplacian_score
 } // end of impl GraphLaplacian
 
+/// floating point type used in svd of the laplacian, independently of the type of input distances and output coordinates.
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum SvdPrecision {
+    /// svd in f32, less memory. This is the default
+    F32,
+    /// svd in f64, more precise eigenvectors for slowly decreasing spectra
+    F64,
+}
+
+/// randomized svd of laplacians too large for a full svd (or in csr representation), see [RangeApproxMode].  
+/// The rank of the range approximation should exceed the number of needed eigen pairs (embedding dimension + 1)
+/// by some oversampling.
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum ApproxSvdMode {
+    /// range of fixed rank refined by nbiter subspace (QR) iterations. Default is rank 20 with 5 iterations
+    Rank { rank: usize, nbiter: usize },
+    /// adaptative range finder stopping when the residual is under epsil or the range reaches max_rank,
+    /// step vectors (at least 2) are added at each iteration
+    Epsil { epsil: f64, step: usize, max_rank: usize },
+}
+
+impl Default for ApproxSvdMode {
+    fn default() -> Self {
+        ApproxSvdMode::Rank { rank: 20, nbiter: 5 }
+    }
+}
+
+impl ApproxSvdMode {
+    fn get_range_mode(&self) -> RangeApproxMode {
+        match *self {
+            ApproxSvdMode::Rank { rank, nbiter } => RangeApproxMode::RANK(RangeRank::new(rank, nbiter)),
+            ApproxSvdMode::Epsil { epsil, step, max_rank } => {
+                RangeApproxMode::EPSIL(RangePrecision::new(epsil, step, max_rank))
+            }
+        }
+    }
+} // end of impl ApproxSvdMode
+
+/// solver of the leading eigen pairs of the symetric laplacian
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum EigenSolver {
+    /// lapack svd of dense laplacians up to 5000 nodes, randomized range finder svd otherwise. This is the default
+    Svd,
+    /// LOBPCG iterative eigensolver computing only the largest eigenvalues, with tolerance [LOBPCG_TOLERANCE]
+    /// and at most [LOBPCG_MAX_ITER] iterations. Faster and more accurate for large csr laplacians.
+    Lobpcg,
+}
+
+// switch to full or partial svd depending on csr representation and size
+// csr implies approx svd.
+fn spectral_svd<F>(
+    mat: &mut MatRepr<F>,
+    nbrow: usize,
+    asked_dim: usize,
+    approx_mode: &ApproxSvdMode,
+    seed: u64,
+    cancellation: Option<&CancellationToken>,
+) -> Result<SvdResult<F>, AnnembedError>
+where
+    F: Send
+        + Sync
+        + num_traits::Float
+        + Lapack
+        + Scalar<Real = F>
+        + ndarray::ScalarOperand
+        + sprs::MulAcc
+        + for<'r> std::ops::MulAssign<&'r F>
+        + num_traits::MulAdd
+        + Default,
+{
+    if !mat.is_finite() {
+        log::error!("spectral_svd, laplacian has non finite values");
+        return Err(AnnembedError::NonFinite("laplacian"));
+    }
+    if !mat.is_csr() && nbrow <= FULL_SVD_SIZE_LIMIT {
+        // try direct svd
+        full_svd(mat.get_full_mut().unwrap())
+    } else {
+        approx_svd(mat, asked_dim, approx_mode, seed, cancellation)
+    }
+} // end of spectral_svd
+
+fn full_svd<F>(b: &mut Array2<F>) -> Result<SvdResult<F>, AnnembedError>
+where
+    F: Lapack + Scalar<Real = F>,
+{
+    //
+    log::info!("GraphLaplacian doing full svd");
+    log::trace!(
+        "GraphLaplacian ... size nbrow {} nbcol {} ",
+        b.shape()[0],
+        b.shape()[1]
+    );
+
+    let slice_for_svd_opt = b.as_slice_mut();
+    if slice_for_svd_opt.is_none() {
+        log::error!("direct_svd Matrix cannot be transformed into a slice : not contiguous or not in standard order");
+        return Err(AnnembedError::NotContiguous);
+    }
+    // use divide conquer (calls lapack gesdd), faster but could use svd (lapack gesvd)
+    log::trace!("direct_svd calling svddc driver");
+    let res_svd_b = b.svddc(JobSvd::Some).map_err(|e| {
+        log::error!("GraphLaplacian do_full_svd svddc failed");
+        AnnembedError::SvdFailed(e.to_string())
+    })?;
+    // we have to decode res and fill in SvdApprox fields.
+    // lax does encapsulte dgesvd (double) and sgesvd (single)  which returns U and Vt as vectors.
+    // We must reconstruct Array2 from slices.
+    // now we must match results
+    // u is (m,r) , vt must be (r, n) with m = self.data.shape()[0]  and n = self.data.shape()[1]
+    // must truncate to asked dim
+    let s: Array1<F> = res_svd_b.1;
+    //
+    Ok(SvdResult {
+        s: Some(s),
+        u: res_svd_b.0,
+        vt: res_svd_b.2,
+    })
+} // end of full_svd
+
+/// do a partial approxlated svd
+fn approx_svd<F>(
+    mat: &MatRepr<F>,
+    asked_dim: usize,
+    approx_mode: &ApproxSvdMode,
+    seed: u64,
+    cancellation: Option<&CancellationToken>,
+) -> Result<SvdResult<F>, AnnembedError>
+where
+    F: Send
+        + Sync
+        + num_traits::Float
+        + Lapack
+        + Scalar
+        + ndarray::ScalarOperand
+        + sprs::MulAcc
+        + for<'r> std::ops::MulAssign<&'r F>
+        + num_traits::MulAdd
+        + Default,
+{
+    if asked_dim < 2 {
+        return Err(AnnembedError::InvalidParameter(format!("approx_svd asked dim {} must be >= 2", asked_dim)));
+    }
+    // get eigen values of normalized symetric lapalcian
+    log::info!(
+        "got laplacian, going to approximated svd ... asked_dim :  {}, mode : {:?}",
+        asked_dim,
+        approx_mode
+    );
+    let mut svdapprox = SvdApprox::new(mat);
+    svdapprox.set_seed(seed);
+    if let Some(token) = cancellation {
+        svdapprox.set_cancellation_token(token);
+    }
+    // we need one dim more beccause we get rid of first eigen vector as in dmap, and for slowly decreasing spectrum RANK approx is
+    // better see Halko-Tropp
+    let svdmode = approx_mode.get_range_mode();
+    let svd_res = svdapprox.direct_svd(svdmode);
+    log::trace!("exited svd");
+    if let Err(e) = &svd_res {
+        log::error!("svd approximation failed : {}", e);
+    }
+    return svd_res;
+} // end if approx_svd
+
+// leading eigen pairs of the symetric laplacian N by lobpcg. N has its spectrum in [-1, 1], lobpcg needs a positive
+// operator so we iterate on N + I and shift eigenvalues back. Eigenvalues (clamped to 0.) are stored in place of singular values.
+fn lobpcg_eigen<F>(mat: &MatRepr<F>, asked_dim: usize, seed: u64) -> Result<SvdResult<F>, AnnembedError>
+where
+    F: Send
+        + Sync
+        + num_traits::Float
+        + Lapack
+        + Scalar<Real = F>
+        + ndarray::ScalarOperand
+        + sprs::MulAcc
+        + for<'r> std::ops::MulAssign<&'r F>
+        + Default,
+{
+    if !mat.is_finite() {
+        log::error!("lobpcg_eigen, laplacian has non finite values");
+        return Err(AnnembedError::NonFinite("laplacian"));
+    }
+    let nbrow = mat.shape()[0];
+    let operator = |x: ArrayView2<F>| -> Array2<F> {
+        let mut y = x.to_owned();
+        match mat.get_data() {
+            MatMode::FULL(full) => y += &full.dot(&x),
+            MatMode::CSR(csr) => sprs::prod::csr_mulacc_dense_rowmaj(csr.view(), x, y.view_mut()),
+        }
+        y
+    };
+    // seeded initial block to get reproducible embeddings
+    let mut rng = Xoshiro256PlusPlus::seed_from_u64(seed);
+    let init = Array2::<F>::from_shape_fn((nbrow, asked_dim), |_| {
+        let x: f64 = StandardNormal.sample(&mut rng);
+        F::from(x).unwrap()
+    });
+    let res = lobpcg(operator, init, |_| {}, None, LOBPCG_TOLERANCE, LOBPCG_MAX_ITER, TruncatedOrder::Largest);
+    let (lambdas, vectors, residuals) = match res {
+        LobpcgResult::Ok(lambdas, vectors, residuals) => (lambdas, vectors, residuals),
+        LobpcgResult::Err(lambdas, vectors, residuals, e) => {
+            log::warn!("lobpcg stopped on error {}, keeping best result", e);
+            (lambdas, vectors, residuals)
+        }
+        LobpcgResult::NoResult(e) => {
+            log::error!("lobpcg failed : {}", e);
+            return Err(AnnembedError::SvdFailed(e.to_string()));
+        }
+    };
+    let max_residual = residuals.iter().fold(F::zero(), |acc, r| acc.max(*r));
+    log::info!("lobpcg max residual norm : {:.2e}", max_residual);
+    if max_residual > F::from(LOBPCG_TOLERANCE).unwrap() {
+        log::warn!("lobpcg did not converge in {} iterations, max residual norm {:.2e}", LOBPCG_MAX_ITER, max_residual);
+    }
+    let s = lambdas.mapv(|l| (l - F::one()).max(F::zero()));
+    Ok(SvdResult {
+        s: Some(s),
+        u: Some(vectors),
+        vt: None,
+    })
+} // end of lobpcg_eigen
+
+/// Sparsification of the dense symetric kernel before svd.
+/// It is only applied in the dense regime (number of nodes below FULL_MAT_REPR); the kernel is then stored as a csr matrix
+/// and goes to the approximated svd.
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum KernelSparsification {
+    /// drop entries of the normalized kernel under the threshold
+    Threshold(f32),
+    /// keep for each node its k largest entries (k > 0). An entry is kept if it is among the k largest of its row or of its column
+    /// so that the kernel stays symetric.
+    TopK(usize),
+}
+
+impl KernelSparsification {
+    /// returns an error if the sparsification keeps no entry, i.e TopK(0)
+    pub fn check(&self) -> Result<(), AnnembedError> {
+        if let KernelSparsification::TopK(0) = self {
+            log::error!("KernelSparsification::TopK needs k > 0");
+            return Err(AnnembedError::InvalidParameter(String::from("kernel sparsification TopK needs k > 0")));
+        }
+        Ok(())
+    }
+} // end of impl KernelSparsification
+
+/// weight of self edges added to the symetrized kernel before normalization.
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum SelfEdgeWeight {
+    /// no self edge, diagonal is 0 as in t-sne, umap and LargeVis. This is the default.
+    None,
+    /// a small self edge weight (SELF_EDGE_EPSILON), just ensuring aperiodicity of the random walk
+    Epsilon,
+    /// self edge weight 1.
+    One,
+    /// self edge weight proportional to the (symetrized) degree of the node, with given factor
+    DegreeProportional(f32),
+}
+
+/// self edge weight used by [SelfEdgeWeight::Epsilon]
+pub const SELF_EDGE_EPSILON: f32 = 1.0E-3;
+
+impl SelfEdgeWeight {
+    // returns self edge weight of a node of degree (without self edge) degree
+    fn get_weight(&self, degree: f32) -> f32 {
+        match self {
+            SelfEdgeWeight::None => 0.,
+            SelfEdgeWeight::Epsilon => SELF_EDGE_EPSILON,
+            SelfEdgeWeight::One => 1.,
+            SelfEdgeWeight::DegreeProportional(factor) => factor * degree,
+        }
+    }
+} // end of impl SelfEdgeWeight
+
+/// options for kernel construction in [get_laplacian_with_options]
+#[derive(Copy, Clone, Debug)]
+pub(crate) struct KernelOptions {
+    /// optional sparsification of dense kernel
+    pub(crate) sparsification: Option<KernelSparsification>,
+    /// self edge weight
+    pub(crate) self_edge: SelfEdgeWeight,
+}
+
+impl Default for KernelOptions {
+    fn default() -> Self {
+        KernelOptions {
+            sparsification: None,
+            self_edge: SelfEdgeWeight::None,
+        }
+    }
+}
+
+// sparsify a dense symetric kernel, returns the csr matrix and logs the fraction of mass dropped
+fn sparsify_kernel(kernel: &Array2<f32>, sparsification: KernelSparsification) -> CsMat<f32> {
+    let nbnodes = kernel.nrows();
+    let keep: Box<dyn Fn(usize, usize) -> bool> = match sparsification {
+        KernelSparsification::Threshold(threshold) => Box::new(move |i, j| kernel[[i, j]] >= threshold),
+        KernelSparsification::TopK(k) => {
+            // value of k-th largest entry of each row
+            let kth_values: Vec<f32> = kernel
+                .rows()
+                .into_iter()
+                .map(|row| {
+                    let mut values: Vec<f32> = row.iter().copied().filter(|v| *v > 0.).collect();
+                    if values.len() <= k {
+                        return 0.;
+                    }
+                    values.sort_unstable_by(|a, b| b.total_cmp(a));
+                    values[k.max(1) - 1]
+                })
+                .collect();
+            Box::new(move |i, j| kernel[[i, j]] >= kth_values[i] || kernel[[i, j]] >= kth_values[j])
+        }
+    };
+    let mut rows = Vec::<usize>::new();
+    let mut cols = Vec::<usize>::new();
+    let mut values = Vec::<f32>::new();
+    let mut total_mass = 0f64;
+    let mut dropped_mass = 0f64;
+    for i in 0..nbnodes {
+        for j in 0..nbnodes {
+            let val = kernel[[i, j]];
+            if val == 0. {
+                continue;
+            }
+            total_mass += val as f64;
+            if keep(i, j) {
+                rows.push(i);
+                cols.push(j);
+                values.push(val);
+            } else {
+                dropped_mass += val as f64;
+            }
+        }
+    }
+    log::info!(
+        "kernel sparsification {:?}, kept {} entries, dropped mass fraction : {:.3e}",
+        sparsification,
+        values.len(),
+        if total_mass > 0. { dropped_mass / total_mass } else { 0. }
+    );
+    TriMatBase::<Vec<usize>, Vec<f32>>::from_triplets((nbnodes, nbnodes), rows, cols, values).to_csr()
+} // end of sparsify_kernel
+
 // the function computes a symetric laplacian graph for svd with transition probabilities taken from NodeParams
 // We will then need the lower non zero eigenvalues and eigen vectors.
 // The best justification for this is in Diffusion Maps.
@@ -130,6 +618,25 @@ impl GraphLaplacian {
 // See also Veerman A Primer on Laplacian Dynamics in Directed Graphs 2020 arxiv https://arxiv.org/abs/2002.02605
 
 pub(crate) fn get_laplacian(initial_space: &NodeParams) -> GraphLaplacian {
+    get_laplacian_with_options(initial_space, &KernelOptions::default())
+} // end of get_laplacian
+
+/// as [get_laplacian] but self edges are added and the dense kernel is sparsified as asked in options.  
+/// Assembly is deterministic: edges are processed in canonical (row, column) order so that for the same NodeParams
+/// the laplacian (values and summation order) is bitwise reproducible from run to run.
+pub(crate) fn get_laplacian_with_options(
+    initial_space: &NodeParams,
+    options: &KernelOptions,
+) -> GraphLaplacian {
+    let stage = Stage::enter("laplacian");
+    stage.record_size("nb_nodes", initial_space.get_nb_nodes());
+    stage.record_size("max_nbng", initial_space.get_max_nbng());
+    let laplacian = assemble_laplacian(initial_space, options);
+    stage.record("csr", if laplacian.is_csr() { 1. } else { 0. });
+    laplacian
+} // end of get_laplacian_with_options
+
+fn assemble_laplacian(initial_space: &NodeParams, options: &KernelOptions) -> GraphLaplacian {
     //
     log::debug!("in get_laplacian");
     //
@@ -148,7 +655,7 @@ pub(crate) fn get_laplacian(initial_space: &NodeParams) -> GraphLaplacian {
             // CAVEAT diagonal transition 0. or 1. ? Choose 0. as in t-sne umap LargeVis
             for j in 0..node_param.edges.len() {
                 let edge = node_param.edges[j];
-                transition_proba[[i, edge.node]] = edge.weight;
+                transition_proba[[i, edge.get_node()]] = edge.weight;
             } // end of for j
         } // end for i
         log::trace!("full matrix initialized");
@@ -156,6 +663,12 @@ pub(crate) fn get_laplacian(initial_space: &NodeParams) -> GraphLaplacian {
         // The UMAP formula (p_i+p_j - p_i *p_j) implies taking the non null proba when one proba is null,
         // so UMAP initialization is more packed.
         let mut symgraph = (&transition_proba + &transition_proba.view().t()) * 0.5;
+        if options.self_edge != SelfEdgeWeight::None {
+            let degrees = symgraph.sum_axis(Axis(1));
+            for i in 0..nbnodes {
+                symgraph[[i, i]] = options.self_edge.get_weight(degrees[i]);
+            }
+        }
         // now we go to the symetric laplacian D^-1/2 * G * D^-1/2 but get rid of the I - ...
         // cf Yan-Jordan Fast Approximate Spectral Clustering ACM-KDD 2009
         //  compute sum of row and renormalize. See Lafon-Keller-Coifman
@@ -169,6 +682,10 @@ pub(crate) fn get_laplacian(initial_space: &NodeParams) -> GraphLaplacian {
             }
         }
         //
+        if let Some(sparsification) = options.sparsification {
+            let csr_mat = sparsify_kernel(&symgraph, sparsification);
+            return GraphLaplacian::new(MatRepr::from_csrmat(csr_mat), diag);
+        }
         log::trace!("\n allocating full matrix laplacian");
         let laplacian = GraphLaplacian::new(MatRepr::from_array2(symgraph), diag);
         laplacian
@@ -181,16 +698,20 @@ pub(crate) fn get_laplacian(initial_space: &NodeParams) -> GraphLaplacian {
             let node_param = node_params.get_node_param(i);
             for j in 0..node_param.edges.len() {
                 let edge = node_param.edges[j];
-                edge_list.insert((i, edge.node), node_param.edges[j].weight);
+                edge_list.insert((i, edge.get_node()), node_param.edges[j].weight);
             } // end of for j
         }
-        // now we iter on the hasmap symetrize the graph, and insert in triplets transition_proba
+        // HashMap iteration order changes from run to run, so does floating point summation order in degrees
+        // and in csr duplicate entries. We sort edges to get a canonical assembly order.
+        let mut sorted_edges: Vec<(&(usize, usize), &f32)> = edge_list.iter().collect();
+        sorted_edges.sort_unstable_by_key(|(key, _)| **key);
+        // now we iter on sorted edges, symetrize the graph, and insert in triplets transition_proba
         let mut diagonal = Array1::<f32>::zeros(nbnodes);
         let mut rows = Vec::<usize>::with_capacity(nbnodes * 2 * max_nbng);
         let mut cols = Vec::<usize>::with_capacity(nbnodes * 2 * max_nbng);
         let mut values = Vec::<f32>::with_capacity(nbnodes * 2 * max_nbng);
 
-        for ((i, j), val) in edge_list.iter() {
+        for ((i, j), val) in sorted_edges.into_iter() {
             assert!(i != j);
             let sym_val;
             if let Some(t_val) = edge_list.get(&(*j, *i)) {
@@ -208,14 +729,21 @@ pub(crate) fn get_laplacian(initial_space: &NodeParams) -> GraphLaplacian {
             values.push(sym_val);
             diagonal[*j] += sym_val;
         }
+        if options.self_edge != SelfEdgeWeight::None {
+            for i in 0..nbnodes {
+                let weight = options.self_edge.get_weight(diagonal[i]);
+                rows.push(i);
+                cols.push(i);
+                values.push(weight);
+                diagonal[i] += weight;
+            }
+        }
         // as in FULL Representation we avoided the I diagnoal term which cancels anyway
-        // Now we reset non diagonal terms to D^-1/2 G D^-1/2  i.e  val[i,j]/(D[i]*D[j])^1/2
+        // Now we reset terms to D^-1/2 G D^-1/2  i.e  val[i,j]/(D[i]*D[j])^1/2
         for i in 0..rows.len() {
             let row = rows[i];
             let col = cols[i];
-            if row != col {
-                values[i] = values[i] / (diagonal[row] * diagonal[col]).sqrt();
-            }
+            values[i] = values[i] / (diagonal[row] * diagonal[col]).sqrt();
         }
         //
         log::trace!("allocating csr laplacian");
@@ -230,4 +758,441 @@ pub(crate) fn get_laplacian(initial_space: &NodeParams) -> GraphLaplacian {
         laplacian
     } // end case CsMat
       //
-} // end of get_laplacian
+} // end of assemble_laplacian
+
+/// builds the symetric laplacian D^-1/2 * G * D^-1/2 from a sparse affinity matrix G given by the user (no NodeParams).  
+/// The affinity is symetrized as (G + G^t)/2, its diagonal is kept and self edges of options are added.
+/// The kernel is dense (and sparsified as asked in options) under FULL_MAT_REPR nodes as in [get_laplacian_with_options].  
+/// Returns an error if the matrix is not square, has negative or non finite entries, or a node has a null degree.
+pub(crate) fn get_laplacian_from_affinity(
+    affinity: &CsMat<f32>,
+    options: &KernelOptions,
+) -> Result<GraphLaplacian, AnnembedError> {
+    let (nbrow, nbcol) = affinity.shape();
+    if nbrow != nbcol {
+        return Err(AnnembedError::InvalidParameter(format!(
+            "affinity matrix must be square, got shape ({}, {})",
+            nbrow, nbcol
+        )));
+    }
+    let stage = Stage::enter("laplacian");
+    stage.record_size("nb_nodes", nbrow);
+    stage.record_size("nnz", affinity.nnz());
+    let nbnodes = nbrow;
+    let mut rows = Vec::<usize>::with_capacity(2 * affinity.nnz() + nbnodes);
+    let mut cols = Vec::<usize>::with_capacity(2 * affinity.nnz() + nbnodes);
+    let mut values = Vec::<f32>::with_capacity(2 * affinity.nnz() + nbnodes);
+    let mut diagonal = Array1::<f32>::zeros(nbnodes);
+    for (val, (i, j)) in affinity.iter() {
+        if !val.is_finite() || *val < 0. {
+            return Err(AnnembedError::InvalidParameter(format!(
+                "affinity entry ({}, {}) must be finite and non negative, got {}",
+                i, j, val
+            )));
+        }
+        // duplicates are summed in csr conversion, so we symetrize by halves
+        let half = 0.5 * val;
+        rows.extend_from_slice(&[i, j]);
+        cols.extend_from_slice(&[j, i]);
+        values.extend_from_slice(&[half, half]);
+        diagonal[i] += half;
+        diagonal[j] += half;
+    }
+    if options.self_edge != SelfEdgeWeight::None {
+        for i in 0..nbnodes {
+            let weight = options.self_edge.get_weight(diagonal[i]);
+            rows.push(i);
+            cols.push(i);
+            values.push(weight);
+            diagonal[i] += weight;
+        }
+    }
+    if let Some(isolated) = diagonal.iter().position(|d| *d <= 0.) {
+        log::error!("get_laplacian_from_affinity, node {} has null degree", isolated);
+        return Err(AnnembedError::InvalidParameter(format!("node {} of affinity has null degree", isolated)));
+    }
+    for k in 0..rows.len() {
+        values[k] /= (diagonal[rows[k]] * diagonal[cols[k]]).sqrt();
+    }
+    let csr_mat: CsMat<f32> =
+        TriMatBase::<Vec<usize>, Vec<f32>>::from_triplets((nbnodes, nbnodes), rows, cols, values).to_csr();
+    let laplacian = if nbnodes <= FULL_MAT_REPR {
+        let kernel = csr_mat.to_dense();
+        match options.sparsification {
+            Some(sparsification) => GraphLaplacian::new(MatRepr::from_csrmat(sparsify_kernel(&kernel, sparsification)), diagonal),
+            None => GraphLaplacian::new(MatRepr::from_array2(kernel), diagonal),
+        }
+    } else {
+        GraphLaplacian::new(MatRepr::from_csrmat(csr_mat), diagonal)
+    };
+    stage.record("csr", if laplacian.is_csr() { 1. } else { 0. });
+    Ok(laplacian)
+} // end of get_laplacian_from_affinity
+
+
+
+/// Normalized directed transition matrix $D_{out}^{-1/2} P D_{in}^{-1/2}$ without symetrization.  
+/// Its left singular vectors relate to nodes as sources of edges and right singular vectors to nodes as targets,
+/// this is the bi-diffusion of the asymetric knn relation.  
+/// Out degrees are stored in the degrees field of the returned GraphLaplacian, in degrees are returned with it.
+/// Nodes never chosen as neighbours have a null in degree and a null column.
+pub(crate) fn get_directed_laplacian(initial_space: &NodeParams) -> (GraphLaplacian, Array1<f32>) {
+    log::debug!("in get_directed_laplacian");
+    let nbnodes = initial_space.get_nb_nodes();
+    let max_nbng = initial_space.get_max_nbng();
+    let mut out_degrees = Array1::<f32>::zeros(nbnodes);
+    let mut in_degrees = Array1::<f32>::zeros(nbnodes);
+    for i in 0..initial_space.params.len() {
+        for edge in &initial_space.get_node_param(i).edges {
+            out_degrees[i] += edge.weight;
+            in_degrees[edge.get_node()] += edge.weight;
+        }
+    }
+    let normalized = |i: usize, j: usize, w: f32| -> f32 {
+        let norm = (out_degrees[i] * in_degrees[j]).sqrt();
+        if norm > 0. {
+            w / norm
+        } else {
+            0.
+        }
+    };
+    let laplacian = if nbnodes <= FULL_MAT_REPR {
+        log::debug!("get_directed_laplacian using full matrix");
+        let mut mat = Array2::<f32>::zeros((nbnodes, nbnodes));
+        for i in 0..initial_space.params.len() {
+            for edge in &initial_space.get_node_param(i).edges {
+                mat[[i, edge.get_node()]] = normalized(i, edge.get_node(), edge.weight);
+            }
+        }
+        GraphLaplacian::new(MatRepr::from_array2(mat), out_degrees.clone())
+    } else {
+        log::debug!("get_directed_laplacian using csr matrix");
+        let mut rows = Vec::<usize>::with_capacity(nbnodes * max_nbng);
+        let mut cols = Vec::<usize>::with_capacity(nbnodes * max_nbng);
+        let mut values = Vec::<f32>::with_capacity(nbnodes * max_nbng);
+        for i in 0..initial_space.params.len() {
+            for edge in &initial_space.get_node_param(i).edges {
+                rows.push(i);
+                cols.push(edge.get_node());
+                values.push(normalized(i, edge.get_node(), edge.weight));
+            }
+        }
+        let trimat = TriMatBase::<Vec<usize>, Vec<f32>>::from_triplets((nbnodes, nbnodes), rows, cols, values);
+        let csr_mat: CsMat<f32> = trimat.to_csr();
+        GraphLaplacian::new(MatRepr::from_csrmat(csr_mat), out_degrees.clone())
+    };
+    (laplacian, in_degrees)
+} // end of get_directed_laplacian
+
+/// Magnetic laplacian of the directed graph of transition probabilities.
+///
+/// The hermitian operator $H = D^{-1/2} (W_{s} \odot e^{i \Theta}) D^{-1/2}$ encodes symetrized weights $W_{s} = (P + P^{t})/2$
+/// in moduli and edge direction in phases $\Theta_{ij} = 2 \pi q (p_{ij} - p_{ji})/(p_{ij} + p_{ji})$.  
+/// It is stored as the pair of real matrices (Re(H), Im(H)), Re(H) is symetric and Im(H) antisymetric.  
+/// See Fanuel, Alaiz, Suykens. Magnetic Eigenmaps for community detection in directed networks. Phys Rev E 2017.
+pub(crate) struct MagneticLaplacian {
+    // real part of H, full matrix
+    pub(crate) re: Array2<f32>,
+    // imaginary part of H, full matrix
+    pub(crate) im: Array2<f32>,
+    // degrees of the symetrized graph
+    pub(crate) degrees: Array1<f32>,
+}
+
+impl MagneticLaplacian {
+    /// The eigen decomposition of an hermitian (n,n) matrix Re + i Im is obtained from the real symetric (2n,2n) matrix
+    /// [[Re, -Im], [Im, Re]]. Each eigenvalue appears twice and an eigenvector (x,y) corresponds to the complex vector x + iy.
+    /// The returned GraphLaplacian has 2n rows and duplicated degrees.
+    pub(crate) fn to_real_symetric(&self) -> GraphLaplacian {
+        let n = self.degrees.len();
+        let mut block = Array2::<f32>::zeros((2 * n, 2 * n));
+        for i in 0..n {
+            for j in 0..n {
+                block[[i, j]] = self.re[[i, j]];
+                block[[i + n, j + n]] = self.re[[i, j]];
+                block[[i, j + n]] = -self.im[[i, j]];
+                block[[i + n, j]] = self.im[[i, j]];
+            }
+        }
+        let mut degrees = Array1::<f32>::zeros(2 * n);
+        for i in 0..n {
+            degrees[i] = self.degrees[i];
+            degrees[i + n] = self.degrees[i];
+        }
+        GraphLaplacian::new(MatRepr::from_array2(block), degrees)
+    } // end of to_real_symetric
+} // end of impl MagneticLaplacian
+
+/// computes the magnetic laplacian with charge q. q = 0 gives back the symetric laplacian of [get_laplacian].
+/// q = 0.25 gives maximal separation of purely one way edges.  
+/// As the decomposition runs on a matrix of size twice the number of nodes, we use full matrices and the
+/// number of nodes should stay moderate.
+pub(crate) fn get_magnetic_laplacian(initial_space: &NodeParams, q: f32) -> MagneticLaplacian {
+    log::debug!("in get_magnetic_laplacian, q : {:.2e}", q);
+    let nbnodes = initial_space.get_nb_nodes();
+    if 2 * nbnodes > FULL_MAT_REPR {
+        log::warn!("get_magnetic_laplacian, large number of nodes {}, magnetic laplacian uses full matrices", nbnodes);
+    }
+    let mut transition_proba = Array2::<f32>::zeros((nbnodes, nbnodes));
+    for i in 0..initial_space.params.len() {
+        let node_param = initial_space.get_node_param(i);
+        for edge in &node_param.edges {
+            transition_proba[[i, edge.get_node()]] = edge.weight;
+        }
+    }
+    let mut re = Array2::<f32>::zeros((nbnodes, nbnodes));
+    let mut im = Array2::<f32>::zeros((nbnodes, nbnodes));
+    let two_pi_q = 2. * std::f32::consts::PI * q;
+    for i in 0..nbnodes {
+        for j in 0..nbnodes {
+            let (p_ij, p_ji) = (transition_proba[[i, j]], transition_proba[[j, i]]);
+            let sum = p_ij + p_ji;
+            if sum > 0. {
+                let theta = two_pi_q * (p_ij - p_ji) / sum;
+                re[[i, j]] = 0.5 * sum * theta.cos();
+                im[[i, j]] = 0.5 * sum * theta.sin();
+            }
+        }
+    }
+    // degrees are those of the symetrized graph, moduli of H entries
+    let degrees: Array1<f32> = ((&transition_proba + &transition_proba.t()) * 0.5).sum_axis(Axis(1));
+    for i in 0..nbnodes {
+        for j in 0..nbnodes {
+            let norm = (degrees[i] * degrees[j]).sqrt();
+            if norm > 0. {
+                re[[i, j]] /= norm;
+                im[[i, j]] /= norm;
+            }
+        }
+    }
+    MagneticLaplacian { re, im, degrees }
+} // end of get_magnetic_laplacian
+
+//========================================================================================
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    fn log_init_test() {
+        let _ = env_logger::builder().is_test(true).try_init();
+    }
+
+    #[test]
+    fn test_laplacian_score() {
+        log_init_test();
+        // a chain 0 - 1 - ... - 19
+        let nb: usize = 20;
+        let params = (0..nb)
+            .map(|i| {
+                let edges = [i.checked_sub(1), Some(i + 1).filter(|j| *j < nb)]
+                    .iter()
+                    .flatten()
+                    .map(|j| OutEdge::new(*j, 1.))
+                    .collect();
+                NodeParam::new(1., edges)
+            })
+            .collect();
+        let laplacian = get_laplacian(&NodeParams::new(params, 2));
+        let smooth: Vec<f64> = (0..nb).map(|i| i as f64).collect();
+        let alternating: Vec<f64> = (0..nb).map(|i| (i % 2) as f64).collect();
+        let smooth_score = laplacian.get_laplacian_score(&smooth).unwrap();
+        let alternating_score = laplacian.get_laplacian_score(&alternating).unwrap();
+        log::info!("laplacian scores smooth : {:.3e}, alternating : {:.3e}", smooth_score, alternating_score);
+        assert!(smooth_score < 0.05);
+        assert!(alternating_score > 1.9);
+        assert!(laplacian.get_laplacian_score(&vec![1.; nb]).unwrap().is_nan());
+        // one value by node is needed
+        let res = laplacian.get_laplacian_score(&smooth[1..]);
+        assert!(matches!(res, Err(AnnembedError::InvalidParameter(_))));
+    } // end of test_laplacian_score
+
+    // a directed 3-cycle 0 -> 1 -> 2 -> 0
+    fn directed_cycle() -> NodeParams {
+        let params = (0..3)
+            .map(|i| NodeParam::new(1., vec![OutEdge::new((i + 1) % 3, 1.)]))
+            .collect();
+        NodeParams::new(params, 1)
+    }
+
+    #[test]
+    fn test_magnetic_laplacian() {
+        log_init_test();
+        let nodeparams = directed_cycle();
+        let magnetic = get_magnetic_laplacian(&nodeparams, 0.25);
+        for i in 0..3 {
+            for j in 0..3 {
+                assert!((magnetic.re[[i, j]] - magnetic.re[[j, i]]).abs() < 1.0E-6);
+                assert!((magnetic.im[[i, j]] + magnetic.im[[j, i]]).abs() < 1.0E-6);
+            }
+        }
+        // one way edges with q = 0.25 have a pure imaginary weight
+        assert!(magnetic.re[[0, 1]].abs() < 1.0E-6);
+        assert!(magnetic.im[[0, 1]] > 0.);
+        let mut real_sym = magnetic.to_real_symetric();
+        let block = real_sym.sym_laplacian.get_full_mut().unwrap();
+        assert_eq!(block.dim(), (6, 6));
+        assert!(block.iter().zip(block.t().iter()).all(|(a, b)| (a - b).abs() < 1.0E-6));
+    } // end of test_magnetic_laplacian
+
+    #[test]
+    fn test_directed_laplacian() {
+        log_init_test();
+        let nodeparams = directed_cycle();
+        let (mut laplacian, in_degrees) = get_directed_laplacian(&nodeparams);
+        assert!(in_degrees.iter().all(|d| (d - 1.).abs() < 1.0E-6));
+        let mat = laplacian.sym_laplacian.get_full_mut().unwrap();
+        // no symetrization
+        assert!((mat[[0, 1]] - 1.).abs() < 1.0E-6);
+        assert_eq!(mat[[1, 0]], 0.);
+    } // end of test_directed_laplacian
+
+    #[test]
+    fn test_kernel_sparsification() {
+        log_init_test();
+        let kernel = ndarray::arr2(&[[0., 0.5, 0.1], [0.5, 0., 0.05], [0.1, 0.05, 0.]]);
+        let csr = sparsify_kernel(&kernel, KernelSparsification::Threshold(0.08));
+        assert_eq!(csr.nnz(), 4);
+        // each row keeps its largest entry, (0,2) is kept as largest of row 2
+        let csr = sparsify_kernel(&kernel, KernelSparsification::TopK(1));
+        assert_eq!(csr.nnz(), 4);
+        assert!(csr.get(0, 2).is_some());
+        assert!(csr.get(1, 2).is_none());
+        assert!(KernelSparsification::TopK(1).check().is_ok());
+        assert!(matches!(KernelSparsification::TopK(0).check(), Err(AnnembedError::InvalidParameter(_))));
+    } // end of test_kernel_sparsification
+
+    #[test]
+    fn test_self_edge() {
+        log_init_test();
+        let nodeparams = directed_cycle();
+        let options = KernelOptions {
+            sparsification: None,
+            self_edge: SelfEdgeWeight::One,
+        };
+        let mut laplacian = get_laplacian_with_options(&nodeparams, &options);
+        // symetrized degree is 1, with self edge 2
+        assert!(laplacian.degrees.iter().all(|d| (d - 2.).abs() < 1.0E-6));
+        let mat = laplacian.sym_laplacian.get_full_mut().unwrap();
+        assert!((mat[[0, 0]] - 0.5).abs() < 1.0E-6);
+        assert!((mat[[0, 1]] - 0.25).abs() < 1.0E-6);
+    } // end of test_self_edge
+
+    #[test]
+    fn test_csr_assembly_deterministic() {
+        log_init_test();
+        // a ring over more nodes than FULL_MAT_REPR to get csr assembly, with asymetric weights
+        let nbnodes = FULL_MAT_REPR + 10;
+        let params: Vec<NodeParam> = (0..nbnodes)
+            .map(|i| {
+                let edges = vec![
+                    OutEdge::new((i + 1) % nbnodes, 0.6 + 1.0E-5 * (i % 7) as f32),
+                    OutEdge::new((i + nbnodes - 1) % nbnodes, 0.4 - 1.0E-5 * (i % 3) as f32),
+                ];
+                NodeParam::new(1., edges)
+            })
+            .collect();
+        let nodeparams = NodeParams::new(params, 2);
+        let first = get_laplacian(&nodeparams);
+        let second = get_laplacian(&nodeparams);
+        assert!(first.is_csr());
+        assert_eq!(first.degrees, second.degrees);
+        let (first_csr, second_csr) = match (first.sym_laplacian.get_data(), second.sym_laplacian.get_data()) {
+            (MatMode::CSR(a), MatMode::CSR(b)) => (a, b),
+            _ => panic!("expected csr laplacian"),
+        };
+        assert_eq!(first_csr.indptr(), second_csr.indptr());
+        assert_eq!(first_csr.indices(), second_csr.indices());
+        assert_eq!(first_csr.data(), second_csr.data());
+    } // end of test_csr_assembly_deterministic
+
+    #[test]
+    fn test_laplacian_from_affinity() {
+        log_init_test();
+        // the affinity of the symetrized directed cycle gives the same laplacian as its NodeParams
+        let nodeparams = directed_cycle();
+        let options = KernelOptions {
+            sparsification: None,
+            self_edge: SelfEdgeWeight::One,
+        };
+        let mut expected = get_laplacian_with_options(&nodeparams, &options);
+        let affinity: CsMat<f32> = TriMatBase::<Vec<usize>, Vec<f32>>::from_triplets(
+            (3, 3),
+            vec![0, 1, 2],
+            vec![1, 2, 0],
+            vec![1., 1., 1.],
+        )
+        .to_csr();
+        let mut laplacian = get_laplacian_from_affinity(&affinity, &options).unwrap();
+        assert_eq!(laplacian.degrees, expected.degrees);
+        let mat = laplacian.sym_laplacian.get_full_mut().unwrap();
+        let expected_mat = expected.sym_laplacian.get_full_mut().unwrap();
+        assert!(mat.iter().zip(expected_mat.iter()).all(|(a, b)| (a - b).abs() < 1.0E-6));
+        // isolated node, negative entry and non square matrix are rejected
+        let isolated: CsMat<f32> =
+            TriMatBase::<Vec<usize>, Vec<f32>>::from_triplets((3, 3), vec![0], vec![1], vec![1.]).to_csr();
+        assert!(get_laplacian_from_affinity(&isolated, &KernelOptions::default()).is_err());
+        let negative: CsMat<f32> =
+            TriMatBase::<Vec<usize>, Vec<f32>>::from_triplets((2, 2), vec![0], vec![1], vec![-1.]).to_csr();
+        assert!(get_laplacian_from_affinity(&negative, &KernelOptions::default()).is_err());
+        let rectangular: CsMat<f32> =
+            TriMatBase::<Vec<usize>, Vec<f32>>::from_triplets((2, 3), vec![0], vec![1], vec![1.]).to_csr();
+        assert!(get_laplacian_from_affinity(&rectangular, &KernelOptions::default()).is_err());
+    } // end of test_laplacian_from_affinity
+
+    #[test]
+    fn test_lobpcg_solver() {
+        log_init_test();
+        // a ring, eigenvalues of the symetric laplacian are cos(2 pi k / n), each one twice but the first
+        let nbnodes = 60;
+        let params: Vec<NodeParam> = (0..nbnodes)
+            .map(|i| {
+                let edges = vec![OutEdge::new((i + 1) % nbnodes, 0.5), OutEdge::new((i + nbnodes - 1) % nbnodes, 0.5)];
+                NodeParam::new(1., edges)
+            })
+            .collect();
+        let nodeparams = NodeParams::new(params, 2);
+        let asked_dim = 5;
+        let mut laplacian = get_laplacian(&nodeparams);
+        let res = laplacian
+            .do_eigen(asked_dim, SvdPrecision::F64, EigenSolver::Lobpcg)
+            .unwrap();
+        let lambdas = res.get_sigma().as_ref().unwrap();
+        assert_eq!(lambdas.len(), asked_dim);
+        let expected = [0, 1, 1, 2, 2].map(|k| (2. * std::f64::consts::PI * k as f64 / nbnodes as f64).cos());
+        for (l, e) in lambdas.iter().zip(expected.iter()) {
+            assert!((l - e).abs() < 1.0E-4, "lobpcg eigenvalue {} expected {}", l, e);
+        }
+        // eigenvectors are orthonormal
+        let u = res.get_u().as_ref().unwrap();
+        let gram = u.t().dot(u);
+        assert!((gram - Array2::<f64>::eye(asked_dim)).iter().all(|x| x.abs() < 1.0E-3));
+        assert_eq!(laplacian.get_eigenvalues().unwrap().len(), asked_dim);
+    } // end of test_lobpcg_solver
+
+    #[test]
+    fn test_approx_svd_mode() {
+        log_init_test();
+        // a ring over more nodes than FULL_MAT_REPR to get a csr laplacian and the randomized svd
+        let nbnodes = FULL_MAT_REPR + 10;
+        let params: Vec<NodeParam> = (0..nbnodes)
+            .map(|i| {
+                let edges = vec![OutEdge::new((i + 1) % nbnodes, 0.5), OutEdge::new((i + nbnodes - 1) % nbnodes, 0.5)];
+                NodeParam::new(1., edges)
+            })
+            .collect();
+        let nodeparams = NodeParams::new(params, 2);
+        let mut laplacian = get_laplacian(&nodeparams);
+        assert!(laplacian.is_csr());
+        laplacian.set_approx_svd(ApproxSvdMode::Rank { rank: 10, nbiter: 3 });
+        let res = laplacian.do_svd(5).unwrap();
+        let lambdas = res.get_sigma().as_ref().unwrap();
+        assert!(lambdas.len() <= 10);
+        assert!((lambdas[0] - 1.).abs() < 1.0E-3);
+        assert!(matches!(
+            ApproxSvdMode::Epsil { epsil: 0.1, step: 5, max_rank: 20 }.get_range_mode(),
+            RangeApproxMode::EPSIL(_)
+        ));
+    } // end of test_approx_svd_mode
+} // end of mod tests
@@ -156,7 +156,7 @@ where
         let mut edge_list = Vec::<(u32, u32, F)>::with_capacity(max_nbng * nbnodes);
         for i in 0..nbnodes {
             for edge in &neighboourhood_info[i] {
-                edge_list.push((i as u32, edge.node as u32, edge.weight));
+                edge_list.push((i as u32, edge.get_node() as u32, edge.weight));
             }
         }
         let mst_edge_iter = kruskal(&edge_list);
@@ -0,0 +1,549 @@
+//! Semi-supervised label propagation on a KGraph.
+//!
+//! Implements the harmonic function classifier of Zhu X., Ghahramani Z., Lafferty J. Semi-Supervised Learning
+//! Using Gaussian Fields and Harmonic Functions. ICML 2003.
+//!
+//! - Edges of the KGraph are weighted by the self-tuned affinities $\exp(-d_{ij}^{2}/(\sigma_{i} \sigma_{j}))$
+//!   (see [spectralclust](crate::spectralclust)) and the graph is symmetrized by taking the mean of the two directions.
+//! - With [PropagationKernel::Diffusion] the weights are renormalized by the degrees $w_{ij}/(q_{i} q_{j})$ as in the
+//!   diffusion maps operator with $\alpha = 1$ (Coifman-Lafon), so that propagation is not biased by the sampling density.
+//! - Labeled nodes are clamped to their class indicator and each unlabeled node iteratively receives the weighted mean
+//!   of the class distributions of its neighbours, which converges to the harmonic solution.
+//! - The predicted label of a node is the class of maximal probability and this probability is its confidence.
+//!   Nodes in connected components without any labeled node get no label and a null confidence.
+//!
+//! The normalized laplacian kept by a diffusion maps embedding (see
+//! [DiffusionParams::set_keep_laplacian](crate::diffmaps::DiffusionParams::set_keep_laplacian)) can also be used directly, with the harmonic function or the
+//! local and global consistency method of Zhou D., Bousquet O., Lal T.N., Weston J., Schölkopf B. Learning with Local and
+//! Global Consistency. NIPS 2004. See [PropagationScheme] and
+//! [DiffusionMaps::label_propagation](crate::diffmaps::DiffusionMaps::label_propagation).
+//!
+
+use std::collections::HashMap;
+
+use hnsw_rs::prelude::DataId;
+use ndarray::{Array1, Array2};
+use num_traits::cast::FromPrimitive;
+use num_traits::Float;
+use rayon::prelude::*;
+use sprs::{CsMat, TriMatBase};
+
+use crate::error::AnnembedError;
+use crate::fromhnsw::kgraph::KGraph;
+use crate::graphlaplace::GraphLaplacian;
+use crate::spectralclust::get_self_tuning_affinity;
+use crate::tools::io::DataLabels;
+use crate::tools::nodeparam::*;
+
+/// default rank of neighbour giving local scale
+const DEFAULT_KNN_SCALE: usize = 7;
+
+/// default maximum number of propagation iterations
+const DEFAULT_MAX_ITER: usize = 1000;
+
+/// default tolerance on the maximal change of a class probability between 2 iterations
+const DEFAULT_EPSIL: f64 = 1.0E-6;
+
+/// Weighting of the graph used for propagation
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum PropagationKernel {
+    /// self-tuned affinities
+    SelfTuning,
+    /// self-tuned affinities renormalized by degrees as in the diffusion operator
+    Diffusion,
+}
+
+/// Propagation scheme on the normalized laplacian of a diffusion maps embedding, with S = $D^{-1/2} G D^{-1/2}$
+/// the normalized kernel and Y the class indicators of labeled nodes.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum PropagationScheme {
+    /// harmonic function : labeled nodes are clamped and the others get the degree weighted mean of their neighbours
+    Harmonic,
+    /// local and global consistency of Zhou et al. : $F = (1 - \alpha) (I - \alpha S)^{-1} Y$ with alpha in \]0, 1\[
+    /// (0.99 in the paper) computed by iterating $F \leftarrow \alpha S F + (1 - \alpha) Y$, rows of F being normalized at the end.
+    /// Labeled nodes are not clamped so that some label noise can be corrected. Convergence is slow for alpha near 1.
+    Consistency { alpha: f64 },
+}
+
+/// Parameters of label propagation
+#[derive(Copy, Clone, Debug)]
+pub struct LabelPropagation {
+    /// rank of neighbour defining local scale
+    knn_scale: usize,
+    kernel: PropagationKernel,
+    max_iter: usize,
+    epsil: f64,
+} // end of LabelPropagation
+
+/// result of label propagation
+#[derive(Clone, Debug)]
+pub struct PropagatedLabels<L> {
+    /// classes found in labels, the columns of probabilities are in this order
+    classes: Vec<L>,
+    /// class probabilities of each node, indexed by node rank (NodeIdx)
+    probabilities: Array2<f64>,
+    /// true for nodes that were labeled
+    seeds: Vec<bool>,
+    /// number of iterations done
+    nb_iter: usize,
+} // end of PropagatedLabels
+
+impl<L: Clone> PropagatedLabels<L> {
+    /// classes, in the order of columns of [get_probabilities](Self::get_probabilities)
+    pub fn get_classes(&self) -> &Vec<L> {
+        &self.classes
+    }
+
+    /// class probabilities, a row by node indexed by node rank (NodeIdx). Rows of unreached nodes are null.
+    pub fn get_probabilities(&self) -> &Array2<f64> {
+        &self.probabilities
+    }
+
+    /// returns true if node was labeled
+    pub fn is_seed(&self, node: NodeIdx) -> bool {
+        self.seeds[node]
+    }
+
+    /// number of iterations done
+    pub fn get_nb_iter(&self) -> usize {
+        self.nb_iter
+    }
+
+    /// predicted label and its confidence for a node. None if no labeled node is connected to it.
+    pub fn get_label(&self, node: NodeIdx) -> Option<(L, f64)> {
+        let row = self.probabilities.row(node);
+        let mut argmax = 0;
+        for j in 1..row.len() {
+            if row[j] > row[argmax] {
+                argmax = j;
+            }
+        }
+        if row[argmax] > 0. {
+            Some((self.classes[argmax].clone(), row[argmax]))
+        } else {
+            None
+        }
+    } // end of get_label
+
+    /// predicted labels indexed by node rank (NodeIdx). See [KGraph::get_data_id_from_idx] to go back to DataId
+    pub fn get_labels(&self) -> Vec<Option<L>> {
+        (0..self.seeds.len()).map(|i| self.get_label(i).map(|(l, _)| l)).collect()
+    }
+
+    /// confidences (probability of the predicted class) indexed by node rank, 0. for unreached nodes
+    pub fn get_confidences(&self) -> Vec<f64> {
+        (0..self.seeds.len()).map(|i| self.get_label(i).map_or(0., |(_, c)| c)).collect()
+    }
+} // end of impl PropagatedLabels
+
+impl LabelPropagation {
+    pub fn new(kernel: PropagationKernel) -> Self {
+        LabelPropagation {
+            knn_scale: DEFAULT_KNN_SCALE,
+            kernel,
+            max_iter: DEFAULT_MAX_ITER,
+            epsil: DEFAULT_EPSIL,
+        }
+    }
+
+    /// set rank of neighbour giving local scale (default 7). It must be at least 1.
+    pub fn set_knn_scale(&mut self, knn_scale: usize) -> Result<(), AnnembedError> {
+        if knn_scale < 1 {
+            log::error!("LabelPropagation::set_knn_scale knn_scale must be at least 1");
+            return Err(AnnembedError::InvalidParameter(String::from(
+                "label propagation needs knn_scale >= 1",
+            )));
+        }
+        self.knn_scale = knn_scale;
+        Ok(())
+    }
+
+    /// set maximum number of iterations (default 1000)
+    pub fn set_max_iter(&mut self, max_iter: usize) {
+        self.max_iter = max_iter;
+    }
+
+    /// set tolerance on the maximal change of a probability between 2 iterations (default 1.0E-6)
+    pub fn set_epsil(&mut self, epsil: f64) {
+        self.epsil = epsil;
+    }
+
+    /// propagates labels, given by DataId, to all nodes of a KGraph. Labels of DataId not in the graph are ignored.
+    pub fn propagate_kgraph<F, L>(
+        &self,
+        kgraph: &KGraph<F>,
+        labels: &DataLabels<L>,
+    ) -> Result<PropagatedLabels<L>, AnnembedError>
+    where
+        F: Float + FromPrimitive + std::marker::Sync + Send + std::fmt::UpperExp + std::iter::Sum,
+        L: Clone + PartialEq,
+    {
+        let nbnodes = kgraph.get_nb_nodes();
+        let mut classes = Vec::<L>::new();
+        let mut seeds = Vec::<Option<usize>>::with_capacity(nbnodes);
+        for i in 0..nbnodes {
+            let label = kgraph.get_data_id_from_idx(i).and_then(|d| labels.get(d));
+            let class = label.map(|l| match classes.iter().position(|c| c == l) {
+                Some(c) => c,
+                None => {
+                    classes.push(l.clone());
+                    classes.len() - 1
+                }
+            });
+            seeds.push(class);
+        }
+        if classes.is_empty() {
+            return Err(AnnembedError::InvalidParameter(String::from(
+                "label propagation, no node of the graph is labeled",
+            )));
+        }
+        log::info!(
+            "label propagation, nb nodes : {}, nb labeled : {}, nb classes : {}",
+            nbnodes,
+            seeds.iter().filter(|s| s.is_some()).count(),
+            classes.len()
+        );
+        let nodeparams = get_self_tuning_affinity(kgraph, self.knn_scale);
+        let (probabilities, nb_iter) = self.propagate(&nodeparams, &seeds, classes.len());
+        Ok(PropagatedLabels {
+            classes,
+            probabilities,
+            seeds: seeds.iter().map(|s| s.is_some()).collect(),
+            nb_iter,
+        })
+    } // end of propagate_kgraph
+
+    // iterates the harmonic mean on unlabeled nodes, seeds giving the class of labeled nodes.
+    // returns class probabilities and number of iterations
+    pub(crate) fn propagate(
+        &self,
+        nodeparams: &NodeParams,
+        seeds: &[Option<usize>],
+        nb_classes: usize,
+    ) -> (Array2<f64>, usize) {
+        let nbnodes = nodeparams.get_nb_nodes();
+        let weights = self.get_weights(nodeparams);
+        let mut current = Array2::<f64>::zeros((nbnodes, nb_classes));
+        for (i, seed) in seeds.iter().enumerate() {
+            if let Some(c) = seed {
+                current[[i, *c]] = 1.;
+            }
+        }
+        let mut nb_iter = 0;
+        while nb_iter < self.max_iter {
+            nb_iter += 1;
+            let mut next = current.clone();
+            let delta = next
+                .outer_iter_mut()
+                .into_par_iter()
+                .enumerate()
+                .filter(|(i, _)| seeds[*i].is_none())
+                .map(|(i, mut row)| {
+                    row.fill(0.);
+                    let mut degree = 0.;
+                    if let Some(neighbours) = weights.outer_view(i) {
+                        for (j, w) in neighbours.iter() {
+                            row.scaled_add(*w, &current.row(j));
+                            degree += *w;
+                        }
+                    }
+                    if degree > 0. {
+                        row /= degree;
+                    }
+                    row.iter()
+                        .zip(current.row(i).iter())
+                        .fold(0f64, |acc, (n, c)| acc.max((n - c).abs()))
+                })
+                .reduce(|| 0f64, f64::max);
+            current = next;
+            if delta < self.epsil {
+                break;
+            }
+        }
+        log::debug!("label propagation done, nb iterations : {}", nb_iter);
+        (current, nb_iter)
+    } // end of propagate
+
+    /// propagates classes of seeds, given as (DataId, class), on a laplacian whose nodes have DataId data_ids.
+    /// The classes of the result are the classes of seeds in increasing order.
+    pub(crate) fn propagate_laplacian(
+        &self,
+        laplacian: &GraphLaplacian,
+        data_ids: &[DataId],
+        seeds: &[(DataId, usize)],
+        scheme: PropagationScheme,
+    ) -> Result<PropagatedLabels<usize>, AnnembedError> {
+        if let PropagationScheme::Consistency { alpha } = scheme {
+            if !(alpha > 0. && alpha < 1.) {
+                return Err(AnnembedError::InvalidParameter(format!("label propagation, alpha {} must be in ]0,1[", alpha)));
+            }
+        }
+        let nbnodes = data_ids.len();
+        let mut classes: Vec<usize> = seeds.iter().map(|(_, c)| *c).collect();
+        classes.sort_unstable();
+        classes.dedup();
+        if classes.is_empty() {
+            return Err(AnnembedError::InvalidParameter(String::from("label propagation, no seed")));
+        }
+        let rows: HashMap<DataId, usize> = data_ids.iter().enumerate().map(|(i, d)| (*d, i)).collect();
+        let mut node_seeds = vec![None; nbnodes];
+        for (data_id, class) in seeds {
+            let row = *rows.get(data_id).ok_or_else(|| {
+                AnnembedError::InvalidParameter(format!("label propagation, seed DataId {} not in embedding", data_id))
+            })?;
+            let class = classes.binary_search(class).unwrap();
+            match node_seeds[row] {
+                Some(c) if c != class => {
+                    return Err(AnnembedError::InvalidParameter(format!(
+                        "label propagation, DataId {} has 2 classes",
+                        data_id
+                    )));
+                }
+                _ => node_seeds[row] = Some(class),
+            }
+        }
+        log::info!(
+            "label propagation on laplacian, {:?}, nb nodes : {}, nb labeled : {}, nb classes : {}",
+            scheme,
+            nbnodes,
+            node_seeds.iter().filter(|s| s.is_some()).count(),
+            classes.len()
+        );
+        let (probabilities, nb_iter) = self.iterate_laplacian(laplacian, &node_seeds, classes.len(), scheme);
+        Ok(PropagatedLabels {
+            classes,
+            probabilities,
+            seeds: node_seeds.iter().map(|s| s.is_some()).collect(),
+            nb_iter,
+        })
+    } // end of propagate_laplacian
+
+    // iterates the propagation scheme with S = D^{-1/2} G D^{-1/2}. For the harmonic function the mean of neighbours
+    // G f / d is obtained as D^{-1/2} S D^{1/2} f. Returns class probabilities and number of iterations
+    fn iterate_laplacian(
+        &self,
+        laplacian: &GraphLaplacian,
+        seeds: &[Option<usize>],
+        nb_classes: usize,
+        scheme: PropagationScheme,
+    ) -> (Array2<f64>, usize) {
+        let nbnodes = seeds.len();
+        let sqrt_degrees: Vec<f64> = laplacian.degrees.iter().map(|d| (*d as f64).sqrt()).collect();
+        let mut initial = Array2::<f64>::zeros((nbnodes, nb_classes));
+        for (i, seed) in seeds.iter().enumerate() {
+            if let Some(c) = seed {
+                initial[[i, *c]] = 1.;
+            }
+        }
+        let mut current = initial.clone();
+        let mut nb_iter = 0;
+        while nb_iter < self.max_iter {
+            nb_iter += 1;
+            let products: Vec<Array1<f32>> = (0..nb_classes)
+                .into_par_iter()
+                .map(|c| {
+                    let column: Array1<f32> = match scheme {
+                        PropagationScheme::Harmonic => {
+                            current.column(c).iter().zip(sqrt_degrees.iter()).map(|(f, d)| (f * d) as f32).collect()
+                        }
+                        PropagationScheme::Consistency { .. } => current.column(c).mapv(|f| f as f32),
+                    };
+                    laplacian.sym_dot_vector(&column.view())
+                })
+                .collect();
+            let mut next = Array2::<f64>::zeros((nbnodes, nb_classes));
+            for (c, product) in products.iter().enumerate() {
+                for i in 0..nbnodes {
+                    next[[i, c]] = match scheme {
+                        PropagationScheme::Harmonic if seeds[i].is_some() => initial[[i, c]],
+                        PropagationScheme::Harmonic if sqrt_degrees[i] > 0. => product[i] as f64 / sqrt_degrees[i],
+                        PropagationScheme::Harmonic => 0.,
+                        PropagationScheme::Consistency { alpha } => alpha * product[i] as f64 + (1. - alpha) * initial[[i, c]],
+                    };
+                }
+            }
+            let delta = next.iter().zip(current.iter()).fold(0f64, |acc, (n, c)| acc.max((n - c).abs()));
+            current = next;
+            if delta < self.epsil {
+                break;
+            }
+        }
+        if let PropagationScheme::Consistency { .. } = scheme {
+            for mut row in current.outer_iter_mut() {
+                let sum = row.sum();
+                if sum > 0. {
+                    row /= sum;
+                }
+            }
+        }
+        log::debug!("label propagation on laplacian done, nb iterations : {}", nb_iter);
+        (current, nb_iter)
+    } // end of iterate_laplacian
+
+    // symmetrized weights, renormalized by degrees for the diffusion kernel
+    fn get_weights(&self, nodeparams: &NodeParams) -> CsMat<f64> {
+        let nbnodes = nodeparams.get_nb_nodes();
+        let mut rows = Vec::<usize>::new();
+        let mut cols = Vec::<usize>::new();
+        let mut values = Vec::<f64>::new();
+        for i in 0..nbnodes {
+            for edge in &nodeparams.get_node_param(i).edges {
+                let j = edge.get_node();
+                if j == i {
+                    continue;
+                }
+                let w = 0.5 * edge.weight as f64;
+                rows.extend_from_slice(&[i, j]);
+                cols.extend_from_slice(&[j, i]);
+                values.extend_from_slice(&[w, w]);
+            }
+        }
+        if self.kernel == PropagationKernel::Diffusion {
+            let mut degrees = vec![0f64; nbnodes];
+            for (i, w) in rows.iter().zip(values.iter()) {
+                degrees[*i] += w;
+            }
+            for k in 0..values.len() {
+                values[k] /= degrees[rows[k]] * degrees[cols[k]];
+            }
+        }
+        TriMatBase::<Vec<usize>, Vec<f64>>::from_triplets((nbnodes, nbnodes), rows, cols, values).to_csr()
+    } // end of get_weights
+} // end of impl LabelPropagation
+
+//==========================================================================================
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::tools::svdapprox::MatRepr;
+
+    fn log_init_test() {
+        let _ = env_logger::builder().is_test(true).try_init();
+    }
+
+    #[test]
+    fn test_harmonic_chain() {
+        log_init_test();
+        // chain 0-1-2-3-4 with unit weights, ends labeled with 2 classes : the harmonic solution is linear
+        let nb = 5;
+        let params = (0..nb)
+            .map(|i| {
+                let edges = (0..nb)
+                    .filter(|j| *j + 1 == i || i + 1 == *j)
+                    .map(|j| OutEdge::new(j, 1.))
+                    .collect();
+                NodeParam::new(1., edges)
+            })
+            .collect();
+        let nodeparams = NodeParams::new(params, 2);
+        let seeds = [Some(0), None, None, None, Some(1)];
+        let propagation = LabelPropagation::new(PropagationKernel::SelfTuning);
+        let (probabilities, nb_iter) = propagation.propagate(&nodeparams, &seeds, 2);
+        log::info!("nb iter : {}, probabilities : {:?}", nb_iter, probabilities);
+        assert!(nb_iter < DEFAULT_MAX_ITER);
+        for (i, expected) in [1., 0.75, 0.5, 0.25, 0.].iter().enumerate() {
+            assert!((probabilities[[i, 0]] - expected).abs() < 1.0E-4);
+            assert!((probabilities[[i, 0]] + probabilities[[i, 1]] - 1.).abs() < 1.0E-4);
+        }
+        // local scale is at least the first neighbour
+        let mut propagation = LabelPropagation::new(PropagationKernel::SelfTuning);
+        assert!(propagation.set_knn_scale(0).is_err());
+        assert!(propagation.set_knn_scale(1).is_ok());
+    } // end of test_harmonic_chain
+
+    #[test]
+    fn test_propagation_two_cliques() {
+        log_init_test();
+        // two cliques of 10 nodes joined by a weak edge, one labeled node in each, and an isolated node
+        let nb = 21;
+        let params = (0..nb)
+            .map(|i| {
+                let mut edges: Vec<OutEdge<f32>> = (0..20)
+                    .filter(|j| *j != i && i < 20 && j / 10 == i / 10)
+                    .map(|j| OutEdge::new(j, 1.))
+                    .collect();
+                if i == 0 {
+                    edges.push(OutEdge::new(10, 0.01));
+                }
+                NodeParam::new(1., edges)
+            })
+            .collect();
+        let nodeparams = NodeParams::new(params, nb);
+        let mut seeds = vec![None; nb];
+        seeds[3] = Some(0);
+        seeds[15] = Some(1);
+        for kernel in [PropagationKernel::SelfTuning, PropagationKernel::Diffusion] {
+            let propagation = LabelPropagation::new(kernel);
+            let (probabilities, nb_iter) = propagation.propagate(&nodeparams, &seeds, 2);
+            let result = PropagatedLabels {
+                classes: vec!["a", "b"],
+                probabilities,
+                seeds: seeds.iter().map(|s| s.is_some()).collect(),
+                nb_iter,
+            };
+            let labels = result.get_labels();
+            assert!(labels[..10].iter().all(|l| *l == Some("a")));
+            assert!(labels[10..20].iter().all(|l| *l == Some("b")));
+            assert_eq!(labels[20], None);
+            let confidences = result.get_confidences();
+            assert!(confidences[..20].iter().all(|c| *c > 0.9));
+            assert_eq!(confidences[20], 0.);
+            assert!(result.is_seed(3) && !result.is_seed(4));
+        }
+    } // end of test_propagation_two_cliques
+
+    // laplacian D^{-1/2} G D^{-1/2} of a weight matrix
+    fn laplacian_from_weights(weights: &Array2<f32>) -> GraphLaplacian {
+        let degrees: Array1<f32> = weights.rows().into_iter().map(|r| r.sum()).collect();
+        let sym = Array2::from_shape_fn(weights.dim(), |(i, j)| weights[[i, j]] / (degrees[i] * degrees[j]).sqrt());
+        GraphLaplacian::new(MatRepr::from_array2(sym), degrees)
+    }
+
+    #[test]
+    fn test_laplacian_propagation() {
+        log_init_test();
+        // chain 0-1-2-3-4 with unit weights, DataId are 10 times node rank, ends labeled with classes 3 and 7
+        let nb = 5;
+        let weights = Array2::<f32>::from_shape_fn((nb, nb), |(i, j)| if i + 1 == j || j + 1 == i { 1. } else { 0. });
+        let laplacian = laplacian_from_weights(&weights);
+        let data_ids: Vec<DataId> = (0..nb).map(|i| 10 * i).collect();
+        let seeds = [(0, 3), (40, 7)];
+        let propagation = LabelPropagation::new(PropagationKernel::Diffusion);
+        let result = propagation
+            .propagate_laplacian(&laplacian, &data_ids, &seeds, PropagationScheme::Harmonic)
+            .unwrap();
+        assert_eq!(result.get_classes(), &vec![3, 7]);
+        for (i, expected) in [1., 0.75, 0.5, 0.25, 0.].iter().enumerate() {
+            assert!((result.get_probabilities()[[i, 0]] - expected).abs() < 1.0E-4);
+        }
+        assert!(result.is_seed(4) && !result.is_seed(3));
+        // consistency method on 2 cliques joined by a weak edge, with a wrong seed in the first clique
+        let nb = 20;
+        let weights = Array2::<f32>::from_shape_fn((nb, nb), |(i, j)| {
+            if i != j && i / 10 == j / 10 {
+                1.
+            } else if (i, j) == (0, 10) || (i, j) == (10, 0) {
+                0.01
+            } else {
+                0.
+            }
+        });
+        let laplacian = laplacian_from_weights(&weights);
+        let data_ids: Vec<DataId> = (0..nb).collect();
+        let seeds = [(1, 0), (2, 0), (3, 0), (4, 1), (15, 1)];
+        let result = propagation
+            .propagate_laplacian(&laplacian, &data_ids, &seeds, PropagationScheme::Consistency { alpha: 0.9 })
+            .unwrap();
+        let labels = result.get_labels();
+        assert!(labels[..10].iter().all(|l| *l == Some(0)));
+        assert!(labels[10..].iter().all(|l| *l == Some(1)));
+        // errors : unknown DataId, conflicting seeds, alpha out of range
+        assert!(propagation.propagate_laplacian(&laplacian, &data_ids, &[(25, 0)], PropagationScheme::Harmonic).is_err());
+        assert!(propagation.propagate_laplacian(&laplacian, &data_ids, &[(2, 0), (2, 1)], PropagationScheme::Harmonic).is_err());
+        assert!(propagation
+            .propagate_laplacian(&laplacian, &data_ids, &seeds, PropagationScheme::Consistency { alpha: 1. })
+            .is_err());
+    } // end of test_laplacian_propagation
+} // end of mod tests
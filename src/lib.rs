@@ -9,6 +9,7 @@ extern crate lazy_static;
 
 
 
+pub mod error;
 pub mod tools;
 pub mod fromhnsw;
 pub mod hdbscan;
@@ -16,8 +17,22 @@ pub mod embedder;
 pub mod embedparams;
 pub mod graphlaplace;
 pub mod diffmaps;
+pub mod phate;
+pub mod spectralclust;
+pub mod labelprop;
+pub mod diffclust;
+pub mod sparse;
+pub mod batchcorrect;
+pub mod featimportance;
+pub mod pipeline;
+pub mod differential;
+#[cfg(feature = "csv")]
+pub mod service;
+pub mod datasets;
 pub mod prelude;
 
+pub use pipeline::{embed, EmbedOutput, EmbedParams};
+
 
 
 lazy_static! {
@@ -30,7 +45,7 @@ lazy_static! {
 // install a logger facility
 fn init_log() -> u64 {
     let _res = env_logger::try_init();
-    println!("\n ************** initializing logger *****************\n");    
+    log::info!("logger initialized");
     return 1;
 }
 
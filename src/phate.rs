@@ -0,0 +1,242 @@
+//! PHATE like embedding.
+//!
+//! Moon et al. Visualizing structure and transitions in high-dimensional biological data. Nature Biotechnology 2019.
+//!
+//! - The diffusion operator P is the random walk on the symetrized kernel of neighbours, as in [diffmaps](crate::diffmaps).
+//! - $P^{t}$ is computed from the eigen decomposition of the symetric laplacian, t is chosen by default by the Von Neumann entropy
+//!   criterion (see [TimeSelection]).
+//! - Each node is represented by its potential $-\log(P^{t}_{i,.})$, potential distances are the euclidean distances between these rows.
+//! - Metric (classical) MDS of potential distances is obtained by the randomized svd of the centered potential matrix.
+//!
+//! As potentials are stored in a full (n,n) matrix, this mode is meant for a moderate number of points.
+
+use num_traits::cast::FromPrimitive;
+use num_traits::Float;
+
+use hnsw_rs::prelude::*;
+use ndarray::{Array1, Array2, Axis};
+
+use crate::diffmaps::{kgraph_from_hnsw_params, select_diffusion_time, TimeSelection};
+use crate::embedder::*;
+use crate::error::AnnembedError;
+use crate::fromhnsw::kgraph::KGraph;
+use crate::graphlaplace::*;
+use crate::tools::svdapprox::*;
+
+/// potentials are $-\log(P^{t} + PHATE\_EPSIL)$
+const PHATE_EPSIL: f32 = 1.0E-7;
+
+/// maximum number of eigen pairs used to compute $P^{t}$
+const MAX_PHATE_RANK: usize = 100;
+
+/// number of nodes above which we warn about memory use of the full potential matrix
+const PHATE_LARGE_SIZE: usize = 20000;
+
+#[derive(Copy, Clone, Debug)]
+pub struct PhateParams {
+    /// dimension of embedding
+    asked_dim: usize,
+    /// diffusion time selection, default to Von Neumann entropy knee
+    time_selection: TimeSelection,
+    /// number of neighbours in kernel, default to hnsw max_nb_connection
+    knbn: Option<usize>,
+    /// if set, neighbourhoods are obtained by a search in hnsw with this ef
+    ef_search: Option<usize>,
+    /// if set, nodes are connected to all neighbours within this radius
+    radius: Option<f32>,
+} // end of PhateParams
+
+impl PhateParams {
+    pub fn new(asked_dim: usize) -> Self {
+        PhateParams {
+            asked_dim,
+            time_selection: TimeSelection::VonNeumannEntropy,
+            knbn: None,
+            ef_search: None,
+            radius: None,
+        }
+    }
+
+    /// set diffusion time selection strategy
+    pub fn set_time_selection(&mut self, selection: TimeSelection) {
+        self.time_selection = selection;
+    }
+
+    /// set the number of neighbours used in the kernel, independently of hnsw max_nb_connection
+    pub fn set_knbn(&mut self, knbn: usize) {
+        self.knbn = Some(knbn);
+    }
+
+    /// neighbourhoods are obtained by a knn search of each point in the hnsw with the given ef_search
+    pub fn set_ef_search(&mut self, ef_search: usize) {
+        self.ef_search = Some(ef_search);
+    }
+
+    /// connect each node to all its neighbours within radius, knbn is then the maximal number of neighbours
+    pub fn set_radius(&mut self, radius: f32) {
+        self.radius = Some(radius);
+    }
+
+    ///
+    pub fn get_embedding_dimension(&self) -> usize {
+        self.asked_dim
+    }
+} // end of impl PhateParams
+
+pub struct Phate {
+    /// parameters
+    params: PhateParams,
+    /// diffusion time used in last embedding
+    time: Option<f64>,
+    /// DataId of each row of last embedding
+    data_ids: Option<Vec<DataId>>,
+} // end of Phate
+
+impl Phate {
+    pub fn new(params: PhateParams) -> Self {
+        Phate {
+            params,
+            time: None,
+            data_ids: None,
+        }
+    }
+
+    /// returns diffusion time used in last embedding
+    pub fn get_diffusion_time(&self) -> Option<f64> {
+        self.time
+    }
+
+    /// returns the DataId of each row of the last embedding
+    pub fn get_data_ids(&self) -> Option<&Vec<DataId>> {
+        self.data_ids.as_ref()
+    }
+
+    /// do the whole work chain : graph extraction from hnsw, kernel, potentials and mds.
+    /// F is the type of graph distances and of the returned coordinates.
+    pub fn embed_hnsw<T, D, F>(&mut self, hnsw: &Hnsw<T, D>) -> Result<Array2<F>, AnnembedError>
+    where
+        D: Distance<T> + Send + Sync,
+        T: Clone + Send + Sync,
+        F: Float + FromPrimitive + std::marker::Sync + Send + std::fmt::UpperExp + std::iter::Sum,
+    {
+        let kgraph = kgraph_from_hnsw_params::<T, D, F>(hnsw, self.params.knbn, self.params.ef_search, self.params.radius)?;
+        self.embed_kgraph(&kgraph)
+    } // end of embed_hnsw
+
+    /// embeds nodes of a KGraph. Rows of result are indexed by node rank in the graph.
+    pub fn embed_kgraph<F>(&mut self, kgraph: &KGraph<F>) -> Result<Array2<F>, AnnembedError>
+    where
+        F: Float + FromPrimitive + std::marker::Sync + Send + std::fmt::UpperExp + std::iter::Sum,
+    {
+        let nbnodes = kgraph.get_nb_nodes();
+        self.data_ids = Some((0..nbnodes).map(|i| *kgraph.get_data_id_from_idx(i).unwrap()).collect());
+        if nbnodes > PHATE_LARGE_SIZE {
+            log::warn!("Phate::embed_kgraph, {} nodes, full potential matrix will need much memory", nbnodes);
+        }
+        let nodeparams = to_proba_edges::<F>(kgraph, 1., 2.)?;
+        let mut laplacian = get_laplacian(&nodeparams);
+        let svd_res = laplacian.do_svd(MAX_PHATE_RANK)?;
+        let sigmas = svd_res
+            .get_sigma()
+            .as_ref()
+            .ok_or(AnnembedError::MissingSvdResult("singular values"))?;
+        let u = svd_res
+            .get_u()
+            .as_ref()
+            .ok_or(AnnembedError::MissingSvdResult("left singular vectors"))?;
+        let rank = MAX_PHATE_RANK.min(sigmas.len()).min(u.ncols());
+        if rank < 3 {
+            return Err(AnnembedError::NotEnoughEigenvectors { computed: rank, needed: 3 });
+        }
+        // the laplacian is symetric, eigenvalues are singular values up to sign given by u_k.v_k
+        let lambdas: Vec<f32> = (0..rank)
+            .map(|k| match svd_res.get_vt() {
+                Some(vt) if u.column(k).dot(&vt.row(k)) < 0. => -sigmas[k],
+                _ => sigmas[k],
+            })
+            .collect();
+        let normalized: Vec<f64> = lambdas.iter().map(|l| (*l / sigmas[0]) as f64).collect();
+        // P^t needs an integer time as eigenvalues can be negative
+        let time = select_diffusion_time(&normalized, self.params.asked_dim, self.params.time_selection)
+            .round()
+            .max(1.);
+        self.time = Some(time);
+        log::info!("Phate diffusion time : {:.3e}", time);
+        let u_rank = u.slice(ndarray::s![.., ..rank]).to_owned();
+        let diffusion = get_diffusion_power(&u_rank, &lambdas, &laplacian.degrees, time as i32);
+        // potentials
+        let mut potential = diffusion.mapv(|p| -(p.max(0.) + PHATE_EPSIL).ln());
+        drop(diffusion);
+        // classical mds of euclidean distances between potential rows is the pca of centered potentials
+        let mean = potential.mean_axis(Axis(0)).unwrap();
+        potential -= &mean;
+        let matrepr = MatRepr::from_array2(potential);
+        let mut svdapprox = SvdApprox::new(&matrepr);
+        let svdmode = RangeApproxMode::RANK(RangeRank::new(self.params.asked_dim + 5, 5));
+        let mds = svdapprox.direct_svd(svdmode)?;
+        let s = mds
+            .get_sigma()
+            .as_ref()
+            .ok_or(AnnembedError::MissingSvdResult("singular values"))?;
+        let mds_u = mds
+            .get_u()
+            .as_ref()
+            .ok_or(AnnembedError::MissingSvdResult("left singular vectors"))?;
+        if s.len() < self.params.asked_dim {
+            return Err(AnnembedError::NotEnoughEigenvectors {
+                computed: s.len(),
+                needed: self.params.asked_dim,
+            });
+        }
+        let mut embedded = Array2::<F>::zeros((nbnodes, self.params.asked_dim));
+        for i in 0..nbnodes {
+            for j in 0..self.params.asked_dim {
+                embedded[[i, j]] = F::from_f32(mds_u[[i, j]] * s[j]).ok_or(AnnembedError::FloatConversion)?;
+            }
+        }
+        Ok(embedded)
+    } // end of embed_kgraph
+} // end of impl Phate
+
+// computes P^t = D^{-1/2} U Lambda^t U^t D^{1/2} from eigen pairs of the symetric laplacian D^{-1/2} K D^{-1/2}
+fn get_diffusion_power(u: &Array2<f32>, lambdas: &[f32], degrees: &Array1<f32>, time: i32) -> Array2<f32> {
+    let lambdas_t: Array1<f32> = lambdas.iter().map(|l| l.powi(time)).collect();
+    let left = u * &lambdas_t;
+    let mut power = left.dot(&u.t());
+    for i in 0..power.nrows() {
+        let d_i = degrees[i].sqrt();
+        for j in 0..power.ncols() {
+            power[[i, j]] *= degrees[j].sqrt() / d_i;
+        }
+    }
+    power
+} // end of get_diffusion_power
+
+//=======================================================================
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    fn log_init_test() {
+        let _ = env_logger::builder().is_test(true).try_init();
+    }
+
+    #[test]
+    fn test_diffusion_power() {
+        log_init_test();
+        // 2 nodes linked, eigen pairs of kernel [[0,1],[1,0]] are 1 and -1
+        let r = 1. / 2f32.sqrt();
+        let u = ndarray::arr2(&[[r, r], [r, -r]]);
+        let degrees = ndarray::arr1(&[1., 1.]);
+        // one step goes to the other node, two steps come back
+        let p1 = get_diffusion_power(&u, &[1., -1.], &degrees, 1);
+        assert!((p1[[0, 1]] - 1.).abs() < 1.0E-6 && p1[[0, 0]].abs() < 1.0E-6);
+        let p2 = get_diffusion_power(&u, &[1., -1.], &degrees, 2);
+        assert!((p2[[0, 0]] - 1.).abs() < 1.0E-6 && p2[[0, 1]].abs() < 1.0E-6);
+        for row in p2.rows() {
+            assert!((row.sum() - 1.).abs() < 1.0E-6);
+        }
+    } // end of test_diffusion_power
+} // end of mod tests
@@ -0,0 +1,1012 @@
+//! End to end workflows with persistence of the Hnsw structure.
+//!
+//! - [build_and_dump_hnsw] builds a Hnsw structure from data rows with their DataIds and dumps it with hnsw_rs io.
+//! - [DiffusionMaps::embed_hnsw_dump] and [embed_hnsw_dump] reload a dumped Hnsw and run a diffusion maps or the Embedder.
+//! - [DiffusionMaps::embed_data_and_dump] and [embed_data_and_dump] do construction, dump and embedding in one call.
+//! - user distances without Default (DistFn, DistPtr) are reloaded by the `_with_dist` variants.
+//! - [cosine_hnsw] builds an exact cosine Hnsw from L2-normalized rows, with the dot product distance [DistUnitDot].
+//!   [cosine_search] normalizes queries in the same way.
+//! - [AnnEmbedPipeline] gathers Hnsw, graph, kernel and embedding parameters and runs the whole chain from data rows
+//!   to a [PipelineResult].
+//! - [embed] (re-exported as `annembed::embed`) is the one call version for f32 rows with the L2 distance.
+//! - the graph of an [AnnEmbedPipeline] can be built by NN-Descent instead of a Hnsw, see [AnnEmbedPipeline::set_nndescent].
+//! - [AnnEmbedPipeline::run_sparse] runs the same chain on rows of a csr matrix, see [sparse](crate::sparse).
+//! - both return a serializable [RunReport] with times, peak memory and diagnostics of the run.
+//!
+//! Hnsw dumps store the DataId of each point, so the DataIds given at insertion identify rows of all embeddings,
+//! whatever the order of nodes (the IndexSet of [KGraph]) in the graph extracted from a reloaded Hnsw.
+//! A typical session builds and dumps the Hnsw once:
+//!
+//! ```ignore
+//! let (_, dumpname) = build_and_dump_hnsw(&data, &ids, 24, 400, DistL2 {}, &dir, "mydata")?;
+//! ```
+//! and later embeds from the dump, possibly many times with different parameters, without the original data:
+//!
+//! ```ignore
+//! let mut dmaps = DiffusionMaps::new(DiffusionParams::new(2, None));
+//! let embedding = dmaps.embed_hnsw_dump::<f32, DistL2, f32>(&dir, &dumpname)?;
+//! // row i of embedding has DataId dmaps.get_data_ids().unwrap()[i]
+//! let (ids, embedding) = embed_hnsw_dump::<f32, DistL2, f32>(&dir, &dumpname, 10, EmbedderParams::default())?;
+//! ```
+//! A Hnsw dumped directly with hnsw_rs `file_dump` is reloaded the same way.
+
+use std::fmt::Display;
+use std::path::Path;
+
+use num_traits::cast::FromPrimitive;
+use num_traits::Float;
+
+use hnsw_rs::hnswio::HnswIo;
+use hnsw_rs::prelude::*;
+use ndarray::{Array2, ArrayBase, ArrayView2, Axis, Data, Ix2};
+use rayon::prelude::*;
+use ndarray_linalg::{Lapack, Scalar};
+use serde::{de::DeserializeOwned, Serialize};
+use sprs::CsMat;
+
+use crate::diffmaps::*;
+use crate::embedder::Embedder;
+use crate::embedparams::EmbedderParams;
+use crate::error::AnnembedError;
+use crate::fromhnsw::{kgraph::KGraph, kgraph_from_hnsw_all, kgraph_from_nndescent, NNDescentParams};
+use crate::graphlaplace::{KernelSparsification, SelfEdgeWeight};
+use crate::tools::io::{write_labeled_embedding, DataLabels};
+use crate::tools::report::{GraphReport, ResourceMonitor, RunReport, SpectralReport};
+use crate::tools::safetensors::SafeTensorsWriter;
+use crate::sparse::{sparse_insert_hnsw, SparseEntry};
+
+/// Builds a Hnsw structure from rows of data, row i being inserted with DataId ids\[i\], and dumps it
+/// in directory dir with given basename. The hnsw is returned to be embedded directly, with the basename actually used
+/// by the dump (hnsw_rs does not overwrite existing dumps and may change the basename).
+#[allow(clippy::too_many_arguments)]
+pub fn build_and_dump_hnsw<'b, T, D, S>(
+    data: &ArrayBase<S, Ix2>,
+    ids: &[DataId],
+    max_nb_connection: usize,
+    ef_construction: usize,
+    distance: D,
+    dir: &Path,
+    basename: &str,
+) -> Result<(Hnsw<'b, T, D>, String), AnnembedError>
+where
+    T: Serialize + DeserializeOwned + Clone + Send + Sync,
+    D: Distance<T> + Send + Sync,
+    S: Data<Elem = T> + Sync,
+{
+    let nb_data = data.nrows();
+    let nb_layer = 16.min((nb_data.max(2) as f32).ln().trunc() as usize).max(1);
+    let mut hnsw = Hnsw::<T, D>::new(max_nb_connection, nb_data, nb_layer, ef_construction, distance);
+    array2_insert_hnsw_with_ids(data, ids, &mut hnsw)
+        .map_err(|_| AnnembedError::GraphConstruction(String::from("insertion in hnsw failed")))?;
+    let dumpname = hnsw
+        .file_dump(dir, basename)
+        .map_err(|e| AnnembedError::HnswIo(e.to_string()))?;
+    log::info!("hnsw dumped in {} with basename {}", dir.display(), dumpname);
+    Ok((hnsw, dumpname))
+} // end of build_and_dump_hnsw
+
+impl DiffusionMaps {
+    /// reloads a Hnsw dumped (by hnsw_rs or [build_and_dump_hnsw]) in directory dir with given basename and embeds it.
+    /// DataIds of rows are given by [get_data_ids](DiffusionMaps::get_data_ids).
+    pub fn embed_hnsw_dump<T, D, F>(&mut self, dir: &Path, basename: &str) -> Result<Array2<F>, AnnembedError>
+    where
+        T: 'static + Serialize + DeserializeOwned + Clone + Sized + Send + Sync + std::fmt::Debug,
+        D: Distance<T> + Default + Send + Sync,
+        F: Float + FromPrimitive + std::marker::Sync + Send + std::fmt::UpperExp + std::iter::Sum,
+    {
+        self.embed_hnsw_dump_with_dist::<T, D, F>(dir, basename, D::default())
+    } // end of embed_hnsw_dump
+
+    /// same as [embed_hnsw_dump](DiffusionMaps::embed_hnsw_dump) for distances without Default, as DistFn or DistPtr.
+    pub fn embed_hnsw_dump_with_dist<T, D, F>(
+        &mut self,
+        dir: &Path,
+        basename: &str,
+        distance: D,
+    ) -> Result<Array2<F>, AnnembedError>
+    where
+        T: 'static + Serialize + DeserializeOwned + Clone + Sized + Send + Sync + std::fmt::Debug,
+        D: Distance<T> + Send + Sync,
+        F: Float + FromPrimitive + std::marker::Sync + Send + std::fmt::UpperExp + std::iter::Sum,
+    {
+        let hnswio = HnswIo::new(dir, basename);
+        let hnsw: Hnsw<T, D> = hnswio
+            .load_hnsw_with_dist(distance)
+            .map_err(|e| AnnembedError::HnswIo(e.to_string()))?;
+        self.embed_hnsw::<T, D, F>(&hnsw)
+    } // end of embed_hnsw_dump_with_dist
+
+    /// builds a Hnsw from data with given DataIds, dumps it in dir with basename and embeds it.
+    #[allow(clippy::too_many_arguments)]
+    pub fn embed_data_and_dump<T, D, F, S>(
+        &mut self,
+        data: &ArrayBase<S, Ix2>,
+        ids: &[DataId],
+        max_nb_connection: usize,
+        ef_construction: usize,
+        distance: D,
+        dir: &Path,
+        basename: &str,
+    ) -> Result<Array2<F>, AnnembedError>
+    where
+        T: Serialize + DeserializeOwned + Clone + Send + Sync,
+        D: Distance<T> + Send + Sync,
+        F: Float + FromPrimitive + std::marker::Sync + Send + std::fmt::UpperExp + std::iter::Sum,
+        S: Data<Elem = T> + Sync,
+    {
+        let (hnsw, _) = build_and_dump_hnsw(data, ids, max_nb_connection, ef_construction, distance, dir, basename)?;
+        self.embed_hnsw::<T, D, F>(&hnsw)
+    } // end of embed_data_and_dump
+} // end of impl DiffusionMaps
+
+/// Dot product distance $1 - <v_a,v_b>$ for L2-normalized vectors.  
+/// Contrary to hnsw_rs DistDot it does not assert that the dot product is at most 1,
+/// the distance is clamped at 0 as rounding on normalized vectors can give a dot product slightly above 1.
+#[derive(Default, Copy, Clone)]
+pub struct DistUnitDot;
+
+impl Distance<f32> for DistUnitDot {
+    fn eval(&self, va: &[f32], vb: &[f32]) -> f32 {
+        assert_eq!(va.len(), vb.len());
+        let dot = va.iter().zip(vb.iter()).map(|(a, b)| a * b).sum::<f32>();
+        (1. - dot).max(0.)
+    }
+} // end of impl Distance for DistUnitDot
+
+// L2-normalizes row in place, returns false if row is null (and left unchanged)
+fn normalize_l2(row: &mut [f32]) -> bool {
+    let norm = row.iter().map(|x| x * x).sum::<f32>().sqrt();
+    if norm > 0. {
+        row.iter_mut().for_each(|x| *x /= norm);
+    }
+    norm > 0.
+} // end of normalize_l2
+
+/// Builds a Hnsw for the cosine distance : rows of data are L2-normalized and inserted by
+/// [array2_insert_hnsw_with_ids], then compared with [DistUnitDot].  
+/// Row i is inserted with DataId ids\[i\], ids must be unique. Null rows are inserted unchanged, at distance 1 of all points.  
+/// Queries must be normalized in the same way, see [cosine_search].
+pub fn cosine_hnsw<'b, S>(
+    data: &ArrayBase<S, Ix2>,
+    ids: &[DataId],
+    max_nb_connection: usize,
+    ef_construction: usize,
+) -> Result<Hnsw<'b, f32, DistUnitDot>, AnnembedError>
+where
+    S: Data<Elem = f32> + Sync,
+{
+    let (nb_data, dim) = data.dim();
+    if ids.len() != nb_data || dim == 0 {
+        return Err(AnnembedError::GraphConstruction(format!(
+            "cosine_hnsw, nb ids {}, nb rows {}, dim {}",
+            ids.len(),
+            nb_data,
+            dim
+        )));
+    }
+    let mut normalized = data.as_standard_layout().into_owned();
+    let nb_null = normalized
+        .axis_iter_mut(Axis(0))
+        .into_par_iter()
+        .filter_map(|mut row| (!normalize_l2(row.as_slice_mut().unwrap())).then_some(()))
+        .count();
+    if nb_null > 0 {
+        log::warn!("cosine_hnsw, {} null rows", nb_null);
+    }
+    let nb_layer = 16.min((nb_data.max(2) as f32).ln().trunc() as usize).max(1);
+    let mut hnsw = Hnsw::<f32, DistUnitDot>::new(max_nb_connection, nb_data, nb_layer, ef_construction, DistUnitDot);
+    array2_insert_hnsw_with_ids(&normalized, ids, &mut hnsw)?;
+    Ok(hnsw)
+} // end of cosine_hnsw
+
+/// searches the knbn neighbours of query in a Hnsw built by [cosine_hnsw], query being L2-normalized as inserted rows.
+/// Distances are then 1 - cos.
+pub fn cosine_search(hnsw: &Hnsw<f32, DistUnitDot>, query: &[f32], knbn: usize, ef_search: usize) -> Vec<Neighbour> {
+    let mut normalized = query.to_vec();
+    normalize_l2(&mut normalized);
+    hnsw.search(&normalized, knbn, ef_search)
+} // end of cosine_search
+
+// runs the Embedder on the graph of knbn neighbours, returns DataIds of rows and embedding
+fn embed_kgraph_from_hnsw<T, D, F>(
+    hnsw: &Hnsw<T, D>,
+    knbn: usize,
+    params: EmbedderParams,
+) -> Result<(Vec<DataId>, Array2<F>), AnnembedError>
+where
+    T: Clone + Send + Sync,
+    D: Distance<T> + Send + Sync,
+    F: Float + FromPrimitive + Lapack + Scalar + ndarray::ScalarOperand + Send + Sync,
+{
+    let kgraph: KGraph<F> = kgraph_from_hnsw_all(hnsw, knbn)
+        .map_err(|_| AnnembedError::GraphConstruction(String::from("kgraph_from_hnsw_all failed")))?;
+    let mut embedder = Embedder::new(&kgraph, params);
+    embedder
+        .embed()
+        .map_err(|e| AnnembedError::Embedding(format!("embedder returned error {}", e)))?;
+    let embedded = embedder
+        .get_embedded()
+        .ok_or(AnnembedError::Embedding(String::from("no embedding computed")))?
+        .clone();
+    Ok((embedder.get_data_ids(), embedded))
+} // end of embed_kgraph_from_hnsw
+
+/// reloads a Hnsw dumped in directory dir with given basename and runs the [Embedder] on the graph of its knbn neighbours.
+/// Returns the DataId of each row and the embedding.
+pub fn embed_hnsw_dump<T, D, F>(
+    dir: &Path,
+    basename: &str,
+    knbn: usize,
+    params: EmbedderParams,
+) -> Result<(Vec<DataId>, Array2<F>), AnnembedError>
+where
+    T: 'static + Serialize + DeserializeOwned + Clone + Sized + Send + Sync + std::fmt::Debug,
+    D: Distance<T> + Default + Send + Sync,
+    F: Float + FromPrimitive + Lapack + Scalar + ndarray::ScalarOperand + Send + Sync,
+{
+    embed_hnsw_dump_with_dist::<T, D, F>(dir, basename, knbn, params, D::default())
+} // end of embed_hnsw_dump
+
+/// same as [embed_hnsw_dump] for distances without Default, as DistFn or DistPtr.
+pub fn embed_hnsw_dump_with_dist<T, D, F>(
+    dir: &Path,
+    basename: &str,
+    knbn: usize,
+    params: EmbedderParams,
+    distance: D,
+) -> Result<(Vec<DataId>, Array2<F>), AnnembedError>
+where
+    T: 'static + Serialize + DeserializeOwned + Clone + Sized + Send + Sync + std::fmt::Debug,
+    D: Distance<T> + Send + Sync,
+    F: Float + FromPrimitive + Lapack + Scalar + ndarray::ScalarOperand + Send + Sync,
+{
+    let hnswio = HnswIo::new(dir, basename);
+    let hnsw: Hnsw<T, D> = hnswio
+        .load_hnsw_with_dist(distance)
+        .map_err(|e| AnnembedError::HnswIo(e.to_string()))?;
+    embed_kgraph_from_hnsw(&hnsw, knbn, params)
+} // end of embed_hnsw_dump_with_dist
+
+/// builds a Hnsw from data with given DataIds, dumps it in dir with basename and runs the [Embedder] on the graph
+/// of its max_nb_connection neighbours. Returns the DataId of each row and the embedding.
+#[allow(clippy::too_many_arguments)]
+pub fn embed_data_and_dump<T, D, F, S>(
+    data: &ArrayBase<S, Ix2>,
+    ids: &[DataId],
+    max_nb_connection: usize,
+    ef_construction: usize,
+    distance: D,
+    dir: &Path,
+    basename: &str,
+    params: EmbedderParams,
+) -> Result<(Vec<DataId>, Array2<F>), AnnembedError>
+where
+    T: Serialize + DeserializeOwned + Clone + Send + Sync,
+    D: Distance<T> + Send + Sync,
+    F: Float + FromPrimitive + Lapack + Scalar + ndarray::ScalarOperand + Send + Sync,
+    S: Data<Elem = T> + Sync,
+{
+    let (hnsw, _) = build_and_dump_hnsw(data, ids, max_nb_connection, ef_construction, distance, dir, basename)?;
+    embed_kgraph_from_hnsw(&hnsw, max_nb_connection, params)
+} // end of embed_data_and_dump
+
+/// Hnsw construction parameters of an [AnnEmbedPipeline]
+#[derive(Copy, Clone, Debug)]
+pub struct HnswParams {
+    /// max number of connections of a point in a layer, default 24
+    pub max_nb_connection: usize,
+    /// ef used in construction, default 400
+    pub ef_construction: usize,
+    /// number of layers, computed from the number of points if None (at most 16)
+    pub nb_layer: Option<usize>,
+    /// keep pruned neighbours so that points get max_nb_connection neighbours, default false
+    pub keep_pruned: bool,
+}
+
+impl Default for HnswParams {
+    fn default() -> Self {
+        HnswParams {
+            max_nb_connection: 24,
+            ef_construction: 400,
+            nb_layer: None,
+            keep_pruned: false,
+        }
+    }
+} // end of impl Default for HnswParams
+
+/// Neighbourhood graph extraction parameters of an [AnnEmbedPipeline], with the meaning of the corresponding
+/// [DiffusionParams] setters. By default the graph of the max_nb_connection neighbours stored in the Hnsw is extracted.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct GraphParams {
+    /// number of neighbours of a node, default to hnsw max_nb_connection
+    pub knbn: Option<usize>,
+    /// if set, neighbourhoods are obtained by a knn search with this ef (knbn can then exceed max_nb_connection)
+    pub ef_search: Option<usize>,
+    /// if set, nodes are connected to their neighbours within radius, knbn being the max number of neighbours
+    pub radius: Option<f32>,
+}
+
+/// Kernel choices of an [AnnEmbedPipeline]. They apply to diffusion maps, the Embedder builds its own kernel
+/// from [EmbedderParams].
+#[derive(Copy, Clone, Debug, Default)]
+pub struct KernelParams {
+    /// self edge weight, see [DiffusionParams::set_self_edge]
+    pub self_edge: Option<SelfEdgeWeight>,
+    /// sparsification of dense kernels, see [DiffusionParams::set_kernel_sparsification]
+    pub sparsification: Option<KernelSparsification>,
+    /// kernel scale chosen from data, see [DiffusionParams::set_auto_scale]
+    pub auto_scale: bool,
+    /// shared nearest neighbour reweighting with a min jaccard index, see [DiffusionParams::set_snn]
+    pub snn: Option<f32>,
+}
+
+/// Embedding method run on the neighbourhood graph by an [AnnEmbedPipeline]
+#[derive(Copy, Clone)]
+pub enum EmbeddingMethod {
+    /// gradient embedding by the [Embedder]
+    Embedder(EmbedderParams),
+    /// diffusion maps
+    DiffusionMaps(DiffusionParams),
+}
+
+/// Result of [AnnEmbedPipeline::run]
+pub struct PipelineResult<F> {
+    /// DataId of each row of embedding
+    data_ids: Vec<DataId>,
+    /// embedded data, rows ordered as data_ids
+    embedding: Array2<F>,
+    /// diffusion time used by diffusion maps
+    diffusion_time: Option<f64>,
+    /// number of edges of the neighbourhood graph
+    nb_edges: usize,
+    /// number of connected components of the neighbourhood graph
+    nb_components: usize,
+    /// sys time (ms) of hnsw construction, graph extraction and embedding
+    times_ms: [u128; 3],
+    /// resources and diagnostics of the run
+    report: RunReport,
+}
+
+impl<F> PipelineResult<F> {
+    /// returns DataId of each row of the embedding
+    pub fn get_data_ids(&self) -> &[DataId] {
+        &self.data_ids
+    }
+
+    /// returns the embedding, row i corresponding to DataId get_data_ids()\[i\]
+    pub fn get_embedding(&self) -> &Array2<F> {
+        &self.embedding
+    }
+
+    /// returns DataIds and embedding
+    pub fn into_parts(self) -> (Vec<DataId>, Array2<F>) {
+        (self.data_ids, self.embedding)
+    }
+
+    /// returns the diffusion time used if the method was diffusion maps (None with commute time weighting)
+    pub fn get_diffusion_time(&self) -> Option<f64> {
+        self.diffusion_time
+    }
+
+    /// returns number of edges of the neighbourhood graph
+    pub fn get_nb_edges(&self) -> usize {
+        self.nb_edges
+    }
+
+    /// returns number of connected components of the neighbourhood graph (edges considered undirected)
+    pub fn get_nb_components(&self) -> usize {
+        self.nb_components
+    }
+
+    /// returns sys times in ms of hnsw construction, graph extraction and embedding
+    pub fn get_times_ms(&self) -> [u128; 3] {
+        self.times_ms
+    }
+
+    /// returns the report of the run : times of stages *hnsw*, *graph* and *embedding*, peak memory, graph statistics,
+    /// spectrum (diffusion maps) or final loss (Embedder)
+    pub fn get_report(&self) -> &RunReport {
+        &self.report
+    }
+}
+
+impl<F: Float> PipelineResult<F> {
+    /// writes the embedding with the label of the DataId of each row. The format (csv, json or npy with a labels sidecar)
+    /// is given by the extension of path, see [write_labeled_embedding].
+    pub fn write_labeled<T: Display>(&self, path: &Path, labels: &DataLabels<T>) -> Result<(), AnnembedError> {
+        write_labeled_embedding(path, labels, &self.data_ids, &self.embedding)
+    }
+} // end of impl PipelineResult
+
+/// Builder of the whole chain : Hnsw construction on data rows, extraction of the neighbourhood graph and embedding
+/// by the [Embedder] or diffusion maps.
+///
+/// ```ignore
+/// let mut pipeline = AnnEmbedPipeline::new(DistL2::default());
+/// pipeline.set_hnsw(HnswParams { max_nb_connection: 32, ..Default::default() })
+///     .set_diffusion_maps(DiffusionParams::new(2, None));
+/// let result = pipeline.run::<f32, f64, _>(&data, None)?;
+/// ```
+#[derive(Clone)]
+pub struct AnnEmbedPipeline<D> {
+    distance: D,
+    hnsw: HnswParams,
+    /// if set the graph is built by NN-Descent instead of a Hnsw
+    nndescent: Option<NNDescentParams>,
+    graph: GraphParams,
+    kernel: KernelParams,
+    method: EmbeddingMethod,
+}
+
+impl<D> AnnEmbedPipeline<D> {
+    /// a pipeline with distance and default parameters, embedding by the [Embedder] with [EmbedderParams::default]
+    pub fn new(distance: D) -> Self {
+        AnnEmbedPipeline {
+            distance,
+            hnsw: HnswParams::default(),
+            nndescent: None,
+            graph: GraphParams::default(),
+            kernel: KernelParams::default(),
+            method: EmbeddingMethod::Embedder(EmbedderParams::default()),
+        }
+    }
+
+    /// set Hnsw construction parameters
+    pub fn set_hnsw(&mut self, hnsw: HnswParams) -> &mut Self {
+        self.hnsw = hnsw;
+        self
+    }
+
+    /// build the graph of params.knbn neighbours by NN-Descent (see [nndescent](crate::fromhnsw::nndescent)) instead of
+    /// a Hnsw, in [run](Self::run). Hnsw and graph extraction parameters are then ignored.
+    pub fn set_nndescent(&mut self, params: NNDescentParams) -> &mut Self {
+        self.nndescent = Some(params);
+        self
+    }
+
+    /// set neighbourhood graph extraction parameters
+    pub fn set_graph(&mut self, graph: GraphParams) -> &mut Self {
+        self.graph = graph;
+        self
+    }
+
+    /// set kernel choices (used by diffusion maps)
+    pub fn set_kernel(&mut self, kernel: KernelParams) -> &mut Self {
+        self.kernel = kernel;
+        self
+    }
+
+    /// embed with the [Embedder]
+    pub fn set_embedder(&mut self, params: EmbedderParams) -> &mut Self {
+        self.method = EmbeddingMethod::Embedder(params);
+        self
+    }
+
+    /// embed with diffusion maps. Graph and kernel parameters of the pipeline override those of params.
+    pub fn set_diffusion_maps(&mut self, params: DiffusionParams) -> &mut Self {
+        self.method = EmbeddingMethod::DiffusionMaps(params);
+        self
+    }
+
+    /// returns the embedding method
+    pub fn get_method(&self) -> &EmbeddingMethod {
+        &self.method
+    }
+
+    // checks parameters before any computation
+    fn check(&self, nb_data: usize, nb_ids: Option<usize>) -> Result<(), AnnembedError> {
+        if nb_data < 2 {
+            return Err(AnnembedError::InvalidParameter(format!("pipeline needs at least 2 points, got {}", nb_data)));
+        }
+        if let Some(nb_ids) = nb_ids {
+            if nb_ids != nb_data {
+                return Err(AnnembedError::InvalidParameter(format!("nb ids {} != nb rows {}", nb_ids, nb_data)));
+            }
+        }
+        if self.hnsw.max_nb_connection == 0 || self.hnsw.ef_construction == 0 {
+            return Err(AnnembedError::InvalidParameter(String::from(
+                "hnsw max_nb_connection and ef_construction must be > 0",
+            )));
+        }
+        if self.graph.knbn == Some(0) {
+            return Err(AnnembedError::InvalidParameter(String::from("graph knbn must be > 0")));
+        }
+        if let Some(sparsification) = self.kernel.sparsification {
+            sparsification.check()?;
+        }
+        Ok(())
+    } // end of check
+
+    /// runs the pipeline on rows of data. Row i is inserted with DataId ids\[i\], or i if ids is None.  
+    /// T is the type of data, F the type of graph distances and of the embedding.
+    pub fn run<T, F, S>(&self, data: &ArrayBase<S, Ix2>, ids: Option<&[DataId]>) -> Result<PipelineResult<F>, AnnembedError>
+    where
+        T: Clone + Send + Sync,
+        D: Distance<T> + Clone + Send + Sync,
+        F: Float
+            + FromPrimitive
+            + Lapack
+            + Scalar
+            + ndarray::ScalarOperand
+            + Send
+            + Sync
+            + std::fmt::UpperExp
+            + std::iter::Sum,
+        S: Data<Elem = T> + Sync,
+    {
+        let nb_data = data.nrows();
+        self.check(nb_data, ids.map(|ids| ids.len()))?;
+        //
+        let mut monitor = ResourceMonitor::new();
+        if let Some(nndescent) = self.nndescent.as_ref() {
+            let kgraph: KGraph<F> = kgraph_from_nndescent(data, ids, nndescent, &self.distance)?;
+            let graph_ms = monitor.end_stage("graph") as u128;
+            return self.embed_kgraph(&kgraph, monitor, 0, graph_ms, nb_data);
+        }
+        let nb_layer = self.get_nb_layer(nb_data);
+        let mut hnsw = Hnsw::<T, D>::new(
+            self.hnsw.max_nb_connection,
+            nb_data,
+            nb_layer,
+            self.hnsw.ef_construction,
+            self.distance.clone(),
+        );
+        hnsw.set_keeping_pruned(self.hnsw.keep_pruned);
+        match ids {
+            Some(ids) => array2_insert_hnsw_with_ids(data, ids, &mut hnsw)?,
+            None => array2_insert_hnsw(data, &mut hnsw)?,
+        };
+        let hnsw_ms = monitor.end_stage("hnsw") as u128;
+        self.embed_from_hnsw(&hnsw, monitor, hnsw_ms, nb_data)
+    } // end of run
+
+    /// runs the pipeline on rows of a csr matrix (see [sparse](crate::sparse)), D being a distance on sparse rows
+    /// as [DistSparseCosine](crate::sparse::DistSparseCosine). Row i is inserted with DataId ids\[i\], or i if ids is None.
+    pub fn run_sparse<F>(&self, csr: &CsMat<f32>, ids: Option<&[DataId]>) -> Result<PipelineResult<F>, AnnembedError>
+    where
+        D: Distance<SparseEntry> + Clone + Send + Sync,
+        F: Float
+            + FromPrimitive
+            + Lapack
+            + Scalar
+            + ndarray::ScalarOperand
+            + Send
+            + Sync
+            + std::fmt::UpperExp
+            + std::iter::Sum,
+    {
+        let nb_data = csr.rows();
+        self.check(nb_data, ids.map(|ids| ids.len()))?;
+        //
+        let mut monitor = ResourceMonitor::new();
+        let mut hnsw = Hnsw::<SparseEntry, D>::new(
+            self.hnsw.max_nb_connection,
+            nb_data,
+            self.get_nb_layer(nb_data),
+            self.hnsw.ef_construction,
+            self.distance.clone(),
+        );
+        hnsw.set_keeping_pruned(self.hnsw.keep_pruned);
+        sparse_insert_hnsw(csr, ids, &mut hnsw)?;
+        let hnsw_ms = monitor.end_stage("hnsw") as u128;
+        self.embed_from_hnsw(&hnsw, monitor, hnsw_ms, nb_data)
+    } // end of run_sparse
+
+    // runs graph extraction and embedding on a built hnsw, monitor having closed the hnsw stage
+    fn embed_from_hnsw<T, F>(
+        &self,
+        hnsw: &Hnsw<T, D>,
+        mut monitor: ResourceMonitor,
+        hnsw_ms: u128,
+        nb_data: usize,
+    ) -> Result<PipelineResult<F>, AnnembedError>
+    where
+        T: Clone + Send + Sync,
+        D: Distance<T> + Send + Sync,
+        F: Float
+            + FromPrimitive
+            + Lapack
+            + Scalar
+            + ndarray::ScalarOperand
+            + Send
+            + Sync
+            + std::fmt::UpperExp
+            + std::iter::Sum,
+    {
+        let kgraph: KGraph<F> = kgraph_from_hnsw_params(hnsw, self.graph.knbn, self.graph.ef_search, self.graph.radius)?;
+        let graph_ms = monitor.end_stage("graph") as u128;
+        self.embed_kgraph(&kgraph, monitor, hnsw_ms, graph_ms, nb_data)
+    } // end of embed_from_hnsw
+
+    // runs the embedding method on the graph, monitor having closed the hnsw and graph stages
+    fn embed_kgraph<F>(
+        &self,
+        kgraph: &KGraph<F>,
+        mut monitor: ResourceMonitor,
+        hnsw_ms: u128,
+        graph_ms: u128,
+        nb_data: usize,
+    ) -> Result<PipelineResult<F>, AnnembedError>
+    where
+        F: Float
+            + FromPrimitive
+            + Lapack
+            + Scalar
+            + ndarray::ScalarOperand
+            + Send
+            + Sync
+            + std::fmt::UpperExp
+            + std::iter::Sum,
+    {
+        let graph_report = GraphReport::from_kgraph(kgraph);
+        log::info!(
+            "pipeline, hnsw built in {} ms, graph with {} nodes built in {} ms",
+            hnsw_ms,
+            kgraph.get_nb_nodes(),
+            graph_ms
+        );
+        //
+        let mut final_loss = None;
+        let mut spectral = None;
+        let (data_ids, embedding, diffusion_time) = match self.method {
+            EmbeddingMethod::Embedder(params) => {
+                let mut embedder = Embedder::new(kgraph, params);
+                embedder.embed()?;
+                let embedding = embedder
+                    .get_embedded()
+                    .ok_or(AnnembedError::Embedding(String::from("no embedding computed")))?
+                    .clone();
+                final_loss = embedder.get_final_loss();
+                (embedder.get_data_ids(), embedding, None)
+            }
+            EmbeddingMethod::DiffusionMaps(mut params) => {
+                if let Some(knbn) = self.graph.knbn {
+                    params.set_knbn(knbn);
+                }
+                if let Some(ef_search) = self.graph.ef_search {
+                    params.set_ef_search(ef_search);
+                }
+                if let Some(radius) = self.graph.radius {
+                    params.set_radius(radius);
+                }
+                if let Some(self_edge) = self.kernel.self_edge {
+                    params.set_self_edge(self_edge);
+                }
+                if let Some(sparsification) = self.kernel.sparsification {
+                    params.set_kernel_sparsification(sparsification)?;
+                }
+                if self.kernel.auto_scale {
+                    params.set_auto_scale(true);
+                }
+                if let Some(min_jaccard) = self.kernel.snn {
+                    params.set_snn(min_jaccard);
+                }
+                let mut dmaps = DiffusionMaps::new(params);
+                let embedding = dmaps.embed_kgraph::<F>(kgraph)?;
+                let data_ids = dmaps.get_data_ids().cloned().unwrap_or_default();
+                spectral = dmaps
+                    .get_eigenvalues()
+                    .map(|lambdas| SpectralReport::new(lambdas.clone(), dmaps.get_diffusion_time()));
+                (data_ids, embedding, dmaps.get_diffusion_time())
+            }
+        };
+        let embed_ms = monitor.end_stage("embedding") as u128;
+        let mut report = monitor.get_report(nb_data);
+        report.final_loss = final_loss;
+        report.spectral = spectral;
+        let (nb_edges, nb_components) = (graph_report.nb_edges, graph_report.nb_components);
+        report.graph = Some(graph_report);
+        //
+        Ok(PipelineResult {
+            data_ids,
+            embedding,
+            diffusion_time,
+            nb_edges,
+            nb_components,
+            times_ms: [hnsw_ms, graph_ms, embed_ms],
+            report,
+        })
+    } // end of embed_kgraph
+
+    // number of layers of the hnsw
+    fn get_nb_layer(&self, nb_data: usize) -> usize {
+        self.hnsw
+            .nb_layer
+            .unwrap_or_else(|| 16.min((nb_data as f32).ln().trunc() as usize).max(1))
+    }
+} // end of impl AnnEmbedPipeline
+
+/// Parameters of [embed] : all parameters of an [AnnEmbedPipeline], the distance being L2.
+/// Default runs the [Embedder] with default parameters on the graph of 24 neighbours.
+#[derive(Copy, Clone)]
+pub struct EmbedParams {
+    pub hnsw: HnswParams,
+    pub graph: GraphParams,
+    pub kernel: KernelParams,
+    pub method: EmbeddingMethod,
+}
+
+impl Default for EmbedParams {
+    fn default() -> Self {
+        EmbedParams {
+            hnsw: HnswParams::default(),
+            graph: GraphParams::default(),
+            kernel: KernelParams::default(),
+            method: EmbeddingMethod::Embedder(EmbedderParams::default()),
+        }
+    }
+} // end of impl Default for EmbedParams
+
+/// Diagnostics of an [embed] run
+#[derive(Clone, Debug)]
+pub struct EmbedDiagnostics {
+    /// number of points embedded
+    pub nb_points: usize,
+    /// number of edges of the neighbourhood graph
+    pub nb_edges: usize,
+    /// number of connected components of the neighbourhood graph. More than 1 component often gives scattered embeddings.
+    pub nb_components: usize,
+    /// diffusion time used by diffusion maps
+    pub diffusion_time: Option<f64>,
+    /// sys time (ms) of hnsw construction, graph extraction and embedding
+    pub times_ms: [u128; 3],
+    /// full report of the run (cpu time, peak memory, graph statistics, spectrum or final loss)
+    pub report: RunReport,
+}
+
+/// Output of [embed]
+pub struct EmbedOutput {
+    /// embedded coordinates, row i being the point with DataId data_ids\[i\]
+    pub coordinates: Array2<f32>,
+    /// DataId (rank of row in data) of each row of coordinates
+    pub data_ids: Vec<DataId>,
+    pub diagnostics: EmbedDiagnostics,
+}
+
+impl EmbedOutput {
+    /// returns coordinates with rows in the order of rows of data
+    pub fn get_coordinates_in_data_order(&self) -> Array2<f32> {
+        let mut reindexed = Array2::<f32>::zeros(self.coordinates.dim());
+        for (row, data_id) in self.data_ids.iter().enumerate() {
+            reindexed.row_mut(*data_id).assign(&self.coordinates.row(row));
+        }
+        reindexed
+    }
+
+    /// dumps coordinates (F32) and data_ids (U64) in safetensors format, see [safetensors](crate::tools::safetensors).
+    pub fn dump_safetensors(&self, path: &std::path::Path) -> Result<(), AnnembedError> {
+        let mut writer = SafeTensorsWriter::new();
+        writer.add_array2_f32("embedding", &self.coordinates)?;
+        writer.add_u64("data_ids", &self.data_ids)?;
+        writer.add_metadata("format", "annembed");
+        writer.write(path)
+    }
+} // end of impl EmbedOutput
+
+/// Embeds rows of data with the L2 distance : builds the Hnsw, extracts the neighbourhood graph and runs
+/// the method of params. Row i of data has DataId i.
+pub fn embed(data: ArrayView2<f32>, params: &EmbedParams) -> Result<EmbedOutput, AnnembedError> {
+    let mut pipeline = AnnEmbedPipeline::new(DistL2 {});
+    pipeline.set_hnsw(params.hnsw).set_graph(params.graph).set_kernel(params.kernel);
+    match params.method {
+        EmbeddingMethod::Embedder(embedder_params) => pipeline.set_embedder(embedder_params),
+        EmbeddingMethod::DiffusionMaps(dmap_params) => pipeline.set_diffusion_maps(dmap_params),
+    };
+    let result = pipeline.run::<f32, f32, _>(&data, None)?;
+    let diagnostics = EmbedDiagnostics {
+        nb_points: data.nrows(),
+        nb_edges: result.get_nb_edges(),
+        nb_components: result.get_nb_components(),
+        diffusion_time: result.get_diffusion_time(),
+        times_ms: result.get_times_ms(),
+        report: result.get_report().clone(),
+    };
+    let (data_ids, coordinates) = result.into_parts();
+    Ok(EmbedOutput {
+        coordinates,
+        data_ids,
+        diagnostics,
+    })
+} // end of embed
+
+//=======================================================================
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    fn log_init_test() {
+        let _ = env_logger::builder().is_test(true).try_init();
+    }
+
+    // removes the graph and data files of a hnsw dump
+    fn remove_hnsw_dump(dir: &Path, dumpname: &str) {
+        for suffix in ["hnsw.graph", "hnsw.data"] {
+            let _ = std::fs::remove_file(dir.join(format!("{}.{}", dumpname, suffix)));
+        }
+    }
+
+    #[test]
+    fn test_dump_reload_ids() {
+        log_init_test();
+        let data = Array2::<f32>::from_shape_fn((200, 3), |(i, j)| ((i * (j + 3)) % 17) as f32 + (i as f32 / 50.).floor());
+        let ids: Vec<DataId> = (0..data.nrows()).map(|i| 1000 + 3 * i).collect();
+        let dir = std::env::temp_dir();
+        let (hnsw, dumpname) = build_and_dump_hnsw(&data, &ids, 8, 48, DistL2 {}, &dir, "annembed_pipeline_test").unwrap();
+        let kgraph_built: KGraph<f32> = kgraph_from_hnsw_all(&hnsw, 8).unwrap();
+        // reload and check every DataId survives the dump
+        let mut hnswio = HnswIo::new(&dir, &dumpname);
+        let reloaded: Hnsw<f32, DistL2> = hnswio.load_hnsw().unwrap();
+        let kgraph_reloaded: KGraph<f32> = kgraph_from_hnsw_all(&reloaded, 8).unwrap();
+        assert_eq!(kgraph_reloaded.get_nb_nodes(), ids.len());
+        for id in &ids {
+            assert!(kgraph_built.get_idx_from_dataid(id).is_some());
+            assert!(kgraph_reloaded.get_idx_from_dataid(id).is_some());
+        }
+        remove_hnsw_dump(&dir, &dumpname);
+    } // end of test_dump_reload_ids
+
+    #[test]
+    fn test_embed_from_dump() {
+        log_init_test();
+        // 3 clusters, rows are distinct and DataIds are neither contiguous nor in row order
+        let nb_data = 150;
+        let data = Array2::<f32>::from_shape_fn((nb_data, 2), |(i, j)| match j {
+            0 => (i % 3) as f32 * 10. + i as f32 / nb_data as f32,
+            _ => ((i * 7 + 13) % 11) as f32 / 11.,
+        });
+        let ids: Vec<DataId> = (0..nb_data).map(|i| 1000 + 7 * ((i * 37) % nb_data)).collect();
+        let dir = std::env::temp_dir();
+        let dumpname = {
+            let (_, dumpname) = build_and_dump_hnsw(&data, &ids, 8, 48, DistL2 {}, &dir, "annembed_embed_dump").unwrap();
+            dumpname
+        };
+        let row_of_id: std::collections::HashMap<DataId, usize> = ids.iter().enumerate().map(|(i, id)| (*id, i)).collect();
+        let mut hnswio = HnswIo::new(&dir, &dumpname);
+        let reloaded: Hnsw<f32, DistL2> = hnswio.load_hnsw().unwrap();
+        // checks DataIds of embedding rows are a permutation of ids and designate the data rows stored in the dump
+        let check_ids = |data_ids: &[DataId]| {
+            let mut sorted = data_ids.to_vec();
+            sorted.sort_unstable();
+            let mut expected = ids.clone();
+            expected.sort_unstable();
+            assert_eq!(sorted, expected);
+            for id in data_ids {
+                let found = reloaded.search(data.row(row_of_id[id]).as_slice().unwrap(), 1, 16);
+                assert_eq!(found[0].d_id, *id);
+                assert_eq!(found[0].distance, 0.);
+            }
+        };
+        // checks embedding row i is the data row of DataId data_ids[i] : rows of a cluster (i % 3) are closer
+        // to the centroid of their cluster than to the other centroids
+        let check_clusters = |data_ids: &[DataId], embedding: &Array2<f32>| {
+            let cluster = |row: usize| row_of_id[&data_ids[row]] % 3;
+            let mut centroids = Array2::<f32>::zeros((3, embedding.ncols()));
+            let mut sizes = [0f32; 3];
+            for (row, coords) in embedding.rows().into_iter().enumerate() {
+                let mut centroid = centroids.row_mut(cluster(row));
+                centroid += &coords;
+                sizes[cluster(row)] += 1.;
+            }
+            for c in 0..3 {
+                centroids.row_mut(c).mapv_inplace(|x| x / sizes[c]);
+            }
+            for (row, coords) in embedding.rows().into_iter().enumerate() {
+                let dist = |c: usize| -> f32 { coords.iter().zip(centroids.row(c)).map(|(x, y)| (x - y) * (x - y)).sum() };
+                let nearest = (0..3).min_by(|a, b| dist(*a).total_cmp(&dist(*b))).unwrap();
+                assert_eq!(nearest, cluster(row), "row {} with DataId {} not in its cluster", row, data_ids[row]);
+            }
+        };
+        // diffusion maps from the dump
+        let mut dmaps = DiffusionMaps::new(DiffusionParams::new(2, Some(1.)));
+        let embedding = dmaps.embed_hnsw_dump::<f32, DistL2, f32>(&dir, &dumpname).unwrap();
+        assert_eq!(embedding.dim(), (nb_data, 2));
+        check_ids(dmaps.get_data_ids().unwrap());
+        check_clusters(dmaps.get_data_ids().unwrap(), &embedding);
+        // Embedder from the dump
+        let (data_ids, embedding) = embed_hnsw_dump::<f32, DistL2, f32>(&dir, &dumpname, 8, EmbedderParams::default()).unwrap();
+        assert_eq!(embedding.nrows(), nb_data);
+        check_ids(&data_ids);
+        check_clusters(&data_ids, &embedding);
+        remove_hnsw_dump(&dir, &dumpname);
+    } // end of test_embed_from_dump
+
+    #[test]
+    fn test_cosine_hnsw() {
+        log_init_test();
+        // rows 0 and 1 are colinear, row 2 orthogonal to them
+        let data = ndarray::arr2(&[[1., 0., 0.], [10., 0., 0.], [0., 3., 0.], [1., 1., 0.]]);
+        let hnsw = cosine_hnsw(&data, &[0, 1, 2, 3], 4, 16).unwrap();
+        // queries are normalized as rows, distances are 1 - cos
+        let found = cosine_search(&hnsw, &[2., 0., 0.], 2, 16);
+        assert!(found.iter().any(|n| n.d_id == 0) && found.iter().any(|n| n.d_id == 1));
+        assert!(found.iter().all(|n| n.distance < 1.0e-6));
+        let found = cosine_search(&hnsw, &[0., 5., 0.], 2, 16);
+        assert_eq!(found[0].d_id, 2);
+        assert_eq!(found[0].distance, 0.);
+        assert!((found[1].distance - (1. - 0.5f32.sqrt())).abs() < 1.0e-6);
+        // ids must be unique
+        assert!(cosine_hnsw(&data, &[0, 1, 1, 3], 4, 16).is_err());
+    } // end of test_cosine_hnsw
+
+    #[test]
+    fn test_pipeline() {
+        log_init_test();
+        // 3 clusters in dimension 5
+        let data = Array2::<f32>::from_shape_fn((300, 5), |(i, j)| {
+            let cluster = (i % 3) as f32 * 10.;
+            cluster + (((i * 7 + j * 13) % 11) as f32) / 11.
+        });
+        let ids: Vec<DataId> = (0..data.nrows()).map(|i| 2 * i).collect();
+        let mut pipeline = AnnEmbedPipeline::new(DistL2 {});
+        pipeline
+            .set_hnsw(HnswParams {
+                max_nb_connection: 12,
+                ef_construction: 64,
+                ..Default::default()
+            })
+            .set_graph(GraphParams {
+                knbn: Some(10),
+                ..Default::default()
+            })
+            .set_diffusion_maps(DiffusionParams::new(2, Some(1.)));
+        let result = pipeline.run::<f32, f64, _>(&data, Some(&ids)).unwrap();
+        assert_eq!(result.get_embedding().dim(), (300, 2));
+        let mut got = result.get_data_ids().to_vec();
+        got.sort_unstable();
+        assert_eq!(got, ids);
+        // report of a diffusion maps run has the spectrum but no loss
+        let report = result.get_report();
+        assert_eq!(report.nb_points, 300);
+        assert_eq!(report.graph.as_ref().unwrap().nb_edges, result.get_nb_edges());
+        assert!(report.spectral.is_some() && report.final_loss.is_none());
+        assert!(report.get_stage_ms("embedding").is_some());
+        // stages inside the embedding are profiled
+        for name in ["insertion", "graph", "node_params", "laplacian", "svd"] {
+            assert!(report.profile.iter().any(|p| p.name == name), "stage {} not profiled", name);
+        }
+        // labels follow the DataId of rows, not their rank
+        let labels = DataLabels::from_pairs(ids.iter().map(|id| (*id, format!("c{}", (id / 2) % 3))).collect());
+        let path = std::env::temp_dir().join(format!("annembed_pipeline_labeled_{}.csv", std::process::id()));
+        result.write_labeled(&path, &labels).unwrap();
+        let content = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        for (line, id) in content.lines().zip(result.get_data_ids()) {
+            assert!(line.starts_with(&format!("c{},", (id / 2) % 3)));
+        }
+        assert_eq!(content.lines().count(), 300);
+        // errors are detected before any computation
+        let res = pipeline.run::<f32, f64, _>(&data, Some(&ids[1..]));
+        assert!(matches!(res, Err(AnnembedError::InvalidParameter(_))));
+        pipeline.set_kernel(KernelParams {
+            sparsification: Some(KernelSparsification::TopK(0)),
+            ..Default::default()
+        });
+        let res = pipeline.run::<f32, f64, _>(&data, Some(&ids));
+        assert!(matches!(res, Err(AnnembedError::InvalidParameter(_))));
+    } // end of test_pipeline
+
+    #[test]
+    fn test_embed() {
+        log_init_test();
+        let data = Array2::<f32>::from_shape_fn((200, 4), |(i, j)| (i % 2) as f32 * 5. + (((i * 3 + j * 5) % 7) as f32) / 7.);
+        let params = EmbedParams {
+            hnsw: HnswParams {
+                max_nb_connection: 10,
+                ef_construction: 48,
+                ..Default::default()
+            },
+            method: EmbeddingMethod::DiffusionMaps(DiffusionParams::new(2, Some(1.))),
+            ..Default::default()
+        };
+        let output = embed(data.view(), &params).unwrap();
+        assert_eq!(output.coordinates.dim(), (200, 2));
+        assert_eq!(output.diagnostics.nb_points, 200);
+        assert!(output.diagnostics.nb_edges > 0 && output.diagnostics.nb_components >= 1);
+        // row of DataId 0 in data order is the row of coordinates having DataId 0
+        let in_order = output.get_coordinates_in_data_order();
+        let rank = output.data_ids.iter().position(|id| *id == 0).unwrap();
+        assert_eq!(in_order.row(0), output.coordinates.row(rank));
+    } // end of test_embed
+} // end of mod tests
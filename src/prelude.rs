@@ -2,4 +2,6 @@
 
 pub use crate::embedder::*;
 pub use crate::embedparams::*;
-pub use crate::tools::io::*;
\ No newline at end of file
+pub use crate::error::*;
+pub use crate::tools::io::*;
+pub use crate::tools::clip::Clipping;
@@ -0,0 +1,390 @@
+//! Unattended service mode : a directory is watched for new csv data files which are embedded, or placed in a reference
+//! embedding, and results are written in an output directory.
+//!
+//! - a [ReferenceArtifact] stores reference data rows with their DataIds, embedded coordinates and the name of the distance
+//!   of the embedding (bincode dump).
+//! - a [ReferenceTransformer] rebuilds the Hnsw of reference rows and places a new point at the mean of the coordinates
+//!   of its knbn nearest reference points, weighted by inverse distances. Only artifacts of the L2 distance are supported.
+//! - a [DirectoryWatcher] polls the input directory. A file is processed once its size is the same in two successive polls,
+//!   so files being copied are not read before completion. Each file *name.csv* gives a file *name.embedded.csv*
+//!   with one row of coordinates by row of data, in the same order.
+//!
+//! A file is processed once in the life of the watcher, unless its modification time changes. Files which cannot be read
+//! or embedded are logged and skipped.
+
+use std::collections::{HashMap, HashSet};
+use std::fs::OpenOptions;
+use std::io::{BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, SystemTime};
+
+use ndarray::{Array2, ArrayView2};
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use hnsw_rs::prelude::*;
+
+use crate::diffmaps::array2_insert_hnsw_with_ids;
+use crate::error::AnnembedError;
+use crate::pipeline::{embed, EmbedOutput, EmbedParams};
+use crate::tools::io::{get_toembed_from_csv, CsvArrayWriter, CsvOptions};
+
+/// Reference data rows and their embedding, to place new points with a [ReferenceTransformer].
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ReferenceArtifact {
+    /// reference rows
+    data: Array2<f32>,
+    /// DataId of each row of data and coordinates
+    data_ids: Vec<DataId>,
+    /// embedded coordinates of reference rows
+    coordinates: Array2<f32>,
+    /// number of reference neighbours used to place a point
+    knbn: usize,
+    /// name of the distance of the embedding, as "DistL2"
+    distance: String,
+} // end of ReferenceArtifact
+
+/// name of the distance of artifacts a [ReferenceTransformer] can use
+pub const REFERENCE_DISTANCE: &str = "DistL2";
+
+impl ReferenceArtifact {
+    /// row i of data and coordinates is the reference point with DataId data_ids\[i\].  
+    /// distance is the name of the distance used to embed data (as "DistL2"), see [REFERENCE_DISTANCE].
+    pub fn new(
+        data: Array2<f32>,
+        data_ids: Vec<DataId>,
+        coordinates: Array2<f32>,
+        knbn: usize,
+        distance: &str,
+    ) -> Result<Self, AnnembedError> {
+        if data.nrows() != data_ids.len() || coordinates.nrows() != data_ids.len() || knbn == 0 || data_ids.is_empty() {
+            return Err(AnnembedError::InvalidParameter(format!(
+                "ReferenceArtifact, nb rows of data {}, of coordinates {}, nb ids {}, knbn {}",
+                data.nrows(),
+                coordinates.nrows(),
+                data_ids.len(),
+                knbn
+            )));
+        }
+        Ok(ReferenceArtifact {
+            data,
+            data_ids,
+            coordinates,
+            knbn,
+            distance: String::from(distance),
+        })
+    }
+
+    /// artifact of data embedded by [embed] (row i of data has DataId i, L2 distance)
+    pub fn from_embed_output(data: ArrayView2<f32>, output: &EmbedOutput, knbn: usize) -> Result<Self, AnnembedError> {
+        let data_ids = (0..data.nrows()).collect();
+        ReferenceArtifact::new(data.to_owned(), data_ids, output.get_coordinates_in_data_order(), knbn, REFERENCE_DISTANCE)
+    }
+
+    /// name of the distance of the embedding
+    pub fn get_distance(&self) -> &str {
+        &self.distance
+    }
+
+    /// embedding dimension
+    pub fn get_dimension(&self) -> usize {
+        self.coordinates.ncols()
+    }
+
+    pub fn get_nb_points(&self) -> usize {
+        self.data_ids.len()
+    }
+
+    /// dumps the artifact in bincode format
+    pub fn dump(&self, path: &Path) -> Result<(), AnnembedError> {
+        let mut writer = BufWriter::new(OpenOptions::new().write(true).create(true).truncate(true).open(path)?);
+        bincode::serialize_into(&mut writer, self).map_err(|e| AnnembedError::Io(std::io::Error::other(e)))?;
+        writer.flush()?;
+        Ok(())
+    }
+
+    /// reloads an artifact dumped by [dump](Self::dump)
+    pub fn reload(path: &Path) -> Result<Self, AnnembedError> {
+        let reader = BufReader::new(OpenOptions::new().read(true).open(path)?);
+        let artifact: ReferenceArtifact =
+            bincode::deserialize_from(reader).map_err(|e| AnnembedError::Io(std::io::Error::other(e)))?;
+        ReferenceArtifact::new(artifact.data, artifact.data_ids, artifact.coordinates, artifact.knbn, &artifact.distance)
+    }
+} // end of impl ReferenceArtifact
+
+/// Places new points in the embedding of a [ReferenceArtifact].
+pub struct ReferenceTransformer {
+    artifact: ReferenceArtifact,
+    hnsw: Hnsw<'static, f32, DistL2>,
+    /// row of each DataId in the artifact
+    rank: HashMap<DataId, usize>,
+    ef_search: usize,
+} // end of ReferenceTransformer
+
+impl ReferenceTransformer {
+    /// builds the Hnsw of reference rows. The distance of the artifact must be [REFERENCE_DISTANCE].
+    pub fn new(artifact: ReferenceArtifact) -> Result<Self, AnnembedError> {
+        if artifact.distance != REFERENCE_DISTANCE {
+            return Err(AnnembedError::InvalidParameter(format!(
+                "ReferenceTransformer, artifact embedded with {}, only {} is supported",
+                artifact.distance, REFERENCE_DISTANCE
+            )));
+        }
+        let nb_data = artifact.get_nb_points();
+        let nb_layer = 16.min((nb_data.max(2) as f32).ln().trunc() as usize).max(1);
+        let max_nb_connection = artifact.knbn.max(16);
+        let mut hnsw = Hnsw::<f32, DistL2>::new(max_nb_connection, nb_data, nb_layer, 200, DistL2 {});
+        array2_insert_hnsw_with_ids(&artifact.data, &artifact.data_ids, &mut hnsw)?;
+        let rank = artifact.data_ids.iter().enumerate().map(|(r, d)| (*d, r)).collect();
+        let ef_search = (2 * artifact.knbn).max(48);
+        Ok(ReferenceTransformer { artifact, hnsw, rank, ef_search })
+    }
+
+    pub fn get_artifact(&self) -> &ReferenceArtifact {
+        &self.artifact
+    }
+
+    /// returns coordinates of rows of data, each being the mean of coordinates of its nearest reference points
+    /// weighted by inverse distances. A row equal to a reference point gets its coordinates.
+    pub fn transform(&self, data: ArrayView2<f32>) -> Result<Array2<f32>, AnnembedError> {
+        if data.ncols() != self.artifact.data.ncols() {
+            return Err(AnnembedError::InvalidParameter(format!(
+                "transform, data dimension {} != reference dimension {}",
+                data.ncols(),
+                self.artifact.data.ncols()
+            )));
+        }
+        let dim = self.artifact.get_dimension();
+        let rows: Vec<Vec<f32>> = data
+            .rows()
+            .into_iter()
+            .collect::<Vec<_>>()
+            .par_iter()
+            .map(|row| {
+                let row = row.to_vec();
+                let neighbours = self.hnsw.search(&row, self.artifact.knbn, self.ef_search);
+                let mut coords = vec![0f32; dim];
+                if let Some(exact) = neighbours.iter().find(|n| n.distance <= f32::EPSILON) {
+                    coords.copy_from_slice(self.artifact.coordinates.row(self.rank[&exact.d_id]).as_slice().unwrap());
+                    return coords;
+                }
+                let mut sum_weights = 0f32;
+                for n in &neighbours {
+                    let weight = 1. / n.distance;
+                    sum_weights += weight;
+                    let neighbour_coords = self.artifact.coordinates.row(self.rank[&n.d_id]);
+                    for j in 0..dim {
+                        coords[j] += weight * neighbour_coords[j];
+                    }
+                }
+                if sum_weights > 0. {
+                    coords.iter_mut().for_each(|c| *c /= sum_weights);
+                }
+                coords
+            })
+            .collect();
+        let mut transformed = Array2::<f32>::zeros((data.nrows(), dim));
+        for (i, coords) in rows.iter().enumerate() {
+            transformed.row_mut(i).assign(&ndarray::ArrayView1::from(coords));
+        }
+        Ok(transformed)
+    } // end of transform
+} // end of impl ReferenceTransformer
+
+/// What a [DirectoryWatcher] does with each new file
+pub enum WatchMode {
+    /// embeds each file independently with [embed]
+    Embed(EmbedParams),
+    /// places rows of each file in a reference embedding
+    Transform(ReferenceTransformer),
+}
+
+impl WatchMode {
+    fn process(&self, data: ArrayView2<f32>) -> Result<Array2<f32>, AnnembedError> {
+        match self {
+            WatchMode::Embed(params) => Ok(embed(data, params)?.get_coordinates_in_data_order()),
+            WatchMode::Transform(transformer) => transformer.transform(data),
+        }
+    }
+} // end of impl WatchMode
+
+/// Polls a directory for new csv files and writes their embedding in an output directory.
+pub struct DirectoryWatcher {
+    input_dir: PathBuf,
+    output_dir: PathBuf,
+    poll_interval: Duration,
+    delimiter: u8,
+    /// files waiting for their size and modification time to be stable
+    pending: HashMap<PathBuf, (u64, SystemTime)>,
+    /// files already processed (or failed), with their modification time
+    done: HashSet<(PathBuf, SystemTime)>,
+} // end of DirectoryWatcher
+
+impl DirectoryWatcher {
+    /// watches input_dir, writes in output_dir. Default poll interval is 2s and delimiter is ','.
+    pub fn new(input_dir: &Path, output_dir: &Path) -> Self {
+        DirectoryWatcher {
+            input_dir: input_dir.to_path_buf(),
+            output_dir: output_dir.to_path_buf(),
+            poll_interval: Duration::from_secs(2),
+            delimiter: b',',
+            pending: HashMap::new(),
+            done: HashSet::new(),
+        }
+    }
+
+    pub fn set_poll_interval(&mut self, poll_interval: Duration) -> &mut Self {
+        self.poll_interval = poll_interval;
+        self
+    }
+
+    /// delimiter of input and output csv files
+    pub fn set_delimiter(&mut self, delimiter: u8) -> &mut Self {
+        self.delimiter = delimiter;
+        self
+    }
+
+    /// output file of an input file : name.csv gives name.embedded.csv in output directory
+    pub fn get_output_path(&self, input: &Path) -> PathBuf {
+        let stem = input.file_stem().map_or(String::from("data"), |s| s.to_string_lossy().to_string());
+        self.output_dir.join(format!("{}.embedded.csv", stem))
+    }
+
+    // embeds one file and writes its output
+    fn process_file(&self, input: &Path, mode: &WatchMode) -> Result<PathBuf, AnnembedError> {
+        let rows = get_toembed_from_csv::<f32>(input, self.delimiter)
+            .map_err(|e| AnnembedError::InvalidParameter(format!("could not read {} : {}", input.display(), e)))?;
+        let nb_cols = rows.first().map_or(0, |r| r.len());
+        let data = Array2::from_shape_vec((rows.len(), nb_cols), rows.into_iter().flatten().collect())
+            .map_err(|e| AnnembedError::InvalidParameter(format!("{} : {}", input.display(), e)))?;
+        let embedded = mode.process(data.view())?;
+        let output = self.get_output_path(input);
+        let mut options = CsvOptions::default();
+        options.set_delimiter(self.delimiter);
+        let mut writer = CsvArrayWriter::to_path(&output, &options)
+            .map_err(|e| AnnembedError::Io(std::io::Error::other(e.to_string())))?;
+        for row in embedded.rows() {
+            writer.write_row(row.as_slice().unwrap())?;
+        }
+        writer.flush()?;
+        Ok(output)
+    } // end of process_file
+
+    /// scans the input directory once and processes csv files whose size and modification time did not change since
+    /// previous scan. A file already processed is processed again if its modification time changed.
+    /// Returns output files written.
+    pub fn poll_once(&mut self, mode: &WatchMode) -> Result<Vec<PathBuf>, AnnembedError> {
+        let mut ready = Vec::<(PathBuf, SystemTime)>::new();
+        for entry in std::fs::read_dir(&self.input_dir)? {
+            let path = entry?.path();
+            if !path.is_file() || path.extension().is_none_or(|e| e != "csv") {
+                continue;
+            }
+            let metadata = std::fs::metadata(&path)?;
+            let state = (metadata.len(), metadata.modified()?);
+            if self.done.contains(&(path.clone(), state.1)) {
+                continue;
+            }
+            match self.pending.insert(path.clone(), state) {
+                Some(previous) if previous == state && state.0 > 0 => ready.push((path, state.1)),
+                _ => (),
+            }
+        }
+        ready.sort();
+        let mut outputs = Vec::<PathBuf>::with_capacity(ready.len());
+        for (path, modified) in ready {
+            self.pending.remove(&path);
+            self.done.insert((path.clone(), modified));
+            match self.process_file(&path, mode) {
+                Ok(output) => {
+                    log::info!("DirectoryWatcher, {} embedded in {}", path.display(), output.display());
+                    outputs.push(output);
+                }
+                Err(e) => log::error!("DirectoryWatcher, could not process {} : {}", path.display(), e),
+            }
+        }
+        Ok(outputs)
+    } // end of poll_once
+
+    /// polls the input directory until stop is set. Returns the number of files embedded.
+    pub fn run(&mut self, mode: &WatchMode, stop: &AtomicBool) -> Result<usize, AnnembedError> {
+        if !self.input_dir.is_dir() {
+            return Err(AnnembedError::InvalidParameter(format!("{} is not a directory", self.input_dir.display())));
+        }
+        std::fs::create_dir_all(&self.output_dir)?;
+        log::info!(
+            "DirectoryWatcher watching {}, writing in {}",
+            self.input_dir.display(),
+            self.output_dir.display()
+        );
+        let mut nb_embedded = 0;
+        while !stop.load(Ordering::Relaxed) {
+            nb_embedded += self.poll_once(mode)?.len();
+            std::thread::sleep(self.poll_interval);
+        }
+        Ok(nb_embedded)
+    } // end of run
+} // end of impl DirectoryWatcher
+
+//=======================================================================
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_reference_transform() {
+        // reference points on a line, embedded as their abscissa
+        let data = Array2::<f32>::from_shape_fn((50, 2), |(i, j)| if j == 0 { i as f32 } else { 0. });
+        let coordinates = Array2::<f32>::from_shape_fn((50, 1), |(i, _)| i as f32);
+        let artifact = ReferenceArtifact::new(data.clone(), (0..50).collect(), coordinates.clone(), 2, "DistL2").unwrap();
+        let path = std::env::temp_dir().join("annembed_test_reference.bin");
+        artifact.dump(&path).unwrap();
+        let transformer = ReferenceTransformer::new(ReferenceArtifact::reload(&path).unwrap()).unwrap();
+        let _ = std::fs::remove_file(&path);
+        let new_points = ndarray::arr2(&[[10f32, 0.], [20.5, 0.]]);
+        let placed = transformer.transform(new_points.view()).unwrap();
+        assert_eq!(placed[[0, 0]], 10.);
+        assert!((placed[[1, 0]] - 20.5).abs() < 1.0E-4);
+        assert!(transformer.transform(ndarray::arr2(&[[1f32]]).view()).is_err());
+        // the distance is kept in the dump, other distances are rejected
+        let artifact = ReferenceArtifact::new(data, (0..50).collect(), coordinates, 2, "DistL1").unwrap();
+        artifact.dump(&path).unwrap();
+        let reloaded = ReferenceArtifact::reload(&path).unwrap();
+        let _ = std::fs::remove_file(&path);
+        assert_eq!(reloaded.get_distance(), "DistL1");
+        assert!(matches!(ReferenceTransformer::new(reloaded), Err(AnnembedError::InvalidParameter(_))));
+    } // end of test_reference_transform
+
+    #[test]
+    fn test_directory_watcher() {
+        let root = std::env::temp_dir().join("annembed_test_watch");
+        let _ = std::fs::remove_dir_all(&root);
+        let (input, output) = (root.join("in"), root.join("out"));
+        std::fs::create_dir_all(&input).unwrap();
+        std::fs::create_dir_all(&output).unwrap();
+        let data = Array2::<f32>::from_shape_fn((20, 2), |(i, j)| if j == 0 { i as f32 } else { 1. });
+        let coordinates = Array2::<f32>::from_shape_fn((20, 1), |(i, _)| i as f32);
+        let artifact = ReferenceArtifact::new(data, (0..20).collect(), coordinates, 2, "DistL2").unwrap();
+        let mode = WatchMode::Transform(ReferenceTransformer::new(artifact).unwrap());
+        std::fs::write(input.join("batch.csv"), "3.,1.\n7.,1.\n").unwrap();
+        std::fs::write(input.join("notes.txt"), "not data").unwrap();
+        let mut watcher = DirectoryWatcher::new(&input, &output);
+        // first poll only records sizes
+        assert!(watcher.poll_once(&mode).unwrap().is_empty());
+        let outputs = watcher.poll_once(&mode).unwrap();
+        assert_eq!(outputs, vec![output.join("batch.embedded.csv")]);
+        let written = std::fs::read_to_string(&outputs[0]).unwrap();
+        assert_eq!(written.lines().count(), 2);
+        // files are processed once
+        assert!(watcher.poll_once(&mode).unwrap().is_empty());
+        // unless they are modified
+        let file = std::fs::File::options().write(true).open(input.join("batch.csv")).unwrap();
+        file.set_modified(SystemTime::now() + Duration::from_secs(10)).unwrap();
+        assert!(watcher.poll_once(&mode).unwrap().is_empty());
+        assert_eq!(watcher.poll_once(&mode).unwrap().len(), 1);
+        let _ = std::fs::remove_dir_all(&root);
+    } // end of test_directory_watcher
+} // end of mod tests
@@ -0,0 +1,696 @@
+//! Sparse high dimensional data, as TF-IDF vectors of text documents.
+//!
+//! - A sparse row is inserted in Hnsw as a slice of [SparseEntry] (column index and value) sorted by column,
+//!   so no dense copy of a row is ever materialized and distances cost is linear in the number of non null entries.
+//! - [DistSparseCosine] is the cosine distance on these slices, [DistSparseDot] the cheaper dot distance for rows
+//!   normalized by [normalize_rows] (as DistDot of hnsw_rs for dense vectors) and [DistSparseL2] the euclidean distance.
+//!   [tfidf] transforms a csr matrix of term counts.
+//! - [SparseTextData] loads a csr matrix in MatrixMarket format with optional document ids and vocabulary files
+//!   (one string by line), as written by scikit-learn `mmwrite` of a TfidfVectorizer output.
+//! - [sparse_insert_hnsw] does the parallel insertion of csr rows, the graph is then extracted as for dense data.
+//!   [AnnEmbedPipeline::run_sparse](crate::pipeline::AnnEmbedPipeline::run_sparse) runs the whole chain and
+//!   [embed_sparse] is the one call version with the cosine distance, [embed_sparse_with] takes any distance on sparse rows.
+//! - [co_embed_sparse] embeds rows and columns (documents and terms) together : latent coordinates of both come from
+//!   a randomized svd of the matrix and are embedded in a shared space, see [CoEmbedOutput].
+
+use std::io::{BufRead, BufReader};
+use std::path::Path;
+
+use hnsw_rs::prelude::*;
+use ndarray::{s, Array1, Array2};
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+use sprs::CsMat;
+
+use crate::diffmaps::INSERTION_BLOCKSIZE;
+use crate::error::AnnembedError;
+use crate::pipeline::{AnnEmbedPipeline, EmbedDiagnostics, EmbedOutput, EmbedParams, EmbeddingMethod};
+use crate::tools::svdapprox::{MatRepr, RangeApproxMode, RangeRank, SvdApprox};
+use crate::tools::stage::Stage;
+
+/// A non null entry of a sparse vector. Slices of entries must be sorted by increasing index.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct SparseEntry {
+    pub index: u32,
+    pub value: f32,
+}
+
+/// Cosine distance $1 - <v_a,v_b>/(|v_a| |v_b|)$ between sparse vectors given as slices of [SparseEntry] sorted by index.
+/// A null vector is at distance 1 of all vectors.
+#[derive(Default, Copy, Clone)]
+pub struct DistSparseCosine;
+
+impl Distance<SparseEntry> for DistSparseCosine {
+    fn eval(&self, va: &[SparseEntry], vb: &[SparseEntry]) -> f32 {
+        let norm_a = va.iter().map(|e| e.value * e.value).sum::<f32>();
+        let norm_b = vb.iter().map(|e| e.value * e.value).sum::<f32>();
+        if norm_a <= 0. || norm_b <= 0. {
+            return 1.;
+        }
+        // merge of sorted indexes
+        let (mut i, mut j) = (0, 0);
+        let mut dot = 0f32;
+        while i < va.len() && j < vb.len() {
+            match va[i].index.cmp(&vb[j].index) {
+                std::cmp::Ordering::Less => i += 1,
+                std::cmp::Ordering::Greater => j += 1,
+                std::cmp::Ordering::Equal => {
+                    dot += va[i].value * vb[j].value;
+                    i += 1;
+                    j += 1;
+                }
+            }
+        }
+        (1. - dot / (norm_a * norm_b).sqrt()).max(0.)
+    }
+} // end of impl Distance for DistSparseCosine
+
+/// Dot distance $1 - <v_a,v_b>$ between sparse vectors of unit L2 norm (see [normalize_rows]) given as slices of
+/// [SparseEntry] sorted by index. It is the cosine distance without norm computations, as DistDot of hnsw_rs
+/// for dense vectors. Results of vectors not normalized are meaningless.
+#[derive(Default, Copy, Clone)]
+pub struct DistSparseDot;
+
+impl Distance<SparseEntry> for DistSparseDot {
+    fn eval(&self, va: &[SparseEntry], vb: &[SparseEntry]) -> f32 {
+        let (mut i, mut j) = (0, 0);
+        let mut dot = 0f32;
+        while i < va.len() && j < vb.len() {
+            match va[i].index.cmp(&vb[j].index) {
+                std::cmp::Ordering::Less => i += 1,
+                std::cmp::Ordering::Greater => j += 1,
+                std::cmp::Ordering::Equal => {
+                    dot += va[i].value * vb[j].value;
+                    i += 1;
+                    j += 1;
+                }
+            }
+        }
+        (1. - dot).max(0.)
+    }
+} // end of impl Distance for DistSparseDot
+
+/// Euclidean distance between sparse vectors given as slices of [SparseEntry] sorted by index.
+#[derive(Default, Copy, Clone)]
+pub struct DistSparseL2;
+
+impl Distance<SparseEntry> for DistSparseL2 {
+    fn eval(&self, va: &[SparseEntry], vb: &[SparseEntry]) -> f32 {
+        let (mut i, mut j) = (0, 0);
+        let mut dist2 = 0f32;
+        while i < va.len() || j < vb.len() {
+            let index_a = va.get(i).map_or(u32::MAX, |e| e.index);
+            let index_b = vb.get(j).map_or(u32::MAX, |e| e.index);
+            let delta = match index_a.cmp(&index_b) {
+                std::cmp::Ordering::Less => {
+                    i += 1;
+                    va[i - 1].value
+                }
+                std::cmp::Ordering::Greater => {
+                    j += 1;
+                    vb[j - 1].value
+                }
+                std::cmp::Ordering::Equal => {
+                    i += 1;
+                    j += 1;
+                    va[i - 1].value - vb[j - 1].value
+                }
+            };
+            dist2 += delta * delta;
+        }
+        dist2.sqrt()
+    }
+} // end of impl Distance for DistSparseL2
+
+/// returns row i of a csr matrix as entries sorted by index
+pub fn get_sparse_row(csr: &CsMat<f32>, i: usize) -> Vec<SparseEntry> {
+    let mut row: Vec<SparseEntry> = csr
+        .outer_view(i)
+        .map(|v| {
+            v.iter()
+                .map(|(j, x)| SparseEntry {
+                    index: j as u32,
+                    value: *x,
+                })
+                .collect()
+        })
+        .unwrap_or_default();
+    row.sort_unstable_by_key(|e| e.index);
+    row
+} // end of get_sparse_row
+
+/// TF-IDF transform of a csr matrix of term counts (documents in rows, terms in columns).
+/// The weight of term j in document i is $c_{ij} (1 + \ln((1+n)/(1+df_{j})))$ where n is the number of documents
+/// and $df_{j}$ the number of documents containing term j (smoothed idf as in scikit-learn), rows are then L2-normalized.
+pub fn tfidf(counts: &CsMat<f32>) -> Result<CsMat<f32>, AnnembedError> {
+    if !counts.is_csr() {
+        return Err(AnnembedError::MatrixRepresentation("csr"));
+    }
+    let nb_doc = counts.rows() as f32;
+    let mut df = vec![0f32; counts.cols()];
+    for row in counts.outer_iterator() {
+        for (j, c) in row.iter() {
+            if *c != 0. {
+                df[j] += 1.;
+            }
+        }
+    }
+    let idf: Vec<f32> = df.iter().map(|d| 1. + ((1. + nb_doc) / (1. + d)).ln()).collect();
+    let mut weighted = counts.clone();
+    for mut row in weighted.outer_iterator_mut() {
+        for (j, x) in row.iter_mut() {
+            *x *= idf[j];
+        }
+    }
+    normalize_rows(&weighted)
+} // end of tfidf
+
+/// returns a csr matrix with rows scaled to unit L2 norm, null rows stay null. Rows can then be compared with [DistSparseDot].
+pub fn normalize_rows(csr: &CsMat<f32>) -> Result<CsMat<f32>, AnnembedError> {
+    if !csr.is_csr() {
+        return Err(AnnembedError::MatrixRepresentation("csr"));
+    }
+    let mut normalized = csr.clone();
+    for mut row in normalized.outer_iterator_mut() {
+        let norm = row.iter().map(|(_, x)| *x * *x).sum::<f32>();
+        if norm > 0. {
+            let norm = norm.sqrt();
+            for (_, x) in row.iter_mut() {
+                *x /= norm;
+            }
+        }
+    }
+    Ok(normalized)
+} // end of normalize_rows
+
+/// Parallel insertion of the rows of a csr matrix into an empty Hnsw, row i being inserted with DataId ids\[i\]
+/// (or i if ids is None). Rows are converted to [SparseEntry] slices block by block.
+/// Returns number of point inserted if success.
+pub fn sparse_insert_hnsw<D>(
+    csr: &CsMat<f32>,
+    ids: Option<&[DataId]>,
+    hnsw: &mut Hnsw<SparseEntry, D>,
+) -> Result<usize, AnnembedError>
+where
+    D: Distance<SparseEntry> + Send + Sync,
+{
+    if !csr.is_csr() {
+        return Err(AnnembedError::MatrixRepresentation("csr"));
+    }
+    if hnsw.get_nb_point() > 0 {
+        log::error!(
+            "sparse_insert_hnsw , insertion on non empty hnsw structure, nb point : {}",
+            hnsw.get_nb_point()
+        );
+        return Err(AnnembedError::InvalidParameter(String::from("insertion in non empty hnsw")));
+    }
+    let nb_row = csr.rows();
+    if let Some(ids) = ids {
+        if ids.len() != nb_row {
+            return Err(AnnembedError::InvalidParameter(format!("nb ids {} != nb rows {}", ids.len(), nb_row)));
+        }
+    }
+    let stage = Stage::enter("insertion");
+    stage.record_size("nb_point", nb_row);
+    stage.record_size("dim", csr.cols());
+    stage.record_size("nnz", csr.nnz());
+    // blocks bound the memory of converted rows, the rows of a block are inserted by all threads
+    let mut nb_null = 0;
+    for start in (0..nb_row).step_by(INSERTION_BLOCKSIZE) {
+        let end = (start + INSERTION_BLOCKSIZE).min(nb_row);
+        let rows: Vec<Vec<SparseEntry>> = (start..end).into_par_iter().map(|i| get_sparse_row(csr, i)).collect();
+        nb_null += rows.iter().filter(|row| row.is_empty()).count();
+        let to_insert: Vec<(&[SparseEntry], DataId)> = rows
+            .iter()
+            .zip(start..end)
+            .map(|(row, i)| (row.as_slice(), ids.map_or(i, |ids| ids[i])))
+            .collect();
+        hnsw.parallel_insert_slice(&to_insert);
+        stage.report_progress(end, nb_row);
+    }
+    if nb_null > 0 {
+        log::warn!("sparse_insert_hnsw, {} null rows", nb_null);
+    }
+    Ok(hnsw.get_nb_point())
+} // end of sparse_insert_hnsw
+
+// reads a file with one string by line, a blank line gives an empty string so that line i stays at rank i
+fn read_lines(path: &Path) -> Result<Vec<String>, AnnembedError> {
+    let reader = BufReader::new(std::fs::File::open(path)?);
+    let mut lines = Vec::<String>::new();
+    for line in reader.lines() {
+        lines.push(String::from(line?.trim()));
+    }
+    Ok(lines)
+} // end of read_lines
+
+/// A csr matrix of documents (rows) by terms (columns) with optional document ids and vocabulary
+pub struct SparseTextData {
+    matrix: CsMat<f32>,
+    /// id of each row
+    row_ids: Option<Vec<String>>,
+    /// term of each column
+    vocabulary: Option<Vec<String>>,
+}
+
+impl SparseTextData {
+    /// a matrix in csr or csc representation (converted to csr), checked against sizes of row_ids and vocabulary
+    pub fn new(
+        matrix: CsMat<f32>,
+        row_ids: Option<Vec<String>>,
+        vocabulary: Option<Vec<String>>,
+    ) -> Result<Self, AnnembedError> {
+        let matrix = if matrix.is_csr() { matrix } else { matrix.to_csr() };
+        if let Some(ids) = row_ids.as_ref() {
+            if ids.len() != matrix.rows() {
+                return Err(AnnembedError::InvalidParameter(format!(
+                    "nb ids {} != nb rows {}",
+                    ids.len(),
+                    matrix.rows()
+                )));
+            }
+        }
+        if let Some(vocabulary) = vocabulary.as_ref() {
+            if vocabulary.len() != matrix.cols() {
+                return Err(AnnembedError::InvalidParameter(format!(
+                    "vocabulary size {} != nb columns {}",
+                    vocabulary.len(),
+                    matrix.cols()
+                )));
+            }
+        }
+        Ok(SparseTextData {
+            matrix,
+            row_ids,
+            vocabulary,
+        })
+    } // end of new
+
+    /// loads a matrix in MatrixMarket coordinate format, with optional files of row ids and vocabulary (one by line,
+    /// a blank line giving an empty id or term)
+    pub fn load(matrix_path: &Path, ids_path: Option<&Path>, vocabulary_path: Option<&Path>) -> Result<Self, AnnembedError> {
+        let trimat = sprs::io::read_matrix_market::<f32, usize, _>(matrix_path).map_err(|e| {
+            AnnembedError::InvalidParameter(format!("could not read matrix {} : {:?}", matrix_path.display(), e))
+        })?;
+        let matrix: CsMat<f32> = trimat.to_csr();
+        log::info!(
+            "read sparse matrix {}, nb rows : {}, nb columns : {}, nnz : {}",
+            matrix_path.display(),
+            matrix.rows(),
+            matrix.cols(),
+            matrix.nnz()
+        );
+        let row_ids = ids_path.map(read_lines).transpose()?;
+        let vocabulary = vocabulary_path.map(read_lines).transpose()?;
+        SparseTextData::new(matrix, row_ids, vocabulary)
+    } // end of load
+
+    /// replaces the counts by their TF-IDF weights, see [tfidf]
+    pub fn apply_tfidf(&mut self) -> Result<(), AnnembedError> {
+        self.matrix = tfidf(&self.matrix)?;
+        Ok(())
+    }
+
+    /// the csr matrix
+    pub fn get_matrix(&self) -> &CsMat<f32> {
+        &self.matrix
+    }
+
+    /// ids of rows if loaded
+    pub fn get_row_ids(&self) -> Option<&Vec<String>> {
+        self.row_ids.as_ref()
+    }
+
+    /// terms of columns if loaded
+    pub fn get_vocabulary(&self) -> Option<&Vec<String>> {
+        self.vocabulary.as_ref()
+    }
+
+    /// returns the (at most) nb terms of highest weight in row, empty without vocabulary
+    pub fn get_top_terms(&self, row: usize, nb: usize) -> Vec<(&str, f32)> {
+        let vocabulary = match self.vocabulary.as_ref() {
+            Some(vocabulary) => vocabulary,
+            None => return Vec::new(),
+        };
+        let mut entries = get_sparse_row(&self.matrix, row);
+        entries.sort_unstable_by(|a, b| b.value.total_cmp(&a.value));
+        entries
+            .iter()
+            .take(nb)
+            .map(|e| (vocabulary[e.index as usize].as_str(), e.value))
+            .collect()
+    } // end of get_top_terms
+} // end of impl SparseTextData
+
+/// Embeds rows of a csr matrix with the cosine distance : builds the Hnsw on sparse rows, extracts the neighbourhood graph
+/// and runs the method of params. Row i of data has DataId i.
+pub fn embed_sparse(csr: &CsMat<f32>, params: &EmbedParams) -> Result<EmbedOutput, AnnembedError> {
+    embed_sparse_with(csr, params, DistSparseCosine)
+} // end of embed_sparse
+
+/// Same as [embed_sparse] with a given distance on sparse rows, as [DistSparseDot] on rows normalized by [normalize_rows].
+/// Neither the rows nor the graph are densified, only the embedding is dense.
+pub fn embed_sparse_with<D>(csr: &CsMat<f32>, params: &EmbedParams, distance: D) -> Result<EmbedOutput, AnnembedError>
+where
+    D: Distance<SparseEntry> + Clone + Send + Sync,
+{
+    let mut pipeline = AnnEmbedPipeline::new(distance);
+    pipeline.set_hnsw(params.hnsw).set_graph(params.graph).set_kernel(params.kernel);
+    match params.method {
+        EmbeddingMethod::Embedder(embedder_params) => pipeline.set_embedder(embedder_params),
+        EmbeddingMethod::DiffusionMaps(dmap_params) => pipeline.set_diffusion_maps(dmap_params),
+    };
+    let result = pipeline.run_sparse::<f32>(csr, None)?;
+    let diagnostics = EmbedDiagnostics {
+        nb_points: csr.rows(),
+        nb_edges: result.get_nb_edges(),
+        nb_components: result.get_nb_components(),
+        diffusion_time: result.get_diffusion_time(),
+        times_ms: result.get_times_ms(),
+        report: result.get_report().clone(),
+    };
+    let (data_ids, coordinates) = result.into_parts();
+    Ok(EmbedOutput {
+        coordinates,
+        data_ids,
+        diagnostics,
+    })
+} // end of embed_sparse_with
+
+/// Parameters of [co_embed_sparse]
+#[derive(Copy, Clone)]
+pub struct CoEmbedParams {
+    /// dimension of the latent space given by the truncated svd, shared by rows and columns
+    pub rank: usize,
+    /// number of QR iterations of the randomized svd
+    pub nbiter: usize,
+    /// if true the matrix is scaled as $D_{r}^{-1/2} A D_{c}^{-1/2}$ (normalized bipartite graph of rows and columns)
+    /// before the svd and the first (trivial) singular triplet is dropped, as in spectral co-clustering.
+    /// Otherwise the svd is done on the matrix as in LSA.
+    pub bipartite: bool,
+    /// parameters of the graph and embedding run on latent coordinates of rows and columns together
+    pub embed: EmbedParams,
+}
+
+impl Default for CoEmbedParams {
+    fn default() -> Self {
+        CoEmbedParams {
+            rank: 50,
+            nbiter: 5,
+            bipartite: true,
+            embed: EmbedParams::default(),
+        }
+    }
+} // end of impl Default for CoEmbedParams
+
+/// Output of [co_embed_sparse] : rows and columns in the same embedded space
+pub struct CoEmbedOutput {
+    /// embedded coordinates of rows, in the order of rows of the matrix
+    pub row_coordinates: Array2<f32>,
+    /// embedded coordinates of columns, in the order of columns of the matrix
+    pub column_coordinates: Array2<f32>,
+    /// singular values of the latent space
+    pub singular_values: Array1<f32>,
+    pub diagnostics: EmbedDiagnostics,
+}
+
+impl CoEmbedOutput {
+    /// returns the (at most) nb columns nearest (L2) to row in the embedded space, with their distance
+    pub fn get_nearest_columns(&self, row: usize, nb: usize) -> Vec<(usize, f32)> {
+        let point = self.row_coordinates.row(row);
+        let mut dists: Vec<(usize, f32)> = self
+            .column_coordinates
+            .outer_iter()
+            .enumerate()
+            .map(|(j, c)| (j, (&c - &point).mapv(|x| x * x).sum().sqrt()))
+            .collect();
+        dists.sort_unstable_by(|a, b| a.1.total_cmp(&b.1));
+        dists.truncate(nb);
+        dists
+    } // end of get_nearest_columns
+} // end of impl CoEmbedOutput
+
+// scales entry (i,j) by 1/sqrt(d_i c_j) with d and c the sums of absolute values of row i and column j
+fn bipartite_normalize(csr: &CsMat<f32>) -> CsMat<f32> {
+    let mut row_deg = vec![0f32; csr.rows()];
+    let mut col_deg = vec![0f32; csr.cols()];
+    for (i, row) in csr.outer_iterator().enumerate() {
+        for (j, x) in row.iter() {
+            row_deg[i] += x.abs();
+            col_deg[j] += x.abs();
+        }
+    }
+    let inv_sqrt = |d: f32| if d > 0. { 1. / d.sqrt() } else { 0. };
+    let mut normalized = csr.clone();
+    for (i, mut row) in normalized.outer_iterator_mut().enumerate() {
+        let scale_i = inv_sqrt(row_deg[i]);
+        for (j, x) in row.iter_mut() {
+            *x *= scale_i * inv_sqrt(col_deg[j]);
+        }
+    }
+    normalized
+} // end of bipartite_normalize
+
+/// Co-embedding of rows and columns of a sparse matrix (documents and terms, cells and genes) in a shared space.
+///
+/// A truncated randomized svd $A \simeq U S V^{t}$ gives latent coordinates $U S^{1/2}$ of rows and $V S^{1/2}$ of columns,
+/// so that the dot product of a row and a column approximates their entry. Rows and columns are then embedded together
+/// with the cosine distance on latent coordinates by the graph and the method of params.embed.
+/// In the hnsw and the graph row i has DataId i and column j has DataId nb_rows + j.  
+/// Null rows or columns have a null latent vector, at the same distance of all other points, and are placed arbitrarily.
+pub fn co_embed_sparse(csr: &CsMat<f32>, params: &CoEmbedParams) -> Result<CoEmbedOutput, AnnembedError> {
+    if !csr.is_csr() {
+        return Err(AnnembedError::MatrixRepresentation("csr"));
+    }
+    let (nb_row, nb_col) = (csr.rows(), csr.cols());
+    // the trivial triplet of the normalized matrix is dropped
+    let nb_skip = usize::from(params.bipartite);
+    let rank = params.rank + nb_skip;
+    if params.rank == 0 || rank > nb_row.min(nb_col) {
+        return Err(AnnembedError::InvalidParameter(format!(
+            "co_embed_sparse rank {} must be in 1..{} for a ({},{}) matrix",
+            params.rank,
+            nb_row.min(nb_col) + 1 - nb_skip,
+            nb_row,
+            nb_col
+        )));
+    }
+    let matrepr = if params.bipartite {
+        MatRepr::from_csrmat(bipartite_normalize(csr))
+    } else {
+        MatRepr::from_csrmat(csr.clone())
+    };
+    let mut svdapprox = SvdApprox::new(&matrepr);
+    let svd_res = svdapprox.direct_svd(RangeApproxMode::RANK(RangeRank::new(rank, params.nbiter)))?;
+    let (s, u, vt) = match (svd_res.s, svd_res.u, svd_res.vt) {
+        (Some(s), Some(u), Some(vt)) => (s, u, vt),
+        _ => return Err(AnnembedError::SvdFailed(String::from("co_embed_sparse, svd did not return U and Vt"))),
+    };
+    let nb_latent = s.len().min(rank).saturating_sub(nb_skip);
+    if nb_latent == 0 {
+        return Err(AnnembedError::SvdFailed(String::from("co_embed_sparse, no singular value left")));
+    }
+    let singular_values = s.slice(s![nb_skip..nb_skip + nb_latent]).to_owned();
+    log::info!("co_embed_sparse, singular values : {:.3e}", singular_values);
+    // stack latent coordinates of rows then columns
+    let mut latent = Array2::<f32>::zeros((nb_row + nb_col, nb_latent));
+    for (k, sigma) in singular_values.iter().enumerate() {
+        let sqrt_sigma = sigma.max(0.).sqrt();
+        latent
+            .slice_mut(s![..nb_row, k])
+            .assign(&u.column(nb_skip + k).mapv(|x| x * sqrt_sigma));
+        latent
+            .slice_mut(s![nb_row.., k])
+            .assign(&vt.row(nb_skip + k).mapv(|x| x * sqrt_sigma));
+    }
+    // L2 distance on normalized latent vectors gives the neighbours of the cosine distance, null vectors stay at distance 1
+    let mut nb_null = 0;
+    for mut point in latent.outer_iter_mut() {
+        let norm = point.dot(&point).sqrt();
+        if norm > 0. {
+            point /= norm;
+        } else {
+            nb_null += 1;
+        }
+    }
+    if nb_null > 0 {
+        log::warn!("co_embed_sparse, {} null latent vectors", nb_null);
+    }
+    let mut pipeline = AnnEmbedPipeline::new(DistL2 {});
+    let embed_params = &params.embed;
+    pipeline.set_hnsw(embed_params.hnsw).set_graph(embed_params.graph).set_kernel(embed_params.kernel);
+    match embed_params.method {
+        EmbeddingMethod::Embedder(embedder_params) => pipeline.set_embedder(embedder_params),
+        EmbeddingMethod::DiffusionMaps(dmap_params) => pipeline.set_diffusion_maps(dmap_params),
+    };
+    let result = pipeline.run::<f32, f32, _>(&latent, None)?;
+    let diagnostics = EmbedDiagnostics {
+        nb_points: nb_row + nb_col,
+        nb_edges: result.get_nb_edges(),
+        nb_components: result.get_nb_components(),
+        diffusion_time: result.get_diffusion_time(),
+        times_ms: result.get_times_ms(),
+        report: result.get_report().clone(),
+    };
+    let (data_ids, coordinates) = result.into_parts();
+    let dim = coordinates.ncols();
+    let mut row_coordinates = Array2::<f32>::zeros((nb_row, dim));
+    let mut column_coordinates = Array2::<f32>::zeros((nb_col, dim));
+    for (i, data_id) in data_ids.iter().enumerate() {
+        if *data_id < nb_row {
+            row_coordinates.row_mut(*data_id).assign(&coordinates.row(i));
+        } else {
+            column_coordinates.row_mut(*data_id - nb_row).assign(&coordinates.row(i));
+        }
+    }
+    Ok(CoEmbedOutput {
+        row_coordinates,
+        column_coordinates,
+        singular_values,
+        diagnostics,
+    })
+} // end of co_embed_sparse
+
+//=======================================================================
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::diffmaps::DiffusionParams;
+    use crate::pipeline::{GraphParams, HnswParams};
+    use sprs::TriMat;
+
+    fn log_init_test() {
+        let _ = env_logger::builder().is_test(true).try_init();
+    }
+
+    #[test]
+    fn test_sparse_cosine() {
+        log_init_test();
+        let entry = |index, value| SparseEntry { index, value };
+        let a = [entry(0, 1.), entry(3, 1.)];
+        let b = [entry(3, 2.), entry(7, 2.)];
+        let dist = DistSparseCosine;
+        assert!((dist.eval(&a, &b) - 0.5).abs() < 1.0E-6);
+        assert!(dist.eval(&a, &a).abs() < 1.0E-6);
+        assert_eq!(dist.eval(&a, &[]), 1.);
+        // same as dense cosine
+        let dense = DistCosine.eval(&[1., 0., 0., 1., 0., 0., 0., 0.], &[0., 0., 0., 2., 0., 0., 0., 2.]);
+        assert!((dist.eval(&a, &b) - dense).abs() < 1.0E-6);
+    } // end of test_sparse_cosine
+
+    #[test]
+    fn test_sparse_dot_l2() {
+        log_init_test();
+        let mut rows = TriMat::<f32>::new((3, 10));
+        rows.add_triplet(0, 0, 3.);
+        rows.add_triplet(0, 3, 4.);
+        rows.add_triplet(1, 3, 2.);
+        rows.add_triplet(1, 7, 2.);
+        let csr: CsMat<f32> = rows.to_csr();
+        let normalized = normalize_rows(&csr).unwrap();
+        let (a, b, null) = (get_sparse_row(&normalized, 0), get_sparse_row(&normalized, 1), get_sparse_row(&normalized, 2));
+        assert!(null.is_empty());
+        // dot distance of normalized rows is the cosine distance
+        assert!((DistSparseDot.eval(&a, &b) - DistSparseCosine.eval(&a, &b)).abs() < 1.0E-6);
+        assert!(DistSparseDot.eval(&a, &a).abs() < 1.0E-6);
+        // l2 as dense l2, with indexes present in one row only
+        let (a, b) = (get_sparse_row(&csr, 0), get_sparse_row(&csr, 1));
+        let dense = DistL2.eval(&[3., 0., 0., 4., 0., 0., 0., 0.], &[0., 0., 0., 2., 0., 0., 0., 2.]);
+        assert!((DistSparseL2.eval(&a, &b) - dense).abs() < 1.0E-6);
+        assert!((DistSparseL2.eval(&a, &[]) - 5.).abs() < 1.0E-6);
+        assert!(normalize_rows(&csr.to_csc()).is_err());
+    } // end of test_sparse_dot_l2
+
+    #[test]
+    fn test_tfidf_and_insertion() {
+        log_init_test();
+        // 2 topics of documents on disjoint vocabularies, a term (column 8) in all documents
+        let nb_doc = 60;
+        let mut counts = TriMat::<f32>::new((nb_doc, 9));
+        for i in 0..nb_doc {
+            let first = 4 * (i % 2);
+            counts.add_triplet(i, first + i % 4, 1. + (i % 3) as f32);
+            counts.add_triplet(i, first + (i + 1) % 4, 1.);
+            counts.add_triplet(i, 8, 3.);
+        }
+        let counts: CsMat<f32> = counts.to_csr();
+        let weighted = tfidf(&counts).unwrap();
+        // idf of a term in all documents is 1, rows are normalized
+        let row = get_sparse_row(&weighted, 0);
+        let norm = row.iter().map(|e| e.value * e.value).sum::<f32>();
+        assert!((norm - 1.).abs() < 1.0E-5);
+        let w8 = row.iter().find(|e| e.index == 8).unwrap().value;
+        let w0 = row.iter().find(|e| e.index == 0).unwrap().value;
+        assert!(w8 / 3. < w0);
+        // neighbours of a document share its topic
+        let mut hnsw = Hnsw::<SparseEntry, DistSparseCosine>::new(8, nb_doc, 4, 48, DistSparseCosine);
+        let ids: Vec<DataId> = (0..nb_doc).map(|i| 100 + i).collect();
+        assert_eq!(sparse_insert_hnsw(&weighted, Some(&ids), &mut hnsw).unwrap(), nb_doc);
+        let neighbours = hnsw.search(&get_sparse_row(&weighted, 0), 5, 24);
+        assert!(neighbours.iter().all(|n| (n.d_id - 100) % 2 == 0));
+    } // end of test_tfidf_and_insertion
+
+    #[test]
+    fn test_read_lines() {
+        log_init_test();
+        // a blank line keeps the rank of the following lines
+        let path = std::env::temp_dir().join(format!("annembed_test_lines_{}.txt", std::process::id()));
+        std::fs::write(&path, "doc0\n\n doc2 \r\ndoc3\n").unwrap();
+        assert_eq!(read_lines(&path).unwrap(), vec!["doc0", "", "doc2", "doc3"]);
+        let _ = std::fs::remove_file(&path);
+    } // end of test_read_lines
+
+    #[test]
+    fn test_co_embed() {
+        log_init_test();
+        // 2 topics of documents on disjoint vocabularies of 6 terms, linked by a term (column 12) in some documents
+        let nb_doc = 120;
+        let mut counts = TriMat::<f32>::new((nb_doc, 13));
+        for i in 0..nb_doc {
+            let first = 6 * (i % 2);
+            counts.add_triplet(i, first + i % 6, 2. + (i % 3) as f32);
+            counts.add_triplet(i, first + (i + 2) % 6, 1.);
+            if i % 5 == 3 {
+                counts.add_triplet(i, 12, 1.);
+            }
+        }
+        let counts: CsMat<f32> = counts.to_csr();
+        let params = CoEmbedParams {
+            rank: 4,
+            embed: EmbedParams {
+                hnsw: HnswParams {
+                    max_nb_connection: 12,
+                    ef_construction: 48,
+                    ..Default::default()
+                },
+                graph: GraphParams {
+                    knbn: Some(10),
+                    ..Default::default()
+                },
+                method: EmbeddingMethod::DiffusionMaps(DiffusionParams::new(2, None)),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let output = co_embed_sparse(&counts, &params).unwrap();
+        assert_eq!(output.row_coordinates.dim(), (nb_doc, 2));
+        assert_eq!(output.column_coordinates.dim(), (13, 2));
+        assert_eq!(output.singular_values.len(), 4);
+        assert_eq!(output.diagnostics.nb_points, nb_doc + 13);
+        // nearest terms of a document are in the vocabulary of its topic or the linking term
+        for doc in [0, 1, 7, 56] {
+            let first = 6 * (doc % 2);
+            let nearest = output.get_nearest_columns(doc, 3);
+            assert!((first..first + 6).contains(&nearest[0].0));
+            assert!(nearest.iter().all(|(j, _)| *j == 12 || (first..first + 6).contains(j)));
+        }
+        // rank must be less than the dimensions of the matrix (one more for the trivial triplet)
+        let params = CoEmbedParams {
+            rank: 13,
+            ..Default::default()
+        };
+        assert!(matches!(co_embed_sparse(&counts, &params), Err(AnnembedError::InvalidParameter(_))));
+    } // end of test_co_embed
+} // end of mod tests
@@ -0,0 +1,545 @@
+//! Self-tuning spectral clustering.
+//!
+//! Implements Zelnik-Manor L., Perona P. Self-Tuning Spectral Clustering. NIPS 2004.
+//!
+//! - The affinity between a node i and its neighbour j is $\exp(-d_{ij}^{2}/(\sigma_{i} \sigma_{j}))$ where the local scale
+//!   $\sigma_{i}$ is the distance of node i to its K-th neighbour (K = 7 as in the paper).
+//! - The top eigenvectors of the normalized laplacian $D^{-1/2} A D^{-1/2}$ are computed and stored in the laplacian.
+//! - For each candidate number of clusters C, the C first eigenvectors are rotated to best align with canonical axes.
+//!   The alignment cost gives a quality in \[0,1\] and the number of clusters retained is the largest reaching the best quality.
+//! - Each node is assigned to the axis on which its rotated eigenvector row has maximal absolute value.
+//!
+
+use ndarray::{Array2, ArrayView2};
+use ndarray_linalg::{svddc::JobSvd, SVDDC};
+use num_traits::cast::FromPrimitive;
+use num_traits::Float;
+
+use crate::error::AnnembedError;
+use crate::fromhnsw::kgraph::KGraph;
+use crate::graphlaplace::*;
+use crate::tools::nodeparam::*;
+
+/// default rank of neighbour giving local scale
+const DEFAULT_KNN_SCALE: usize = 7;
+
+/// qualities within this tolerance of the best one are considered equal, we then retain the largest number of clusters
+const QUALITY_TOLERANCE: f64 = 1.0E-3;
+
+/// maximum number of gradient iterations in rotation optimization
+const MAX_ROTATION_ITER: usize = 200;
+
+/// maximum number of alternate iterations in rotation discretization
+const MAX_DISCRETIZATION_ITER: usize = 50;
+
+/// Parameters of self-tuning spectral clustering
+#[derive(Copy, Clone, Debug)]
+pub struct SpectralClustering {
+    /// maximum number of clusters searched
+    max_clusters: usize,
+    /// rank of neighbour defining local scale
+    knn_scale: usize,
+} // end of SpectralClustering
+
+/// result of spectral clustering
+#[derive(Clone, Debug)]
+pub struct SpectralClusters {
+    /// label of each node, indexed by node rank (NodeIdx) in the graph
+    labels: Vec<usize>,
+    /// number of clusters retained
+    nb_clusters: usize,
+    /// for each number of clusters tried, the quality of the rotation alignment
+    qualities: Vec<(usize, f64)>,
+} // end of SpectralClusters
+
+impl SpectralClusters {
+    /// labels indexed by node rank (NodeIdx). See [KGraph::get_data_id_from_idx] to go back to DataId
+    pub fn get_labels(&self) -> &Vec<usize> {
+        &self.labels
+    }
+
+    /// number of clusters retained
+    pub fn get_nb_clusters(&self) -> usize {
+        self.nb_clusters
+    }
+
+    /// quality (in \[0,1\], 1 being perfect alignment) for each number of clusters examined
+    pub fn get_qualities(&self) -> &Vec<(usize, f64)> {
+        &self.qualities
+    }
+} // end of impl SpectralClusters
+
+impl SpectralClustering {
+    /// max_clusters is the maximum number of clusters searched. It must be at least 2.
+    pub fn new(max_clusters: usize) -> Result<Self, AnnembedError> {
+        if max_clusters < 2 {
+            log::error!("SpectralClustering::new max_clusters must be at least 2, got {}", max_clusters);
+            return Err(AnnembedError::InvalidParameter(format!(
+                "spectral clustering needs max_clusters >= 2, got {}",
+                max_clusters
+            )));
+        }
+        Ok(SpectralClustering {
+            max_clusters,
+            knn_scale: DEFAULT_KNN_SCALE,
+        })
+    }
+
+    /// set rank of neighbour giving local scale (default 7). It must be at least 1.
+    pub fn set_knn_scale(&mut self, knn_scale: usize) -> Result<(), AnnembedError> {
+        if knn_scale < 1 {
+            log::error!("SpectralClustering::set_knn_scale knn_scale must be at least 1");
+            return Err(AnnembedError::InvalidParameter(String::from(
+                "spectral clustering needs knn_scale >= 1",
+            )));
+        }
+        self.knn_scale = knn_scale;
+        Ok(())
+    }
+
+    /// clusters the nodes of a KGraph. Neighbours are expected sorted by increasing distance as in KGraph construction.
+    pub fn cluster_kgraph<F>(&self, kgraph: &KGraph<F>) -> Result<SpectralClusters, AnnembedError>
+    where
+        F: Float + FromPrimitive + std::marker::Sync + Send + std::fmt::UpperExp + std::iter::Sum,
+    {
+        let nodeparams = get_self_tuning_affinity(kgraph, self.knn_scale);
+        let mut laplacian = get_laplacian(&nodeparams);
+        self.cluster_laplacian(&mut laplacian)
+    } // end of cluster_kgraph
+
+    // runs the svd and the rotation search on the stored eigenvectors
+    pub(crate) fn cluster_laplacian(
+        &self,
+        laplacian: &mut GraphLaplacian,
+    ) -> Result<SpectralClusters, AnnembedError> {
+        laplacian.do_svd(self.max_clusters)?;
+        let u = laplacian
+            .get_eigenvectors()
+            .ok_or(AnnembedError::MissingSvdResult("eigenvectors"))?;
+        let max_clusters = self.max_clusters.min(u.ncols());
+        if max_clusters < 2 {
+            return Err(AnnembedError::NotEnoughEigenvectors {
+                computed: u.ncols(),
+                needed: 2,
+            });
+        }
+        if let Some(lambdas) = laplacian.get_eigenvalues() {
+            log::debug!("spectral clustering, eigenvalues : {:?}", lambdas);
+        }
+        let x = u.mapv(|v| v as f64);
+        // as in the paper, the rotated vectors for C clusters are used (with the next eigenvector) to initialize C+1
+        let mut qualities = Vec::<(usize, f64)>::with_capacity(max_clusters);
+        let mut rotated: Vec<Array2<f64>> = Vec::with_capacity(max_clusters);
+        let mut current = x.slice(ndarray::s![.., ..1]).to_owned();
+        for c in 2..=max_clusters {
+            let mut init = Array2::<f64>::zeros((x.nrows(), c));
+            init.slice_mut(ndarray::s![.., ..c - 1]).assign(&current);
+            init.column_mut(c - 1).assign(&x.column(c - 1));
+            let (quality, z) = optimize_rotation(&init.view());
+            log::debug!("spectral clustering nb clusters : {}, quality : {:.3e}", c, quality);
+            qualities.push((c, quality));
+            current = z.clone();
+            rotated.push(z);
+        }
+        let best = qualities.iter().map(|q| q.1).fold(f64::MIN, f64::max);
+        let rank = qualities
+            .iter()
+            .rposition(|q| q.1 >= best - QUALITY_TOLERANCE)
+            .unwrap();
+        let nb_clusters = qualities[rank].0;
+        log::info!("spectral clustering retained {} clusters, quality : {:.3e}", nb_clusters, qualities[rank].1);
+        let labels = rotated[rank]
+            .rows()
+            .into_iter()
+            .map(|row| {
+                let mut argmax = 0;
+                for j in 1..row.len() {
+                    if row[j].abs() > row[argmax].abs() {
+                        argmax = j;
+                    }
+                }
+                argmax
+            })
+            .collect();
+        Ok(SpectralClusters {
+            labels,
+            nb_clusters,
+            qualities,
+        })
+    } // end of cluster_laplacian
+} // end of impl SpectralClustering
+
+//==========================================================================================
+
+/// computes local scales (distance to the knn_scale-th neighbour) and the self-tuned affinities
+/// $\exp(-d_{ij}^{2}/(\sigma_{i} \sigma_{j}))$ of a KGraph.
+pub(crate) fn get_self_tuning_affinity<F>(kgraph: &KGraph<F>, knn_scale: usize) -> NodeParams
+where
+    F: Float + FromPrimitive + std::marker::Sync + Send + std::fmt::UpperExp + std::iter::Sum,
+{
+    let scales: Vec<f32> = kgraph
+        .iter_neighbourhoods()
+        .map(|(_, edges)| {
+            if edges.is_empty() {
+                return 1.;
+            }
+            let rank = knn_scale.min(edges.len()) - 1;
+            let scale = edges[rank].weight.to_f32().unwrap();
+            if scale > 0. {
+                scale
+            } else {
+                f32::MIN_POSITIVE
+            }
+        })
+        .collect();
+    let params = kgraph
+        .iter_neighbourhoods()
+        .map(|(i, edges)| {
+            let affinities = edges
+                .iter()
+                .map(|edge| {
+                    let d = edge.weight.to_f32().unwrap();
+                    OutEdge::new(edge.get_node(), (-d * d / (scales[i] * scales[edge.get_node()])).exp())
+                })
+                .collect();
+            NodeParam::new(scales[i], affinities)
+        })
+        .collect();
+    NodeParams::new(params, kgraph.get_max_nbng())
+} // end of get_self_tuning_affinity
+
+// returns the list of (i,j) pairs i < j of the Givens rotations parameterizing a rotation of dimension dim
+fn givens_pairs(dim: usize) -> Vec<(usize, usize)> {
+    let mut pairs = Vec::with_capacity(dim * (dim - 1) / 2);
+    for i in 0..dim {
+        for j in (i + 1)..dim {
+            pairs.push((i, j));
+        }
+    }
+    pairs
+}
+
+// Givens rotation (or its derivative w.r.t. theta if derivative is true)
+fn givens(dim: usize, pair: (usize, usize), theta: f64, derivative: bool) -> Array2<f64> {
+    let (i, j) = pair;
+    let (c, s) = (theta.cos(), theta.sin());
+    let mut g = if derivative {
+        Array2::<f64>::zeros((dim, dim))
+    } else {
+        Array2::<f64>::eye(dim)
+    };
+    if derivative {
+        g[[i, i]] = -s;
+        g[[i, j]] = -c;
+        g[[j, i]] = c;
+        g[[j, j]] = -s;
+    } else {
+        g[[i, i]] = c;
+        g[[i, j]] = -s;
+        g[[j, i]] = s;
+        g[[j, j]] = c;
+    }
+    g
+}
+
+// rotation as the product of Givens rotations. If k is Some, the k-th factor is replaced by its derivative.
+fn build_rotation(dim: usize, pairs: &[(usize, usize)], thetas: &[f64], k: Option<usize>) -> Array2<f64> {
+    let mut r = Array2::<f64>::eye(dim);
+    for (l, pair) in pairs.iter().enumerate() {
+        r = r.dot(&givens(dim, *pair, thetas[l], k == Some(l)));
+    }
+    r
+}
+
+// alignment cost J = sum_i sum_j Z_ij^2 / M_i^2 where M_i = max_j |Z_ij|
+fn alignment_cost(z: &Array2<f64>) -> f64 {
+    z.rows()
+        .into_iter()
+        .map(|row| {
+            let m2 = row.iter().fold(0f64, |acc, v| acc.max(v * v));
+            if m2 > 0. {
+                row.iter().map(|v| v * v).sum::<f64>() / m2
+            } else {
+                0.
+            }
+        })
+        .sum()
+}
+
+// gradient of alignment cost w.r.t. Givens angles
+fn alignment_gradient(x: &ArrayView2<f64>, pairs: &[(usize, usize)], thetas: &[f64]) -> Vec<f64> {
+    let dim = x.ncols();
+    let z = x.dot(&build_rotation(dim, pairs, thetas, None));
+    // index of max absolute value on each row
+    let argmax: Vec<usize> = z
+        .rows()
+        .into_iter()
+        .map(|row| {
+            let mut m = 0;
+            for j in 1..row.len() {
+                if row[j].abs() > row[m].abs() {
+                    m = j;
+                }
+            }
+            m
+        })
+        .collect();
+    let mut gradient = vec![0f64; pairs.len()];
+    for k in 0..pairs.len() {
+        let a = x.dot(&build_rotation(dim, pairs, thetas, Some(k)));
+        let mut grad = 0f64;
+        for i in 0..z.nrows() {
+            let m = z[[i, argmax[i]]];
+            if m == 0. {
+                continue;
+            }
+            let dm = a[[i, argmax[i]]];
+            let m2 = m * m;
+            for j in 0..dim {
+                grad += 2. * z[[i, j]] * a[[i, j]] / m2 - 2. * z[[i, j]] * z[[i, j]] * dm / (m2 * m);
+            }
+        }
+        gradient[k] = grad;
+    }
+    gradient
+}
+
+// Rotates rows of x to best align with canonical axes by gradient descent on Givens angles.
+// Returns the quality 1 - (J/n - 1)/C in [0,1] and the rotated matrix.
+pub(crate) fn optimize_rotation(x: &ArrayView2<f64>) -> (f64, Array2<f64>) {
+    let (nbrow, dim) = x.dim();
+    let pairs = givens_pairs(dim);
+    let mut thetas = vec![0f64; pairs.len()];
+    let mut z = x.to_owned();
+    let mut cost = alignment_cost(&z);
+    let mut alpha = 1.0f64;
+    for _ in 0..MAX_ROTATION_ITER {
+        let gradient = alignment_gradient(x, &pairs, &thetas);
+        let new_thetas: Vec<f64> = thetas
+            .iter()
+            .zip(gradient.iter())
+            .map(|(t, g)| t - alpha * g / nbrow as f64)
+            .collect();
+        let new_z = x.dot(&build_rotation(dim, &pairs, &new_thetas, None));
+        let new_cost = alignment_cost(&new_z);
+        if new_cost < cost {
+            let improvement = (cost - new_cost) / cost;
+            thetas = new_thetas;
+            z = new_z;
+            cost = new_cost;
+            if improvement < 1.0E-6 {
+                break;
+            }
+        } else {
+            alpha *= 0.5;
+            if alpha < 1.0E-6 {
+                break;
+            }
+        }
+    }
+    let quality = 1. - (cost / nbrow as f64 - 1.) / dim as f64;
+    (quality, z)
+} // end of optimize_rotation
+
+/// Discrete assignment of the discretization of Yu S.X., Shi J. Multiclass Spectral Clustering. ICCV 2003.
+#[derive(Clone, Debug)]
+pub struct RotationAssignment {
+    /// label of each row, clusters left empty by the discretization are removed so labels are contiguous from 0
+    pub labels: Vec<usize>,
+    /// normalized rows rotated to be near indicator vectors of their cluster
+    pub indicators: Array2<f64>,
+    /// mean over rows of the coordinate on the assigned axis, 1 for exact indicators
+    pub alignment: f64,
+    pub nb_iter: usize,
+}
+
+/// Rows of eigenvectors (one row by node, columns being the C first eigenvectors of the normalized laplacian, the stationary
+/// one included) are normalized to the unit sphere, then we alternate :
+/// - discrete assignment of each row to the axis of its maximal rotated coordinate,
+/// - rotation R closest to the assignment indicators Y, $R = V U^{t}$ with $Y^{t} X = U \Sigma V^{t}$,
+///
+/// until the objective $\sum \Sigma$ is stable. The initial rotation is made of rows as orthogonal as possible.  
+/// It is an alternative to k-means on spectral coordinates with no random initialization.
+pub fn discretize_by_rotation(eigenvectors: &ArrayView2<f64>) -> Result<RotationAssignment, AnnembedError> {
+    let (nbrow, nb_clusters) = eigenvectors.dim();
+    if nb_clusters < 2 || nbrow < nb_clusters {
+        return Err(AnnembedError::InvalidParameter(format!(
+            "discretize_by_rotation, {} rows for {} clusters",
+            nbrow, nb_clusters
+        )));
+    }
+    let mut x = eigenvectors.to_owned();
+    for mut row in x.rows_mut() {
+        let norm = row.dot(&row).sqrt();
+        if norm > 0. {
+            row /= norm;
+        }
+    }
+    // initial rotation : first row, then successively the row least aligned with rows already chosen
+    let mut rotation = Array2::<f64>::zeros((nb_clusters, nb_clusters));
+    rotation.column_mut(0).assign(&x.row(0));
+    let mut alignments = ndarray::Array1::<f64>::zeros(nbrow);
+    for j in 1..nb_clusters {
+        alignments += &x.dot(&rotation.column(j - 1)).mapv(f64::abs);
+        let next = alignments
+            .iter()
+            .enumerate()
+            .fold((0, f64::MAX), |best, (i, a)| if *a < best.1 { (i, *a) } else { best })
+            .0;
+        rotation.column_mut(j).assign(&x.row(next));
+    }
+    //
+    let mut labels = vec![0usize; nbrow];
+    let mut last_objective = 0.;
+    let mut nb_iter = 0;
+    while nb_iter < MAX_DISCRETIZATION_ITER {
+        nb_iter += 1;
+        let z = x.dot(&rotation);
+        labels = z.rows().into_iter().map(|row| argmax(row.iter().copied())).collect();
+        // Y^t X, Y being the indicator matrix of labels
+        let mut cross = Array2::<f64>::zeros((nb_clusters, nb_clusters));
+        for (i, label) in labels.iter().enumerate() {
+            let mut row = cross.row_mut(*label);
+            row += &x.row(i);
+        }
+        let (u, sigma, vt) = cross
+            .svddc(JobSvd::All)
+            .map_err(|e| AnnembedError::SvdFailed(e.to_string()))?;
+        let u = u.ok_or(AnnembedError::MissingSvdResult("left singular vectors"))?;
+        let vt = vt.ok_or(AnnembedError::MissingSvdResult("right singular vectors"))?;
+        let objective = sigma.sum();
+        log::debug!("discretize_by_rotation iteration {}, objective : {:.6e}", nb_iter, objective);
+        if (objective - last_objective).abs() <= 1.0E-10 * nbrow as f64 {
+            break;
+        }
+        last_objective = objective;
+        rotation = vt.t().dot(&u.t());
+    }
+    let indicators = x.dot(&rotation);
+    labels = indicators.rows().into_iter().map(|row| argmax(row.iter().copied())).collect();
+    let alignment = labels.iter().enumerate().map(|(i, l)| indicators[[i, *l]]).sum::<f64>() / nbrow as f64;
+    // contiguous labels
+    let mut new_label = vec![usize::MAX; nb_clusters];
+    let mut nb_used = 0;
+    for label in labels.iter_mut() {
+        if new_label[*label] == usize::MAX {
+            new_label[*label] = nb_used;
+            nb_used += 1;
+        }
+        *label = new_label[*label];
+    }
+    log::info!(
+        "rotation discretization, nb clusters : {} (asked {}), alignment : {:.3e}, nb iterations : {}",
+        nb_used,
+        nb_clusters,
+        alignment,
+        nb_iter
+    );
+    Ok(RotationAssignment {
+        labels,
+        indicators,
+        alignment,
+        nb_iter,
+    })
+} // end of discretize_by_rotation
+
+// rank of the maximal value
+fn argmax(values: impl Iterator<Item = f64>) -> usize {
+    values
+        .enumerate()
+        .fold((0, f64::MIN), |best, (j, v)| if v > best.1 { (j, v) } else { best })
+        .0
+}
+
+//==========================================================================================
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    fn log_init_test() {
+        let _ = env_logger::builder().is_test(true).try_init();
+    }
+
+    #[test]
+    fn test_rotation_recovers_indicators() {
+        log_init_test();
+        // 3 clusters indicator vectors rotated by a rotation, optimization must recover perfect alignment
+        let nbrow = 30;
+        let mut indicators = Array2::<f64>::zeros((nbrow, 3));
+        for i in 0..nbrow {
+            indicators[[i, i % 3]] = 1.;
+        }
+        let pairs = givens_pairs(3);
+        let rotation = build_rotation(3, &pairs, &[0.3, -0.2, 0.5], None);
+        let x = indicators.dot(&rotation);
+        let quality_before = 1. - (alignment_cost(&x) / nbrow as f64 - 1.) / 3.;
+        let (quality, z) = optimize_rotation(&x.view());
+        log::info!("quality before : {:.3e} after : {:.3e}", quality_before, quality);
+        assert!(quality > 0.99);
+        assert!(quality > quality_before);
+        // rows of the same cluster go to the same axis
+        let label = |i: usize| {
+            let row = z.row(i);
+            (0..3).max_by(|a, b| row[*a].abs().partial_cmp(&row[*b].abs()).unwrap()).unwrap()
+        };
+        for i in 3..nbrow {
+            assert_eq!(label(i), label(i % 3));
+        }
+    } // end of test_rotation_recovers_indicators
+
+    #[test]
+    fn test_discretize_by_rotation() {
+        log_init_test();
+        // normalized indicators of 3 clusters of unequal sizes, with noise, rotated
+        let nbrow = 60;
+        let cluster = |i: usize| if i < 30 { 0 } else if i < 45 { 1 } else { 2 };
+        let x = Array2::<f64>::from_shape_fn((nbrow, 3), |(i, j)| {
+            let noise = 0.05 * ((7 * i + 3 * j) % 5) as f64 / 5.;
+            if cluster(i) == j { 1. } else { noise }
+        });
+        let rotation = build_rotation(3, &givens_pairs(3), &[0.7, -0.4, 1.1], None);
+        let assignment = discretize_by_rotation(&x.dot(&rotation).view()).unwrap();
+        log::info!("alignment : {:.3e}, nb iter : {}", assignment.alignment, assignment.nb_iter);
+        assert!(assignment.alignment > 0.95);
+        let labels = &assignment.labels;
+        for i in 0..nbrow {
+            assert_eq!(labels[i], labels[[0, 30, 45][cluster(i)]]);
+        }
+        assert_ne!(labels[0], labels[30]);
+        assert_ne!(labels[30], labels[45]);
+        assert_ne!(labels[0], labels[45]);
+    } // end of test_discretize_by_rotation
+
+    #[test]
+    fn test_spectral_two_cliques() {
+        log_init_test();
+        // two cliques of 10 nodes joined by a weak edge
+        let nb = 20;
+        let params = (0..nb)
+            .map(|i| {
+                let mut edges: Vec<OutEdge<f32>> = (0..nb)
+                    .filter(|j| *j != i && j / 10 == i / 10)
+                    .map(|j| OutEdge::new(j, 1.))
+                    .collect();
+                if i == 0 {
+                    edges.push(OutEdge::new(10, 0.01));
+                }
+                NodeParam::new(1., edges)
+            })
+            .collect();
+        let nodeparams = NodeParams::new(params, nb);
+        let mut laplacian = get_laplacian(&nodeparams);
+        let clusters = SpectralClustering::new(4).unwrap().cluster_laplacian(&mut laplacian).unwrap();
+        log::info!("qualities : {:?}", clusters.get_qualities());
+        assert_eq!(clusters.get_nb_clusters(), 2);
+        let labels = clusters.get_labels();
+        assert!(labels[..10].iter().all(|l| *l == labels[0]));
+        assert!(labels[10..].iter().all(|l| *l == labels[10]));
+        assert_ne!(labels[0], labels[10]);
+        // invalid parameters are errors
+        assert!(SpectralClustering::new(1).is_err());
+        assert!(SpectralClustering::new(2).unwrap().set_knn_scale(0).is_err());
+    } // end of test_spectral_two_cliques
+} // end of mod tests
@@ -0,0 +1,357 @@
+//! Interoperability with [AnnData](https://anndata.readthedocs.io) .h5ad files, as used by scanpy in single-cell genomics.
+//!
+//! [read_h5ad] reads the matrix X (dense, csr or csc) and optionally a column of obs as labels.
+//! A dense X can be inserted in Hnsw with [array2_insert_hnsw](crate::diffmaps::array2_insert_hnsw), a sparse one with
+//! [sparse_insert_hnsw](crate::sparse::sparse_insert_hnsw) : DataId of a cell is then its row in X.
+//! [write_h5ad_obsm] stores an embedding in obsm\[key\] of an existing file, [write_embedder_h5ad] and [write_dmap_h5ad]
+//! store the result of an Embedder or of a DiffusionMaps in obsm\["X_annembed"\], reindexed by DataId,
+//! so that `scanpy.pl.embedding(adata, basis="annembed")` works directly.
+//!
+//! We follow the anndata on-disk specification from version 0.8 (groups with *encoding-type* attributes).
+//! Files written by older anndata versions (obs as a compound dataset) and fixed length string columns are not supported.
+//!
+//! This module requires feature *anndata*, which links to the hdf5 C library (found via HDF5_DIR or pkg-config)
+//! through the safe bindings of the hdf5-metno crate.
+
+use std::path::Path;
+
+use hdf5::types::{TypeDescriptor, VarLenUnicode};
+use hdf5::{Dataset, File, Group, Location, LocationType};
+
+use ndarray::Array2;
+use num_traits::Float;
+use sprs::CsMat;
+
+use hnsw_rs::prelude::DataId;
+use ndarray_linalg::{Lapack, Scalar};
+
+use crate::diffmaps::DiffusionMaps;
+use crate::embedder::Embedder;
+use crate::error::AnnembedError;
+
+/// obsm key used by [write_embedder_h5ad] and [write_dmap_h5ad]
+pub const OBSM_KEY: &str = "X_annembed";
+
+fn hdf5_error(e: hdf5::Error) -> AnnembedError {
+    AnnembedError::Io(std::io::Error::other(format!("hdf5 : {}", e)))
+}
+
+// true if name is a group, false if it is a dataset
+fn is_group(loc: &Group, name: &str) -> Result<bool, AnnembedError> {
+    Ok(loc.loc_type_by_name(name).map_err(hdf5_error)? == LocationType::Group)
+}
+
+// strings of a dataset, stored with variable length as anndata does
+fn read_string_dataset(dataset: &Dataset, name: &str) -> Result<Vec<String>, AnnembedError> {
+    match dataset.dtype().and_then(|t| t.to_descriptor()).map_err(hdf5_error)? {
+        TypeDescriptor::VarLenUnicode | TypeDescriptor::VarLenAscii => Ok(dataset
+            .read_raw::<VarLenUnicode>()
+            .map_err(hdf5_error)?
+            .into_iter()
+            .map(|s| s.as_str().to_string())
+            .collect()),
+        descriptor => Err(AnnembedError::InvalidParameter(format!(
+            "{} does not contain variable length strings but {:?}",
+            name, descriptor
+        ))),
+    }
+} // end of read_string_dataset
+
+// value of a scalar string attribute, None if absent
+fn read_string_attr(object: &Location, name: &str) -> Result<Option<String>, AnnembedError> {
+    if !object.attr_names().map_err(hdf5_error)?.iter().any(|a| a == name) {
+        return Ok(None);
+    }
+    let value = object.attr(name).and_then(|a| a.read_scalar::<VarLenUnicode>()).map_err(hdf5_error)?;
+    Ok(Some(value.as_str().to_string()))
+}
+
+// writes (or replaces) a scalar variable length utf8 string attribute, as h5py does
+fn write_string_attr(object: &Location, name: &str, value: &str) -> Result<(), AnnembedError> {
+    let value: VarLenUnicode = value
+        .parse()
+        .map_err(|_| AnnembedError::InvalidParameter(format!("attribute value {:?} contains a nul byte", value)))?;
+    let attr = if object.attr_names().map_err(hdf5_error)?.iter().any(|a| a == name) {
+        object.attr(name)
+    } else {
+        object.new_attr::<VarLenUnicode>().create(name)
+    };
+    attr.and_then(|attr| attr.write_scalar(&value)).map_err(hdf5_error)
+} // end of write_string_attr
+
+/// matrix X of an AnnData file, cells as rows
+pub enum H5adMatrix {
+    Dense(Array2<f32>),
+    /// a csc X is converted to csr
+    Csr(CsMat<f32>),
+}
+
+impl H5adMatrix {
+    pub fn get_shape(&self) -> (usize, usize) {
+        match self {
+            H5adMatrix::Dense(mat) => mat.dim(),
+            H5adMatrix::Csr(mat) => mat.shape(),
+        }
+    }
+} // end of impl H5adMatrix
+
+/// content of an .h5ad file we use for embedding
+pub struct H5adData {
+    /// X, row i is the cell with DataId i
+    pub x: H5adMatrix,
+    /// index of obs (cell barcodes)
+    pub obs_names: Vec<String>,
+    /// values (as strings) of the obs column asked for, for example cell types
+    pub labels: Option<Vec<String>>,
+}
+
+fn read_x(file: &File) -> Result<H5adMatrix, AnnembedError> {
+    if !file.link_exists("X") {
+        return Err(AnnembedError::InvalidParameter(String::from("no X in h5ad file")));
+    }
+    if !is_group(file, "X")? {
+        let dataset = file.dataset("X").map_err(hdf5_error)?;
+        if dataset.ndim() != 2 {
+            return Err(AnnembedError::InvalidParameter(format!("X has dimensions {:?}", dataset.shape())));
+        }
+        return Ok(H5adMatrix::Dense(dataset.read_2d::<f32>().map_err(hdf5_error)?));
+    }
+    let group = file.group("X").map_err(hdf5_error)?;
+    let encoding = read_string_attr(&group, "encoding-type")?.unwrap_or_default();
+    let shape = group.attr("shape").and_then(|a| a.read_raw::<i64>()).map_err(hdf5_error)?;
+    if shape.len() != 2 || shape.iter().any(|d| *d < 0) {
+        return Err(AnnembedError::InvalidParameter(format!("X has shape {:?}", shape)));
+    }
+    let shape = (shape[0] as usize, shape[1] as usize);
+    let read_1d = |name: &str| group.dataset(name).and_then(|d| d.read_raw::<i64>()).map_err(hdf5_error);
+    let data = group.dataset("data").and_then(|d| d.read_raw::<f32>()).map_err(hdf5_error)?;
+    let (indices, indptr) = (read_1d("indices")?, read_1d("indptr")?);
+    if indices.iter().chain(indptr.iter()).any(|i| *i < 0) {
+        return Err(AnnembedError::InvalidParameter(String::from("X has negative indices")));
+    }
+    let indices: Vec<usize> = indices.into_iter().map(|i| i as usize).collect();
+    let indptr: Vec<usize> = indptr.into_iter().map(|i| i as usize).collect();
+    let csr = match encoding.as_str() {
+        "csr_matrix" => CsMat::new_from_unsorted(shape, indptr, indices, data),
+        "csc_matrix" => CsMat::new_from_unsorted_csc(shape, indptr, indices, data).map(|csc| csc.to_csr()),
+        _ => return Err(AnnembedError::InvalidParameter(format!("X has unsupported encoding {:?}", encoding))),
+    }
+    .map_err(|(_, _, _, e)| AnnembedError::InvalidParameter(format!("X is not a valid {} : {}", encoding, e)))?;
+    Ok(H5adMatrix::Csr(csr))
+} // end of read_x
+
+// values of an obs column as strings : categorical, strings or numbers
+fn read_obs_column(obs: &Group, column: &str) -> Result<Vec<String>, AnnembedError> {
+    if !obs.link_exists(column) {
+        return Err(AnnembedError::InvalidParameter(format!("no column {} in obs", column)));
+    }
+    if is_group(obs, column)? {
+        let group = obs.group(column).map_err(hdf5_error)?;
+        let categories = read_string_dataset(&group.dataset("categories").map_err(hdf5_error)?, "categories")?;
+        let codes = group.dataset("codes").and_then(|d| d.read_raw::<i64>()).map_err(hdf5_error)?;
+        // missing values have code -1
+        return Ok(codes
+            .into_iter()
+            .map(|c| usize::try_from(c).ok().and_then(|c| categories.get(c).cloned()).unwrap_or_else(|| String::from("NA")))
+            .collect());
+    }
+    let dataset = obs.dataset(column).map_err(hdf5_error)?;
+    let descriptor = dataset.dtype().and_then(|t| t.to_descriptor()).map_err(hdf5_error)?;
+    if matches!(
+        descriptor,
+        TypeDescriptor::VarLenUnicode | TypeDescriptor::VarLenAscii | TypeDescriptor::FixedAscii(_) | TypeDescriptor::FixedUnicode(_)
+    ) {
+        read_string_dataset(&dataset, column)
+    } else {
+        let values = dataset.read_raw::<f64>().map_err(hdf5_error)?;
+        Ok(values.into_iter().map(|v| v.to_string()).collect())
+    }
+} // end of read_obs_column
+
+// obs index dataset
+fn get_obs_index(obs: &Group) -> Result<Dataset, AnnembedError> {
+    let index = read_string_attr(obs, "_index")?.unwrap_or_else(|| String::from("_index"));
+    obs.dataset(&index).map_err(hdf5_error)
+}
+
+/// reads X, the obs index and optionally the obs column label_column of an .h5ad file
+pub fn read_h5ad(path: &Path, label_column: Option<&str>) -> Result<H5adData, AnnembedError> {
+    let file = File::open(path).map_err(hdf5_error)?;
+    let x = read_x(&file)?;
+    if !file.link_exists("obs") || !is_group(&file, "obs")? {
+        return Err(AnnembedError::InvalidParameter(String::from(
+            "obs is not a group, files written by anndata < 0.8 are not supported",
+        )));
+    }
+    let obs = file.group("obs").map_err(hdf5_error)?;
+    let obs_names = read_string_dataset(&get_obs_index(&obs)?, "obs index")?;
+    if obs_names.len() != x.get_shape().0 {
+        return Err(AnnembedError::InvalidParameter(format!(
+            "obs has {} rows, X has {}",
+            obs_names.len(),
+            x.get_shape().0
+        )));
+    }
+    let labels = label_column.map(|column| read_obs_column(&obs, column)).transpose()?;
+    log::info!(
+        "read_h5ad {} : X shape {:?} ({}), label column {:?}",
+        path.display(),
+        x.get_shape(),
+        if matches!(x, H5adMatrix::Dense(_)) { "dense" } else { "csr" },
+        label_column
+    );
+    Ok(H5adData { x, obs_names, labels })
+} // end of read_h5ad
+
+/// writes embedding in obsm\[key\] of an existing .h5ad file, replacing a previous one.
+/// Row i of embedding must correspond to row i of X (and obs).
+/// f32 embeddings are stored as float32, others as float64.
+pub fn write_h5ad_obsm<F: Float>(path: &Path, key: &str, embedding: &Array2<F>) -> Result<(), AnnembedError> {
+    let file = File::open_rw(path).map_err(hdf5_error)?;
+    let obs = file.group("obs").map_err(hdf5_error)?;
+    let nb_obs = get_obs_index(&obs)?.shape().first().copied().unwrap_or(0);
+    if nb_obs != embedding.nrows() {
+        return Err(AnnembedError::InvalidParameter(format!(
+            "embedding has {} rows, obs has {}",
+            embedding.nrows(),
+            nb_obs
+        )));
+    }
+    //
+    let obsm = if file.link_exists("obsm") {
+        file.group("obsm").map_err(hdf5_error)?
+    } else {
+        let group = file.create_group("obsm").map_err(hdf5_error)?;
+        write_string_attr(&group, "encoding-type", "dict")?;
+        write_string_attr(&group, "encoding-version", "0.1.0")?;
+        group
+    };
+    if obsm.link_exists(key) {
+        obsm.unlink(key).map_err(hdf5_error)?;
+    }
+    let builder = obsm.new_dataset_builder();
+    let dataset = if std::mem::size_of::<F>() == 4 {
+        builder.with_data(&embedding.mapv(|x| x.to_f32().unwrap())).create(key)
+    } else {
+        builder.with_data(&embedding.mapv(|x| x.to_f64().unwrap())).create(key)
+    }
+    .map_err(hdf5_error)?;
+    write_string_attr(&dataset, "encoding-type", "array")?;
+    write_string_attr(&dataset, "encoding-version", "0.2.0")?;
+    file.flush().map_err(hdf5_error)?;
+    log::info!("wrote embedding {:?} in obsm/{} of {}", embedding.dim(), key, path.display());
+    Ok(())
+} // end of write_h5ad_obsm
+
+/// reads obsm\[key\] of an .h5ad file
+pub fn read_h5ad_obsm(path: &Path, key: &str) -> Result<Array2<f64>, AnnembedError> {
+    let file = File::open(path).map_err(hdf5_error)?;
+    let dataset = file.group("obsm").and_then(|obsm| obsm.dataset(key)).map_err(hdf5_error)?;
+    if dataset.ndim() != 2 {
+        return Err(AnnembedError::InvalidParameter(format!("obsm/{} has dimensions {:?}", key, dataset.shape())));
+    }
+    dataset.read_2d::<f64>().map_err(hdf5_error)
+} // end of read_h5ad_obsm
+
+/// stores the embedding of embedder in obsm\["X_annembed"\] of the .h5ad file it was computed from.
+/// DataIds must be the rows of X, as given by [read_h5ad].
+pub fn write_embedder_h5ad<F>(embedder: &Embedder<F>, path: &Path) -> Result<(), AnnembedError>
+where
+    F: Float + Lapack + Scalar + ndarray::ScalarOperand + Send + Sync,
+{
+    if embedder.get_embedded().is_none() {
+        return Err(AnnembedError::Embedding(String::from("embedder has no embedding")));
+    }
+    write_h5ad_obsm(path, OBSM_KEY, &embedder.get_embedded_reindexed())
+} // end of write_embedder_h5ad
+
+/// stores embedding, as returned by the last embedding of dmaps, in obsm\["X_annembed"\] of the .h5ad file it was computed from.
+/// Rows are reordered by DataId which must be the rows of X, as given by [read_h5ad].
+pub fn write_dmap_h5ad<G: Float>(dmaps: &DiffusionMaps, embedding: &Array2<G>, path: &Path) -> Result<(), AnnembedError> {
+    let data_ids: &Vec<DataId> = dmaps
+        .get_data_ids()
+        .ok_or_else(|| AnnembedError::Embedding(String::from("diffusion maps has no embedding")))?;
+    if data_ids.len() != embedding.nrows() || data_ids.iter().any(|id| *id >= embedding.nrows()) {
+        return Err(AnnembedError::InvalidParameter(String::from(
+            "DataIds of diffusion maps are not the rows of embedding",
+        )));
+    }
+    let mut reindexed = Array2::<G>::zeros(embedding.dim());
+    for (row, id) in data_ids.iter().enumerate() {
+        reindexed.row_mut(*id).assign(&embedding.row(row));
+    }
+    write_h5ad_obsm(path, OBSM_KEY, &reindexed)
+} // end of write_dmap_h5ad
+
+//=======================================================================
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    fn log_init_test() {
+        let _ = env_logger::builder().is_test(true).try_init();
+    }
+
+    // creates a group with anndata encoding attributes
+    fn create_group(loc: &Group, name: &str, encoding: &str, version: &str) -> Group {
+        let group = loc.create_group(name).unwrap();
+        write_string_attr(&group, "encoding-type", encoding).unwrap();
+        write_string_attr(&group, "encoding-version", version).unwrap();
+        group
+    }
+
+    fn create_string_dataset(loc: &Group, name: &str, strings: &[&str]) {
+        let strings: Vec<VarLenUnicode> = strings.iter().map(|s| s.parse().unwrap()).collect();
+        let dataset = loc.new_dataset_builder().with_data(&strings).create(name).unwrap();
+        write_string_attr(&dataset, "encoding-type", "string-array").unwrap();
+        write_string_attr(&dataset, "encoding-version", "0.2.0").unwrap();
+    }
+
+    // a minimal h5ad file with a dense X of 4 cells and 3 genes, and a categorical cell_type column in obs
+    fn create_h5ad(path: &Path) {
+        let file = File::create(path).unwrap();
+        write_string_attr(&file, "encoding-type", "anndata").unwrap();
+        write_string_attr(&file, "encoding-version", "0.1.0").unwrap();
+        let x = Array2::<f32>::from_shape_fn((4, 3), |(i, j)| (3 * i + j) as f32);
+        let dataset = file.new_dataset_builder().with_data(&x).create("X").unwrap();
+        write_string_attr(&dataset, "encoding-type", "array").unwrap();
+        write_string_attr(&dataset, "encoding-version", "0.2.0").unwrap();
+        //
+        let obs = create_group(&file, "obs", "dataframe", "0.2.0");
+        write_string_attr(&obs, "_index", "_index").unwrap();
+        create_string_dataset(&obs, "_index", &["AAAC-1", "AAAG-1", "AACT-1", "AAGT-1"]);
+        let cell_type = create_group(&obs, "cell_type", "categorical", "0.2.0");
+        create_string_dataset(&cell_type, "categories", &["B", "T"]);
+        let codes: Vec<i8> = vec![1, 0, -1, 1];
+        cell_type.new_dataset_builder().with_data(&codes).create("codes").unwrap();
+        let _ = create_group(&file, "var", "dataframe", "0.2.0");
+    }
+
+    #[test]
+    fn test_h5ad_roundtrip() {
+        log_init_test();
+        //
+        let path = std::env::temp_dir().join(format!("annembed_test_{}.h5ad", std::process::id()));
+        create_h5ad(&path);
+        let data = read_h5ad(&path, Some("cell_type")).unwrap();
+        assert_eq!(data.x.get_shape(), (4, 3));
+        match &data.x {
+            H5adMatrix::Dense(x) => assert_eq!(x.row(1).to_vec(), vec![3., 4., 5.]),
+            H5adMatrix::Csr(_) => panic!("X should be dense"),
+        }
+        assert_eq!(data.obs_names[3], "AAGT-1");
+        assert_eq!(data.labels.unwrap(), vec!["T", "B", "NA", "T"]);
+        assert!(read_h5ad(&path, Some("batch")).is_err());
+        // obsm is created then replaced
+        let embedding = Array2::<f32>::from_shape_fn((4, 2), |(i, j)| (i * 2 + j) as f32);
+        write_h5ad_obsm(&path, OBSM_KEY, &embedding).unwrap();
+        write_h5ad_obsm(&path, OBSM_KEY, &embedding.mapv(|x| -x)).unwrap();
+        let reloaded = read_h5ad_obsm(&path, OBSM_KEY).unwrap();
+        assert_eq!(reloaded, embedding.mapv(|x| -x as f64));
+        // wrong number of rows
+        assert!(write_h5ad_obsm(&path, "X_bad", &Array2::<f64>::zeros((3, 2))).is_err());
+        let _ = std::fs::remove_file(&path);
+    } // end of test_h5ad_roundtrip
+} // end of mod tests
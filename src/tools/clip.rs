@@ -0,0 +1,122 @@
+//! Clipping strategies used to restrain values in embedding : scaling of diffusion maps coordinates
+//! into the initial box, random placement around projected points and gradient coefficients.
+//!
+//! A strategy clips a value to a bound given by the caller ([ClipStrategy::clip]) and can choose the bound
+//! from a set of values ([ClipStrategy::get_bound]). The strategy used by the Embedder is selected by [Clipping]
+//! in [EmbedderParams](crate::embedparams::EmbedderParams).
+
+use serde::{Deserialize, Serialize};
+
+/// A clipping strategy
+pub trait ClipStrategy {
+    /// restrains x to \[-bound, bound\] (bound > 0)
+    fn clip(&self, x: f64, bound: f64) -> f64;
+
+    /// returns the bound to use for a set of values given by their absolute values. Defaults to the maximum, so that nothing is clipped.
+    /// abs_values can be reordered.
+    fn get_bound(&self, abs_values: &mut [f64]) -> f64 {
+        abs_values.iter().fold(0., |m: f64, v| m.max(*v))
+    }
+} // end of trait ClipStrategy
+
+/// values out of \[-bound, bound\] are set to the nearest bound
+#[derive(Copy, Clone, Debug, Default)]
+pub struct HardClip;
+
+impl ClipStrategy for HardClip {
+    fn clip(&self, x: f64, bound: f64) -> f64 {
+        if x > bound {
+            log::trace!("truncated >");
+            bound
+        } else if x < -bound {
+            log::trace!("truncated <");
+            -bound
+        } else {
+            x
+        }
+    }
+} // end of impl ClipStrategy for HardClip
+
+/// smooth saturation : $bound * tanh(x/bound)$, nearly identity for small values
+#[derive(Copy, Clone, Debug, Default)]
+pub struct SoftTanhClip;
+
+impl ClipStrategy for SoftTanhClip {
+    fn clip(&self, x: f64, bound: f64) -> f64 {
+        bound * (x / bound).tanh()
+    }
+} // end of impl ClipStrategy for SoftTanhClip
+
+/// hard clip at a bound given by a quantile of absolute values, so that outliers do not fix the scale
+#[derive(Copy, Clone, Debug)]
+pub struct WinsorizeClip {
+    /// quantile (in \]0, 1\]) of absolute values used as bound
+    pub quantile: f64,
+}
+
+impl ClipStrategy for WinsorizeClip {
+    fn clip(&self, x: f64, bound: f64) -> f64 {
+        HardClip.clip(x, bound)
+    }
+
+    fn get_bound(&self, abs_values: &mut [f64]) -> f64 {
+        if abs_values.is_empty() {
+            return 0.;
+        }
+        let rank = ((self.quantile.clamp(0., 1.) * abs_values.len() as f64).ceil() as usize).clamp(1, abs_values.len()) - 1;
+        let (_, bound, _) = abs_values.select_nth_unstable_by(rank, |a, b| a.total_cmp(b));
+        *bound
+    }
+} // end of impl ClipStrategy for WinsorizeClip
+
+/// Selection of the clipping strategy in parameters. Default is [Clipping::Hard]
+#[derive(Copy, Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub enum Clipping {
+    /// see [HardClip]
+    #[default]
+    Hard,
+    /// see [SoftTanhClip]
+    SoftTanh,
+    /// see [WinsorizeClip], the argument is the quantile
+    Winsorize(f64),
+}
+
+impl ClipStrategy for Clipping {
+    fn clip(&self, x: f64, bound: f64) -> f64 {
+        match self {
+            Clipping::Hard => HardClip.clip(x, bound),
+            Clipping::SoftTanh => SoftTanhClip.clip(x, bound),
+            Clipping::Winsorize(quantile) => WinsorizeClip { quantile: *quantile }.clip(x, bound),
+        }
+    }
+
+    fn get_bound(&self, abs_values: &mut [f64]) -> f64 {
+        match self {
+            Clipping::Hard => HardClip.get_bound(abs_values),
+            Clipping::SoftTanh => SoftTanhClip.get_bound(abs_values),
+            Clipping::Winsorize(quantile) => WinsorizeClip { quantile: *quantile }.get_bound(abs_values),
+        }
+    }
+} // end of impl ClipStrategy for Clipping
+
+//=======================================================================
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_clip_strategies() {
+        assert_eq!(Clipping::Hard.clip(3., 2.), 2.);
+        assert_eq!(Clipping::Hard.clip(-1., 2.), -1.);
+        let soft = Clipping::SoftTanh.clip(3., 2.);
+        assert!(soft < 2. && soft > 1.8);
+        assert!((Clipping::SoftTanh.clip(0.01, 2.) - 0.01).abs() < 1.0e-5);
+        // bound is max for hard clip, 0.8 quantile for winsorize
+        let mut values: Vec<f64> = (1..=10).map(|i| i as f64).collect();
+        assert_eq!(Clipping::Hard.get_bound(&mut values), 10.);
+        assert_eq!(Clipping::Winsorize(0.8).get_bound(&mut values), 8.);
+        assert_eq!(Clipping::Winsorize(0.8).clip(9., 8.), 8.);
+    } // end of test_clip_strategies
+} // end of mod tests
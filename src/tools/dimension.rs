@@ -1,6 +1,6 @@
 //! dimension estimation
 
-use anyhow::{anyhow};
+use crate::error::AnnembedError;
 
 use num_traits::{Float};
 use crate::tools::nodeparam::*;
@@ -10,7 +10,7 @@ use crate::tools::nodeparam::*;
 ///     Maximum likelyhood estimation of intrinsic dimension.
 ///     Levina E. and Bickel P.J NIPS 2004.  [Levina-Bickel](https://www.stat.berkeley.edu/~bickel/mldim.pdf)
 /// 
-pub(crate) fn intrinsic_dimension_from_edges<F>(edges : &Vec<OutEdge<F>>) ->  Result<f64,anyhow::Error> 
+pub(crate) fn intrinsic_dimension_from_edges<F>(edges : &Vec<OutEdge<F>>) ->  Result<f64,AnnembedError> 
                 where F : Float  {
     let k_first: usize;
     let k_last : usize;
@@ -25,7 +25,7 @@ pub(crate) fn intrinsic_dimension_from_edges<F>(edges : &Vec<OutEdge<F>>) ->  Re
     }
     else {
         log::error!("intrinsic_dimension_from_edges not enough edges");
-        return Err(anyhow!("not enough neighbours"));
+        return Err(AnnembedError::InvalidParameter(String::from("not enough neighbours")));
     }
     //
     let mut density : f64 = 0.;
@@ -62,6 +62,6 @@ pub(crate) fn intrinsic_dimension_from_edges<F>(edges : &Vec<OutEdge<F>>) ->  Re
         return Ok(density);
     }
     else {
-        return Err(anyhow!("not positive distances"));
+        return Err(AnnembedError::InvalidParameter(String::from("not positive distances")));
     }
 } // end of intrinsic_dimension_from_edges
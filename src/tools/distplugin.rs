@@ -0,0 +1,50 @@
+//! Loading of a user defined distance from a dynamic library.
+//!
+//! This enables domain specific metrics (weighted L2, learned metrics...) to drive the neighbourhood graph
+//! without forking the crate. The library must export, with C ABI, a function with the signature of [PluginDistance]:
+//!
+//! ```text
+//! #[no_mangle]
+//! pub extern "C" fn annembed_distance(va: *const f64, vb: *const f64, len: usize) -> f32
+//! ```
+//!
+//! The loaded function is wrapped in a [DistFn] so it can be used wherever a hnsw_rs distance is expected.
+//! Library code can directly pass a DistFn or a DistPtr to the Hnsw structure, the plugin is meant for the binary.
+
+use std::path::Path;
+
+use hnsw_rs::prelude::*;
+use libloading::Library;
+
+use crate::error::AnnembedError;
+
+/// C ABI signature of a distance exported by a plugin, arguments are pointers to the 2 vectors and their length
+pub type PluginDistance = unsafe extern "C" fn(*const f64, *const f64, usize) -> f32;
+
+/// default name of the symbol searched in a plugin
+pub const DEFAULT_PLUGIN_SYMBOL: &str = "annembed_distance";
+
+/// loads the distance exported as symbol by the dynamic library at path (a .so, .dylib or .dll file).  
+/// The library is owned by the returned distance so it stays loaded as long as the Hnsw structure lives.
+pub fn load_distance_plugin(path: &Path, symbol: &str) -> Result<DistFn<f64>, AnnembedError> {
+    log::info!("loading distance {} from {}", symbol, path.display());
+    // loading runs the initialization routines of the library, which we trust as the user gave it
+    let library = unsafe { Library::new(path) }.map_err(|e| {
+        log::error!("load_distance_plugin could not open library {} : {}", path.display(), e);
+        AnnembedError::Io(std::io::Error::other(format!("could not open distance library {} : {}", path.display(), e)))
+    })?;
+    // the library exports the function with the signature documented in the module
+    let dist: PluginDistance = unsafe { library.get::<PluginDistance>(symbol.as_bytes()) }
+        .map(|sym| *sym)
+        .map_err(|e| {
+            log::error!("load_distance_plugin no symbol {} in {} : {}", symbol, path.display(), e);
+            AnnembedError::InvalidParameter(format!("no symbol {} in library {} : {}", symbol, path.display(), e))
+        })?;
+    let dist_fn = move |va: &[f64], vb: &[f64]| -> f32 {
+        assert_eq!(va.len(), vb.len());
+        // dist points into library, kept alive by the closure
+        let _library = &library;
+        unsafe { dist(va.as_ptr(), vb.as_ptr(), va.len()) }
+    };
+    Ok(DistFn::new(Box::new(dist_fn)))
+} // end of load_distance_plugin
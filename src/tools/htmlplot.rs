@@ -0,0 +1,320 @@
+//! Interactive scatter plot of a 2d embedding in a self-contained html file.
+//!
+//! The file embeds data and a small javascript drawing on a canvas, so it can be opened in any browser
+//! and shared without network access nor plotting stack :
+//! - hovering a point shows its DataId and label,
+//! - points are colored by label, clicking a label of the legend hides or shows its points,
+//! - the wheel zooms, dragging pans and a double click resets the view.
+//!
+//! Only the 2 first columns of the embedding are plotted.
+
+use std::io::{BufWriter, Write};
+use std::path::Path;
+
+use indexmap::IndexSet;
+use ndarray::ArrayView2;
+use num_traits::Float;
+use serde::Serialize;
+
+use hnsw_rs::prelude::DataId;
+
+use crate::error::AnnembedError;
+
+// data serialized in the html file
+#[derive(Serialize)]
+struct PlotData<'a> {
+    x: Vec<f32>,
+    y: Vec<f32>,
+    ids: &'a [DataId],
+    /// rank of label of each point in categories
+    labels: Option<Vec<usize>>,
+    categories: Vec<String>,
+}
+
+/// Writer of an interactive html scatter plot
+#[derive(Clone, Debug)]
+pub struct HtmlPlot {
+    title: String,
+    /// radius of points in pixels
+    point_size: f32,
+}
+
+impl Default for HtmlPlot {
+    fn default() -> Self {
+        HtmlPlot {
+            title: String::from("annembed"),
+            point_size: 2.,
+        }
+    }
+}
+
+impl HtmlPlot {
+    pub fn new(title: &str) -> Self {
+        HtmlPlot {
+            title: String::from(title),
+            ..Default::default()
+        }
+    }
+
+    /// radius of points in pixels, default is 2.
+    pub fn set_point_size(&mut self, point_size: f32) -> &mut Self {
+        self.point_size = point_size;
+        self
+    }
+
+    pub fn get_title(&self) -> &str {
+        &self.title
+    }
+
+    /// returns the html page plotting rows of embedding, row i having DataId data_ids\[i\] and label labels\[i\] if given.
+    pub fn to_html<F, L>(
+        &self,
+        embedding: &ArrayView2<F>,
+        data_ids: &[DataId],
+        labels: Option<&[L]>,
+    ) -> Result<String, AnnembedError>
+    where
+        F: Float,
+        L: std::fmt::Display,
+    {
+        if embedding.ncols() < 2 {
+            return Err(AnnembedError::InvalidParameter(format!(
+                "html plot needs an embedding of dimension >= 2, got {}",
+                embedding.ncols()
+            )));
+        }
+        let nb_points = embedding.nrows();
+        if data_ids.len() != nb_points || labels.is_some_and(|l| l.len() != nb_points) {
+            return Err(AnnembedError::InvalidParameter(format!(
+                "html plot, nb ids {} or nb labels {:?} != nb points {}",
+                data_ids.len(),
+                labels.map(|l| l.len()),
+                nb_points
+            )));
+        }
+        let coordinate = |j: usize| -> Vec<f32> {
+            embedding
+                .column(j)
+                .iter()
+                .map(|x| x.to_f32().unwrap_or(f32::NAN))
+                .collect()
+        };
+        let mut categories = IndexSet::<String>::new();
+        let labels = labels.map(|labels| {
+            labels
+                .iter()
+                .map(|l| categories.insert_full(l.to_string()).0)
+                .collect::<Vec<usize>>()
+        });
+        let data = PlotData {
+            x: coordinate(0),
+            y: coordinate(1),
+            ids: data_ids,
+            labels,
+            categories: categories.into_iter().collect(),
+        };
+        // a "</" in a label must not close the script element
+        let json = serde_json::to_string(&data)
+            .map_err(|e| AnnembedError::InvalidParameter(format!("html plot serialization : {}", e)))?
+            .replace("</", "<\\/");
+        let html = HTML_TEMPLATE
+            .replace("@TITLE@", &escape_html(&self.title))
+            .replace("@SIZE@", &self.point_size.to_string())
+            .replace("@DATA@", &json);
+        Ok(html)
+    } // end of to_html
+
+    /// writes the html page in path, see [to_html](Self::to_html)
+    pub fn write<F, L>(
+        &self,
+        path: &Path,
+        embedding: &ArrayView2<F>,
+        data_ids: &[DataId],
+        labels: Option<&[L]>,
+    ) -> Result<(), AnnembedError>
+    where
+        F: Float,
+        L: std::fmt::Display,
+    {
+        let html = self.to_html(embedding, data_ids, labels)?;
+        let file = std::fs::File::create(path)?;
+        let mut writer = BufWriter::new(file);
+        writer.write_all(html.as_bytes())?;
+        writer.flush()?;
+        log::info!("html plot of {} points written in {}", data_ids.len(), path.display());
+        Ok(())
+    } // end of write
+} // end of impl HtmlPlot
+
+fn escape_html(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+const HTML_TEMPLATE: &str = r#"<!DOCTYPE html>
+<html>
+<head>
+<meta charset="utf-8">
+<title>@TITLE@</title>
+<style>
+body { margin: 0; font-family: sans-serif; display: flex; height: 100vh; }
+#plot { flex: 1; position: relative; }
+canvas { width: 100%; height: 100%; display: block; cursor: crosshair; }
+#legend { width: 220px; overflow-y: auto; padding: 8px; border-left: 1px solid #ccc; font-size: 13px; }
+#legend div.entry { cursor: pointer; white-space: nowrap; }
+#legend div.hidden { opacity: 0.3; }
+#legend span { display: inline-block; width: 10px; height: 10px; margin-right: 6px; border-radius: 5px; }
+#tip { position: absolute; pointer-events: none; background: #fff; border: 1px solid #888; padding: 2px 6px;
+       font-size: 12px; display: none; white-space: nowrap; }
+</style>
+</head>
+<body>
+<div id="plot"><canvas id="canvas"></canvas><div id="tip"></div></div>
+<div id="legend"><b>@TITLE@</b><div id="info"></div><div id="entries"></div></div>
+<script>
+const data = @DATA@;
+const radius = @SIZE@;
+const n = data.x.length;
+const canvas = document.getElementById("canvas");
+const ctx = canvas.getContext("2d");
+const tip = document.getElementById("tip");
+const hidden = new Set();
+const color = c => data.labels === null ? "hsl(210,65%,45%)" : "hsl(" + ((c * 137.508) % 360) + ",65%,45%)";
+const labelOf = i => data.labels === null ? null : data.categories[data.labels[i]];
+const visible = i => data.labels === null || !hidden.has(data.labels[i]);
+let xmin = Infinity, xmax = -Infinity, ymin = Infinity, ymax = -Infinity;
+for (let i = 0; i < n; i++) {
+  if (!isFinite(data.x[i]) || !isFinite(data.y[i])) continue;
+  xmin = Math.min(xmin, data.x[i]); xmax = Math.max(xmax, data.x[i]);
+  ymin = Math.min(ymin, data.y[i]); ymax = Math.max(ymax, data.y[i]);
+}
+let view = {};
+function reset() {
+  const w = canvas.width, h = canvas.height;
+  const dx = Math.max(xmax - xmin, 1e-12), dy = Math.max(ymax - ymin, 1e-12);
+  view = { cx: (xmin + xmax) / 2, cy: (ymin + ymax) / 2, scale: 0.9 * Math.min(w / dx, h / dy) };
+}
+function screen(i) {
+  return [(data.x[i] - view.cx) * view.scale + canvas.width / 2, canvas.height / 2 - (data.y[i] - view.cy) * view.scale];
+}
+function draw() {
+  ctx.clearRect(0, 0, canvas.width, canvas.height);
+  const r = radius * window.devicePixelRatio;
+  for (let i = 0; i < n; i++) {
+    if (!visible(i)) continue;
+    const [sx, sy] = screen(i);
+    ctx.fillStyle = color(data.labels === null ? 0 : data.labels[i]);
+    ctx.beginPath(); ctx.arc(sx, sy, r, 0, 2 * Math.PI); ctx.fill();
+  }
+}
+function resize() {
+  canvas.width = canvas.clientWidth * window.devicePixelRatio;
+  canvas.height = canvas.clientHeight * window.devicePixelRatio;
+  reset(); draw();
+}
+function nearest(mx, my) {
+  let best = -1, bestd = Math.pow(4 * radius * window.devicePixelRatio, 2);
+  for (let i = 0; i < n; i++) {
+    if (!visible(i)) continue;
+    const [sx, sy] = screen(i);
+    const d = (sx - mx) * (sx - mx) + (sy - my) * (sy - my);
+    if (d < bestd) { bestd = d; best = i; }
+  }
+  return best;
+}
+let drag = null;
+canvas.addEventListener("mousedown", e => { drag = [e.offsetX, e.offsetY]; });
+window.addEventListener("mouseup", () => { drag = null; });
+canvas.addEventListener("mousemove", e => {
+  const ratio = window.devicePixelRatio;
+  if (drag !== null) {
+    view.cx -= (e.offsetX - drag[0]) * ratio / view.scale;
+    view.cy += (e.offsetY - drag[1]) * ratio / view.scale;
+    drag = [e.offsetX, e.offsetY];
+    draw();
+    return;
+  }
+  const i = nearest(e.offsetX * ratio, e.offsetY * ratio);
+  if (i < 0) { tip.style.display = "none"; return; }
+  const label = labelOf(i);
+  tip.textContent = "id : " + data.ids[i] + (label === null ? "" : ", label : " + label);
+  tip.style.left = (e.offsetX + 12) + "px";
+  tip.style.top = (e.offsetY + 12) + "px";
+  tip.style.display = "block";
+});
+canvas.addEventListener("mouseleave", () => { tip.style.display = "none"; });
+canvas.addEventListener("wheel", e => {
+  e.preventDefault();
+  const ratio = window.devicePixelRatio;
+  const mx = e.offsetX * ratio, my = e.offsetY * ratio;
+  const x = (mx - canvas.width / 2) / view.scale + view.cx, y = view.cy - (my - canvas.height / 2) / view.scale;
+  view.scale *= Math.exp(-e.deltaY * 0.001);
+  view.cx = x - (mx - canvas.width / 2) / view.scale;
+  view.cy = y + (my - canvas.height / 2) / view.scale;
+  draw();
+}, { passive: false });
+canvas.addEventListener("dblclick", () => { reset(); draw(); });
+document.getElementById("info").textContent = n + " points";
+if (data.labels !== null) {
+  const counts = new Array(data.categories.length).fill(0);
+  data.labels.forEach(c => counts[c]++);
+  const entries = document.getElementById("entries");
+  data.categories.forEach((name, c) => {
+    const entry = document.createElement("div");
+    entry.className = "entry";
+    const dot = document.createElement("span");
+    dot.style.background = color(c);
+    entry.appendChild(dot);
+    entry.appendChild(document.createTextNode(name + " (" + counts[c] + ")"));
+    entry.addEventListener("click", () => {
+      if (hidden.has(c)) { hidden.delete(c); entry.classList.remove("hidden"); }
+      else { hidden.add(c); entry.classList.add("hidden"); }
+      draw();
+    });
+    entries.appendChild(entry);
+  });
+}
+window.addEventListener("resize", resize);
+resize();
+</script>
+</body>
+</html>
+"#;
+
+//=======================================================================
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use ndarray::Array2;
+
+    fn log_init_test() {
+        let _ = env_logger::builder().is_test(true).try_init();
+    }
+
+    #[test]
+    fn test_html_plot() {
+        log_init_test();
+        let embedding = Array2::<f64>::from_shape_fn((4, 3), |(i, j)| (i * 3 + j) as f64);
+        let ids: Vec<DataId> = vec![10, 11, 12, 13];
+        let labels = ["a", "b</script>", "a", "c"];
+        let plot = HtmlPlot::new("test <plot>");
+        let html = plot.to_html(&embedding.view(), &ids, Some(&labels)).unwrap();
+        assert!(html.contains("<title>test &lt;plot&gt;</title>"));
+        assert!(html.contains("\"ids\":[10,11,12,13]"));
+        assert!(html.contains("\"labels\":[0,1,0,2]"));
+        assert!(html.contains("\"x\":[0.0,3.0,6.0,9.0]"));
+        // a label cannot close the script
+        assert_eq!(html.matches("</script>").count(), 1);
+        // without labels
+        let html = plot.to_html::<f64, String>(&embedding.view(), &ids, None).unwrap();
+        assert!(html.contains("\"labels\":null"));
+        // errors
+        assert!(plot.to_html(&embedding.view(), &ids[1..], Some(&labels)).is_err());
+        let line = Array2::<f64>::zeros((4, 1));
+        assert!(plot.to_html::<f64, String>(&line.view(), &ids, None).is_err());
+    } // end of test_html_plot
+} // end of mod tests
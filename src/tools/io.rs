@@ -1,26 +1,44 @@
-//! To take charge of io csv bincode...
+//! To take charge of io csv bincode, numpy npy and npz...
 //! 
 //! 
 //!
 
 
+#[cfg(feature = "csv")]
 use log::*;
-use anyhow::anyhow;
 
 use std::fs::OpenOptions;
 use std::path::Path;
+use std::io::{BufWriter, Write};
+#[cfg(feature = "csv")]
 use std::io::{Read, BufReader, BufRead};
+use std::collections::HashMap;
+use std::fmt::Display;
 
 use num_traits::Float;
+#[cfg(feature = "npy")]
+use num_traits::cast::FromPrimitive;
+#[cfg(feature = "csv")]
 use std::str::FromStr;
 
+use hnsw_rs::hnsw::DataId;
+
+use crate::error::AnnembedError;
+
 
 use ndarray::Array2;
+#[cfg(feature = "npy")]
+use ndarray::{Ix2, OwnedRepr};
+#[cfg(feature = "npy")]
+use ndarray_npy::{read_npy, write_npy, NpzReader, NpzWriter, ReadNpyError, ReadNpzError};
 
-use csv::*;
+#[cfg(feature = "csv")]
+use csv::{Writer, ReaderBuilder};
 
 
-/// This function is mostly dedicated to write embedded data in very few dimensions
+/// This function is mostly dedicated to write embedded data in very few dimensions.
+/// See [CsvArrayWriter] for precision, delimiter, header and append options.
+#[cfg(feature = "csv")]
 pub fn write_csv_labeled_array2<F, T>(csv_writer : &mut Writer<std::fs::File>, labels : &[T], mat : &Array2<F>) -> std::io::Result<usize>
             where F : Float , T : ToString {
     //
@@ -39,7 +57,326 @@ pub fn write_csv_labeled_array2<F, T>(csv_writer : &mut Writer<std::fs::File>, l
 } // end of dump_csv_array2
 
 
+/// Options of [CsvArrayWriter] : float precision (digits after point in scientific notation, default 5),
+/// delimiter (default ','), optional header of column names and append mode.
+#[derive(Clone, Debug)]
+pub struct CsvOptions {
+    precision : usize,
+    delimiter : u8,
+    header : Option<Vec<String>>,
+    append : bool,
+} // end of CsvOptions
+
+impl Default for CsvOptions {
+    fn default() -> Self {
+        CsvOptions{precision : 5, delimiter : b',', header : None, append : false}
+    }
+}
+
+impl CsvOptions {
+    /// number of digits after the point, floats are written in scientific notation
+    pub fn set_precision(&mut self, precision : usize) -> &mut Self {
+        self.precision = precision;
+        self
+    }
+
+    pub fn set_delimiter(&mut self, delimiter : u8) -> &mut Self {
+        self.delimiter = delimiter;
+        self
+    }
+
+    /// names of columns, written as first record. For labeled rows the first name is the label column.
+    pub fn set_header(&mut self, header : Vec<String>) -> &mut Self {
+        self.header = Some(header);
+        self
+    }
+
+    /// append to an existing file. The header is then written only if the file is empty.
+    pub fn set_append(&mut self, append : bool) -> &mut Self {
+        self.append = append;
+        self
+    }
+} // end of impl CsvOptions
+
+
+/// A csv writer of float rows, possibly labeled, configured by [CsvOptions].  
+/// Rows can be streamed one by one with [write_row](Self::write_row) and [write_labeled_row](Self::write_labeled_row)
+/// so that the whole Array2 needs not be in memory.  
+/// It does not depend on feature *csv*, fields are quoted as the csv crate does when they contain the delimiter,
+/// a quote or a line break.
+pub struct CsvArrayWriter<W : Write> {
+    writer : BufWriter<W>,
+    delimiter : u8,
+    precision : usize,
+    line : Vec<String>,
+    nb_rows : usize,
+} // end of CsvArrayWriter
+
+
+impl CsvArrayWriter<std::fs::File> {
+    /// opens (creates, truncates or appends to) file at path
+    pub fn to_path(path : &Path, options : &CsvOptions) -> Result<Self, AnnembedError> {
+        let fileres = if options.append {
+            OpenOptions::new().append(true).create(true).open(path)
+        } else {
+            OpenOptions::new().write(true).create(true).truncate(true).open(path)
+        };
+        if let Err(e) = fileres {
+            log::error!("CsvArrayWriter could not open file {}", path.display());
+            return Err(AnnembedError::Io(e));
+        }
+        let file = fileres.unwrap();
+        let empty = file.metadata()?.len() == 0;
+        Ok(CsvArrayWriter::new(file, options, empty)?)
+    }
+} // end of impl CsvArrayWriter<File>
+
+
+impl <W : Write> CsvArrayWriter<W> {
+    /// wraps writer, header of options is written if write_header is true
+    pub fn new(writer : W, options : &CsvOptions, write_header : bool) -> std::io::Result<Self> {
+        let mut csv_writer = CsvArrayWriter{writer : BufWriter::new(writer), delimiter : options.delimiter, 
+                                    precision : options.precision, line : Vec::new(), nb_rows : 0};
+        if write_header {
+            if let Some(header) = &options.header {
+                csv_writer.line = header.clone();
+                csv_writer.write_record()?;
+            }
+        }
+        Ok(csv_writer)
+    }
+
+    // writes fields in line as a record
+    fn write_record(&mut self) -> std::io::Result<()> {
+        let delimiter = self.delimiter as char;
+        for (i, field) in self.line.iter().enumerate() {
+            if i > 0 {
+                write!(self.writer, "{}", delimiter)?;
+            }
+            if field.contains(|c| c == delimiter || c == '"' || c == '\n' || c == '\r') {
+                write!(self.writer, "\"{}\"", field.replace('"', "\"\""))?;
+            }
+            else {
+                write!(self.writer, "{}", field)?;
+            }
+        }
+        writeln!(self.writer)
+    }
+
+    fn format_row<F : Float>(&mut self, row : &[F]) {
+        let precision = self.precision;
+        self.line.extend(row.iter().map(|x| format!("{:.*e}", precision, x.to_f64().unwrap())));
+    }
+
+    /// writes a row of floats
+    pub fn write_row<F : Float>(&mut self, row : &[F]) -> std::io::Result<()> {
+        self.line.clear();
+        self.format_row(row);
+        self.write_record()?;
+        self.nb_rows += 1;
+        Ok(())
+    }
+
+    /// writes label then a row of floats
+    pub fn write_labeled_row<F : Float, T : ToString>(&mut self, label : &T, row : &[F]) -> std::io::Result<()> {
+        self.line.clear();
+        self.line.push(label.to_string());
+        self.format_row(row);
+        self.write_record()?;
+        self.nb_rows += 1;
+        Ok(())
+    }
+
+    /// writes all rows of mat, each preceded by its label. labels must be aligned with rows of mat.
+    pub fn write_labeled_array2<F : Float, T : ToString>(&mut self, labels : &[T], mat : &Array2<F>) -> std::io::Result<()> {
+        for (label, row) in labels.iter().zip(mat.rows()) {
+            match row.as_slice() {
+                Some(slice) => self.write_labeled_row(label, slice)?,
+                None => self.write_labeled_row(label, &row.to_vec())?,
+            }
+        }
+        Ok(())
+    }
+
+    /// number of rows written (header excluded)
+    pub fn get_nb_rows(&self) -> usize {
+        self.nb_rows
+    }
+
+    pub fn flush(&mut self) -> std::io::Result<()> {
+        self.writer.flush()
+    }
+} // end of impl CsvArrayWriter
+
+
+/// A label (or any metadata) column attached to data at the beginning of processing.  
+/// Labels are stored by DataId so that writers can realign them with embedded rows whatever
+/// the reindexation done in graph construction.
+pub struct DataLabels<T> {
+    labels : HashMap<DataId, T>,
+} // end of struct DataLabels
+
+
+impl <T> DataLabels<T> {
+    /// labels given in data order, the label at rank i is attached to DataId i.
+    pub fn from_vec(labels : Vec<T>) -> Self {
+        DataLabels{labels : labels.into_iter().enumerate().collect()}
+    }
+
+    /// labels given with their DataId.
+    pub fn from_pairs(pairs : Vec<(DataId, T)>) -> Self {
+        DataLabels{labels : pairs.into_iter().collect()}
+    }
+
+    /// number of labels
+    pub fn len(&self) -> usize {
+        self.labels.len()
+    }
+
+    /// true if there is no label
+    pub fn is_empty(&self) -> bool {
+        self.labels.is_empty()
+    }
+
+    /// returns label of a DataId if any
+    pub fn get(&self, data_id : &DataId) -> Option<&T> {
+        self.labels.get(data_id)
+    }
+
+    /// returns labels aligned with a list of DataId, for example the rows of an embedding as given by
+    /// [Embedder::get_data_ids](crate::embedder::Embedder::get_data_ids). Returns an error if a DataId has no label.
+    pub fn aligned(&self, data_ids : &[DataId]) -> Result<Vec<&T>, AnnembedError> {
+        let mut aligned = Vec::<&T>::with_capacity(data_ids.len());
+        for d in data_ids {
+            match self.labels.get(d) {
+                Some(label) => aligned.push(label),
+                None => {
+                    log::error!("DataLabels::aligned no label for data_id {}", d);
+                    return Err(AnnembedError::InvalidParameter(format!("no label for data_id {}", d)));
+                }
+            }
+        }
+        Ok(aligned)
+    } // end of aligned
+} // end of impl DataLabels
+
+
+// escape a string to be written as a json string
+fn json_escape(s : &str) -> String {
+    let mut escaped = String::with_capacity(s.len() + 2);
+    for c in s.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            '\t' => escaped.push_str("\\t"),
+            c if (c as u32) < 0x20 => escaped.push_str(&format!("\\u{:04x}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+} // end of json_escape
+
+
+/// writes rows of an array2 as a json array of records `{"label" : ..., "coords" : [...]}`.  
+/// labels must be aligned with rows of mat (see [DataLabels::aligned]). Non finite values are written as `null`.
+pub fn write_json_labeled_array2<F, T, W>(writer : &mut W, labels : &[T], mat : &Array2<F>) -> std::io::Result<usize>
+            where F : Float, T : Display, W : Write {
+    //
+    let (nbrow, nbcol) = mat.dim();
+    writeln!(writer, "[")?;
+    for i in 0..nbrow {
+        write!(writer, "{{\"label\" : \"{}\", \"coords\" : [", json_escape(&labels[i].to_string()))?;
+        for j in 0..nbcol {
+            if j > 0 {
+                write!(writer, ", ")?;
+            }
+            let x = mat[[i,j]].to_f32().unwrap();
+            if x.is_finite() {
+                write!(writer, "{:.5e}", x)?;
+            }
+            else {
+                write!(writer, "null")?;
+            }
+        }
+        if i + 1 < nbrow {
+            writeln!(writer, "]}},")?;
+        }
+        else {
+            writeln!(writer, "]}}")?;
+        }
+    }
+    writeln!(writer, "]")?;
+    writer.flush()?;
+    //
+    return Ok(1);
+} // end of write_json_labeled_array2
+
+
+/// writes labels, one by line, in a sidecar text file to accompany an output format that cannot store
+/// them (binary arrays for example). Line i corresponds to row i of the array written.
+pub fn write_labels_sidecar<T>(path : &Path, labels : &[T]) -> Result<(), AnnembedError>
+            where T : Display {
+    let fileres = OpenOptions::new().write(true).create(true).truncate(true).open(path);
+    if let Err(e) = fileres {
+        log::error!("write_labels_sidecar could not open file {}", path.display());
+        return Err(AnnembedError::Io(e));
+    }
+    let mut bufwriter = BufWriter::new(fileres.unwrap());
+    for label in labels {
+        writeln!(bufwriter, "{}", label)?;
+    }
+    bufwriter.flush()?;
+    Ok(())
+} // end of write_labels_sidecar
+
+
+/// writes the rows of mat, row i having DataId data_ids\[i\], with their labels. The format is given by the extension of path :
+/// - *.json* : records of label and coordinates, see [write_json_labeled_array2],
+/// - *.npy* : the array in numpy format and the labels, one by line, in a sidecar file with extension *labels.txt*
+///   (see [write_labels_sidecar]). Needs feature npy,
+/// - otherwise csv, the label being the first field of each record (see [CsvArrayWriter]).
+///
+/// Returns an error if a DataId has no label.
+pub fn write_labeled_embedding<F, T>(path : &Path, labels : &DataLabels<T>, data_ids : &[DataId], mat : &Array2<F>) -> Result<(), AnnembedError>
+            where F : Float, T : Display {
+    if data_ids.len() != mat.nrows() {
+        log::error!("write_labeled_embedding got {} DataId for {} rows", data_ids.len(), mat.nrows());
+        return Err(AnnembedError::InvalidParameter(format!("{} DataId for {} rows", data_ids.len(), mat.nrows())));
+    }
+    let aligned = labels.aligned(data_ids)?;
+    match path.extension().and_then(|e| e.to_str()) {
+        Some("json") => {
+            let mut bufwriter = BufWriter::new(OpenOptions::new().write(true).create(true).truncate(true).open(path)?);
+            write_json_labeled_array2(&mut bufwriter, &aligned, mat)?;
+        }
+        Some("npy") => {
+            #[cfg(feature = "npy")]
+            {
+                write_array2_to_npy(path, mat)?;
+                write_labels_sidecar(&path.with_extension("labels.txt"), &aligned)?;
+            }
+            #[cfg(not(feature = "npy"))]
+            {
+                log::error!("write_labeled_embedding, npy output needs feature npy");
+                return Err(AnnembedError::InvalidParameter(String::from("npy output needs feature npy")));
+            }
+        }
+        _ => {
+            let mut writer = CsvArrayWriter::to_path(path, &CsvOptions::default())?;
+            let aligned : Vec<String> = aligned.iter().map(|l| l.to_string()).collect();
+            writer.write_labeled_array2(&aligned, mat)?;
+            writer.flush()?;
+        }
+    }
+    log::info!("wrote {} labeled rows in {}", mat.nrows(), path.display());
+    Ok(())
+} // end of write_labeled_embedding
+
+
 /// This function dumps an array2 into a csf file 
+#[cfg(feature = "csv")]
 pub fn write_csv_array2<F>(csv_writer : &mut Writer<std::fs::File>, mat : &Array2<F>) -> std::io::Result<usize>
             where F : Float {
     //
@@ -57,16 +394,112 @@ pub fn write_csv_array2<F>(csv_writer : &mut Writer<std::fs::File>, mat : &Array
 } // end of write_csv_array2
 
 
+/// reads a 2 dimensional array of floats in numpy format (.npy as written by numpy.save), see [ndarray_npy::read_npy].  
+/// dtype must be little endian f4 or f8, C or Fortran order.
+#[cfg(feature = "npy")]
+pub fn read_npy_to_array2<F : Float + FromPrimitive>(path : &Path) -> Result<Array2<F>, AnnembedError> {
+    // f4 arrays are read as such and converted
+    let mat = match read_npy::<_, Array2<f64>>(path) {
+        Ok(mat) => mat.mapv(|x| F::from_f64(x).unwrap()),
+        Err(ReadNpyError::WrongDescriptor(_)) => {
+            let mat = read_npy::<_, Array2<f32>>(path).map_err(|e| npy_error(path, e))?;
+            mat.mapv(|x| F::from_f32(x).unwrap())
+        }
+        Err(e) => return Err(npy_error(path, e)),
+    };
+    log::info!("read npy file {:?}, shape {:?}", path, mat.dim());
+    Ok(mat)
+} // end of read_npy_to_array2
+
+
+// npy errors (io, format or dtype) are reported as io errors with the file path
+#[cfg(feature = "npy")]
+fn npy_error<E : Display>(path : &Path, e : E) -> AnnembedError {
+    log::error!("npy file {:?} : {}", path, e);
+    AnnembedError::Io(std::io::Error::other(format!("npy file {:?} : {}", path, e)))
+}
+
+
+// converts to f32 for 4 bytes floats and to f64 otherwise, so values are kept exactly
+#[cfg(feature = "npy")]
+enum NpyFloat {
+    F32(Array2<f32>),
+    F64(Array2<f64>),
+}
+
+#[cfg(feature = "npy")]
+impl NpyFloat {
+    fn new<F : Float>(mat : &Array2<F>) -> Self {
+        if std::mem::size_of::<F>() == 4 {
+            NpyFloat::F32(mat.mapv(|x| x.to_f32().unwrap()))
+        } else {
+            NpyFloat::F64(mat.mapv(|x| x.to_f64().unwrap()))
+        }
+    }
+} // end of impl NpyFloat
+
+
+/// writes an array2 in numpy format (.npy, C order), readable with numpy.load, see [ndarray_npy::write_npy].  
+/// dtype is <f4 for f32 arrays and <f8 for f64 arrays so values are kept exactly.
+#[cfg(feature = "npy")]
+pub fn write_array2_to_npy<F : Float>(path : &Path, mat : &Array2<F>) -> Result<(), AnnembedError> {
+    match NpyFloat::new(mat) {
+        NpyFloat::F32(mat) => write_npy(path, &mat).map_err(|e| npy_error(path, e))?,
+        NpyFloat::F64(mat) => write_npy(path, &mat).map_err(|e| npy_error(path, e))?,
+    }
+    Ok(())
+} // end of write_array2_to_npy
+
+
+/// writes named arrays in a npz archive, as numpy.savez does, see [ndarray_npy::NpzWriter].
+/// Each array is stored (not compressed) as name.npy, so the archive is readable with numpy.load.
+#[cfg(feature = "npy")]
+pub fn write_array2_to_npz<F : Float>(path : &Path, arrays : &[(&str, &Array2<F>)]) -> Result<(), AnnembedError> {
+    let file = OpenOptions::new().write(true).create(true).truncate(true).open(path)?;
+    let mut npz = NpzWriter::new(BufWriter::new(file));
+    for (name, mat) in arrays {
+        match NpyFloat::new(mat) {
+            NpyFloat::F32(mat) => npz.add_array(*name, &mat).map_err(|e| npy_error(path, e))?,
+            NpyFloat::F64(mat) => npz.add_array(*name, &mat).map_err(|e| npy_error(path, e))?,
+        }
+    }
+    npz.finish().map_err(|e| npy_error(path, e))?.flush()?;
+    log::info!("wrote {} arrays in npz file {:?}", arrays.len(), path);
+    Ok(())
+} // end of write_array2_to_npz
+
+
+/// reads the array named name (without the .npy suffix) from a npz archive as written by numpy.savez
+/// or numpy.savez_compressed, see [ndarray_npy::NpzReader].
+#[cfg(feature = "npy")]
+pub fn read_npz_to_array2<F : Float + FromPrimitive>(path : &Path, name : &str) -> Result<Array2<F>, AnnembedError> {
+    let mut npz = NpzReader::new(std::io::BufReader::new(OpenOptions::new().read(true).open(path)?)).map_err(|e| npy_error(path, e))?;
+    let fname = format!("{}.npy", name);
+    let entry = npz.names().map_err(|e| npy_error(path, e))?.into_iter().find(|n| n == name || *n == fname)
+            .ok_or_else(|| AnnembedError::InvalidParameter(format!("no array {} in npz file {:?}", name, path)))?;
+    let mat = match npz.by_name::<OwnedRepr<f64>, Ix2>(&entry) {
+        Ok(mat) => mat.mapv(|x| F::from_f64(x).unwrap()),
+        Err(ReadNpzError::Npy(ReadNpyError::WrongDescriptor(_))) => {
+            let mat = npz.by_name::<OwnedRepr<f32>, Ix2>(&entry).map_err(|e| npy_error(path, e))?;
+            mat.mapv(|x| F::from_f32(x).unwrap())
+        }
+        Err(e) => return Err(npy_error(path, e)),
+    };
+    log::info!("read array {} from npz file {:?}, shape {:?}", name, path, mat.dim());
+    Ok(mat)
+} // end of read_npz_to_array2
+
+
 // count number of first lines beginning with '#' or '%'
-pub(crate) fn get_header_size(filepath : &Path) -> anyhow::Result<usize> {
+#[cfg(feature = "csv")]
+pub(crate) fn get_header_size(filepath : &Path) -> Result<usize, AnnembedError> {
     //
     log::debug!("get_header_size");
     //
     let fileres = OpenOptions::new().read(true).open(&filepath);
-    if fileres.is_err() {
+    if let Err(e) = fileres {
         log::error!("fn get_header_size : could not open file {:?}", filepath.as_os_str());
-        println!("fn get_header_size : could not open file {:?}", filepath.as_os_str());
-        return Err(anyhow!("fn get_header_size : could not open file {}", filepath.display()));            
+        return Err(AnnembedError::Io(e));
     }
     let mut file = fileres?;
     let mut nb_header_lines = 0;
@@ -96,16 +529,16 @@ pub(crate) fn get_header_size(filepath : &Path) -> anyhow::Result<usize> {
 /// get data to embed from a csv file
 /// Each line of the file must have a vector of float values with some standard csv delimiters.
 /// A header is possible with lines beginning with '#' or '%'
-pub fn get_toembed_from_csv<F> (filepath : &Path, delim : u8) -> anyhow::Result<Vec<Vec<F>>> 
+#[cfg(feature = "csv")]
+pub fn get_toembed_from_csv<F> (filepath : &Path, delim : u8) -> Result<Vec<Vec<F>>, AnnembedError> 
     where F : FromStr + Float {
     //
     let nb_headers_line = get_header_size(&filepath)?;
     log::info!("directed_from_csv , got header nb lines {}", nb_headers_line);
     let fileres = OpenOptions::new().read(true).open(&filepath);
-    if fileres.is_err() {
-        log::error!("ProcessingState reload_json : reload could not open file {:?}", filepath.as_os_str());
-        println!("directed_from_csv could not open file {:?}", filepath.as_os_str());
-        return Err(anyhow!("directed_from_csv could not open file {}", filepath.display()));            
+    if let Err(e) = fileres {
+        log::error!("directed_from_csv could not open file {:?}", filepath.as_os_str());
+        return Err(AnnembedError::Io(e));
     }
     let file = fileres?;
     let mut bufreader = BufReader::new(file);
@@ -123,7 +556,7 @@ pub fn get_toembed_from_csv<F> (filepath : &Path, delim : u8) -> anyhow::Result<
     let mut rdr = ReaderBuilder::new().delimiter(delim).flexible(false).has_headers(false).from_reader(bufreader);
     for result in rdr.records() {
         num_record += 1;
-        let record = result?;
+        let record = result.map_err(std::io::Error::from)?;
         if log::log_enabled!(Level::Info) && nb_record <= 2 {
             log::debug!(" record num {:?}, {:?}", nb_record, record);
         }
@@ -132,13 +565,13 @@ pub fn get_toembed_from_csv<F> (filepath : &Path, delim : u8) -> anyhow::Result<
             log::info!("nb fields = {}", nb_fields);
             if nb_fields < 2 {
                 log::error!("found only one field in record, check the delimitor , got {:?} as delimitor ", delim as char);
-                return Err(anyhow!("found only one field in record, check the delimitor , got {:?} as delimitor ", delim as char));
+                return Err(AnnembedError::InvalidParameter(format!("found only one field in record, check the delimitor , got {:?} as delimitor ", delim as char)));
             }
         }
         else {
             if record.len() != nb_fields {
-                println!("non constant number of fields at record {} first record has {}",num_record,  nb_fields);
-                return Err(anyhow!("non constant number of fields at record {} first record has {}",num_record,  nb_fields));   
+                log::error!("non constant number of fields at record {} first record has {}",num_record,  nb_fields);
+                return Err(AnnembedError::InvalidParameter(format!("non constant number of fields at record {} first record has {}",num_record,  nb_fields)));
             }
             // We have a new vector with nb_fields to parse
             let mut v = Vec::<F>::with_capacity(nb_fields);
@@ -150,7 +583,7 @@ pub fn get_toembed_from_csv<F> (filepath : &Path, delim : u8) -> anyhow::Result<
                 }
                 else {
                     log::error!("error decoding field {} of record  {}, field : {:?}",j, num_record, field);
-                    return Err(anyhow!("error decoding field {} of record  {}, field : {:?}",j, num_record, field)); 
+                    return Err(AnnembedError::InvalidParameter(format!("error decoding field {} of record  {}, field : {:?}",j, num_record, field)));
                 }
             }
             toembed.push(v);
@@ -160,6 +593,97 @@ pub fn get_toembed_from_csv<F> (filepath : &Path, delim : u8) -> anyhow::Result<
     Ok(toembed)
 } // end of get_toembed_from_csv
 
+/// options of [read_csv_to_array2].  
+/// Default is ',' as delimiter, no header row and no label column.
+#[cfg(feature = "csv")]
+#[derive(Clone, Debug)]
+pub struct CsvReadOptions {
+    delimiter : u8,
+    has_header : bool,
+    label_column : Option<usize>,
+} // end of CsvReadOptions
+
+#[cfg(feature = "csv")]
+impl Default for CsvReadOptions {
+    fn default() -> Self {
+        CsvReadOptions{delimiter : b',', has_header : false, label_column : None}
+    }
+}
+
+#[cfg(feature = "csv")]
+impl CsvReadOptions {
+    pub fn set_delimiter(&mut self, delimiter : u8) -> &mut Self {
+        self.delimiter = delimiter;
+        self
+    }
+
+    /// first record (after lines beginning with '#' or '%') gives column names and is not decoded
+    pub fn set_header(&mut self, has_header : bool) -> &mut Self {
+        self.has_header = has_header;
+        self
+    }
+
+    /// rank of the column (among all columns of the file) containing the label of each row
+    pub fn set_label_column(&mut self, column : usize) -> &mut Self {
+        self.label_column = Some(column);
+        self
+    }
+} // end of impl CsvReadOptions
+
+
+/// reads a csv file into an Array2, one row by record.  
+/// Lines beginning with '#' or '%' at the start of the file are skipped as in [get_toembed_from_csv].
+/// If options have a label column, its fields are returned (in row order) besides the array and the other columns are decoded as F.
+#[cfg(feature = "csv")]
+pub fn read_csv_to_array2<F>(filepath : &Path, options : &CsvReadOptions) -> Result<(Array2<F>, Option<Vec<String>>), AnnembedError>
+    where F : FromStr + Float {
+    //
+    let nb_headers_line = get_header_size(filepath)?;
+    let file = OpenOptions::new().read(true).open(filepath).map_err(|e| {
+        log::error!("read_csv_to_array2 could not open file {}", filepath.display());
+        AnnembedError::Io(e)
+    })?;
+    let mut bufreader = BufReader::new(file);
+    let mut headerline = String::new();
+    for _ in 0..nb_headers_line {
+        bufreader.read_line(&mut headerline)?;
+    }
+    let mut rdr = ReaderBuilder::new().delimiter(options.delimiter).flexible(false).has_headers(options.has_header).from_reader(bufreader);
+    if options.has_header {
+        log::info!("read_csv_to_array2 column names : {:?}", rdr.headers().map_err(std::io::Error::from)?);
+    }
+    //
+    let mut nb_fields = 0;
+    let mut values = Vec::<F>::new();
+    let mut labels = Vec::<String>::new();
+    let mut nb_rows = 0;
+    for result in rdr.records() {
+        let record = result.map_err(std::io::Error::from)?;
+        if nb_rows == 0 {
+            nb_fields = record.len();
+            if let Some(column) = options.label_column {
+                if column >= nb_fields {
+                    return Err(AnnembedError::InvalidParameter(format!("label column {} but records have {} fields, check the delimiter {:?}", column, nb_fields, options.delimiter as char)));
+                }
+            }
+        }
+        for (j, field) in record.iter().enumerate() {
+            if options.label_column == Some(j) {
+                labels.push(field.to_string());
+            }
+            else {
+                let val = field.trim().parse::<F>().map_err(|_| AnnembedError::InvalidParameter(format!("error decoding field {} of record {}, field : {:?}", j, nb_rows, field)))?;
+                values.push(val);
+            }
+        }
+        nb_rows += 1;
+    }
+    let nb_cols = if options.label_column.is_some() { nb_fields.saturating_sub(1) } else { nb_fields };
+    log::info!("read_csv_to_array2 read {} rows of {} values in {}", nb_rows, nb_cols, filepath.display());
+    let mat = Array2::from_shape_vec((nb_rows, nb_cols), values).map_err(|e| AnnembedError::InvalidParameter(format!("read_csv_to_array2 : {}", e)))?;
+    Ok((mat, options.label_column.map(|_| labels)))
+} // end of read_csv_to_array2
+
 
 
 //========================================================================================
@@ -182,6 +706,7 @@ fn log_init_test() {
 
 static TESTDIR : &str = "/home/jpboth/Rust/annembed/Tmp";
 
+#[cfg(feature = "csv")]
 #[test]
 fn load_csv() {
     log_init_test();
@@ -195,4 +720,186 @@ fn load_csv() {
 } // end of load_csv
 
 
+#[cfg(feature = "csv")]
+#[test]
+fn read_csv_labeled() {
+    log_init_test();
+    //
+    let path = std::env::temp_dir().join(format!("annembed_test_{}.csv", std::process::id()));
+    std::fs::write(&path, "# comment line\nx;name;y\n1.5;a;2\n-3;\"b;c\";4e-1\n").unwrap();
+    let mut options = CsvReadOptions::default();
+    options.set_delimiter(b';').set_header(true).set_label_column(1);
+    let (mat, labels) = read_csv_to_array2::<f32>(&path, &options).unwrap();
+    assert_eq!(mat, ndarray::arr2(&[[1.5f32, 2.], [-3., 0.4]]));
+    assert_eq!(labels.unwrap(), vec!["a".to_string(), "b;c".to_string()]);
+    // without header the column names are not decoded
+    options.set_header(false);
+    assert!(read_csv_to_array2::<f32>(&path, &options).is_err());
+    // label column out of range
+    let mut options = CsvReadOptions::default();
+    options.set_delimiter(b';').set_header(true).set_label_column(3);
+    assert!(read_csv_to_array2::<f32>(&path, &options).is_err());
+    // no label column
+    std::fs::write(&path, "1,2,3\n4,5,6\n").unwrap();
+    let (mat, labels) = read_csv_to_array2::<f64>(&path, &CsvReadOptions::default()).unwrap();
+    assert_eq!(mat.dim(), (2, 3));
+    assert!(labels.is_none());
+    let _ = std::fs::remove_file(&path);
+} // end of read_csv_labeled
+
+
+#[cfg(feature = "npy")]
+#[test]
+fn npy_roundtrip() {
+    log_init_test();
+    //
+    let path = std::env::temp_dir().join(format!("annembed_test_{}.npy", std::process::id()));
+    let mat = ndarray::arr2(&[[1f32, 2., 3.], [4., 5., 6.]]);
+    write_array2_to_npy(&path, &mat).unwrap();
+    // f32 arrays are written as <f4
+    assert_eq!(read_npy::<_, Array2<f32>>(&path).unwrap(), mat);
+    assert_eq!(read_npy_to_array2::<f32>(&path).unwrap(), mat);
+    let reloaded = read_npy_to_array2::<f64>(&path).unwrap();
+    assert_eq!(reloaded, mat.mapv(|x| x as f64));
+    // f4 in fortran order as numpy.save(np.asfortranarray(a.astype(np.float32)))
+    let header = "{'descr': '<f4', 'fortran_order': True, 'shape': (2, 3), }";
+    let mut bytes = b"\x93NUMPY\x01\x00".to_vec();
+    bytes.extend_from_slice(&(header.len() as u16).to_le_bytes());
+    bytes.extend_from_slice(header.as_bytes());
+    for x in [1f32, 4., 2., 5., 3., 6.] {
+        bytes.extend_from_slice(&x.to_le_bytes());
+    }
+    std::fs::write(&path, &bytes).unwrap();
+    assert_eq!(read_npy_to_array2::<f64>(&path).unwrap(), reloaded);
+    // wrong number of values
+    bytes.truncate(bytes.len() - 4);
+    std::fs::write(&path, &bytes).unwrap();
+    assert!(read_npy_to_array2::<f64>(&path).is_err());
+    // shape whose number of values overflows
+    let header = "{'descr': '<f4', 'fortran_order': False, 'shape': (18446744073709551615, 2), }";
+    let mut bytes = b"\x93NUMPY\x01\x00".to_vec();
+    bytes.extend_from_slice(&(header.len() as u16).to_le_bytes());
+    bytes.extend_from_slice(header.as_bytes());
+    std::fs::write(&path, &bytes).unwrap();
+    assert!(read_npy_to_array2::<f64>(&path).is_err());
+    let _ = std::fs::remove_file(&path);
+} // end of npy_roundtrip
+
+
+#[cfg(feature = "npy")]
+#[test]
+fn npz_roundtrip() {
+    log_init_test();
+    //
+    let path = std::env::temp_dir().join(format!("annembed_test_{}.npz", std::process::id()));
+    let embedding = ndarray::arr2(&[[0.1f64, -2.5], [3.25, 1e-12], [7., 8.]]);
+    let data = ndarray::arr2(&[[1f64, 2., 3., 4.]]);
+    write_array2_to_npz(&path, &[("embedding", &embedding), ("data", &data)]).unwrap();
+    assert_eq!(read_npz_to_array2::<f64>(&path, "embedding").unwrap(), embedding);
+    assert_eq!(read_npz_to_array2::<f64>(&path, "data").unwrap(), data);
+    assert!(read_npz_to_array2::<f64>(&path, "labels").is_err());
+    // f32 arrays are kept as <f4 and can be read in f64
+    let embedding = embedding.mapv(|x| x as f32);
+    write_array2_to_npz(&path, &[("embedding", &embedding)]).unwrap();
+    assert_eq!(read_npz_to_array2::<f32>(&path, "embedding").unwrap(), embedding);
+    assert_eq!(read_npz_to_array2::<f64>(&path, "embedding").unwrap(), embedding.mapv(|x| x as f64));
+    let _ = std::fs::remove_file(&path);
+} // end of npz_roundtrip
+
+
+#[test]
+fn labels_to_json() {
+    log_init_test();
+    //
+    let labels = DataLabels::from_pairs(vec![(3, String::from("a\"b")), (7, String::from("c"))]);
+    let aligned = labels.aligned(&[7, 3]).unwrap();
+    assert_eq!(aligned, vec!["c", "a\"b"]);
+    assert!(labels.aligned(&[5]).is_err());
+    let mat = ndarray::arr2(&[[1f32, 2.], [3., 4.]]);
+    let mut out = Vec::<u8>::new();
+    write_json_labeled_array2(&mut out, &aligned, &mat).unwrap();
+    let out = String::from_utf8(out).unwrap();
+    assert!(out.starts_with("[\n{\"label\" : \"c\", \"coords\" : [1.00000e0, 2.00000e0]},"));
+    assert!(out.contains("\"a\\\"b\""));
+    // non finite values are not valid json numbers
+    let mat = ndarray::arr2(&[[f32::NAN, 2.], [f32::INFINITY, 4.]]);
+    let mut out = Vec::<u8>::new();
+    write_json_labeled_array2(&mut out, &aligned, &mat).unwrap();
+    let out = String::from_utf8(out).unwrap();
+    assert!(out.contains("\"coords\" : [null, 2.00000e0]"));
+    assert!(out.contains("\"coords\" : [null, 4.00000e0]"));
+} // end of labels_to_json
+
+
+#[test]
+fn labeled_embedding_outputs() {
+    log_init_test();
+    //
+    let labels = DataLabels::from_vec(vec![String::from("a"), String::from("b"), String::from("c")]);
+    // rows in another order than DataId
+    let mat = ndarray::arr2(&[[1f64, 2.], [3., 4.]]);
+    let path = std::env::temp_dir().join(format!("annembed_labeled_{}.csv", std::process::id()));
+    write_labeled_embedding(&path, &labels, &[2, 0], &mat).unwrap();
+    assert_eq!(std::fs::read_to_string(&path).unwrap(), "c,1.00000e0,2.00000e0\na,3.00000e0,4.00000e0\n");
+    // a row without label
+    assert!(write_labeled_embedding(&path, &labels, &[2, 5], &mat).is_err());
+    std::fs::remove_file(&path).unwrap();
+    let path = path.with_extension("json");
+    write_labeled_embedding(&path, &labels, &[1, 0], &mat).unwrap();
+    assert!(std::fs::read_to_string(&path).unwrap().starts_with("[\n{\"label\" : \"b\", \"coords\" : [1.00000e0, 2.00000e0]},"));
+    std::fs::remove_file(&path).unwrap();
+    #[cfg(feature = "npy")]
+    {
+        let path = path.with_extension("npy");
+        write_labeled_embedding(&path, &labels, &[1, 0], &mat).unwrap();
+        assert_eq!(read_npy_to_array2::<f64>(&path).unwrap(), mat);
+        let sidecar = path.with_extension("labels.txt");
+        assert_eq!(std::fs::read_to_string(&sidecar).unwrap(), "b\na\n");
+        std::fs::remove_file(&path).unwrap();
+        std::fs::remove_file(&sidecar).unwrap();
+    }
+} // end of labeled_embedding_outputs
+
+
+#[test]
+fn csv_writer_options() {
+    log_init_test();
+    //
+    let mut options = CsvOptions::default();
+    options.set_precision(2).set_delimiter(b';').set_header(vec![String::from("label"), String::from("x"), String::from("y")]);
+    let mat = ndarray::arr2(&[[1f32, 2.], [3., 4.]]);
+    let mut out = Vec::<u8>::new();
+    {
+        let mut writer = CsvArrayWriter::new(&mut out, &options, true).unwrap();
+        writer.write_labeled_array2(&["a", "b"], &mat).unwrap();
+        // streaming a row
+        writer.write_labeled_row(&"c", &[0.5f64, -1.]).unwrap();
+        assert_eq!(writer.get_nb_rows(), 3);
+        writer.flush().unwrap();
+    }
+    let out = String::from_utf8(out).unwrap();
+    assert_eq!(out, "label;x;y\na;1.00e0;2.00e0\nb;3.00e0;4.00e0\nc;5.00e-1;-1.00e0\n");
+    // labels with delimiter or quotes are quoted
+    let mut out = Vec::<u8>::new();
+    {
+        let mut writer = CsvArrayWriter::new(&mut out, &options, false).unwrap();
+        writer.write_labeled_row(&"a;\"b\"", &[1f32]).unwrap();
+        writer.flush().unwrap();
+    }
+    assert_eq!(String::from_utf8(out).unwrap(), "\"a;\"\"b\"\"\";1.00e0\n");
+    // append mode writes header once
+    let path = std::env::temp_dir().join(format!("annembed_csv_{}.csv", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    options.set_append(true);
+    for _ in 0..2 {
+        let mut writer = CsvArrayWriter::to_path(&path, &options).unwrap();
+        writer.write_row(&[1f32, 2.]).unwrap();
+        writer.flush().unwrap();
+    }
+    let content = std::fs::read_to_string(&path).unwrap();
+    assert_eq!(content, "label;x;y\n1.00e0;2.00e0\n1.00e0;2.00e0\n");
+    std::fs::remove_file(&path).unwrap();
+} // end of csv_writer_options
+
+
 } // end of mod tests
\ No newline at end of file
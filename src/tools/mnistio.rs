@@ -0,0 +1,285 @@
+//! Reading of IDX files, the format of [MNIST digits](http://yann.lecun.com/exdb/mnist/) and
+//! [Fashion MNIST](https://github.com/zalandoresearch/fashion-mnist) databases.
+//!
+//! Files can be given by any path, gzipped files (as downloaded) are detected from their magic bytes and decompressed on the fly.
+//! Images and labels are read as iterators ([IdxImages], [IdxLabels]) or loaded together in a [MnistData].
+
+use crate::error::AnnembedError;
+
+use std::fs::OpenOptions;
+use std::io::{BufRead, BufReader, Read};
+use std::path::{Path, PathBuf};
+
+use byteorder::{BigEndian, ReadBytesExt};
+use flate2::read::GzDecoder;
+use ndarray::{s, Array1, Array3};
+
+/// magic number of idx files of u8 labels
+const IDX_LABEL_MAGIC: u32 = 2049;
+/// magic number of idx files of u8 images
+const IDX_IMAGE_MAGIC: u32 = 2051;
+/// first bytes of a gzip file
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+
+/// opens a file for reading, decompressing it if it is gzipped (detected from its first bytes, not from its extension)
+pub fn open_maybe_gz(path: &Path) -> Result<Box<dyn Read>, AnnembedError> {
+    let file = OpenOptions::new().read(true).open(path).map_err(|e| {
+        log::error!("open_maybe_gz could not open file {}", path.display());
+        AnnembedError::Io(e)
+    })?;
+    let mut bufreader = BufReader::new(file);
+    let is_gz = bufreader.fill_buf()?.starts_with(&GZIP_MAGIC);
+    if is_gz {
+        log::debug!("decompressing gzipped file {}", path.display());
+        Ok(Box::new(BufReader::new(GzDecoder::new(bufreader))))
+    } else {
+        Ok(Box::new(bufreader))
+    }
+} // end of open_maybe_gz
+
+/// returns path if it exists, else path with .gz appended if it exists
+fn find_maybe_gz(path: &Path) -> Result<PathBuf, AnnembedError> {
+    if path.exists() {
+        return Ok(path.to_path_buf());
+    }
+    let mut gz = path.as_os_str().to_owned();
+    gz.push(".gz");
+    let gz = PathBuf::from(gz);
+    if gz.exists() {
+        return Ok(gz);
+    }
+    Err(AnnembedError::InvalidParameter(format!("found neither {} nor its .gz version", path.display())))
+} // end of find_maybe_gz
+
+fn read_magic(reader: &mut dyn Read, expected: u32, path: &Path) -> Result<(), AnnembedError> {
+    let magic = reader.read_u32::<BigEndian>()?;
+    if magic != expected {
+        log::error!("file {} has magic {}, expected {}", path.display(), magic, expected);
+        return Err(AnnembedError::InvalidParameter(format!("file {} has magic {}, expected {}", path.display(), magic, expected)));
+    }
+    Ok(())
+}
+
+/// Iterator over the labels of an idx1 file
+pub struct IdxLabels {
+    nb_items: usize,
+    nb_read: usize,
+    reader: Box<dyn Read>,
+}
+
+impl IdxLabels {
+    /// opens (possibly gzipped) label file and reads its header
+    pub fn new(path: &Path) -> Result<Self, AnnembedError> {
+        let mut reader = open_maybe_gz(path)?;
+        read_magic(&mut reader, IDX_LABEL_MAGIC, path)?;
+        let nb_items = reader.read_u32::<BigEndian>()? as usize;
+        log::debug!("label file {}, nb items {}", path.display(), nb_items);
+        Ok(IdxLabels { nb_items, nb_read: 0, reader })
+    }
+
+    /// number of labels in file
+    pub fn get_nb_items(&self) -> usize {
+        self.nb_items
+    }
+} // end of impl IdxLabels
+
+impl Iterator for IdxLabels {
+    type Item = u8;
+
+    fn next(&mut self) -> Option<u8> {
+        if self.nb_read >= self.nb_items {
+            return None;
+        }
+        match self.reader.read_u8() {
+            Ok(label) => {
+                self.nb_read += 1;
+                Some(label)
+            }
+            Err(e) => {
+                log::error!("IdxLabels truncated file at item {} : {}", self.nb_read, e);
+                self.nb_read = self.nb_items;
+                None
+            }
+        }
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (0, Some(self.nb_items - self.nb_read))
+    }
+} // end of impl Iterator for IdxLabels
+
+/// Iterator over the images of an idx3 file, each image is returned row by row as a Vec of nb_rows * nb_columns bytes
+pub struct IdxImages {
+    nb_items: usize,
+    nb_rows: usize,
+    nb_columns: usize,
+    nb_read: usize,
+    reader: Box<dyn Read>,
+}
+
+impl IdxImages {
+    /// opens (possibly gzipped) image file and reads its header
+    pub fn new(path: &Path) -> Result<Self, AnnembedError> {
+        let mut reader = open_maybe_gz(path)?;
+        read_magic(&mut reader, IDX_IMAGE_MAGIC, path)?;
+        let nb_items = reader.read_u32::<BigEndian>()? as usize;
+        let nb_rows = reader.read_u32::<BigEndian>()? as usize;
+        let nb_columns = reader.read_u32::<BigEndian>()? as usize;
+        log::debug!("image file {}, nb items {}, images {} x {}", path.display(), nb_items, nb_rows, nb_columns);
+        Ok(IdxImages { nb_items, nb_rows, nb_columns, nb_read: 0, reader })
+    }
+
+    /// number of images in file
+    pub fn get_nb_items(&self) -> usize {
+        self.nb_items
+    }
+
+    /// returns (nb_rows, nb_columns) of images
+    pub fn get_image_dim(&self) -> (usize, usize) {
+        (self.nb_rows, self.nb_columns)
+    }
+} // end of impl IdxImages
+
+impl Iterator for IdxImages {
+    type Item = Vec<u8>;
+
+    fn next(&mut self) -> Option<Vec<u8>> {
+        if self.nb_read >= self.nb_items {
+            return None;
+        }
+        let mut image = vec![0u8; self.nb_rows * self.nb_columns];
+        match self.reader.read_exact(&mut image) {
+            Ok(()) => {
+                self.nb_read += 1;
+                Some(image)
+            }
+            Err(e) => {
+                log::error!("IdxImages truncated file at item {} : {}", self.nb_read, e);
+                self.nb_read = self.nb_items;
+                None
+            }
+        }
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (0, Some(self.nb_items - self.nb_read))
+    }
+} // end of impl Iterator for IdxImages
+
+/// A struct to load/store data in [MNIST](http://yann.lecun.com/exdb/mnist/) format (digits or fashion).
+/// stores labels (between 0 and 9) coming from file train-labels-idx1-ubyte
+/// and images (28*28 for MNIST) with values between 0 and 255 coming from train-images-idx3-ubyte
+pub struct MnistData {
+    images: Array3<u8>,
+    labels: Array1<u8>,
+}
+
+impl MnistData {
+    /// loads images and labels from (possibly gzipped) files at any path
+    pub fn new<P: AsRef<Path>>(image_path: P, label_path: P) -> Result<MnistData, AnnembedError> {
+        let images_iter = IdxImages::new(image_path.as_ref())?;
+        let (nb_rows, nb_columns) = images_iter.get_image_dim();
+        let nb_items = images_iter.get_nb_items();
+        let mut images = Array3::<u8>::zeros((nb_rows, nb_columns, nb_items));
+        let mut nb_images = 0;
+        for (k, image) in images_iter.enumerate() {
+            for i in 0..nb_rows {
+                let mut smut_ik = images.slice_mut(s![i, .., k]);
+                for j in 0..nb_columns {
+                    smut_ik[j] = image[i * nb_columns + j];
+                }
+            }
+            nb_images += 1;
+        }
+        let labels: Vec<u8> = IdxLabels::new(label_path.as_ref())?.collect();
+        if nb_images != nb_items || labels.len() != nb_items {
+            return Err(AnnembedError::InvalidParameter(format!(
+                "MnistData::new, read {} images, {} labels, expected {}",
+                nb_images,
+                labels.len(),
+                nb_items
+            )));
+        }
+        Ok(MnistData { images, labels: Array1::from(labels) })
+    } // end of new for MnistData
+
+    /// loads prefix-images-idx3-ubyte and prefix-labels-idx1-ubyte (or their .gz versions) in dir.
+    /// prefix is "train" or "t10k" for the MNIST databases
+    pub fn from_dir<P: AsRef<Path>>(dir: P, prefix: &str) -> Result<MnistData, AnnembedError> {
+        let dir = dir.as_ref();
+        let image_path = find_maybe_gz(&dir.join(format!("{}-images-idx3-ubyte", prefix)))?;
+        let label_path = find_maybe_gz(&dir.join(format!("{}-labels-idx1-ubyte", prefix)))?;
+        MnistData::new(image_path, label_path)
+    }
+
+    /// returns labels of images. labels\[k\] is the label of the k th image.
+    pub fn get_labels(&self) -> &Array1<u8> {
+        &self.labels
+    }
+
+    /// returns images. images are stored in Array3 with Array3[[.., .., k]] being the k images!
+    /// Each image is stored as it is in the Mnist files, Array3[[i, .., k]] is the i row of the k image
+    pub fn get_images(&self) -> &Array3<u8> {
+        &self.images
+    }
+
+    /// returns images flattened row by row as vectors of f32, as needed to insert them in a Hnsw
+    pub fn get_images_as_vec(&self) -> Vec<Vec<f32>> {
+        let (_, _, nb_images) = self.images.dim();
+        (0..nb_images)
+            .map(|k| self.images.slice(s![.., .., k]).iter().map(|v| *v as f32).collect())
+            .collect()
+    }
+} // end of impl MnistData
+
+//=======================================================================
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use byteorder::WriteBytesExt;
+    use flate2::{write::GzEncoder, Compression};
+    use std::io::Write;
+
+    fn log_init_test() {
+        let _ = env_logger::builder().is_test(true).try_init();
+    }
+
+    // writes 3 images 2x2 and their labels, images file gzipped
+    fn write_idx_files(dir: &Path) {
+        let mut images = Vec::<u8>::new();
+        images.write_u32::<BigEndian>(IDX_IMAGE_MAGIC).unwrap();
+        for v in [3, 2, 2] {
+            images.write_u32::<BigEndian>(v).unwrap();
+        }
+        images.extend(0..12u8);
+        let file = std::fs::File::create(dir.join("train-images-idx3-ubyte.gz")).unwrap();
+        let mut encoder = GzEncoder::new(file, Compression::default());
+        encoder.write_all(&images).unwrap();
+        encoder.finish().unwrap();
+        let mut labels = Vec::<u8>::new();
+        labels.write_u32::<BigEndian>(IDX_LABEL_MAGIC).unwrap();
+        labels.write_u32::<BigEndian>(3).unwrap();
+        labels.extend([7u8, 1, 9]);
+        std::fs::write(dir.join("train-labels-idx1-ubyte"), labels).unwrap();
+    }
+
+    #[test]
+    fn test_idx_gz() {
+        log_init_test();
+        let dir = std::env::temp_dir().join(format!("annembed_idx_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        write_idx_files(&dir);
+        let labels: Vec<u8> = IdxLabels::new(&dir.join("train-labels-idx1-ubyte")).unwrap().collect();
+        assert_eq!(labels, vec![7, 1, 9]);
+        let images: Vec<Vec<u8>> = IdxImages::new(&dir.join("train-images-idx3-ubyte.gz")).unwrap().collect();
+        assert_eq!(images[1], vec![4, 5, 6, 7]);
+        // from_dir finds the gzipped images file
+        let mnist = MnistData::from_dir(&dir, "train").unwrap();
+        assert_eq!(mnist.get_images()[[1, 0, 2]], 10);
+        assert_eq!(mnist.get_images_as_vec()[2], vec![8., 9., 10., 11.]);
+        assert!(MnistData::from_dir(&dir, "t10k").is_err());
+        std::fs::remove_dir_all(&dir).unwrap();
+    } // end of test_idx_gz
+} // end of mod tests
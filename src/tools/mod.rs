@@ -6,3 +6,17 @@ pub mod dichotomy;
 pub mod io;
 pub mod dimension;
 pub mod nodeparam;
+pub mod clip;
+pub mod stage;
+pub mod threads;
+pub mod quant;
+pub mod report;
+pub mod safetensors;
+pub mod htmlplot;
+#[cfg(feature = "mnist")]
+pub mod mnistio;
+#[cfg(feature = "arrow")]
+pub mod tabular;
+#[cfg(feature = "anndata")]
+pub mod anndata;
+pub mod distplugin;
@@ -5,6 +5,7 @@
 //! 
 //! 
 
+
 use serde::{Serialize, Deserialize};
 
 use num_traits::Float;
@@ -14,20 +15,76 @@ use std::cmp::Ordering;
 
 use hnsw_rs::hnsw::Neighbour;
 
+use crate::error::AnnembedError;
+
 /// keep a node index compatible with NdArray
 pub type NodeIdx = usize;
 
+/// type of node indexes stored in edges. u32 by default, halving memory of edges with f32 weights
+/// (an OutEdge<f64> is padded to 16 bytes anyway). The feature *large_graph* switches to usize for graphs
+/// with more than u32::MAX nodes. It is internal to [OutEdge], whose destination node is read by [OutEdge::get_node] as a [NodeIdx],
+/// so that the interface does not depend on the feature. Sparse laplacian matrices keep usize indexes.
+#[cfg(not(feature = "large_graph"))]
+pub type EdgeIdx = u32;
+#[cfg(feature = "large_graph")]
+pub type EdgeIdx = usize;
+
+/// number of bits of [EdgeIdx], written in dumps of graphs and embedders so that a dump is not reloaded
+/// with another edge index width.
+pub const EDGE_IDX_BITS : u32 = EdgeIdx::BITS;
+
+/// checks that the nodes of a graph with nb_nodes nodes can be stored in [EdgeIdx].  
+/// Graph constructions of the crate call it so that [OutEdge::new] cannot panic on their nodes.
+pub fn check_edge_idx(nb_nodes : usize) -> Result<(), AnnembedError> {
+    if EdgeIdx::try_from(nb_nodes.saturating_sub(1)).is_err() {
+        log::error!("{} nodes do not fit in edge indexes of {} bits, use feature large_graph", nb_nodes, EDGE_IDX_BITS);
+        return Err(AnnembedError::InvalidParameter(format!("{} nodes do not fit in edge indexes of {} bits, use feature large_graph",
+                        nb_nodes, EDGE_IDX_BITS)));
+    }
+    Ok(())
+} // end of check_edge_idx
+
+/// checks that a dump was written with edge indexes of [EDGE_IDX_BITS] bits
+pub(crate) fn check_edge_idx_bits(bits : u32) -> Result<(), AnnembedError> {
+    if bits != EDGE_IDX_BITS {
+        log::error!("dump has edge indexes of {} bits, expected {}", bits, EDGE_IDX_BITS);
+        return Err(AnnembedError::InvalidParameter(format!("dump has edge indexes of {} bits, this build uses {} (see feature large_graph)",
+                        bits, EDGE_IDX_BITS)));
+    }
+    Ok(())
+} // end of check_edge_idx_bits
+
 /// an outEdge gives the destination node and weight of edge.
 #[derive(Clone,Copy,Debug, Serialize, Deserialize)]
 pub struct OutEdge<F> {
-    pub node : NodeIdx,
+    // destination node, stored as an EdgeIdx whose width depends on feature large_graph.
+    // It is private so that the public interface only sees NodeIdx through get_node
+    node : EdgeIdx,
     pub weight: F
 }  // end of struct OutEdge<F>
 
 
 impl <F>  OutEdge<F> {
+    /// panics if node does not fit in [EdgeIdx], see [try_new](Self::try_new).
+    /// Graphs built by the crate check their number of nodes with [check_edge_idx].
     pub fn new(node:NodeIdx, weight: F) -> Self {
-        OutEdge{node, weight}
+        Self::try_new(node, weight).unwrap()
+    }
+
+    /// returns an error if node does not fit in [EdgeIdx], then enable feature large_graph
+    pub fn try_new(node:NodeIdx, weight: F) -> Result<Self, AnnembedError> {
+        match EdgeIdx::try_from(node) {
+            Ok(node) => Ok(OutEdge{node, weight}),
+            Err(_) => Err(AnnembedError::InvalidParameter(format!("node {} overflows edge indexes of {} bits, use feature large_graph",
+                                node, EDGE_IDX_BITS))),
+        }
+    }
+
+    /// returns destination node of edge
+    #[allow(clippy::unnecessary_cast)]
+    #[inline]
+    pub fn get_node(&self) -> NodeIdx {
+        self.node as NodeIdx
     }
 }
 
@@ -53,10 +110,7 @@ impl <F> From<Neighbour> for OutEdge<F>
             where F  : Float + FromPrimitive {
     //
     fn from(neighbour : Neighbour) -> OutEdge<F> {
-        OutEdge{
-            node : neighbour.d_id,
-            weight : F::from_f32(neighbour.distance).unwrap()
-        }
+        OutEdge::new(neighbour.d_id, F::from_f32(neighbour.distance).unwrap())
     } // end of from
 }   // end impl From<Neighbour>
 
@@ -68,7 +122,7 @@ impl <F> From<Neighbour> for OutEdge<F>
 ///    (distance and proba) to its nearest neighbours as referenced in field neighbours of KGraph.
 ///
 /// Identity of neighbour node must be fetched in KGraph structure to spare memory
-#[derive(Clone)]
+#[derive(Clone, Serialize, Deserialize)]
 pub struct NodeParam {
     pub(crate) scale: f32,
     pub(crate) edges: Vec<OutEdge<f32>>,
@@ -81,7 +135,7 @@ impl NodeParam {
 
     /// for a given node index return corresponding edge if it is in neighbours, None else 
     pub fn get_edge(&self, i : NodeIdx) -> Option<&OutEdge<f32>> {
-        self.edges.iter().find( |&&edge| edge.node == i)
+        self.edges.iter().find( |&&edge| edge.get_node() == i)
     }  // end of is_around
 
     /// perplexity. Hill number cf Leinster
@@ -107,6 +161,7 @@ impl Default for NodeParam {
 
 
 /// We maintain NodeParam for each node as it enables scaling in the embedded space and cross entropy minimization.
+#[derive(Clone, Serialize, Deserialize)]
 pub struct NodeParams {
     pub params: Vec<NodeParam>,
     pub max_nbng : usize,
@@ -116,6 +171,49 @@ impl NodeParams {
     pub fn new(params :Vec<NodeParam>, max_nbng : usize) -> Self {
         NodeParams{params, max_nbng}
     }
+
+    /// builds NodeParams from affinities computed by the user.  
+    /// neighbours\[i\] lists the (node, affinity) couples of node i, nodes being indexes in 0..neighbours.len().
+    /// Affinities must be finite and positive, they are normalized to a probability for each node.
+    /// A node must have at least one neighbour, no self loop or duplicated neighbour.  
+    /// scales are the local scales of nodes (used to modulate distances in embedded space), all set to 1. if None.
+    pub fn from_affinities(neighbours : Vec<Vec<(NodeIdx, f32)>>, scales : Option<Vec<f32>>) -> Result<Self, AnnembedError> {
+        let nb_nodes = neighbours.len();
+        check_edge_idx(nb_nodes)?;
+        let scales = scales.unwrap_or_else(|| vec![1.; nb_nodes]);
+        if scales.len() != nb_nodes {
+            return Err(AnnembedError::InvalidParameter(format!("NodeParams::from_affinities got {} scales for {} nodes", scales.len(), nb_nodes)));
+        }
+        let mut params = Vec::<NodeParam>::with_capacity(nb_nodes);
+        let mut max_nbng = 0;
+        for (i, (node_neighbours, scale)) in neighbours.into_iter().zip(scales).enumerate() {
+            if !(scale.is_finite() && scale > 0.) {
+                return Err(AnnembedError::InvalidParameter(format!("NodeParams::from_affinities node {} has scale {}", i, scale)));
+            }
+            if node_neighbours.is_empty() {
+                return Err(AnnembedError::InvalidParameter(format!("NodeParams::from_affinities node {} has no neighbour", i)));
+            }
+            let mut sum = 0.;
+            for (j, (node, affinity)) in node_neighbours.iter().enumerate() {
+                if *node >= nb_nodes || *node == i {
+                    return Err(AnnembedError::InvalidParameter(format!("NodeParams::from_affinities node {} has invalid neighbour {}", i, node)));
+                }
+                if node_neighbours[..j].iter().any(|(n, _)| n == node) {
+                    return Err(AnnembedError::InvalidParameter(format!("NodeParams::from_affinities node {} has duplicated neighbour {}", i, node)));
+                }
+                if !(affinity.is_finite() && *affinity > 0.) {
+                    return Err(AnnembedError::InvalidParameter(format!("NodeParams::from_affinities node {} has affinity {} to {}", i, affinity, node)));
+                }
+                sum += affinity;
+            }
+            let mut edges : Vec<OutEdge<f32>> = node_neighbours.iter().map(|(node, affinity)| OutEdge::new(*node, affinity / sum)).collect();
+            // probabilities are stored in decreasing order
+            edges.sort_unstable_by(|a, b| b.weight.partial_cmp(&a.weight).unwrap());
+            max_nbng = max_nbng.max(edges.len());
+            params.push(NodeParam::new(scale, edges));
+        }
+        Ok(NodeParams{params, max_nbng})
+    } // end of from_affinities
     //
     pub fn get_node_param(&self, node: NodeIdx) -> &NodeParam {
         return &self.params[node];
@@ -130,3 +228,46 @@ impl NodeParams {
     }
 } // end of NodeParams
 
+//=================================================================================================================
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_from_affinities() {
+        let neighbours = vec![vec![(1, 1.), (2, 3.)], vec![(0, 2.)], vec![(0, 1.), (1, 1.)]];
+        let node_params = NodeParams::from_affinities(neighbours, None).unwrap();
+        assert_eq!(node_params.get_nb_nodes(), 3);
+        assert_eq!(node_params.get_max_nbng(), 2);
+        let edges = &node_params.get_node_param(0).edges;
+        assert_eq!(edges[0].get_node(), 2);
+        assert_eq!(edges[0].weight, 0.75);
+        assert_eq!(node_params.get_node_param(1).get_edge(0).unwrap().weight, 1.);
+        // out of range, self loop, duplicate, negative affinity, missing neighbours, bad scales
+        assert!(NodeParams::from_affinities(vec![vec![(2, 1.)], vec![(0, 1.)]], None).is_err());
+        assert!(NodeParams::from_affinities(vec![vec![(0, 1.)], vec![(0, 1.)]], None).is_err());
+        assert!(NodeParams::from_affinities(vec![vec![(1, 1.), (1, 2.)], vec![(0, 1.)]], None).is_err());
+        assert!(NodeParams::from_affinities(vec![vec![(1, -1.)], vec![(0, 1.)]], None).is_err());
+        assert!(NodeParams::from_affinities(vec![vec![(1, 1.)], vec![]], None).is_err());
+        assert!(NodeParams::from_affinities(vec![vec![(1, 1.)], vec![(0, 1.)]], Some(vec![1.])).is_err());
+    } // end of test_from_affinities
+
+    #[test]
+    fn test_edge_idx() {
+        let edge = OutEdge::<f32>::try_new(5, 1.).unwrap();
+        assert_eq!(edge.get_node(), 5);
+        assert!(check_edge_idx(0).is_ok());
+        assert!(check_edge_idx(1000).is_ok());
+        #[cfg(not(feature = "large_graph"))]
+        {
+            let nb_nodes = u32::MAX as usize + 2;
+            assert!(OutEdge::<f32>::try_new(nb_nodes - 1, 1.).is_err());
+            assert!(check_edge_idx(nb_nodes).is_err());
+        }
+        assert!(check_edge_idx_bits(EDGE_IDX_BITS).is_ok());
+        assert!(check_edge_idx_bits(EDGE_IDX_BITS / 2).is_err());
+    } // end of test_edge_idx
+} // end of mod tests
+
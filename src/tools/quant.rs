@@ -0,0 +1,71 @@
+//! Quantile estimation used in graph and embedding statistics.
+//!
+//! With feature *quantiles* (default) [Quantiles] is the CKMS sketch of crate quantiles.
+//! Without it [Quantiles] is [ExactQuantiles] which keeps all inserted values. It has the same interface
+//! (new, insert, query, count) and is enough for statistics gathered once per node.
+
+#[cfg(feature = "quantiles")]
+pub type Quantiles<T> = quantiles::ckms::CKMS<T>;
+
+#[cfg(not(feature = "quantiles"))]
+pub type Quantiles<T> = ExactQuantiles<T>;
+
+/// Exact quantiles of inserted values. Each query selects in a copy of values, in linear time.
+#[derive(Clone, Debug)]
+pub struct ExactQuantiles<T> {
+    values: Vec<T>,
+}
+
+impl<T: Copy + PartialOrd> ExactQuantiles<T> {
+    /// error is ignored as quantiles are exact, it is kept for compatibility with CKMS
+    pub fn new(_error: f64) -> Self {
+        ExactQuantiles { values: Vec::new() }
+    }
+
+    pub fn insert(&mut self, v: T) {
+        self.values.push(v);
+    }
+
+    /// number of values inserted
+    pub fn count(&self) -> usize {
+        self.values.len()
+    }
+
+    /// returns the rank (from 1) and the value at quantile q (clamped to \[0,1\]). None if no value was inserted.
+    pub fn query(&self, q: f64) -> Option<(usize, T)> {
+        let nb_values = self.values.len();
+        if nb_values == 0 {
+            return None;
+        }
+        let rank = ((q.clamp(0., 1.) * nb_values as f64).ceil() as usize).clamp(1, nb_values);
+        let mut values = self.values.clone();
+        let (_, value, _) =
+            values.select_nth_unstable_by(rank - 1, |a, b| a.partial_cmp(b).unwrap_or(std::cmp::Ordering::Equal));
+        Some((rank, *value))
+    }
+} // end of impl ExactQuantiles
+
+//=======================================================================
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_exact_quantiles() {
+        let mut quant = ExactQuantiles::<f32>::new(0.001);
+        assert!(quant.query(0.5).is_none());
+        for i in (1..=100).rev() {
+            quant.insert(i as f32);
+        }
+        assert_eq!(quant.count(), 100);
+        assert_eq!(quant.query(0.5), Some((50, 50.)));
+        assert_eq!(quant.query(0.), Some((1, 1.)));
+        assert_eq!(quant.query(1.), Some((100, 100.)));
+        // same interface as the sketch
+        let mut sketch = Quantiles::<f32>::new(0.001);
+        sketch.insert(2.);
+        assert_eq!(sketch.query(0.5).unwrap().1, 2.);
+    } // end of test_exact_quantiles
+} // end of mod tests
@@ -0,0 +1,225 @@
+//! Report of an embedding run : resources (wall time, cpu time, peak resident memory) and diagnostics
+//! (graph statistics, spectrum, final loss).
+//!
+//! A [ResourceMonitor] is started before the first stage and closes stages as they end, sampling resident memory
+//! with crate memory_stats. It also profiles the stages of the crate (graph, node_params, laplacian, svd, gradient)
+//! run meanwhile by its thread, see [StageProfiler]. The [RunReport] it produces is filled with diagnostics by the caller and is serializable
+//! with serde, so it can be dumped in json with [RunReport::dump_json] or sent to any serde format.
+
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use std::time::SystemTime;
+
+use cpu_time::ProcessTime;
+use num_traits::cast::FromPrimitive;
+use num_traits::Float;
+use serde::{Deserialize, Serialize};
+
+use crate::error::AnnembedError;
+use crate::fromhnsw::kgraph::KGraph;
+use crate::tools::stage::StageProfiler;
+
+/// Statistics of the neighbourhood graph. Degrees are out degrees (number of neighbours kept for each node).
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct GraphReport {
+    pub nb_nodes: usize,
+    pub nb_edges: usize,
+    /// number of connected components, edges considered undirected
+    pub nb_components: usize,
+    pub min_degree: usize,
+    pub mean_degree: f64,
+    pub max_degree: usize,
+}
+
+impl GraphReport {
+    pub fn from_kgraph<F>(kgraph: &KGraph<F>) -> Self
+    where
+        F: FromPrimitive + Float + std::fmt::UpperExp + Sync + Send + std::iter::Sum,
+    {
+        let mut report = GraphReport {
+            nb_nodes: kgraph.get_nb_nodes(),
+            min_degree: usize::MAX,
+            ..Default::default()
+        };
+        for (_, edges) in kgraph.iter_neighbourhoods() {
+            report.nb_edges += edges.len();
+            report.min_degree = report.min_degree.min(edges.len());
+            report.max_degree = report.max_degree.max(edges.len());
+        }
+        if report.nb_nodes == 0 {
+            report.min_degree = 0;
+        } else {
+            report.mean_degree = report.nb_edges as f64 / report.nb_nodes as f64;
+        }
+        report.nb_components = kgraph.get_connected_components().iter().max().map_or(0, |c| c + 1);
+        report
+    }
+} // end of impl GraphReport
+
+/// Spectral diagnostics of a diffusion maps embedding
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct SpectralReport {
+    /// eigenvalues computed, decreasing, the first one is the stationary one
+    pub eigenvalues: Vec<f64>,
+    /// difference between the 2 first eigenvalues
+    pub spectral_gap: f64,
+    /// diffusion time used, None with commute time weighting
+    pub diffusion_time: Option<f64>,
+}
+
+impl SpectralReport {
+    pub fn new(eigenvalues: Vec<f64>, diffusion_time: Option<f64>) -> Self {
+        let spectral_gap = if eigenvalues.len() >= 2 { eigenvalues[0] - eigenvalues[1] } else { 0. };
+        SpectralReport { eigenvalues, spectral_gap, diffusion_time }
+    }
+} // end of impl SpectralReport
+
+/// Time and memory of a stage of the crate (see [Stage](crate::tools::stage::Stage)),
+/// collected by a [StageProfiler](crate::tools::stage::StageProfiler).
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct StageProfile {
+    /// stage name : insertion, graph, node_params, laplacian, svd or gradient
+    pub name: String,
+    pub sys_ms: u64,
+    /// process cpu time in ms (all threads) during the stage
+    pub cpu_ms: u64,
+    /// maximum of resident memory (bytes) sampled at stage entry, progress reports and exit.
+    /// None if memory stats are not available on the platform
+    pub peak_rss_bytes: Option<u64>,
+}
+
+/// Resources and diagnostics of a run.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct RunReport {
+    /// number of points embedded
+    pub nb_points: usize,
+    /// sys time in ms of the whole run
+    pub wall_ms: u64,
+    /// process cpu time in ms of the whole run (all threads)
+    pub cpu_ms: u64,
+    /// maximum of resident memory (bytes) sampled at end of stages. None if memory stats are not available on the platform
+    pub peak_rss_bytes: Option<u64>,
+    /// sys time in ms of each stage, in execution order
+    pub stages_ms: Vec<(String, u64)>,
+    /// time and memory of the stages inside the hnsw, graph and embedding stages, in order of completion
+    pub profile: Vec<StageProfile>,
+    pub graph: Option<GraphReport>,
+    /// present if embedding was done by diffusion maps
+    pub spectral: Option<SpectralReport>,
+    /// cross entropy at end of gradient optimization, present if embedding was done by the Embedder
+    pub final_loss: Option<f64>,
+}
+
+impl RunReport {
+    /// returns sys time in ms of stage name, if it was recorded
+    pub fn get_stage_ms(&self, name: &str) -> Option<u64> {
+        self.stages_ms.iter().find(|(stage, _)| stage == name).map(|(_, ms)| *ms)
+    }
+
+    /// dumps the report in json
+    pub fn dump_json(&self, path: &Path) -> Result<(), AnnembedError> {
+        let mut writer = BufWriter::new(std::fs::File::create(path)?);
+        serde_json::to_writer_pretty(&mut writer, self).map_err(std::io::Error::from)?;
+        writer.flush()?;
+        Ok(())
+    }
+} // end of impl RunReport
+
+/// returns current resident memory of the process in bytes, None if not available on the platform
+pub fn get_resident_memory() -> Option<u64> {
+    memory_stats::memory_stats().map(|stats| stats.physical_mem as u64)
+}
+
+/// Measures time of a run and of its stages and keeps the maximum of resident memory sampled at end of stages.  
+/// The monitor must stay in the thread that created it to profile the stages of the crate.
+pub struct ResourceMonitor {
+    sys_start: SystemTime,
+    cpu_start: ProcessTime,
+    stage_start: SystemTime,
+    peak_rss: Option<u64>,
+    stages_ms: Vec<(String, u64)>,
+    profiler: StageProfiler,
+}
+
+impl ResourceMonitor {
+    /// starts monitoring, the first stage begins now
+    pub fn new() -> Self {
+        let now = SystemTime::now();
+        ResourceMonitor {
+            sys_start: now,
+            cpu_start: ProcessTime::now(),
+            stage_start: now,
+            peak_rss: get_resident_memory(),
+            stages_ms: Vec::new(),
+            profiler: StageProfiler::start(),
+        }
+    }
+
+    /// samples resident memory and updates its maximum
+    pub fn sample_memory(&mut self) {
+        if let Some(rss) = get_resident_memory() {
+            self.peak_rss = Some(self.peak_rss.map_or(rss, |peak| peak.max(rss)));
+        }
+    }
+
+    /// ends the current stage, recording its sys time, and begins the next one. Returns the stage time in ms.
+    pub fn end_stage(&mut self, name: &str) -> u64 {
+        let ms = self.stage_start.elapsed().map_or(0, |d| d.as_millis() as u64);
+        self.stages_ms.push((String::from(name), ms));
+        self.sample_memory();
+        self.stage_start = SystemTime::now();
+        ms
+    }
+
+    /// returns a report with times and memory. Diagnostics are left to the caller.
+    pub fn get_report(&mut self, nb_points: usize) -> RunReport {
+        self.sample_memory();
+        RunReport {
+            nb_points,
+            wall_ms: self.sys_start.elapsed().map_or(0, |d| d.as_millis() as u64),
+            cpu_ms: self.cpu_start.elapsed().as_millis() as u64,
+            peak_rss_bytes: self.peak_rss,
+            stages_ms: self.stages_ms.clone(),
+            profile: self.profiler.get_profiles(),
+            ..Default::default()
+        }
+    }
+} // end of impl ResourceMonitor
+
+impl Default for ResourceMonitor {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+//=======================================================================
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_run_report_json() {
+        let mut monitor = ResourceMonitor::new();
+        monitor.end_stage("hnsw");
+        drop(crate::tools::stage::Stage::enter("node_params"));
+        monitor.end_stage("graph");
+        let mut report = monitor.get_report(10);
+        assert_eq!(report.profile.len(), 1);
+        assert_eq!(report.profile[0].name, "node_params");
+        report.spectral = Some(SpectralReport::new(vec![1., 0.75, 0.5], Some(2.)));
+        report.final_loss = Some(1.5);
+        assert_eq!(report.spectral.as_ref().unwrap().spectral_gap, 0.25);
+        assert!(report.get_stage_ms("graph").is_some() && report.get_stage_ms("embedding").is_none());
+        if let Some(peak) = report.peak_rss_bytes {
+            assert!(peak > 0);
+        }
+        // json round trip
+        let path = std::env::temp_dir().join("annembed_test_report.json");
+        report.dump_json(&path).unwrap();
+        let reloaded: RunReport = serde_json::from_reader(std::fs::File::open(&path).unwrap()).unwrap();
+        assert_eq!(reloaded, report);
+        let _ = std::fs::remove_file(&path);
+    } // end of test_run_report_json
+} // end of mod tests
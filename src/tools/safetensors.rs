@@ -0,0 +1,311 @@
+//! Minimal reader and writer of the [safetensors](https://github.com/huggingface/safetensors) format, to exchange
+//! embeddings, eigenvectors or any array with ML serving stacks (python safetensors, candle, transformers...)
+//! without pickle nor csv conversion.
+//!
+//! A file is made of an 8 bytes little endian u64 giving the size of a json header, the json header, then the raw
+//! little endian bytes of tensors. The header gives for each tensor its dtype, shape and offsets in the byte buffer,
+//! and an optional *\_\_metadata\_\_* map of strings.
+//! We support dtypes F32, F64 and U64 (used for DataId).
+
+use std::collections::BTreeMap;
+use std::io::{BufWriter, Read, Write};
+use std::path::Path;
+
+use ndarray::{Array1, Array2};
+use num_traits::Float;
+use serde::{Deserialize, Serialize};
+
+use crate::error::AnnembedError;
+
+/// key of the metadata map in the json header
+const METADATA_KEY: &str = "__metadata__";
+
+/// upper bound on header size, as in the reference implementation
+const MAX_HEADER_SIZE: u64 = 100_000_000;
+
+/// element types we read and write
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Dtype {
+    F32,
+    F64,
+    U64,
+}
+
+impl Dtype {
+    /// size in bytes of an element
+    pub fn get_size(&self) -> usize {
+        match self {
+            Dtype::F32 => 4,
+            Dtype::F64 | Dtype::U64 => 8,
+        }
+    }
+} // end of impl Dtype
+
+/// description of a tensor in the json header
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct TensorInfo {
+    pub dtype: Dtype,
+    pub shape: Vec<usize>,
+    /// begin and end of tensor bytes, relative to the end of header
+    pub data_offsets: (usize, usize),
+}
+
+fn invalid_data(msg: String) -> AnnembedError {
+    AnnembedError::Io(std::io::Error::new(std::io::ErrorKind::InvalidData, msg))
+}
+
+/// Collects named tensors and metadata, then writes them in a safetensors file.
+///
+/// ```ignore
+/// let mut writer = SafeTensorsWriter::new();
+/// writer.add_array2_f32("embedding", &embedded)?;
+/// writer.add_u64("data_ids", &data_ids)?;
+/// writer.add_metadata("method", "diffusion maps");
+/// writer.write(Path::new("embedded.safetensors"))?;
+/// ```
+#[derive(Default)]
+pub struct SafeTensorsWriter {
+    /// tensors in order of insertion
+    tensors: Vec<(String, TensorInfo, Vec<u8>)>,
+    metadata: BTreeMap<String, String>,
+} // end of SafeTensorsWriter
+
+impl SafeTensorsWriter {
+    pub fn new() -> Self {
+        SafeTensorsWriter::default()
+    }
+
+    /// adds (or replaces) a metadata entry
+    pub fn add_metadata(&mut self, key: &str, value: &str) {
+        self.metadata.insert(key.to_string(), value.to_string());
+    }
+
+    // stores bytes of a tensor, names must be unique
+    fn add_bytes(&mut self, name: &str, dtype: Dtype, shape: Vec<usize>, bytes: Vec<u8>) -> Result<(), AnnembedError> {
+        if name == METADATA_KEY || self.tensors.iter().any(|t| t.0 == name) {
+            return Err(AnnembedError::InvalidParameter(format!("safetensors, duplicate or reserved tensor name {}", name)));
+        }
+        let begin = self.tensors.last().map_or(0, |t| t.1.data_offsets.1);
+        let info = TensorInfo {
+            dtype,
+            shape,
+            data_offsets: (begin, begin + bytes.len()),
+        };
+        self.tensors.push((name.to_string(), info, bytes));
+        Ok(())
+    }
+
+    /// adds a matrix stored as F32, whatever its float type
+    pub fn add_array2_f32<F: Float>(&mut self, name: &str, mat: &Array2<F>) -> Result<(), AnnembedError> {
+        let bytes = mat.iter().flat_map(|x| x.to_f32().unwrap().to_le_bytes()).collect();
+        self.add_bytes(name, Dtype::F32, vec![mat.nrows(), mat.ncols()], bytes)
+    }
+
+    /// adds a matrix stored as F64
+    pub fn add_array2_f64<F: Float>(&mut self, name: &str, mat: &Array2<F>) -> Result<(), AnnembedError> {
+        let bytes = mat.iter().flat_map(|x| x.to_f64().unwrap().to_le_bytes()).collect();
+        self.add_bytes(name, Dtype::F64, vec![mat.nrows(), mat.ncols()], bytes)
+    }
+
+    /// adds a vector stored as F64
+    pub fn add_array1_f64<F: Float>(&mut self, name: &str, values: &[F]) -> Result<(), AnnembedError> {
+        let bytes = values.iter().flat_map(|x| x.to_f64().unwrap().to_le_bytes()).collect();
+        self.add_bytes(name, Dtype::F64, vec![values.len()], bytes)
+    }
+
+    /// adds a vector of integers (DataId for example) stored as U64
+    pub fn add_u64(&mut self, name: &str, values: &[usize]) -> Result<(), AnnembedError> {
+        let bytes = values.iter().flat_map(|x| (*x as u64).to_le_bytes()).collect();
+        self.add_bytes(name, Dtype::U64, vec![values.len()], bytes)
+    }
+
+    /// returns the number of tensors added
+    pub fn get_nb_tensors(&self) -> usize {
+        self.tensors.len()
+    }
+
+    /// writes header and tensors in file at path
+    pub fn write(&self, path: &Path) -> Result<(), AnnembedError> {
+        let mut header = serde_json::Map::new();
+        if !self.metadata.is_empty() {
+            header.insert(METADATA_KEY.to_string(), serde_json::to_value(&self.metadata).map_err(std::io::Error::from)?);
+        }
+        for (name, info, _) in &self.tensors {
+            header.insert(name.clone(), serde_json::to_value(info).map_err(std::io::Error::from)?);
+        }
+        let mut header = serde_json::to_vec(&header).map_err(std::io::Error::from)?;
+        // tensors bytes are aligned on 8 bytes
+        let padded_len = header.len().div_ceil(8) * 8;
+        header.resize(padded_len, b' ');
+        //
+        let file = std::fs::OpenOptions::new().write(true).create(true).truncate(true).open(path)?;
+        let mut writer = BufWriter::new(file);
+        writer.write_all(&(header.len() as u64).to_le_bytes())?;
+        writer.write_all(&header)?;
+        for (_, _, bytes) in &self.tensors {
+            writer.write_all(bytes)?;
+        }
+        writer.flush()?;
+        log::info!("safetensors, {} tensors written in {}", self.tensors.len(), path.display());
+        Ok(())
+    } // end of write
+} // end of impl SafeTensorsWriter
+
+//=====================================================================================
+
+/// A safetensors file loaded in memory. Float tensors are returned as f64 whatever their stored float type.
+pub struct SafeTensors {
+    tensors: BTreeMap<String, TensorInfo>,
+    metadata: BTreeMap<String, String>,
+    /// bytes following the header
+    data: Vec<u8>,
+} // end of SafeTensors
+
+impl SafeTensors {
+    /// reads a safetensors file. Fails on malformed header or offsets out of data.
+    pub fn load(path: &Path) -> Result<Self, AnnembedError> {
+        let mut file = std::fs::File::open(path)?;
+        let mut len_bytes = [0u8; 8];
+        file.read_exact(&mut len_bytes)?;
+        let header_len = u64::from_le_bytes(len_bytes);
+        if header_len > MAX_HEADER_SIZE {
+            return Err(invalid_data(format!("safetensors header too large : {}", header_len)));
+        }
+        let mut header = vec![0u8; header_len as usize];
+        file.read_exact(&mut header)?;
+        let header: BTreeMap<String, serde_json::Value> = serde_json::from_slice(&header).map_err(std::io::Error::from)?;
+        let mut data = Vec::<u8>::new();
+        file.read_to_end(&mut data)?;
+        //
+        let mut tensors = BTreeMap::<String, TensorInfo>::new();
+        let mut metadata = BTreeMap::<String, String>::new();
+        for (name, value) in header {
+            if name == METADATA_KEY {
+                metadata = serde_json::from_value(value).map_err(std::io::Error::from)?;
+                continue;
+            }
+            let info: TensorInfo = serde_json::from_value(value).map_err(std::io::Error::from)?;
+            let nb_bytes = info.shape.iter().product::<usize>() * info.dtype.get_size();
+            let (begin, end) = info.data_offsets;
+            if begin > end || end > data.len() || end - begin != nb_bytes {
+                return Err(invalid_data(format!("safetensors, inconsistent offsets or shape for tensor {}", name)));
+            }
+            tensors.insert(name, info);
+        }
+        log::debug!("safetensors, loaded {} tensors from {}", tensors.len(), path.display());
+        Ok(SafeTensors { tensors, metadata, data })
+    } // end of load
+
+    /// returns names of tensors (sorted)
+    pub fn get_names(&self) -> Vec<&String> {
+        self.tensors.keys().collect()
+    }
+
+    /// returns the metadata map of header
+    pub fn get_metadata(&self) -> &BTreeMap<String, String> {
+        &self.metadata
+    }
+
+    /// returns dtype, shape and offsets of tensor name
+    pub fn get_info(&self, name: &str) -> Option<&TensorInfo> {
+        self.tensors.get(name)
+    }
+
+    fn get_checked(&self, name: &str, dims: usize) -> Result<&TensorInfo, AnnembedError> {
+        let info = self
+            .tensors
+            .get(name)
+            .ok_or_else(|| AnnembedError::InvalidParameter(format!("safetensors, no tensor {}", name)))?;
+        if info.shape.len() != dims {
+            return Err(AnnembedError::InvalidParameter(format!(
+                "safetensors, tensor {} has shape {:?}, expected {} dimensions",
+                name, info.shape, dims
+            )));
+        }
+        Ok(info)
+    }
+
+    // values of a float tensor converted to f64
+    fn get_f64_values(&self, name: &str, info: &TensorInfo) -> Result<Vec<f64>, AnnembedError> {
+        let bytes = &self.data[info.data_offsets.0..info.data_offsets.1];
+        match info.dtype {
+            Dtype::F32 => Ok(bytes
+                .chunks_exact(4)
+                .map(|b| f32::from_le_bytes(b.try_into().unwrap()) as f64)
+                .collect()),
+            Dtype::F64 => Ok(bytes.chunks_exact(8).map(|b| f64::from_le_bytes(b.try_into().unwrap())).collect()),
+            Dtype::U64 => Err(AnnembedError::InvalidParameter(format!("safetensors, tensor {} is not a float tensor", name))),
+        }
+    }
+
+    /// returns a 2 dimensional float tensor
+    pub fn get_array2_f64(&self, name: &str) -> Result<Array2<f64>, AnnembedError> {
+        let info = self.get_checked(name, 2)?;
+        let values = self.get_f64_values(name, info)?;
+        Array2::from_shape_vec((info.shape[0], info.shape[1]), values).map_err(|e| invalid_data(e.to_string()))
+    }
+
+    /// returns a 1 dimensional float tensor
+    pub fn get_array1_f64(&self, name: &str) -> Result<Array1<f64>, AnnembedError> {
+        let info = self.get_checked(name, 1)?;
+        Ok(Array1::from_vec(self.get_f64_values(name, info)?))
+    }
+
+    /// returns a 1 dimensional U64 tensor
+    pub fn get_u64(&self, name: &str) -> Result<Vec<u64>, AnnembedError> {
+        let info = self.get_checked(name, 1)?;
+        if info.dtype != Dtype::U64 {
+            return Err(AnnembedError::InvalidParameter(format!("safetensors, tensor {} is not U64", name)));
+        }
+        let bytes = &self.data[info.data_offsets.0..info.data_offsets.1];
+        Ok(bytes.chunks_exact(8).map(|b| u64::from_le_bytes(b.try_into().unwrap())).collect())
+    }
+} // end of impl SafeTensors
+
+//=====================================================================================
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    fn log_init_test() {
+        let _ = env_logger::builder().is_test(true).try_init();
+    }
+
+    #[test]
+    fn test_safetensors_roundtrip() {
+        log_init_test();
+        //
+        let embedded = Array2::<f64>::from_shape_fn((7, 3), |(i, j)| i as f64 - 0.25 * j as f64);
+        let eigenvalues = [1., 0.75, 0.5];
+        let data_ids: Vec<usize> = (0..7).rev().collect();
+        let mut writer = SafeTensorsWriter::new();
+        writer.add_array2_f32("embedding", &embedded).unwrap();
+        writer.add_array2_f64("embedding_f64", &embedded).unwrap();
+        writer.add_array1_f64("eigenvalues", &eigenvalues).unwrap();
+        writer.add_u64("data_ids", &data_ids).unwrap();
+        writer.add_metadata("method", "test");
+        assert!(writer.add_u64("data_ids", &data_ids).is_err());
+        //
+        let path = std::env::temp_dir().join("annembed_test.safetensors");
+        writer.write(&path).unwrap();
+        // header is aligned
+        let bytes = std::fs::read(&path).unwrap();
+        let header_len = u64::from_le_bytes(bytes[0..8].try_into().unwrap());
+        assert_eq!(header_len % 8, 0);
+        //
+        let reloaded = SafeTensors::load(&path).unwrap();
+        assert_eq!(reloaded.get_names().len(), 4);
+        assert_eq!(reloaded.get_metadata().get("method").unwrap(), "test");
+        assert_eq!(reloaded.get_info("embedding").unwrap().dtype, Dtype::F32);
+        assert_eq!(reloaded.get_array2_f64("embedding").unwrap(), embedded);
+        assert_eq!(reloaded.get_array2_f64("embedding_f64").unwrap(), embedded);
+        assert_eq!(reloaded.get_array1_f64("eigenvalues").unwrap().to_vec(), eigenvalues.to_vec());
+        let ids: Vec<usize> = reloaded.get_u64("data_ids").unwrap().iter().map(|x| *x as usize).collect();
+        assert_eq!(ids, data_ids);
+        assert!(reloaded.get_u64("embedding").is_err());
+        assert!(reloaded.get_array2_f64("eigenvalues").is_err());
+        let _ = std::fs::remove_file(&path);
+    } // end of test_safetensors_roundtrip
+} // end of mod tests
@@ -0,0 +1,439 @@
+//! Reporting of computation stages (insertion, graph build, laplacian, svd, gradient).
+//!
+//! A [Stage] is entered at the beginning of a phase and reports its sizes and timings as structured fields.
+//! With feature *tracing* a stage is a `tracing` span named *annembed* with a field *stage*, and fields
+//! are emitted as events inside the span, so that a subscriber of an embedding service can aggregate them.
+//! Without the feature fields are sent to `log` at info level.
+//!
+//! Stages also report their progress (blocks inserted, chunks extracted, svd iterations, gradient batches) as a [StageProgress]
+//! giving an estimation of remaining time from the throughput measured so far. Progress is logged at debug level
+//! and sent to the callback registered by [set_progress_callback].
+//!
+//! An [EmbeddingObserver] is set on a run, by [Embedder::set_observer](crate::embedder::Embedder::set_observer) or
+//! [DiffusionMaps::set_observer](crate::diffmaps::DiffusionMaps::set_observer). It is notified of progress, of the end of each stage
+//! (*graph* when the kgraph is built, *laplacian*, *svd*, *gradient*) and of the cross entropy along gradient iterations.
+//! Stages are reported to the observer of the run entering them, concurrent runs in other threads have their own observers.  
+//! A [CancellationToken] set on a run in the same way is checked in its gradient iterations and range finders,
+//! a cancelled computation returns [AnnembedError::Cancelled](crate::error::AnnembedError::Cancelled). Other runs go on.  
+//! A [StageProfiler] collects time and peak resident memory of each stage run by the current thread.
+
+use cpu_time::ProcessTime;
+use std::cell::{Cell, RefCell};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::SystemTime;
+
+use crate::tools::report::{get_resident_memory, StageProfile};
+
+/// Progress of a stage : nb_done units of work (blocks, chunks, iterations, batches) over nb_total.
+#[derive(Clone, Debug)]
+pub struct StageProgress {
+    /// stage name, see [Stage::enter]
+    pub stage: &'static str,
+    pub nb_done: usize,
+    /// total number of units, for iterative algorithms with early stop this is an upper bound
+    pub nb_total: usize,
+    /// elapsed sys time in ms since the stage started
+    pub elapsed_ms: u128,
+}
+
+impl StageProgress {
+    /// fraction of work done
+    pub fn get_fraction(&self) -> f64 {
+        if self.nb_total == 0 {
+            return 1.;
+        }
+        self.nb_done as f64 / self.nb_total as f64
+    }
+
+    /// estimated remaining time in ms, from the throughput measured so far. None before any unit is done.
+    pub fn get_remaining_ms(&self) -> Option<f64> {
+        if self.nb_done == 0 {
+            return None;
+        }
+        let remaining = self.nb_total.saturating_sub(self.nb_done) as f64;
+        Some(self.elapsed_ms as f64 * remaining / self.nb_done as f64)
+    }
+} // end of impl StageProgress
+
+type ProgressCallback = Arc<dyn Fn(&StageProgress) + Send + Sync>;
+
+static PROGRESS_CALLBACK: RwLock<Option<ProgressCallback>> = RwLock::new(None);
+
+/// registers the callback receiving progress of all stages (from any thread), replacing the previous one.
+pub fn set_progress_callback<C>(callback: C)
+where
+    C: Fn(&StageProgress) + Send + Sync + 'static,
+{
+    *PROGRESS_CALLBACK.write().unwrap() = Some(Arc::new(callback));
+}
+
+/// removes the progress callback
+pub fn reset_progress_callback() {
+    *PROGRESS_CALLBACK.write().unwrap() = None;
+}
+
+/// Receives notifications of an embedding, from any thread. All methods have a default doing nothing.
+pub trait EmbeddingObserver: Send + Sync {
+    /// progress of a stage, see [StageProgress]
+    fn on_progress(&self, _progress: &StageProgress) {}
+
+    /// stage is done, with its elapsed sys time in ms
+    fn on_stage_done(&self, _stage: &'static str, _sys_ms: u128) {}
+
+    /// cross entropy after gradient iteration epoch over nb_epoch, sent every [epoch_period](Self::epoch_period) iterations
+    fn on_epoch(&self, _epoch: usize, _nb_epoch: usize, _loss: f64) {}
+
+    /// number of gradient iterations between calls to [on_epoch](Self::on_epoch), 0 for no call.
+    /// Each call needs a cross entropy computation, whose cost is about that of a gradient iteration.
+    fn epoch_period(&self) -> usize {
+        10
+    }
+} // end of trait EmbeddingObserver
+
+thread_local! {
+    // observer of the run executed by this thread
+    static OBSERVER: RefCell<Option<Arc<dyn EmbeddingObserver>>> = const { RefCell::new(None) };
+}
+
+/// returns the observer of the run executed by the current thread
+pub(crate) fn get_observer() -> Option<Arc<dyn EmbeddingObserver>> {
+    OBSERVER.with(|o| o.borrow().clone())
+}
+
+// replaces the observer of the current thread (to propagate it in another thread), returns the previous one
+pub(crate) fn set_thread_observer(observer: Option<Arc<dyn EmbeddingObserver>>) -> Option<Arc<dyn EmbeddingObserver>> {
+    OBSERVER.with(|o| std::mem::replace(&mut *o.borrow_mut(), observer))
+}
+
+/// Sets the observer of a run in the current thread while alive, the previous one is restored on drop.
+/// [install_in_pool](crate::tools::threads::install_in_pool) propagates it to the pool of the run.
+pub(crate) struct ObserverScope {
+    previous: Option<Arc<dyn EmbeddingObserver>>,
+}
+
+impl ObserverScope {
+    pub(crate) fn enter(observer: Option<Arc<dyn EmbeddingObserver>>) -> Self {
+        ObserverScope {
+            previous: set_thread_observer(observer),
+        }
+    }
+}
+
+impl Drop for ObserverScope {
+    fn drop(&mut self) {
+        set_thread_observer(self.previous.take());
+    }
+}
+
+/// A flag shared between the caller and the computation it was set on.
+#[derive(Clone, Debug, Default)]
+pub struct CancellationToken {
+    cancelled: Arc<AtomicBool>,
+}
+
+impl CancellationToken {
+    pub fn new() -> Self {
+        CancellationToken::default()
+    }
+
+    /// asks running computations to stop
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Relaxed)
+    }
+} // end of impl CancellationToken
+
+/// true if token is set and has been cancelled
+pub(crate) fn is_cancelled(token: Option<&CancellationToken>) -> bool {
+    token.is_some_and(|t| t.is_cancelled())
+}
+
+type ProfileSink = Arc<Mutex<Vec<StageProfile>>>;
+
+thread_local! {
+    // sinks of the profilers alive in this thread, innermost last
+    static PROFILERS: RefCell<Vec<ProfileSink>> = const { RefCell::new(Vec::new()) };
+}
+
+// returns the sinks of the profilers of the current thread
+pub(crate) fn get_thread_profilers() -> Vec<ProfileSink> {
+    PROFILERS.with(|p| p.borrow().clone())
+}
+
+// replaces the sinks of the current thread (to propagate them in another thread), returns the previous ones
+pub(crate) fn set_thread_profilers(sinks: Vec<ProfileSink>) -> Vec<ProfileSink> {
+    PROFILERS.with(|p| std::mem::replace(&mut *p.borrow_mut(), sinks))
+}
+
+/// Collects a [StageProfile] for each stage run by the current thread while the profiler is alive.  
+/// Embeddings running in a pool of their own (see [install_in_pool](crate::tools::threads::install_in_pool))
+/// report to the profilers of the calling thread. Profilers can be nested, a stage is reported to all of them.
+pub struct StageProfiler {
+    sink: ProfileSink,
+}
+
+impl StageProfiler {
+    pub fn start() -> Self {
+        let sink = ProfileSink::default();
+        PROFILERS.with(|p| p.borrow_mut().push(sink.clone()));
+        StageProfiler { sink }
+    }
+
+    /// returns the profiles of stages done so far, in order of completion
+    pub fn get_profiles(&self) -> Vec<StageProfile> {
+        self.sink.lock().unwrap().clone()
+    }
+} // end of impl StageProfiler
+
+impl Drop for StageProfiler {
+    fn drop(&mut self) {
+        PROFILERS.with(|p| p.borrow_mut().retain(|sink| !Arc::ptr_eq(sink, &self.sink)));
+    }
+}
+
+/// Measures progress of a stage from its creation, for code that does not hold the [Stage] (svd iterations for example).
+pub struct ProgressMeter {
+    stage: &'static str,
+    start: SystemTime,
+}
+
+impl ProgressMeter {
+    pub fn new(stage: &'static str) -> Self {
+        ProgressMeter {
+            stage,
+            start: SystemTime::now(),
+        }
+    }
+
+    /// reports nb_done units over nb_total to the registered callback
+    pub fn report(&self, nb_done: usize, nb_total: usize) {
+        let progress = StageProgress {
+            stage: self.stage,
+            nb_done,
+            nb_total,
+            elapsed_ms: self.start.elapsed().map(|d| d.as_millis()).unwrap_or(0),
+        };
+        log::debug!(
+            "{:?}, remaining ms : {:?}",
+            progress,
+            progress.get_remaining_ms()
+        );
+        // clone so that the callback runs without the lock
+        let callback = PROGRESS_CALLBACK.read().unwrap().clone();
+        if let Some(callback) = callback {
+            callback(&progress);
+        }
+        if let Some(observer) = get_observer() {
+            observer.on_progress(&progress);
+        }
+    }
+} // end of impl ProgressMeter
+
+/// A running computation stage. Exiting (dropping) the stage reports sys and cpu time in ms.
+pub struct Stage {
+    name: &'static str,
+    sys_start: SystemTime,
+    cpu_start: ProcessTime,
+    meter: ProgressMeter,
+    // profilers of the thread entering the stage and resident memory sampled for them
+    profilers: Vec<ProfileSink>,
+    peak_rss: Cell<Option<u64>>,
+    #[cfg(feature = "tracing")]
+    _span: tracing::span::EnteredSpan,
+}
+
+impl Stage {
+    /// enters stage name. Stage names used in the crate are *insertion*, *graph*, *laplacian*, *svd* and *gradient*
+    pub fn enter(name: &'static str) -> Self {
+        #[cfg(feature = "tracing")]
+        let _span = tracing::info_span!("annembed", stage = name).entered();
+        let stage = Stage {
+            name,
+            sys_start: SystemTime::now(),
+            cpu_start: ProcessTime::now(),
+            meter: ProgressMeter::new(name),
+            profilers: get_thread_profilers(),
+            peak_rss: Cell::new(None),
+            #[cfg(feature = "tracing")]
+            _span,
+        };
+        stage.sample_memory();
+        stage
+    } // end of enter
+
+    // updates the peak of resident memory, only if the stage is profiled as sampling has a cost
+    fn sample_memory(&self) {
+        if self.profilers.is_empty() {
+            return;
+        }
+        if let Some(rss) = get_resident_memory() {
+            self.peak_rss.set(Some(self.peak_rss.get().map_or(rss, |peak| peak.max(rss))));
+        }
+    }
+
+    /// returns stage name
+    pub fn get_name(&self) -> &'static str {
+        self.name
+    }
+
+    /// reports a structured field of the stage
+    pub fn record<T: Into<f64>>(&self, field: &'static str, value: T) {
+        let value: f64 = value.into();
+        #[cfg(feature = "tracing")]
+        tracing::info!(stage = self.name, field, value);
+        #[cfg(not(feature = "tracing"))]
+        log::info!("stage {} {} : {:.5e}", self.name, field, value);
+    } // end of record
+
+    /// reports a size (number of points, edges ...)
+    pub fn record_size(&self, field: &'static str, value: usize) {
+        self.record(field, value as f64);
+    }
+
+    /// reports progress of the stage, see [StageProgress]
+    pub fn report_progress(&self, nb_done: usize, nb_total: usize) {
+        self.meter.report(nb_done, nb_total);
+        self.sample_memory();
+    }
+
+    /// elapsed sys time in ms since stage entry
+    pub fn get_sys_ms(&self) -> u128 {
+        self.sys_start.elapsed().map(|d| d.as_millis()).unwrap_or(0)
+    }
+
+    /// elapsed cpu time in ms since stage entry
+    pub fn get_cpu_ms(&self) -> u128 {
+        self.cpu_start.elapsed().as_millis()
+    }
+} // end of impl Stage
+
+impl Drop for Stage {
+    fn drop(&mut self) {
+        let (sys_ms, cpu_ms) = (self.get_sys_ms(), self.get_cpu_ms());
+        #[cfg(feature = "tracing")]
+        tracing::info!(
+            stage = self.name,
+            sys_ms = sys_ms as u64,
+            cpu_ms = cpu_ms as u64,
+            "stage done"
+        );
+        #[cfg(not(feature = "tracing"))]
+        log::info!(
+            "stage {} done, sys time(ms) {} cpu time(ms) {}",
+            self.name,
+            sys_ms,
+            cpu_ms
+        );
+        if let Some(observer) = get_observer() {
+            observer.on_stage_done(self.name, sys_ms);
+        }
+        if !self.profilers.is_empty() {
+            self.sample_memory();
+            let profile = StageProfile {
+                name: String::from(self.name),
+                sys_ms: sys_ms as u64,
+                cpu_ms: cpu_ms as u64,
+                peak_rss_bytes: self.peak_rss.get(),
+            };
+            for sink in &self.profilers {
+                sink.lock().unwrap().push(profile.clone());
+            }
+        }
+    }
+} // end of impl Drop for Stage
+
+//=======================================================================
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_stage_progress() {
+        let progress = StageProgress {
+            stage: "gradient",
+            nb_done: 5,
+            nb_total: 20,
+            elapsed_ms: 1000,
+        };
+        assert_eq!(progress.get_fraction(), 0.25);
+        assert_eq!(progress.get_remaining_ms(), Some(3000.));
+        // callback receives reports of stages
+        let received = Arc::new(std::sync::Mutex::new(Vec::<(usize, usize)>::new()));
+        let received_cb = received.clone();
+        set_progress_callback(move |p| {
+            if p.stage == "test_stage" {
+                received_cb.lock().unwrap().push((p.nb_done, p.nb_total));
+            }
+        });
+        let stage = Stage::enter("test_stage");
+        stage.report_progress(1, 2);
+        stage.report_progress(2, 2);
+        reset_progress_callback();
+        stage.report_progress(2, 2);
+        assert_eq!(*received.lock().unwrap(), vec![(1, 2), (2, 2)]);
+    } // end of test_stage_progress
+
+    #[test]
+    fn test_observer_cancellation() {
+        struct Recorder(Arc<std::sync::Mutex<Vec<&'static str>>>);
+        impl EmbeddingObserver for Recorder {
+            fn on_stage_done(&self, stage: &'static str, _sys_ms: u128) {
+                self.0.lock().unwrap().push(stage);
+            }
+        }
+        let done = Arc::new(std::sync::Mutex::new(Vec::<&'static str>::new()));
+        {
+            let _scope = ObserverScope::enter(Some(Arc::new(Recorder(done.clone()))));
+            drop(Stage::enter("test_observer"));
+            // stages of other threads are not observed
+            std::thread::spawn(|| drop(Stage::enter("test_observer"))).join().unwrap();
+            // propagation to a pool
+            crate::tools::threads::install_in_pool(Some(2), || drop(Stage::enter("test_observer"))).unwrap();
+        }
+        drop(Stage::enter("test_observer"));
+        assert_eq!(*done.lock().unwrap(), vec!["test_observer", "test_observer"]);
+        assert!(get_observer().is_none());
+        // clones share the flag
+        let token = CancellationToken::new();
+        assert!(!is_cancelled(Some(&token)));
+        token.clone().cancel();
+        assert!(token.is_cancelled());
+        assert!(is_cancelled(Some(&token)));
+        assert!(!is_cancelled(None));
+    } // end of test_observer_cancellation
+
+    #[test]
+    fn test_stage_profiler() {
+        let outer = StageProfiler::start();
+        drop(Stage::enter("graph"));
+        {
+            let inner = StageProfiler::start();
+            let stage = Stage::enter("svd");
+            stage.report_progress(1, 1);
+            drop(stage);
+            let profiles = inner.get_profiles();
+            assert_eq!(profiles.len(), 1);
+            assert_eq!(profiles[0].name, "svd");
+        }
+        // stages of other threads are not collected
+        std::thread::spawn(|| drop(Stage::enter("gradient"))).join().unwrap();
+        // propagation to another thread
+        let sinks = get_thread_profilers();
+        std::thread::spawn(move || {
+            set_thread_profilers(sinks);
+            drop(Stage::enter("laplacian"));
+        })
+        .join()
+        .unwrap();
+        let names: Vec<String> = outer.get_profiles().into_iter().map(|p| p.name).collect();
+        assert_eq!(names, vec!["graph", "svd", "laplacian"]);
+        drop(outer);
+        assert!(get_thread_profilers().is_empty());
+    } // end of test_stage_profiler
+} // end of mod tests
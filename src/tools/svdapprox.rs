@@ -29,11 +29,14 @@
 // ndarray_linalg::Scalar provides Exp notation + Display + Debug + Serialize and sum on iterators
 
 use rand_distr::{Distribution, StandardNormal};
+
+use crate::error::AnnembedError;
+use crate::tools::stage::{is_cancelled, CancellationToken, ProgressMeter};
 use rand_xoshiro::rand_core::SeedableRng;
 use rand_xoshiro::Xoshiro256PlusPlus;
 
 use ndarray::{
-    Array, Array1, Array2, ArrayBase, ArrayView, ArrayView1, ArrayView2, ArrayViewMut1, Dim,
+    s, Array, Array1, Array2, ArrayBase, ArrayView, ArrayView1, ArrayView2, ArrayViewMut1, Axis, Dim,
     Dimension, Ix1, Ix2,
 };
 
@@ -48,10 +51,13 @@ use num_traits::cast::FromPrimitive;
 use num_traits::float::*; // tp get FRAC_1_PI from FloatConst
 
 use parking_lot::RwLock;
-use rayon::iter::{IntoParallelIterator, ParallelIterator};
+use rayon::iter::{IndexedParallelIterator, IntoParallelIterator, ParallelIterator};
 
 use sprs::{prod, CsMat, CsMatView, TriMat};
 
+/// default seed of random gaussian matrices used in range approximations
+pub const DEFAULT_SVD_SEED: u64 = 4664397;
+
 struct RandomGaussianMatrix<F: Float> {
     mat: Array2<F>,
 }
@@ -61,11 +67,10 @@ where
     F: Float + FromPrimitive,
 {
     /// given dimensions allocate and initialize with random gaussian values matrix
-    pub fn new(dims: Ix2) -> Self {
-        let mut rng = Xoshiro256PlusPlus::seed_from_u64(4664397);
+    pub fn new(dims: Ix2, rng: &mut Xoshiro256PlusPlus) -> Self {
         let stdnormal = StandardNormal {};
         let mat: Array2<F> =
-            ArrayBase::from_shape_fn(dims, |_| F::from_f64(stdnormal.sample(&mut rng)).unwrap());
+            ArrayBase::from_shape_fn(dims, |_| F::from_f64(stdnormal.sample(rng)).unwrap());
         //
         RandomGaussianMatrix { mat }
     }
@@ -77,8 +82,8 @@ struct RandomGaussianGenerator<F> {
 }
 
 impl<F: Float + FromPrimitive> RandomGaussianGenerator<F> {
-    pub fn new() -> Self {
-        let rng = Xoshiro256PlusPlus::seed_from_u64(4664397);
+    pub fn new_with_seed(seed: u64) -> Self {
+        let rng = Xoshiro256PlusPlus::seed_from_u64(seed);
         RandomGaussianGenerator::<F> {
             rng,
             _ty: PhantomData,
@@ -86,7 +91,7 @@ impl<F: Float + FromPrimitive> RandomGaussianGenerator<F> {
     }
 
     pub fn generate_matrix(&mut self, dims: Ix2) -> RandomGaussianMatrix<F> {
-        RandomGaussianMatrix::<F>::new(dims)
+        RandomGaussianMatrix::<F>::new(dims, &mut self.rng)
     }
 
     // generate a standard N(0,1) vector of N(0,1) of dimension dim
@@ -167,6 +172,14 @@ where
         };
     } // end of shape
 
+    /// returns true if all entries (stored entries in csr mode) are finite
+    pub fn is_finite(&self) -> bool {
+        match &self.data {
+            MatMode::FULL(mat) => mat.iter().all(|x| x.is_finite()),
+            MatMode::CSR(csmat) => csmat.data().iter().all(|x| x.is_finite()),
+        }
+    } // end of is_finite
+
     /// returns true if we have a row compressed representation
     pub fn is_csr(&self) -> bool {
         match &self.data {
@@ -176,24 +189,24 @@ where
     } // end of is_csr
 
     /// returns a mutable reference to full matrice if data is given as full matrix, an Error otherwise
-    pub fn get_full_mut(&mut self) -> Result<&mut Array2<F>, usize> {
+    pub fn get_full_mut(&mut self) -> Result<&mut Array2<F>, AnnembedError> {
         match &mut self.data {
             MatMode::FULL(mat) => {
                 return Ok(mat);
             }
             _ => {
-                return Err(1);
+                return Err(AnnembedError::MatrixRepresentation("full"));
             }
         };
     } // end of get_full_mut
 
-    pub fn get_csr(&self) -> Result<&CsMat<F>, usize> {
+    pub fn get_csr(&self) -> Result<&CsMat<F>, AnnembedError> {
         match &self.data {
             MatMode::CSR(mat) => {
                 return Ok(mat);
             }
             _ => {
-                return Err(1);
+                return Err(AnnembedError::MatrixRepresentation("csr"));
             }
         };
     } // end of get_csr
@@ -224,6 +237,29 @@ where
         };
     } // end of matDotVector
 
+    /// Matrix by dense matrix multiplication (BLAS3 in full mode). In csr mode rows of the result are computed in parallel.
+    pub fn mat_dot_dense(&self, x: &ArrayView2<F>) -> Array2<F>
+    where
+        F: Send,
+    {
+        match &self.data {
+            MatMode::FULL(mat) => mat.dot(x),
+            MatMode::CSR(csmat) => {
+                assert_eq!(csmat.cols(), x.nrows());
+                let mut res = Array2::<F>::zeros((csmat.rows(), x.ncols()));
+                res.axis_iter_mut(Axis(0))
+                    .into_par_iter()
+                    .enumerate()
+                    .for_each(|(i, mut row)| {
+                        for (j, val) in csmat.outer_view(i).unwrap().iter() {
+                            row.scaled_add(*val, &x.row(j));
+                        }
+                    });
+                res
+            }
+        }
+    } // end of mat_dot_dense
+
     /// just multiplication by beta in a unified way
     pub fn scale(&mut self, beta: F) {
         match &mut self.data {
@@ -364,6 +400,10 @@ pub struct RangeApprox<'a, F: Scalar> {
     mat: &'a MatRepr<F>,
     /// mode of approximation asked for.
     mode: RangeApproxMode,
+    /// seed of the random gaussian matrices
+    seed: u64,
+    /// token stopping the range finder
+    cancellation: Option<CancellationToken>,
 } // end of struct RangeApprox
 
 /// Lapack is necessary here beccause of QR_ traits coming from Lapack
@@ -382,28 +422,48 @@ where
 {
     /// describes the problem, matrix format and range approximation mode asked for.
     pub fn new(mat: &'a MatRepr<F>, mode: RangeApproxMode) -> Self {
-        RangeApprox { mat, mode }
+        RangeApprox {
+            mat,
+            mode,
+            seed: DEFAULT_SVD_SEED,
+            cancellation: None,
+        }
+    }
+
+    /// sets the seed of random gaussian matrices. Defaults to [DEFAULT_SVD_SEED]
+    pub fn set_seed(&mut self, seed: u64) {
+        self.seed = seed;
+    }
+
+    /// sets a token stopping the range finder when cancelled, see [get_approximator](Self::get_approximator)
+    pub fn set_cancellation_token(&mut self, token: &CancellationToken) {
+        self.cancellation = Some(token.clone());
     }
 
     /// This function returns an orthonormal matrix Q such that either  || (I - Q * Qt) * A || < epsil.
     /// or a fixed rank orthonormal Q such that || (I - Q * Qt) * A || small enough if asked rank is sufficiently large.
-    /// Depending on mode, an adaptative algorithm or the fixed rang QR iterations will be called
-    /// For CsMat matrice only the RangeApproxMode::EPSIL is possible (as we need QR decomposition for Sparse Mat from sprs...),
-    /// in the other case the function will return None..
-    pub fn get_approximator(&self) -> Option<Array2<F>> {
+    /// Depending on mode, an adaptative algorithm or the fixed rang QR iterations will be called.  
+    /// Both modes are available for full and CsMat matrices, in RANK mode CsMat matrices go to [subspace_iteration_csr]
+    /// which only needs sparse by dense products and QR of dense (m,rank) matrices.  
+    /// Returns [AnnembedError::Cancelled] if the computation was cancelled, see [set_cancellation_token](Self::set_cancellation_token),
+    /// and an error if a QR decomposition failed.
+    pub fn get_approximator(&self) -> Result<Array2<F>, AnnembedError> {
+        let cancellation = self.cancellation.as_ref();
         let approximator = match self.mode {
-            RangeApproxMode::EPSIL(precision) => adaptative_range_finder_matrep(
+            RangeApproxMode::EPSIL(precision) => block_range_finder_matrep(
                 self.mat,
                 precision.epsil,
                 precision.step,
                 precision.max_rank,
+                self.seed,
+                cancellation,
             ),
             RangeApproxMode::RANK(rank) => {
                 match &self.mat.data {
-                    MatMode::FULL(array) => subspace_iteration_full(&array, rank.rank, rank.nbiter),
+                    MatMode::FULL(array) => subspace_iteration_full(&array, rank.rank, rank.nbiter, self.seed, cancellation)?,
 
                     MatMode::CSR(csr_mat) => {
-                        subspace_iteration_csr(&csr_mat, rank.rank, rank.nbiter)
+                        subspace_iteration_csr(&csr_mat, rank.rank, rank.nbiter, self.seed, cancellation)?
                     }
                 } // end of match on representation
             }
@@ -419,8 +479,12 @@ where
                 delta
             );
         }
+        // a range finder stopped by cancellation returns an incomplete approximation
+        if is_cancelled(cancellation) {
+            return Err(AnnembedError::Cancelled);
+        }
         //
-        Some(approximator)
+        Ok(approximator)
     } // end of get_approximator
 } // end of impl RangeApprox
 
@@ -435,12 +499,18 @@ where
 ///
 // TODO Oversampling between 5 and 10 ?
 // Nota : if nbiter == 0 We get Tropp Algo 4.1 or Algo 2.1 of Wei-Zhang-Chen
-pub fn subspace_iteration_full<F>(mat: &Array2<F>, rank: usize, nbiter: usize) -> Array2<F>
+pub fn subspace_iteration_full<F>(
+    mat: &Array2<F>,
+    rank: usize,
+    nbiter: usize,
+    seed: u64,
+    cancellation: Option<&CancellationToken>,
+) -> Result<Array2<F>, AnnembedError>
 where
     F: Send + Sync + Float + Scalar + Lapack + ndarray::ScalarOperand,
 {
     //
-    let mut rng = RandomGaussianGenerator::<F>::new();
+    let mut rng = RandomGaussianGenerator::<F>::new_with_seed(seed);
     let data_shape = mat.shape();
     let m = data_shape[0];
     let n = data_shape[1];
@@ -457,8 +527,13 @@ where
         lda: l as i32,
     };
     // do first QR decomposition of y and overwrite it
-    do_qr(layout, &mut y_m_l);
+    do_qr(layout, &mut y_m_l)?;
+    let meter = ProgressMeter::new("svd");
     for j in 1..nbiter {
+        // caller detects cancellation, see direct_svd
+        if is_cancelled(cancellation) {
+            break;
+        }
         log::debug!("svdapprox::subspace_iteration_full iter : {}", j);
         // data.t() * y
         ndarray::linalg::general_mat_mul(F::one(), &mat.t(), &y_m_l, F::zero(), &mut y_n_l);
@@ -469,7 +544,7 @@ where
                 lda: y_n_l.shape()[1] as i32,
             },
             &mut y_n_l,
-        );
+        )?;
         // data * y_n_l  -> (m,l)    (m,n)*(n,l) = (m,l)    y_m_l = mat.dot(&mut y_n_l)
         ndarray::linalg::general_mat_mul(F::one(), &mat, &y_n_l, F::zero(), &mut y_m_l);
         // qr of y * data
@@ -479,10 +554,11 @@ where
                 lda: y_m_l.shape()[1] as i32,
             },
             &mut y_m_l,
-        );
+        )?;
+        meter.report(j, nbiter - 1);
     }
     //
-    y_m_l
+    Ok(y_m_l)
 } // end of subspace_iteration_full
 
 ///
@@ -493,7 +569,13 @@ where
 ///
 /// It implements the QR iterations as descibed in Algorithm 4.4 from Halko-Tropp
 ///
-pub fn subspace_iteration_csr<F>(csrmat: &CsMat<F>, rank: usize, nbiter: usize) -> Array2<F>
+pub fn subspace_iteration_csr<F>(
+    csrmat: &CsMat<F>,
+    rank: usize,
+    nbiter: usize,
+    seed: u64,
+    cancellation: Option<&CancellationToken>,
+) -> Result<Array2<F>, AnnembedError>
 where
     F: Send + Sync + Float + Scalar + Lapack + ndarray::ScalarOperand + sprs::MulAcc,
 {
@@ -504,7 +586,7 @@ where
         nbiter
     );
     //
-    let mut rng = RandomGaussianGenerator::<F>::new();
+    let mut rng = RandomGaussianGenerator::<F>::new_with_seed(seed);
     let data_shape = csrmat.shape();
     let m = data_shape.0;
     let n = data_shape.1;
@@ -524,8 +606,12 @@ where
         lda: l as i32,
     };
     // do first QR decomposition of y and overwrite it
-    do_qr(layout, &mut y_m_l);
+    do_qr(layout, &mut y_m_l)?;
+    let meter = ProgressMeter::new("svd");
     for j in 1..nbiter {
+        if is_cancelled(cancellation) {
+            break;
+        }
         log::debug!("svdapprox::subspace_iteration_csr iter : {}", j);
         // data.t() * y
         y_n_l.fill(F::zero());
@@ -537,7 +623,7 @@ where
                 lda: y_n_l.shape()[1] as i32,
             },
             &mut y_n_l,
-        );
+        )?;
         // data * y_n_l  -> (m,l)
         y_m_l.fill(F::zero());
         prod::csr_mulacc_dense_rowmaj(csrmat.view(), y_n_l.view(), y_m_l.view_mut());
@@ -548,10 +634,11 @@ where
                 lda: y_m_l.shape()[1] as i32,
             },
             &mut y_m_l,
-        );
+        )?;
+        meter.report(j, nbiter - 1);
     }
     //
-    y_m_l
+    Ok(y_m_l)
 } // end of subspace_iteration_matrepr
 
 // 1. we sample y vectors by batches of size r,
@@ -593,7 +680,9 @@ pub fn adaptative_range_finder_matrep<F>(
     epsil: f64,
     r: usize,
     max_rank: usize,
-) -> Array2<F>
+    seed: u64,
+    cancellation: Option<&CancellationToken>,
+) -> Result<Array2<F>, AnnembedError>
 where
     F: Float
         + Scalar
@@ -615,7 +704,7 @@ where
         max_rank
     );
     //
-    let mut rng = RandomGaussianGenerator::new();
+    let mut rng = RandomGaussianGenerator::new_with_seed(seed);
     let data_shape = mat.shape();
     let m = data_shape[0]; // nb rows
 
@@ -655,19 +744,24 @@ where
     log::debug!(" norms_y : {:.3e}", norms_y);
     //
     let mut norm_sup_y;
-    let norm_iter_res = norms_y.iter().max_by(|x, y| x.partial_cmp(y).unwrap());
-    if norm_iter_res.is_none() {
+    if norms_y.iter().any(|x| !x.is_finite()) {
         log::error!("svdapprox::adaptative_range_finder_matrep cannot sort norms");
         log::error!(" norms_y : {:.3e}", norms_y);
-        std::panic!("adaptative_range_finder_matrep sorting norms failed, most probably some Nan");
+        return Err(AnnembedError::NonFinite("norms of range finder vectors"));
+    }
+    let norm_iter_res = norms_y.iter().max_by(|x, y| x.partial_cmp(y).unwrap_or(std::cmp::Ordering::Equal));
+    if norm_iter_res.is_none() {
+        log::error!("svdapprox::adaptative_range_finder_matrep needs r > 0");
+        return Err(AnnembedError::InvalidParameter(String::from("adaptative_range_finder_matrep needs r > 0")));
     }
     norm_sup_y = norm_iter_res.unwrap();
     let mut j = 0;
     let mut nb_iter = 0;
     let max_iter = data_shape[0].min(data_shape[1]);
+    let meter = ProgressMeter::new("svd");
     let stop_val = *norm_sup_y * F::from_f64(stop_val).unwrap();
     //
-    while norm_sup_y > &stop_val && nb_iter <= max_iter && q_mat.len() < max_rank {
+    while norm_sup_y > &stop_val && nb_iter <= max_iter && q_mat.len() < max_rank && !is_cancelled(cancellation) {
         // numerical stabilization
         if q_mat.len() > 0 {
             orthogonalize_with_q(&q_mat[0..q_mat.len()], &mut y_vec[j].write().view_mut());
@@ -704,7 +798,7 @@ where
         }
         norm_sup_y = norms_y
             .iter()
-            .max_by(|x, y| x.partial_cmp(y).unwrap())
+            .max_by(|x, y| x.partial_cmp(y).unwrap_or(std::cmp::Ordering::Equal))
             .unwrap();
         if log::log_enabled!(log::Level::Debug) {
             if nb_iter % (max_rank / 10).max(1) == 0 {
@@ -721,6 +815,10 @@ where
         // we update j and nb_iter
         j = (j + 1) % r;
         nb_iter += 1;
+        // rank reached is bounded by max_rank, so remaining time is an upper bound
+        if q_mat.len() % (max_rank / 100).max(1) == 0 {
+            meter.report(q_mat.len(), max_rank);
+        }
     }
     log::debug!(
         "adaptative_range_finder_matrep exit iteration {}, norm sup {:.3e} ",
@@ -739,9 +837,112 @@ where
     }
     log::debug!("\n exiting adaptative_range_finder_matrep");
     // we return an array2 where each row is a data of reduced dimension
-    unsafe { q_as_array2.assume_init() }
+    Ok(unsafe { q_as_array2.assume_init() })
 } // end of adaptative_range_finder_csmat
 
+#[cfg_attr(doc, katexit::katexit)]
+///
+/// Blocked version of [adaptative_range_finder_matrep] (see Martinsson-Voronin A randomized blocked algorithm
+/// for efficiently computing rank-revealing factorizations 2016).  
+/// Each iteration samples a block of r gaussian vectors, computes their image by mat with one matrix-matrix product,
+/// orthogonalizes the block against Q by block Gram-Schmidt (done twice for stability), orthonormalizes it and appends it to Q.
+/// Vectors of the block that vanish in the orthogonalization (rank deficiency) are dropped.
+/// Iterations stop when the largest norm of the projected block, which bounds
+/// $ || mat - Q*Q^{t}*mat || $ with high probability, is under epsil relative to the first block or when max_rank is reached.  
+/// It returns a (m,l) orthonormal matrix Q with l <= max_rank.
+pub fn block_range_finder_matrep<F>(
+    mat: &MatRepr<F>,
+    epsil: f64,
+    r: usize,
+    max_rank: usize,
+    seed: u64,
+    cancellation: Option<&CancellationToken>,
+) -> Array2<F>
+where
+    F: Float
+        + Scalar
+        + Lapack
+        + ndarray::ScalarOperand
+        + sprs::MulAcc
+        + Sync
+        + Send
+        + num_traits::MulAdd
+        + for<'r> std::ops::MulAssign<&'r F>
+        + Default,
+{
+    log::debug!(
+        "in block_range_finder_matrep, mat shape {:?}, epsil {:.3e}, r : {} , max_rank {}",
+        mat.shape(),
+        epsil,
+        r,
+        max_rank
+    );
+    let mut rng = RandomGaussianGenerator::<F>::new_with_seed(seed);
+    let [m, n] = mat.shape();
+    let r = r.max(1);
+    let max_rank = max_rank.min(m).min(n);
+    let coeff_norm = F::from(1. / (n as f64).sqrt()).unwrap();
+    // same stopping criteria as adaptative_range_finder_matrep
+    let stop_coeff = F::from(epsil / (10. * (2. / f64::FRAC_1_PI()).sqrt())).unwrap();
+    let mut stop_val = None;
+    let sqrt_eps = ndarray_linalg::Scalar::sqrt(F::epsilon());
+    let mut q_mat = Array2::<F>::zeros((m, max_rank));
+    let mut rank = 0;
+    let meter = ProgressMeter::new("svd");
+    while rank < max_rank && !is_cancelled(cancellation) {
+        // the generator state goes on so that successive blocks are independant
+        let mut omega = rng.generate_stdn_vect(Ix1(n * r)).into_shape((n, r)).unwrap();
+        omega *= coeff_norm;
+        let mut y = mat.mat_dot_dense(&omega.view());
+        let column_norms = |y: &Array2<F>| -> Vec<F> { y.columns().into_iter().map(|c| norm_frobenius_full(&c)).collect() };
+        let initial_norms = column_norms(&y);
+        if rank > 0 {
+            let q = q_mat.slice(s![.., ..rank]);
+            for _ in 0..2 {
+                let proj = q.dot(&q.t().dot(&y));
+                y -= &proj;
+            }
+        }
+        let norm_sup = column_norms(&y).into_iter().fold(F::zero(), |acc, x| acc.max(x));
+        let stop = *stop_val.get_or_insert(norm_sup * stop_coeff);
+        log::debug!("block_range_finder_matrep rank {} norm sup {:.3e}", rank, norm_sup);
+        if norm_sup <= stop || norm_sup < sqrt_eps {
+            break;
+        }
+        // orthonormalization inside the block, a vector is dropped if it is negligible or mostly in the span
+        // of previous ones (orthogonality would be lost)
+        let nb_new = r.min(max_rank - rank);
+        let mut block = Vec::<Array1<F>>::with_capacity(nb_new);
+        for (j, column) in y.columns().into_iter().enumerate() {
+            if block.len() == nb_new {
+                break;
+            }
+            let mut c = column.to_owned();
+            for _ in 0..2 {
+                for q in &block {
+                    let proj = q.dot(&c);
+                    c.scaled_add(-proj, q);
+                }
+            }
+            let norm_c = norm_frobenius_full(&c.view());
+            if norm_c <= stop || norm_c < sqrt_eps * initial_norms[j] {
+                continue;
+            }
+            block.push(c / norm_c);
+        }
+        if block.is_empty() {
+            break;
+        }
+        for q in &block {
+            q_mat.column_mut(rank).assign(q);
+            rank += 1;
+        }
+        meter.report(rank, max_rank);
+    }
+    log::debug!("block_range_finder_matrep returning a matrix ({}, {})", m, rank);
+    q_mat.slice(s![.., ..rank]).to_owned()
+} // end of block_range_finder_matrep
+
 /// just to check a range approximation, we estimate largest singular values
 pub fn check_range_approx<F>(a_mat: &ArrayView2<F>, q_mat: &ArrayView2<F>) -> f64
 where
@@ -826,6 +1027,88 @@ impl<F> SvdResult<F> {
     }
 } // end of impl SvdResult
 
+impl<F> SvdResult<F>
+where
+    F: Float + Scalar + ndarray::ScalarOperand,
+{
+    /// returns the largest absolute value of the coefficients of Ut * U - I, None if U was not computed.  
+    /// Should be of the order of epsilon of type F for a converged svd.
+    pub fn get_u_orthonormality_error(&self) -> Option<f64> {
+        let u = self.u.as_ref()?;
+        let gram = u.t().dot(u);
+        let mut max_error = 0f64;
+        for ((i, j), v) in gram.indexed_iter() {
+            let expected = if i == j { F::one() } else { F::zero() };
+            max_error = max_error.max(num_traits::Float::abs(*v - expected).to_f64().unwrap());
+        }
+        Some(max_error)
+    } // end of get_u_orthonormality_error
+
+    /// returns true if U is computed and columns of U are orthonormal up to epsil
+    pub fn check_u_orthonormality(&self, epsil: f64) -> bool {
+        match self.get_u_orthonormality_error() {
+            Some(error) => {
+                log::debug!("SvdResult orthonormality error : {:.3e}", error);
+                error <= epsil
+            }
+            None => false,
+        }
+    } // end of check_u_orthonormality
+
+    /// keeps only the first k singular values and corresponding singular vectors.
+    /// Does nothing if k is greater than the computed rank.
+    pub fn truncate(&mut self, k: usize) {
+        if let Some(s) = self.s.as_mut() {
+            if k < s.len() {
+                *s = s.slice(ndarray::s![..k]).to_owned();
+            }
+        }
+        if let Some(u) = self.u.as_mut() {
+            if k < u.ncols() {
+                *u = u.slice(ndarray::s![.., ..k]).to_owned();
+            }
+        }
+        if let Some(vt) = self.vt.as_mut() {
+            if k < vt.nrows() {
+                *vt = vt.slice(ndarray::s![..k, ..]).to_owned();
+            }
+        }
+    } // end of truncate
+
+    /// returns for each computed singular value the ratio $ \sigma_{i}^2 / \sum_{j} \sigma_{j}^2 $.  
+    /// The sum runs on computed singular values only, so for a truncated svd the ratios are relative to the captured variance.
+    pub fn get_explained_variance_ratio(&self) -> Option<Array1<F>> {
+        let s = self.s.as_ref()?;
+        let total: F = s.iter().map(|x| *x * *x).sum();
+        if total <= F::zero() {
+            return None;
+        }
+        Some(s.mapv(|x| x * x / total))
+    } // end of get_explained_variance_ratio
+
+    /// projects a new data vector (of dimension n, the number of columns of the decomposed matrix) on the right singular vectors.  
+    /// The result has dimension the rank of the svd and is comparable to rows of U * S. Returns None if Vt was not computed
+    /// or dimensions do not match.
+    pub fn project(&self, v: &ArrayView1<F>) -> Option<Array1<F>> {
+        let vt = self.vt.as_ref()?;
+        if vt.ncols() != v.len() {
+            log::error!("SvdResult::project dimension mismatch, vt has {} columns, vector has dim {}", vt.ncols(), v.len());
+            return None;
+        }
+        Some(vt.dot(v))
+    } // end of project
+
+    /// projects rows of a (p,n) matrix, returns a (p, rank) matrix. See [project](Self::project).
+    pub fn project_rows(&self, mat: &ArrayView2<F>) -> Option<Array2<F>> {
+        let vt = self.vt.as_ref()?;
+        if vt.ncols() != mat.ncols() {
+            log::error!("SvdResult::project_rows dimension mismatch, vt has {} columns, data has dim {}", vt.ncols(), mat.ncols());
+            return None;
+        }
+        Some(mat.dot(&vt.t()))
+    } // end of project_rows
+} // end of impl SvdResult utilities
+
 /// Approximated svd.
 /// The first step is to find a range approximation of the matrix.
 /// This step can be done by asking for a required precision or a minimum rank for dense matrices represented by Array2
@@ -833,6 +1116,10 @@ impl<F> SvdResult<F> {
 pub struct SvdApprox<'a, F: Scalar> {
     /// matrix we want to approximate range of.
     data: &'a MatRepr<F>,
+    /// seed of the random gaussian matrices of range approximation
+    seed: u64,
+    /// token stopping the range approximation
+    cancellation: Option<CancellationToken>,
 } // end of struct SvdApprox
 
 impl<'a, F> SvdApprox<'a, F>
@@ -849,21 +1136,38 @@ where
         + Default,
 {
     pub fn new(data: &'a MatRepr<F>) -> Self {
-        SvdApprox { data }
+        SvdApprox {
+            data,
+            seed: DEFAULT_SVD_SEED,
+            cancellation: None,
+        }
+    }
+
+    /// sets the seed used in range approximation. Defaults to [DEFAULT_SVD_SEED]
+    pub fn set_seed(&mut self, seed: u64) {
+        self.seed = seed;
+    }
+
+    /// sets a token stopping the range approximation, [direct_svd](Self::direct_svd) then returns [AnnembedError::Cancelled]
+    pub fn set_cancellation_token(&mut self, token: &CancellationToken) {
+        self.cancellation = Some(token.clone());
     }
 
     /// direct svd from Algo 5.1 of Halko-Tropp
     /// Returns an error if either the preliminary range_approximation or the partial svd failed, else returns a SvdResult
-    pub fn direct_svd(&mut self, parameters: RangeApproxMode) -> Result<SvdResult<F>, String> {
+    pub fn direct_svd(&mut self, parameters: RangeApproxMode) -> Result<SvdResult<F>, AnnembedError> {
         log::debug!("in SvdApprox::direct_svd");
-        let ra = RangeApprox::new(self.data, parameters);
-        let q;
-        let q_opt = ra.get_approximator();
-        if q_opt.is_some() {
-            q = q_opt.unwrap();
-        } else {
-            return Err(String::from("range approximation failed"));
+        // lapack drivers in range approximation would fail on NaN
+        if !self.data.is_finite() {
+            log::error!("SvdApprox::direct_svd matrix has non finite values");
+            return Err(AnnembedError::NonFinite("matrix to approximate"));
         }
+        let mut ra = RangeApprox::new(self.data, parameters);
+        ra.set_seed(self.seed);
+        if let Some(token) = self.cancellation.as_ref() {
+            ra.set_cancellation_token(token);
+        }
+        let q = ra.get_approximator()?;
         //
         let mut b = match &self.data.data {
             MatMode::FULL(mat) => q.t().dot(mat),
@@ -879,14 +1183,15 @@ where
         };
         let slice_for_svd_opt = b.as_slice_mut();
         if slice_for_svd_opt.is_none() {
-            println!("direct_svd Matrix cannot be transformed into a slice : not contiguous or not in standard order");
-            return Err(String::from("not contiguous or not in standard order"));
+            log::error!("direct_svd Matrix cannot be transformed into a slice : not contiguous or not in standard order");
+            return Err(AnnembedError::NotContiguous);
         }
         // use divide conquer (calls lapack gesdd), faster but could use svd (lapack gesvd)
         log::trace!("direct_svd calling svddc driver");
         let res_svd_b = F::svddc(layout, JobSvd::Some, slice_for_svd_opt.unwrap());
-        if res_svd_b.is_err() {
-            println!("direct_svd, svddc failed");
+        if let Err(e) = &res_svd_b {
+            log::error!("direct_svd, svddc failed");
+            return Err(AnnembedError::SvdFailed(e.to_string()));
         };
         // we have to decode res and fill in SvdApprox fields.
         // lax does encapsulte dgesvd (double) and sgesvd (single)  which returns U and Vt as vectors.
@@ -901,8 +1206,8 @@ where
         let s: Array1<F> = res_svd_b
             .s
             .iter()
-            .map(|x| F::from(*x).unwrap())
-            .collect::<Array1<F>>();
+            .map(|x| F::from(*x).ok_or(AnnembedError::FloatConversion))
+            .collect::<Result<Array1<F>, AnnembedError>>()?;
         //
         let s_u: Option<Array2<F>>;
         if let Some(u_vec) = res_svd_b.u {
@@ -1126,21 +1431,21 @@ fn orthogonalize_with_q<F: Scalar + ndarray::ScalarOperand>(
 // instead of calling mat.qr() and returning res.0
 // The purpose of this function is just to avoid the R allocation in Lax qr
 //
-fn do_qr<F>(layout: MatrixLayout, mat: &mut Array2<F>)
+fn do_qr<F>(layout: MatrixLayout, mat: &mut Array2<F>) -> Result<(), AnnembedError>
 where
     F: Float + Lapack + Scalar + ndarray::ScalarOperand,
 {
-    let (_, _) = match layout {
-        MatrixLayout::C { row, lda } => (row as usize, lda as usize),
-        _ => panic!(),
-    };
-    let tau_res = F::householder(layout, mat.as_slice_mut().unwrap());
-    if tau_res.is_err() {
-        log::error!("svdapprox::do_qr : a lapack error occurred in F::householder");
-        panic!();
+    if !matches!(layout, MatrixLayout::C { .. }) {
+        log::error!("svdapprox::do_qr : matrix must be in C order");
+        return Err(AnnembedError::NotContiguous);
     }
-    let tau = tau_res.unwrap();
-    F::q(layout, mat.as_slice_mut().unwrap(), &tau).unwrap();
+    let slice = mat.as_slice_mut().ok_or(AnnembedError::NotContiguous)?;
+    let tau = F::householder(layout, slice).map_err(|e| {
+        log::error!("svdapprox::do_qr : a lapack error occurred in F::householder");
+        AnnembedError::SvdFailed(format!("qr decomposition : {}", e))
+    })?;
+    F::q(layout, slice, &tau).map_err(|e| AnnembedError::SvdFailed(format!("qr decomposition : {}", e)))?;
+    Ok(())
 } // end of do_qr
 
 //=========================================================================
@@ -1208,6 +1513,29 @@ mod tests {
         assert!((radius_from_full - radius_from_csmat).abs() < 0.0001 * radius_from_full);
     } // enf of test_spectral_radius_csr
 
+    #[test]
+    fn test_svdresult_utilities() {
+        log_init_test();
+        // A = U * S * Vt with U the 2 first columns of identity (3,3) and Vt a rotation
+        let (c, s) = (0.6f64, 0.8f64);
+        let mut svd_res = SvdResult {
+            s: Some(ndarray::arr1(&[4., 2.])),
+            u: Some(ndarray::arr2(&[[1., 0.], [0., 1.], [0., 0.]])),
+            vt: Some(ndarray::arr2(&[[c, s], [-s, c]])),
+        };
+        assert!(svd_res.check_u_orthonormality(1.0E-10));
+        let ratios = svd_res.get_explained_variance_ratio().unwrap();
+        assert!((ratios[0] - 0.8).abs() < 1.0E-10);
+        // projection of first row of A gives first row of U * S
+        let a_row = ndarray::arr1(&[4. * c, 4. * s]);
+        let proj = svd_res.project(&a_row.view()).unwrap();
+        assert!((proj[0] - 4.).abs() < 1.0E-10 && proj[1].abs() < 1.0E-10);
+        svd_res.truncate(1);
+        assert_eq!(svd_res.get_u().as_ref().unwrap().dim(), (3, 1));
+        assert_eq!(svd_res.get_vt().as_ref().unwrap().dim(), (1, 2));
+        assert_eq!(svd_res.get_sigma().as_ref().unwrap().len(), 1);
+    } // end of test_svdresult_utilities
+
     #[test]
     fn test_arrayview_mut() {
         log_init_test();
@@ -1223,7 +1551,7 @@ mod tests {
     fn test_range_approx_randomized_1() {
         log_init_test();
         //
-        let data = RandomGaussianGenerator::<f64>::new().generate_matrix(Dim([15, 50]));
+        let data = RandomGaussianGenerator::<f64>::new_with_seed(DEFAULT_SVD_SEED).generate_matrix(Dim([15, 50]));
         let norm_data = estimate_first_singular_value_fullmat(&data.mat.view());
         let rp = RangePrecision {
             epsil: 0.05,
@@ -1246,7 +1574,7 @@ mod tests {
     fn test_range_approx_randomized_2() {
         log_init_test();
         //
-        let data = RandomGaussianGenerator::<f32>::new().generate_matrix(Dim([50, 500]));
+        let data = RandomGaussianGenerator::<f32>::new_with_seed(DEFAULT_SVD_SEED).generate_matrix(Dim([50, 500]));
         let norm_data = estimate_first_singular_value_fullmat(&data.mat.view());
         let rp = RangePrecision {
             epsil: 0.05,
@@ -1270,7 +1598,7 @@ mod tests {
     fn test_range_approx_subspace_iteration_1() {
         log_init_test();
         //
-        let data = RandomGaussianGenerator::<f64>::new().generate_matrix(Dim([12, 50]));
+        let data = RandomGaussianGenerator::<f64>::new_with_seed(DEFAULT_SVD_SEED).generate_matrix(Dim([12, 50]));
         let norm_data = estimate_first_singular_value_fullmat(&data.mat.view());
         let rp = RangeRank {
             rank: 12,
@@ -1291,7 +1619,7 @@ mod tests {
     fn test_range_approx_subspace_iteration_2() {
         log_init_test();
         //
-        let mut data = RandomGaussianGenerator::<f64>::new()
+        let mut data = RandomGaussianGenerator::<f64>::new_with_seed(DEFAULT_SVD_SEED)
             .generate_matrix(Dim([30, 500]))
             .mat;
         // reduce rank to 26
@@ -1327,10 +1655,10 @@ mod tests {
         let n = 3003;
         let rank = 200;
         let asked_rank = 500; // we check we exit at rank = 200
-        let u = RandomGaussianGenerator::<f64>::new()
+        let u = RandomGaussianGenerator::<f64>::new_with_seed(DEFAULT_SVD_SEED)
             .generate_matrix(Dim([m, m]))
             .mat;
-        let v = RandomGaussianGenerator::<f64>::new()
+        let v = RandomGaussianGenerator::<f64>::new_with_seed(DEFAULT_SVD_SEED)
             .generate_matrix(Dim([n, n]))
             .mat;
         // a rank deficient matrix (m,n)
@@ -1358,6 +1686,60 @@ mod tests {
         );
     } // end of test_range_approx_epsil
 
+    #[test]
+    fn test_block_range_finder() {
+        log_init_test();
+        // a (300, 200) matrix of rank 12
+        let rank = 12;
+        let mut rng = RandomGaussianGenerator::<f64>::new_with_seed(DEFAULT_SVD_SEED);
+        let u = rng.generate_stdn_vect(Ix1(300 * rank)).into_shape((300, rank)).unwrap();
+        let v = rng.generate_stdn_vect(Ix1(rank * 200)).into_shape((rank, 200)).unwrap();
+        let mat = u.dot(&v);
+        let full = MatRepr::from_array2(mat.clone());
+        let csr = MatRepr::from_csrmat(CsMat::csr_from_dense(mat.view(), 0.));
+        // dense and csr products agree
+        let x = rng.generate_stdn_vect(Ix1(200 * 3)).into_shape((200, 3)).unwrap();
+        let diff = &full.mat_dot_dense(&x.view()) - &csr.mat_dot_dense(&x.view());
+        assert!(diff.iter().all(|d| d.abs() < 1.0E-10));
+        for matrepr in [&full, &csr] {
+            // blocks of 5 vectors, the exact rank is found and Q is orthonormal
+            let q = block_range_finder_matrep(matrepr, 1.0E-3, 5, 50, DEFAULT_SVD_SEED, None);
+            log::info!("block range finder q shape {:?}", q.dim());
+            assert_eq!(q.dim(), (300, rank));
+            let gram = q.t().dot(&q);
+            assert!((gram - Array2::<f64>::eye(rank)).iter().all(|x| x.abs() < 1.0E-8));
+            let residue = &mat - &q.dot(&q.t().dot(&mat));
+            assert!(norm_frobenius_full(&residue.view()) < 1.0E-6 * norm_frobenius_full(&mat.view()));
+        }
+        // max_rank bounds the range
+        let q = block_range_finder_matrep(&full, 1.0E-3, 5, 8, DEFAULT_SVD_SEED, None);
+        assert_eq!(q.ncols(), 8);
+    } // end of test_block_range_finder
+
+    #[test]
+    fn test_subspace_iteration_csr_vs_full() {
+        log_init_test();
+        // a sparse (400, 300) matrix with a slowly decaying spectrum : a diagonal block plus a few random entries
+        let (m, n) = (400, 300);
+        let mut mat = Array2::<f64>::zeros((m, n));
+        for i in 0..n {
+            mat[[i, i]] = 1. / (1. + i as f64).sqrt();
+            mat[[(7 * i + 3) % m, (13 * i + 5) % n]] += 0.1;
+        }
+        let csr = CsMat::csr_from_dense(mat.view(), 0.);
+        let (rank, nbiter) = (20, 4);
+        let q_csr = subspace_iteration_csr(&csr, rank, nbiter, DEFAULT_SVD_SEED, None).unwrap();
+        let q_full = subspace_iteration_full(&mat, rank, nbiter, DEFAULT_SVD_SEED, None).unwrap();
+        assert_eq!(q_csr.dim(), (m, rank));
+        let gram = q_csr.t().dot(&q_csr);
+        assert!((gram - Array2::<f64>::eye(rank)).iter().all(|x| x.abs() < 1.0E-8));
+        // the same range is found
+        let residue_csr = check_range_approx_repr(&MatRepr::from_csrmat(csr), &q_csr);
+        let residue_full = check_range_approx(&mat.view(), &q_full.view());
+        log::info!("subspace iteration residues csr {:.3e} full {:.3e}", residue_csr, residue_full);
+        assert!((residue_csr - residue_full).abs() < 1.0E-4 * residue_full.max(1.));
+    } // end of test_subspace_iteration_csr_vs_full
+
     #[test]
     fn test_range_approx_rank() {
         log_init_test();
@@ -1366,10 +1748,10 @@ mod tests {
         let n = 503;
         let rank = 20;
 
-        let u = RandomGaussianGenerator::<f64>::new()
+        let u = RandomGaussianGenerator::<f64>::new_with_seed(DEFAULT_SVD_SEED)
             .generate_matrix(Dim([m, m]))
             .mat;
-        let v = RandomGaussianGenerator::<f64>::new()
+        let v = RandomGaussianGenerator::<f64>::new_with_seed(DEFAULT_SVD_SEED)
             .generate_matrix(Dim([n, n]))
             .mat;
         // a rank deficient matrix (m,n)
@@ -1415,7 +1797,7 @@ mod tests {
         // get same matri in a csr representation
         let csr_mat: CsMat<f64> = get_wiki_csr_mat_f64();
         // A is (4,5)
-        let gmat = RandomGaussianGenerator::<f64>::new().generate_matrix(Dim([4, 4]));
+        let gmat = RandomGaussianGenerator::<f64>::new_with_seed(DEFAULT_SVD_SEED).generate_matrix(Dim([4, 4]));
         let mut prodmat = Array2::<f64>::zeros((5, 4));
         prod::csc_mulacc_dense_colmaj(
             csr_mat.transpose_view(),
@@ -1714,7 +2096,7 @@ mod tests {
         log_init_test();
         // get wiki (4,5) matrix
         let csr_mat = get_wiki_csr_mat_f64();
-        let gmat = RandomGaussianGenerator::<f64>::new().generate_matrix(Dim([4, 7]));
+        let gmat = RandomGaussianGenerator::<f64>::new_with_seed(DEFAULT_SVD_SEED).generate_matrix(Dim([4, 7]));
         // compute transpose(gmat.mat) *csr_mat
         let mult_res = transpose_dense_mult_csr(&gmat.mat, &csr_mat);
         // brute force
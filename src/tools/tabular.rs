@@ -0,0 +1,389 @@
+//! Streaming ingestion of tabular datasets stored in [Parquet](https://parquet.apache.org) or
+//! [Arrow IPC](https://arrow.apache.org/docs/format/Columnar.html#ipc-file-format) (feather v2) files.
+//!
+//! Numeric columns (all of them or a selection by name) are read record batch by record batch, each batch being
+//! converted to an `Array2<T>` with one row by record. [tabular_insert_hnsw] inserts batches into an Hnsw as they
+//! are read, so the whole dataset is never materialized as a Vec<Vec<T>>.
+//! Only the selected columns are decoded (parquet projection), values are cast to f64 by arrow then converted to T,
+//! so integer columns up to 2^53 are exact. Null values are an error.
+//!
+//! This module requires feature *arrow*.
+
+use std::fs::File;
+use std::path::Path;
+
+use arrow::array::{Array, AsArray, RecordBatch};
+use arrow::compute::cast;
+use arrow::datatypes::{DataType, Float64Type, Schema};
+use arrow::error::ArrowError;
+use arrow::ipc::reader::FileReader;
+use parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder;
+use parquet::arrow::ProjectionMask;
+
+use ndarray::Array2;
+use num_traits::cast::FromPrimitive;
+use num_traits::Float;
+
+use hnsw_rs::prelude::*;
+
+use crate::diffmaps::INSERTION_BLOCKSIZE;
+use crate::error::AnnembedError;
+use crate::tools::stage::Stage;
+
+fn invalid_data<E: std::fmt::Display>(e: E) -> AnnembedError {
+    AnnembedError::Io(std::io::Error::new(std::io::ErrorKind::InvalidData, e.to_string()))
+}
+
+/// file formats we read
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum TabularFormat {
+    Parquet,
+    /// Arrow IPC file format, as written by pyarrow.feather.write_feather or arrow::ipc::writer::FileWriter
+    ArrowIpc,
+}
+
+impl TabularFormat {
+    /// format deduced from file extension : .parquet or .pq, .arrow, .feather or .ipc
+    pub fn from_path(path: &Path) -> Option<Self> {
+        let extension = path.extension()?.to_str()?.to_lowercase();
+        match extension.as_str() {
+            "parquet" | "pq" => Some(TabularFormat::Parquet),
+            "arrow" | "feather" | "ipc" => Some(TabularFormat::ArrowIpc),
+            _ => None,
+        }
+    }
+} // end of impl TabularFormat
+
+/// Options of [TabularReader].
+/// By default all numeric columns are read by batches of [INSERTION_BLOCKSIZE] records.
+#[derive(Clone, Debug)]
+pub struct TabularOptions {
+    columns: Option<Vec<String>>,
+    batch_size: usize,
+    format: Option<TabularFormat>,
+}
+
+impl Default for TabularOptions {
+    fn default() -> Self {
+        TabularOptions {
+            columns: None,
+            batch_size: INSERTION_BLOCKSIZE,
+            format: None,
+        }
+    }
+}
+
+impl TabularOptions {
+    /// names of the columns to read, in the order of the rows of batches. They must be numeric.
+    pub fn set_columns(&mut self, columns: Vec<String>) -> &mut Self {
+        self.columns = Some(columns);
+        self
+    }
+
+    /// number of records by batch. Only used for parquet, arrow ipc files keep the batches they were written with.
+    pub fn set_batch_size(&mut self, batch_size: usize) -> &mut Self {
+        self.batch_size = batch_size;
+        self
+    }
+
+    /// format of the file, if it cannot be deduced from its extension
+    pub fn set_format(&mut self, format: TabularFormat) -> &mut Self {
+        self.format = Some(format);
+        self
+    }
+} // end of impl TabularOptions
+
+// names and ranks in schema of the columns to read
+fn select_columns(schema: &Schema, columns: &Option<Vec<String>>) -> Result<(Vec<String>, Vec<usize>), AnnembedError> {
+    let selected: Vec<(String, usize)> = match columns {
+        Some(names) => names
+            .iter()
+            .map(|name| {
+                let rank = schema
+                    .index_of(name)
+                    .map_err(|_| AnnembedError::InvalidParameter(format!("no column {} in schema", name)))?;
+                if !schema.field(rank).data_type().is_numeric() {
+                    return Err(AnnembedError::InvalidParameter(format!(
+                        "column {} has type {}, not numeric",
+                        name,
+                        schema.field(rank).data_type()
+                    )));
+                }
+                Ok((name.clone(), rank))
+            })
+            .collect::<Result<_, _>>()?,
+        None => schema
+            .fields()
+            .iter()
+            .enumerate()
+            .filter(|(_, field)| {
+                let numeric = field.data_type().is_numeric();
+                if !numeric {
+                    log::info!("skipping non numeric column {} of type {}", field.name(), field.data_type());
+                }
+                numeric
+            })
+            .map(|(rank, field)| (field.name().clone(), rank))
+            .collect(),
+    };
+    if selected.is_empty() {
+        return Err(AnnembedError::InvalidParameter(String::from("no numeric column to read")));
+    }
+    Ok(selected.into_iter().unzip())
+} // end of select_columns
+
+/// Reads the numeric columns of a Parquet or Arrow IPC file as a sequence of `Array2<T>`, one by record batch.
+pub struct TabularReader {
+    batches: Box<dyn Iterator<Item = Result<RecordBatch, ArrowError>> + Send>,
+    columns: Vec<String>,
+    /// number of records in file, if known from metadata
+    nb_rows: Option<usize>,
+    nb_rows_read: usize,
+}
+
+impl TabularReader {
+    pub fn open(path: &Path, options: &TabularOptions) -> Result<Self, AnnembedError> {
+        let format = options
+            .format
+            .or_else(|| TabularFormat::from_path(path))
+            .ok_or_else(|| AnnembedError::InvalidParameter(format!("cannot deduce tabular format of {}", path.display())))?;
+        if options.batch_size == 0 {
+            return Err(AnnembedError::InvalidParameter(String::from("batch size must be positive")));
+        }
+        let file = File::open(path)?;
+        let reader = match format {
+            TabularFormat::Parquet => {
+                let builder = ParquetRecordBatchReaderBuilder::try_new(file).map_err(invalid_data)?;
+                let (columns, ranks) = select_columns(builder.schema(), &options.columns)?;
+                let nb_rows = builder.metadata().file_metadata().num_rows() as usize;
+                let mask = ProjectionMask::roots(builder.parquet_schema(), ranks);
+                let batches = builder
+                    .with_projection(mask)
+                    .with_batch_size(options.batch_size)
+                    .build()
+                    .map_err(invalid_data)?;
+                TabularReader {
+                    batches: Box::new(batches),
+                    columns,
+                    nb_rows: Some(nb_rows),
+                    nb_rows_read: 0,
+                }
+            }
+            TabularFormat::ArrowIpc => {
+                let schema = FileReader::try_new(File::open(path)?, None).map_err(invalid_data)?.schema();
+                let (columns, ranks) = select_columns(&schema, &options.columns)?;
+                let batches = FileReader::try_new(file, Some(ranks)).map_err(invalid_data)?;
+                TabularReader {
+                    batches: Box::new(batches),
+                    columns,
+                    nb_rows: None,
+                    nb_rows_read: 0,
+                }
+            }
+        };
+        log::info!(
+            "TabularReader opened {} ({:?}), columns : {:?}",
+            path.display(),
+            format,
+            reader.columns
+        );
+        Ok(reader)
+    } // end of open
+
+    /// names of the columns read, in the order of the columns of batches
+    pub fn get_columns(&self) -> &[String] {
+        &self.columns
+    }
+
+    /// number of records of the file if known (parquet metadata)
+    pub fn get_nb_rows(&self) -> Option<usize> {
+        self.nb_rows
+    }
+
+    /// number of records returned by [next_batch](Self::next_batch) up to now
+    pub fn get_nb_rows_read(&self) -> usize {
+        self.nb_rows_read
+    }
+
+    /// next record batch, rows in file order. Returns None when file is exhausted.
+    pub fn next_batch<T>(&mut self) -> Result<Option<Array2<T>>, AnnembedError>
+    where
+        T: Float + FromPrimitive,
+    {
+        let batch = match self.batches.next() {
+            None => return Ok(None),
+            Some(batch) => batch.map_err(invalid_data)?,
+        };
+        let (nb_row, nb_col) = (batch.num_rows(), self.columns.len());
+        let mut values = vec![T::zero(); nb_row * nb_col];
+        for (j, name) in self.columns.iter().enumerate() {
+            let column = batch
+                .column_by_name(name)
+                .ok_or_else(|| invalid_data(format!("column {} missing in batch", name)))?;
+            if column.null_count() > 0 {
+                return Err(AnnembedError::InvalidParameter(format!(
+                    "column {} has {} null values in batch beginning at record {}",
+                    name,
+                    column.null_count(),
+                    self.nb_rows_read
+                )));
+            }
+            let column = cast(column, &DataType::Float64).map_err(invalid_data)?;
+            for (i, x) in column.as_primitive::<Float64Type>().values().iter().enumerate() {
+                values[i * nb_col + j] = T::from_f64(*x).ok_or(AnnembedError::FloatConversion)?;
+            }
+        }
+        self.nb_rows_read += nb_row;
+        Ok(Some(Array2::from_shape_vec((nb_row, nb_col), values).unwrap()))
+    } // end of next_batch
+} // end of impl TabularReader
+
+/// Parallel insertion of the records of a Parquet or Arrow IPC file into an empty Hnsw<T,D>, batch by batch,
+/// each record being inserted with its rank in file as DataId.
+/// Only one batch (see [TabularOptions::set_batch_size]) is in memory at any time.
+/// Returns the reader (to get column names) and the number of point inserted if success.
+pub fn tabular_insert_hnsw<T, D>(
+    path: &Path,
+    options: &TabularOptions,
+    hnsw: &mut Hnsw<T, D>,
+) -> Result<(TabularReader, usize), AnnembedError>
+where
+    T: Float + FromPrimitive + Send + Sync,
+    D: Distance<T> + Send + Sync,
+{
+    if hnsw.get_nb_point() > 0 {
+        log::error!(
+            "tabular_insert_hnsw , insertion on non empty hnsw structure, nb point : {}",
+            hnsw.get_nb_point()
+        );
+        return Err(AnnembedError::InvalidParameter(String::from("insertion in non empty hnsw")));
+    }
+    let mut reader = TabularReader::open(path, options)?;
+    let stage = Stage::enter("insertion");
+    stage.record_size("dim", reader.get_columns().len());
+    while let Some(batch) = reader.next_batch::<T>()? {
+        let first = reader.get_nb_rows_read() - batch.nrows();
+        let to_insert: Vec<(&[T], DataId)> = batch
+            .as_slice()
+            .unwrap()
+            .chunks(batch.ncols())
+            .enumerate()
+            .map(|(k, row)| (row, first + k))
+            .collect();
+        hnsw.parallel_insert_slice(&to_insert);
+        if let Some(nb_rows) = reader.get_nb_rows() {
+            stage.report_progress(reader.get_nb_rows_read(), nb_rows);
+        }
+    }
+    stage.record_size("nb_point", hnsw.get_nb_point());
+    //
+    let nb_point = hnsw.get_nb_point();
+    Ok((reader, nb_point))
+} // end of tabular_insert_hnsw
+
+//=======================================================================
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    use std::sync::Arc;
+
+    use arrow::array::{Float32Array, Int64Array, StringArray};
+    use arrow::datatypes::Field;
+    use arrow::ipc::writer::FileWriter;
+    use parquet::arrow::ArrowWriter;
+
+    fn log_init_test() {
+        let _ = env_logger::builder().is_test(true).try_init();
+    }
+
+    // 10 records with columns x (f32), label (string), y (i64)
+    fn test_batch() -> RecordBatch {
+        let schema = Arc::new(Schema::new(vec![
+            Field::new("x", DataType::Float32, false),
+            Field::new("label", DataType::Utf8, false),
+            Field::new("y", DataType::Int64, false),
+        ]));
+        let x = Float32Array::from((0..10).map(|i| i as f32 * 0.5).collect::<Vec<f32>>());
+        let label = StringArray::from((0..10).map(|i| format!("l{}", i)).collect::<Vec<String>>());
+        let y = Int64Array::from((0..10).map(|i| 100 - i).collect::<Vec<i64>>());
+        RecordBatch::try_new(schema, vec![Arc::new(x), Arc::new(label), Arc::new(y)]).unwrap()
+    }
+
+    #[test]
+    fn test_parquet_batches() {
+        log_init_test();
+        //
+        let path = std::env::temp_dir().join(format!("annembed_test_{}.parquet", std::process::id()));
+        let batch = test_batch();
+        let mut writer = ArrowWriter::try_new(File::create(&path).unwrap(), batch.schema(), None).unwrap();
+        writer.write(&batch).unwrap();
+        writer.close().unwrap();
+        // all numeric columns, by batches of 4
+        let mut options = TabularOptions::default();
+        options.set_batch_size(4);
+        let mut reader = TabularReader::open(&path, &options).unwrap();
+        assert_eq!(reader.get_columns(), &["x", "y"]);
+        assert_eq!(reader.get_nb_rows(), Some(10));
+        let mut sizes = Vec::<usize>::new();
+        while let Some(mat) = reader.next_batch::<f32>().unwrap() {
+            let first = reader.get_nb_rows_read() - mat.nrows();
+            assert_eq!(mat[[0, 0]], first as f32 * 0.5);
+            assert_eq!(mat[[0, 1]], (100 - first) as f32);
+            sizes.push(mat.nrows());
+        }
+        assert_eq!(sizes, vec![4, 4, 2]);
+        // selection keeps asked order
+        options.set_columns(vec![String::from("y"), String::from("x")]);
+        let mat = TabularReader::open(&path, &options).unwrap().next_batch::<f64>().unwrap().unwrap();
+        assert_eq!(mat.row(1).to_vec(), vec![99., 0.5]);
+        // non numeric or absent columns are rejected
+        options.set_columns(vec![String::from("label")]);
+        assert!(TabularReader::open(&path, &options).is_err());
+        options.set_columns(vec![String::from("z")]);
+        assert!(TabularReader::open(&path, &options).is_err());
+        // insertion
+        let mut options = TabularOptions::default();
+        options.set_batch_size(3);
+        let mut hnsw = Hnsw::<f32, DistL2>::new(8, 10, 16, 50, DistL2 {});
+        let (reader, nb_point) = tabular_insert_hnsw(&path, &options, &mut hnsw).unwrap();
+        assert_eq!(nb_point, 10);
+        assert_eq!(reader.get_nb_rows_read(), 10);
+        assert!(tabular_insert_hnsw(&path, &options, &mut hnsw).is_err());
+        let _ = std::fs::remove_file(&path);
+    } // end of test_parquet_batches
+
+    #[test]
+    fn test_ipc_batches() {
+        log_init_test();
+        //
+        let path = std::env::temp_dir().join(format!("annembed_test_{}.arrow", std::process::id()));
+        let batch = test_batch();
+        let mut writer = FileWriter::try_new(File::create(&path).unwrap(), &batch.schema()).unwrap();
+        writer.write(&batch.slice(0, 6)).unwrap();
+        writer.write(&batch.slice(6, 4)).unwrap();
+        writer.finish().unwrap();
+        //
+        let mut options = TabularOptions::default();
+        options.set_columns(vec![String::from("y")]);
+        let mut reader = TabularReader::open(&path, &options).unwrap();
+        assert_eq!(reader.get_nb_rows(), None);
+        let first = reader.next_batch::<f64>().unwrap().unwrap();
+        assert_eq!(first.dim(), (6, 1));
+        let second = reader.next_batch::<f64>().unwrap().unwrap();
+        assert_eq!(second[[3, 0]], 91.);
+        assert!(reader.next_batch::<f64>().unwrap().is_none());
+        // format given explicitly for an unknown extension
+        let renamed = path.with_extension("bin");
+        std::fs::rename(&path, &renamed).unwrap();
+        assert!(TabularReader::open(&renamed, &TabularOptions::default()).is_err());
+        let mut options = TabularOptions::default();
+        options.set_format(TabularFormat::ArrowIpc);
+        let mut hnsw = Hnsw::<f64, DistL2>::new(8, 10, 16, 50, DistL2 {});
+        let (_, nb_point) = tabular_insert_hnsw(&renamed, &options, &mut hnsw).unwrap();
+        assert_eq!(nb_point, 10);
+        let _ = std::fs::remove_file(&renamed);
+    } // end of test_ipc_batches
+} // end of mod tests
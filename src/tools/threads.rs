@@ -0,0 +1,60 @@
+//! Bounding the number of threads used by an embedding.
+//!
+//! Parallel sections run in the rayon pool of the caller, by default the global pool.
+//! With [EmbedderParams::set_num_threads](crate::embedparams::EmbedderParams::set_num_threads) or
+//! [DiffusionParams::set_num_threads](crate::diffmaps::DiffusionParams::set_num_threads) an embedding runs in a pool
+//! of its own, so that a server can bound the cpu usage of each request.
+
+use crate::error::AnnembedError;
+use crate::tools::stage::{get_observer, get_thread_profilers, set_thread_observer, set_thread_profilers};
+
+/// runs op in a rayon pool of num_threads threads built for this call, or in the current pool if num_threads is None.  
+/// Stages run by op are reported to the [StageProfiler](crate::tools::stage::StageProfiler)s and to the
+/// [EmbeddingObserver](crate::tools::stage::EmbeddingObserver) of the calling thread.
+pub fn install_in_pool<R, OP>(num_threads: Option<usize>, op: OP) -> Result<R, AnnembedError>
+where
+    OP: FnOnce() -> R + Send,
+    R: Send,
+{
+    match num_threads {
+        None => Ok(op()),
+        Some(0) => {
+            log::error!("install_in_pool, number of threads must be positive");
+            Err(AnnembedError::InvalidParameter(String::from("number of threads must be positive")))
+        }
+        Some(nb_threads) => {
+            let pool = rayon::ThreadPoolBuilder::new()
+                .num_threads(nb_threads)
+                .build()
+                .map_err(|e| AnnembedError::InvalidParameter(format!("thread pool construction failed : {}", e)))?;
+            log::debug!("install_in_pool, running in a pool of {} threads", nb_threads);
+            let profilers = get_thread_profilers();
+            let observer = get_observer();
+            Ok(pool.install(|| {
+                let previous = set_thread_profilers(profilers);
+                let previous_observer = set_thread_observer(observer);
+                let result = op();
+                set_thread_profilers(previous);
+                set_thread_observer(previous_observer);
+                result
+            }))
+        }
+    }
+} // end of install_in_pool
+
+//=======================================================================
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_install_in_pool() {
+        let nb = install_in_pool(Some(3), rayon::current_num_threads).unwrap();
+        assert_eq!(nb, 3);
+        let nb = install_in_pool(None, rayon::current_num_threads).unwrap();
+        assert_eq!(nb, rayon::current_num_threads());
+        assert!(install_in_pool(Some(0), || ()).is_err());
+    } // end of test_install_in_pool
+} // end of mod tests